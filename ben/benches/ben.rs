@@ -0,0 +1,133 @@
+use ben::{decode::Dict, DictEncoder, Entry, ListEncoder, Parser};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+/// Metainfo of a multi-file torrent with 2000 pieces.
+fn torrent() -> Vec<u8> {
+    let mut buf = vec![];
+    let mut dict = DictEncoder::new(&mut buf);
+    dict.insert("announce", "udp://tracker.example.com:6969/announce");
+    let mut list = dict.insert_list("announce-list");
+    for i in 0..10 {
+        let mut tier = list.push_list();
+        tier.push(format!("udp://tracker{}.example.com:6969/announce", i));
+    }
+    list.finish();
+    dict.insert("creation date", 1_600_000_000);
+
+    let mut info = dict.insert_dict("info");
+    let mut files = info.insert_list("files");
+    for i in 0..100 {
+        let mut file = files.push_dict();
+        file.insert("length", 1_234_567 + i);
+        let mut path = file.insert_list("path");
+        path.push("Some Directory");
+        path.push(format!("File number {}.mkv", i));
+    }
+    files.finish();
+    info.insert("name", "Some Directory");
+    info.insert("piece length", 262_144);
+    let mut pieces = info.insert_bytes_exact("pieces", 2000 * 20);
+    pieces.write(vec![0xab; 2000 * 20]).unwrap();
+    pieces.finish().unwrap();
+    info.finish();
+    dict.finish();
+    buf
+}
+
+/// Response to a DHT `get_peers` query with 8 nodes and 50 peers.
+fn get_peers_response() -> Vec<u8> {
+    let mut buf = vec![];
+    let mut dict = DictEncoder::new(&mut buf);
+    let mut r = dict.insert_dict("r");
+    r.insert("id", &[0x11u8; 20][..]);
+    r.insert("nodes", &[0x22u8; 26 * 8][..]);
+    r.insert("token", &[0x33u8; 8][..]);
+    let mut values = r.insert_list("values");
+    for _ in 0..50 {
+        values.push(&[0x44u8; 6][..]);
+    }
+    values.finish();
+    r.finish();
+    dict.insert("t", "aa");
+    dict.insert("y", "r");
+    dict.finish();
+    buf
+}
+
+/// List of 1000 integers with a wide range of lengths.
+fn ints() -> Vec<u8> {
+    let mut buf = vec![];
+    let mut list = ListEncoder::new(&mut buf);
+    for i in 0..1000i64 {
+        list.push(i.wrapping_mul(0x5851_f42d_4c95_7f2d) >> (i % 64));
+    }
+    list.finish();
+    buf
+}
+
+/// Dictionary of 5000 keys, like the file tree of a big torrent.
+fn big_dict() -> (Vec<u8>, Vec<String>) {
+    let keys: Vec<_> = (0..5000)
+        .map(|i| format!("File number {:04}.mkv", i))
+        .collect();
+    let mut buf = vec![];
+    let mut dict = DictEncoder::new(&mut buf);
+    for (i, key) in keys.iter().enumerate() {
+        dict.insert(key, i as i64);
+    }
+    dict.finish();
+    (buf, keys)
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    let mut parser = Parser::new();
+    for (name, input) in [
+        ("torrent", torrent()),
+        ("get_peers", get_peers_response()),
+        ("ints", ints()),
+    ] {
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let entry = parser.parse::<Entry>(black_box(&input)).unwrap();
+                black_box(entry.as_raw_bytes().len());
+            })
+        });
+    }
+    group.finish();
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    let mut parser = Parser::new();
+    let (input, keys) = big_dict();
+    let dict = parser.parse::<Dict>(&input).unwrap();
+    group.bench_function("dict", |b| {
+        b.iter(|| {
+            for key in keys.iter().step_by(50) {
+                black_box(dict.get(black_box(key)));
+            }
+        })
+    });
+    group.bench_function("indexed", |b| {
+        b.iter(|| {
+            let indexed = dict.indexed();
+            for key in keys.iter().step_by(50) {
+                black_box(indexed.get(black_box(key)));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    group.bench_function("torrent", |b| b.iter(torrent));
+    group.bench_function("get_peers", |b| b.iter(get_peers_response));
+    group.bench_function("ints", |b| b.iter(ints));
+    group.finish();
+}
+
+criterion_group!(benches, parse, lookup, encode);
+criterion_main!(benches);
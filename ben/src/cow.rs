@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+
+use crate::decode::Entry;
+use crate::encode::{encode_bytes, Encode};
+
+/// A parsed `Entry` with targeted edits on top.
+///
+/// Encoding a `CowEntry` copies the parts of the original buffer that were
+/// not edited verbatim, so a field can be rewritten without decoding and
+/// re-encoding the rest of the structure.
+///
+/// # Examples
+///
+/// Basic usage:
+/// ```
+/// use ben::{CowEntry, Encode, Entry, Parser};
+///
+/// let bytes = b"d8:announce3:foo7:comment3:baz4:infod4:name3:baree";
+/// let parser = &mut Parser::new();
+/// let entry = parser.parse::<Entry>(bytes).unwrap();
+///
+/// let mut cow = CowEntry::new(entry);
+/// cow.insert("announce", "bar");
+/// cow.insert("created by", "ben");
+/// cow.remove("comment");
+///
+/// assert_eq!(
+///     &b"d8:announce3:bar10:created by3:ben4:infod4:name3:baree"[..],
+///     &cow.encode_to_vec()[..]
+/// );
+/// ```
+pub struct CowEntry<'b, 'p> {
+    entry: Entry<'b, 'p>,
+    edits: BTreeMap<Vec<u8>, Edit<'b, 'p>>,
+}
+
+enum Edit<'b, 'p> {
+    Replace(Vec<u8>),
+    Remove,
+    Nested(CowEntry<'b, 'p>),
+}
+
+impl<'b, 'p> CowEntry<'b, 'p> {
+    pub fn new(entry: Entry<'b, 'p>) -> Self {
+        Self {
+            entry,
+            edits: BTreeMap::new(),
+        }
+    }
+
+    /// Returns true if any edits have been made.
+    pub fn is_edited(&self) -> bool {
+        self.edits.values().any(|e| match e {
+            Edit::Nested(cow) => cow.is_edited(),
+            _ => true,
+        })
+    }
+
+    /// Sets the value for the given key, inserting the key if it's missing.
+    ///
+    /// Edits only apply if the entry is a dictionary.
+    pub fn insert<E: Encode>(&mut self, key: &str, value: E) {
+        let value = value.encode_to_vec();
+        self.edits.insert(key.into(), Edit::Replace(value));
+    }
+
+    /// Removes the given key.
+    pub fn remove(&mut self, key: &str) {
+        self.edits.insert(key.into(), Edit::Remove);
+    }
+
+    /// Returns the `CowEntry` for editing the dictionary under the given key
+    /// of the original entry. Returns `None` if there is no such dictionary.
+    pub fn get_dict(&mut self, key: &str) -> Option<&mut CowEntry<'b, 'p>> {
+        if !matches!(self.edits.get(key.as_bytes()), Some(Edit::Nested(_))) {
+            let entry = self.entry.as_dict()?.get(key)?;
+            if !entry.is_dict() {
+                return None;
+            }
+            self.edits
+                .insert(key.into(), Edit::Nested(CowEntry::new(entry)));
+        }
+
+        match self.edits.get_mut(key.as_bytes()) {
+            Some(Edit::Nested(cow)) => Some(cow),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Encode for CowEntry<'_, '_> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let dict = match self.entry.as_dict() {
+            Some(dict) if !self.edits.is_empty() => dict,
+            _ => {
+                buf.extend_from_slice(self.entry.as_encoded_bytes());
+                return;
+            }
+        };
+
+        buf.push(b'd');
+        let mut edits = self.edits.iter().peekable();
+        for (key, value) in dict.raw_iter() {
+            // New keys which sort before this one
+            while let Some((k, edit)) = edits.next_if(|(k, _)| k.as_slice() < key) {
+                write_edit(buf, k, edit);
+            }
+
+            match edits.next_if(|(k, _)| k.as_slice() == key) {
+                Some((k, edit)) => write_edit(buf, k, edit),
+                None => {
+                    encode_bytes(buf, key);
+                    buf.extend_from_slice(value.as_encoded_bytes());
+                }
+            }
+        }
+
+        for (k, edit) in edits {
+            write_edit(buf, k, edit);
+        }
+        buf.push(b'e');
+    }
+}
+
+fn write_edit(buf: &mut Vec<u8>, key: &[u8], edit: &Edit<'_, '_>) {
+    match edit {
+        Edit::Replace(value) => {
+            encode_bytes(buf, key);
+            buf.extend_from_slice(value);
+        }
+        Edit::Nested(cow) => {
+            encode_bytes(buf, key);
+            cow.encode(buf);
+        }
+        Edit::Remove => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parser;
+
+    #[test]
+    fn no_edits_copies_verbatim() {
+        let s = b"d1:ai1e1:bl1:xee";
+        let p = &mut Parser::new();
+        let entry = p.parse::<Entry>(s).unwrap();
+        let cow = CowEntry::new(entry);
+        assert!(!cow.is_edited());
+        assert_eq!(&s[..], &cow.encode_to_vec()[..]);
+    }
+
+    #[test]
+    fn replace_value() {
+        let s = b"d8:announce3:foo4:infod6:lengthi10eee";
+        let p = &mut Parser::new();
+        let entry = p.parse::<Entry>(s).unwrap();
+        let mut cow = CowEntry::new(entry);
+        cow.insert("announce", "udp://bar");
+        assert_eq!(
+            &b"d8:announce9:udp://bar4:infod6:lengthi10eee"[..],
+            &cow.encode_to_vec()[..]
+        );
+    }
+
+    #[test]
+    fn insert_keys_in_order() {
+        let s = b"d1:b0:1:d0:e";
+        let p = &mut Parser::new();
+        let entry = p.parse::<Entry>(s).unwrap();
+        let mut cow = CowEntry::new(entry);
+        cow.insert("e", 5);
+        cow.insert("c", "x");
+        cow.insert("a", vec!["y"]);
+        assert_eq!(
+            &b"d1:al1:ye1:b0:1:c1:x1:d0:1:ei5ee"[..],
+            &cow.encode_to_vec()[..]
+        );
+    }
+
+    #[test]
+    fn remove_keys() {
+        let s = b"d1:ai1e1:bi2e1:ci3ee";
+        let p = &mut Parser::new();
+        let entry = p.parse::<Entry>(s).unwrap();
+        let mut cow = CowEntry::new(entry);
+        cow.remove("a");
+        cow.remove("c");
+        cow.remove("missing");
+        assert_eq!(&b"d1:bi2ee"[..], &cow.encode_to_vec()[..]);
+    }
+
+    #[test]
+    fn nested_edit() {
+        let s = b"d4:infod6:lengthi3e4:name3:fooe1:zi0ee";
+        let p = &mut Parser::new();
+        let entry = p.parse::<Entry>(s).unwrap();
+        let mut cow = CowEntry::new(entry);
+        assert!(cow.get_dict("z").is_none());
+        assert!(cow.get_dict("missing").is_none());
+
+        let info = cow.get_dict("info").unwrap();
+        info.insert("name", "bar");
+        cow.get_dict("info").unwrap().insert("private", 1);
+        assert!(cow.is_edited());
+        assert_eq!(
+            &b"d4:infod6:lengthi3e4:name3:bar7:privatei1ee1:zi0ee"[..],
+            &cow.encode_to_vec()[..]
+        );
+    }
+
+    #[test]
+    fn not_a_dict() {
+        let s = b"l1:ae";
+        let p = &mut Parser::new();
+        let entry = p.parse::<Entry>(s).unwrap();
+        let mut cow = CowEntry::new(entry);
+        cow.insert("a", "b");
+        assert_eq!(&s[..], &cow.encode_to_vec()[..]);
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{cell::OnceCell, fmt};
 
 use super::{
     int::Int,
@@ -28,9 +28,18 @@ impl<'b, 'p> IntoIterator for Dict<'b, 'p> {
 
 impl<'b, 'p> Dict<'b, 'p> {
     /// Gets an iterator over the entries of the dictionary.
+    ///
+    /// Keys which aren't valid UTF-8, only possible with
+    /// [`Parser::binary_keys`](crate::Parser::binary_keys), are skipped.
     pub fn iter(&self) -> DictIter<'b, 'p> {
         DictIter::new(self.entry)
     }
+
+    /// Gets an iterator over the entries of the dictionary with the keys as
+    /// bytes.
+    pub fn raw_iter(&self) -> RawDictIter<'b, 'p> {
+        RawDictIter::new(self.entry)
+    }
     /// Returns raw bytes of this dictionary.
     ///
     /// # Examples
@@ -50,8 +59,8 @@ impl<'b, 'p> Dict<'b, 'p> {
 
     /// Returns the `Entry` for the given key.
     pub fn get(&self, key: &str) -> Option<Entry<'b, 'p>> {
-        self.iter()
-            .find_map(|(k, v)| if k == key { Some(v) } else { None })
+        self.raw_iter()
+            .find_map(|(k, v)| if k == key.as_bytes() { Some(v) } else { None })
     }
 
     /// Returns the `Dict` for the given key.
@@ -91,16 +100,114 @@ impl<'b, 'p> Dict<'b, 'p> {
     pub fn is_empty(&self) -> bool {
         self.entry.token().next == 1
     }
+
+    /// Returns the dictionary with an index of its keys, for looking up
+    /// many keys of a big dictionary.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    /// ```
+    /// use ben::{Parser, decode::Dict};
+    ///
+    /// let bytes = b"d1:ai1e1:bi2ee";
+    /// let parser = &mut Parser::new();
+    /// let dict = parser.parse::<Dict>(bytes).unwrap().indexed();
+    /// assert_eq!(Some(2), dict.get_int::<i64>("b"));
+    /// assert_eq!(Some(1), dict.get_int::<i64>("a"));
+    /// ```
+    pub fn indexed(&self) -> IndexedDict<'b, 'p> {
+        IndexedDict {
+            dict: Dict { entry: self.entry },
+            index: OnceCell::new(),
+        }
+    }
+}
+
+/// A bencode dictionary with an index of its keys.
+///
+/// [`Dict::get`] walks the entries on every call. The index is built on
+/// the first lookup instead, so that the following lookups are binary
+/// searches over the keys, which the parser ensures are sorted.
+pub struct IndexedDict<'b, 'p> {
+    dict: Dict<'b, 'p>,
+    index: OnceCell<Vec<(&'b [u8], Entry<'b, 'p>)>>,
+}
+
+impl fmt::Debug for IndexedDict<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.dict.fmt(f)
+    }
+}
+
+impl<'b, 'p> IndexedDict<'b, 'p> {
+    /// Returns the dictionary without the index.
+    pub fn dict(&self) -> &Dict<'b, 'p> {
+        &self.dict
+    }
+
+    fn index(&self) -> &[(&'b [u8], Entry<'b, 'p>)] {
+        self.index.get_or_init(|| self.dict.raw_iter().collect())
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.index().len()
+    }
+
+    /// Returns true if the dictionary is empty
+    pub fn is_empty(&self) -> bool {
+        self.dict.is_empty()
+    }
+
+    /// Returns the `Entry` for the given key.
+    pub fn get(&self, key: &str) -> Option<Entry<'b, 'p>> {
+        // The first of duplicate keys, like `Dict::get`
+        let index = self.index();
+        let i = index.partition_point(|&(k, _)| k < key.as_bytes());
+        match index.get(i) {
+            Some(&(k, v)) if k == key.as_bytes() => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Returns the `Dict` for the given key.
+    pub fn get_dict(&self, key: &str) -> Option<Dict<'b, 'p>> {
+        self.get(key)?.as_dict()
+    }
+
+    /// Returns the `List` for the given key.
+    pub fn get_list(&self, key: &str) -> Option<List<'b, 'p>> {
+        self.get(key)?.as_list()
+    }
+
+    /// Returns the byte slice for the given key.
+    pub fn get_bytes(&self, key: &str) -> Option<&'b [u8]> {
+        self.get(key)?.as_bytes()
+    }
+
+    /// Returns the string slice for the given key.
+    pub fn get_str(&self, key: &str) -> Option<&'b str> {
+        self.get(key)?.as_str()
+    }
+
+    /// Returns the `Int` for the given key.
+    pub fn get_int<I>(&self, key: &str) -> Option<I>
+    where
+        I: Int,
+    {
+        self.get(key)?.as_int()
+    }
 }
 
 pub struct DictIter<'b, 'p> {
-    iter: ListIter<'b, 'p>,
+    iter: RawDictIter<'b, 'p>,
 }
 
 impl<'b, 'p> DictIter<'b, 'p> {
     fn new(entry: Entry<'b, 'p>) -> Self {
         Self {
-            iter: ListIter::new(entry),
+            iter: RawDictIter::new(entry),
         }
     }
 }
@@ -109,13 +216,31 @@ impl<'b, 'p> Iterator for DictIter<'b, 'p> {
     type Item = (&'b str, Entry<'b, 'p>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let key = self.iter.next()?;
-        let value = self.iter.next()?;
+        self.iter
+            .by_ref()
+            .find_map(|(k, v)| Some((std::str::from_utf8(k).ok()?, v)))
+    }
+}
 
-        // Safety: Validated by the parser
-        let key = unsafe { std::str::from_utf8_unchecked(key.as_raw_bytes()) };
+pub struct RawDictIter<'b, 'p> {
+    iter: ListIter<'b, 'p>,
+}
 
-        Some((key, value))
+impl<'b, 'p> RawDictIter<'b, 'p> {
+    fn new(entry: Entry<'b, 'p>) -> Self {
+        Self {
+            iter: ListIter::new(entry),
+        }
+    }
+}
+
+impl<'b, 'p> Iterator for RawDictIter<'b, 'p> {
+    type Item = (&'b [u8], Entry<'b, 'p>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self.iter.next()?;
+        let value = self.iter.next()?;
+        Some((key.as_raw_bytes(), value))
     }
 }
 
@@ -220,4 +345,43 @@ mod tests {
         assert!(!dict.is_empty());
         assert_eq!(dict.get("a").unwrap().as_raw_bytes(), b"l1:ad1:al1:aee1:be");
     }
+
+    #[test]
+    fn indexed_dict_get() {
+        let s = b"d1:ai1e1:bl1:ce1:dd1:ei2eee";
+        let p = &mut Parser::new();
+        let dict = p.parse::<Dict>(s).unwrap().indexed();
+        assert_eq!(dict.len(), 3);
+        assert_eq!(dict.get_int::<i64>("a"), Some(1));
+        assert_eq!(dict.get_list("b").unwrap().as_raw_bytes(), b"l1:ce");
+        assert_eq!(dict.get_dict("d").unwrap().get_int::<i64>("e"), Some(2));
+        assert!(dict.get("c").is_none());
+        assert!(dict.get("").is_none());
+        assert!(dict.get("e").is_none());
+    }
+
+    #[test]
+    fn indexed_dict_duplicate_keys() {
+        let s = b"d1:ai1e1:ai4e1:bi2e1:bi5e1:ci3ee";
+        let p = &mut Parser::new();
+        let dict = p.parse::<Dict>(s).unwrap();
+        let indexed = dict.indexed();
+        for key in ["a", "b", "c", "d"] {
+            assert_eq!(
+                indexed.get_int::<i64>(key),
+                dict.get_int::<i64>(key),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn indexed_empty_dict() {
+        let p = &mut Parser::new();
+        let dict = p.parse::<Dict>(b"de").unwrap().indexed();
+        assert!(dict.is_empty());
+        assert_eq!(dict.len(), 0);
+        assert!(dict.get("a").is_none());
+    }
 }
@@ -68,6 +68,40 @@ impl<'b, 'p> Entry<'b, 'p> {
         }
     }
 
+    /// Returns the encoded bytes of this entry, including the headers of
+    /// strings and ints.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    /// ```
+    /// use ben::{Parser, Entry};
+    ///
+    /// let bytes = b"l1:ai10ee";
+    /// let parser = &mut Parser::new();
+    /// let list = parser.parse::<Entry>(bytes).unwrap().as_list().unwrap();
+    /// assert_eq!(b"1:a", list.get(0).unwrap().as_encoded_bytes());
+    /// assert_eq!(b"i10e", list.get(1).unwrap().as_encoded_bytes());
+    /// ```
+    pub fn as_encoded_bytes(&self) -> &'b [u8] {
+        let t = self.token();
+        let (start, len) = match t.kind {
+            TokenKind::Int => (t.start - 1, t.len + 2),
+            TokenKind::ByteStr => {
+                // Length prefix has no leading zeros (ensured by parser)
+                let header = num_digits(t.len) + 1;
+                (t.start - header, t.len + header)
+            }
+            TokenKind::List | TokenKind::Dict => (t.start, t.len),
+        };
+
+        // Safety: Tokens are always in-bounds (ensured by parser)
+        unsafe {
+            let p = self.buf.add(start as usize);
+            std::slice::from_raw_parts(p, len as usize)
+        }
+    }
+
     /// Returns true if this entry is a list.
     pub fn is_list(&self) -> bool {
         self.token().kind == TokenKind::List
@@ -230,6 +264,15 @@ impl<'b, 'p> Entry<'b, 'p> {
     }
 }
 
+fn num_digits(mut n: u32) -> u32 {
+    let mut digits = 1;
+    while n >= 10 {
+        n /= 10;
+        digits += 1;
+    }
+    digits
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -280,7 +323,7 @@ mod tests {
     fn int_value_invalid() {
         let s = b"ixyze";
         let err = Parser::new().parse::<i64>(s).unwrap_err();
-        assert_eq!(Error::Invalid, err);
+        assert_eq!(Error::Invalid { pos: 1 }, err);
     }
 
     #[test]
@@ -295,7 +338,7 @@ mod tests {
     fn decode_empty() {
         let p = &mut Parser::new();
         let err = p.parse::<Entry>(&[]).unwrap_err();
-        assert_eq!(err, Error::Eof);
+        assert_eq!(err, Error::Eof { pos: 0 });
     }
 
     #[test]
@@ -1,3 +1,4 @@
+use crate::error::{Error, Result};
 use itoa::Buffer;
 
 pub fn encode_int(buf: &mut Vec<u8>, value: i64) {
@@ -70,6 +71,13 @@ impl<'a> ListEncoder<'a> {
         self.buf.into()
     }
 
+    /// Create a new `ExactBytesEncoder` for a byte string of `len` bytes in
+    /// this list.
+    #[inline]
+    pub fn push_bytes_exact(&mut self, len: usize) -> ExactBytesEncoder<'_> {
+        ExactBytesEncoder::new(self.buf, len)
+    }
+
     /// Finish building this list.
     #[inline]
     pub fn finish(self) {}
@@ -84,7 +92,8 @@ impl Drop for ListEncoder<'_> {
 
 /// Bencode Dictionary representation.
 ///
-/// Note: This will not enforce order or uniqueness of keys.
+/// Note: This will not enforce order or uniqueness of keys. See
+/// `SortedDictEncoder` for a dictionary which checks them in release builds too.
 /// These invariants have to be maintained by the caller.
 ///
 /// If the invariants don't meet in debug mode, the add calls will
@@ -136,6 +145,14 @@ impl<'a> DictEncoder<'a> {
         self.buf.into()
     }
 
+    /// Create a new `ExactBytesEncoder` for a byte string of `len` bytes
+    /// for given key inside this dictionary.
+    #[inline]
+    pub fn insert_bytes_exact(&mut self, key: &str, len: usize) -> ExactBytesEncoder<'_> {
+        self.insert_key(key);
+        ExactBytesEncoder::new(self.buf, len)
+    }
+
     fn insert_key(&mut self, key: &str) {
         self.assert_key_ordering(key);
         encode_bytes(self.buf, key);
@@ -175,6 +192,109 @@ impl Drop for DictEncoder<'_> {
     }
 }
 
+/// Bencode Dictionary representation which enforces the order and uniqueness
+/// of the keys in all builds.
+///
+/// Unlike `DictEncoder`, inserting a key out of order returns an error and
+/// leaves the dictionary unchanged.
+pub struct SortedDictEncoder<'a> {
+    buf: &'a mut Vec<u8>,
+    last_key: Option<String>,
+}
+
+impl<'a> SortedDictEncoder<'a> {
+    /// Create a new dict
+    #[inline]
+    pub fn new(buf: &'a mut Vec<u8>) -> Self {
+        buf.push(b'd');
+        Self {
+            buf,
+            last_key: None,
+        }
+    }
+
+    /// `Encode` the value for given key inside this dictionary.
+    #[inline]
+    pub fn insert<E: Encode>(&mut self, key: &str, value: E) -> Result<()> {
+        self.insert_key(key)?;
+        value.encode(self.buf);
+        Ok(())
+    }
+
+    /// Create a new `ListEncoder` for given key inside this dictionary.
+    #[inline]
+    pub fn insert_list(&mut self, key: &str) -> Result<ListEncoder<'_>> {
+        self.insert_key(key)?;
+        Ok(self.buf.into())
+    }
+
+    /// Create a new `SortedDictEncoder` for given key inside this dictionary.
+    #[inline]
+    pub fn insert_dict(&mut self, key: &str) -> Result<SortedDictEncoder<'_>> {
+        self.insert_key(key)?;
+        Ok(self.buf.into())
+    }
+
+    /// Create a new `LazyBytesEncoder` for given key inside this dictionary.
+    #[inline]
+    pub fn insert_bytes_lazy<const N: usize>(
+        &mut self,
+        key: &str,
+    ) -> Result<LazyBytesEncoder<'_, N>> {
+        self.insert_key(key)?;
+        Ok(self.buf.into())
+    }
+
+    /// Create a new `ExactBytesEncoder` for a byte string of `len` bytes
+    /// for given key inside this dictionary.
+    #[inline]
+    pub fn insert_bytes_exact(&mut self, key: &str, len: usize) -> Result<ExactBytesEncoder<'_>> {
+        self.insert_key(key)?;
+        Ok(ExactBytesEncoder::new(self.buf, len))
+    }
+
+    fn insert_key(&mut self, key: &str) -> Result<()> {
+        if let Some(last_key) = &mut self.last_key {
+            let pos = self.buf.len();
+            if key < &last_key[..] {
+                return Err(Error::UnsortedKey {
+                    pos,
+                    key: key.to_owned(),
+                });
+            }
+            if key == &last_key[..] {
+                return Err(Error::DuplicateKey {
+                    pos,
+                    key: key.to_owned(),
+                });
+            }
+            last_key.clear();
+            last_key.push_str(key);
+        } else {
+            self.last_key = Some(key.to_owned());
+        }
+
+        encode_bytes(self.buf, key);
+        Ok(())
+    }
+
+    /// Finish building this dictionary.
+    #[inline]
+    pub fn finish(self) {}
+}
+
+impl Drop for SortedDictEncoder<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        self.buf.push(b'e');
+    }
+}
+
+/// Bencode byte string of at most `N` bytes whose length isn't known
+/// upfront.
+///
+/// The bytes are collected in a fixed size buffer and the string is written
+/// once the encoder is finished or dropped.
 pub struct LazyBytesEncoder<'a, const N: usize> {
     buf: &'a mut Vec<u8>,
     data: [u8; N],
@@ -190,16 +310,127 @@ impl<'a, const N: usize> LazyBytesEncoder<'a, N> {
         }
     }
 
+    /// Append the bytes to the string.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string would exceed `N` bytes. See `try_extend` for a
+    /// non-panicking version.
     pub fn extend(&mut self, bytes: impl AsRef<[u8]>) {
+        self.try_extend(bytes).unwrap();
+    }
+
+    /// Append the bytes to the string. Returns an error and leaves the
+    /// string unchanged if it would exceed `N` bytes.
+    pub fn try_extend(&mut self, bytes: impl AsRef<[u8]>) -> Result<()> {
         let bytes = bytes.as_ref();
         let new_len = self.len + bytes.len();
+        if new_len > N {
+            return Err(Error::LengthMismatch {
+                expected: N,
+                actual: new_len,
+            });
+        }
         self.data[self.len..new_len].copy_from_slice(bytes);
         self.len = new_len;
+        Ok(())
     }
 
     pub fn finish(self) {}
 }
 
+/// Bencode byte string whose exact length is known before its contents.
+///
+/// The length prefix is written upfront and the bytes are streamed straight
+/// into the buffer, so there's no limit on the size of the string, e.g. for
+/// the concatenated piece hashes of a torrent.
+///
+/// Writing more than `len` bytes is an error. If the encoder is dropped
+/// before all the bytes are written, the rest of the string is filled with
+/// zeros so that the output is still valid bencode; use `finish` to check
+/// that the string is complete.
+///
+/// # Examples
+///
+/// Basic usage:
+/// ```
+/// use ben::DictEncoder;
+///
+/// let hashes = [[1; 20], [2; 20]];
+///
+/// let mut buf = vec![];
+/// let mut dict = DictEncoder::new(&mut buf);
+/// let mut pieces = dict.insert_bytes_exact("pieces", hashes.len() * 20);
+/// for hash in &hashes {
+///     pieces.write(hash).unwrap();
+/// }
+/// pieces.finish().unwrap();
+/// dict.finish();
+///
+/// assert!(buf.starts_with(b"d6:pieces40:\x01"));
+/// assert_eq!(buf.len(), b"d6:pieces40:e".len() + 40);
+/// ```
+pub struct ExactBytesEncoder<'a> {
+    buf: &'a mut Vec<u8>,
+    len: usize,
+    written: usize,
+}
+
+impl<'a> ExactBytesEncoder<'a> {
+    /// Create a byte string of `len` bytes. The length prefix is written
+    /// right away.
+    pub fn new(buf: &'a mut Vec<u8>, len: usize) -> Self {
+        let mut fmt = Buffer::new();
+        buf.extend(fmt.format(len).as_bytes());
+        buf.push(b':');
+        buf.reserve(len);
+        Self {
+            buf,
+            len,
+            written: 0,
+        }
+    }
+
+    /// Number of bytes still to be written.
+    pub fn remaining(&self) -> usize {
+        self.len - self.written
+    }
+
+    /// Append the bytes to the string. Returns an error and writes nothing
+    /// if the string would exceed its length.
+    pub fn write(&mut self, bytes: impl AsRef<[u8]>) -> Result<()> {
+        let bytes = bytes.as_ref();
+        if bytes.len() > self.remaining() {
+            return Err(Error::LengthMismatch {
+                expected: self.len,
+                actual: self.written + bytes.len(),
+            });
+        }
+        self.buf.extend_from_slice(bytes);
+        self.written += bytes.len();
+        Ok(())
+    }
+
+    /// Finish the string. Returns an error if fewer bytes were written than
+    /// its length, in which case the rest is filled with zeros.
+    pub fn finish(self) -> Result<()> {
+        if self.written < self.len {
+            return Err(Error::LengthMismatch {
+                expected: self.len,
+                actual: self.written,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ExactBytesEncoder<'_> {
+    fn drop(&mut self) {
+        let len = self.buf.len() + self.remaining();
+        self.buf.resize(len, 0);
+    }
+}
+
 impl<'a, const N: usize> Drop for LazyBytesEncoder<'a, N> {
     fn drop(&mut self) {
         self.data[..self.len].encode(self.buf);
@@ -218,6 +449,12 @@ impl<'a> From<&'a mut Vec<u8>> for DictEncoder<'a> {
     }
 }
 
+impl<'a> From<&'a mut Vec<u8>> for SortedDictEncoder<'a> {
+    fn from(buf: &'a mut Vec<u8>) -> Self {
+        Self::new(buf)
+    }
+}
+
 impl<'a, const N: usize> From<&'a mut Vec<u8>> for LazyBytesEncoder<'a, N> {
     fn from(buf: &'a mut Vec<u8>) -> Self {
         Self::new(buf)
@@ -227,14 +464,14 @@ impl<'a, const N: usize> From<&'a mut Vec<u8>> for LazyBytesEncoder<'a, N> {
 impl<T: Encode + ?Sized> Encode for &T {
     #[inline]
     fn encode(&self, buf: &mut Vec<u8>) {
-        (&**self).encode(buf);
+        (**self).encode(buf);
     }
 }
 
 impl<T: Encode + ?Sized> Encode for Box<T> {
     #[inline]
     fn encode(&self, buf: &mut Vec<u8>) {
-        (&**self).encode(buf);
+        (**self).encode(buf);
     }
 }
 
@@ -400,7 +637,7 @@ mod tests {
     fn lazy_bytes_partially_filled() {
         let mut v = vec![];
         let mut b = LazyBytesEncoder::<2>::new(&mut v);
-        b.extend(&[1]);
+        b.extend([1]);
         b.finish();
         assert_eq!(v, [b'1', b':', 1]);
     }
@@ -409,7 +646,7 @@ mod tests {
     fn lazy_bytes_filled() {
         let mut v = vec![];
         let mut b = LazyBytesEncoder::<2>::new(&mut v);
-        b.extend(&[1, 2]);
+        b.extend([1, 2]);
         b.finish();
         assert_eq!(v, [b'2', b':', 1, 2]);
     }
@@ -419,7 +656,119 @@ mod tests {
     fn lazy_bytes_extra() {
         let mut v = vec![];
         let mut b = LazyBytesEncoder::<2>::new(&mut v);
-        b.extend(&[1, 2, 3]);
+        b.extend([1, 2, 3]);
+    }
+
+    #[test]
+    fn lazy_bytes_try_extend() {
+        let mut v = vec![];
+        let mut b = LazyBytesEncoder::<2>::new(&mut v);
+        b.try_extend([1]).unwrap();
+        assert_eq!(
+            Err(Error::LengthMismatch {
+                expected: 2,
+                actual: 3
+            }),
+            b.try_extend([2, 3])
+        );
+        b.try_extend([2]).unwrap();
+        b.finish();
+        assert_eq!(v, [b'2', b':', 1, 2]);
+    }
+
+    #[test]
+    fn exact_bytes() {
+        let mut v = vec![];
+        let mut b = ExactBytesEncoder::new(&mut v, 3);
+        b.write([1, 2]).unwrap();
+        assert_eq!(1, b.remaining());
+        assert_eq!(
+            Err(Error::LengthMismatch {
+                expected: 3,
+                actual: 4
+            }),
+            b.write([3, 4])
+        );
+        b.write([3]).unwrap();
+        b.finish().unwrap();
+        assert_eq!(v, [b'3', b':', 1, 2, 3]);
+    }
+
+    #[test]
+    fn exact_bytes_underfilled() {
+        let mut v = vec![];
+        let mut list = ListEncoder::new(&mut v);
+        let mut b = list.push_bytes_exact(3);
+        b.write([1]).unwrap();
+        assert_eq!(
+            Err(Error::LengthMismatch {
+                expected: 3,
+                actual: 1
+            }),
+            b.finish()
+        );
+        list.finish();
+        assert_eq!(v, [b'l', b'3', b':', 1, 0, 0, b'e']);
+    }
+
+    #[test]
+    fn exact_bytes_in_sorted_dict() {
+        let buf = &mut vec![];
+        let mut dict = SortedDictEncoder::new(buf);
+        dict.insert("a", 1).unwrap();
+        let mut b = dict.insert_bytes_exact("b", 2).unwrap();
+        b.write(b"xy").unwrap();
+        b.finish().unwrap();
+        assert!(dict.insert_bytes_exact("a", 0).is_err());
+        dict.finish();
+        assert_eq!(b"d1:ai1e1:b2:xye", &buf[..]);
+    }
+
+    #[test]
+    fn sorted_dict() {
+        let buf = &mut vec![];
+        let mut dict = SortedDictEncoder::new(buf);
+        dict.insert("a", "Hello").unwrap();
+        let mut inner = dict.insert_dict("b").unwrap();
+        inner.insert("x", 1).unwrap();
+        inner.finish();
+        dict.finish();
+        assert_eq!(b"d1:a5:Hello1:bd1:xi1eee", &buf[..]);
+    }
+
+    #[test]
+    fn sorted_dict_unordered() {
+        let buf = &mut vec![];
+        let mut dict = SortedDictEncoder::new(buf);
+        dict.insert("b", "Hello").unwrap();
+        assert_eq!(
+            dict.insert("a", "World").unwrap_err(),
+            Error::UnsortedKey {
+                pos: 11,
+                key: "a".into()
+            }
+        );
+
+        // Failed insert doesn't change the dictionary
+        dict.insert("c", "World").unwrap();
+        dict.finish();
+        assert_eq!(b"d1:b5:Hello1:c5:Worlde", &buf[..]);
+    }
+
+    #[test]
+    fn sorted_dict_duplicate() {
+        let buf = &mut vec![];
+        let mut dict = SortedDictEncoder::new(buf);
+        dict.insert("a", "Hello").unwrap();
+        assert_eq!(
+            dict.insert_list("a").err(),
+            Some(Error::DuplicateKey {
+                pos: 11,
+                key: "a".into()
+            })
+        );
+        dict.finish();
+        assert_eq!(b"d1:a5:Helloe", &buf[..]);
     }
 
     #[cfg(debug_assertions)]
@@ -2,33 +2,64 @@ use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Parse errors. `pos` is the byte offset in the input where the error was
+/// detected.
 #[derive(Debug, Error, Clone, PartialEq)]
 pub enum Error {
-    #[error("Unexpected End of File")]
+    #[error("Unexpected End of File at {pos}")]
     /// Unexpected End of File
-    Eof,
+    Eof { pos: usize },
 
-    #[error("Unexpected trailing data in the input")]
+    #[error("Unexpected trailing data in the input at {pos}")]
     /// Unexpected trailing data in the input
-    TrailingData,
+    TrailingData { pos: usize },
 
-    #[error("Invalid input")]
+    #[error("Invalid input at {pos}")]
     /// Invalid input
-    Invalid,
+    Invalid { pos: usize },
 
-    #[error("Exceeded Token limit")]
+    #[error("Dictionary key `{key}` at {pos} is not sorted")]
+    /// Dictionary keys are not sorted
+    UnsortedKey { pos: usize, key: String },
+
+    #[error("Dictionary key `{key}` at {pos} is duplicate")]
+    /// Dictionary keys are not unique
+    DuplicateKey { pos: usize, key: String },
+
+    #[error("Exceeded Token limit at {pos}")]
     /// Exceeded Token limit
-    TokenLimit,
+    TokenLimit { pos: usize },
 
-    #[error("Exceeded Depth limit")]
+    #[error("Exceeded Depth limit at {pos}")]
     /// Exceeded Depth limit
-    DepthLimit,
+    DepthLimit { pos: usize },
 
-    #[error("Integer overflow")]
+    #[error("Integer overflow at {pos}")]
     /// Integer Overflow
-    Overflow,
+    Overflow { pos: usize },
 
     #[error("Decode error")]
     /// Decode error
     Decode,
+
+    #[error("Expected {expected} bytes, got {actual}")]
+    /// Byte string written doesn't match the length given upfront
+    LengthMismatch { expected: usize, actual: usize },
+}
+
+impl Error {
+    /// Byte offset in the input where the error was detected, if any.
+    pub fn pos(&self) -> Option<usize> {
+        match *self {
+            Error::Eof { pos }
+            | Error::TrailingData { pos }
+            | Error::Invalid { pos }
+            | Error::UnsortedKey { pos, .. }
+            | Error::DuplicateKey { pos, .. }
+            | Error::TokenLimit { pos }
+            | Error::DepthLimit { pos }
+            | Error::Overflow { pos } => Some(pos),
+            Error::Decode | Error::LengthMismatch { .. } => None,
+        }
+    }
 }
@@ -2,13 +2,20 @@
 //! a flat stream of tokens rather than an actual tree and thus avoids
 //! unneccessary allocations.
 
+mod cow;
 pub mod decode;
 mod encode;
 mod error;
 mod parse;
 mod token;
+mod value;
 
+pub use cow::CowEntry;
 pub use decode::{Decode, Entry};
-pub use encode::{encode_bytes, encode_int, DictEncoder, Encode, LazyBytesEncoder, ListEncoder};
+pub use encode::{
+    encode_bytes, encode_int, DictEncoder, Encode, ExactBytesEncoder, LazyBytesEncoder,
+    ListEncoder, SortedDictEncoder,
+};
 pub use error::{Error, Result};
 pub use parse::Parser;
+pub use value::Value;
@@ -8,6 +8,7 @@ pub struct Parser {
     scopes: Vec<Scope>,
     token_limit: usize,
     depth_limit: usize,
+    binary_keys: bool,
 }
 
 impl Default for Parser {
@@ -17,6 +18,7 @@ impl Default for Parser {
             scopes: vec![],
             token_limit: usize::MAX,
             depth_limit: usize::MAX,
+            binary_keys: false,
         }
     }
 }
@@ -45,6 +47,13 @@ impl Parser {
         self.depth_limit = depth_limit
     }
 
+    /// Accept dictionary keys which aren't valid UTF-8, such as the hashes
+    /// keying the `piece layers` of BitTorrent v2 torrents. Such keys are
+    /// only returned by [`Dict::raw_iter`](crate::decode::Dict::raw_iter).
+    pub fn binary_keys(&mut self, allow: bool) {
+        self.binary_keys = allow;
+    }
+
     /// Parse a bencoded slice and returns the parsed object
     pub fn parse<'b, 'p, T>(&'p mut self, buf: &'b [u8]) -> Result<T>
     where
@@ -54,7 +63,7 @@ impl Parser {
         if len == buf.len() {
             T::decode(dec).ok_or(Error::Decode)
         } else {
-            Err(Error::TrailingData)
+            Err(Error::TrailingData { pos: len })
         }
     }
 
@@ -82,6 +91,7 @@ impl Parser {
             scopes: &mut self.scopes,
             token_limit: self.token_limit,
             depth_limit: self.depth_limit,
+            binary_keys: self.binary_keys,
         };
 
         state.parse()?;
@@ -115,28 +125,26 @@ struct ParserState<'a> {
     scopes: &'a mut Vec<Scope>,
     token_limit: usize,
     depth_limit: usize,
+    binary_keys: bool,
 }
 
 macro_rules! ensure {
-    ($cond:expr) => {
-        ensure!($cond, Invalid);
+    ($cond:expr, $pos:expr) => {
+        ensure!($cond, Invalid, $pos);
     };
-    ($cond:expr, $err:ident) => {
+    ($cond:expr, $err:ident, $pos:expr) => {
         if !$cond {
-            return Err(Error::$err);
+            return Err(Error::$err { pos: $pos });
         }
     };
 }
 
 impl<'a> ParserState<'a> {
     fn peek_char(&self) -> Result<u8> {
-        self.buf.get(self.pos).copied().ok_or(Error::Eof)
-    }
-
-    fn next_char(&mut self) -> Result<u8> {
-        let c = self.peek_char()?;
-        self.pos += 1;
-        Ok(c)
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or(Error::Eof { pos: self.pos })
     }
 
     fn parse(&mut self) -> Result<()> {
@@ -146,13 +154,13 @@ impl<'a> ParserState<'a> {
             if let Some(scope) = self.scopes.last() {
                 if scope.dict && c != b'e' {
                     // The key must be a string
-                    ensure!(c.is_ascii_digit());
+                    ensure!(c.is_ascii_digit(), self.pos);
 
                     // Parse key as a valid UTF-8 string
-                    self.parse_string(true)?;
+                    self.parse_string(!self.binary_keys)?;
 
                     c = self.peek_char()?;
-                    ensure!(c != b'e');
+                    ensure!(c != b'e', self.pos);
                 }
             }
 
@@ -170,7 +178,7 @@ impl<'a> ParserState<'a> {
                 b'i' => self.parse_int()?,
                 b'0'..=b'9' => self.parse_string(false)?,
                 b'e' => self.pop_scope()?,
-                _ => return Err(Error::Invalid),
+                _ => return Err(Error::Invalid { pos: self.pos }),
             }
 
             if self.scopes.is_empty() {
@@ -178,12 +186,12 @@ impl<'a> ParserState<'a> {
             }
         }
 
-        ensure!(self.scopes.is_empty());
+        ensure!(self.scopes.is_empty(), self.pos);
         Ok(())
     }
 
     fn pop_scope(&mut self) -> Result<()> {
-        let scope = self.scopes.pop().ok_or(Error::Invalid)?;
+        let scope = self.scopes.pop().ok_or(Error::Invalid { pos: self.pos })?;
 
         self.pos += 1;
 
@@ -194,9 +202,14 @@ impl<'a> ParserState<'a> {
 
         if scope.dict {
             let dict = Entry::from_raw(self.buf.as_ptr(), t).as_dict().unwrap();
-            let mut last_key = "";
-            for (k, _) in dict {
-                ensure!(last_key <= k);
+            let mut last_key: &[u8] = b"";
+            for (k, _) in dict.raw_iter() {
+                if last_key > k {
+                    return Err(Error::UnsortedKey {
+                        pos: k.as_ptr() as usize - self.buf.as_ptr() as usize,
+                        key: String::from_utf8_lossy(k).into_owned(),
+                    });
+                }
                 last_key = k;
             }
         }
@@ -209,50 +222,52 @@ impl<'a> ParserState<'a> {
         self.pos += 1;
 
         let start = self.pos;
-        let mut c = self.next_char()?;
-
-        if c == b'-' {
-            c = self.next_char()?;
-            ensure!(c != b'0');
-        }
-
-        ensure!(c != b'e');
-
-        if c == b'0' {
-            c = self.next_char()?;
-        } else {
-            while c.is_ascii_digit() {
-                c = self.next_char()?;
+        let signed = self.buf.get(start) == Some(&b'-');
+        let digits_start = (start + signed as usize).min(self.buf.len());
+        let n = digits_len(&self.buf[digits_start..]);
+
+        match &self.buf[digits_start..digits_start + n] {
+            [b'0', ..] if signed => return Err(Error::Invalid { pos: digits_start }),
+            [b'0', _, ..] => {
+                return Err(Error::Invalid {
+                    pos: digits_start + 1,
+                })
             }
+            _ => {}
         }
 
-        ensure!(c == b'e');
+        let end = digits_start + n;
+        match self.buf.get(end) {
+            Some(b'e') if n > 0 => {}
+            Some(_) => return Err(Error::Invalid { pos: end }),
+            None => return Err(Error::Eof { pos: end }),
+        }
 
-        let len = self.pos - start - 1;
-        let t = Token::new(TokenKind::Int, start as u32, len as u32, 1);
+        self.pos = end + 1;
+        let t = Token::new(TokenKind::Int, start as u32, (end - start) as u32, 1);
         self.create_token(t)
     }
 
     fn parse_string(&mut self, validate_utf8: bool) -> Result<()> {
-        let mut len: usize = 0;
+        let start = self.pos;
 
-        let mut c = self.next_char()?;
-        if c == b'0' {
-            c = self.next_char()?;
-        } else {
-            while c.is_ascii_digit() {
-                let digit = (c - b'0') as usize;
-                len = len
-                    .checked_mul(10)
-                    .and_then(|n| n.checked_add(digit))
-                    .ok_or(Error::Overflow)?;
-
-                c = self.next_char()?;
-            }
+        // A length with more digits than this overflows anyway
+        let end = self.buf.len().min(start + MAX_LEN_DIGITS + 1);
+        let n = digits_len(&self.buf[start..end]);
+        let digits = &self.buf[start..start + n];
+        ensure!(!matches!(digits, [b'0', _, ..]), start + 1);
+
+        let len = digits.iter().try_fold(0usize, |len, c| {
+            len.checked_mul(10)?.checked_add((c - b'0') as usize)
+        });
+        let len = len.ok_or(Error::Overflow { pos: start })?;
+
+        match self.buf.get(start + n) {
+            Some(b':') => self.pos = start + n + 1,
+            Some(_) => return Err(Error::Invalid { pos: start + n }),
+            None => return Err(Error::Eof { pos: start + n }),
         }
-
-        ensure!(c == b':');
-        ensure!(len <= self.buf.len() - self.pos, Eof);
+        ensure!(len <= self.buf.len() - self.pos, Eof, self.buf.len());
 
         let t = Token::new(TokenKind::ByteStr, self.pos as u32, len as u32, 1);
         self.create_token(t)?;
@@ -263,17 +278,20 @@ impl<'a> ParserState<'a> {
         if validate_utf8 {
             let value = &self.buf[start..self.pos];
 
-            std::str::from_utf8(value).map_err(|_| Error::Invalid)?;
+            std::str::from_utf8(value).map_err(|e| Error::Invalid {
+                pos: start + e.valid_up_to(),
+            })?;
         }
 
         Ok(())
     }
 
     fn create_token(&mut self, token: Token) -> Result<()> {
-        ensure!(self.tokens.len() < self.token_limit, TokenLimit);
+        let pos = token.start as usize;
+        ensure!(self.tokens.len() < self.token_limit, TokenLimit, pos);
 
         if let TokenKind::Dict | TokenKind::List = token.kind {
-            ensure!(self.scopes.len() < self.depth_limit, DepthLimit);
+            ensure!(self.scopes.len() < self.depth_limit, DepthLimit, pos);
             let s = Scope::new(self.tokens.len(), token.kind == TokenKind::Dict);
             self.scopes.push(s);
         }
@@ -283,6 +301,31 @@ impl<'a> ParserState<'a> {
     }
 }
 
+/// Number of digits in the longest string length which fits in `usize`.
+const MAX_LEN_DIGITS: usize = (usize::MAX.ilog10() + 1) as usize;
+
+/// Number of leading ASCII digits of `buf`.
+///
+/// Checks eight bytes at a time, as the integers and lengths in torrents and
+/// DHT messages are often long.
+fn digits_len(buf: &[u8]) -> usize {
+    let mut n = 0;
+    for chunk in buf.chunks_exact(8) {
+        let x = u64::from_le_bytes(chunk.try_into().unwrap());
+
+        // Every byte is in 0x30..=0x39 iff its high nibble is 3 and adding 6
+        // doesn't carry into the high nibble. A byte >= 0xfa carries into the
+        // next one, but then its own high nibble already fails the check.
+        let high = x & 0xf0f0_f0f0_f0f0_f0f0;
+        let carry = (x.wrapping_add(0x0606_0606_0606_0606) & 0xf0f0_f0f0_f0f0_f0f0) >> 4;
+        if high | carry != 0x3333_3333_3333_3333 {
+            break;
+        }
+        n += 8;
+    }
+    n + buf[n..].iter().take_while(|c| c.is_ascii_digit()).count()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -292,7 +335,7 @@ mod tests {
         let s = b"";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Eof);
+        assert_eq!(err, Error::Eof { pos: 0 });
     }
 
     #[test]
@@ -324,7 +367,7 @@ mod tests {
         let s = b"ie";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(err, Error::Invalid { pos: 1 });
     }
 
     #[test]
@@ -343,7 +386,7 @@ mod tests {
         let s = b"3:abcd";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(Error::TrailingData, err);
+        assert_eq!(Error::TrailingData { pos: 5 }, err);
     }
 
     #[test]
@@ -351,7 +394,7 @@ mod tests {
         let s = b"3:ab";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(Error::Eof, err);
+        assert_eq!(Error::Eof { pos: 4 }, err);
     }
 
     #[test]
@@ -359,7 +402,7 @@ mod tests {
         let s = format!("{}:", (usize::MAX as u128 + 1));
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s.as_bytes()).unwrap_err();
-        assert_eq!(Error::Overflow, err);
+        assert_eq!(Error::Overflow { pos: 0 }, err);
     }
 
     #[test]
@@ -374,21 +417,21 @@ mod tests {
     fn unclosed_dict() {
         let s = b"d";
         let err = Parser::new().parse::<Entry>(s).unwrap_err();
-        assert_eq!(Error::Eof, err);
+        assert_eq!(Error::Eof { pos: 1 }, err);
     }
 
     #[test]
     fn key_only_dict() {
         let s = b"d1:ae";
         let err = Parser::new().parse::<Entry>(s).unwrap_err();
-        assert_eq!(Error::Invalid, err);
+        assert_eq!(Error::Invalid { pos: 4 }, err);
     }
 
     #[test]
     fn key_only_dict_2() {
         let s = b"d1:a1:a1:ae";
         let err = Parser::new().parse::<Entry>(s).unwrap_err();
-        assert_eq!(Error::Invalid, err);
+        assert_eq!(Error::Invalid { pos: 10 }, err);
     }
 
     #[test]
@@ -413,7 +456,20 @@ mod tests {
         let s = &[b'd', b'1', b':', 0x80, b'2', b':', b'a', b'b', b'e'];
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(err, Error::Invalid { pos: 3 });
+    }
+
+    #[test]
+    fn dict_binary_key() {
+        let s = &[b'd', b'1', b':', 0x80, b'2', b':', b'a', b'b', b'e'];
+        let mut parser = Parser::new();
+        parser.binary_keys(true);
+        let dict = parser.parse::<crate::decode::Dict>(s).unwrap();
+        assert_eq!(dict.iter().count(), 0);
+
+        let (k, v) = dict.raw_iter().next().unwrap();
+        assert_eq!(k, [0x80]);
+        assert_eq!(v.as_bytes(), Some(&b"ab"[..]));
     }
 
     #[test]
@@ -453,7 +509,7 @@ mod tests {
     fn unclosed_list() {
         let s = b"l";
         let err = Parser::new().parse::<Entry>(s).unwrap_err();
-        assert_eq!(Error::Eof, err);
+        assert_eq!(Error::Eof { pos: 1 }, err);
     }
 
     #[test]
@@ -516,7 +572,7 @@ mod tests {
 
         let s = b"l1:a2:ab3:abc4:abcde";
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(Error::TokenLimit, err);
+        assert_eq!(Error::TokenLimit { pos: 10 }, err);
 
         let entry = parser.parse::<Entry>(b"le").unwrap();
         assert_eq!(b"le", entry.as_raw_bytes());
@@ -528,7 +584,7 @@ mod tests {
         parser.depth_limit(3);
 
         let err = parser.parse::<Entry>(b"lllleeee").unwrap_err();
-        assert_eq!(Error::DepthLimit, err);
+        assert_eq!(Error::DepthLimit { pos: 3 }, err);
 
         let entry = parser.parse::<Entry>(b"llleee").unwrap();
         assert_eq!(b"llleee", entry.as_raw_bytes());
@@ -541,19 +597,19 @@ mod tests {
     fn multiple_root_tokens() {
         let mut parser = Parser::new();
         assert_eq!(
-            Error::TrailingData,
+            Error::TrailingData { pos: 3 },
             parser.parse::<Entry>(b"1:a1:b").unwrap_err()
         );
         assert_eq!(
-            Error::TrailingData,
+            Error::TrailingData { pos: 3 },
             parser.parse::<Entry>(b"i1e1:b").unwrap_err()
         );
         assert_eq!(
-            Error::TrailingData,
+            Error::TrailingData { pos: 5 },
             parser.parse::<Entry>(b"l1:aede").unwrap_err()
         );
         assert_eq!(
-            Error::TrailingData,
+            Error::TrailingData { pos: 2 },
             parser.parse::<Entry>(b"lel1:ae").unwrap_err()
         );
     }
@@ -592,7 +648,7 @@ mod tests {
         let s = b"i-0e";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(err, Error::Invalid { pos: 2 });
     }
 
     #[test]
@@ -600,7 +656,7 @@ mod tests {
         let s = b"i--1e";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(err, Error::Invalid { pos: 2 });
     }
 
     #[test]
@@ -608,7 +664,7 @@ mod tests {
         let s = b"i000e";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(err, Error::Invalid { pos: 2 });
     }
 
     #[test]
@@ -616,7 +672,7 @@ mod tests {
         let s = b"i01e";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(err, Error::Invalid { pos: 2 });
     }
 
     #[test]
@@ -624,7 +680,7 @@ mod tests {
         let s = b"i-e";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(err, Error::Invalid { pos: 2 });
     }
 
     #[test]
@@ -640,7 +696,7 @@ mod tests {
         let s = b"001:a";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(err, Error::Invalid { pos: 1 });
     }
 
     #[test]
@@ -648,7 +704,54 @@ mod tests {
         let s = b":";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(err, Error::Invalid { pos: 0 });
+    }
+
+    #[test]
+    fn reject_int_bad_digit_after_chunk() {
+        let s = b"i123456789x1e";
+        let mut parser = Parser::new();
+        let err = parser.parse::<Entry>(s).unwrap_err();
+        assert_eq!(err, Error::Invalid { pos: 10 });
+    }
+
+    #[test]
+    fn reject_unclosed_int() {
+        let s = b"i1234567890";
+        let mut parser = Parser::new();
+        let err = parser.parse::<Entry>(s).unwrap_err();
+        assert_eq!(err, Error::Eof { pos: 11 });
+    }
+
+    #[test]
+    fn reject_string_bad_length() {
+        let s = b"1x:a";
+        let mut parser = Parser::new();
+        let err = parser.parse::<Entry>(s).unwrap_err();
+        assert_eq!(err, Error::Invalid { pos: 1 });
+    }
+
+    #[test]
+    fn reject_string_length_without_colon() {
+        let s = b"12";
+        let mut parser = Parser::new();
+        let err = parser.parse::<Entry>(s).unwrap_err();
+        assert_eq!(err, Error::Eof { pos: 2 });
+    }
+
+    #[test]
+    fn count_digits() {
+        assert_eq!(0, digits_len(b""));
+        assert_eq!(3, digits_len(b"123e"));
+        assert_eq!(8, digits_len(b"12345678"));
+        assert_eq!(11, digits_len(b"12345678901:"));
+        assert_eq!(7, digits_len(b"1234567/9"));
+        assert_eq!(7, digits_len(b"1234567:9"));
+        assert_eq!(16, digits_len(b"1234567890123456\xfa"));
+        assert_eq!(
+            2,
+            digits_len(&[b'1', b'2', 0xfa, b'4', b'5', b'6', b'7', b'8'])
+        );
     }
 
     #[test]
@@ -656,7 +759,13 @@ mod tests {
         let s = b"d1:b0:1:a0:e";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(
+            err,
+            Error::UnsortedKey {
+                pos: 8,
+                key: "a".into()
+            }
+        );
     }
 
     #[test]
@@ -664,7 +773,13 @@ mod tests {
         let s = b"d1:ad1:b0:1:a0:ee";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(
+            err,
+            Error::UnsortedKey {
+                pos: 12,
+                key: "a".into()
+            }
+        );
     }
 
     #[test]
@@ -672,6 +787,12 @@ mod tests {
         let s = b"l1:ad1:b0:1:a0:ee";
         let mut parser = Parser::new();
         let err = parser.parse::<Entry>(s).unwrap_err();
-        assert_eq!(err, Error::Invalid);
+        assert_eq!(
+            err,
+            Error::UnsortedKey {
+                pos: 12,
+                key: "a".into()
+            }
+        );
     }
 }
@@ -0,0 +1,373 @@
+use std::collections::btree_map::{self, BTreeMap};
+use std::fmt;
+
+use crate::decode::{Decode, Entry};
+use crate::encode::{encode_bytes, encode_int, Encode};
+
+/// Byte strings longer than this which aren't ASCII text are printed as
+/// their length only. They're usually hashes, e.g. the `pieces` of a
+/// torrent, and would drown out the rest.
+const MAX_PRINTED_BINARY: usize = 32;
+
+/// An owned bencode value which can be edited and encoded again.
+///
+/// Unlike `Entry`, which borrows the parsed buffer, a `Value` owns its data,
+/// so it suits tools which rewrite parts of a structure, such as the
+/// trackers or the comment of a .torrent file. Dictionaries keep their keys
+/// sorted, so the encoding is always canonical. Keys are byte strings, since
+/// some aren't text, e.g. the `piece layers` of v2 torrents are keyed by
+/// hashes. Parse them with [`Parser::binary_keys`](crate::Parser::binary_keys)
+/// enabled.
+///
+/// Integers outside the `i64` range can't be represented, so parsing them
+/// as a `Value` fails.
+///
+/// # Examples
+///
+/// Basic usage:
+/// ```
+/// use ben::{Parser, Value};
+///
+/// let bytes = b"d8:announce3:foo4:infod4:name3:baree";
+/// let parser = &mut Parser::new();
+/// let mut value = parser.parse::<Value>(bytes).unwrap();
+///
+/// value.insert("comment", "ben");
+/// value.remove("announce");
+/// value.get_mut("info").unwrap().insert("private", 1);
+///
+/// assert_eq!(
+///     &b"d7:comment3:ben4:infod4:name3:bar7:privatei1eee"[..],
+///     &value.to_bytes()[..]
+/// );
+/// ```
+#[derive(Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    /// An empty dictionary.
+    pub fn dict() -> Self {
+        Value::Dict(BTreeMap::new())
+    }
+
+    /// An empty list.
+    pub fn list() -> Self {
+        Value::List(Vec::new())
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Returns the byte string if it's valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Option<&mut Vec<Value>> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict_mut(&mut self) -> Option<&mut BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Returns the value under the key if this is a dictionary.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&Value> {
+        self.as_dict()?.get(key.as_ref())
+    }
+
+    /// Returns the value under the key for editing if this is a dictionary.
+    pub fn get_mut(&mut self, key: impl AsRef<[u8]>) -> Option<&mut Value> {
+        self.as_dict_mut()?.get_mut(key.as_ref())
+    }
+
+    /// Sets the value for the given key, returning the value it replaced.
+    ///
+    /// Edits only apply if the value is a dictionary.
+    pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Value>) -> Option<Value> {
+        self.as_dict_mut()?.insert(key.into(), value.into())
+    }
+
+    /// Removes the given key, returning its value.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Option<Value> {
+        self.as_dict_mut()?.remove(key.as_ref())
+    }
+
+    /// Returns the entry of the key for in-place editing, e.g. to add to
+    /// a list under the key, creating it if it's missing. `None` unless
+    /// the value is a dictionary.
+    pub fn entry(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+    ) -> Option<btree_map::Entry<'_, Vec<u8>, Value>> {
+        Some(self.as_dict_mut()?.entry(key.into()))
+    }
+
+    /// Appends the value to the end of the list.
+    ///
+    /// Edits only apply if the value is a list.
+    pub fn push(&mut self, value: impl Into<Value>) {
+        if let Some(list) = self.as_list_mut() {
+            list.push(value.into());
+        }
+    }
+
+    /// Encode this value into a vector of bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.encode_to_vec()
+    }
+}
+
+impl Encode for Value {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Int(n) => encode_int(buf, *n),
+            Value::Bytes(b) => encode_bytes(buf, b),
+            Value::List(list) => list.encode(buf),
+            Value::Dict(dict) => {
+                buf.push(b'd');
+                for (k, v) in dict {
+                    encode_bytes(buf, k);
+                    v.encode(buf);
+                }
+                buf.push(b'e');
+            }
+        }
+    }
+}
+
+impl<'b, 'p> Decode<'b, 'p> for Value {
+    fn decode(entry: Entry<'b, 'p>) -> Option<Self> {
+        if let Some(dict) = entry.as_dict() {
+            let dict = dict
+                .raw_iter()
+                .map(|(k, v)| Some((k.to_vec(), Value::decode(v)?)))
+                .collect::<Option<_>>()?;
+            Some(Value::Dict(dict))
+        } else if let Some(list) = entry.as_list() {
+            let list = list.iter().map(Value::decode).collect::<Option<_>>()?;
+            Some(Value::List(list))
+        } else if let Some(b) = entry.as_bytes() {
+            Some(Value::Bytes(b.to_vec()))
+        } else {
+            entry.as_int().map(Value::Int)
+        }
+    }
+}
+
+/// Byte string printed as text if it's ASCII.
+struct PrintBytes<'a>(&'a [u8]);
+
+impl fmt::Debug for PrintBytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match std::str::from_utf8(self.0) {
+            Ok(s) if s.is_ascii() => write!(f, "{:?}", s),
+            _ if self.0.len() > MAX_PRINTED_BINARY => write!(f, "<{} bytes>", self.0.len()),
+            _ => write!(f, "'{}'", data_encoding::BASE32.encode(self.0)),
+        }
+    }
+}
+
+/// Prints the value on one line, or indented with `{:#?}`.
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bytes(b) => PrintBytes(b).fmt(f),
+            Value::List(list) => f.debug_list().entries(list).finish(),
+            Value::Dict(dict) => f
+                .debug_map()
+                .entries(dict.iter().map(|(k, v)| (PrintBytes(k), v)))
+                .finish(),
+        }
+    }
+}
+
+/// Prints the value indented, one dictionary entry or list item per line.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#?}", self)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Bytes(s.into_bytes())
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(b: &[u8]) -> Self {
+        Value::Bytes(b.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Bytes(b)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(list: Vec<Value>) -> Self {
+        Value::List(list)
+    }
+}
+
+impl From<BTreeMap<Vec<u8>, Value>> for Value {
+    fn from(dict: BTreeMap<Vec<u8>, Value>) -> Self {
+        Value::Dict(dict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parser;
+
+    fn parse(s: &[u8]) -> Value {
+        Parser::new().parse::<Value>(s).unwrap()
+    }
+
+    #[test]
+    fn decode_encode_round_trip() {
+        let s = b"d1:ai-3e1:bl1:x0:e1:cd1:di0eee";
+        let value = parse(s);
+        assert_eq!(value.get("a").and_then(Value::as_int), Some(-3));
+        assert_eq!(value.get("b").unwrap().as_list().unwrap().len(), 2);
+        assert_eq!(&s[..], &value.to_bytes()[..]);
+    }
+
+    #[test]
+    fn binary_keys() {
+        let mut s = b"d5:pieced32:".to_vec();
+        s.extend([0xff; 32]);
+        s.extend(b"1:aee");
+        let mut parser = Parser::new();
+        parser.binary_keys(true);
+        let mut value = parser.parse::<Value>(&s).unwrap();
+        assert_eq!(value.to_bytes(), s);
+
+        let layers = value.get_mut("piece").unwrap();
+        assert_eq!(layers.get([0xff; 32]), Some(&Value::from("a")));
+        layers.insert(vec![0xfe; 2], 1);
+        assert_eq!(
+            format!("{:?}", value),
+            r#"{"piece": {'737A====': 1, '777777777777777777777777777777777777777777777777777Q====': "a"}}"#
+        );
+    }
+
+    #[test]
+    fn int_out_of_range() {
+        let mut parser = Parser::new();
+        assert!(parser.parse::<Value>(b"i99999999999999999999e").is_err());
+    }
+
+    #[test]
+    fn edit_dict_and_list() {
+        let mut value = parse(b"d8:announce3:foo13:announce-listll3:fooeee");
+        assert_eq!(value.insert("announce", "bar"), Some(Value::from("foo")));
+        assert_eq!(value.remove("missing"), None);
+
+        let tiers = value.get_mut("announce-list").unwrap();
+        tiers.push(vec![Value::from("bar")]);
+
+        value
+            .entry("url-list")
+            .unwrap()
+            .or_insert_with(Value::list)
+            .push("http://x");
+
+        assert_eq!(
+            &b"d8:announce3:bar13:announce-listll3:fooel3:baree8:url-listl8:http://xee"[..],
+            &value.to_bytes()[..]
+        );
+    }
+
+    #[test]
+    fn edits_need_matching_type() {
+        let mut value = Value::from(5);
+        assert_eq!(value.insert("a", 1), None);
+        assert!(value.entry("a").is_none());
+        value.push(1);
+        assert_eq!(value, Value::Int(5));
+
+        let mut list = Value::list();
+        assert_eq!(list.insert("a", 1), None);
+        assert_eq!(list.to_bytes(), b"le");
+    }
+
+    #[test]
+    fn print() {
+        let mut value = Value::dict();
+        value.insert("name", "a \"b\"");
+        value.insert("hash", vec![0xff; 5]);
+        value.insert("pieces", vec![0xff; 40]);
+        value.insert("files", vec![Value::from(1), Value::from(2)]);
+
+        assert_eq!(
+            format!("{:?}", value),
+            r#"{"files": [1, 2], "hash": '77777777', "name": "a \"b\"", "pieces": <40 bytes>}"#
+        );
+        assert_eq!(
+            value.to_string(),
+            r#"{
+    "files": [
+        1,
+        2,
+    ],
+    "hash": '77777777',
+    "name": "a \"b\"",
+    "pieces": <40 bytes>,
+}"#
+        );
+    }
+}
@@ -0,0 +1,28 @@
+//! Smoke tests for wasm32-unknown-unknown, run with `wasm-pack test --node`.
+
+#![cfg(target_arch = "wasm32")]
+
+use ben::decode::Dict;
+use ben::{DictEncoder, Parser};
+use wasm_bindgen_test::wasm_bindgen_test;
+
+#[wasm_bindgen_test]
+fn encode_and_parse() {
+    let mut buf = Vec::new();
+    let mut dict = DictEncoder::new(&mut buf);
+    dict.insert("a", 1i64);
+    dict.insert("b", "hello");
+    dict.finish();
+    assert_eq!(buf, b"d1:ai1e1:b5:helloe");
+
+    let mut parser = Parser::new();
+    let dict = parser.parse::<Dict>(&buf).unwrap();
+    assert_eq!(dict.get_int::<i64>("a"), Some(1));
+    assert_eq!(dict.get_str("b"), Some("hello"));
+}
+
+#[wasm_bindgen_test]
+fn parse_error() {
+    let mut parser = Parser::new();
+    assert!(parser.parse::<Dict>(b"d1:a").is_err());
+}
@@ -0,0 +1,66 @@
+//! Download throughput from a large in-memory swarm, on a current-thread
+//! and a multi-threaded runtime. Needs the `testing` feature:
+//! `cargo bench --features testing --bench worker`.
+
+use btrs::testing::{Role, Swarm};
+use btrs::{Session, TorrentWorker};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use tokio::runtime::{Builder, Runtime};
+
+/// 64 MiB torrent of 256 KiB pieces.
+const LEN: usize = 64 << 20;
+const PIECE_LEN: usize = 256 << 10;
+
+/// Seeds in the swarm, more than the worker connects to at once.
+const SEEDS: usize = 100;
+
+fn swarm() -> Swarm {
+    let swarm = Swarm::new(LEN, PIECE_LEN);
+    for _ in 0..SEEDS {
+        swarm.add_peer(Role::Seed);
+    }
+    swarm
+}
+
+/// Worker hashing the pieces on the hashing threads, unlike the one of
+/// `Swarm::worker`.
+fn worker(swarm: &Swarm) -> TorrentWorker {
+    let session = Session::new();
+    let peer_id = session.identity().generate_peer_id();
+    let mut worker = TorrentWorker::without_dht(session, swarm.torrent(), peer_id);
+    worker.set_dialer(swarm.dialer());
+    worker.set_piece_reader(swarm.piece_reader());
+    worker
+}
+
+fn runtimes() -> [(&'static str, Runtime); 2] {
+    let current = Builder::new_current_thread().enable_all().build().unwrap();
+    let multi = Builder::new_multi_thread().enable_all().build().unwrap();
+    [("current_thread", current), ("multi_thread", multi)]
+}
+
+fn download(c: &mut Criterion) {
+    let swarm = swarm();
+    let mut group = c.benchmark_group("download");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.sample_size(10);
+    for (name, rt) in runtimes() {
+        // Hashing on the worker's task, as before the hashing threads
+        group.bench_function(format!("{}/inline", name), |b| {
+            b.iter(|| {
+                let mut worker = swarm.worker();
+                black_box(rt.block_on(swarm.download(&mut worker)))
+            })
+        });
+        group.bench_function(format!("{}/threads", name), |b| {
+            b.iter(|| {
+                let mut worker = worker(&swarm);
+                black_box(rt.block_on(swarm.download(&mut worker)))
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, download);
+criterion_main!(benches);
@@ -50,7 +50,7 @@ impl Bitfield {
     }
 
     pub fn len(&self) -> usize {
-        self.bits as usize
+        self.bits
     }
 
     pub fn is_empty(&self) -> bool {
@@ -58,7 +58,7 @@ impl Bitfield {
     }
 
     pub fn len_bytes(&self) -> usize {
-        (self.bits + 7) / 8
+        self.bits.div_ceil(8)
     }
 
     pub fn get_bit(&self, index: usize) -> bool {
@@ -124,9 +124,21 @@ impl Bitfield {
         }
     }
 
+    /// Set the bits which are set in `other`, growing to its length if it
+    /// is longer.
+    pub fn union_with(&mut self, other: &Bitfield) {
+        if other.bits > self.bits {
+            self.resize(other.bits);
+        }
+
+        for (a, b) in self.buf.iter_mut().zip(&other.buf) {
+            *a |= b;
+        }
+    }
+
     pub fn resize(&mut self, bits: usize) {
-        let words = (31 + self.bits) / 32;
-        let new_words = (31 + bits) / 32;
+        let words = self.bits.div_ceil(32);
+        let new_words = bits.div_ceil(32);
 
         if words != new_words {
             self.buf.resize(new_words, 0);
@@ -207,18 +219,18 @@ mod tests {
         let mut b = Bitfield::with_size(16);
         b.set_bit(4);
         b.set_bit(14);
-        assert_eq!(b.get_bit(2), false);
-        assert_eq!(b.get_bit(4), true);
-        assert_eq!(b.get_bit(17), false);
+        assert!(!b.get_bit(2));
+        assert!(b.get_bit(4));
+        assert!(!b.get_bit(17));
     }
 
     #[test]
     fn clear_bit() {
         let mut b = Bitfield::with_size(16);
         b.set_bit(4);
-        assert_eq!(b.get_bit(4), true);
+        assert!(b.get_bit(4));
         b.clear_bit(4);
-        assert_eq!(b.get_bit(4), false);
+        assert!(!b.get_bit(4));
     }
 
     #[test]
@@ -258,23 +270,43 @@ mod tests {
     fn resize_larger() {
         let mut b = Bitfield::with_size(16);
         b.set_bit(5);
-        assert_eq!(b.get_bit(5), true);
+        assert!(b.get_bit(5));
 
         b.resize(128);
-        assert_eq!(b.get_bit(5), true);
+        assert!(b.get_bit(5));
+    }
+
+    #[test]
+    fn union_with() {
+        let mut a = Bitfield::with_size(10);
+        a.set_bit(1);
+        let mut b = Bitfield::with_size(40);
+        b.set_bit(2);
+        b.set_bit(39);
+
+        a.union_with(&b);
+        assert_eq!(a.len(), 40);
+        assert_eq!(a.count(), 3);
+        assert!(a.get_bit(1) && a.get_bit(2) && a.get_bit(39));
+
+        let mut c = Bitfield::with_size(3);
+        c.set_bit(0);
+        c.union_with(&Bitfield::new());
+        assert_eq!(c.len(), 3);
+        assert_eq!(c.count(), 1);
     }
 
     #[test]
     fn resize_smaller() {
         let mut b = Bitfield::with_size(128);
         b.set_bit(5);
-        assert_eq!(b.get_bit(5), true);
+        assert!(b.get_bit(5));
 
         b.resize(8);
-        assert_eq!(b.get_bit(5), true);
+        assert!(b.get_bit(5));
 
         b.resize(4);
-        assert_eq!(b.get_bit(5), false);
+        assert!(!b.get_bit(5));
     }
 
     #[test]
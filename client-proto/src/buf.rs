@@ -1,6 +1,98 @@
 use crate::avg::MovingAverage;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
 
-const MAX_BUF_SIZE: usize = 1024 * 1024;
+/// Size a receive buffer grows to ahead of the data unless changed.
+pub const DEFAULT_MAX_BUF_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+struct Budget {
+    used: AtomicUsize,
+    limit: AtomicUsize,
+    max_buf_size: AtomicUsize,
+}
+
+/// Memory of the receive buffers sharing it, e.g. those of all the
+/// connections of a session.
+///
+/// Buffers grow ahead of the data, up to `max_buf_size` each, only while
+/// the total stays within the limit. Past it they grow no more than the
+/// packets being read need and give the spare room back once drained, so
+/// the total is bounded by the limit plus a packet per connection. Cloning
+/// returns a handle to the same budget.
+#[derive(Debug, Clone)]
+pub struct BufBudget {
+    inner: Arc<Budget>,
+}
+
+impl Default for BufBudget {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl BufBudget {
+    /// Budget of `limit` bytes in total, or unlimited if `None`.
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            inner: Arc::new(Budget {
+                used: AtomicUsize::new(0),
+                limit: AtomicUsize::new(limit.unwrap_or(usize::MAX)),
+                max_buf_size: AtomicUsize::new(DEFAULT_MAX_BUF_SIZE),
+            }),
+        }
+    }
+
+    /// Bytes held by the buffers.
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Relaxed)
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        match self.inner.limit.load(Relaxed) {
+            usize::MAX => None,
+            limit => Some(limit),
+        }
+    }
+
+    /// Change the total limit. `None` removes it.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.inner.limit.store(limit.unwrap_or(usize::MAX), Relaxed);
+    }
+
+    pub fn max_buf_size(&self) -> usize {
+        self.inner.max_buf_size.load(Relaxed)
+    }
+
+    /// Set the size each buffer may grow to ahead of the data. Packets
+    /// longer than that still fit, the buffer growing just for them.
+    pub fn set_max_buf_size(&self, size: usize) {
+        self.inner.max_buf_size.store(size, Relaxed);
+    }
+
+    fn is_over_limit(&self) -> bool {
+        self.used() > self.inner.limit.load(Relaxed)
+    }
+
+    /// Take `n` more bytes if they fit within the limit.
+    fn try_add(&self, n: usize) -> bool {
+        let limit = self.inner.limit.load(Relaxed);
+        self.inner
+            .used
+            .fetch_update(Relaxed, Relaxed, |used| {
+                used.checked_add(n).filter(|&total| total <= limit)
+            })
+            .is_ok()
+    }
+
+    fn add(&self, n: usize) {
+        self.inner.used.fetch_add(n, Relaxed);
+    }
+
+    fn sub(&self, n: usize) {
+        self.inner.used.fetch_sub(n, Relaxed);
+    }
+}
 
 pub struct RecvBuf {
     buf: Vec<u8>,
@@ -8,6 +100,23 @@ pub struct RecvBuf {
     read_pos: usize,
     write_rate: MovingAverage<5>,
     read_rate: MovingAverage<5>,
+
+    /// Where the size of `buf` is accounted
+    budget: Option<BufBudget>,
+}
+
+impl Drop for RecvBuf {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.sub(self.buf.len());
+        }
+    }
+}
+
+impl Default for RecvBuf {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RecvBuf {
@@ -18,14 +127,51 @@ impl RecvBuf {
             read_pos: 0,
             write_rate: MovingAverage::new(),
             read_rate: MovingAverage::new(),
+            budget: None,
         }
     }
 
     pub fn with_capacity(cap: usize) -> Self {
-        Self {
-            buf: vec![0; cap],
-            ..Self::new()
+        let mut b = Self::new();
+        b.buf = vec![0; cap];
+        b
+    }
+
+    /// Account the buffer in `budget` from now on, and follow its limits.
+    pub fn set_budget(&mut self, budget: BufBudget) {
+        if let Some(old) = &self.budget {
+            old.sub(self.buf.len());
         }
+        budget.add(self.buf.len());
+        self.budget = Some(budget);
+    }
+
+    /// Resize the buffer to `len`, accounting the change in the budget.
+    /// Growing past the limit of the budget fails unless `force`d.
+    fn resize(&mut self, len: usize, force: bool) -> bool {
+        let old = self.buf.len();
+        if let Some(budget) = &self.budget {
+            if len > old {
+                if force {
+                    budget.add(len - old);
+                } else if !budget.try_add(len - old) {
+                    return false;
+                }
+            } else {
+                budget.sub(old - len);
+            }
+        }
+        self.buf.resize(len, 0);
+        if len < old {
+            self.buf.shrink_to_fit();
+        }
+        true
+    }
+
+    fn max_size(&self) -> usize {
+        self.budget
+            .as_ref()
+            .map_or(DEFAULT_MAX_BUF_SIZE, |b| b.max_buf_size())
     }
 
     /// Reserve at least `len` unread bytes in the buffer and return a mutable reference
@@ -41,8 +187,11 @@ impl RecvBuf {
         self.discard_read(len);
 
         if self.read_pos + len > self.buf.len() {
-            let new_len = self.read_pos + len;
-            self.buf.resize(new_len, 0);
+            // Needed to read the packet, whatever the budget
+            self.resize(self.read_pos + len, true);
+        } else if unread == 0 && self.budget.as_ref().is_some_and(|b| b.is_over_limit()) {
+            // Drained, so give back the room grown ahead of the data
+            self.resize(len, false);
         }
 
         &mut self.buf[self.write_pos..]
@@ -88,19 +237,27 @@ impl RecvBuf {
         // If writes are filling atleast 90% of current buffer length at once,
         // increase the buffer length by 50%
         if write_rate >= self.buf.len() * 90 / 100 {
-            let mut new_len = MAX_BUF_SIZE.min(self.buf.len() * 3 / 2);
+            let max_size = self.max_size();
+            let mut new_len = max_size.min(self.buf.len() * 3 / 2);
 
             let read = self.read_rate.mean() as usize;
             if read > 0 {
                 // Make the new length multiple of read rate so that there is
                 // less copying when discarding read bytes
-                new_len = read * ((new_len + read - 1) / read);
+                new_len = read * new_len.div_ceil(read);
             }
 
-            self.buf.resize(new_len, 0);
+            if new_len > self.buf.len() {
+                self.resize(new_len, false);
+            }
         }
     }
 
+    /// The bytes received but not read yet.
+    pub fn unread(&self) -> &[u8] {
+        &self.buf[self.read_pos..self.write_pos]
+    }
+
     /// Read one bytes from current read cursor position without advancing.
     pub fn peek(&self) -> u8 {
         assert!(self.read_pos < self.write_pos);
@@ -127,7 +284,7 @@ impl RecvBuf {
 
 #[cfg(test)]
 mod tests {
-    use super::RecvBuf;
+    use super::{BufBudget, RecvBuf};
 
     #[test]
     fn read_consumes_all_written() {
@@ -136,7 +293,9 @@ mod tests {
         w[..8].fill(1);
         b.advance_write(8);
 
+        assert_eq!(b.unread(), &[1; 8]);
         assert_eq!(b.read(8), &[1; 8]);
+        assert!(b.unread().is_empty());
         assert_eq!(b.read_pos, 8);
         assert_eq!(b.write_pos, 8);
     }
@@ -272,4 +431,71 @@ mod tests {
         b.write_reserve(2);
         b.advance_write(3);
     }
+
+    fn fill(b: &mut RecvBuf, n: usize) {
+        let w = b.write_reserve(n);
+        let len = w.len();
+        b.advance_write(len);
+        b.read(len);
+    }
+
+    #[test]
+    fn growth_is_capped() {
+        let budget = BufBudget::new(None);
+        budget.set_max_buf_size(100);
+        let mut b = RecvBuf::new();
+        b.set_budget(budget.clone());
+        for _ in 0..20 {
+            fill(&mut b, 50);
+        }
+        assert!(b.buf.len() <= 150, "{}", b.buf.len());
+        assert_eq!(budget.used(), b.buf.len());
+
+        // Larger packets still fit
+        fill(&mut b, 1000);
+        assert_eq!(b.buf.len(), 1000);
+        assert_eq!(budget.used(), 1000);
+
+        drop(b);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn budget_bounds_total() {
+        let budget = BufBudget::new(Some(300));
+        let mut a = RecvBuf::with_capacity(12);
+        let mut b = RecvBuf::with_capacity(12);
+        a.set_budget(budget.clone());
+        b.set_budget(budget.clone());
+        assert_eq!(budget.used(), 24);
+
+        for _ in 0..20 {
+            fill(&mut a, 100);
+            fill(&mut b, 100);
+        }
+        assert!(budget.used() <= 300, "{}", budget.used());
+        assert_eq!(budget.used(), a.buf.len() + b.buf.len());
+
+        // Over the limit for a big packet, then back within it once it's
+        // read
+        let w = a.write_reserve(400);
+        let len = w.len();
+        a.advance_write(len);
+        assert!(budget.used() > 300);
+        a.read(400);
+        a.write_reserve(10);
+        assert_eq!(a.buf.len(), 10);
+        assert!(budget.used() <= 300, "{}", budget.used());
+    }
+
+    #[test]
+    fn budget_can_be_replaced() {
+        let first = BufBudget::new(None);
+        let second = BufBudget::new(None);
+        let mut b = RecvBuf::with_capacity(12);
+        b.set_budget(first.clone());
+        b.set_budget(second.clone());
+        assert_eq!(first.used(), 0);
+        assert_eq!(second.used(), 12);
+    }
 }
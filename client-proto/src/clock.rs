@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Time source of a connection.
+///
+/// Connections read the system clock unless given a virtual one, which only
+/// moves when advanced. The simulation harness uses that to make timing
+/// dependent behavior like the RTT estimate deterministic.
+#[derive(Debug, Clone, Default)]
+pub enum Clock {
+    #[default]
+    System,
+    Virtual(VirtualClock),
+}
+
+impl Clock {
+    pub fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            Clock::Virtual(clock) => clock.now(),
+        }
+    }
+}
+
+/// A clock advanced by hand. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    start: Instant,
+
+    /// Nanoseconds since `start`
+    elapsed: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    /// Time passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed.load(Relaxed))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.elapsed.fetch_add(by.as_nanos() as u64, Relaxed);
+    }
+
+    /// Move the clock forward to `elapsed` since its creation. Never moves
+    /// it backwards.
+    pub fn advance_to(&self, elapsed: Duration) {
+        self.elapsed.fetch_max(elapsed.as_nanos() as u64, Relaxed);
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<VirtualClock> for Clock {
+    fn from(clock: VirtualClock) -> Self {
+        Clock::Virtual(clock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_clock() {
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        let shared = Clock::from(clock.clone());
+
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(shared.now() - start, Duration::from_millis(10));
+
+        clock.advance_to(Duration::from_millis(5));
+        assert_eq!(clock.elapsed(), Duration::from_millis(10));
+        clock.advance_to(Duration::from_millis(25));
+        assert_eq!(shared.now() - start, Duration::from_millis(25));
+    }
+}
@@ -1,16 +1,105 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::ops::Deref;
+use std::time::{Duration, Instant};
 
 use ben::{Encode, Parser};
-use bytes::{Buf, BufMut};
 
 use crate::bitfield::Bitfield;
-use crate::event::Event;
-use crate::ext::{ExtendedMessage, MetadataMsg};
-use crate::handshake::Handshake;
+use crate::clock::Clock;
+use crate::event::{Event, PeerEvent};
+use crate::ext::{ExtHandshake, ExtendedMessage, MetadataMsg};
+use crate::frame::Frame;
+use crate::handshake::{Extension, Handshake, PROTOCOL};
+use crate::metainfo::TorrentLimits;
+use crate::rtt::RttEstimator;
 use crate::state::Error;
-use crate::{msg::*, InfoHash, PeerId};
+use crate::stats::{Activity, WireStats};
+use crate::{msg::*, Extensions, InfoHash, PeerId};
+
+/// Max number of block requests from the peer we queue up. This is the same
+/// `reqq` we advertise in the extended handshake.
+const MAX_INBOUND_REQUESTS: usize = 500;
+
+/// Max number of our requests whose send time is kept for RTT samples.
+const MAX_TIMED_REQUESTS: usize = 1000;
+
+/// Max number of pieces the peer's bitfield is grown to by HAVEs which
+/// arrive before the bitfield, or instead of it.
+const MAX_PIECES: usize = 1 << 21;
+
+/// Default number of messages with unknown ids we tolerate from a peer.
+const DEFAULT_MAX_UNKNOWN_MSGS: u32 = 10;
+
+/// Max number of peer events kept until polled. The oldest are dropped
+/// first, so the last event still tells the current state.
+const MAX_PEER_EVENTS: usize = 32;
+
+/// Id we give ut_metadata in our extended handshake. The extensions
+/// registered by the embedder get the ids after it.
+const UT_METADATA_ID: u8 = 1;
+
+/// Max number of extended messages of the embedder's extensions kept until
+/// polled. The oldest are dropped first. The metadata and the handshake are
+/// never dropped, but only the latest of each is kept.
+const MAX_EVENTS: usize = 64;
+
+/// Extensions we don't implement whose messages are common enough that
+/// they're dropped without logging.
+pub const DEFAULT_IGNORED_EXTENSIONS: &[&str] = &["ut_comment", "ut_squelch", "lt_tex"];
+
+/// Limits on the extended messages accepted from a peer.
+///
+/// Peers exceeding them are considered to be flooding us, e.g. with
+/// ut_metadata data to run us out of memory. Each extension, including
+/// repeated extended handshakes, has its own budget once the peer's
+/// extended handshake arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtLimits {
+    /// Messages accepted before the peer's extended handshake.
+    pub max_msgs_before_handshake: u32,
+
+    /// Total bytes of the messages accepted before the peer's extended
+    /// handshake.
+    pub max_bytes_before_handshake: usize,
+
+    /// Period over which the rate of each extension is measured.
+    pub window: Duration,
+
+    /// Messages of one extension accepted per `window`.
+    pub max_msgs_per_window: u32,
+
+    /// Bytes of one extension accepted per `window`.
+    pub max_bytes_per_window: usize,
+
+    /// Bytes of one extension accepted over the whole connection.
+    pub max_total_bytes: u64,
+}
+
+impl Default for ExtLimits {
+    fn default() -> Self {
+        Self {
+            max_msgs_before_handshake: 8,
+            max_bytes_before_handshake: 64 * 1024,
+            window: Duration::from_secs(10),
+            max_msgs_per_window: 500,
+            max_bytes_per_window: 4 * 1024 * 1024,
+            max_total_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Extended messages of one extension received from the peer.
+#[derive(Debug, Default)]
+struct ExtUsage {
+    window_start: Option<Instant>,
+    window_msgs: u32,
+    window_bytes: usize,
+    total_msgs: u32,
+    total_bytes: u64,
+}
 
 pub struct Connection {
     send_buf: Vec<u8>,
@@ -18,10 +107,64 @@ pub struct Connection {
     bitfield: Bitfield,
     choked: bool,
     interested: bool,
+
+    /// We told the peer we're interested
+    am_interested: bool,
+
+    /// Since when we're interested and the peer has unchoked us
+    wanting_since: Option<Instant>,
     parser: Parser,
     events: VecDeque<Event>,
+    peer_events: VecDeque<PeerEvent>,
     ut_metadata: Option<UtMetadata>,
+
+    /// Metadata the peer offers past this length isn't requested
+    max_metadata_len: usize,
     ext_handshaked: bool,
+    requests: VecDeque<BlockRequest>,
+    unknown_msgs: u32,
+    max_unknown_msgs: u32,
+    ext_limits: ExtLimits,
+    early_ext: ExtUsage,
+    ext_usage: HashMap<u8, ExtUsage>,
+    ext_flood: bool,
+
+    /// Names of the peer's extensions by the ids it gave them
+    peer_ext_names: HashMap<u8, String>,
+
+    /// Extensions registered by the embedder, advertised with the ids from
+    /// `UT_METADATA_ID + 1` on
+    extensions: Vec<String>,
+    ignored_exts: HashSet<String>,
+
+    /// Ids we didn't advertise whose messages were logged already
+    logged_exts: HashSet<u8>,
+
+    /// Addresses the peer sent in its extended handshake
+    peer_ipv4: Option<Ipv4Addr>,
+    peer_ipv6: Option<Ipv6Addr>,
+    peer_listen_port: Option<u16>,
+    peer_reqq: Option<u32>,
+    extended: bool,
+    peer_extensions: Extensions,
+    peer_handshaked: bool,
+    dht_port: Option<u16>,
+    peer_dht_port: Option<u16>,
+    download_only: bool,
+    auto_choke: bool,
+    client_version: Option<String>,
+    own_peer_id: Option<PeerId>,
+    sent_requests: VecDeque<(BlockRequest, Instant)>,
+    rtt: RttEstimator,
+    clock: Clock,
+    wire_stats: WireStats,
+    activity: Activity,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Connection {
@@ -32,21 +175,177 @@ impl Connection {
             bitfield: Bitfield::new(),
             choked: true,
             interested: false,
+            am_interested: false,
+            wanting_since: None,
             parser: Parser::new(),
             events: VecDeque::new(),
+            peer_events: VecDeque::new(),
             ut_metadata: None,
+            max_metadata_len: TorrentLimits::default().max_metadata_len,
             ext_handshaked: false,
+            requests: VecDeque::new(),
+            unknown_msgs: 0,
+            max_unknown_msgs: DEFAULT_MAX_UNKNOWN_MSGS,
+            ext_limits: ExtLimits::default(),
+            early_ext: ExtUsage::default(),
+            ext_usage: HashMap::new(),
+            ext_flood: false,
+            peer_ext_names: HashMap::new(),
+            extensions: vec![],
+            ignored_exts: DEFAULT_IGNORED_EXTENSIONS
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect(),
+            logged_exts: HashSet::new(),
+            peer_ipv4: None,
+            peer_ipv6: None,
+            peer_listen_port: None,
+            peer_reqq: None,
+            extended: true,
+            peer_extensions: Extensions::default(),
+            peer_handshaked: false,
+            dht_port: None,
+            peer_dht_port: None,
+            download_only: false,
+            auto_choke: true,
+            client_version: None,
+            own_peer_id: None,
+            sent_requests: VecDeque::new(),
+            rtt: RttEstimator::new(),
+            clock: Clock::System,
+            wire_stats: WireStats::default(),
+            activity: Activity::default(),
         }
     }
 
+    /// Read the time from `clock` instead of the system clock.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
     pub fn poll_event(&mut self) -> Option<Event> {
         self.events.pop_front()
     }
 
+    /// Take the oldest change of the peer's choke or interest state.
+    pub fn poll_peer_event(&mut self) -> Option<PeerEvent> {
+        self.peer_events.pop_front()
+    }
+
+    fn add_event(&mut self, event: Event) {
+        let is_extended = |e: &Event| matches!(e, Event::Extended { .. });
+        if is_extended(&event) {
+            if self.events.iter().filter(|e| is_extended(e)).count() >= MAX_EVENTS {
+                let oldest = self.events.iter().position(is_extended);
+                self.events.remove(oldest.unwrap());
+            }
+        } else {
+            let kind = mem::discriminant(&event);
+            self.events.retain(|e| mem::discriminant(e) != kind);
+        }
+        self.events.push_back(event);
+    }
+
+    fn add_peer_event(&mut self, event: PeerEvent) {
+        if self.peer_events.len() >= MAX_PEER_EVENTS {
+            self.peer_events.pop_front();
+        }
+        self.peer_events.push_back(event);
+    }
+
+    /// Advertise the extension protocol in the handshake. Enabled by default.
+    ///
+    /// Some old clients drop the connection if they see unknown reserved bits.
+    pub fn set_extended(&mut self, enable: bool) {
+        self.extended = enable;
+    }
+
+    /// Advertise our DHT node listening on `port` in the handshake. Peers
+    /// which run a DHT node too are sent the port right after the
+    /// handshakes. `None`, the default, advertises no DHT.
+    pub fn set_dht_port(&mut self, port: Option<u16>) {
+        self.dht_port = port;
+    }
+
+    /// Never unchoke the peer, even when it is interested. Disabled by
+    /// default.
+    pub fn set_download_only(&mut self, enable: bool) {
+        self.download_only = enable;
+    }
+
+    /// Unchoke the peer as soon as it is interested and choke it once it
+    /// isn't anymore. Enabled by default; turn it off to leave the choking
+    /// to the caller, e.g. a choker sharing the upload slots among the
+    /// peers.
+    pub fn set_auto_choke(&mut self, enable: bool) {
+        self.auto_choke = enable;
+    }
+
+    /// Advertise the extension `name` in the extended handshake, for the
+    /// embedder to implement on top of the connection. Returns the id the
+    /// peer sends its messages with, which they arrive as
+    /// [`Event::Extended`] with.
+    pub fn register_extension(&mut self, name: impl Into<String>) -> u8 {
+        let name = name.into();
+        let i = match self.extensions.iter().position(|n| *n == name) {
+            Some(i) => i,
+            None => {
+                assert!(name != "ut_metadata", "ut_metadata is built in");
+                self.extensions.push(name);
+                self.extensions.len() - 1
+            }
+        };
+        u8::try_from(i + 1)
+            .ok()
+            .and_then(|i| i.checked_add(UT_METADATA_ID))
+            .expect("Too many extensions")
+    }
+
+    /// Id the peer gave the extension `name` in its extended handshake, to
+    /// send the messages of the extension with.
+    pub fn peer_extension_id(&self, name: &str) -> Option<u8> {
+        self.peer_ext_names
+            .iter()
+            .find(|(_, n)| *n == name)
+            .map(|(&id, _)| id)
+    }
+
+    /// Client name and version sent as "v" in the extended handshake. Not
+    /// sent by default.
+    pub fn set_client_version(&mut self, version: impl Into<String>) {
+        self.client_version = Some(version.into());
+    }
+
     pub fn send_handshake(&mut self, info_hash: &InfoHash, peer_id: &PeerId) {
         let mut h = Handshake::new(*info_hash, *peer_id);
-        h.set_extended(true);
+        h.set_extended(self.extended);
+        h.set_dht(self.dht_port.is_some());
         self.send_buf.extend_from_slice(h.as_bytes());
+        self.own_peer_id = Some(*peer_id);
+        self.send_dht_port();
+    }
+
+    /// Send our DHT port once both handshakes are through, if both of us
+    /// run a DHT node.
+    fn send_dht_port(&mut self) {
+        if self.own_peer_id.is_none() || !self.peer_handshaked {
+            return;
+        }
+
+        if let Some(port) = self.dht_port {
+            if self.peer_supports(Extension::Dht) {
+                trace!("Send port {}", port);
+                self.send_frame(Frame::Port(port));
+            }
+        }
+    }
+
+    /// Check the beginning of the peer's handshake, so that peers which
+    /// don't speak BitTorrent can be dropped without waiting for the rest.
+    pub fn check_protocol(&self, prefix: &[u8]) -> anyhow::Result<()> {
+        let len = prefix.len().min(PROTOCOL.len());
+        ensure!(prefix[..len] == PROTOCOL[..len], Error::UnsupportedProtocol);
+        Ok(())
     }
 
     pub fn recv_handshake(
@@ -55,92 +354,108 @@ impl Connection {
         data: [u8; 68],
     ) -> anyhow::Result<PeerId> {
         let h: Handshake = unsafe { std::mem::transmute(data) };
+        self.wire_stats.bytes_down += data.len() as u64;
         ensure!(h.is_supported(), Error::UnsupportedProtocol);
-        ensure!(h.info_hash == *info_hash, Error::UnsupportedProtocol);
+        ensure!(h.info_hash == *info_hash, Error::InfoHashMismatch);
+
+        // Our own handshake echoed back
+        ensure!(self.own_peer_id != Some(h.peer_id), Error::SelfConnection);
+        self.peer_extensions = *h.extensions();
+        self.peer_handshaked = true;
+        self.send_dht_port();
         Ok(h.peer_id)
     }
 
     pub fn send_keepalive(&mut self) {
         trace!("Send keepalive");
-        self.send_buf.put_u32(0);
+        self.send_frame(Frame::KeepAlive);
     }
 
     pub fn send_choke(&mut self) {
         trace!("Send choke");
-        self.send_buf.put_u32(1);
-        self.send_buf.put_u8(CHOKE);
+        self.send_frame(Frame::Choke);
+
+        // Choking a peer discards all of its pending requests
+        self.requests.clear();
     }
 
     pub fn send_unchoke(&mut self) {
         trace!("Send unchoke");
-        self.send_buf.put_u32(1);
-        self.send_buf.put_u8(UNCHOKE);
+        self.send_frame(Frame::Unchoke);
     }
 
     pub fn send_interested(&mut self) {
         trace!("Send interested");
-        self.send_buf.put_u32(1);
-        self.send_buf.put_u8(INTERESTED);
+        self.send_frame(Frame::Interested);
+        self.am_interested = true;
+        self.update_wanting();
     }
 
     pub fn send_not_interested(&mut self) {
         trace!("Send not interested");
-        self.send_buf.put_u32(1);
-        self.send_buf.put_u8(NOT_INTERESTED);
+        self.send_frame(Frame::NotInterested);
+        self.am_interested = false;
+        self.update_wanting();
     }
 
     pub fn send_have(&mut self, index: u32) {
         trace!("Send have {}", index);
-        self.send_buf.put_u32(5);
-        self.send_buf.put_u8(HAVE);
-        self.send_buf.put_u32(index);
+        self.send_frame(Frame::Have(index));
     }
 
     pub fn send_bitfield(&mut self) {
         trace!("Send bitfield");
-        let bytes = self.bitfield.as_bytes();
-        self.send_buf.put_u32(bytes.len() as u32 + 1);
-        self.send_buf.put_u8(BITFIELD);
-        self.send_buf.extend_from_slice(bytes);
+        Frame::Bitfield(self.bitfield.as_bytes()).encode(&mut self.send_buf);
+    }
+
+    /// Tell the peer the pieces we have, right after the handshake.
+    pub fn send_have_bitfield(&mut self, have: &Bitfield) {
+        trace!("Send bitfield of {} pieces", have.count());
+        self.send_frame(Frame::Bitfield(have.as_bytes()));
     }
 
     pub fn send_request(&mut self, index: u32, begin: u32, len: u32) {
         trace!("Send request {}, {}, {}", index, begin, len);
-        self.send_buf.put_u32(13);
-        self.send_buf.put_u8(REQUEST);
-        self.send_buf.put_u32(index);
-        self.send_buf.put_u32(begin);
-        self.send_buf.put_u32(len);
+        let req = BlockRequest { index, begin, len };
+        self.send_frame(Frame::Request(req));
+
+        if self.sent_requests.len() >= MAX_TIMED_REQUESTS {
+            self.sent_requests.pop_front();
+        }
+        self.sent_requests.push_back((req, self.clock.now()));
     }
 
     pub fn send_piece(&mut self, index: u32, begin: u32, data: &[u8]) {
         trace!("Send piece {}, {}, {}", index, begin, data.len());
-        self.send_buf.put_u32(9 + data.len() as u32);
-        self.send_buf.put_u8(PIECE);
-        self.send_buf.put_u32(index);
-        self.send_buf.put_u32(begin);
-        self.send_buf.extend_from_slice(data);
+        self.send_frame(Frame::Piece(PieceBlock { index, begin, data }));
     }
 
     pub fn send_cancel(&mut self, index: u32, begin: u32, len: u32) {
         trace!("Send cancel {}, {}, {}", index, begin, len);
-        self.send_buf.put_u32(13);
-        self.send_buf.put_u8(CANCEL);
-        self.send_buf.put_u32(index);
-        self.send_buf.put_u32(begin);
-        self.send_buf.put_u32(len);
+        let req = BlockRequest { index, begin, len };
+        self.send_frame(Frame::Cancel(req));
+        self.sent_requests.retain(|(r, _)| *r != req);
     }
 
-    pub fn send_ext<E: Encode + Debug>(&mut self, id: u8, payload: E) {
-        trace!("Send ext {}, {:?}", id, payload);
-        self.encode_buf.clear();
-        payload.encode(&mut self.encode_buf);
+    /// Ask for hashes of a file's merkle tree (BEP 52).
+    pub fn send_hash_request(&mut self, req: HashRequest) {
+        trace!("Send hash request {:?}", req);
+        self.send_frame(Frame::HashRequest(req));
+    }
 
-        let len = 2 + self.encode_buf.len() as u32;
-        self.send_buf.put_u32(len);
-        self.send_buf.put_u8(EXTENDED);
-        self.send_buf.put_u8(id);
-        self.send_buf.extend_from_slice(&self.encode_buf);
+    /// Answer a hash request with the hashes followed by their proof.
+    pub fn send_hashes(&mut self, req: HashRequest, hashes: &[u8]) {
+        trace!("Send hashes {:?}, len {}", req, hashes.len());
+        self.send_frame(Frame::Hashes { req, hashes });
+    }
+
+    pub fn send_hash_reject(&mut self, req: HashRequest) {
+        trace!("Send hash reject {:?}", req);
+        self.send_frame(Frame::HashReject(req));
+    }
+
+    pub fn send_ext<E: Encode + Debug>(&mut self, id: u8, payload: E) {
+        self.send_ext_data(id, payload, &[]);
     }
 
     pub fn send_ext_data<E: Encode + Debug>(&mut self, id: u8, payload: E, data: &[u8]) {
@@ -148,26 +463,57 @@ impl Connection {
 
         self.encode_buf.clear();
         payload.encode(&mut self.encode_buf);
+        self.encode_buf.extend_from_slice(data);
+
+        let frame = Frame::Extended {
+            id,
+            payload: &self.encode_buf,
+        };
+        frame.encode(&mut self.send_buf);
+    }
 
-        let len = 2 + self.encode_buf.len() + data.len();
-        trace!("Send ext with trailing data {}, {}", id, len);
+    /// Send an extended message with a payload encoded by the caller, e.g.
+    /// for an extension the connection doesn't implement.
+    pub fn send_ext_raw(&mut self, id: u8, payload: &[u8]) {
+        trace!("Send raw ext {}, len: {}", id, payload.len());
+        self.send_frame(Frame::Extended { id, payload });
+    }
+
+    /// Queue a message to be sent to the peer.
+    pub fn send_frame(&mut self, frame: Frame<'_>) {
+        let now = self.clock.now();
+        if let Frame::Piece(block) = &frame {
+            self.wire_stats.payload_up += block.data.len() as u64;
+            self.activity.last_block_sent = Some(now);
+        }
+        self.activity.last_sent = Some(now);
+        frame.encode(&mut self.send_buf);
+    }
 
-        self.send_buf.put_u32(len as u32);
-        self.send_buf.put_u8(EXTENDED);
-        self.send_buf.put_u8(id);
-        self.send_buf.extend_from_slice(&self.encode_buf);
-        self.send_buf.extend_from_slice(data);
+    /// Returns true if the peer advertised the extension in its handshake.
+    pub fn peer_supports(&self, ext: Extension) -> bool {
+        ext.is_set(&self.peer_extensions)
+    }
+
+    /// Returns true if both sides advertised the extension protocol, so
+    /// extended messages may be sent.
+    pub fn is_extended(&self) -> bool {
+        self.extended && self.peer_supports(Extension::Extended)
     }
 
     pub fn request_metadata(&mut self) -> bool {
+        if !self.is_extended() {
+            trace!("Extension protocol not supported");
+            return false;
+        }
+
         if let Some(meta) = &mut self.ut_metadata {
             trace!("Requesting metadata");
             meta.piece = 0;
             meta.buf.clear();
 
             let id = meta.id;
-            let len = meta.len as u32;
-            self.send_ext(0, MetadataMsg::Handshake(id, len));
+            self.send_ext_handshake();
             self.send_ext(id, MetadataMsg::Request(0));
             true
         } else {
@@ -176,6 +522,49 @@ impl Connection {
         }
     }
 
+    /// Send our extended handshake, advertising ut_metadata and the
+    /// registered extensions along with the client version, if set.
+    pub fn send_ext_handshake(&mut self) {
+        if !self.is_extended() {
+            trace!("Extension protocol not supported");
+            return;
+        }
+
+        let mut extensions = vec![("ut_metadata", UT_METADATA_ID)];
+        for (i, name) in self.extensions.iter().enumerate() {
+            extensions.push((name, UT_METADATA_ID + 1 + i as u8));
+        }
+        let handshake = ExtHandshake {
+            extensions: &extensions,
+            metadata_size: self.ut_metadata.as_ref().map(|m| m.len as u32),
+            version: self.client_version.as_deref(),
+        };
+        let mut payload = vec![];
+        handshake.encode(&mut payload);
+        self.send_ext_raw(0, &payload);
+    }
+
+    /// Number of bytes queued to be sent.
+    pub fn pending_send(&self) -> usize {
+        self.send_buf.len()
+    }
+
+    /// The queued bytes, to write them to the peer and then
+    /// [`consume_sent`](Self::consume_sent) the ones written.
+    pub fn pending_bytes(&self) -> &[u8] {
+        &self.send_buf
+    }
+
+    /// Drop the first `n` queued bytes, which were written to the peer.
+    pub fn consume_sent(&mut self, n: usize) {
+        self.wire_stats.bytes_up += n as u64;
+        self.send_buf.drain(..n);
+    }
+
+    /// Take the queued bytes, e.g. to hand them to a simulated peer. They
+    /// don't count as sent, since they may never be written; a writer uses
+    /// [`pending_bytes`](Self::pending_bytes) and
+    /// [`consume_sent`](Self::consume_sent) instead.
     pub fn send_buf(&mut self) -> SendBuf<'_> {
         SendBuf {
             buf: &mut self.send_buf,
@@ -186,115 +575,485 @@ impl Connection {
         self.choked
     }
 
+    /// The peer told us it's interested in our pieces.
+    pub fn is_peer_interested(&self) -> bool {
+        self.interested
+    }
+
     pub fn ext_handshaked(&self) -> bool {
         self.ext_handshaked
     }
 
-    pub fn recv_packet<'a>(&mut self, mut data: &'a [u8]) -> Option<Packet<'a>> {
-        let id = data.get_u8();
+    /// Take the DHT port the peer sent, if it sent one since the last call.
+    pub fn take_peer_dht_port(&mut self) -> Option<u16> {
+        self.peer_dht_port.take()
+    }
+
+    /// Max number of outstanding requests the peer accepts, if it told us
+    /// in the extended handshake.
+    pub fn peer_reqq(&self) -> Option<u32> {
+        self.peer_reqq
+    }
+
+    /// The peer's own IPv4 and IPv6 addresses, if it told us in the
+    /// extended handshake. A dual-stack peer can be reached at either.
+    pub fn peer_ips(&self) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+        (self.peer_ipv4, self.peer_ipv6)
+    }
+
+    /// Port the peer listens on, if it told us in the extended handshake.
+    pub fn peer_listen_port(&self) -> Option<u16> {
+        self.peer_listen_port
+    }
+
+    /// Set the number of messages with unknown ids after which the peer is
+    /// considered to be sending garbage.
+    pub fn set_max_unknown_msgs(&mut self, max: u32) {
+        self.max_unknown_msgs = max;
+    }
+
+    /// Number of messages with unknown ids received from the peer so far.
+    pub fn unknown_msgs(&self) -> u32 {
+        self.unknown_msgs
+    }
+
+    /// Returns true if the peer sent more messages with unknown ids than
+    /// we are willing to tolerate.
+    pub fn is_garbage(&self) -> bool {
+        self.unknown_msgs > self.max_unknown_msgs
+    }
+
+    /// Don't request the metadata if the peer says it's longer than `len`.
+    pub fn set_max_metadata_len(&mut self, len: usize) {
+        self.max_metadata_len = len;
+    }
+
+    /// Change the limits on the extended messages accepted from the peer.
+    pub fn set_ext_limits(&mut self, limits: ExtLimits) {
+        self.ext_limits = limits;
+    }
+
+    /// Drop the messages of these extensions quietly, instead of
+    /// [`DEFAULT_IGNORED_EXTENSIONS`]. The extensions are named by the ids
+    /// the peer gave them, since we didn't advertise them. Messages of
+    /// other extensions we didn't advertise are logged once per id.
+    pub fn set_ignored_extensions(&mut self, names: &[&str]) {
+        self.ignored_exts = names.iter().map(|&name| name.to_owned()).collect();
+    }
+
+    pub fn ext_limits(&self) -> &ExtLimits {
+        &self.ext_limits
+    }
+
+    /// Returns true if the peer sent more extended messages than the
+    /// limits allow. Messages over the limits are dropped unprocessed.
+    pub fn is_flooding(&self) -> bool {
+        self.ext_flood
+    }
+
+    /// Round trip time of the peer, estimated from the time it takes to
+    /// receive the blocks we request.
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// Bytes exchanged with the peer so far, split into block data and
+    /// protocol overhead.
+    pub fn wire_stats(&self) -> WireStats {
+        self.wire_stats
+    }
+
+    /// When messages last went each way.
+    pub fn activity(&self) -> Activity {
+        self.activity
+    }
+
+    /// Since when the peer could have sent us blocks but didn't: we're
+    /// interested, it has unchoked us, and it sent no block since. `None`
+    /// while we're not interested or it's choking us.
+    ///
+    /// A peer which only sends keep-alives all the while is a freeloader,
+    /// holding on to the connection for what we upload to it.
+    pub fn starved_since(&self) -> Option<Instant> {
+        let since = self.wanting_since?;
+        Some(
+            self.activity
+                .last_block_recv
+                .map_or(since, |t| t.max(since)),
+        )
+    }
+
+    fn update_wanting(&mut self) {
+        if !self.am_interested || self.choked {
+            self.wanting_since = None;
+        } else if self.wanting_since.is_none() {
+            self.wanting_since = Some(self.clock.now());
+        }
+    }
+
+    /// Pieces the peer has announced so far.
+    pub fn peer_pieces(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Returns true if the peer has all of the `num_pieces` pieces.
+    pub fn peer_is_seed(&self, num_pieces: usize) -> bool {
+        num_pieces > 0 && (0..num_pieces).all(|i| self.bitfield.get_bit(i))
+    }
+
+    /// Take the oldest block request from the peer that is yet to be served.
+    pub fn pop_request(&mut self) -> Option<BlockRequest> {
+        self.requests.pop_front()
+    }
+
+    /// Take the request for the rarest piece, going by `availability`, the
+    /// number of peers which have each piece by index. Pieces past its end
+    /// count as had by none. Among equally rare pieces the oldest request
+    /// goes first.
+    ///
+    /// Serving the rare pieces first spreads them through the swarm sooner
+    /// than serving the requests in order.
+    pub fn pop_rarest_request(&mut self, availability: &[u32]) -> Option<BlockRequest> {
+        let (i, _) = self
+            .requests
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, r)| availability.get(r.index as usize).copied().unwrap_or(0))?;
+        self.requests.remove(i)
+    }
+
+    /// Number of block requests from the peer that are yet to be served.
+    pub fn num_requests(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Handle a message from the peer with its length prefix removed.
+    /// Malformed messages are skipped and counted like unknown ones.
+    pub fn recv_packet<'a>(&mut self, data: &'a [u8]) -> Option<Packet<'a>> {
+        // The length prefix
+        self.wire_stats.bytes_down += 4 + data.len() as u64;
+        self.activity.last_recv = Some(self.clock.now());
+        match Frame::decode(data) {
+            Ok(frame) => self.recv_frame(frame),
+            Err(e) => {
+                self.unknown_msgs += 1;
+                warn!("{} ({} so far)", e, self.unknown_msgs);
+                None
+            }
+        }
+    }
+
+    pub fn recv_frame<'a>(&mut self, frame: Frame<'a>) -> Option<Packet<'a>> {
         let mut packet = None;
-        match id {
-            CHOKE => {
+        match frame {
+            Frame::KeepAlive => {
+                trace!("Got keepalive");
+            }
+            Frame::Choke => {
                 trace!("Got choke");
+                if !self.choked {
+                    self.add_peer_event(PeerEvent::Choked);
+                }
                 self.choked = true;
+                self.update_wanting();
+
+                // The peer discards our pending requests
+                self.sent_requests.clear();
             }
-            UNCHOKE => {
+            Frame::Unchoke => {
                 trace!("Got unchoke");
+                if self.choked {
+                    self.add_peer_event(PeerEvent::Unchoked);
+                }
                 self.choked = false;
+                self.update_wanting();
             }
-            INTERESTED => {
+            Frame::Interested => {
                 trace!("Got interested");
+                if !self.interested {
+                    self.add_peer_event(PeerEvent::InterestedInUs);
+                }
                 self.interested = true;
-                self.send_unchoke();
+                if self.auto_choke && !self.download_only {
+                    self.send_unchoke();
+                }
             }
-            NOT_INTERESTED => {
+            Frame::NotInterested => {
                 trace!("Got not-interested");
+                if self.interested {
+                    self.add_peer_event(PeerEvent::NotInterestedInUs);
+                }
                 self.interested = false;
-                self.send_choke();
+                if self.auto_choke {
+                    self.send_choke();
+                }
             }
-            HAVE => {
-                let index = data.get_u32();
+            Frame::Have(index) => {
                 trace!("Got have: {}", index);
-                self.bitfield.set_bit(index as usize);
+                self.recv_have(index as usize);
             }
-            BITFIELD => {
+            Frame::Bitfield(data) => {
                 trace!("Got bitfield len: {}", data.len());
-                self.bitfield.copy_from_slice(&data);
+
+                // Keep the pieces of the HAVEs sent before the bitfield
+                let mut bitfield = Bitfield::new();
+                bitfield.copy_from_slice(data);
+                bitfield.union_with(&self.bitfield);
+                self.bitfield = bitfield;
             }
-            REQUEST => {
-                let index = data.get_u32();
-                let begin = data.get_u32();
-                let len = data.get_u32();
+            Frame::Request(req) => {
+                let BlockRequest { index, begin, len } = req;
                 trace!("Got Request: index {}, begin {}, len {}", index, begin, len);
+                self.queue_request(req);
                 packet = Some(Packet::Request { index, begin, len });
             }
-            PIECE => {
-                let index = data.get_u32();
-                let begin = data.get_u32();
-                trace!("Got Piece: index {}, begin {}", index, begin);
-                packet = Some(Packet::Piece(PieceBlock { index, begin, data }));
+            Frame::Piece(block) => {
+                trace!("Got Piece: index {}, begin {}", block.index, block.begin);
+                self.sample_rtt(&block);
+                self.wire_stats.payload_down += block.data.len() as u64;
+                self.activity.last_block_recv = Some(self.clock.now());
+                packet = Some(Packet::Piece(block));
             }
-            CANCEL => {
-                let index = data.get_u32();
-                let begin = data.get_u32();
-                let len = data.get_u32();
-                trace!("Got Request: index {}, begin {}, len {}", index, begin, len);
+            Frame::Cancel(req) => {
+                let BlockRequest { index, begin, len } = req;
+                trace!("Got Cancel: index {}, begin {}, len {}", index, begin, len);
+                self.cancel_request(req);
                 packet = Some(Packet::Cancel { index, begin, len });
             }
-            EXTENDED => {
-                trace!("Got Extended: len {}", data.len());
-                self.recv_ext(data);
+            Frame::Port(port) => {
+                trace!("Got port: {}", port);
+                if port != 0 {
+                    self.peer_dht_port = Some(port);
+                }
+            }
+            Frame::Extended { id, payload } => {
+                trace!("Got Extended: id {}, len {}", id, payload.len());
+                if self.accept_ext(id, payload.len()) {
+                    self.recv_ext(id, payload);
+                } else {
+                    self.ext_flood = true;
+                    warn!(
+                        "Extended message over the limits: id {}, len {}",
+                        id,
+                        payload.len()
+                    );
+                }
+            }
+            Frame::HashRequest(req) => {
+                trace!("Got hash request: {:?}", req);
+                packet = Some(Packet::HashRequest(req));
+            }
+            Frame::Hashes { req, hashes } => {
+                trace!("Got hashes: {:?}, len {}", req, hashes.len());
+                packet = Some(Packet::Hashes { req, hashes });
+            }
+            Frame::HashReject(req) => {
+                trace!("Got hash reject: {:?}", req);
+                packet = Some(Packet::HashReject(req));
+            }
+            Frame::Unknown { id, payload } => {
+                // The whole message was already consumed by the caller, so
+                // we can safely skip it.
+                self.unknown_msgs += 1;
+                warn!(
+                    "Unknown message id: {}, len: {} ({} so far)",
+                    id,
+                    payload.len(),
+                    self.unknown_msgs
+                );
             }
-            _ => {}
         }
 
         packet
     }
 
-    fn recv_ext(&mut self, ext: &[u8]) {
-        let ext = match ExtendedMessage::parse(ext, &mut self.parser) {
+    /// Peers may skip the bitfield and send HAVEs only, or send some of the
+    /// pieces as HAVEs after a partial bitfield (lazy bitfield), so the
+    /// bitfield grows as needed.
+    fn recv_have(&mut self, index: usize) {
+        if index >= self.bitfield.len() {
+            if index >= MAX_PIECES {
+                warn!("Have for piece {} out of range", index);
+                self.unknown_msgs += 1;
+                return;
+            }
+            self.bitfield.resize(index + 1);
+        }
+        self.bitfield.set_bit(index);
+    }
+
+    fn queue_request(&mut self, req: BlockRequest) {
+        if self.requests.len() >= MAX_INBOUND_REQUESTS {
+            warn!("Too many pending requests, dropping {:?}", req);
+            return;
+        }
+
+        if !self.requests.contains(&req) {
+            self.requests.push_back(req);
+        }
+    }
+
+    fn sample_rtt(&mut self, block: &PieceBlock<'_>) {
+        let pos = self
+            .sent_requests
+            .iter()
+            .position(|(r, _)| r.index == block.index && r.begin == block.begin);
+
+        if let Some((_, sent)) = pos.and_then(|i| self.sent_requests.remove(i)) {
+            let now = self.clock.now();
+            self.rtt.add_sample(now.saturating_duration_since(sent));
+        }
+    }
+
+    fn cancel_request(&mut self, req: BlockRequest) {
+        if let Some(i) = self.requests.iter().position(|r| *r == req) {
+            self.requests.remove(i);
+        } else {
+            trace!("Cancelled request not found (already served?): {:?}", req);
+        }
+    }
+
+    /// Count the extended message against the peer's budget. Returns false
+    /// if it is over the limits.
+    fn accept_ext(&mut self, id: u8, len: usize) -> bool {
+        let limits = self.ext_limits;
+        if !self.ext_handshaked {
+            let early = &mut self.early_ext;
+            early.total_msgs += 1;
+            early.total_bytes += len as u64;
+            return early.total_msgs <= limits.max_msgs_before_handshake
+                && early.total_bytes <= limits.max_bytes_before_handshake as u64;
+        }
+
+        let now = self.clock.now();
+        let usage = self.ext_usage.entry(id).or_default();
+        let expired = usage
+            .window_start
+            .is_none_or(|start| now.saturating_duration_since(start) >= limits.window);
+        if expired {
+            usage.window_start = Some(now);
+            usage.window_msgs = 0;
+            usage.window_bytes = 0;
+        }
+
+        usage.window_msgs += 1;
+        usage.window_bytes += len;
+        usage.total_bytes += len as u64;
+        usage.window_msgs <= limits.max_msgs_per_window
+            && usage.window_bytes <= limits.max_bytes_per_window
+            && usage.total_bytes <= limits.max_total_bytes
+    }
+
+    /// Extended messages are told apart by the ids we gave the extensions
+    /// in our handshake. ut_metadata is handled here, and the messages of
+    /// the extensions registered by the embedder are passed on as
+    /// [`Event::Extended`].
+    ///
+    /// Messages with ids we never advertised are dropped. Peers sending
+    /// them regardless tend to use the ids they gave the extensions, which
+    /// name them in the log, unless the extension is ignored.
+    fn recv_ext(&mut self, id: u8, payload: &[u8]) {
+        if self.is_registered_ext(id) {
+            let payload = payload.to_vec();
+            self.add_event(Event::Extended { id, payload });
+            return;
+        }
+
+        match id {
+            0 | UT_METADATA_ID if self.recv_known_ext(id, payload) => {}
+            0 => warn!("Invalid extended handshake"),
+            UT_METADATA_ID => debug!("Unhandled ut_metadata message"),
+            _ => {
+                let name = self.peer_ext_names.get(&id);
+                if name.is_some_and(|name| self.ignored_exts.contains(name)) {
+                    trace!("Ignoring extended message: id {}, {:?}", id, name);
+                } else if self.logged_exts.insert(id) {
+                    debug!(
+                        "Extended message with an id we didn't advertise: id {}, {:?}",
+                        id, name
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns true if `id` is the one we gave an extension registered by
+    /// the embedder.
+    fn is_registered_ext(&self, id: u8) -> bool {
+        id > UT_METADATA_ID && usize::from(id - UT_METADATA_ID) <= self.extensions.len()
+    }
+
+    /// Handle the extended handshake and the ut_metadata data. Returns
+    /// false if the message is neither.
+    fn recv_known_ext(&mut self, id: u8, payload: &[u8]) -> bool {
+        let ext = match ExtendedMessage::parse(id, payload, &mut self.parser) {
             Ok(e) => e,
             Err(e) => {
-                warn!("{}", e);
-                return;
+                debug!("{}", e);
+                return false;
             }
         };
 
         if ext.is_handshake() {
-            self.ut_metadata = ext.metadata().map(|m| UtMetadata {
+            let max_len = self.max_metadata_len;
+            let metadata = ext.metadata().filter(|m| {
+                let within = m.len <= max_len;
+                if !within {
+                    debug!("Metadata of {} bytes is over the limit", m.len);
+                }
+                within
+            });
+            self.ut_metadata = metadata.map(|m| UtMetadata {
                 id: m.id,
                 len: m.len,
                 buf: Vec::new(),
                 piece: 0,
             });
+            self.peer_reqq = ext.reqq().map(|n| n.max(1));
+            self.peer_ipv4 = ext.ipv4();
+            self.peer_ipv6 = ext.ipv6();
+            self.peer_listen_port = ext.listen_port();
+            self.peer_ext_names = ext
+                .extensions()
+                .into_iter()
+                .filter(|&(_, id)| id != 0)
+                .map(|(name, id)| (id, name))
+                .collect();
             self.ext_handshaked = true;
-            return;
+            self.add_event(Event::ExtendedHandshake);
+            return true;
         }
 
-        if let Some(meta) = &mut self.ut_metadata {
-            if let Ok(piece) = ext.data(meta.piece) {
-                meta.buf.extend_from_slice(piece);
-
-                if meta.buf.len() > meta.len {
-                    meta.piece = 0;
-                    meta.buf.clear();
-                    return;
-                }
+        let meta = match &mut self.ut_metadata {
+            Some(meta) => meta,
+            None => return false,
+        };
+        let piece = match ext.data(meta.piece) {
+            Ok(piece) => piece,
+            Err(_) => return false,
+        };
 
-                if meta.buf.len() == meta.len {
-                    meta.piece = 0;
-                    self.events
-                        .push_back(Event::Metadata(std::mem::take(&mut meta.buf)));
-                    return;
-                }
+        meta.buf.extend_from_slice(piece);
 
-                meta.piece += 1;
+        if meta.buf.len() > meta.len {
+            meta.piece = 0;
+            meta.buf.clear();
+            return true;
+        }
 
-                let id = meta.id;
-                let piece = meta.piece;
-                self.send_ext(id, MetadataMsg::Request(piece));
-            }
+        if meta.buf.len() == meta.len {
+            meta.piece = 0;
+            let metadata = std::mem::take(&mut meta.buf);
+            self.add_event(Event::Metadata(metadata));
+            return true;
         }
+
+        meta.piece += 1;
+
+        let id = meta.id;
+        let piece = meta.piece;
+        self.send_ext(id, MetadataMsg::Request(piece));
+        true
     }
 }
 
@@ -314,7 +1073,7 @@ impl<'a> Deref for SendBuf<'a> {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &self.buf
+        self.buf
     }
 }
 
@@ -335,46 +1094,61 @@ mod tests {
         assert_eq!(conn.send_buf, &[0, 0, 0, 0])
     }
 
+    #[test]
+    fn send_handshake_without_extensions() {
+        let mut conn = Connection::new();
+        conn.send_handshake(&[1; 20], &[2; 20]);
+        assert_eq!(conn.send_buf[25], 0x10);
+
+        let mut conn = Connection::new();
+        conn.set_extended(false);
+        conn.send_handshake(&[1; 20], &[2; 20]);
+        assert_eq!(conn.send_buf[20..28], [0; 8]);
+    }
+
     #[test]
     fn send_choke() {
         let mut conn = Connection::new();
         conn.send_choke();
-        assert_eq!(conn.send_buf, &[0, 0, 0, 1, CHOKE])
+        assert_eq!(conn.send_buf, &[0, 0, 0, 1, MessageId::Choke as u8])
     }
 
     #[test]
     fn send_unchoke() {
         let mut conn = Connection::new();
         conn.send_unchoke();
-        assert_eq!(conn.send_buf, &[0, 0, 0, 1, UNCHOKE])
+        assert_eq!(conn.send_buf, &[0, 0, 0, 1, MessageId::Unchoke as u8])
     }
 
     #[test]
     fn send_interested() {
         let mut conn = Connection::new();
         conn.send_interested();
-        assert_eq!(conn.send_buf, &[0, 0, 0, 1, INTERESTED])
+        assert_eq!(conn.send_buf, &[0, 0, 0, 1, MessageId::Interested as u8])
     }
 
     #[test]
     fn send_not_interested() {
         let mut conn = Connection::new();
         conn.send_not_interested();
-        assert_eq!(conn.send_buf, &[0, 0, 0, 1, NOT_INTERESTED])
+        assert_eq!(conn.send_buf, &[0, 0, 0, 1, MessageId::NotInterested as u8])
     }
 
     #[test]
     fn send_have() {
         let mut conn = Connection::new();
         conn.send_have(4);
-        assert_eq!(conn.send_buf, &[0, 0, 0, 5, HAVE, 0, 0, 0, 4])
+        assert_eq!(
+            conn.send_buf,
+            &[0, 0, 0, 5, MessageId::Have as u8, 0, 0, 0, 4]
+        )
     }
 
     #[test]
     fn send_bitfield_empty() {
         let mut conn = Connection::new();
         conn.send_bitfield();
-        assert_eq!(conn.send_buf, &[0, 0, 0, 1, BITFIELD])
+        assert_eq!(conn.send_buf, &[0, 0, 0, 1, MessageId::Bitfield as u8])
     }
 
     #[test]
@@ -383,7 +1157,10 @@ mod tests {
         conn.bitfield.resize(3);
         conn.bitfield.set_bit(1);
         conn.send_bitfield();
-        assert_eq!(conn.send_buf, &[0, 0, 0, 2, BITFIELD, 0b01000000])
+        assert_eq!(
+            conn.send_buf,
+            &[0, 0, 0, 2, MessageId::Bitfield as u8, 0b01000000]
+        )
     }
 
     #[test]
@@ -392,7 +1169,25 @@ mod tests {
         conn.send_request(2, 4, 5);
         assert_eq!(
             conn.send_buf,
-            &[0, 0, 0, 13, REQUEST, 0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 5]
+            &[
+                0,
+                0,
+                0,
+                13,
+                MessageId::Request as u8,
+                0,
+                0,
+                0,
+                2,
+                0,
+                0,
+                0,
+                4,
+                0,
+                0,
+                0,
+                5
+            ]
         )
     }
 
@@ -402,7 +1197,25 @@ mod tests {
         conn.send_piece(3, 5, &[1, 2, 3, 4]);
         assert_eq!(
             conn.send_buf,
-            &[0, 0, 0, 13, PIECE, 0, 0, 0, 3, 0, 0, 0, 5, 1, 2, 3, 4]
+            &[
+                0,
+                0,
+                0,
+                13,
+                MessageId::Piece as u8,
+                0,
+                0,
+                0,
+                3,
+                0,
+                0,
+                0,
+                5,
+                1,
+                2,
+                3,
+                4
+            ]
         )
     }
 
@@ -412,7 +1225,25 @@ mod tests {
         conn.send_cancel(2, 4, 5);
         assert_eq!(
             conn.send_buf,
-            &[0, 0, 0, 13, CANCEL, 0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 5]
+            &[
+                0,
+                0,
+                0,
+                13,
+                MessageId::Cancel as u8,
+                0,
+                0,
+                0,
+                2,
+                0,
+                0,
+                0,
+                4,
+                0,
+                0,
+                0,
+                5
+            ]
         )
     }
 
@@ -422,7 +1253,21 @@ mod tests {
         conn.send_ext(2, "hello");
         assert_eq!(
             conn.send_buf,
-            &[0, 0, 0, 9, EXTENDED, 2, b'5', b':', b'h', b'e', b'l', b'l', b'o']
+            &[
+                0,
+                0,
+                0,
+                9,
+                MessageId::Extended as u8,
+                2,
+                b'5',
+                b':',
+                b'h',
+                b'e',
+                b'l',
+                b'l',
+                b'o'
+            ]
         )
     }
 
@@ -445,6 +1290,7 @@ mod tests {
         let data = &tx.send_buf()[4..];
         assert!(rx.recv_packet(data).is_none());
         assert!(rx.choked);
+        assert_eq!(rx.poll_peer_event(), Some(PeerEvent::Choked));
     }
 
     #[test]
@@ -456,6 +1302,7 @@ mod tests {
         let data = &tx.send_buf()[4..];
         assert!(rx.recv_packet(data).is_none());
         assert!(!rx.choked);
+        assert_eq!(rx.poll_peer_event(), Some(PeerEvent::Unchoked));
     }
 
     #[test]
@@ -467,7 +1314,37 @@ mod tests {
         let data = &tx.send_buf()[4..];
         assert!(rx.recv_packet(data).is_none());
         assert!(rx.interested);
-        assert_eq!(rx.send_buf, &[0, 0, 0, 1, UNCHOKE]);
+        assert_eq!(rx.send_buf, &[0, 0, 0, 1, MessageId::Unchoke as u8]);
+        assert_eq!(rx.poll_peer_event(), Some(PeerEvent::InterestedInUs));
+    }
+
+    #[test]
+    fn download_only_never_unchokes() {
+        let mut rx = Connection::new();
+        let mut tx = Connection::new();
+        rx.set_download_only(true);
+        tx.send_interested();
+
+        let data = &tx.send_buf()[4..];
+        assert!(rx.recv_packet(data).is_none());
+        assert!(rx.interested);
+        assert!(rx.send_buf.is_empty());
+    }
+
+    #[test]
+    fn no_auto_choke() {
+        let mut rx = Connection::new();
+        let mut tx = Connection::new();
+        rx.set_auto_choke(false);
+        tx.send_interested();
+        tx.send_not_interested();
+
+        let data = tx.send_buf().to_vec();
+        assert!(rx.recv_packet(&data[4..5]).is_none());
+        assert!(rx.is_peer_interested());
+        assert!(rx.recv_packet(&data[9..]).is_none());
+        assert!(!rx.is_peer_interested());
+        assert!(rx.send_buf.is_empty());
     }
 
     #[test]
@@ -480,7 +1357,30 @@ mod tests {
         let data = &tx.send_buf()[4..];
         assert!(rx.recv_packet(data).is_none());
         assert!(!rx.interested);
-        assert_eq!(rx.send_buf, &[0, 0, 0, 1, CHOKE]);
+        assert_eq!(rx.send_buf, &[0, 0, 0, 1, MessageId::Choke as u8]);
+        assert_eq!(rx.poll_peer_event(), Some(PeerEvent::NotInterestedInUs));
+    }
+
+    #[test]
+    fn peer_events_only_on_change() {
+        let mut rx = Connection::new();
+
+        // Choked to begin with
+        rx.recv_frame(Frame::Choke);
+        assert_eq!(rx.poll_peer_event(), None);
+
+        rx.recv_frame(Frame::Unchoke);
+        rx.recv_frame(Frame::Unchoke);
+        assert_eq!(rx.poll_peer_event(), Some(PeerEvent::Unchoked));
+        assert_eq!(rx.poll_peer_event(), None);
+
+        for _ in 0..MAX_PEER_EVENTS {
+            rx.recv_frame(Frame::Choke);
+            rx.recv_frame(Frame::Unchoke);
+        }
+        let events: Vec<_> = std::iter::from_fn(|| rx.poll_peer_event()).collect();
+        assert_eq!(events.len(), MAX_PEER_EVENTS);
+        assert_eq!(events.last(), Some(&PeerEvent::Unchoked));
     }
 
     #[test]
@@ -492,7 +1392,41 @@ mod tests {
 
         let data = &tx.send_buf()[4..];
         assert!(rx.recv_packet(data).is_none());
-        assert_eq!(rx.bitfield.get_bit(5), true);
+        assert!(rx.bitfield.get_bit(5));
+    }
+
+    #[test]
+    fn have_without_bitfield() {
+        let mut rx = Connection::new();
+        let mut tx = Connection::new();
+        tx.send_have(9);
+        tx.send_have(2);
+
+        let buf = tx.send_buf();
+        assert!(rx.recv_packet(&buf[4..9]).is_none());
+        assert!(rx.recv_packet(&buf[13..]).is_none());
+        assert_eq!(rx.peer_pieces().len(), 10);
+        assert_eq!(rx.peer_pieces().count(), 2);
+        assert!(rx.peer_pieces().get_bit(2));
+
+        rx.recv_frame(Frame::Have(MAX_PIECES as u32));
+        assert_eq!(rx.peer_pieces().len(), 10);
+        assert_eq!(rx.unknown_msgs(), 1);
+    }
+
+    #[test]
+    fn late_bitfield_keeps_haves() {
+        let mut rx = Connection::new();
+        rx.recv_frame(Frame::Have(3));
+        rx.recv_frame(Frame::Bitfield(&[0b1000_0000, 0]));
+        rx.recv_frame(Frame::Have(15));
+
+        assert_eq!(rx.peer_pieces().len(), 16);
+        assert_eq!(rx.peer_pieces().count(), 3);
+        assert!(rx.peer_pieces().get_bit(0));
+        assert!(rx.peer_pieces().get_bit(3));
+        assert!(rx.peer_pieces().get_bit(15));
+        assert!(rx.peer_is_seed(1));
     }
 
     #[test]
@@ -559,6 +1493,343 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_request_queues_it() {
+        let mut rx = Connection::new();
+        let mut tx = Connection::new();
+        tx.send_request(2, 3, 4);
+        tx.send_request(2, 5, 4);
+
+        let buf = tx.send_buf();
+        rx.recv_packet(&buf[4..17]);
+        rx.recv_packet(&buf[21..]);
+        drop(buf);
+
+        assert_eq!(rx.num_requests(), 2);
+        let r = rx.pop_request().unwrap();
+        assert_eq!(
+            r,
+            BlockRequest {
+                index: 2,
+                begin: 3,
+                len: 4
+            }
+        );
+        assert_eq!(rx.num_requests(), 1);
+    }
+
+    #[test]
+    fn rarest_request_first() {
+        let mut rx = Connection::new();
+        let mut tx = Connection::new();
+        for index in [0, 1, 2, 1] {
+            tx.send_request(index, 0, 4);
+        }
+        tx.send_request(1, 4, 4);
+
+        let buf = tx.send_buf().to_vec();
+        for msg in buf.chunks(17) {
+            rx.recv_packet(&msg[4..]);
+        }
+        assert_eq!(rx.num_requests(), 4);
+
+        let order: Vec<_> = std::iter::from_fn(|| rx.pop_rarest_request(&[3, 1, 1]))
+            .map(|r| (r.index, r.begin))
+            .collect();
+        assert_eq!(order, [(1, 0), (2, 0), (1, 4), (0, 0)]);
+    }
+
+    #[test]
+    fn parse_cancel_removes_queued_request() {
+        let mut rx = Connection::new();
+        let mut tx = Connection::new();
+        tx.send_request(2, 3, 4);
+        rx.recv_packet(&tx.send_buf()[4..]);
+        tx.send_request(2, 5, 4);
+        rx.recv_packet(&tx.send_buf()[4..]);
+        assert_eq!(rx.num_requests(), 2);
+
+        tx.send_cancel(2, 3, 4);
+        rx.recv_packet(&tx.send_buf()[4..]);
+        assert_eq!(rx.num_requests(), 1);
+        assert_eq!(rx.pop_request().unwrap().begin, 5);
+        assert!(rx.pop_request().is_none());
+    }
+
+    #[test]
+    fn cancel_unknown_request_is_ignored() {
+        let mut rx = Connection::new();
+        let mut tx = Connection::new();
+        tx.send_request(2, 3, 4);
+        rx.recv_packet(&tx.send_buf()[4..]);
+
+        tx.send_cancel(1, 3, 4);
+        rx.recv_packet(&tx.send_buf()[4..]);
+        assert_eq!(rx.num_requests(), 1);
+    }
+
+    #[test]
+    fn choke_discards_queued_requests() {
+        let mut rx = Connection::new();
+        let mut tx = Connection::new();
+        tx.send_request(2, 3, 4);
+        rx.recv_packet(&tx.send_buf()[4..]);
+        assert_eq!(rx.num_requests(), 1);
+
+        rx.send_choke();
+        assert_eq!(rx.num_requests(), 0);
+    }
+
+    #[test]
+    fn rtt_from_requested_blocks() {
+        let mut a = Connection::new();
+        let mut b = Connection::new();
+        a.send_request(2, 0, 5);
+        a.send_request(2, 5, 5);
+        drop(a.send_buf());
+
+        // Blocks we didn't ask for are no samples
+        b.send_piece(3, 0, b"hello");
+        a.recv_packet(&b.send_buf()[4..]);
+        assert_eq!(a.rtt().srtt(), None);
+
+        b.send_piece(2, 5, b"hello");
+        a.recv_packet(&b.send_buf()[4..]);
+        assert!(a.rtt().srtt().is_some());
+        assert_eq!(a.sent_requests.len(), 1);
+
+        // Pending requests are dropped on choke
+        a.recv_packet(&[MessageId::Choke as u8]);
+        assert!(a.sent_requests.is_empty());
+    }
+
+    #[test]
+    fn peer_is_seed() {
+        let mut rx = Connection::new();
+        assert!(!rx.peer_is_seed(10));
+
+        rx.recv_packet(&[MessageId::Bitfield as u8, 0xff, 0x80]);
+        assert!(rx.peer_is_seed(9));
+        assert!(!rx.peer_is_seed(10));
+
+        rx.recv_packet(&[MessageId::Have as u8, 0, 0, 0, 9]);
+        assert!(rx.peer_is_seed(10));
+    }
+
+    #[test]
+    fn unknown_msg_is_skipped_and_counted() {
+        let mut rx = Connection::new();
+        rx.set_max_unknown_msgs(1);
+
+        assert!(rx.recv_packet(&[100, 1, 2, 3]).is_none());
+        assert_eq!(rx.unknown_msgs(), 1);
+        assert!(!rx.is_garbage());
+
+        // Known messages are still processed
+        rx.choked = false;
+        assert!(rx.recv_packet(&[MessageId::Choke as u8]).is_none());
+        assert!(rx.choked);
+
+        assert!(rx.recv_packet(&[101]).is_none());
+        assert_eq!(rx.unknown_msgs(), 2);
+        assert!(rx.is_garbage());
+    }
+
+    #[test]
+    fn ext_flood_before_handshake() {
+        let mut c = Connection::new();
+        c.set_ext_limits(ExtLimits {
+            max_msgs_before_handshake: 2,
+            ..ExtLimits::default()
+        });
+
+        let mut sender = Connection::new();
+        for _ in 0..2 {
+            sender.send_ext_data(1, MetadataMsg::Data(0, 10), b"xxxxxyyyyy");
+            c.recv_packet(&sender.send_buf()[4..]);
+        }
+        assert!(!c.is_flooding());
+
+        sender.send_ext(0, MetadataMsg::Handshake(2, 20, None));
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert!(c.is_flooding());
+
+        // The handshake over the limit is dropped
+        assert!(!c.ext_handshaked());
+    }
+
+    #[test]
+    fn registered_ext_messages_are_events() {
+        let mut c = Connection::new();
+        assert_eq!(c.register_extension("ut_comment"), 2);
+        assert_eq!(c.register_extension("lt_example"), 3);
+        let mut sender = Connection::new();
+        sender.send_ext_raw(2, b"hello");
+        c.recv_packet(&sender.send_buf()[4..]);
+
+        sender.send_ext(0, MetadataMsg::Handshake(2, 20, None));
+        c.recv_packet(&sender.send_buf()[4..]);
+        sender.send_ext_data(1, MetadataMsg::Data(0, 10), b"xxxxxyyyyy");
+        c.recv_packet(&sender.send_buf()[4..]);
+        sender.send_ext_raw(3, b"d1:ai1ee");
+        c.recv_packet(&sender.send_buf()[4..]);
+        sender.send_ext_raw(4, b"d1:ai1ee");
+        c.recv_packet(&sender.send_buf()[4..]);
+
+        // The metadata data is not passed on, nor the message with an id
+        // we didn't advertise
+        let payload = b"hello".to_vec();
+        assert_eq!(c.poll_event(), Some(Event::Extended { id: 2, payload }));
+        assert_eq!(c.poll_event(), Some(Event::ExtendedHandshake));
+        let payload = b"d1:ai1ee".to_vec();
+        assert_eq!(c.poll_event(), Some(Event::Extended { id: 3, payload }));
+        assert_eq!(c.poll_event(), None);
+    }
+
+    #[test]
+    fn only_extended_messages_are_dropped() {
+        let mut c = Connection::new();
+        assert_eq!(c.register_extension("lt_example"), 2);
+        let mut sender = Connection::new();
+        sender.send_ext(0, MetadataMsg::Handshake(2, 10, None));
+        c.recv_packet(&sender.send_buf()[4..]);
+        sender.send_ext_data(1, MetadataMsg::Data(0, 10), b"xxxxxyyyyy");
+        c.recv_packet(&sender.send_buf()[4..]);
+
+        for i in 0..MAX_EVENTS + 10 {
+            sender.send_ext_raw(2, &[i as u8]);
+            c.recv_packet(&sender.send_buf()[4..]);
+        }
+        sender.send_ext(0, MetadataMsg::Handshake(2, 10, None));
+        c.recv_packet(&sender.send_buf()[4..]);
+
+        // The flood of messages pushes out the oldest messages only, and
+        // the repeated handshake the first one
+        assert_eq!(
+            c.poll_event(),
+            Some(Event::Metadata(b"xxxxxyyyyy".to_vec()))
+        );
+        let payload = vec![10];
+        assert_eq!(c.poll_event(), Some(Event::Extended { id: 2, payload }));
+        let rest: Vec<_> = std::iter::from_fn(|| c.poll_event()).collect();
+        assert_eq!(rest.len(), MAX_EVENTS);
+        assert_eq!(rest.last(), Some(&Event::ExtendedHandshake));
+    }
+
+    #[test]
+    fn unadvertised_ext_messages_are_dropped() {
+        let mut c = Connection::new();
+        let mut sender = Connection::new();
+        sender.send_ext_raw(0, b"d1:md10:ut_commenti4e6:ut_pexi5eee");
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert!(c.ext_handshaked());
+        assert_eq!(c.poll_event(), Some(Event::ExtendedHandshake));
+
+        // By the ids the peer gave them, ut_comment being ignored
+        for id in [4, 5, 5] {
+            sender.send_ext_raw(id, b"de");
+            c.recv_packet(&sender.send_buf()[4..]);
+        }
+        assert_eq!(c.poll_event(), None);
+        assert_eq!(c.logged_exts, HashSet::from([5]));
+
+        c.set_ignored_extensions(&["ut_pex"]);
+        sender.send_ext_raw(4, b"de");
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert_eq!(c.poll_event(), None);
+        assert_eq!(c.logged_exts, HashSet::from([4, 5]));
+    }
+
+    #[test]
+    fn ext_flood_per_extension() {
+        let clock = crate::clock::VirtualClock::new();
+        let mut c = Connection::new();
+        c.set_clock(clock.clone().into());
+        c.set_ext_limits(ExtLimits {
+            window: Duration::from_secs(1),
+            max_msgs_per_window: 2,
+            ..ExtLimits::default()
+        });
+
+        let mut sender = Connection::new();
+        sender.send_ext(0, MetadataMsg::Handshake(2, 20, None));
+        c.recv_packet(&sender.send_buf()[4..]);
+
+        // Each extension has its own budget
+        for id in [1, 1, 3] {
+            sender.send_ext(id, MetadataMsg::Request(0));
+            c.recv_packet(&sender.send_buf()[4..]);
+        }
+        assert!(!c.is_flooding());
+
+        clock.advance(Duration::from_secs(1));
+        for _ in 0..2 {
+            sender.send_ext(1, MetadataMsg::Request(0));
+            c.recv_packet(&sender.send_buf()[4..]);
+        }
+        assert!(!c.is_flooding());
+
+        sender.send_ext(1, MetadataMsg::Request(0));
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert!(c.is_flooding());
+    }
+
+    #[test]
+    fn starved_by_peer() {
+        let clock = crate::clock::VirtualClock::new();
+        let mut c = Connection::new();
+        c.set_clock(clock.clone().into());
+        let mut peer = Connection::new();
+        let start = clock.now();
+
+        c.send_interested();
+        assert_eq!(c.activity().last_sent, Some(start));
+        assert_eq!(c.starved_since(), None);
+
+        clock.advance(Duration::from_secs(1));
+        peer.send_unchoke();
+        c.recv_packet(&peer.send_buf()[4..]);
+        let unchoked = clock.now();
+        assert_eq!(c.starved_since(), Some(unchoked));
+
+        // Keep-alives don't count as data
+        clock.advance(Duration::from_secs(1));
+        c.recv_packet(&[]);
+        assert_eq!(c.activity().last_recv, Some(clock.now()));
+        assert_eq!(c.starved_since(), Some(unchoked));
+
+        clock.advance(Duration::from_secs(1));
+        peer.send_piece(0, 0, b"data");
+        c.recv_packet(&peer.send_buf()[4..]);
+        assert_eq!(c.activity().last_block_recv, Some(clock.now()));
+        assert_eq!(c.starved_since(), Some(clock.now()));
+
+        c.send_not_interested();
+        assert_eq!(c.starved_since(), None);
+    }
+
+    #[test]
+    fn ext_flood_total_bytes() {
+        let mut c = Connection::new();
+        c.set_ext_limits(ExtLimits {
+            max_total_bytes: 100,
+            ..ExtLimits::default()
+        });
+
+        let mut sender = Connection::new();
+        sender.send_ext(0, MetadataMsg::Handshake(2, 1000, None));
+        c.recv_packet(&sender.send_buf()[4..]);
+
+        sender.send_ext_data(1, MetadataMsg::Data(0, 30), &[0; 30]);
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert!(!c.is_flooding());
+
+        sender.send_ext_data(1, MetadataMsg::Data(1, 30), &[0; 30]);
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert!(c.is_flooding());
+        assert_eq!(c.ut_metadata.as_ref().unwrap().piece, 1);
+    }
+
     #[test]
     fn handshake() {
         let mut c = Connection::new();
@@ -567,6 +1838,213 @@ mod tests {
         let h = Handshake::new([0; 20], [2; 20]);
         let p = c.recv_handshake(&[0; 20], *h.as_bytes()).unwrap();
         assert_eq!(p, [2; 20]);
+        assert!(!c.peer_supports(Extension::Extended));
+        assert!(!c.is_extended());
+    }
+
+    #[test]
+    fn wire_stats() {
+        let mut rx = Connection::new();
+        let mut tx = Connection::new();
+        tx.send_handshake(&[0; 20], &[1; 20]);
+        let h = Handshake::new([0; 20], [1; 20]);
+        rx.recv_handshake(&[0; 20], *h.as_bytes()).unwrap();
+
+        tx.send_have(1);
+        tx.send_piece(1, 0, &[7; 100]);
+        let buf = tx.pending_bytes().to_vec();
+        rx.recv_packet(&buf[72..77]);
+        rx.recv_packet(&buf[81..]);
+        rx.recv_packet(&[]);
+
+        // Only the bytes written count as sent
+        tx.consume_sent(100);
+        assert_eq!(tx.wire_stats().bytes_up, 100);
+        tx.consume_sent(buf.len() - 100);
+        let sent = tx.wire_stats();
+        assert_eq!(sent.bytes_up, 68 + 9 + 113);
+        assert_eq!(sent.payload_up, 100);
+        assert_eq!(sent.protocol_up(), 90);
+
+        let received = rx.wire_stats();
+        assert_eq!(received.bytes_down, sent.bytes_up + 4);
+        assert_eq!(received.payload_down, 100);
+        assert_eq!(received.protocol_down(), 94);
+        assert_eq!(received.bytes_up, 0);
+    }
+
+    #[test]
+    fn handshake_from_self() {
+        let mut c = Connection::new();
+        c.send_handshake(&[0; 20], &[1; 20]);
+
+        let h = Handshake::new([0; 20], [1; 20]);
+        let err = c.recv_handshake(&[0; 20], *h.as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), Error::SelfConnection.to_string());
+        assert!(Error::SelfConnection.is_handshake());
+    }
+
+    #[test]
+    fn extended_only_if_both_support_it() {
+        let mut h = Handshake::new([0; 20], [2; 20]);
+        h.set_extended(true);
+
+        let mut c = Connection::new();
+        c.recv_handshake(&[0; 20], *h.as_bytes()).unwrap();
+        assert!(c.peer_supports(Extension::Extended));
+        assert!(!c.peer_supports(Extension::Fast));
+        assert!(c.is_extended());
+
+        let mut c = Connection::new();
+        c.set_extended(false);
+        c.recv_handshake(&[0; 20], *h.as_bytes()).unwrap();
+        assert!(c.peer_supports(Extension::Extended));
+        assert!(!c.is_extended());
+    }
+
+    #[test]
+    fn dht_port_sent_after_both_handshakes() {
+        let mut h = Handshake::new([0; 20], [2; 20]);
+        h.set_dht(true);
+
+        let mut c = Connection::new();
+        c.set_dht_port(Some(6881));
+        c.send_handshake(&[0; 20], &[1; 20]);
+        assert!(Extension::Dht.is_set(&c.send_buf[20..28].try_into().unwrap()));
+        assert_eq!(c.send_buf.len(), 68);
+
+        c.recv_handshake(&[0; 20], *h.as_bytes()).unwrap();
+        assert_eq!(
+            c.send_buf[68..],
+            [0, 0, 0, 3, MessageId::Port as u8, 0x1a, 0xe1]
+        );
+
+        // Not to peers without a DHT node
+        let mut c = Connection::new();
+        c.set_dht_port(Some(6881));
+        c.recv_handshake(&[0; 20], *Handshake::new([0; 20], [2; 20]).as_bytes())
+            .unwrap();
+        c.send_handshake(&[0; 20], &[1; 20]);
+        assert_eq!(c.send_buf.len(), 68);
+    }
+
+    #[test]
+    fn recv_peer_dht_port() {
+        let mut c = Connection::new();
+        assert_eq!(c.recv_packet(&[MessageId::Port as u8, 0x1a, 0xe1]), None);
+        assert_eq!(c.take_peer_dht_port(), Some(6881));
+        assert_eq!(c.take_peer_dht_port(), None);
+
+        c.recv_packet(&[MessageId::Port as u8, 0, 0]);
+        assert_eq!(c.take_peer_dht_port(), None);
+    }
+
+    #[test]
+    fn metadata_request_sends_client_version() {
+        let mut h = Handshake::new([0; 20], [2; 20]);
+        h.set_extended(true);
+        let mut c = Connection::new();
+        c.set_client_version("95th 0.1");
+        c.recv_handshake(&[0; 20], *h.as_bytes()).unwrap();
+
+        let mut sender = Connection::new();
+        sender.send_ext(0, MetadataMsg::Handshake(2, 20, None));
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert!(c.request_metadata());
+
+        // Length, EXTENDED and the handshake id
+        let buf = c.send_buf().to_vec();
+        let mut parser = Parser::new();
+        let ext = ExtendedMessage::parse(0, &buf[6..], &mut parser).unwrap();
+        assert_eq!(ext.value.as_dict().unwrap().get_str("v"), Some("95th 0.1"));
+    }
+
+    #[test]
+    fn ext_handshake_sends_client_version() {
+        let mut h = Handshake::new([0; 20], [2; 20]);
+        h.set_extended(true);
+        let mut c = Connection::new();
+        c.set_client_version("95th 0.1");
+        c.recv_handshake(&[0; 20], *h.as_bytes()).unwrap();
+        c.send_ext_handshake();
+
+        let buf = c.send_buf().to_vec();
+        let mut parser = Parser::new();
+        let ext = ExtendedMessage::parse(0, &buf[6..], &mut parser).unwrap();
+        assert_eq!(ext.value.as_dict().unwrap().get_str("v"), Some("95th 0.1"));
+        assert_eq!(ext.extensions(), [("ut_metadata".to_string(), 1)]);
+    }
+
+    #[test]
+    fn registered_extensions_are_advertised() {
+        let mut h = Handshake::new([0; 20], [2; 20]);
+        h.set_extended(true);
+        let mut c = Connection::new();
+        assert_eq!(c.register_extension("ut_comment"), 2);
+        assert_eq!(c.register_extension("lt_tex"), 3);
+        assert_eq!(c.register_extension("ut_comment"), 2);
+        c.recv_handshake(&[0; 20], *h.as_bytes()).unwrap();
+
+        let mut sender = Connection::new();
+        sender.send_ext_raw(
+            0,
+            b"d1:md10:ut_commenti7e11:ut_metadatai2ee13:metadata_sizei20ee",
+        );
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert_eq!(c.peer_extension_id("ut_comment"), Some(7));
+        assert_eq!(c.peer_extension_id("lt_tex"), None);
+        assert!(c.request_metadata());
+
+        let buf = c.send_buf().to_vec();
+        let mut parser = Parser::new();
+        let ext = ExtendedMessage::parse(0, &buf[6..], &mut parser).unwrap();
+        let extensions = [
+            ("lt_tex".to_string(), 3),
+            ("ut_comment".to_string(), 2),
+            ("ut_metadata".to_string(), 1),
+        ];
+        assert_eq!(ext.extensions(), extensions);
+    }
+
+    #[test]
+    fn metadata_over_the_limit_is_not_requested() {
+        let mut h = Handshake::new([0; 20], [2; 20]);
+        h.set_extended(true);
+        let mut c = Connection::new();
+        c.set_max_metadata_len(16);
+        c.recv_handshake(&[0; 20], *h.as_bytes()).unwrap();
+
+        let mut sender = Connection::new();
+        sender.send_ext(0, MetadataMsg::Handshake(2, 20, None));
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert!(c.ext_handshaked());
+        assert!(!c.request_metadata());
+        assert_eq!(c.pending_send(), 0);
+    }
+
+    #[test]
+    fn no_metadata_request_without_extension_protocol() {
+        let mut c = Connection::new();
+        let h = Handshake::new([0; 20], [2; 20]);
+        c.recv_handshake(&[0; 20], *h.as_bytes()).unwrap();
+
+        let mut sender = Connection::new();
+        sender.send_ext(0, MetadataMsg::Handshake(2, 20, None));
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert!(!c.request_metadata());
+        assert!(c.send_buf().is_empty());
+    }
+
+    #[test]
+    fn check_protocol() {
+        let c = Connection::new();
+        let h = Handshake::new([0; 20], [2; 20]);
+        let bytes = h.as_bytes();
+        assert!(c.check_protocol(&bytes[..1]).is_ok());
+        assert!(c.check_protocol(&bytes[..]).is_ok());
+        assert!(c.check_protocol(b"GET / HTTP/1.1").is_err());
+        assert!(c.check_protocol(b"\x13BitTorrent_").is_err());
+        assert!(c.check_protocol(b"").is_ok());
     }
 
     #[test]
@@ -574,7 +2052,7 @@ mod tests {
         let mut c = Connection::new();
         let mut sender = Connection::new();
 
-        sender.send_ext(0, MetadataMsg::Handshake(2, 20));
+        sender.send_ext(0, MetadataMsg::Handshake(2, 20, None));
         c.recv_packet(&sender.send_buf()[4..]);
 
         assert_eq!(
@@ -587,6 +2065,7 @@ mod tests {
             }
         );
 
+        assert_eq!(c.poll_event(), Some(Event::ExtendedHandshake));
         assert_eq!(c.poll_event(), None);
 
         sender.send_ext_data(1, MetadataMsg::Data(0, 10), b"xxxxxyyyyy");
@@ -623,14 +2102,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn ext_handshake_sets_peer_reqq() {
+        let mut c = Connection::new();
+        let mut sender = Connection::new();
+        assert_eq!(c.peer_reqq(), None);
+
+        sender.send_ext(0, MetadataMsg::Handshake(2, 20, None));
+        c.recv_packet(&sender.send_buf()[4..]);
+        assert_eq!(c.peer_reqq(), Some(500));
+    }
+
     #[test]
     fn get_metadata_with_other_interleaving_msg() {
         let mut c = Connection::new();
         let mut sender = Connection::new();
 
-        sender.send_ext(0, MetadataMsg::Handshake(2, 10));
+        sender.send_ext(0, MetadataMsg::Handshake(2, 10, None));
         c.recv_packet(&sender.send_buf()[4..]);
 
+        assert_eq!(c.poll_event(), Some(Event::ExtendedHandshake));
         assert_eq!(c.poll_event(), None);
 
         // A wild choke appears
@@ -1,4 +1,34 @@
 #[derive(Debug, PartialEq)]
 pub enum Event {
     Metadata(Vec<u8>),
+
+    /// The peer's extended handshake arrived, so the ids it gave the
+    /// extensions are known from here on, see
+    /// [`peer_extension_id`](crate::conn::Connection::peer_extension_id).
+    ExtendedHandshake,
+
+    /// Extended message the connection doesn't handle itself, with the id
+    /// we advertised for its extension. Lets the embedder implement its own
+    /// extensions.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+/// Changes of the peer's choke and interest state, so that the embedder
+/// can react without polling the state after every message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The peer stopped serving our requests.
+    Choked,
+
+    /// The peer will serve our requests.
+    Unchoked,
+
+    /// The peer wants pieces we have.
+    InterestedInUs,
+
+    /// The peer no longer wants anything from us.
+    NotInterestedInUs,
 }
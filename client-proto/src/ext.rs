@@ -1,5 +1,6 @@
 use anyhow::{ensure, Context};
 use ben::{DictEncoder, Encode, Entry, Parser};
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 const METADATA_PIECE_LEN: usize = 0x4000;
 
@@ -17,13 +18,12 @@ mod msg_type {
 }
 
 impl<'a, 'p> ExtendedMessage<'a, 'p> {
-    pub fn parse(data: &'a [u8], parser: &'p mut Parser) -> anyhow::Result<Self> {
+    pub fn parse(id: u8, data: &'a [u8], parser: &'p mut Parser) -> anyhow::Result<Self> {
         ensure!(!data.is_empty(), "Unexpected EOF");
-        let id = data[0];
-        let (value, i) = parser.parse_prefix::<Entry>(&data[1..])?;
+        let (value, i) = parser.parse_prefix::<Entry>(data)?;
         debug!("ext header len: {}", value.as_raw_bytes().len());
 
-        let rest = &data[i + 1..];
+        let rest = &data[i..];
         debug!("ext data len: {}", rest.len());
         Ok(Self { id, value, rest })
     }
@@ -46,6 +46,41 @@ impl<'a, 'p> ExtendedMessage<'a, 'p> {
         Some(Metadata { id, len })
     }
 
+    /// Names of the extensions in the peer's extended handshake and the ids
+    /// the peer gave them. Extensions the peer disabled have id 0.
+    pub fn extensions(&self) -> Vec<(String, u8)> {
+        let m = match self.value.as_dict().and_then(|d| d.get_dict("m")) {
+            Some(m) => m,
+            None => return vec![],
+        };
+        m.iter()
+            .filter_map(|(name, id)| Some((name.to_owned(), id.as_int()?)))
+            .collect()
+    }
+
+    /// The peer's own IPv4 address, as sent in its extended handshake.
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        let ip: [u8; 4] = self.value.as_dict()?.get_bytes("ipv4")?.try_into().ok()?;
+        Some(ip.into())
+    }
+
+    /// The peer's own IPv6 address, as sent in its extended handshake.
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        let ip: [u8; 16] = self.value.as_dict()?.get_bytes("ipv6")?.try_into().ok()?;
+        Some(ip.into())
+    }
+
+    /// Port the peer listens on, as sent in its extended handshake.
+    pub fn listen_port(&self) -> Option<u16> {
+        self.value.as_dict()?.get_int("p").filter(|&p| p != 0)
+    }
+
+    /// Max number of outstanding requests the peer is willing to queue up,
+    /// as advertised in its extended handshake.
+    pub fn reqq(&self) -> Option<u32> {
+        self.value.as_dict()?.get_int("reqq")
+    }
+
     pub fn data(&self, expected_piece: u32) -> anyhow::Result<&'a [u8]> {
         trace!("data: {:#?}", self.value);
         let dict = self.value.as_dict().context("Not a dict")?;
@@ -72,26 +107,63 @@ pub struct Metadata {
 
 #[allow(unused)]
 #[derive(Debug)]
-pub enum MetadataMsg {
-    Handshake(u8, u32),
+pub enum MetadataMsg<'a> {
+    /// Extended handshake with the ut_metadata id, the metadata size and
+    /// our client name and version, if any.
+    Handshake(u8, u32, Option<&'a str>),
     Request(u32),
     Reject(u32),
     Data(u32, u32),
 }
 
-impl Encode for MetadataMsg {
+/// Extended handshake advertising the extensions we support.
+#[derive(Debug)]
+pub struct ExtHandshake<'a> {
+    /// Names of the extensions and the ids we gave them, which the peer
+    /// sends their messages with.
+    pub extensions: &'a [(&'a str, u8)],
+    pub metadata_size: Option<u32>,
+
+    /// Our client name and version.
+    pub version: Option<&'a str>,
+}
+
+impl Encode for ExtHandshake<'_> {
     fn encode(&self, buf: &mut Vec<u8>) {
+        let mut dict = DictEncoder::new(buf);
+        let mut extensions = self.extensions.to_vec();
+        extensions.sort_unstable();
+        let mut m = dict.insert_dict("m");
+        for (name, id) in extensions {
+            m.insert(name, i64::from(id));
+        }
+        m.finish();
+
+        if let Some(len) = self.metadata_size {
+            dict.insert("metadata_size", i64::from(len));
+        }
+        dict.insert("p", 6881);
+        dict.insert("reqq", 500);
+        if let Some(v) = self.version {
+            dict.insert("v", v);
+        }
+    }
+}
+
+impl Encode for MetadataMsg<'_> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        if let MetadataMsg::Handshake(id, len, version) = *self {
+            let handshake = ExtHandshake {
+                extensions: &[("ut_metadata", id)],
+                metadata_size: Some(len),
+                version,
+            };
+            return handshake.encode(buf);
+        }
+
         let mut dict = DictEncoder::new(buf);
         match *self {
-            MetadataMsg::Handshake(id, len) => {
-                let mut m = dict.insert_dict("m");
-                m.insert("ut_metadata", i64::from(id));
-                m.finish();
-
-                dict.insert("metadata_size", i64::from(len));
-                dict.insert("p", 6881);
-                dict.insert("reqq", 500);
-            }
+            MetadataMsg::Handshake(..) => unreachable!(),
             MetadataMsg::Request(piece) => {
                 dict.insert("msg_type", msg_type::REQUEST as i64);
                 dict.insert("piece", piece as i64);
@@ -116,7 +188,7 @@ mod tests {
     #[test]
     fn extended_new() {
         let mut parser = Parser::new();
-        let ext = ExtendedMessage::parse(&[0, b'd', b'e', 1, 2, 3, 4], &mut parser).unwrap();
+        let ext = ExtendedMessage::parse(0, &[b'd', b'e', 1, 2, 3, 4], &mut parser).unwrap();
         assert_eq!(0, ext.id);
         assert!(ext.value.is_dict());
         assert_eq!(b"de", ext.value.as_raw_bytes());
@@ -126,17 +198,80 @@ mod tests {
     #[test]
     fn extended_new_2() {
         let mut parser = Parser::new();
-        let ext = ExtendedMessage::parse(&[0, b'd', b'e'], &mut parser).unwrap();
+        let ext = ExtendedMessage::parse(0, b"de", &mut parser).unwrap();
         assert_eq!(0, ext.id);
         assert!(ext.value.is_dict());
         assert_eq!(b"de", ext.value.as_raw_bytes());
         assert!(ext.rest.is_empty());
     }
 
+    #[test]
+    fn extended_handshake_reqq() {
+        let mut parser = Parser::new();
+        let mut data = vec![];
+        MetadataMsg::Handshake(2, 100, None).encode(&mut data);
+        let ext = ExtendedMessage::parse(0, &data, &mut parser).unwrap();
+        assert_eq!(Some(500), ext.reqq());
+        assert_eq!(ext.extensions(), [("ut_metadata".to_string(), 2)]);
+    }
+
+    #[test]
+    fn extended_handshake_extensions() {
+        let mut parser = Parser::new();
+        let mut data = vec![];
+        let handshake = ExtHandshake {
+            extensions: &[("ut_metadata", 1), ("ut_comment", 2)],
+            metadata_size: None,
+            version: None,
+        };
+        handshake.encode(&mut data);
+        let ext = ExtendedMessage::parse(0, &data, &mut parser).unwrap();
+        let extensions = [
+            ("ut_comment".to_string(), 2),
+            ("ut_metadata".to_string(), 1),
+        ];
+        assert_eq!(ext.extensions(), extensions);
+        assert!(ext.metadata().is_none());
+    }
+
+    #[test]
+    fn extended_handshake_version() {
+        let mut parser = Parser::new();
+        let mut data = vec![];
+        MetadataMsg::Handshake(2, 100, Some("95th 0.1")).encode(&mut data);
+        let ext = ExtendedMessage::parse(0, &data, &mut parser).unwrap();
+        assert_eq!(Some("95th 0.1"), ext.value.as_dict().unwrap().get_str("v"));
+    }
+
+    #[test]
+    fn extended_handshake_addrs() {
+        let mut parser = Parser::new();
+        let mut data = b"d4:ipv44:".to_vec();
+        data.extend([10, 0, 0, 1]);
+        data.extend(b"4:ipv616:");
+        data.extend(Ipv6Addr::LOCALHOST.octets());
+        data.extend(b"1:pi6881ee");
+        let ext = ExtendedMessage::parse(0, &data, &mut parser).unwrap();
+        assert_eq!(ext.ipv4(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(ext.ipv6(), Some(Ipv6Addr::LOCALHOST));
+        assert_eq!(ext.listen_port(), Some(6881));
+
+        let ext = ExtendedMessage::parse(0, b"d4:ipv43:abce", &mut parser).unwrap();
+        assert_eq!(ext.ipv4(), None);
+        assert_eq!(ext.listen_port(), None);
+    }
+
+    #[test]
+    fn extended_handshake_without_reqq() {
+        let mut parser = Parser::new();
+        let ext = ExtendedMessage::parse(0, b"de", &mut parser).unwrap();
+        assert_eq!(None, ext.reqq());
+    }
+
     #[test]
     fn extended_empty() {
         let mut parser = Parser::new();
-        let err = ExtendedMessage::parse(&[], &mut parser).unwrap_err();
+        let err = ExtendedMessage::parse(0, &[], &mut parser).unwrap_err();
         assert_eq!(err.to_string(), "Unexpected EOF");
     }
 }
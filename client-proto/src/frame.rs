@@ -0,0 +1,387 @@
+use bytes::{Buf, BufMut};
+
+use crate::msg::*;
+use crate::state::Error;
+
+/// A peer wire message. Decoding and encoding a frame gives back the exact
+/// same bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame<'a> {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(&'a [u8]),
+    Request(BlockRequest),
+    Piece(PieceBlock<'a>),
+    Cancel(BlockRequest),
+
+    /// DHT port of the peer (BEP 5).
+    Port(u16),
+
+    /// Extended message (BEP 10). `payload` is the bencoded header
+    /// followed by the trailing data, if any.
+    Extended {
+        id: u8,
+        payload: &'a [u8],
+    },
+
+    /// Request for hashes of a merkle tree (BEP 52).
+    HashRequest(HashRequest),
+
+    /// Hashes of a merkle tree followed by their proof, 32 bytes each.
+    Hashes {
+        req: HashRequest,
+        hashes: &'a [u8],
+    },
+
+    /// Refusal of a hash request.
+    HashReject(HashRequest),
+
+    /// Message with an id we don't know about.
+    Unknown {
+        id: u8,
+        payload: &'a [u8],
+    },
+}
+
+impl<'a> Frame<'a> {
+    /// Decode a message without its length prefix. An empty message is a
+    /// keep-alive.
+    pub fn decode(mut data: &'a [u8]) -> Result<Self, Error> {
+        if data.is_empty() {
+            return Ok(Frame::KeepAlive);
+        }
+
+        let len = data.len();
+        let id = match MessageId::try_from(data.get_u8()) {
+            Ok(id) => id,
+            Err(id) => return Ok(Frame::Unknown { id, payload: data }),
+        };
+
+        let valid = match id.fixed_len() {
+            Some(n) => data.len() == n,
+            None if id == MessageId::Hashes => {
+                data.len() >= id.header_len() && (data.len() - id.header_len()).is_multiple_of(32)
+            }
+            None => data.len() >= id.header_len(),
+        };
+
+        if !valid {
+            let id = id.into();
+            return Err(Error::InvalidMessage { id, len });
+        }
+
+        let frame = match id {
+            MessageId::Choke => Frame::Choke,
+            MessageId::Unchoke => Frame::Unchoke,
+            MessageId::Interested => Frame::Interested,
+            MessageId::NotInterested => Frame::NotInterested,
+            MessageId::Have => Frame::Have(data.get_u32()),
+            MessageId::Bitfield => Frame::Bitfield(data),
+            MessageId::Request => Frame::Request(block_request(&mut data)),
+            MessageId::Piece => {
+                let index = data.get_u32();
+                let begin = data.get_u32();
+                Frame::Piece(PieceBlock { index, begin, data })
+            }
+            MessageId::Cancel => Frame::Cancel(block_request(&mut data)),
+            MessageId::Port => Frame::Port(data.get_u16()),
+            MessageId::Extended => {
+                let id = data.get_u8();
+                Frame::Extended { id, payload: data }
+            }
+            MessageId::HashRequest => Frame::HashRequest(hash_request(&mut data)),
+            MessageId::Hashes => {
+                let req = hash_request(&mut data);
+                Frame::Hashes { req, hashes: data }
+            }
+            MessageId::HashReject => Frame::HashReject(hash_request(&mut data)),
+        };
+
+        Ok(frame)
+    }
+
+    /// Length of the message, without the length prefix.
+    pub fn len(&self) -> usize {
+        match self {
+            Frame::KeepAlive => 0,
+            Frame::Choke | Frame::Unchoke | Frame::Interested | Frame::NotInterested => 1,
+            Frame::Have(_) => 5,
+            Frame::Bitfield(b) => 1 + b.len(),
+            Frame::Request(_) | Frame::Cancel(_) => 13,
+            Frame::Port(_) => 3,
+            Frame::Piece(p) => 9 + p.data.len(),
+            Frame::Extended { payload, .. } => 2 + payload.len(),
+            Frame::HashRequest(_) | Frame::HashReject(_) => 1 + HashRequest::LEN,
+            Frame::Hashes { hashes, .. } => 1 + HashRequest::LEN + hashes.len(),
+            Frame::Unknown { payload, .. } => 1 + payload.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the message along with its length prefix.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.reserve(4 + self.len());
+        buf.put_u32(self.len() as u32);
+
+        match self {
+            Frame::KeepAlive => {}
+            Frame::Choke => buf.put_u8(MessageId::Choke.into()),
+            Frame::Unchoke => buf.put_u8(MessageId::Unchoke.into()),
+            Frame::Interested => buf.put_u8(MessageId::Interested.into()),
+            Frame::NotInterested => buf.put_u8(MessageId::NotInterested.into()),
+            Frame::Have(index) => {
+                buf.put_u8(MessageId::Have.into());
+                buf.put_u32(*index);
+            }
+            Frame::Bitfield(b) => {
+                buf.put_u8(MessageId::Bitfield.into());
+                buf.extend_from_slice(b);
+            }
+            Frame::Request(r) => {
+                buf.put_u8(MessageId::Request.into());
+                put_block_request(buf, r);
+            }
+            Frame::Piece(p) => {
+                buf.put_u8(MessageId::Piece.into());
+                buf.put_u32(p.index);
+                buf.put_u32(p.begin);
+                buf.extend_from_slice(p.data);
+            }
+            Frame::Cancel(r) => {
+                buf.put_u8(MessageId::Cancel.into());
+                put_block_request(buf, r);
+            }
+            Frame::Port(port) => {
+                buf.put_u8(MessageId::Port.into());
+                buf.put_u16(*port);
+            }
+            Frame::Extended { id, payload } => {
+                buf.put_u8(MessageId::Extended.into());
+                buf.put_u8(*id);
+                buf.extend_from_slice(payload);
+            }
+            Frame::HashRequest(req) => {
+                buf.put_u8(MessageId::HashRequest.into());
+                put_hash_request(buf, req);
+            }
+            Frame::Hashes { req, hashes } => {
+                buf.put_u8(MessageId::Hashes.into());
+                put_hash_request(buf, req);
+                buf.extend_from_slice(hashes);
+            }
+            Frame::HashReject(req) => {
+                buf.put_u8(MessageId::HashReject.into());
+                put_hash_request(buf, req);
+            }
+            Frame::Unknown { id, payload } => {
+                buf.put_u8(*id);
+                buf.extend_from_slice(payload);
+            }
+        }
+    }
+}
+
+fn block_request(data: &mut &[u8]) -> BlockRequest {
+    BlockRequest {
+        index: data.get_u32(),
+        begin: data.get_u32(),
+        len: data.get_u32(),
+    }
+}
+
+fn put_block_request(buf: &mut Vec<u8>, r: &BlockRequest) {
+    buf.put_u32(r.index);
+    buf.put_u32(r.begin);
+    buf.put_u32(r.len);
+}
+
+fn hash_request(data: &mut &[u8]) -> HashRequest {
+    let mut pieces_root = [0; 32];
+    data.copy_to_slice(&mut pieces_root);
+    HashRequest {
+        pieces_root,
+        base_layer: data.get_u32(),
+        index: data.get_u32(),
+        length: data.get_u32(),
+        proof_layers: data.get_u32(),
+    }
+}
+
+fn put_hash_request(buf: &mut Vec<u8>, r: &HashRequest) {
+    buf.extend_from_slice(&r.pieces_root);
+    buf.put_u32(r.base_layer);
+    buf.put_u32(r.index);
+    buf.put_u32(r.length);
+    buf.put_u32(r.proof_layers);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: Frame<'_>) {
+        let mut buf = vec![];
+        frame.encode(&mut buf);
+        assert_eq!(buf.len(), 4 + frame.len());
+        assert_eq!(&buf[..4], &(frame.len() as u32).to_be_bytes());
+        assert_eq!(Frame::decode(&buf[4..]).unwrap(), frame);
+
+        // And back to the same bytes
+        let mut buf2 = vec![];
+        Frame::decode(&buf[4..]).unwrap().encode(&mut buf2);
+        assert_eq!(buf, buf2);
+    }
+
+    /// Small xorshift generator so that the property tests are repeatable.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn u32(&mut self) -> u32 {
+            self.next() as u32
+        }
+
+        fn bytes(&mut self, buf: &mut Vec<u8>, max: usize) {
+            buf.clear();
+            let len = self.next() as usize % (max + 1);
+            buf.extend((0..len).map(|_| self.next() as u8));
+        }
+    }
+
+    #[test]
+    fn round_trip_fixed() {
+        round_trip(Frame::KeepAlive);
+        round_trip(Frame::Choke);
+        round_trip(Frame::Unchoke);
+        round_trip(Frame::Interested);
+        round_trip(Frame::NotInterested);
+        round_trip(Frame::Have(7));
+        round_trip(Frame::Port(6881));
+        round_trip(Frame::Bitfield(&[]));
+        round_trip(Frame::Bitfield(&[0xff, 0x80]));
+        round_trip(Frame::Extended {
+            id: 0,
+            payload: b"d1:md11:ut_metadatai2eee",
+        });
+        round_trip(Frame::Extended {
+            id: 3,
+            payload: b"d8:msg_typei1e5:piecei0eexxxx",
+        });
+        round_trip(Frame::Unknown {
+            id: 100,
+            payload: &[1, 2, 3],
+        });
+    }
+
+    #[test]
+    fn round_trip_random() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut data = vec![];
+
+        for _ in 0..1000 {
+            rng.bytes(&mut data, 64);
+            let req = BlockRequest {
+                index: rng.u32(),
+                begin: rng.u32(),
+                len: rng.u32(),
+            };
+
+            round_trip(Frame::Have(rng.u32()));
+            round_trip(Frame::Request(req));
+            round_trip(Frame::Cancel(req));
+            round_trip(Frame::Bitfield(&data));
+            round_trip(Frame::Piece(PieceBlock {
+                index: rng.u32(),
+                begin: rng.u32(),
+                data: &data,
+            }));
+            round_trip(Frame::Extended {
+                id: rng.next() as u8,
+                payload: &data,
+            });
+
+            let hash_req = HashRequest {
+                pieces_root: [rng.next() as u8; 32],
+                base_layer: rng.u32(),
+                index: rng.u32(),
+                length: rng.u32(),
+                proof_layers: rng.u32(),
+            };
+            let whole = data.len() / 32 * 32;
+            round_trip(Frame::HashRequest(hash_req));
+            round_trip(Frame::HashReject(hash_req));
+            round_trip(Frame::Hashes {
+                req: hash_req,
+                hashes: &data[..whole],
+            });
+        }
+    }
+
+    #[test]
+    fn decode_random_bytes() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut data = vec![];
+
+        for _ in 0..10000 {
+            rng.bytes(&mut data, 20);
+            if let Some(id) = data.first_mut() {
+                // Mostly known ids
+                *id %= 24;
+            }
+
+            // Whatever decodes must encode back to the same bytes
+            if let Ok(frame) = Frame::decode(&data) {
+                let mut buf = vec![];
+                frame.encode(&mut buf);
+                assert_eq!(&buf[4..], &data[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn reject_invalid_lengths() {
+        use MessageId::*;
+
+        assert!(Frame::decode(&[Choke as u8, 0]).is_err());
+        assert!(Frame::decode(&[Have as u8, 0, 0, 1]).is_err());
+        assert!(Frame::decode(&[Request as u8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]).is_err());
+        assert!(Frame::decode(&[Piece as u8, 0, 0, 0, 1, 0, 0, 0]).is_err());
+        assert!(Frame::decode(&[Port as u8, 0x1a]).is_err());
+        assert!(Frame::decode(&[Extended as u8]).is_err());
+        assert!(Frame::decode(&[HashRequest as u8; 48]).is_err());
+
+        let mut hashes = vec![Hashes as u8];
+        hashes.extend([0; 48 + 31]);
+        assert!(Frame::decode(&hashes).is_err());
+        hashes.push(0);
+        assert!(Frame::decode(&hashes).is_ok());
+    }
+
+    #[test]
+    fn message_ids() {
+        for id in 0..=u8::MAX {
+            match MessageId::try_from(id) {
+                Ok(msg_id) => assert_eq!(u8::from(msg_id), id),
+                Err(unknown) => {
+                    assert_eq!(unknown, id);
+                    assert!(matches!(Frame::decode(&[id]), Ok(Frame::Unknown { .. })));
+                }
+            }
+        }
+        assert_eq!(MessageId::try_from(20), Ok(MessageId::Extended));
+        assert_eq!(MessageId::NotInterested.to_string(), "not-interested");
+    }
+}
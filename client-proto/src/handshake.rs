@@ -1,6 +1,43 @@
 use crate::{Extensions, InfoHash, PeerId};
 
-const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+pub const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// Protocol extensions advertised in the reserved bytes of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    /// BEP 5: the peer runs a DHT node.
+    Dht,
+    /// BEP 6: fast extension.
+    Fast,
+    /// BEP 10: extension protocol.
+    Extended,
+}
+
+impl Extension {
+    /// Byte index and mask of the reserved bit.
+    const fn bit(self) -> (usize, u8) {
+        match self {
+            Extension::Dht => (7, 0x01),
+            Extension::Fast => (7, 0x04),
+            Extension::Extended => (5, 0x10),
+        }
+    }
+
+    /// Returns true if the bit of this extension is set.
+    pub fn is_set(self, extensions: &Extensions) -> bool {
+        let (i, mask) = self.bit();
+        extensions[i] & mask != 0
+    }
+
+    pub fn set(self, extensions: &mut Extensions, enable: bool) {
+        let (i, mask) = self.bit();
+        if enable {
+            extensions[i] |= mask;
+        } else {
+            extensions[i] &= !mask;
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 #[repr(C)]
@@ -26,11 +63,15 @@ impl Handshake {
     }
 
     pub fn set_extended(&mut self, enable: bool) {
-        if enable {
-            self.extensions[5] |= 0x10;
-        } else {
-            self.extensions[5] &= !0x10;
-        }
+        Extension::Extended.set(&mut self.extensions, enable);
+    }
+
+    pub fn set_dht(&mut self, enable: bool) {
+        Extension::Dht.set(&mut self.extensions, enable);
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
     }
 
     pub fn as_bytes(&self) -> &[u8; 68] {
@@ -42,3 +83,27 @@ impl Handshake {
         self.protocol == *PROTOCOL
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extension_bits() {
+        let mut h = Handshake::new([0; 20], [0; 20]);
+        assert_eq!(&[0; 8], h.extensions());
+
+        h.set_extended(true);
+        assert_eq!(&[0, 0, 0, 0, 0, 0x10, 0, 0], h.extensions());
+        assert!(Extension::Extended.is_set(h.extensions()));
+        assert!(!Extension::Fast.is_set(h.extensions()));
+
+        let mut ext = *h.extensions();
+        Extension::Dht.set(&mut ext, true);
+        Extension::Fast.set(&mut ext, true);
+        assert_eq!([0, 0, 0, 0, 0, 0x10, 0, 0x05], ext);
+
+        h.set_extended(false);
+        assert_eq!(&[0; 8], h.extensions());
+    }
+}
@@ -4,19 +4,29 @@ extern crate tracing;
 #[macro_use]
 extern crate anyhow;
 
-pub type InfoHash = [u8; 20];
-pub type PeerId = [u8; 20];
+pub use hash20::{InfoHash, PeerId};
 pub type Extensions = [u8; 8];
 
 pub mod avg;
 pub mod bitfield;
 pub mod buf;
+pub mod clock;
 pub mod conn;
 pub mod event;
 mod ext;
+pub mod frame;
 mod handshake;
 pub mod magnet;
+pub mod merkle;
 pub mod metainfo;
 pub mod msg;
+pub mod rtt;
 mod state;
+pub mod stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod torrent;
+
+pub use handshake::Extension;
+pub use state::Error;
+pub use stats::{Activity, WireStats};
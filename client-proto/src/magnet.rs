@@ -1,19 +1,33 @@
 use std::{collections::HashSet, net::SocketAddr};
 
-use url::Url;
+use anyhow::Context;
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use url::{form_urlencoded::byte_serialize, Url};
 
-use crate::{metainfo::MetaInfo, torrent::Torrent, InfoHash};
+use crate::{
+    merkle::{self, Hash},
+    metainfo::{MetaInfo, Version},
+    torrent::Torrent,
+    InfoHash,
+};
 
 const SCHEME: &str = "magnet";
 const INFOHASH_PREFIX: &str = "urn:btih:";
 
+/// Version 2 info hash (BEP 52), a multihash of the SHA-256: code 0x12
+/// and length 0x20, followed by the hash, in hex.
+const INFOHASH_V2_PREFIX: &str = "urn:btmh:1220";
+
 const TORRENT_ID: &str = "xt";
 const DISPLAY_NAME: &str = "dn";
 const TRACKER_URL: &str = "tr";
 const PEER: &str = "x.pe";
 
 pub struct TorrentMagnet {
+    /// Info hash of the swarm to join: the `btih`, or the `btmh` truncated
+    /// to 20 bytes if that's the only one.
     pub info_hash: InfoHash,
+    pub info_hash_v2: Option<Hash>,
     pub display_name: Option<String>,
     pub tracker_urls: Vec<String>,
     pub peer_addrs: HashSet<SocketAddr>,
@@ -21,11 +35,12 @@ pub struct TorrentMagnet {
 
 impl TorrentMagnet {
     pub fn parse(uri: &str) -> anyhow::Result<Self> {
-        let url = Url::parse(uri).unwrap();
+        let url = Url::parse(uri).context("Invalid magnet URI")?;
         anyhow::ensure!(url.scheme() == SCHEME, "Incorrect scheme");
 
         let mut magnet = TorrentMagnet {
             info_hash: InfoHash::default(),
+            info_hash_v2: None,
             display_name: None,
             tracker_urls: Vec::new(),
             peer_addrs: HashSet::new(),
@@ -40,6 +55,13 @@ impl TorrentMagnet {
                         ensure!(!has_ih, "Multiple infohashes found");
                         decode_infohash(ih_str, &mut magnet.info_hash)?;
                         has_ih = true;
+                    } else if let Some(hash) = value.strip_prefix(INFOHASH_V2_PREFIX) {
+                        ensure!(magnet.info_hash_v2.is_none(), "Multiple infohashes found");
+                        ensure!(hash.len() == 64, "Invalid infohash length");
+                        let mut v2 = [0; 32];
+                        let decoded = HEXLOWER_PERMISSIVE.decode_mut(hash.as_bytes(), &mut v2);
+                        ensure!(decoded.is_ok(), "Invalid infohash");
+                        magnet.info_hash_v2 = Some(v2);
                     }
                 }
                 DISPLAY_NAME => magnet.display_name = Some(value.to_string()),
@@ -55,18 +77,79 @@ impl TorrentMagnet {
             }
         }
 
-        anyhow::ensure!(has_ih, "No infohash found");
+        match magnet.info_hash_v2 {
+            Some(v2) if !has_ih => magnet.info_hash.copy_from_slice(&v2[..20]),
+            _ => anyhow::ensure!(has_ih, "No infohash found"),
+        }
         Ok(magnet)
     }
 
+    /// Magnet URI for this torrent.
+    pub fn to_uri(&self) -> String {
+        // Version 2 only torrents have no v1 info hash
+        let v2_only = self
+            .info_hash_v2
+            .is_some_and(|v2| v2[..20] == self.info_hash[..]);
+        let mut ids = vec![];
+        if !v2_only {
+            ids.push(INFOHASH_PREFIX.to_owned() + &HEXLOWER.encode(&self.info_hash));
+        }
+        if let Some(v2) = &self.info_hash_v2 {
+            ids.push(INFOHASH_V2_PREFIX.to_owned() + &HEXLOWER.encode(v2));
+        }
+        let ids: Vec<_> = ids
+            .iter()
+            .map(|id| format!("{}={}", TORRENT_ID, id))
+            .collect();
+        let mut uri = format!("{}:?{}", SCHEME, ids.join("&"));
+
+        let mut push = |key: &str, value: &str| {
+            uri.push('&');
+            uri.push_str(key);
+            uri.push('=');
+            uri.extend(byte_serialize(value.as_bytes()));
+        };
+
+        if let Some(name) = &self.display_name {
+            push(DISPLAY_NAME, name);
+        }
+
+        for url in &self.tracker_urls {
+            push(TRACKER_URL, url);
+        }
+
+        for addr in &self.peer_addrs {
+            push(PEER, &addr.to_string());
+        }
+
+        uri
+    }
+
     pub fn with_metadata(self, metadata: MetaInfo) -> Torrent {
+        let info_hash_v2 = self
+            .info_hash_v2
+            .or_else(|| (metadata.version != Version::V1).then(|| merkle::sha256(&metadata.raw)));
+
+        // Hybrid torrents are checked with their SHA-1 hashes, as the
+        // piece layers of their files aren't fetched
+        let version = match metadata.version {
+            Version::V2 => Version::V2,
+            _ => Version::V1,
+        };
         Torrent {
             info_hash: self.info_hash,
+            info_hash_v2,
             length: metadata.length,
             name: metadata.name.or(self.display_name).unwrap_or_default(),
+            version,
             piece_hashes: metadata.pieces,
+            piece_roots: metadata.piece_roots,
             piece_len: metadata.piece_len,
+            files: metadata.files,
+            private: metadata.private,
             tracker_urls: self.tracker_urls,
+            url_list: vec![],
+            http_seeds: vec![],
             peers: HashSet::new(),
             peers_v6: HashSet::new(),
         }
@@ -74,12 +157,12 @@ impl TorrentMagnet {
 }
 
 fn decode_infohash(encoded: &str, info_hash: &mut InfoHash) -> anyhow::Result<()> {
-    use data_encoding::{BASE32, HEXLOWER_PERMISSIVE as HEX};
+    use data_encoding::BASE32;
 
     let encoded = encoded.as_bytes();
 
     let result = match encoded.len() {
-        40 => HEX.decode_mut(encoded, info_hash),
+        40 => HEXLOWER_PERMISSIVE.decode_mut(encoded, info_hash),
         32 => BASE32.decode_mut(encoded, info_hash),
         _ => bail!("Invalid infohash length"),
     };
@@ -87,3 +170,75 @@ fn decode_infohash(encoded: &str, info_hash: &mut InfoHash) -> anyhow::Result<()
     ensure!(result.is_ok(), "Invalid infohash");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_uri() {
+        let magnet = TorrentMagnet {
+            info_hash: [0xab; 20],
+            info_hash_v2: None,
+            display_name: Some("foo bar".into()),
+            tracker_urls: vec!["udp://tracker.example.com:80/announce".into()],
+            peer_addrs: HashSet::new(),
+        };
+
+        assert_eq!(
+            magnet.to_uri(),
+            "magnet:?xt=urn:btih:abababababababababababababababababababab\
+             &dn=foo+bar\
+             &tr=udp%3A%2F%2Ftracker.example.com%3A80%2Fannounce"
+        );
+    }
+
+    #[test]
+    fn to_uri_parse() {
+        let mut magnet = TorrentMagnet {
+            info_hash: [7; 20],
+            info_hash_v2: None,
+            display_name: Some("a&b=c".into()),
+            tracker_urls: vec![
+                "http://a.com/announce?x=1&y=2".into(),
+                "udp://b.com:80".into(),
+            ],
+            peer_addrs: HashSet::new(),
+        };
+        magnet.peer_addrs.insert("[::1]:6881".parse().unwrap());
+
+        let parsed = TorrentMagnet::parse(&magnet.to_uri()).unwrap();
+        assert_eq!(parsed.info_hash, magnet.info_hash);
+        assert_eq!(parsed.display_name, magnet.display_name);
+        assert_eq!(parsed.tracker_urls, magnet.tracker_urls);
+        assert_eq!(parsed.peer_addrs, magnet.peer_addrs);
+    }
+
+    #[test]
+    fn v2_info_hash() {
+        let v2 = "1220".to_owned() + &"cd".repeat(32);
+        let magnet = TorrentMagnet::parse(&format!("magnet:?xt=urn:btmh:{}", v2)).unwrap();
+        assert_eq!(magnet.info_hash_v2, Some([0xcd; 32]));
+        assert_eq!(magnet.info_hash, [0xcd; 20]);
+        assert_eq!(magnet.to_uri(), format!("magnet:?xt=urn:btmh:{}", v2));
+
+        // Hybrid torrents join the v1 swarm
+        let uri = format!("magnet:?xt=urn:btih:{}&xt=urn:btmh:{}", "ab".repeat(20), v2);
+        let magnet = TorrentMagnet::parse(&uri).unwrap();
+        assert_eq!(magnet.info_hash, [0xab; 20]);
+        assert_eq!(magnet.info_hash_v2, Some([0xcd; 32]));
+        assert_eq!(magnet.to_uri(), uri);
+
+        // Only SHA-256 multihashes
+        let sha1 = "1114".to_owned() + &"cd".repeat(20);
+        assert!(TorrentMagnet::parse(&format!("magnet:?xt=urn:btmh:{}", sha1)).is_err());
+        assert!(TorrentMagnet::parse(&format!("magnet:?xt=urn:btmh:{}", &v2[..60])).is_err());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(TorrentMagnet::parse("not a uri").is_err());
+        assert!(TorrentMagnet::parse("http://a.com/?xt=urn:btih:").is_err());
+        assert!(TorrentMagnet::parse("magnet:?dn=foo").is_err());
+    }
+}
@@ -0,0 +1,507 @@
+//! Merkle trees of version 2 torrents (BEP 52).
+//!
+//! Each file is hashed as a binary tree whose leaves are the SHA-256 of its
+//! 16 KiB blocks. The tree is padded up to a power of two leaves with zero
+//! hashes. The roots of the subtrees covering one piece each make the
+//! piece layer of the file.
+
+use crate::msg::HashRequest;
+use sha2::{Digest, Sha256};
+
+/// Size of the blocks the leaves are the hashes of.
+pub const BLOCK_SIZE: usize = 0x4000;
+
+/// Max number of hashes asked for with one `hash request`.
+pub const MAX_REQUEST_HASHES: usize = 512;
+
+pub type Hash = [u8; 32];
+
+pub fn sha256(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+/// Root of a subtree of `2^height` leaves which are all zero hashes, i.e.
+/// the padding of a tree at that height.
+pub fn pad_hash(height: u32) -> Hash {
+    (0..height).fold([0; 32], |pad, _| hash_pair(&pad, &pad))
+}
+
+/// Root of the tree over `layer`, padded with `pad` up to `width` hashes.
+/// `width` must be a power of two and not less than the number of hashes.
+pub fn root(mut layer: Vec<Hash>, width: usize, pad: Hash) -> Hash {
+    debug_assert!(width.is_power_of_two() && layer.len() <= width);
+
+    let mut pad = pad;
+    let mut width = width;
+    while width > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(pad);
+        }
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// Root of the tree over the blocks of `data`, padded up to `width` leaves.
+pub fn data_root(data: &[u8], width: usize) -> Hash {
+    let leaves = data.chunks(BLOCK_SIZE).map(sha256).collect();
+    root(leaves, width, [0; 32])
+}
+
+/// Whether `leaf` is leaf `index` of the tree with the given root. `proof`
+/// holds the sibling hashes from the leaf up to the root, as in the
+/// `hashes` replies of BEP 52.
+pub fn verify_proof(leaf: Hash, index: usize, proof: &[Hash], root: &Hash) -> bool {
+    let mut hash = leaf;
+    let mut index = index;
+    for sibling in proof {
+        hash = if index.is_multiple_of(2) {
+            hash_pair(&hash, sibling)
+        } else {
+            hash_pair(sibling, &hash)
+        };
+        index /= 2;
+    }
+    index == 0 && hash == *root
+}
+
+/// Requests for the piece layer of a file whose tree has the root
+/// `pieces_root`, with `num_pieces` pieces of `piece_len`. Each covers
+/// up to [`MAX_REQUEST_HASHES`] of the layer along with their proof.
+pub fn layer_requests(pieces_root: Hash, piece_len: usize, num_pieces: usize) -> Vec<HashRequest> {
+    let width = num_pieces.next_power_of_two();
+    let length = width.min(MAX_REQUEST_HASHES);
+    let base_layer = (piece_len / BLOCK_SIZE).trailing_zeros();
+    let proof_layers = (width / length).trailing_zeros();
+    (0..num_pieces)
+        .step_by(length)
+        .map(|index| HashRequest {
+            pieces_root,
+            base_layer,
+            index: index as u32,
+            length: length as u32,
+            proof_layers,
+        })
+        .collect()
+}
+
+/// The hashes of a `hashes` reply to `req` if they're part of the tree of
+/// `req.pieces_root`. `data` holds `req.length` hashes followed by the
+/// uncle hashes from their subtree up to the root.
+pub fn verify_hashes(req: &HashRequest, data: &[u8]) -> Option<Vec<Hash>> {
+    let length = req.length as usize;
+    let index = req.index as usize;
+    if !length.is_power_of_two()
+        || !index.is_multiple_of(length)
+        || data.len() != (length + req.proof_layers as usize) * 32
+    {
+        return None;
+    }
+
+    let mut hashes: Vec<Hash> = data
+        .chunks_exact(32)
+        .map(|h| h.try_into().unwrap())
+        .collect();
+    let proof = hashes.split_off(length);
+    let subtree = root(hashes.clone(), length, [0; 32]);
+    verify_proof(subtree, index / length, &proof, &req.pieces_root).then_some(hashes)
+}
+
+/// The `hashes` reply to `req` from `layer`, the hashes at
+/// `req.base_layer` of a file's tree, which is `width` hashes wide at that
+/// layer and padded with `pad`. `None` if the request doesn't fit the tree.
+pub fn layer_hashes(req: &HashRequest, layer: &[Hash], width: usize, pad: Hash) -> Option<Vec<u8>> {
+    let length = req.length as usize;
+    let index = req.index as usize;
+    if !length.is_power_of_two()
+        || length > width
+        || !index.is_multiple_of(length)
+        || index >= width
+        || req.proof_layers > (width / length).trailing_zeros()
+    {
+        return None;
+    }
+
+    let mut data = Vec::with_capacity((length + req.proof_layers as usize) * 32);
+    for i in index..index + length {
+        data.extend(layer.get(i).unwrap_or(&pad));
+    }
+
+    // Uncles from the subtree of the requested hashes up to the root
+    let mut level: Vec<Hash> = layer
+        .chunks(length)
+        .map(|c| root(c.to_vec(), length, pad))
+        .collect();
+    let mut pad = (0..length.trailing_zeros()).fold(pad, |pad, _| hash_pair(&pad, &pad));
+    let mut pos = index / length;
+    for _ in 0..req.proof_layers {
+        data.extend(level.get(pos ^ 1).unwrap_or(&pad));
+        level = level
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        pos /= 2;
+    }
+    Some(data)
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Merkle root of one piece of a version 2 torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceRoot {
+    pub root: Hash,
+
+    /// Bytes of file data in the piece. The rest of the piece, if any, is
+    /// padding up to the next file.
+    pub len: usize,
+
+    /// Number of leaves of the subtree, a power of two.
+    pub width: usize,
+
+    /// Root of the tree of the file, its `pieces root`.
+    pub pieces_root: Hash,
+
+    /// Index of the piece in the file.
+    pub file_index: usize,
+
+    /// Number of leaves of the tree of the file.
+    pub file_width: usize,
+}
+
+impl PieceRoot {
+    /// Whether `data` is the piece. Past the file data it must be zeros.
+    pub fn verify(&self, data: &[u8]) -> bool {
+        if data.len() < self.len || data[self.len..].iter().any(|&b| b != 0) {
+            return false;
+        }
+        data_root(&data[..self.len], self.width) == self.root
+    }
+
+    /// Request for the hashes of the blocks of the piece, with their proof
+    /// up to the root of the file. `None` for a piece of a single block,
+    /// which is its own hash.
+    pub fn blocks_request(&self) -> Option<HashRequest> {
+        if self.width < 2 {
+            return None;
+        }
+        Some(HashRequest {
+            pieces_root: self.pieces_root,
+            base_layer: 0,
+            index: (self.file_index * self.width) as u32,
+            length: self.width as u32,
+            proof_layers: (self.file_width / self.width).trailing_zeros(),
+        })
+    }
+
+    /// Which blocks of `data`, the piece, match the `hashes` replied to
+    /// [`blocks_request`](Self::blocks_request). `None` if they aren't the
+    /// hashes of the piece.
+    pub fn check_blocks(&self, hashes: &[u8], data: &[u8]) -> Option<Vec<bool>> {
+        let leaves = verify_hashes(&self.blocks_request()?, hashes)?;
+        if root(leaves.clone(), self.width, [0; 32]) != self.root {
+            return None;
+        }
+
+        let checked = data
+            .chunks(BLOCK_SIZE)
+            .enumerate()
+            .map(|(i, block)| {
+                // Past the file data the piece is padding
+                let start = i * BLOCK_SIZE;
+                let file_len = self.len.saturating_sub(start).min(block.len());
+                let (file, pad) = block.split_at(file_len);
+                (file.is_empty() || sha256(file) == leaves[i]) && pad.iter().all(|&b| b == 0)
+            })
+            .collect();
+        Some(checked)
+    }
+}
+
+/// Roots of the pieces of a file of `len` bytes, from its `pieces root` and
+/// its piece layer. Files of one piece or less have no piece layer, the
+/// pieces root being the root of their only piece. Returns `None` if the
+/// layer doesn't add up to the pieces root.
+pub fn piece_roots(
+    piece_len: usize,
+    len: u64,
+    pieces_root: Hash,
+    layer: Option<&[u8]>,
+) -> Option<Vec<PieceRoot>> {
+    let piece_width = piece_len / BLOCK_SIZE;
+    if len <= piece_len as u64 {
+        let len = len as usize;
+        let width = len.div_ceil(BLOCK_SIZE).next_power_of_two();
+        return Some(vec![PieceRoot {
+            root: pieces_root,
+            len,
+            width,
+            pieces_root,
+            file_index: 0,
+            file_width: width,
+        }]);
+    }
+
+    let layer = layer?;
+    let num_pieces = usize::try_from(len.div_ceil(piece_len as u64)).ok()?;
+    if layer.len() != num_pieces * 32 {
+        return None;
+    }
+
+    let hashes: Vec<Hash> = layer
+        .chunks_exact(32)
+        .map(|h| h.try_into().unwrap())
+        .collect();
+    let pad = pad_hash(piece_width.trailing_zeros());
+    if root(hashes.clone(), num_pieces.next_power_of_two(), pad) != pieces_root {
+        return None;
+    }
+
+    let mut left = len;
+    let file_width = num_pieces.next_power_of_two() * piece_width;
+    let roots = hashes
+        .into_iter()
+        .enumerate()
+        .map(|(file_index, root)| {
+            let n = left.min(piece_len as u64);
+            left -= n;
+            PieceRoot {
+                root,
+                len: n as usize,
+                width: piece_width,
+                pieces_root,
+                file_index,
+                file_width,
+            }
+        })
+        .collect();
+    Some(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::HEXLOWER;
+
+    #[test]
+    fn sha256_digest() {
+        assert_eq!(
+            HEXLOWER.encode(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn padding() {
+        let a = sha256(b"a");
+        let b = sha256(b"b");
+        let ab = hash_pair(&a, &b);
+        let zero = [0; 32];
+        assert_eq!(root(vec![a], 1, zero), a);
+        assert_eq!(root(vec![a, b], 2, zero), ab);
+        assert_eq!(root(vec![a, b], 4, zero), hash_pair(&ab, &pad_hash(1)));
+        assert_eq!(root(vec![], 2, zero), pad_hash(1));
+        assert_eq!(root(vec![a], 2, pad_hash(3)), hash_pair(&a, &pad_hash(3)));
+    }
+
+    #[test]
+    fn proofs() {
+        let leaves: Vec<_> = (0..5u8).map(|i| sha256(&[i])).collect();
+        let zero = [0; 32];
+        let l01 = hash_pair(&leaves[0], &leaves[1]);
+        let l23 = hash_pair(&leaves[2], &leaves[3]);
+        let l45 = hash_pair(&leaves[4], &zero);
+        let right = hash_pair(&l45, &pad_hash(1));
+        let tree = root(leaves.clone(), 8, zero);
+        assert_eq!(tree, hash_pair(&hash_pair(&l01, &l23), &right));
+
+        let proof = [leaves[2], l01, right];
+        assert!(verify_proof(leaves[3], 3, &proof, &tree));
+        assert!(!verify_proof(leaves[3], 2, &proof, &tree));
+        assert!(!verify_proof(leaves[2], 3, &proof, &tree));
+    }
+
+    #[test]
+    fn hashes_replies() {
+        let piece_len = BLOCK_SIZE;
+        let layer: Vec<Hash> = (0..3u8).map(|i| sha256(&[i])).collect();
+        let pieces_root = root(layer.clone(), 4, [0; 32]);
+        let reqs = layer_requests(pieces_root, piece_len, 3);
+        assert_eq!(reqs.len(), 1);
+        assert_eq!((reqs[0].length, reqs[0].proof_layers), (4, 0));
+
+        let mut data: Vec<u8> = layer.iter().flatten().copied().collect();
+        data.extend([0; 32]);
+        let hashes = verify_hashes(&reqs[0], &data).unwrap();
+        assert_eq!(&hashes[..3], &layer[..]);
+
+        data[0] ^= 1;
+        assert!(verify_hashes(&reqs[0], &data).is_none());
+        assert!(verify_hashes(&reqs[0], &data[32..]).is_none());
+    }
+
+    #[test]
+    fn hashes_with_proof() {
+        let num_pieces = MAX_REQUEST_HASHES + 1;
+        let piece_len = BLOCK_SIZE * 4;
+        let layer: Vec<Hash> = (0..num_pieces).map(|i| sha256(&i.to_be_bytes())).collect();
+        let pad = pad_hash(2);
+        let pieces_root = root(layer.clone(), 2 * MAX_REQUEST_HASHES, pad);
+
+        let reqs = layer_requests(pieces_root, piece_len, num_pieces);
+        assert_eq!(reqs.len(), 2);
+        assert_eq!(reqs[1].index as usize, MAX_REQUEST_HASHES);
+        assert_eq!((reqs[1].base_layer, reqs[1].proof_layers), (2, 1));
+
+        // The second half is one hash and padding, proven by the first
+        let first = root(
+            layer[..MAX_REQUEST_HASHES].to_vec(),
+            MAX_REQUEST_HASHES,
+            pad,
+        );
+        let mut data = layer[MAX_REQUEST_HASHES].to_vec();
+        for _ in 1..MAX_REQUEST_HASHES {
+            data.extend(pad);
+        }
+        data.extend(first);
+        let hashes = verify_hashes(&reqs[1], &data).unwrap();
+        assert_eq!(hashes[0], layer[MAX_REQUEST_HASHES]);
+
+        // Not the proof of the first half
+        let wrong = HashRequest {
+            index: 0,
+            ..reqs[1]
+        };
+        assert!(verify_hashes(&wrong, &data).is_none());
+    }
+
+    #[test]
+    fn serve_layer_hashes() {
+        let num_pieces = MAX_REQUEST_HASHES + 3;
+        let piece_len = BLOCK_SIZE * 4;
+        let layer: Vec<Hash> = (0..num_pieces).map(|i| sha256(&i.to_be_bytes())).collect();
+        let pad = pad_hash(2);
+        let width = num_pieces.next_power_of_two();
+        let pieces_root = root(layer.clone(), width, pad);
+
+        for req in layer_requests(pieces_root, piece_len, num_pieces) {
+            let data = layer_hashes(&req, &layer, width, pad).unwrap();
+            let hashes = verify_hashes(&req, &data).unwrap();
+            let index = req.index as usize;
+            assert_eq!(hashes[0], layer[index]);
+        }
+
+        let req = HashRequest {
+            pieces_root,
+            base_layer: 2,
+            index: 4,
+            length: 4,
+            proof_layers: 8,
+        };
+        let data = layer_hashes(&req, &layer, width, pad).unwrap();
+        assert_eq!(verify_hashes(&req, &data).unwrap(), layer[4..8]);
+
+        // Past the tree
+        let bad = [
+            HashRequest { index: 2, ..req },
+            HashRequest { length: 3, ..req },
+            HashRequest {
+                proof_layers: 9,
+                ..req
+            },
+            HashRequest {
+                index: width as u32,
+                ..req
+            },
+        ];
+        for req in bad {
+            assert!(layer_hashes(&req, &layer, width, pad).is_none());
+        }
+    }
+
+    #[test]
+    fn check_blocks() {
+        let piece_len = BLOCK_SIZE * 4;
+        let data: Vec<u8> = (0..piece_len * 2 + 100).map(|i| (i / 1000) as u8).collect();
+        let file_root = data_root(&data, 16);
+        let layer: Vec<u8> = data
+            .chunks(piece_len)
+            .flat_map(|piece| data_root(piece, 4))
+            .collect();
+        let roots = piece_roots(piece_len, data.len() as u64, file_root, Some(&layer)).unwrap();
+
+        // Blocks of the last piece, padded, with the proof from the layer
+        let last = &roots[2];
+        let req = last.blocks_request().unwrap();
+        assert_eq!((req.index, req.length, req.proof_layers), (8, 4, 2));
+        let leaves: Vec<Hash> = data.chunks(BLOCK_SIZE).map(sha256).collect();
+        let width = 16;
+        let hashes = layer_hashes(&req, &leaves, width, [0; 32]).unwrap();
+
+        let mut piece = data[piece_len * 2..].to_vec();
+        piece.resize(piece_len, 0);
+        assert_eq!(
+            last.check_blocks(&hashes, &piece).unwrap(),
+            [true, true, true, true]
+        );
+        piece[10] ^= 1;
+        piece[BLOCK_SIZE * 3] = 1;
+        assert_eq!(
+            last.check_blocks(&hashes, &piece).unwrap(),
+            [false, true, true, false]
+        );
+
+        // Hashes of another piece
+        assert!(roots[1].check_blocks(&hashes, &piece).is_none());
+    }
+
+    #[test]
+    fn layer_adds_up_to_file_root() {
+        let piece_len = BLOCK_SIZE * 2;
+        let data = vec![9; piece_len * 2 + 100];
+        let file_root = data_root(&data, 8);
+
+        let layer: Vec<u8> = data
+            .chunks(piece_len)
+            .flat_map(|piece| data_root(piece, 2))
+            .collect();
+        let roots = piece_roots(piece_len, data.len() as u64, file_root, Some(&layer)).unwrap();
+        assert_eq!(roots.len(), 3);
+        assert_eq!(roots[2].len, 100);
+        for (root, piece) in roots.iter().zip(data.chunks(piece_len)) {
+            assert!(root.verify(piece));
+        }
+
+        // Padding up to the next file is fine, other data isn't
+        let mut padded = data[piece_len * 2..].to_vec();
+        padded.resize(piece_len, 0);
+        assert!(roots[2].verify(&padded));
+        padded[200] = 1;
+        assert!(!roots[2].verify(&padded));
+
+        assert!(piece_roots(piece_len, data.len() as u64, file_root, Some(&layer[32..])).is_none());
+        assert!(piece_roots(piece_len, data.len() as u64, [0; 32], Some(&layer)).is_none());
+        assert!(piece_roots(piece_len, data.len() as u64, file_root, None).is_none());
+    }
+
+    #[test]
+    fn small_file() {
+        let data = vec![3; BLOCK_SIZE * 3];
+        let roots =
+            piece_roots(BLOCK_SIZE * 8, data.len() as u64, data_root(&data, 4), None).unwrap();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].width, 4);
+        assert!(roots[0].verify(&data));
+    }
+}
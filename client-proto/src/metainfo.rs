@@ -1,12 +1,395 @@
+use crate::merkle::{self, Hash, PieceRoot};
+use crate::msg::HashRequest;
+use crate::InfoHash;
 use anyhow::Context;
 use ben::{decode::Dict, Parser};
+use sha1::Sha1;
+use std::collections::HashMap;
 use thiserror::Error;
 
+/// Piece layers of the files of a version 2 torrent, by the roots of the
+/// files.
+pub type PieceLayers = Vec<(Hash, Vec<u8>)>;
+
 pub struct MetaInfo {
     pub name: Option<String>,
+
+    /// Total length of the files, padding files included.
     pub length: usize,
     pub piece_len: usize,
+
+    /// SHA-1 hashes of the pieces. Empty for version 2 torrents, whose
+    /// pieces are checked with `piece_roots`.
     pub pieces: Vec<u8>,
+
+    /// Files of a multi-file torrent. Empty for single-file torrents.
+    pub files: Vec<FileInfo>,
+
+    /// Private torrent (BEP 27), whose peers only come from its trackers.
+    pub private: bool,
+
+    /// Version of the torrent. Hybrid torrents are downloaded like version
+    /// 1 torrents, as their piece layers don't come with the metadata.
+    pub version: Version,
+
+    /// Merkle roots of the pieces of a version 2 torrent.
+    pub piece_roots: Vec<PieceRoot>,
+
+    /// Piece layers of a version 2 torrent by the root of their file,
+    /// fetched from the peers along with the info dictionary.
+    pub piece_layers: PieceLayers,
+
+    /// The info dictionary as parsed, whose SHA-1, or SHA-256 for version
+    /// 2 torrents, is the info hash.
+    pub raw: Vec<u8>,
+}
+
+/// Whether `info` is the info dictionary of the torrent: its SHA-1 is the
+/// info hash, or its SHA-256 truncated to 20 bytes for torrents found
+/// through their version 2 info hash.
+pub fn is_info_of(info: &[u8], info_hash: &InfoHash) -> bool {
+    Sha1::from(info).digest().bytes() == *info_hash || merkle::sha256(info)[..20] == info_hash[..]
+}
+
+/// Piece layer of a file of a version 2 torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceLayer {
+    /// Root of the file's merkle tree.
+    pub pieces_root: Hash,
+    pub piece_len: usize,
+    pub num_pieces: usize,
+}
+
+impl PieceLayer {
+    /// Requests for the hashes of the layer, along with their proof.
+    pub fn requests(&self) -> Vec<HashRequest> {
+        merkle::layer_requests(self.pieces_root, self.piece_len, self.num_pieces)
+    }
+}
+
+/// Piece layers needed to download a version 2 torrent from its info
+/// dictionary, which come with torrent files but not with the metadata of
+/// the peers: those of the files of more than one piece. Empty for the
+/// other versions. Torrents over the `limits` are rejected.
+pub fn missing_layers(
+    data: &[u8],
+    parser: &mut Parser,
+    limits: &TorrentLimits,
+) -> anyhow::Result<Vec<PieceLayer>> {
+    use ParseError::*;
+    // File names needn't be UTF-8
+    parser.binary_keys(true);
+    let info = parser.parse::<Dict>(data)?;
+    if Version::of(&info) != Version::V2 {
+        return Ok(vec![]);
+    }
+
+    let piece_len: usize = info.get_int("piece length").context(PieceLengthRequired)?;
+    let tree = info.get_dict("file tree").context(InvalidFileTree)?;
+    let mut files = vec![];
+    parse_file_tree(&tree, &mut vec![], &mut files, 0)?;
+
+    let length = files
+        .iter()
+        .try_fold(0u64, |sum, f| sum.checked_add(f.length))
+        .and_then(|len| usize::try_from(len).ok())
+        .context(TooLarge)?;
+    limits.check(piece_len, length, 0)?;
+    ensure!(piece_len >= merkle::BLOCK_SIZE, InvalidPieceLength);
+
+    let mut layers = vec![];
+    for file in files.into_iter().filter(|f| f.length > piece_len as u64) {
+        layers.push(PieceLayer {
+            pieces_root: file.pieces_root.context(InvalidFileTree)?,
+            piece_len,
+            num_pieces: file.length.div_ceil(piece_len as u64) as usize,
+        });
+    }
+    Ok(layers)
+}
+
+/// File of a multi-file torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// Path within the torrent's directory, one component per element.
+    pub path: Vec<String>,
+    pub length: u64,
+
+    /// Padding file (BEP 47), which only aligns the next file to a piece
+    /// boundary. It's all zeros, so it's neither shown nor stored.
+    pub padding: bool,
+}
+
+/// Bounds on the size of the torrents accepted from torrent files and from
+/// the metadata of peers, so that a crafted info dictionary can't make us
+/// allocate for an absurd number of pieces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TorrentLimits {
+    pub max_piece_len: usize,
+    pub max_pieces: usize,
+
+    /// Total length of the files, padding files included.
+    pub max_length: u64,
+
+    /// Length of the info dictionary fetched from the peers (BEP 9), which
+    /// is buffered until it's complete.
+    pub max_metadata_len: usize,
+}
+
+impl Default for TorrentLimits {
+    fn default() -> Self {
+        Self {
+            max_piece_len: 256 * 1024 * 1024,
+            max_pieces: 1 << 21,
+            max_length: 1 << 45,
+            max_metadata_len: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl TorrentLimits {
+    /// Check the layout of a torrent with `num_pieces` hashes against the
+    /// limits. The number of pieces implied by the lengths is checked too,
+    /// as that's what the pieces are allocated for.
+    pub(crate) fn check(
+        &self,
+        piece_len: usize,
+        length: usize,
+        num_pieces: usize,
+    ) -> anyhow::Result<()> {
+        use ParseError::*;
+        ensure!(
+            piece_len > 0 && piece_len <= self.max_piece_len,
+            InvalidPieceLength
+        );
+        ensure!(length as u64 <= self.max_length, TooLarge);
+
+        let implied = length.div_ceil(piece_len);
+        ensure!(
+            num_pieces <= self.max_pieces && implied <= self.max_pieces,
+            TooLarge
+        );
+        Ok(())
+    }
+}
+
+/// Check the SHA-1 piece hashes of a version 1 torrent against its layout:
+/// one 20 byte hash for each piece of `piece_len` in `length`. The piece
+/// length must be checked to be non-zero already.
+pub(crate) fn check_pieces(pieces: &[u8], piece_len: usize, length: usize) -> anyhow::Result<()> {
+    use ParseError::*;
+    ensure!(pieces.len().is_multiple_of(20), InvalidPieces(pieces.len()));
+
+    let expected = length.div_ceil(piece_len);
+    let actual = pieces.len() / 20;
+    ensure!(actual == expected, PieceCountMismatch { expected, actual });
+    Ok(())
+}
+
+/// Version of the metadata of a torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// SHA-1 hashes of the pieces (BEP 3).
+    V1,
+
+    /// Merkle trees of the files (BEP 52).
+    V2,
+
+    /// Both, so that the torrent is shared in either swarm.
+    Hybrid,
+}
+
+impl Version {
+    pub(crate) fn of(info: &Dict) -> Self {
+        let v2 =
+            info.get_int::<i64>("meta version") == Some(2) && info.get_dict("file tree").is_some();
+        match (v2, info.get("pieces").is_some()) {
+            (false, _) => Version::V1,
+            (true, false) => Version::V2,
+            (true, true) => Version::Hybrid,
+        }
+    }
+}
+
+/// Files of a version 2 torrent laid out in pieces, and the merkle roots
+/// of the pieces.
+pub(crate) struct Layout {
+    /// Files with padding files after them up to the next piece boundary,
+    /// as each file starts a new piece. Empty for single-file torrents.
+    pub files: Vec<FileInfo>,
+
+    /// Total length of the files, padding files included.
+    pub length: usize,
+    pub piece_roots: Vec<PieceRoot>,
+}
+
+/// Piece layers of a torrent file keyed by the roots of their files.
+pub(crate) fn layer_map<'b>(piece_layers: Option<Dict<'b, '_>>) -> HashMap<&'b [u8], &'b [u8]> {
+    piece_layers
+        .iter()
+        .flat_map(|d| d.raw_iter())
+        .filter_map(|(k, v)| Some((k, v.as_bytes()?)))
+        .collect()
+}
+
+/// Lay out the `file tree` of a version 2 torrent, checking the piece
+/// `layers` against the roots of the files.
+pub(crate) fn parse_v2(info: &Dict, layers: &HashMap<&[u8], &[u8]>) -> anyhow::Result<Layout> {
+    use ParseError::*;
+
+    let piece_len: usize = info.get_int("piece length").context(PieceLengthRequired)?;
+    ensure!(
+        piece_len.is_power_of_two() && piece_len >= merkle::BLOCK_SIZE,
+        InvalidFileTree
+    );
+
+    let tree = info.get_dict("file tree").context(InvalidFileTree)?;
+    let mut tree_files = vec![];
+    parse_file_tree(&tree, &mut vec![], &mut tree_files, 0)?;
+
+    let name = info.get_str("name").unwrap_or_default();
+    let single = matches!(&tree_files[..], [f] if f.path == [name]);
+
+    let mut files = vec![];
+    let mut piece_roots = vec![];
+    let mut length: usize = 0;
+    let last = tree_files.iter().rposition(|f| f.length > 0);
+    for (i, file) in tree_files.into_iter().enumerate() {
+        let len = file.length;
+        if len > 0 {
+            let root = file.pieces_root.context(InvalidFileTree)?;
+            let layer = layers.get(&root[..]).copied();
+            let roots =
+                merkle::piece_roots(piece_len, len, root, layer).context(InvalidPieceLayers)?;
+            piece_roots.extend(roots);
+        }
+
+        length = usize::try_from(len)
+            .ok()
+            .and_then(|len| length.checked_add(len))
+            .context(InvalidFile)?;
+        files.push(FileInfo {
+            path: file.path,
+            length: len,
+            padding: false,
+        });
+
+        let pad = (piece_len - length % piece_len) % piece_len;
+        if len > 0 && pad > 0 && Some(i) != last {
+            length += pad;
+            files.push(FileInfo {
+                path: vec![".pad".into(), pad.to_string()],
+                length: pad as u64,
+                padding: true,
+            });
+        }
+    }
+
+    if single {
+        files.clear();
+    }
+    Ok(Layout {
+        files,
+        length,
+        piece_roots,
+    })
+}
+
+/// File of a version 2 torrent as listed in its `file tree`.
+struct TreeFile {
+    path: Vec<String>,
+    length: u64,
+
+    /// Merkle root of the file, absent for empty files
+    pieces_root: Option<Hash>,
+}
+
+/// Directories are deeper than this only in crafted torrents.
+const MAX_TREE_DEPTH: usize = 64;
+
+/// Collect the files of the `file tree` in the order of the tree, which is
+/// the order of the pieces.
+fn parse_file_tree(
+    dir: &Dict,
+    path: &mut Vec<String>,
+    files: &mut Vec<TreeFile>,
+    depth: usize,
+) -> anyhow::Result<()> {
+    use ParseError::*;
+    ensure!(depth < MAX_TREE_DEPTH, InvalidFileTree);
+
+    // The keys are read as bytes to keep the order of the tree, but the
+    // names are UTF-8 like the paths of version 1 torrents. Lossy names
+    // could collide.
+    for (name, entry) in dir.raw_iter() {
+        let entry = entry.as_dict().context(InvalidFileTree)?;
+        if name.is_empty() {
+            // The file itself, under its name
+            ensure!(!path.is_empty(), InvalidFileTree);
+            let length = entry.get_int("length").context(InvalidFileTree)?;
+            let pieces_root = match entry.get_bytes("pieces root") {
+                Some(root) => Some(root.try_into().ok().context(InvalidFileTree)?),
+                None => None,
+            };
+            files.push(TreeFile {
+                path: path.clone(),
+                length,
+                pieces_root,
+            });
+        } else {
+            let name = std::str::from_utf8(name).ok().context(InvalidFileTree)?;
+            path.push(name.to_owned());
+            parse_file_tree(&entry, path, files, depth + 1)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+/// Whether the info dictionary marks the torrent as private (BEP 27).
+pub(crate) fn is_private(info: &Dict) -> bool {
+    info.get_int::<i64>("private") == Some(1)
+}
+
+/// Files of a multi-file torrent and the total length of the torrent. The
+/// files are empty for single-file torrents.
+pub(crate) fn parse_files(info: &Dict) -> anyhow::Result<(Vec<FileInfo>, usize)> {
+    use ParseError::*;
+
+    let list = match info.get_list("files") {
+        Some(list) => list,
+        None => {
+            let length = info.get_int("length").context(LengthRequired)?;
+            return Ok((vec![], length));
+        }
+    };
+
+    let mut files = Vec::new();
+    let mut total: usize = 0;
+    for file in list.iter() {
+        let file = file.as_dict().context(InvalidFile)?;
+        let length: u64 = file.get_int("length").context(InvalidFile)?;
+        let path = file
+            .get_list("path")
+            .context(InvalidFile)?
+            .iter()
+            .map(|p| p.as_str().map(String::from))
+            .collect::<Option<Vec<_>>>()
+            .context(InvalidFile)?;
+        let padding = file.get_str("attr").is_some_and(|a| a.contains('p'));
+
+        total = usize::try_from(length)
+            .ok()
+            .and_then(|len| total.checked_add(len))
+            .context(InvalidFile)?;
+        files.push(FileInfo {
+            path,
+            length,
+            padding,
+        });
+    }
+
+    Ok((files, total))
 }
 
 impl MetaInfo {
@@ -15,19 +398,62 @@ impl MetaInfo {
     }
 
     pub fn parse_with(data: &[u8], parser: &mut Parser) -> anyhow::Result<Self> {
+        Self::parse_with_limits(data, parser, &TorrentLimits::default())
+    }
+
+    /// Parse the info dictionary, e.g. one received from peers, rejecting
+    /// torrents over the `limits`.
+    pub fn parse_with_limits(
+        data: &[u8],
+        parser: &mut Parser,
+        limits: &TorrentLimits,
+    ) -> anyhow::Result<Self> {
+        Self::parse_with_layers(data, parser, limits, vec![])
+    }
+
+    /// Parse the info dictionary along with the piece layers of a version 2
+    /// torrent, see [`missing_layers`].
+    pub fn parse_with_layers(
+        data: &[u8],
+        parser: &mut Parser,
+        limits: &TorrentLimits,
+        piece_layers: PieceLayers,
+    ) -> anyhow::Result<Self> {
         use ParseError::*;
+        // File names needn't be UTF-8
+        parser.binary_keys(true);
         let info = parser.parse::<Dict>(data)?;
-
-        let length = info.get_int("length").context(LengthRequired)?;
+        let version = Version::of(&info);
         let piece_len = info.get_int("piece length").context(PieceLengthRequired)?;
-        let pieces = info.get_bytes("pieces").context(PiecesRequired)?;
+
+        let (files, length, pieces, piece_roots) = if version == Version::V2 {
+            let layers = piece_layers
+                .iter()
+                .map(|(root, layer)| (&root[..], &layer[..]))
+                .collect();
+            let v2 = parse_v2(&info, &layers)?;
+            limits.check(piece_len, v2.length, v2.piece_roots.len())?;
+            (v2.files, v2.length, vec![], v2.piece_roots)
+        } else {
+            let (files, length) = parse_files(&info)?;
+            let pieces = info.get_bytes("pieces").context(PiecesRequired)?;
+            limits.check(piece_len, length, pieces.len() / 20)?;
+            check_pieces(pieces, piece_len, length)?;
+            (files, length, pieces.to_vec(), vec![])
+        };
         let name = info.get_str("name").map(String::from);
 
         Ok(MetaInfo {
             name,
             length,
             piece_len,
-            pieces: pieces.to_vec(),
+            pieces,
+            files,
+            private: is_private(&info),
+            version,
+            piece_roots,
+            piece_layers,
+            raw: data.to_vec(),
         })
     }
 }
@@ -46,6 +472,379 @@ pub(crate) enum ParseError {
     #[error("Torrent length is required")]
     LengthRequired,
 
+    #[error("Torrent file entry is invalid")]
+    InvalidFile,
+
     #[error("Announce URL is required")]
     AnnounceRequired,
+
+    #[error("Torrent file tree is invalid")]
+    InvalidFileTree,
+
+    #[error("Torrent piece layers are missing or invalid")]
+    InvalidPieceLayers,
+
+    #[error("Torrent piece length is invalid or too large")]
+    InvalidPieceLength,
+
+    #[error("Torrent is too large")]
+    TooLarge,
+
+    #[error("Torrent piece hashes are {0} bytes, not a multiple of 20")]
+    InvalidPieces(usize),
+
+    #[error("Torrent has {actual} piece hashes but its length makes {expected} pieces")]
+    PieceCountMismatch { expected: usize, actual: usize },
+}
+
+/// Maps the pieces of a torrent to the files they're made of. The files
+/// are laid out one after another in the order of the torrent, so a piece
+/// may span several files and a file may span several pieces.
+#[derive(Debug, Clone)]
+pub struct FileMap {
+    piece_len: u64,
+
+    /// Offset of the first byte of each file in the torrent
+    starts: Vec<u64>,
+    total_len: u64,
+
+    /// Whether each file is a padding file
+    padding: Vec<bool>,
+}
+
+/// Part of a file within a piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file: usize,
+
+    /// Offset within the file
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl FileMap {
+    pub fn new(piece_len: usize, file_lens: impl IntoIterator<Item = u64>) -> Self {
+        Self::with_padding(piece_len, file_lens.into_iter().map(|len| (len, false)))
+    }
+
+    /// Map of files given as their length and whether they're padding
+    /// files. The padding files take their place in the pieces but are
+    /// left out of `piece_files`.
+    pub fn with_padding(piece_len: usize, files: impl IntoIterator<Item = (u64, bool)>) -> Self {
+        let mut starts = Vec::new();
+        let mut padding = Vec::new();
+        let mut total_len = 0;
+        for (len, pad) in files {
+            starts.push(total_len);
+            padding.push(pad);
+            total_len += len;
+        }
+
+        Self {
+            piece_len: piece_len as u64,
+            starts,
+            total_len,
+            padding,
+        }
+    }
+
+    /// Map of a single-file torrent.
+    pub fn single(piece_len: usize, len: u64) -> Self {
+        Self::new(piece_len, [len])
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.starts.len()
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.total_len.div_ceil(self.piece_len) as usize
+    }
+
+    pub fn total_len(&self) -> u64 {
+        self.total_len
+    }
+
+    /// Length of the files which are stored, i.e. without the padding.
+    pub fn stored_len(&self) -> u64 {
+        (0..self.num_files())
+            .filter(|&f| !self.padding[f])
+            .map(|f| self.file_len(f))
+            .sum()
+    }
+
+    pub fn is_padding(&self, file: usize) -> bool {
+        self.padding[file]
+    }
+
+    pub fn file_len(&self, file: usize) -> u64 {
+        let end = self.starts.get(file + 1).copied().unwrap_or(self.total_len);
+        end - self.starts[file]
+    }
+
+    /// Length of the piece, which is shorter than the others if it's the
+    /// last one.
+    pub fn piece_len(&self, piece: u32) -> u64 {
+        let start = piece as u64 * self.piece_len;
+        self.piece_len.min(self.total_len.saturating_sub(start))
+    }
+
+    /// File and offset within it of the byte at `offset` in the piece, or
+    /// `None` if it's past the end of the torrent. Empty files take no
+    /// bytes, so they're never returned.
+    pub fn to_file(&self, piece: u32, offset: u32) -> Option<(usize, u64)> {
+        self.file_at(piece as u64 * self.piece_len + offset as u64)
+    }
+
+    /// File and offset within it of the byte at `pos` in the torrent, or
+    /// `None` if it's past the end of the torrent.
+    pub fn file_at(&self, pos: u64) -> Option<(usize, u64)> {
+        if pos >= self.total_len {
+            return None;
+        }
+
+        let file = self.starts.partition_point(|&start| start <= pos) - 1;
+        Some((file, pos - self.starts[file]))
+    }
+
+    /// Piece and offset within it of the byte at `offset` in the file, or
+    /// `None` if it's past the end of the file.
+    pub fn to_piece(&self, file: usize, offset: u64) -> Option<(u32, u32)> {
+        if file >= self.starts.len() || offset >= self.file_len(file) {
+            return None;
+        }
+
+        let pos = self.starts[file] + offset;
+        let piece = pos / self.piece_len;
+        Some((piece as u32, (pos % self.piece_len) as u32))
+    }
+
+    /// Pieces holding a byte of the file. Empty for empty files.
+    pub fn file_pieces(&self, file: usize) -> std::ops::Range<u32> {
+        let len = self.file_len(file);
+        if len == 0 {
+            return 0..0;
+        }
+
+        let start = self.starts[file];
+        let first = start / self.piece_len;
+        let last = (start + len - 1) / self.piece_len;
+        first as u32..last as u32 + 1
+    }
+
+    /// Parts of the files making up the piece, in order. Padding files are
+    /// not stored, so they're skipped.
+    pub fn piece_files(&self, piece: u32) -> impl Iterator<Item = FileSlice> + '_ {
+        let mut pos = piece as u64 * self.piece_len;
+        let end = pos + self.piece_len(piece);
+        let first = self.to_file(piece, 0).map_or(self.starts.len(), |(f, _)| f);
+
+        (first..self.starts.len())
+            .map_while(move |file| {
+                if pos >= end {
+                    return None;
+                }
+
+                let file_end = self.starts[file] + self.file_len(file);
+                let len = file_end.min(end) - pos;
+                let slice = FileSlice {
+                    file,
+                    offset: pos - self.starts[file],
+                    len,
+                };
+                pos += len;
+                Some(slice)
+            })
+            .filter(|s| s.len > 0 && !self.padding[s.file])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Files of 5, 0, 12 and 3 bytes in pieces of 8 bytes:
+    // piece 0: [0 0 0 0 0 2 2 2]
+    // piece 1: [2 2 2 2 2 2 2 2]
+    // piece 2: [2 3 3 3]
+    fn map() -> FileMap {
+        FileMap::new(8, [5, 0, 12, 3])
+    }
+
+    #[test]
+    fn lengths() {
+        let map = map();
+        assert_eq!(map.num_files(), 4);
+        assert_eq!(map.num_pieces(), 3);
+        assert_eq!(map.total_len(), 20);
+        assert_eq!(map.file_len(1), 0);
+        assert_eq!(map.file_len(3), 3);
+        assert_eq!(map.piece_len(1), 8);
+        assert_eq!(map.piece_len(2), 4);
+        assert_eq!(map.piece_len(3), 0);
+    }
+
+    #[test]
+    fn piece_to_file() {
+        let map = map();
+        assert_eq!(map.to_file(0, 4), Some((0, 4)));
+        assert_eq!(map.to_file(0, 5), Some((2, 0)));
+        assert_eq!(map.to_file(1, 0), Some((2, 3)));
+        assert_eq!(map.to_file(2, 1), Some((3, 0)));
+        assert_eq!(map.to_file(2, 3), Some((3, 2)));
+        assert_eq!(map.to_file(2, 4), None);
+    }
+
+    #[test]
+    fn file_to_piece() {
+        let map = map();
+        assert_eq!(map.to_piece(0, 0), Some((0, 0)));
+        assert_eq!(map.to_piece(1, 0), None);
+        assert_eq!(map.to_piece(2, 3), Some((1, 0)));
+        assert_eq!(map.to_piece(3, 2), Some((2, 3)));
+        assert_eq!(map.to_piece(3, 3), None);
+        assert_eq!(map.to_piece(4, 0), None);
+
+        for piece in 0..3 {
+            for offset in 0..map.piece_len(piece) as u32 {
+                let (file, file_offset) = map.to_file(piece, offset).unwrap();
+                assert_eq!(map.to_piece(file, file_offset), Some((piece, offset)));
+            }
+        }
+    }
+
+    #[test]
+    fn file_pieces() {
+        let map = map();
+        assert_eq!(map.file_pieces(0), 0..1);
+        assert_eq!(map.file_pieces(1), 0..0);
+        assert_eq!(map.file_pieces(2), 0..3);
+        assert_eq!(map.file_pieces(3), 2..3);
+    }
+
+    #[test]
+    fn piece_files() {
+        let map = map();
+        let slices: Vec<_> = map.piece_files(0).collect();
+        assert_eq!(
+            slices,
+            [
+                FileSlice {
+                    file: 0,
+                    offset: 0,
+                    len: 5
+                },
+                FileSlice {
+                    file: 2,
+                    offset: 0,
+                    len: 3
+                },
+            ]
+        );
+
+        let slices: Vec<_> = map.piece_files(2).collect();
+        assert_eq!(slices.len(), 2);
+        assert_eq!(
+            slices[1],
+            FileSlice {
+                file: 3,
+                offset: 0,
+                len: 3
+            }
+        );
+        assert_eq!(map.piece_files(3).count(), 0);
+    }
+
+    #[test]
+    fn single_file() {
+        let map = FileMap::single(16, 40);
+        assert_eq!(map.num_pieces(), 3);
+        assert_eq!(map.to_file(2, 7), Some((0, 39)));
+        assert_eq!(map.to_piece(0, 20), Some((1, 4)));
+        assert_eq!(map.piece_files(2).next().unwrap().len, 8);
+    }
+
+    #[test]
+    fn parse_files_with_padding() {
+        let mut data = b"d5:filesl".to_vec();
+        data.extend(b"d6:lengthi5e4:pathl1:aee");
+        data.extend(b"d4:attr1:p6:lengthi3e4:pathl4:.pad1:3ee");
+        data.extend(b"d6:lengthi10e4:pathl3:dir1:bee");
+        data.extend(b"e4:name1:t12:piece lengthi8e6:pieces60:");
+        data.extend([0; 60]);
+        data.push(b'e');
+
+        let info = MetaInfo::parse(&data).unwrap();
+        assert_eq!(info.length, 18);
+        assert_eq!(info.files.len(), 3);
+        assert_eq!(info.files[2].path, ["dir", "b"]);
+        assert!(info.files[1].padding && !info.files[2].padding);
+
+        let map = FileMap::with_padding(8, info.files.iter().map(|f| (f.length, f.padding)));
+        assert_eq!(map.num_pieces(), 3);
+        assert_eq!(map.stored_len(), 15);
+        assert!(map.is_padding(1));
+        assert_eq!(map.to_file(1, 0), Some((2, 0)));
+        let files: Vec<_> = map.piece_files(0).map(|s| s.file).collect();
+        assert_eq!(files, [0]);
+
+        assert!(MetaInfo::parse(b"d5:filesli1ee4:name1:t12:piece lengthi8e6:pieces0:e").is_err());
+    }
+
+    #[test]
+    fn torrents_over_the_limits() {
+        let limits = TorrentLimits {
+            max_piece_len: 16,
+            max_pieces: 4,
+            max_length: 64,
+            ..TorrentLimits::default()
+        };
+        let parse = |info: &[u8]| MetaInfo::parse_with_limits(info, &mut Parser::new(), &limits);
+
+        let mut data = b"d6:lengthi64e4:name1:t12:piece lengthi16e6:pieces80:".to_vec();
+        data.extend([0; 80]);
+        data.push(b'e');
+        let info = parse(&data).unwrap();
+        assert_eq!(info.length, 64);
+
+        // Piece length of zero or over the limit
+        assert!(parse(b"d6:lengthi8e4:name1:t12:piece lengthi0e6:pieces0:e").is_err());
+        assert!(parse(b"d6:lengthi8e4:name1:t12:piece lengthi32e6:pieces0:e").is_err());
+
+        // Too long, or too many pieces by the lengths or by the hashes
+        assert!(parse(b"d6:lengthi65e4:name1:t12:piece lengthi16e6:pieces0:e").is_err());
+        assert!(parse(b"d6:lengthi40e4:name1:t12:piece lengthi8e6:pieces0:e").is_err());
+        let mut data = b"d6:lengthi8e4:name1:t12:piece lengthi8e6:pieces100:".to_vec();
+        data.extend([0; 100]);
+        data.push(b'e');
+        assert!(parse(&data).is_err());
+    }
+
+    #[test]
+    fn piece_hashes_match_the_length() {
+        let info = |length: usize, pieces: usize| {
+            let mut data =
+                format!("d6:lengthi{}e4:name1:t12:piece lengthi16e", length).into_bytes();
+            data.extend(format!("6:pieces{}:", pieces).bytes());
+            data.extend(vec![0; pieces]);
+            data.push(b'e');
+            data
+        };
+        let error = |length, pieces| {
+            let e = MetaInfo::parse(&info(length, pieces)).err().unwrap();
+            e.downcast::<ParseError>().unwrap().to_string()
+        };
+
+        assert!(MetaInfo::parse(&info(33, 60)).is_ok());
+        assert!(MetaInfo::parse(&info(0, 0)).is_ok());
+        assert_eq!(
+            error(33, 50),
+            "Torrent piece hashes are 50 bytes, not a multiple of 20"
+        );
+        assert_eq!(
+            error(33, 40),
+            "Torrent has 2 piece hashes but its length makes 3 pieces"
+        );
+        assert!(MetaInfo::parse(&info(16, 40)).is_err());
+    }
 }
@@ -1,35 +1,181 @@
-pub const CHOKE: u8 = 0;
-pub const UNCHOKE: u8 = 1;
-pub const INTERESTED: u8 = 2;
-pub const NOT_INTERESTED: u8 = 3;
-pub const HAVE: u8 = 4;
-pub const BITFIELD: u8 = 5;
-pub const REQUEST: u8 = 6;
-pub const PIECE: u8 = 7;
-pub const CANCEL: u8 = 8;
-pub const EXTENDED: u8 = 20;
+use std::fmt;
 
-#[derive(Debug, PartialEq)]
-pub enum Packet<'a> {
-    Request { index: u32, begin: u32, len: u32 },
-    Piece(PieceBlock<'a>),
-    Cancel { index: u32, begin: u32, len: u32 },
+use crate::merkle::Hash;
+
+/// Id of a peer wire message, the byte after the length prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MessageId {
+    Choke = 0,
+    Unchoke = 1,
+    Interested = 2,
+    NotInterested = 3,
+    Have = 4,
+    Bitfield = 5,
+    Request = 6,
+    Piece = 7,
+    Cancel = 8,
+    Port = 9,
+    Extended = 20,
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
 }
 
-impl Packet<'_> {
-    pub fn header_len(id: u8) -> usize {
-        match id {
-            HAVE => 4,
-            REQUEST | CANCEL => 12,
-            PIECE => 8,
+impl MessageId {
+    /// Length of the fixed fields after the id. Messages can't be shorter.
+    pub fn header_len(self) -> usize {
+        match self {
+            MessageId::Have => 4,
+            MessageId::Request | MessageId::Cancel => 12,
+            MessageId::Piece => 8,
+            MessageId::Port => 2,
+            MessageId::Extended => 1,
+            MessageId::HashRequest | MessageId::Hashes | MessageId::HashReject => HashRequest::LEN,
             _ => 0,
         }
     }
+
+    /// Length of the message after the id, if all messages with the id
+    /// have the same length.
+    pub fn fixed_len(self) -> Option<usize> {
+        match self {
+            MessageId::Choke
+            | MessageId::Unchoke
+            | MessageId::Interested
+            | MessageId::NotInterested
+            | MessageId::Have
+            | MessageId::Request
+            | MessageId::Cancel
+            | MessageId::Port
+            | MessageId::HashRequest
+            | MessageId::HashReject => Some(self.header_len()),
+            MessageId::Bitfield | MessageId::Piece | MessageId::Extended | MessageId::Hashes => {
+                None
+            }
+        }
+    }
+}
+
+impl TryFrom<u8> for MessageId {
+    /// The unknown id
+    type Error = u8;
+
+    fn try_from(id: u8) -> Result<Self, u8> {
+        let id = match id {
+            0 => MessageId::Choke,
+            1 => MessageId::Unchoke,
+            2 => MessageId::Interested,
+            3 => MessageId::NotInterested,
+            4 => MessageId::Have,
+            5 => MessageId::Bitfield,
+            6 => MessageId::Request,
+            7 => MessageId::Piece,
+            8 => MessageId::Cancel,
+            9 => MessageId::Port,
+            20 => MessageId::Extended,
+            21 => MessageId::HashRequest,
+            22 => MessageId::Hashes,
+            23 => MessageId::HashReject,
+            id => return Err(id),
+        };
+        Ok(id)
+    }
+}
+
+impl From<MessageId> for u8 {
+    fn from(id: MessageId) -> Self {
+        id as u8
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MessageId::Choke => "choke",
+            MessageId::Unchoke => "unchoke",
+            MessageId::Interested => "interested",
+            MessageId::NotInterested => "not-interested",
+            MessageId::Have => "have",
+            MessageId::Bitfield => "bitfield",
+            MessageId::Request => "request",
+            MessageId::Piece => "piece",
+            MessageId::Cancel => "cancel",
+            MessageId::Port => "port",
+            MessageId::Extended => "extended",
+            MessageId::HashRequest => "hash-request",
+            MessageId::Hashes => "hashes",
+            MessageId::HashReject => "hash-reject",
+        };
+        f.write_str(name)
+    }
 }
 
 #[derive(Debug, PartialEq)]
+pub enum Packet<'a> {
+    Request {
+        index: u32,
+        begin: u32,
+        len: u32,
+    },
+    Piece(PieceBlock<'a>),
+    Cancel {
+        index: u32,
+        begin: u32,
+        len: u32,
+    },
+
+    /// The peer wants hashes of a merkle tree of ours (BEP 52).
+    HashRequest(HashRequest),
+
+    /// Hashes we asked for, `hashes` being the requested ones followed by
+    /// the proof, 32 bytes each.
+    Hashes {
+        req: HashRequest,
+        hashes: &'a [u8],
+    },
+
+    /// The peer doesn't have the hashes we asked for.
+    HashReject(HashRequest),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PieceBlock<'a> {
     pub index: u32,
     pub begin: u32,
     pub data: &'a [u8],
 }
+
+/// A block request received from the peer which is yet to be served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub len: u32,
+}
+
+/// Hashes of a file's merkle tree (BEP 52), as asked for by a `hash
+/// request` and echoed by the `hashes` and `hash reject` replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HashRequest {
+    /// Root of the file's tree, its `pieces root`.
+    pub pieces_root: Hash,
+
+    /// Layer of the hashes, 0 being the hashes of the 16 KiB blocks.
+    pub base_layer: u32,
+
+    /// Offset of the first hash in the layer.
+    pub index: u32,
+
+    /// Number of hashes, a power of two.
+    pub length: u32,
+
+    /// Number of layers of uncle hashes above the requested ones, which
+    /// prove them against the root.
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    /// Length of the fields on the wire.
+    pub const LEN: usize = 48;
+}
@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+/// Smoothed round trip time of a peer, computed like TCP does
+/// ([RFC 6298][rfc]).
+///
+/// The time from sending a block request to receiving the block is used as
+/// the sample. It includes the time the peer takes to serve the request, so
+/// it is an upper bound of the network latency.
+///
+/// [rfc]: https://datatracker.ietf.org/doc/html/rfc6298
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    min: Option<Duration>,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_sample(&mut self, rtt: Duration) {
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let diff = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + diff) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    /// Smoothed round trip time. `None` until the first sample.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Lowest round trip time seen. Unlike the smoothed one it doesn't grow
+    /// with the requests queued at the peer, so it's the closest to the
+    /// network latency. `None` until the first sample.
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    /// Mean deviation of the round trip time.
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    /// How long to wait for a response before considering it lost.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.srtt.map(|srtt| srtt + self.rttvar * 4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn first_sample() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.srtt(), None);
+        assert_eq!(rtt.min(), None);
+        assert_eq!(rtt.timeout(), None);
+
+        rtt.add_sample(ms(100));
+        assert_eq!(rtt.srtt(), Some(ms(100)));
+        assert_eq!(rtt.rttvar(), ms(50));
+        assert_eq!(rtt.timeout(), Some(ms(300)));
+    }
+
+    #[test]
+    fn converge() {
+        let mut rtt = RttEstimator::new();
+        rtt.add_sample(ms(1000));
+        for _ in 0..50 {
+            rtt.add_sample(ms(100));
+        }
+
+        let srtt = rtt.srtt().unwrap();
+        assert!(srtt >= ms(100) && srtt <= ms(105), "{:?}", srtt);
+        assert!(rtt.rttvar() <= ms(5));
+    }
+
+    #[test]
+    fn min_ignores_queueing() {
+        let mut rtt = RttEstimator::new();
+        rtt.add_sample(ms(100));
+        for n in 2..20 {
+            rtt.add_sample(ms(100 * n));
+        }
+        assert!(rtt.srtt().unwrap() > ms(1000));
+        assert_eq!(rtt.min(), Some(ms(100)));
+    }
+}
@@ -4,4 +4,39 @@ use thiserror::Error;
 pub enum Error {
     #[error("Unsupported protocol")]
     UnsupportedProtocol,
+
+    #[error("Info hash mismatch")]
+    InfoHashMismatch,
+
+    #[error("Handshake timed out")]
+    HandshakeTimeout,
+
+    #[error("Connection closed after {0} bytes of handshake")]
+    IncompleteHandshake(usize),
+
+    #[error("Connected to ourselves")]
+    SelfConnection,
+
+    #[error("Invalid message: id {id}, len {len}")]
+    InvalidMessage { id: u8, len: usize },
+
+    #[error("Too many unknown messages: {0}")]
+    TooManyUnknownMessages(u32),
+
+    #[error("Too many extended messages")]
+    ExtendedFlood,
+}
+
+impl Error {
+    /// Returns true if the peer failed the handshake.
+    pub fn is_handshake(&self) -> bool {
+        matches!(
+            self,
+            Self::UnsupportedProtocol
+                | Self::InfoHashMismatch
+                | Self::HandshakeTimeout
+                | Self::IncompleteHandshake(_)
+                | Self::SelfConnection
+        )
+    }
 }
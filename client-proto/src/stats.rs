@@ -0,0 +1,87 @@
+use std::time::Instant;
+
+/// Bytes exchanged with a peer over its connection, handshake and framing
+/// included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WireStats {
+    /// Bytes written to the peer.
+    pub bytes_up: u64,
+
+    /// Bytes read from the peer.
+    pub bytes_down: u64,
+
+    /// Block data sent in piece messages.
+    pub payload_up: u64,
+
+    /// Block data received in piece messages.
+    pub payload_down: u64,
+}
+
+impl WireStats {
+    /// Bytes sent which weren't block data: the handshake, message headers
+    /// and all the other messages.
+    pub fn protocol_up(&self) -> u64 {
+        self.bytes_up.saturating_sub(self.payload_up)
+    }
+
+    /// Bytes received which weren't block data.
+    pub fn protocol_down(&self) -> u64 {
+        self.bytes_down.saturating_sub(self.payload_down)
+    }
+
+    /// The bytes exchanged since the `earlier` snapshot of the same counters.
+    pub fn since(&self, earlier: &WireStats) -> WireStats {
+        WireStats {
+            bytes_up: self.bytes_up - earlier.bytes_up,
+            bytes_down: self.bytes_down - earlier.bytes_down,
+            payload_up: self.payload_up - earlier.payload_up,
+            payload_down: self.payload_down - earlier.payload_down,
+        }
+    }
+}
+
+/// When messages last went either way over a connection, by the clock of
+/// the connection. `None` until the first one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Activity {
+    /// Any message queued for the peer, keep-alives included.
+    pub last_sent: Option<Instant>,
+
+    /// Any message received from the peer, keep-alives included.
+    pub last_recv: Option<Instant>,
+
+    /// Block data queued for the peer.
+    pub last_block_sent: Option<Instant>,
+
+    /// Block data received from the peer.
+    pub last_block_recv: Option<Instant>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overhead() {
+        let earlier = WireStats {
+            bytes_up: 100,
+            bytes_down: 50,
+            payload_up: 60,
+            payload_down: 0,
+        };
+        let now = WireStats {
+            bytes_up: 300,
+            bytes_down: 16500,
+            payload_up: 200,
+            payload_down: 16384,
+        };
+        assert_eq!(now.protocol_up(), 100);
+        assert_eq!(now.protocol_down(), 116);
+
+        let delta = now.since(&earlier);
+        assert_eq!(delta.bytes_up, 200);
+        assert_eq!(delta.payload_up, 140);
+        assert_eq!(delta.protocol_up(), 60);
+        assert_eq!(delta.protocol_down(), 66);
+    }
+}
@@ -0,0 +1,461 @@
+//! Deterministic simulation of a connection and a scripted peer.
+//!
+//! [`Sim`] links the [`Connection`] under test with the connection of a
+//! peer whose replies come from a [`Behavior`]. Messages take a fixed
+//! latency to cross the virtual wire, and both sides read the time from the
+//! same [`VirtualClock`], so a run depends on nothing but the script.
+//!
+//! Only available with the `testing` feature.
+
+use crate::bitfield::Bitfield;
+use crate::clock::VirtualClock;
+use crate::conn::Connection;
+use crate::ext::{ExtendedMessage, MetadataMsg};
+use crate::frame::Frame;
+use crate::msg::{Packet, PieceBlock};
+use crate::InfoHash;
+use ben::Parser;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+pub const INFO_HASH: InfoHash = [0xaa; 20];
+
+/// Upper bound of the steps in `Sim::run_until_idle`, so that a script
+/// which never settles fails instead of hanging.
+const MAX_STEPS: usize = 1_000_000;
+
+/// Extension id of ut_metadata on the side of `MetadataHost`.
+const UT_METADATA_ID: u8 = 3;
+
+const METADATA_PIECE_LEN: usize = 0x4000;
+
+/// Script of the simulated peer.
+pub trait Behavior {
+    /// Called once the handshakes are exchanged.
+    fn on_connect(&mut self, _peer: &mut Connection, _now: Duration) {}
+
+    /// Called for every message from the connection under test, after
+    /// `peer` has handled it. Replies are queued on `peer`.
+    fn on_frame(&mut self, peer: &mut Connection, frame: &Frame<'_>, now: Duration);
+
+    /// Time the behavior wants to act at next, if any.
+    fn next_wakeup(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called at the time returned by `next_wakeup`.
+    fn on_wakeup(&mut self, _peer: &mut Connection, _now: Duration) {}
+}
+
+/// A block received by the connection under test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Received {
+    /// Since the start of the simulation
+    pub at: Duration,
+    pub index: u32,
+    pub begin: u32,
+    pub len: u32,
+}
+
+struct Transit {
+    /// Since the start of the simulation
+    at: Duration,
+    to_peer: bool,
+    data: Vec<u8>,
+}
+
+pub struct Sim<B> {
+    pub clock: VirtualClock,
+
+    /// The connection under test
+    pub conn: Connection,
+
+    /// The connection of the simulated peer
+    pub peer: Connection,
+    pub behavior: B,
+    latency: Duration,
+
+    /// Messages in the order they arrive
+    wire: VecDeque<Transit>,
+    received: Vec<Received>,
+}
+
+impl<B: Behavior> Sim<B> {
+    /// Connect to a peer following `behavior`. Messages take `latency` to
+    /// arrive, the handshakes none.
+    pub fn new(behavior: B, latency: Duration) -> Self {
+        let clock = VirtualClock::new();
+        let mut conn = Connection::new();
+        let mut peer = Connection::new();
+        conn.set_clock(clock.clone().into());
+        peer.set_clock(clock.clone().into());
+
+        // The behavior decides when to unchoke
+        peer.set_download_only(true);
+
+        conn.send_handshake(&INFO_HASH, &[1; 20]);
+        peer.send_handshake(&INFO_HASH, &[2; 20]);
+        let conn_handshake = handshake(&mut conn);
+        let peer_handshake = handshake(&mut peer);
+        peer.recv_handshake(&INFO_HASH, conn_handshake).unwrap();
+        conn.recv_handshake(&INFO_HASH, peer_handshake).unwrap();
+
+        let mut sim = Self {
+            clock,
+            conn,
+            peer,
+            behavior,
+            latency,
+            wire: VecDeque::new(),
+            received: vec![],
+        };
+        sim.behavior.on_connect(&mut sim.peer, Duration::ZERO);
+        sim
+    }
+
+    /// Time since the start of the simulation.
+    pub fn now(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// Blocks received by the connection under test so far.
+    pub fn received(&self) -> &[Received] {
+        &self.received
+    }
+
+    /// Send what both sides have queued and handle the next message or
+    /// wakeup, advancing the clock to its time. Returns false if there is
+    /// nothing left to do.
+    pub fn step(&mut self) -> bool {
+        self.flush();
+
+        let arrival = self.wire.front().map(|t| t.at);
+        let wakeup = self.behavior.next_wakeup();
+
+        let deliver = match (arrival, wakeup) {
+            (Some(a), Some(w)) => a <= w,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return false,
+        };
+
+        if deliver {
+            let transit = self.wire.pop_front().unwrap();
+            self.clock.advance_to(transit.at);
+            self.deliver(transit);
+        } else {
+            self.clock.advance_to(wakeup.unwrap());
+            let now = self.now();
+            self.behavior.on_wakeup(&mut self.peer, now);
+        }
+        true
+    }
+
+    /// Run the events up to `until` and move the clock there.
+    pub fn run_until(&mut self, until: Duration) {
+        loop {
+            self.flush();
+            let next = [self.wire.front().map(|t| t.at), self.behavior.next_wakeup()];
+            match next.iter().flatten().min() {
+                Some(&t) if t <= until => {
+                    self.step();
+                }
+                _ => break,
+            }
+        }
+        self.clock.advance_to(until);
+    }
+
+    /// Run until neither side has anything more to do.
+    ///
+    /// # Panics
+    ///
+    /// If the script doesn't settle within a million steps.
+    pub fn run_until_idle(&mut self) {
+        for _ in 0..MAX_STEPS {
+            if !self.step() {
+                return;
+            }
+        }
+        panic!("Simulation did not settle");
+    }
+
+    fn flush(&mut self) {
+        let at = self.now() + self.latency;
+        for (to_peer, conn) in [(true, &mut self.conn), (false, &mut self.peer)] {
+            let buf = conn.send_buf();
+            if !buf.is_empty() {
+                let data = buf.to_vec();
+                self.wire.push_back(Transit { at, to_peer, data });
+            }
+        }
+    }
+
+    fn deliver(&mut self, transit: Transit) {
+        let now = self.now();
+        let mut data = &transit.data[..];
+        while data.len() >= 4 {
+            let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            let msg = &data[4..4 + len];
+            data = &data[4 + len..];
+
+            if transit.to_peer {
+                let frame = Frame::decode(msg).unwrap();
+                self.peer.recv_frame(frame.clone());
+                self.behavior.on_frame(&mut self.peer, &frame, now);
+            } else if let Some(Packet::Piece(PieceBlock { index, begin, data })) =
+                self.conn.recv_packet(msg)
+            {
+                self.received.push(Received {
+                    at: now,
+                    index,
+                    begin,
+                    len: data.len() as u32,
+                });
+            }
+        }
+    }
+}
+
+fn handshake(conn: &mut Connection) -> [u8; 68] {
+    let buf = conn.send_buf();
+    buf[..].try_into().unwrap()
+}
+
+/// A peer having all the pieces. It unchokes when the other side gets
+/// interested and serves the requests while unchoked. Every byte of a
+/// block is the index of its piece.
+#[derive(Debug)]
+pub struct Seed {
+    num_pieces: usize,
+    unchoked: bool,
+
+    /// Scripted chokes (`false`) and unchokes (`true`), in order
+    schedule: VecDeque<(Duration, bool)>,
+    served: usize,
+}
+
+impl Seed {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            num_pieces,
+            unchoked: false,
+            schedule: VecDeque::new(),
+            served: 0,
+        }
+    }
+
+    /// Choke the connection at `at`. Calls must be in the order of time.
+    pub fn choke_at(mut self, at: Duration) -> Self {
+        self.schedule.push_back((at, false));
+        self
+    }
+
+    /// Unchoke the connection at `at`. Calls must be in the order of time.
+    pub fn unchoke_at(mut self, at: Duration) -> Self {
+        self.schedule.push_back((at, true));
+        self
+    }
+
+    /// Number of blocks sent.
+    pub fn served(&self) -> usize {
+        self.served
+    }
+
+    fn set_unchoked(&mut self, peer: &mut Connection, unchoked: bool) {
+        if unchoked == self.unchoked {
+            return;
+        }
+
+        self.unchoked = unchoked;
+        if unchoked {
+            peer.send_unchoke();
+        } else {
+            peer.send_choke();
+
+            // Pending requests are discarded on choke
+            while peer.pop_request().is_some() {}
+        }
+    }
+}
+
+impl Behavior for Seed {
+    fn on_connect(&mut self, peer: &mut Connection, _now: Duration) {
+        let pieces = Bitfield::with_value(self.num_pieces, true);
+        peer.send_frame(Frame::Bitfield(pieces.as_bytes()));
+    }
+
+    fn on_frame(&mut self, peer: &mut Connection, frame: &Frame<'_>, _now: Duration) {
+        match frame {
+            Frame::Interested if self.schedule.is_empty() => self.set_unchoked(peer, true),
+            Frame::Request(_) => {
+                while let Some(req) = peer.pop_request() {
+                    if self.unchoked {
+                        let data = vec![req.index as u8; req.len as usize];
+                        peer.send_piece(req.index, req.begin, &data);
+                        self.served += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn next_wakeup(&self) -> Option<Duration> {
+        self.schedule.front().map(|&(at, _)| at)
+    }
+
+    fn on_wakeup(&mut self, peer: &mut Connection, now: Duration) {
+        while let Some(&(at, unchoked)) = self.schedule.front() {
+            if at > now {
+                break;
+            }
+            self.schedule.pop_front();
+            self.set_unchoked(peer, unchoked);
+        }
+    }
+}
+
+/// A peer serving the info dictionary with ut_metadata (BEP 9).
+pub struct MetadataHost {
+    metadata: Vec<u8>,
+    parser: Parser,
+    requests: Vec<u32>,
+}
+
+impl MetadataHost {
+    pub fn new(metadata: Vec<u8>) -> Self {
+        Self {
+            metadata,
+            parser: Parser::new(),
+            requests: vec![],
+        }
+    }
+
+    /// Metadata pieces requested so far.
+    pub fn requests(&self) -> &[u32] {
+        &self.requests
+    }
+}
+
+impl Behavior for MetadataHost {
+    fn on_connect(&mut self, peer: &mut Connection, _now: Duration) {
+        let len = self.metadata.len() as u32;
+        peer.send_ext(0, MetadataMsg::Handshake(UT_METADATA_ID, len, None));
+    }
+
+    fn on_frame(&mut self, peer: &mut Connection, frame: &Frame<'_>, _now: Duration) {
+        let payload = match frame {
+            Frame::Extended { id, payload } if *id == UT_METADATA_ID => payload,
+            _ => return,
+        };
+
+        let msg = match ExtendedMessage::parse(UT_METADATA_ID, payload, &mut self.parser) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        let dict = match msg.value.as_dict() {
+            Some(dict) => dict,
+            None => return,
+        };
+
+        let piece: u32 = match (dict.get_int("msg_type"), dict.get_int("piece")) {
+            (Some(0u8), Some(piece)) => piece,
+            _ => return,
+        };
+        self.requests.push(piece);
+
+        let len = self.metadata.len() as u32;
+        match self.metadata.chunks(METADATA_PIECE_LEN).nth(piece as usize) {
+            Some(data) => peer.send_ext_data(1, MetadataMsg::Data(piece, len), data),
+            None => peer.send_ext(1, MetadataMsg::Reject(piece)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+
+    const LATENCY: Duration = Duration::from_millis(50);
+    const BLOCK: u32 = 0x4000;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn unchoke_on_interest() {
+        let mut sim = Sim::new(Seed::new(4), LATENCY);
+        assert!(sim.conn.is_choked());
+
+        sim.conn.send_interested();
+        sim.run_until(ms(99));
+        assert!(sim.conn.is_choked());
+
+        // Interested arrives at 50ms, unchoke at 100ms
+        sim.run_until(ms(100));
+        assert!(!sim.conn.is_choked());
+        assert!(sim.conn.peer_is_seed(4));
+    }
+
+    #[test]
+    fn requests_are_pipelined() {
+        let mut sim = Sim::new(Seed::new(4), LATENCY);
+        sim.conn.send_interested();
+        sim.run_until_idle();
+
+        let start = sim.now();
+        for begin in 0..8 {
+            sim.conn.send_request(1, begin * BLOCK, BLOCK);
+        }
+        sim.run_until_idle();
+
+        // All of the blocks arrive after a single round trip
+        assert_eq!(sim.behavior.served(), 8);
+        assert_eq!(sim.received().len(), 8);
+        assert!(sim.received().iter().all(|r| r.at == start + LATENCY * 2));
+        assert_eq!(sim.conn.rtt().srtt(), Some(LATENCY * 2));
+    }
+
+    #[test]
+    fn choke_discards_requests() {
+        let seed = Seed::new(4).unchoke_at(ms(0)).choke_at(ms(120));
+        let mut sim = Sim::new(seed, LATENCY);
+        sim.run_until(ms(60));
+        assert!(!sim.conn.is_choked());
+
+        // Served before the choke at 120ms
+        sim.conn.send_request(0, 0, BLOCK);
+        sim.run_until(ms(75));
+
+        // Arrives after the choke
+        sim.conn.send_request(0, BLOCK, BLOCK);
+        sim.run_until_idle();
+
+        assert!(sim.conn.is_choked());
+        assert_eq!(sim.behavior.served(), 1);
+        let received: Vec<_> = sim.received().iter().map(|r| (r.begin, r.at)).collect();
+        assert_eq!(received, [(0, ms(160))]);
+    }
+
+    #[test]
+    fn metadata_exchange() {
+        let metadata: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        let mut sim = Sim::new(MetadataHost::new(metadata.clone()), LATENCY);
+        assert!(!sim.conn.request_metadata());
+
+        // The extended handshake takes a one way trip
+        sim.run_until_idle();
+        assert_eq!(sim.now(), LATENCY);
+        assert!(sim.conn.request_metadata());
+        sim.run_until_idle();
+
+        // One round trip per piece
+        assert_eq!(sim.behavior.requests(), [0, 1, 2]);
+        assert_eq!(sim.now(), LATENCY * 7);
+        assert_eq!(sim.conn.poll_event(), Some(Event::ExtendedHandshake));
+        assert_eq!(sim.conn.poll_event(), Some(Event::Metadata(metadata)));
+    }
+}
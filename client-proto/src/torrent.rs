@@ -1,39 +1,115 @@
 use std::collections::HashSet;
+use std::fmt;
 use std::net::SocketAddr;
 
-use crate::metainfo::ParseError;
+use crate::magnet::TorrentMagnet;
+use crate::merkle::{self, Hash, PieceRoot};
+use crate::metainfo::{
+    check_pieces, is_private, layer_map, parse_files, parse_v2, FileInfo, FileMap, ParseError,
+    TorrentLimits, Version,
+};
 use anyhow::Context;
 use ben::{decode::Dict, Parser};
+use data_encoding::{BASE32, HEXLOWER};
 use sha1::Sha1;
 
 use crate::InfoHash;
 
 pub struct Torrent {
+    /// SHA-1 of the info dictionary. For version 2 only torrents, its
+    /// SHA-256 truncated to 20 bytes, which is what the v2 swarm uses on
+    /// the wire.
     pub info_hash: InfoHash,
+
+    /// SHA-256 of the info dictionary of torrents with version 2 metadata,
+    /// their `btmh` in magnets.
+    pub info_hash_v2: Option<Hash>,
+    pub version: Version,
+
+    /// SHA-1 hashes of the pieces. Empty for version 2 only torrents.
     pub piece_hashes: Vec<u8>,
+
+    /// Merkle roots of the pieces of torrents with version 2 metadata.
+    pub piece_roots: Vec<PieceRoot>,
     pub piece_len: usize,
+
+    /// Total length of the files, padding files included.
     pub length: usize,
     pub name: String,
+
+    /// Files of a multi-file torrent, padding files included. Empty for
+    /// single-file torrents.
+    pub files: Vec<FileInfo>,
+
+    /// Private torrent (BEP 27), whose peers only come from its trackers.
+    pub private: bool,
     pub tracker_urls: Vec<String>,
+
+    /// Servers hosting the file itself (BEP 19)
+    pub url_list: Vec<String>,
+
+    /// Servers handing out the pieces on request (BEP 17)
+    pub http_seeds: Vec<String>,
     pub peers: HashSet<SocketAddr>,
     pub peers_v6: HashSet<SocketAddr>,
 }
 
 impl Torrent {
     pub fn parse_file(data: &[u8]) -> anyhow::Result<Self> {
+        Self::parse_file_with_limits(data, &TorrentLimits::default())
+    }
+
+    /// Parse a torrent file, rejecting torrents over the `limits`.
+    pub fn parse_file_with_limits(data: &[u8], limits: &TorrentLimits) -> anyhow::Result<Self> {
         use ParseError::*;
 
+        // The piece layers are keyed by the merkle roots of the files
         let parser = &mut Parser::new();
+        parser.binary_keys(true);
         let dict = parser.parse::<Dict>(data)?;
         let announce = dict.get_str("announce").context(AnnounceRequired)?;
         let info = dict.get_dict("info").context(InfoDictRequired)?;
         let info_bytes = info.as_raw_bytes();
-        let info_hash = Sha1::from(info_bytes).digest().bytes();
+        let version = Version::of(&info);
 
-        let length = info.get_int("length").context(LengthRequired)?;
+        let mut info_hash = Sha1::from(info_bytes).digest().bytes();
+        let info_hash_v2 = (version != Version::V1).then(|| merkle::sha256(info_bytes));
+        if let (Version::V2, Some(v2)) = (version, info_hash_v2) {
+            info_hash.copy_from_slice(&v2[..20]);
+        }
+
+        let pieces = || info.get_bytes("pieces").context(PiecesRequired);
+        let (files, length, piece_hashes, piece_roots) = match version {
+            Version::V1 => {
+                let (files, length) = parse_files(&info)?;
+                (files, length, pieces()?.to_vec(), vec![])
+            }
+            Version::V2 => {
+                let v2 = parse_v2(&info, &layer_map(dict.get_dict("piece layers")))?;
+                (v2.files, v2.length, vec![], v2.piece_roots)
+            }
+            Version::Hybrid => {
+                // The v1 files have the same padding as the v2 layout
+                let v2 = parse_v2(&info, &layer_map(dict.get_dict("piece layers")))?;
+                let (files, length) = parse_files(&info)?;
+                let pieces = pieces()?;
+                ensure!(
+                    pieces.len() == v2.piece_roots.len() * 20,
+                    InvalidPieceLayers
+                );
+                (files, length, pieces.to_vec(), v2.piece_roots)
+            }
+        };
         let name = info.get_str("name").unwrap_or_default();
         let piece_len = info.get_int("piece length").context(PieceLengthRequired)?;
-        let pieces = info.get_bytes("pieces").context(PiecesRequired)?;
+        let num_pieces = match version {
+            Version::V2 => piece_roots.len(),
+            _ => piece_hashes.len() / 20,
+        };
+        limits.check(piece_len, length, num_pieces)?;
+        if version != Version::V2 {
+            check_pieces(&piece_hashes, piece_len, length)?;
+        }
 
         let mut tracker_urls = Vec::new();
         tracker_urls.push(announce.to_string());
@@ -47,15 +123,416 @@ impl Torrent {
             );
         }
 
+        // `url-list` may also be a single URL
+        let url_list = match dict.get_str("url-list") {
+            Some(url) => vec![url.to_string()],
+            None => str_list(&dict, "url-list"),
+        };
+
         Ok(Torrent {
             info_hash,
-            piece_hashes: pieces.to_vec(),
+            info_hash_v2,
+            version,
+            piece_hashes,
+            piece_roots,
             piece_len,
             length,
             name: name.to_owned(),
+            files,
+            private: is_private(&info),
             tracker_urls,
+            url_list,
+            http_seeds: str_list(&dict, "httpseeds"),
             peers: HashSet::new(),
             peers_v6: HashSet::new(),
         })
     }
+
+    pub fn num_pieces(&self) -> usize {
+        match self.version {
+            Version::V2 => self.piece_roots.len(),
+            _ => self.piece_hashes.len() / 20,
+        }
+    }
+
+    /// Files to show and store, i.e. without the padding files.
+    pub fn visible_files(&self) -> impl Iterator<Item = &FileInfo> {
+        self.files.iter().filter(|f| !f.padding)
+    }
+
+    /// Map of the pieces to the files. The padding files are part of the
+    /// map so that the files line up with the pieces.
+    pub fn file_map(&self) -> FileMap {
+        if self.files.is_empty() {
+            return FileMap::single(self.piece_len, self.length as u64);
+        }
+
+        let files = self.files.iter().map(|f| (f.length, f.padding));
+        FileMap::with_padding(self.piece_len, files)
+    }
+
+    /// Shareable magnet link for this torrent containing the info hash,
+    /// display name and trackers.
+    pub fn to_magnet(&self) -> String {
+        let magnet = TorrentMagnet {
+            info_hash: self.info_hash,
+            info_hash_v2: self.info_hash_v2,
+            display_name: Some(self.name.clone()).filter(|n| !n.is_empty()),
+            tracker_urls: self.unique_trackers(),
+            peer_addrs: HashSet::new(),
+        };
+        magnet.to_uri()
+    }
+
+    /// Details of the torrent for display. Printing the summary gives the
+    /// details one per line.
+    pub fn summary(&self) -> Summary {
+        let files = if self.files.is_empty() {
+            vec![FileInfo {
+                path: vec![self.name.clone()],
+                length: self.length as u64,
+                padding: false,
+            }]
+        } else {
+            self.visible_files().cloned().collect()
+        };
+
+        Summary {
+            name: self.name.clone(),
+            size: files.iter().map(|f| f.length).sum(),
+            piece_len: self.piece_len,
+            num_pieces: self.num_pieces(),
+            files,
+            trackers: self.unique_trackers(),
+            private: self.private,
+            info_hash_hex: HEXLOWER.encode(&self.info_hash),
+            info_hash_base32: BASE32.encode(&self.info_hash),
+        }
+    }
+
+    fn unique_trackers(&self) -> Vec<String> {
+        let mut tracker_urls: Vec<String> = Vec::with_capacity(self.tracker_urls.len());
+        for url in &self.tracker_urls {
+            // `announce` is usually repeated in `announce-list`
+            if !tracker_urls.contains(url) {
+                tracker_urls.push(url.clone());
+            }
+        }
+        tracker_urls
+    }
+}
+
+/// Details of a torrent for display, as returned by `Torrent::summary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub name: String,
+
+    /// Total length of the files, without the padding files.
+    pub size: u64,
+    pub piece_len: usize,
+    pub num_pieces: usize,
+
+    /// Files without the padding files. Single-file torrents have one file
+    /// named after the torrent.
+    pub files: Vec<FileInfo>,
+
+    /// Tracker URLs without duplicates.
+    pub trackers: Vec<String>,
+    pub private: bool,
+    pub info_hash_hex: String,
+    pub info_hash_base32: String,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Name: {}", self.name)?;
+        writeln!(f, "Size: {} ({} bytes)", human_size(self.size), self.size)?;
+        writeln!(
+            f,
+            "Pieces: {} x {}",
+            self.num_pieces,
+            human_size(self.piece_len as u64)
+        )?;
+        writeln!(f, "Private: {}", if self.private { "yes" } else { "no" })?;
+        writeln!(f, "Info hash: {}", self.info_hash_hex)?;
+        writeln!(f, "Info hash (base32): {}", self.info_hash_base32)?;
+
+        writeln!(f, "Trackers:")?;
+        for url in &self.trackers {
+            writeln!(f, "  {}", url)?;
+        }
+
+        writeln!(f, "Files:")?;
+        for file in &self.files {
+            writeln!(f, "  {} ({})", file.path.join("/"), human_size(file.length))?;
+        }
+        Ok(())
+    }
+}
+
+/// Size in the largest binary unit it makes at least one of, e.g. "1.5 MiB".
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn str_list(dict: &Dict, key: &str) -> Vec<String> {
+    dict.get_list(key)
+        .map(|list| {
+            list.iter()
+                .filter_map(|url| url.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_magnet() {
+        let torrent = Torrent {
+            info_hash: [1; 20],
+            info_hash_v2: None,
+            version: Version::V1,
+            piece_hashes: vec![],
+            piece_roots: vec![],
+            piece_len: 0,
+            length: 0,
+            name: "file.txt".into(),
+            files: vec![],
+            private: false,
+            tracker_urls: vec!["http://a.com".into(), "http://a.com".into()],
+            url_list: vec![],
+            http_seeds: vec![],
+            peers: HashSet::new(),
+            peers_v6: HashSet::new(),
+        };
+
+        assert_eq!(
+            torrent.to_magnet(),
+            "magnet:?xt=urn:btih:0101010101010101010101010101010101010101\
+             &dn=file.txt&tr=http%3A%2F%2Fa.com"
+        );
+    }
+
+    #[test]
+    fn parse_web_seeds() {
+        let info =
+            b"4:infod6:lengthi10e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let torrent = |http_seeds: &[u8], url_list: &[u8]| {
+            let mut data = b"d8:announce8:http://a".to_vec();
+            data.extend_from_slice(http_seeds);
+            data.extend_from_slice(info);
+            data.extend_from_slice(url_list);
+            data.push(b'e');
+            Torrent::parse_file(&data).unwrap()
+        };
+
+        let t = torrent(b"9:httpseedsl8:http://be", b"8:url-list8:http://c");
+        assert_eq!(t.http_seeds, ["http://b"]);
+        assert_eq!(t.url_list, ["http://c"]);
+
+        let t = torrent(b"", b"8:url-listl8:http://c8:http://de");
+        assert!(t.http_seeds.is_empty());
+        assert_eq!(t.url_list, ["http://c", "http://d"]);
+    }
+
+    #[test]
+    fn padding_files() {
+        let mut data = b"d8:announce8:http://a4:infod5:filesl".to_vec();
+        data.extend(b"d6:lengthi5e4:pathl1:aee");
+        data.extend(b"d4:attr1:p6:lengthi3e4:pathl4:.pad1:3ee");
+        data.extend(b"d6:lengthi10e4:pathl3:dir1:bee");
+        data.extend(b"e4:name1:t12:piece lengthi8e6:pieces60:");
+        data.extend([0; 60]);
+        data.extend(b"ee");
+
+        let t = Torrent::parse_file(&data).unwrap();
+        assert_eq!(t.length, 18);
+        let visible: Vec<_> = t.visible_files().map(|f| f.path.join("/")).collect();
+        assert_eq!(visible, ["a", "dir/b"]);
+
+        let map = t.file_map();
+        assert_eq!(map.num_files(), 3);
+        assert_eq!(map.stored_len(), 15);
+
+        let summary = t.summary();
+        assert_eq!(summary.size, 15);
+        assert_eq!(summary.files.len(), 2);
+    }
+
+    #[test]
+    fn summary() {
+        let mut data = b"d8:announce8:http://a13:announce-listll8:http://ael8:http://bee".to_vec();
+        data.extend(b"4:infod6:lengthi1572864e4:name5:a.iso12:piece lengthi262144e");
+        data.extend(b"6:pieces120:");
+        data.extend([0; 120]);
+        data.extend(b"7:privatei1eee");
+
+        let t = Torrent::parse_file(&data).unwrap();
+        let summary = t.summary();
+        assert_eq!(summary.name, "a.iso");
+        assert_eq!(summary.size, 1572864);
+        assert_eq!(summary.num_pieces, 6);
+        assert_eq!(summary.trackers, ["http://a", "http://b"]);
+        assert!(summary.private);
+        assert_eq!(summary.files[0].path, ["a.iso"]);
+        assert_eq!(summary.info_hash_hex, HEXLOWER.encode(&t.info_hash));
+        assert_eq!(summary.info_hash_base32.len(), 32);
+
+        let text = summary.to_string();
+        assert!(text.contains("Size: 1.5 MiB (1572864 bytes)\n"));
+        assert!(text.contains("Pieces: 6 x 256.0 KiB\n"));
+        assert!(text.contains("Private: yes\n"));
+        assert!(text.ends_with("Files:\n  a.iso (1.5 MiB)\n"));
+    }
+
+    /// Version 2 torrent with a file of three pieces and one of less than a
+    /// piece, and the data of the pieces.
+    fn v2_torrent(hybrid: bool) -> (Vec<u8>, Vec<u8>) {
+        use merkle::data_root;
+
+        let piece_len = merkle::BLOCK_SIZE * 2;
+        let a = vec![1; piece_len * 2 + 100];
+        let b = vec![2; 5000];
+        let root_a = data_root(&a, 8);
+        let root_b = data_root(&b, 1);
+        let layer_a: Vec<u8> = a.chunks(piece_len).flat_map(|p| data_root(p, 2)).collect();
+
+        let mut data = b"d8:announce8:http://a4:infod9:file treed".to_vec();
+        for (name, file, root) in [("a", &a, root_a), ("b", &b, root_b)] {
+            data.extend(format!("1:{}d0:d6:lengthi{}e11:pieces root32:", name, file.len()).bytes());
+            data.extend(root);
+            data.extend(b"ee");
+        }
+        data.push(b'e');
+        if hybrid {
+            data.extend(b"5:filesld6:lengthi65636e4:pathl1:aee");
+            data.extend(b"d4:attr1:p6:lengthi32668e4:pathl4:.pad5:32668ee");
+            data.extend(b"d6:lengthi5000e4:pathl1:beee");
+        }
+        data.extend(b"12:meta versioni2e4:name1:t12:piece lengthi32768e");
+        if hybrid {
+            data.extend(b"6:pieces80:");
+            data.extend([0; 80]);
+        }
+        data.extend(b"e12:piece layersd32:");
+        data.extend(root_a);
+        data.extend(format!("{}:", layer_a.len()).bytes());
+        data.extend(layer_a);
+        data.extend(b"ee");
+
+        let mut content = a;
+        content.resize(piece_len * 3, 0);
+        content.extend(b);
+        (data, content)
+    }
+
+    #[test]
+    fn parse_v2() {
+        let (data, content) = v2_torrent(false);
+        let t = Torrent::parse_file(&data).unwrap();
+        assert_eq!(t.version, Version::V2);
+        assert!(t.piece_hashes.is_empty());
+        assert_eq!(t.num_pieces(), 4);
+        assert_eq!(t.length, content.len());
+
+        let files: Vec<_> = t
+            .files
+            .iter()
+            .map(|f| (f.path.join("/"), f.length))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("a".into(), 65636),
+                (".pad/32668".into(), 32668),
+                ("b".into(), 5000)
+            ]
+        );
+        assert_eq!(t.file_map().stored_len(), 70636);
+
+        for (root, piece) in t.piece_roots.iter().zip(content.chunks(t.piece_len)) {
+            assert!(root.verify(piece));
+        }
+
+        assert!(Parser::new().parse::<Dict>(&data).is_err());
+        assert_eq!(t.info_hash[..], merkle::sha256(info_bytes(&data))[..20]);
+    }
+
+    #[test]
+    fn parse_hybrid() {
+        let (data, _) = v2_torrent(true);
+        let t = Torrent::parse_file(&data).unwrap();
+        assert_eq!(t.version, Version::Hybrid);
+        assert_eq!(t.piece_roots.len(), 4);
+        assert_eq!(t.info_hash, Sha1::from(info_bytes(&data)).digest().bytes());
+    }
+
+    fn info_bytes(data: &[u8]) -> &[u8] {
+        let mut parser = Parser::new();
+        parser.binary_keys(true);
+        let dict = parser.parse::<Dict>(data).unwrap();
+        dict.get_dict("info").unwrap().as_raw_bytes()
+    }
+
+    #[test]
+    fn metadata_with_fetched_layers() {
+        use crate::metainfo::{missing_layers, MetaInfo};
+
+        let (data, content) = v2_torrent(false);
+        let t = Torrent::parse_file(&data).unwrap();
+        let info = info_bytes(&data);
+        let limits = TorrentLimits::default();
+
+        // The layer of the file of three pieces
+        let layers = missing_layers(info, &mut Parser::new(), &limits).unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].num_pieces, 3);
+        assert!(MetaInfo::parse_with_limits(info, &mut Parser::new(), &limits).is_err());
+
+        let layer: Vec<u8> = t.piece_roots[..3].iter().flat_map(|r| r.root).collect();
+        let fetched = vec![(layers[0].pieces_root, layer)];
+        let m = MetaInfo::parse_with_layers(info, &mut Parser::new(), &limits, fetched).unwrap();
+        assert_eq!(m.version, Version::V2);
+        assert_eq!(m.piece_roots, t.piece_roots);
+        assert_eq!(m.length, content.len());
+    }
+
+    #[test]
+    fn reject_non_utf8_file_names() {
+        let (data, _) = v2_torrent(false);
+        let pos = data.windows(4).position(|w| w == b"1:bd").unwrap();
+        let mut data = data;
+        data[pos + 2] = 0xff;
+        assert!(Torrent::parse_file(&data).is_err());
+    }
+
+    #[test]
+    fn reject_bad_piece_layers() {
+        let (mut data, _) = v2_torrent(false);
+        let last = data.len() - 3;
+        data[last] ^= 1;
+        assert!(Torrent::parse_file(&data).is_err());
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1024), "1.0 KiB");
+        assert_eq!(human_size(5 << 30), "5.0 GiB");
+    }
 }
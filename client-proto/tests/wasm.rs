@@ -0,0 +1,43 @@
+//! Smoke tests for wasm32-unknown-unknown, run with `wasm-pack test --node`.
+//! Only the parsing of torrents and magnets is meant for the web; the
+//! connections need sockets and a clock.
+
+#![cfg(target_arch = "wasm32")]
+
+use client_proto::magnet::TorrentMagnet;
+use client_proto::metainfo::MetaInfo;
+use client_proto::torrent::Torrent;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+const INFO: &[u8] = b"d6:lengthi10e4:name1:a12:piece lengthi16384e6:pieces0:e";
+
+#[wasm_bindgen_test]
+fn parse_magnet() {
+    let uri = "magnet:?xt=urn:btih:abababababababababababababababababababab&dn=foo";
+    let magnet = TorrentMagnet::parse(uri).unwrap();
+    assert_eq!(magnet.info_hash, [0xab; 20]);
+    assert_eq!(magnet.display_name.as_deref(), Some("foo"));
+    assert!(TorrentMagnet::parse("magnet:?dn=foo").is_err());
+}
+
+#[wasm_bindgen_test]
+fn parse_metainfo() {
+    let info = MetaInfo::parse(INFO).unwrap();
+    assert_eq!(info.length, 10);
+    assert_eq!(info.name.as_deref(), Some("a"));
+}
+
+#[wasm_bindgen_test]
+fn parse_torrent() {
+    let mut data = b"d8:announce8:http://a4:info".to_vec();
+    data.extend(INFO);
+    data.push(b'e');
+
+    let torrent = Torrent::parse_file(&data).unwrap();
+    assert_eq!(torrent.tracker_urls, ["http://a"]);
+    assert_eq!(torrent.piece_len, 16384);
+
+    // The info hash is hashed in wasm as well
+    let magnet = TorrentMagnet::parse(&torrent.to_magnet()).unwrap();
+    assert_eq!(magnet.info_hash, torrent.info_hash);
+}
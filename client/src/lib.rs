@@ -2,14 +2,105 @@
 extern crate tracing;
 
 use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, ensure};
-use proto::{buf::RecvBuf, conn::Connection, event::Event, msg::Packet};
+use futures::FutureExt;
+use proto::{
+    bitfield::Bitfield,
+    buf::{BufBudget, RecvBuf},
+    conn::{Connection, ExtLimits},
+    event::{Event, PeerEvent},
+    metainfo::PieceLayer,
+    msg::{BlockRequest, HashRequest, MessageId, Packet},
+};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub use proto::*;
 
 pub mod metadata;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+/// How long the peer has to send its handshake unless changed.
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the peer has to send a piece layer, all batches together.
+const PIECE_LAYER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes `Client::read_available` makes room for beyond the unread ones,
+/// enough for a burst of a few dozen small messages.
+const READ_AHEAD: usize = 512;
+
+/// Max length of the packets read from the peer unless changed.
+pub const DEFAULT_MAX_PACKET_LEN: usize = 1024 * 1024;
+
+/// When [`Client::flush`] writes the queued messages to the stream.
+///
+/// Holding the messages back for a bit lets the ones queued soon after go
+/// out in the same write, saving syscalls and small packets when a lot of
+/// requests are sent. The messages are flushed anyway once the client has
+/// to wait for the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Longest time a message is held back.
+    pub max_delay: Duration,
+
+    /// Write right away once this many bytes are queued.
+    pub max_bytes: usize,
+}
+
+impl FlushPolicy {
+    /// Write on every flush.
+    pub const IMMEDIATE: Self = Self {
+        max_delay: Duration::ZERO,
+        max_bytes: 0,
+    };
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self::IMMEDIATE
+    }
+}
+
+/// Decides whether to write the queued messages according to the flush
+/// policy.
+#[derive(Debug, Default)]
+struct Flusher {
+    policy: FlushPolicy,
+
+    /// When the oldest message held back was flushed.
+    pending_since: Option<Instant>,
+}
+
+impl Flusher {
+    /// When the messages held back have to be written.
+    fn deadline(&self) -> Option<Instant> {
+        self.pending_since.map(|t| t + self.policy.max_delay)
+    }
+
+    async fn flush(
+        &mut self,
+        stream: &mut impl AsyncStream,
+        conn: &mut Connection,
+        force: bool,
+    ) -> io::Result<()> {
+        let pending = conn.pending_send();
+        if !force && pending > 0 {
+            let now = Instant::now();
+            let since = *self.pending_since.get_or_insert(now);
+            if pending < self.policy.max_bytes && now < since + self.policy.max_delay {
+                return Ok(());
+            }
+        }
+
+        flush(stream, conn).await?;
+        self.pending_since = None;
+        Ok(())
+    }
+}
 
 pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin {}
 
@@ -19,6 +110,9 @@ pub struct Client<Stream> {
     stream: Stream,
     conn: Connection,
     recv_buf: RecvBuf,
+    handshake_timeout: Duration,
+    max_packet_len: usize,
+    flusher: Flusher,
 }
 
 impl<Stream> Client<Stream>
@@ -30,9 +124,74 @@ where
             stream,
             conn: Connection::new(),
             recv_buf: RecvBuf::with_capacity(12),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_packet_len: DEFAULT_MAX_PACKET_LEN,
+            flusher: Flusher::default(),
         }
     }
 
+    /// Advertise the extension protocol in the handshake. Enabled by default.
+    pub fn set_extended(&mut self, enable: bool) {
+        self.conn.set_extended(enable);
+    }
+
+    /// Set how long the peer has to send its handshake. 10 seconds by
+    /// default.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Set the max length of the packets read from the peer. Longer packets
+    /// fail the read. 1 MiB by default, which fits the blocks but may not
+    /// fit the bitfield of a torrent with a lot of pieces; see
+    /// [`Client::fit_bitfield`].
+    pub fn set_max_packet_len(&mut self, len: usize) {
+        self.max_packet_len = len;
+    }
+
+    /// Raise the max packet length, if needed, to fit the bitfield of
+    /// a torrent with `num_pieces` pieces.
+    pub fn fit_bitfield(&mut self, num_pieces: usize) {
+        let len = 1 + num_pieces.div_ceil(8);
+        self.max_packet_len = self.max_packet_len.max(len);
+    }
+
+    /// Account the receive buffer in `budget`, shared with other
+    /// connections, and follow its limits on the buffer's growth.
+    pub fn set_recv_budget(&mut self, budget: BufBudget) {
+        self.recv_buf.set_budget(budget);
+    }
+
+    /// Set when `flush` writes the queued messages. By default they're
+    /// written on every flush.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flusher.policy = policy;
+    }
+
+    /// Client name and version sent in the extended handshake.
+    pub fn set_client_version(&mut self, version: impl Into<String>) {
+        self.conn.set_client_version(version);
+    }
+
+    /// Advertise our DHT node listening on `port` in the handshake, and send
+    /// the port to peers which run a DHT node too. Not advertised by
+    /// default.
+    pub fn set_dht_port(&mut self, port: Option<u16>) {
+        self.conn.set_dht_port(port);
+    }
+
+    /// Never unchoke the peer, even when it is interested.
+    pub fn set_download_only(&mut self, enable: bool) {
+        self.conn.set_download_only(enable);
+    }
+
+    /// Unchoke the peer once it's interested and choke it once it isn't.
+    /// Enabled by default; turn it off to choke and unchoke the peer
+    /// yourself.
+    pub fn set_auto_choke(&mut self, enable: bool) {
+        self.conn.set_auto_choke(enable);
+    }
+
     pub async fn send_handshake(
         &mut self,
         info_hash: &InfoHash,
@@ -40,30 +199,60 @@ where
     ) -> anyhow::Result<()> {
         debug!("Send handshake");
         self.conn.send_handshake(info_hash, peer_id);
-        self.flush().await
+        self.flush_now().await
     }
 
+    /// Receive the peer's handshake. Fails with a [`Error`] telling which
+    /// check failed if the handshake is cut short, has the wrong protocol
+    /// string or info hash, or doesn't arrive in time.
     pub async fn recv_handshake(&mut self, info_hash: &InfoHash) -> anyhow::Result<PeerId> {
         debug!("Recv handshake");
 
         let mut buf = [0; 68];
-        self.stream.read_exact(&mut buf).await?;
+        let timeout = self.handshake_timeout;
+        match tokio::time::timeout(timeout, self.read_handshake(&mut buf)).await {
+            Ok(result) => result?,
+            Err(_) => bail!(Error::HandshakeTimeout),
+        }
         self.conn.recv_handshake(info_hash, buf)
     }
 
+    /// Read the handshake, checking the protocol string as it comes in.
+    async fn read_handshake(&mut self, buf: &mut [u8; 68]) -> anyhow::Result<()> {
+        let mut len = 0;
+        while len < buf.len() {
+            let n = self.stream.read(&mut buf[len..]).await?;
+            ensure!(n > 0, Error::IncompleteHandshake(len));
+            len += n;
+            self.conn.check_protocol(&buf[..len])?;
+        }
+        Ok(())
+    }
+
     pub async fn read_packet(&mut self) -> anyhow::Result<Option<Packet<'_>>> {
         let len = self.read_packet_bytes().await?;
         if len == 0 {
             // Keep-alive
+            self.conn.recv_packet(&[]);
             return Ok(None);
         }
 
-        let header_len = Packet::header_len(self.recv_buf.peek());
-        ensure!(len >= header_len + 1, "Invalid packet length");
+        // Unknown messages are skipped by the connection
+        let header_len = MessageId::try_from(self.recv_buf.peek()).map_or(0, MessageId::header_len);
+        ensure!(len > header_len, "Invalid packet length");
 
         let buf = self.recv_buf.read(len);
         let packet = self.conn.recv_packet(buf);
-        flush(&mut self.stream, &mut self.conn).await?;
+        ensure!(
+            !self.conn.is_garbage(),
+            Error::TooManyUnknownMessages(self.conn.unknown_msgs())
+        );
+        ensure!(!self.conn.is_flooding(), Error::ExtendedFlood);
+
+        // Replies such as the extended handshake
+        self.flusher
+            .flush(&mut self.stream, &mut self.conn, false)
+            .await?;
         Ok(packet)
     }
 
@@ -76,6 +265,10 @@ where
 
     pub async fn get_metadata(&mut self) -> anyhow::Result<Vec<u8>> {
         debug!("Request metadata");
+        ensure!(
+            self.conn.is_extended(),
+            "Peer doesn't support the extension protocol"
+        );
 
         while !self.conn.ext_handshaked() {
             self.read_packet().await?;
@@ -88,12 +281,62 @@ where
         loop {
             self.read_packet().await?;
 
+            // Skip the other events, e.g. messages of other extensions
             while let Some(event) = self.conn.poll_event() {
-                match event {
-                    Event::Metadata(metadata) => return Ok(metadata),
+                if let Event::Metadata(metadata) = event {
+                    return Ok(metadata);
+                }
+            }
+        }
+    }
+
+    /// Fetch the piece layer of a file of a version 2 torrent from the peer
+    /// (BEP 52), e.g. after its metadata. Each batch of hashes is checked
+    /// against the root of the file with its proof. The peer has 30
+    /// seconds to send them all.
+    pub async fn get_piece_layer(&mut self, layer: &PieceLayer) -> anyhow::Result<Vec<u8>> {
+        debug!("Request piece layer of {} pieces", layer.num_pieces);
+        let requests = layer.requests();
+        for &req in &requests {
+            self.conn.send_hash_request(req);
+        }
+        self.flush_now().await?;
+
+        let read = self.read_piece_layer(layer, &requests);
+        match tokio::time::timeout(PIECE_LAYER_TIMEOUT, read).await {
+            Ok(result) => result,
+            Err(_) => bail!("Piece layer request timed out"),
+        }
+    }
+
+    async fn read_piece_layer(
+        &mut self,
+        layer: &PieceLayer,
+        requests: &[HashRequest],
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut hashes = vec![None; requests.len()];
+        while hashes.iter().any(Option::is_none) {
+            match self.read_packet().await? {
+                Some(Packet::Hashes { req, hashes: data }) => {
+                    let i = match requests.iter().position(|r| *r == req) {
+                        Some(i) => i,
+                        None => continue,
+                    };
+                    let verified = merkle::verify_hashes(&req, data);
+                    ensure!(verified.is_some(), "Invalid hashes");
+                    hashes[i] = verified;
                 }
+                Some(Packet::HashReject(req)) if requests.contains(&req) => {
+                    bail!("Hash request rejected");
+                }
+                _ => {}
             }
         }
+
+        // The last batch is padded up to a power of two
+        let mut layer_bytes: Vec<u8> = hashes.into_iter().flatten().flatten().flatten().collect();
+        layer_bytes.truncate(layer.num_pieces * 32);
+        Ok(layer_bytes)
     }
 
     /// Receive one packet from the peer with length header removed.
@@ -106,19 +349,56 @@ where
             return Ok(0);
         }
 
-        ensure!(len <= 1024 * 1024, "Packet too large: {}", len);
+        ensure!(len <= self.max_packet_len, "Packet too large: {}", len);
         self.read_bytes(len).await?;
         Ok(len)
     }
 
+    /// Set the number of messages with unknown ids that are skipped before
+    /// the connection is considered broken.
+    pub fn set_max_unknown_msgs(&mut self, max: u32) {
+        self.conn.set_max_unknown_msgs(max);
+    }
+
+    /// Refuse to fetch metadata longer than `len` from the peer, which
+    /// [`Client::get_metadata`] buffers whole.
+    pub fn set_max_metadata_len(&mut self, len: usize) {
+        self.conn.set_max_metadata_len(len);
+    }
+
+    /// Change the limits on the extended messages accepted from the peer.
+    /// The connection is considered broken once the peer exceeds them.
+    pub fn set_ext_limits(&mut self, limits: ExtLimits) {
+        self.conn.set_ext_limits(limits);
+    }
+
+    /// Drop the messages of these extensions quietly.
+    pub fn set_ignored_extensions(&mut self, names: &[&str]) {
+        self.conn.set_ignored_extensions(names);
+    }
+
     pub fn send_request(&mut self, index: u32, begin: u32, len: u32) {
         self.conn.send_request(index, begin, len);
     }
 
+    pub fn send_cancel(&mut self, index: u32, begin: u32, len: u32) {
+        self.conn.send_cancel(index, begin, len);
+    }
+
     pub fn send_have(&mut self, index: u32) {
         self.conn.send_have(index);
     }
 
+    /// Tell the peer the pieces we have. Only valid as the first message
+    /// after the handshake; later pieces are sent with `send_have`.
+    pub fn send_have_bitfield(&mut self, have: &Bitfield) {
+        self.conn.send_have_bitfield(have);
+    }
+
+    pub fn send_choke(&mut self) {
+        self.conn.send_choke();
+    }
+
     pub fn send_unchoke(&mut self) {
         self.conn.send_unchoke();
     }
@@ -135,14 +415,206 @@ where
         self.conn.send_piece(index, begin, data);
     }
 
+    /// Ask the peer for hashes of a merkle tree (BEP 52).
+    pub fn send_hash_request(&mut self, req: HashRequest) {
+        self.conn.send_hash_request(req);
+    }
+
+    /// Answer a hash request of the peer with the requested `hashes`
+    /// followed by their proof (BEP 52).
+    pub fn send_hashes(&mut self, req: HashRequest, hashes: &[u8]) {
+        self.conn.send_hashes(req, hashes);
+    }
+
+    /// Refuse a hash request of the peer (BEP 52).
+    pub fn send_hash_reject(&mut self, req: HashRequest) {
+        self.conn.send_hash_reject(req);
+    }
+
+    /// Send our extended handshake, e.g. for the peer to learn our client
+    /// version and extensions when we aren't fetching the metadata.
+    pub fn send_ext_handshake(&mut self) {
+        self.conn.send_ext_handshake();
+    }
+
+    /// Advertise the extension `name` in the extended handshake. Returns the
+    /// id its messages arrive with as [`Event::Extended`].
+    pub fn register_extension(&mut self, name: impl Into<String>) -> u8 {
+        self.conn.register_extension(name)
+    }
+
+    /// Id the peer gave the extension `name`, to send its messages with.
+    pub fn peer_extension_id(&self, name: &str) -> Option<u8> {
+        self.conn.peer_extension_id(name)
+    }
+
+    /// Send an extended message with a payload encoded by the caller.
+    pub fn send_ext_raw(&mut self, id: u8, payload: &[u8]) {
+        self.conn.send_ext_raw(id, payload);
+    }
+
+    /// Write the queued messages, unless the flush policy holds them back
+    /// for a while. Held back messages are written by a later flush or
+    /// while waiting for the peer's messages.
     pub async fn flush(&mut self) -> anyhow::Result<()> {
-        flush(&mut self.stream, &mut self.conn).await
+        self.flusher
+            .flush(&mut self.stream, &mut self.conn, false)
+            .await?;
+        Ok(())
+    }
+
+    /// Write the queued messages right away regardless of the flush
+    /// policy, e.g. before closing the connection.
+    pub async fn flush_now(&mut self) -> anyhow::Result<()> {
+        self.flusher
+            .flush(&mut self.stream, &mut self.conn, true)
+            .await?;
+        Ok(())
+    }
+
+    /// Write the queued messages and shut down the writing half of the
+    /// stream, so that the peer sees the connection closed gracefully.
+    pub async fn close(&mut self) -> anyhow::Result<()> {
+        self.flush_now().await?;
+        self.stream.shutdown().await?;
+        Ok(())
     }
 
     pub fn is_choked(&self) -> bool {
         self.conn.is_choked()
     }
 
+    /// The peer told us it's interested in our pieces.
+    pub fn is_peer_interested(&self) -> bool {
+        self.conn.is_peer_interested()
+    }
+
+    /// Returns true if the peer advertised the extension in its handshake.
+    pub fn peer_supports(&self, ext: Extension) -> bool {
+        self.conn.peer_supports(ext)
+    }
+
+    /// Take the DHT port the peer sent, if it sent one since the last call.
+    pub fn take_peer_dht_port(&mut self) -> Option<u16> {
+        self.conn.take_peer_dht_port()
+    }
+
+    /// Max number of outstanding requests the peer accepts, if known.
+    pub fn peer_reqq(&self) -> Option<u32> {
+        self.conn.peer_reqq()
+    }
+
+    /// The peer's address of the other IP family than `addr`, the one we
+    /// reached it at, if it told us in the extended handshake.
+    pub fn peer_alt_addr(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        let ip = match (addr.ip(), self.conn.peer_ips()) {
+            (IpAddr::V4(_), (_, Some(ip))) => IpAddr::V6(ip),
+            (IpAddr::V6(_), (Some(ip), _)) => IpAddr::V4(ip),
+            _ => return None,
+        };
+        let port = self.conn.peer_listen_port().unwrap_or(addr.port());
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// Smoothed time from requesting a block to receiving it. `None` until
+    /// the first requested block is received.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.conn.rtt().srtt()
+    }
+
+    /// Shortest time from requesting a block to receiving it, which grows
+    /// less with the requests queued at the peer than `rtt`. `None` until
+    /// the first requested block is received.
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.conn.rtt().min()
+    }
+
+    /// Take the oldest event seen by `read_packet`, e.g. an extended
+    /// message for an extension the connection doesn't implement.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.conn.poll_event()
+    }
+
+    /// Take the oldest change of the peer's choke or interest state seen
+    /// by `read_packet`.
+    pub fn poll_peer_event(&mut self) -> Option<PeerEvent> {
+        self.conn.poll_peer_event()
+    }
+
+    /// Bytes exchanged with the peer so far, split into block data and
+    /// protocol overhead.
+    pub fn wire_stats(&self) -> WireStats {
+        self.conn.wire_stats()
+    }
+
+    /// When messages last went each way over the connection.
+    pub fn activity(&self) -> Activity {
+        self.conn.activity()
+    }
+
+    /// Since when the peer has unchoked us, while we're interested, without
+    /// sending a block. `None` while we're not interested or choked.
+    pub fn starved_since(&self) -> Option<Instant> {
+        self.conn.starved_since()
+    }
+
+    /// Pieces the peer has announced so far.
+    pub fn peer_pieces(&self) -> &Bitfield {
+        self.conn.peer_pieces()
+    }
+
+    /// Returns true if the peer has all of the `num_pieces` pieces.
+    pub fn peer_is_seed(&self, num_pieces: usize) -> bool {
+        self.conn.peer_is_seed(num_pieces)
+    }
+
+    /// Take the next block request from the peer which is yet to be served.
+    ///
+    /// Requests cancelled by the peer are never returned.
+    pub fn pop_request(&mut self) -> Option<BlockRequest> {
+        self.conn.pop_request()
+    }
+
+    /// Number of block requests from the peer that are yet to be served.
+    pub fn num_requests(&self) -> usize {
+        self.conn.num_requests()
+    }
+
+    /// Take in what the peer has sent already, without waiting for more, so
+    /// that `has_buffered_packet` sees the rest of a burst of messages.
+    pub fn read_available(&mut self) -> io::Result<()> {
+        let unread = self.recv_buf.unread().len();
+        let b = self.recv_buf.write_reserve(unread + READ_AHEAD);
+        match self.stream.read(b).now_or_never() {
+            // The end of the stream is reported by the next read
+            Some(Ok(n)) => self.recv_buf.advance_write(n),
+            Some(Err(e)) => return Err(e),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Returns true if a whole packet is received but not read yet, so that
+    /// `read_packet` returns without waiting for the peer.
+    pub fn has_buffered_packet(&self) -> bool {
+        let unread = self.recv_buf.unread();
+        match unread.get(..4) {
+            Some(len) => {
+                let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+                unread.len() - 4 >= len
+            }
+            None => false,
+        }
+    }
+
+    /// Take the request for the piece fewest peers have according to
+    /// `availability`, the number of peers having each piece, e.g.
+    /// `WorkQueue::availability_snapshot` of the torrent, so that our upload
+    /// bandwidth goes to the rare pieces first.
+    pub fn pop_rarest_request(&mut self, availability: &[u32]) -> Option<BlockRequest> {
+        self.conn.pop_rarest_request(availability)
+    }
+
     async fn read_bytes(&mut self, len: usize) -> io::Result<()> {
         loop {
             let b = self.recv_buf.write_reserve(len);
@@ -152,7 +624,22 @@ where
                 return Ok(());
             }
 
-            let n = self.stream.read(b).await?;
+            let n = match self.flusher.deadline() {
+                Some(deadline) => {
+                    let read = self.stream.read(b);
+                    match tokio::time::timeout_at(deadline.into(), read).await {
+                        Ok(n) => n?,
+                        Err(_) => {
+                            // Time's up for the messages held back
+                            self.flusher
+                                .flush(&mut self.stream, &mut self.conn, true)
+                                .await?;
+                            continue;
+                        }
+                    }
+                }
+                None => self.stream.read(b).await?,
+            };
             if n == 0 {
                 return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "early EOF"));
             }
@@ -162,102 +649,30 @@ where
     }
 }
 
-async fn flush(stream: &mut impl AsyncStream, conn: &mut Connection) -> anyhow::Result<()> {
-    stream.write_all(&conn.send_buf()).await?;
+/// Write the queued messages. Only the bytes written are taken from the
+/// queue, so that the rest is still sent if the write is cancelled.
+async fn flush(stream: &mut impl AsyncStream, conn: &mut Connection) -> io::Result<()> {
+    while conn.pending_send() > 0 {
+        let n = stream.write(conn.pending_bytes()).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        conn.consume_sent(n);
+    }
     stream.flush().await?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        io,
-        pin::Pin,
-        task::{Context, Poll},
-    };
+    use std::time::Duration;
 
-    use futures::{
-        channel::mpsc::{self, Receiver, Sender},
-        join, ready, SinkExt, StreamExt,
-    };
+    use futures::join;
     use proto::msg::{Packet, PieceBlock};
-    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-
-    use crate::Client;
-
-    struct Peer {
-        tx: Sender<Vec<u8>>,
-        rx: Receiver<Vec<u8>>,
-        remaining: Vec<u8>,
-    }
-
-    impl Peer {
-        pub fn create_pair() -> (Peer, Peer) {
-            let (t1, r1) = mpsc::channel(200);
-            let (t2, r2) = mpsc::channel(200);
-            let p1 = Peer {
-                tx: t1,
-                rx: r2,
-                remaining: vec![],
-            };
-            let p2 = Peer {
-                tx: t2,
-                rx: r1,
-                remaining: vec![],
-            };
-            (p1, p2)
-        }
-    }
-
-    impl AsyncRead for Peer {
-        fn poll_read(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
-            buf: &mut ReadBuf<'_>,
-        ) -> Poll<io::Result<()>> {
-            let data = if self.remaining.is_empty() {
-                match ready!(self.rx.poll_next_unpin(cx)) {
-                    Some(data) => data,
-                    None => return Poll::Ready(Ok(())),
-                }
-            } else {
-                std::mem::take(&mut self.remaining)
-            };
-
-            if data.len() <= buf.capacity() {
-                buf.put_slice(&data);
-            } else {
-                buf.put_slice(&data[..buf.capacity()]);
-                self.remaining = data[buf.capacity()..].to_vec();
-            }
-
-            Poll::Ready(Ok(()))
-        }
-    }
-
-    fn err() -> io::Error {
-        io::Error::from(io::ErrorKind::BrokenPipe)
-    }
-
-    impl AsyncWrite for Peer {
-        fn poll_write(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
-            buf: &[u8],
-        ) -> Poll<io::Result<usize>> {
-            ready!(self.tx.poll_ready(cx)).map_err(|_| err())?;
-            self.tx.start_send_unpin(buf.to_vec()).map_err(|_| err())?;
-            Poll::Ready(Ok(buf.len()))
-        }
-
-        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-            self.tx.poll_flush_unpin(cx).map_err(|_| err())
-        }
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-            self.tx.poll_close_unpin(cx).map_err(|_| err())
-        }
-    }
+    use crate::testing::Peer;
+    use crate::{Client, Error, FlushPolicy, DEFAULT_MAX_PACKET_LEN};
 
     #[tokio::test]
     async fn handshake() {
@@ -279,6 +694,78 @@ mod tests {
         join!(f1, f2);
     }
 
+    #[tokio::test]
+    async fn handshake_errors() {
+        let (a, b) = Peer::create_pair();
+        let mut c = Client::new(a);
+        let mut peer = Client::new(b);
+        peer.stream.write_all(b"GET / HTTP/1.1").await.unwrap();
+        let e = c.recv_handshake(&[0; 20]).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::UnsupportedProtocol)));
+
+        let (a, b) = Peer::create_pair();
+        let mut c = Client::new(a);
+        let mut peer = Client::new(b);
+        peer.stream.write_all(b"\x13BitTorrent").await.unwrap();
+        drop(peer);
+        let e = c.recv_handshake(&[0; 20]).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(Error::IncompleteHandshake(11))
+        ));
+
+        let (a, _b) = Peer::create_pair();
+        let mut c = Client::new(a);
+        c.set_handshake_timeout(Duration::from_millis(10));
+        let e = c.recv_handshake(&[0; 20]).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::HandshakeTimeout)));
+    }
+
+    #[tokio::test]
+    async fn get_piece_layer() {
+        use proto::merkle::{self, BLOCK_SIZE};
+        use proto::metainfo::PieceLayer;
+
+        let layer: Vec<_> = (0..3u8).map(|i| merkle::sha256(&[i])).collect();
+        let piece_layer = PieceLayer {
+            pieces_root: merkle::root(layer.clone(), 4, [0; 32]),
+            piece_len: BLOCK_SIZE,
+            num_pieces: 3,
+        };
+
+        let (a, b) = Peer::create_pair();
+        let hashes = layer.clone();
+        let f1 = async move {
+            let mut c = Client::new(b);
+            let req = match c.read_packet().await.unwrap() {
+                Some(Packet::HashRequest(req)) => req,
+                p => panic!("{:?}", p),
+            };
+            let mut data: Vec<u8> = hashes.iter().flatten().copied().collect();
+            data.extend([0; 32]);
+            c.conn.send_hashes(req, &data);
+            c.flush().await.unwrap();
+
+            // Garbage for the second request
+            let req = match c.read_packet().await.unwrap() {
+                Some(Packet::HashRequest(req)) => req,
+                p => panic!("{:?}", p),
+            };
+            data[0] ^= 1;
+            c.conn.send_hashes(req, &data);
+            c.flush().await.unwrap();
+        };
+
+        let f2 = async move {
+            let mut c = Client::new(a);
+            let fetched = c.get_piece_layer(&piece_layer).await.unwrap();
+            assert_eq!(fetched, layer.concat());
+            assert!(c.get_piece_layer(&piece_layer).await.is_err());
+        };
+
+        join!(f1, f2);
+    }
+
     #[tokio::test]
     async fn send_piece() {
         let (a, b) = Peer::create_pair();
@@ -304,6 +791,50 @@ mod tests {
         join!(f1, f2);
     }
 
+    #[tokio::test]
+    async fn unknown_messages_are_skipped_until_limit() {
+        let (a, b) = Peer::create_pair();
+        let f1 = async move {
+            let mut a = a;
+            // Two messages with an unknown id followed by a piece
+            a.write_all(&[0, 0, 0, 2, 100, 1]).await.unwrap();
+            a.write_all(&[0, 0, 0, 1, 101]).await.unwrap();
+            let mut c = Client::new(a);
+            c.send_piece(1, 2, b"hello");
+            c.flush().await.unwrap();
+        };
+
+        let f2 = async move {
+            let mut c = Client::new(b);
+            c.set_max_unknown_msgs(1);
+            assert!(c.read_packet().await.unwrap().is_none());
+            assert!(c.read_packet().await.is_err());
+        };
+
+        join!(f1, f2);
+    }
+
+    #[tokio::test]
+    async fn max_packet_len() {
+        // Bitfield of a torrent with 12M pieces
+        let bitfield = vec![0xff; 3 * DEFAULT_MAX_PACKET_LEN / 2];
+        let mut packet = ((bitfield.len() + 1) as u32).to_be_bytes().to_vec();
+        packet.push(5);
+        packet.extend(&bitfield);
+
+        let (mut a, b) = Peer::create_pair();
+        a.write_all(&packet).await.unwrap();
+        let mut c = Client::new(b);
+        assert!(c.read_packet().await.is_err());
+
+        let (mut a, b) = Peer::create_pair();
+        a.write_all(&packet).await.unwrap();
+        let mut c = Client::new(b);
+        c.fit_bitfield(bitfield.len() * 8);
+        assert!(c.read_packet().await.unwrap().is_none());
+        assert_eq!(c.peer_pieces().count(), bitfield.len() * 8);
+    }
+
     #[tokio::test]
     async fn send_interested_and_receive_unchoke() {
         let (a, b) = Peer::create_pair();
@@ -348,4 +879,63 @@ mod tests {
 
         join!(f1, f2);
     }
+
+    #[tokio::test]
+    async fn flush_policy_coalesces_writes() {
+        let (a, mut b) = Peer::create_pair();
+        let mut c = Client::new(a);
+        c.set_flush_policy(FlushPolicy {
+            max_delay: Duration::from_secs(3600),
+            max_bytes: 10,
+        });
+
+        // A Have message is 9 bytes
+        c.send_have(1);
+        c.flush().await.unwrap();
+        assert!(b.rx.try_recv().is_err());
+
+        c.send_have(2);
+        c.flush().await.unwrap();
+        assert_eq!(b.rx.try_recv().unwrap().len(), 18);
+
+        c.send_have(3);
+        c.flush_now().await.unwrap();
+        assert_eq!(b.rx.try_recv().unwrap().len(), 9);
+    }
+
+    #[tokio::test]
+    async fn held_back_messages_are_sent_while_reading() {
+        let (a, mut b) = Peer::create_pair();
+        let mut c = Client::new(a);
+        c.set_flush_policy(FlushPolicy {
+            max_delay: Duration::from_millis(10),
+            max_bytes: 1024,
+        });
+
+        c.send_have(1);
+        c.flush().await.unwrap();
+        assert!(b.rx.try_recv().is_err());
+
+        // Nothing to read, but the Have goes out once the delay passes
+        let read = tokio::time::timeout(Duration::from_millis(200), c.read_packet());
+        assert!(read.await.is_err());
+        assert_eq!(b.rx.try_recv().unwrap().len(), 9);
+    }
+
+    #[tokio::test]
+    async fn cancelled_flush_keeps_the_unwritten_bytes() {
+        let (a, mut b) = tokio::io::duplex(4);
+        let mut c = Client::new(a);
+        c.send_have(1);
+
+        // Only 4 of the 9 bytes fit
+        let flush = tokio::time::timeout(Duration::from_millis(10), c.flush());
+        assert!(flush.await.is_err());
+
+        let mut buf = [0; 9];
+        let (flushed, read) = join!(c.flush(), b.read_exact(&mut buf));
+        flushed.unwrap();
+        read.unwrap();
+        assert_eq!(buf, [0, 0, 0, 5, 4, 0, 0, 0, 1]);
+    }
 }
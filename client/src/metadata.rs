@@ -3,29 +3,33 @@ use std::net::SocketAddr;
 use anyhow::{bail, ensure};
 use ben::Parser;
 use futures::{stream::FuturesUnordered, StreamExt};
-use proto::{metainfo::MetaInfo, InfoHash, PeerId};
-use sha1::Sha1;
+use proto::metainfo::{is_info_of, missing_layers, MetaInfo, PieceLayers, TorrentLimits};
+use proto::{InfoHash, PeerId};
 use tokio::net::TcpStream;
 
 use crate::Client;
 
+/// Fetch the info dictionary from the first of the `peers` to send a valid
+/// one, along with the piece layers of version 2 torrents. Torrents over
+/// the `limits` are rejected.
 pub async fn request_metadata(
     peers: impl Iterator<Item = &SocketAddr>,
     info_hash: &InfoHash,
     peer_id: &PeerId,
+    version: &str,
+    limits: &TorrentLimits,
 ) -> anyhow::Result<MetaInfo> {
     let mut f = peers
-        .map(|peer| request_metadata_from_peer(*peer, info_hash, peer_id))
+        .map(|peer| request_metadata_from_peer(*peer, info_hash, peer_id, version, limits))
         .collect::<FuturesUnordered<_>>();
 
     let parser = &mut Parser::new();
     while let Some(result) = f.next().await {
         match result {
-            Ok(m) => {
-                if let Ok(m) = MetaInfo::parse_with(&m, parser) {
-                    return Ok(m);
-                }
-            }
+            Ok((m, layers)) => match MetaInfo::parse_with_layers(&m, parser, limits, layers) {
+                Ok(m) => return Ok(m),
+                Err(e) => warn!("Invalid metadata: {}", e),
+            },
             Err(e) => warn!("{}", e),
         }
     }
@@ -38,16 +42,25 @@ async fn request_metadata_from_peer(
     peer: SocketAddr,
     info_hash: &InfoHash,
     peer_id: &PeerId,
-) -> anyhow::Result<Vec<u8>> {
+    version: &str,
+    limits: &TorrentLimits,
+) -> anyhow::Result<(Vec<u8>, PieceLayers)> {
     let socket = TcpStream::connect(peer).await?;
     let mut client = Client::new(socket);
+    client.set_client_version(version);
+    client.set_max_metadata_len(limits.max_metadata_len);
     client.send_handshake(info_hash, peer_id).await?;
     client.recv_handshake(info_hash).await?;
     client.send_unchoke();
     client.send_interested();
 
     let metadata = client.get_metadata().await?;
-    let hash = Sha1::from(&metadata).digest().bytes();
-    ensure!(hash == *info_hash, "Invalid metadata");
-    Ok(metadata)
+    ensure!(is_info_of(&metadata, info_hash), "Invalid metadata");
+
+    let mut layers = vec![];
+    for layer in missing_layers(&metadata, &mut Parser::new(), limits)? {
+        let hashes = client.get_piece_layer(&layer).await?;
+        layers.push((layer.pieces_root, hashes));
+    }
+    Ok((metadata, layers))
 }
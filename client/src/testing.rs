@@ -0,0 +1,93 @@
+//! In-memory streams for testing code built on [`Client`](crate::Client)
+//! without sockets.
+//!
+//! Only available with the `testing` feature.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    ready, SinkExt, StreamExt,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// One end of an in-memory connection. What is written to one end of
+/// a pair is read from the other. Dropping an end closes the connection.
+pub struct Peer {
+    pub(crate) tx: Sender<Vec<u8>>,
+    pub(crate) rx: Receiver<Vec<u8>>,
+    remaining: Vec<u8>,
+}
+
+impl Peer {
+    pub fn create_pair() -> (Peer, Peer) {
+        let (t1, r1) = mpsc::channel(200);
+        let (t2, r2) = mpsc::channel(200);
+        let p1 = Peer {
+            tx: t1,
+            rx: r2,
+            remaining: vec![],
+        };
+        let p2 = Peer {
+            tx: t2,
+            rx: r1,
+            remaining: vec![],
+        };
+        (p1, p2)
+    }
+}
+
+impl AsyncRead for Peer {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let data = if self.remaining.is_empty() {
+            match ready!(self.rx.poll_next_unpin(cx)) {
+                Some(data) => data,
+                None => return Poll::Ready(Ok(())),
+            }
+        } else {
+            std::mem::take(&mut self.remaining)
+        };
+
+        if data.len() <= buf.remaining() {
+            buf.put_slice(&data);
+        } else {
+            let n = buf.remaining();
+            buf.put_slice(&data[..n]);
+            self.remaining = data[n..].to_vec();
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn err() -> io::Error {
+    io::Error::from(io::ErrorKind::BrokenPipe)
+}
+
+impl AsyncWrite for Peer {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.tx.poll_ready(cx)).map_err(|_| err())?;
+        self.tx.start_send_unpin(buf.to_vec()).map_err(|_| err())?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.tx.poll_flush_unpin(cx).map_err(|_| err())
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.tx.poll_close_unpin(cx).map_err(|_| err())
+    }
+}
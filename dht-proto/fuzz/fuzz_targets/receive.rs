@@ -0,0 +1,16 @@
+#![no_main]
+
+use dht_proto::{Dht, NodeId};
+use libfuzzer_sys::fuzz_target;
+use std::{net::SocketAddr, time::Instant};
+
+// Feed arbitrary datagrams to the DHT. Malformed messages must be dropped
+// without panicking.
+fuzz_target!(|data: &[u8]| {
+    let now = Instant::now();
+    let router = SocketAddr::from(([127, 0, 0, 1], 6881));
+    let mut dht = Dht::new(NodeId::all(1), vec![router], now);
+
+    dht.receive(data, router, now);
+    while dht.poll_event().is_some() {}
+});
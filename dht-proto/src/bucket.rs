@@ -1,24 +1,59 @@
-use crate::contact::Contact;
+use crate::{contact::Contact, id::NodeId, table::TableConfig};
 
 #[derive(Debug, Default, Clone)]
 pub struct Bucket {
+    /// Nodes in the order they were last heard from, least recent first.
     pub live: Vec<Contact>,
     pub extra: Vec<Contact>,
+
+    /// Live node being pinged to see if it can be replaced by a newer one.
+    pub pinging: Option<NodeId>,
 }
 
 impl Bucket {
-    // The 'K' constant in Kademlia algorithm
+    // The 'K' constant in Kademlia algorithm. The default bucket size and
+    // the number of nodes sent in replies.
     pub const MAX_LEN: usize = 8;
 
     pub const fn new() -> Self {
         Self {
             live: Vec::new(),
             extra: Vec::new(),
+            pinging: None,
         }
     }
 
-    pub fn is_full(&self) -> bool {
-        self.live.len() >= Self::MAX_LEN && self.extra.len() >= Self::MAX_LEN
+    pub fn is_full(&self, config: &TableConfig) -> bool {
+        self.live.len() >= config.bucket_size && self.extra.len() >= config.extra_size
+    }
+
+    /// Drop the nodes over the sizes of `config`, the ones which failed
+    /// first and then the least recently seen. Confirmed replacements take
+    /// the places of failed nodes and fill any room left.
+    pub fn truncate(&mut self, config: &TableConfig) {
+        while self.live.len() > config.bucket_size {
+            let i = self.find_bad().unwrap_or(0);
+            self.remove_live(i);
+        }
+
+        while let Some(j) = self.extra.iter().rposition(|c| c.is_confirmed()) {
+            if self.live.len() >= config.bucket_size {
+                let Some(i) = self.find_bad() else { break };
+                self.remove_live(i);
+            }
+            let c = self.extra.remove(j);
+            self.live.push(c);
+        }
+
+        let n = self.extra.len().saturating_sub(config.extra_size);
+        self.extra.drain(..n);
+    }
+
+    fn remove_live(&mut self, i: usize) {
+        let c = self.live.remove(i);
+        if self.pinging == Some(c.id) {
+            self.pinging = None;
+        }
     }
 
     pub fn get_contacts<'a>(&'a self, out: &mut Vec<&'a Contact>) {
@@ -28,23 +63,19 @@ impl Bucket {
             .for_each(|c| out.push(c));
     }
 
-    pub fn replace_node(&mut self, contact: Contact) -> bool {
-        debug_assert!(self.live.len() >= Bucket::MAX_LEN);
-
-        let maybe_stale = find_stale(&mut self.live).or_else(|| find_stale(&mut self.extra));
-
-        if let Some(stale) = maybe_stale {
-            *stale = contact;
-            return true;
-        }
-
-        false
+    /// Index of the live node which failed the most, if any failed at all.
+    pub fn find_bad(&self) -> Option<usize> {
+        self.live
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.failed())
+            .max_by_key(|(_, c)| c.fail_count())
+            .map(|(i, _)| i)
     }
-}
 
-fn find_stale(contacts: &mut Vec<Contact>) -> Option<&mut Contact> {
-    contacts
-        .iter_mut()
-        .filter(|c| c.fail_count() > 0)
-        .max_by_key(|c| c.fail_count())
+    /// Mark the live node at `i` as the most recently seen one.
+    pub fn touch(&mut self, i: usize) {
+        let c = self.live.remove(i);
+        self.live.push(c);
+    }
 }
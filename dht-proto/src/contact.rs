@@ -1,11 +1,14 @@
 use crate::{
+    bucket::Bucket,
     id::NodeId,
     util::{self, WithBytes},
 };
-use ben::{Encode, LazyBytesEncoder};
+use ben::{DictEncoder, Encode, LazyBytesEncoder};
 use std::net::SocketAddr;
+use std::time::Instant;
 
 bitflags::bitflags! {
+    /// Progress of a contact within a lookup.
     pub struct ContactStatus: u8 {
         const QUERIED       = 1 << 0;
         const INITIAL       = 1 << 1;
@@ -18,12 +21,17 @@ bitflags::bitflags! {
     }
 }
 
+/// A node in the routing table or a lookup, along with how well it has
+/// been responding to us.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Contact {
     pub id: NodeId,
     pub addr: SocketAddr,
     pub status: ContactStatus,
     timeout_count: Option<u8>,
+
+    /// Last time the node replied to us or queried us
+    pub last_seen: Option<Instant>,
 }
 
 impl Contact {
@@ -33,6 +41,7 @@ impl Contact {
             addr,
             timeout_count: None,
             status: ContactStatus::INITIAL,
+            last_seen: None,
         }
     }
 
@@ -80,6 +89,12 @@ impl Contact {
     }
 }
 
+impl From<Node> for Contact {
+    fn from(node: Node) -> Self {
+        Self::new(node.id, node.addr)
+    }
+}
+
 impl Encode for Contact {
     fn encode(&self, buf: &mut Vec<u8>) {
         let mut bytes = LazyBytesEncoder::<38>::new(buf);
@@ -89,6 +104,98 @@ impl Encode for Contact {
     }
 }
 
+/// A node as given in a compact node list, its id and address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl Node {
+    pub fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Self { id, addr }
+    }
+
+    /// Append the node in its compact form, the id followed by the address.
+    pub fn write_compact(&self, buf: &mut Vec<u8>) {
+        buf.extend(&self.id[..]);
+        util::write_addr(buf, self.addr);
+    }
+}
+
+impl From<&Contact> for Node {
+    fn from(contact: &Contact) -> Self {
+        Self::new(contact.id, contact.addr)
+    }
+}
+
+/// Compact node lists, e.g. for a reply or the closest nodes a lookup
+/// found. Holds at most `Bucket::MAX_LEN` nodes of each address family.
+#[derive(Debug, Default)]
+pub struct CompactNodeList {
+    nodes: Vec<u8>,
+    nodes6: Vec<u8>,
+}
+
+impl CompactNodeList {
+    const V4_LEN: usize = std::mem::size_of::<CompactNode<4>>();
+    const V6_LEN: usize = std::mem::size_of::<CompactNode<16>>();
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the node to the list of its address family. Returns false if
+    /// that list is already full.
+    pub fn push(&mut self, node: Node) -> bool {
+        let (buf, len) = match node.addr {
+            SocketAddr::V4(_) => (&mut self.nodes, Self::V4_LEN),
+            SocketAddr::V6(_) => (&mut self.nodes6, Self::V6_LEN),
+        };
+
+        if buf.len() / len >= Bucket::MAX_LEN {
+            return false;
+        }
+
+        if buf.is_empty() {
+            buf.reserve_exact(len * Bucket::MAX_LEN);
+        }
+
+        node.write_compact(buf);
+        true
+    }
+
+    /// The nodes in the order they were added, the IPv4 ones first.
+    pub fn iter(&self) -> impl Iterator<Item = Node> + '_ {
+        CompactNodeIter::<4>::new(&self.nodes).chain(CompactNodeIter::<16>::new(&self.nodes6))
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len() / Self::V4_LEN + self.nodes6.len() / Self::V6_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.nodes6.is_empty()
+    }
+
+    /// Write the lists to the reply as `nodes` and `nodes6`. `nodes` is
+    /// always written, `nodes6` only if there are IPv6 nodes.
+    pub fn write_to(&self, dict: &mut DictEncoder<'_>) {
+        dict.insert("nodes", &self.nodes[..]);
+        if !self.nodes6.is_empty() {
+            dict.insert("nodes6", &self.nodes6[..]);
+        }
+    }
+}
+
+impl Extend<Node> for CompactNodeList {
+    fn extend<I: IntoIterator<Item = Node>>(&mut self, iter: I) {
+        for node in iter {
+            self.push(node);
+        }
+    }
+}
+
 #[repr(C)]
 struct CompactNode<const N: usize> {
     id: NodeId,
@@ -96,20 +203,25 @@ struct CompactNode<const N: usize> {
     port: [u8; 2],
 }
 
+/// Nodes of a compact node list, `nodes` with `N` = 4 for IPv4 or `nodes6`
+/// with `N` = 16 for IPv6.
 pub struct CompactNodeIter<'a, const N: usize> {
     iter: std::slice::Iter<'a, CompactNode<N>>,
 }
 
 impl<'a, const N: usize> CompactNodeIter<'a, N> {
-    pub fn new(buf: &'a [u8]) -> anyhow::Result<Self> {
+    /// Iterate over the nodes in a compact node list. A truncated node at
+    /// the end of the list is ignored.
+    pub fn new(buf: &'a [u8]) -> Self {
         let size = std::mem::size_of::<CompactNode<N>>();
 
-        anyhow::ensure!(
-            buf.len() % size == 0,
-            "Compact node list must have length multiple of {}, actual: {}",
-            size,
-            buf.len()
-        );
+        if !buf.len().is_multiple_of(size) {
+            debug!(
+                "Compact node list must have length multiple of {}, actual: {}",
+                size,
+                buf.len()
+            );
+        }
 
         let iter = unsafe {
             let ptr = buf.as_ptr().cast::<CompactNode<N>>();
@@ -117,30 +229,77 @@ impl<'a, const N: usize> CompactNodeIter<'a, N> {
             slice.iter()
         };
 
-        Ok(Self { iter })
+        Self { iter }
     }
 }
 
 impl<'a> Iterator for CompactNodeIter<'a, 4> {
-    type Item = Contact;
+    type Item = Node;
 
     fn next(&mut self) -> Option<Self::Item> {
         let node = self.iter.next()?;
         let port = u16::from_be_bytes(node.port);
         let addr = SocketAddr::from((node.ip, port));
 
-        Some(Contact::new(node.id, addr))
+        Some(Node::new(node.id, addr))
     }
 }
 
 impl<'a> Iterator for CompactNodeIter<'a, 16> {
-    type Item = Contact;
+    type Item = Node;
 
     fn next(&mut self) -> Option<Self::Item> {
         let node = self.iter.next()?;
         let port = u16::from_be_bytes(node.port);
         let addr = SocketAddr::from((node.ip, port));
 
-        Some(Contact::new(node.id, addr))
+        Some(Node::new(node.id, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_compact_nodes() {
+        let a = Node::new(NodeId::all(1), SocketAddr::from(([1, 2, 3, 4], 5)));
+        let b = Node::new(NodeId::all(2), SocketAddr::from(([5, 6, 7, 8], 9)));
+
+        let mut buf = vec![];
+        a.write_compact(&mut buf);
+        b.write_compact(&mut buf);
+        buf.truncate(buf.len() - 3);
+
+        let nodes: Vec<_> = CompactNodeIter::<4>::new(&buf).collect();
+        assert_eq!(nodes, vec![a]);
+    }
+
+    #[test]
+    fn empty_compact_nodes() {
+        assert_eq!(CompactNodeIter::<16>::new(&[]).count(), 0);
+    }
+
+    #[test]
+    fn compact_node_list_bounded() {
+        let mut list = CompactNodeList::new();
+        for i in 0..Bucket::MAX_LEN as u8 {
+            let n = Node::new(NodeId::all(i), SocketAddr::from(([1, 2, 3, i], 5)));
+            assert!(list.push(n));
+        }
+
+        let n = Node::new(NodeId::all(9), SocketAddr::from(([1, 2, 3, 9], 5)));
+        assert!(!list.push(n));
+
+        let n6 = Node::new(NodeId::all(9), SocketAddr::from(([1; 16], 5)));
+        assert!(list.push(n6));
+
+        assert_eq!(
+            CompactNodeIter::<4>::new(&list.nodes).count(),
+            Bucket::MAX_LEN
+        );
+        assert_eq!(CompactNodeIter::<16>::new(&list.nodes6).next(), Some(n6));
+        assert_eq!(list.len(), Bucket::MAX_LEN + 1);
+        assert_eq!(list.iter().last(), Some(n6));
     }
 }
@@ -1,11 +1,12 @@
 use ben::Encode;
 use data_encoding::HEXUPPER_PERMISSIVE as hex;
+use hash20::InfoHash;
 use rand::distributions::uniform::{SampleBorrow, SampleUniform, UniformSampler};
 use rand::Rng;
 use std::fmt;
 use std::ops::{BitAnd, BitAndAssign, BitXor, BitXorAssign, Deref, DerefMut};
 
-type Bytes = [u8; 20];
+type Bytes = hash20::Hash;
 
 #[derive(Copy, Clone, Default, PartialEq, PartialOrd, Eq, Ord, Hash)]
 #[repr(transparent)]
@@ -52,6 +53,20 @@ impl NodeId {
         Ok(id)
     }
 
+    /// Returns the node ID matching the given info hash. Lookups for a
+    /// torrent search for the nodes closest to this ID.
+    pub const fn from_info_hash(info_hash: &InfoHash) -> Self {
+        Self(*info_hash)
+    }
+
+    pub const fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+
+    pub const fn into_bytes(self) -> Bytes {
+        self.0
+    }
+
     pub fn is_zero(&self) -> bool {
         self.iter().all(|b| *b == 0)
     }
@@ -101,6 +116,26 @@ impl From<Bytes> for NodeId {
     }
 }
 
+impl From<&Bytes> for NodeId {
+    fn from(buf: &Bytes) -> Self {
+        Self(*buf)
+    }
+}
+
+impl From<NodeId> for Bytes {
+    fn from(id: NodeId) -> Self {
+        id.0
+    }
+}
+
+impl TryFrom<&[u8]> for NodeId {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        Bytes::try_from(buf).map(Self)
+    }
+}
+
 impl Deref for NodeId {
     type Target = Bytes;
 
@@ -246,6 +281,18 @@ mod tests {
         assert_eq!("3F3F3F3F3F3F3F3F3F3F3F3F3F3F3F3F3F3F3F3F", s);
     }
 
+    #[test]
+    fn info_hash_conversions() {
+        let info_hash = [7; 20];
+        let id = NodeId::from_info_hash(&info_hash);
+        assert_eq!(id, NodeId::from(&info_hash));
+        assert_eq!(id, NodeId::from(info_hash));
+        assert_eq!(info_hash, <[u8; 20]>::from(id));
+        assert_eq!(&info_hash, id.as_bytes());
+        assert_eq!(id, NodeId::try_from(&info_hash[..]).unwrap());
+        assert!(NodeId::try_from(&info_hash[1..]).is_err());
+    }
+
     #[test]
     fn sort_order() {
         let mut a = [NodeId::all(0), NodeId::all(3), NodeId::all(1)];
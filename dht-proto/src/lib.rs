@@ -4,10 +4,16 @@ extern crate tracing;
 mod bucket;
 mod contact;
 mod id;
-mod msg;
+pub mod msg;
 mod server;
 mod table;
 mod util;
 
+pub use contact::{CompactNodeIter, CompactNodeList, Node};
+pub use hash20::InfoHash;
 pub use id::NodeId;
-pub use server::{ClientRequest, Dht, Event, TaskId};
+pub use server::{
+    ClientRequest, Dht, Event, KeepaliveConfig, Metrics, QueryCounts, QueryHandler, QueryReply,
+    TaskId,
+};
+pub use table::TableConfig;
@@ -1,3 +1,27 @@
+//! KRPC messages of the DHT (BEP 5), for building DHT services of other
+//! kinds on top of this crate, such as bootstrap routers or measurement
+//! nodes, without the state machine of [`Dht`](crate::Dht).
+//!
+//! Incoming messages are parsed with [`recv::Msg`], borrowing from the
+//! datagram. Our queries are encoded with the types of [`send`], and the
+//! nodes of the replies with [`CompactNodeList`](crate::CompactNodeList).
+//!
+//! ```
+//! use ben::Parser;
+//! use dht_proto::msg::recv::{Msg, QueryKind};
+//!
+//! let data = b"d1:ad2:id20:aaaaaaaaaaaaaaaaaaaae1:q4:ping1:t2:xy1:y1:qe";
+//! let mut parser = Parser::new();
+//! let entry = parser.parse(data).unwrap();
+//! match Msg::from_entry(entry).unwrap() {
+//!     Msg::Query(q) => {
+//!         assert_eq!(q.kind, QueryKind::Ping);
+//!         assert_eq!(q.txn_id, b"xy");
+//!     }
+//!     _ => unreachable!(),
+//! }
+//! ```
+
 pub mod recv;
 pub mod send;
 mod txn;
@@ -2,15 +2,55 @@ use crate::id::NodeId;
 use crate::msg::TxnId;
 use ben::decode::{Dict, List};
 use ben::{Decode, Entry};
-use std::convert::TryInto;
+use std::fmt;
 
+/// Max length of a query transaction id. Other implementations send ids of
+/// various lengths which are echoed back as is, but there is no reason to
+/// echo anything longer than this.
+pub const MAX_TXN_ID_LEN: usize = 16;
+
+/// Max length of an announce token. Tokens are a few bytes long in practice.
+pub const MAX_TOKEN_LEN: usize = 64;
+
+/// Why a message was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    NotADict,
+    MissingField(&'static str),
+    InvalidField(&'static str),
+    TxnIdTooLong(usize),
+    TokenTooLong(usize),
+    UnknownType,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotADict => write!(f, "Message is not a dictionary"),
+            Self::MissingField(key) => write!(f, "Missing field: {}", key),
+            Self::InvalidField(key) => write!(f, "Invalid field: {}", key),
+            Self::TxnIdTooLong(len) => write!(f, "Transaction id too long: {} bytes", len),
+            Self::TokenTooLong(len) => write!(f, "Token too long: {} bytes", len),
+            Self::UnknownType => write!(f, "Unknown message type"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Query from another node.
 #[derive(Debug)]
 pub struct Query<'a> {
-    pub txn_id: TxnId,
+    /// Transaction id exactly as sent by the querying node.
+    pub txn_id: &'a [u8],
     pub id: NodeId,
     pub kind: QueryKind<'a>,
+
+    /// All the arguments, including the ones of `kind`.
+    pub args: Dict<'a, 'a>,
 }
 
+/// Method of a query and its arguments.
 #[derive(Debug, PartialEq)]
 pub enum QueryKind<'a> {
     Ping,
@@ -26,21 +66,31 @@ pub enum QueryKind<'a> {
         port: u16,
         token: &'a [u8],
     },
+    /// Query of a method the DHT doesn't handle itself.
+    Other(&'a [u8]),
 }
 
+/// Reply to one of our queries. Only transaction ids of the widths we send
+/// are accepted, see [`TxnId`].
 #[derive(Debug)]
 pub struct Response<'a> {
     pub txn_id: TxnId,
+
+    /// The `r` dictionary, with the id of the node and the method's values.
     pub body: Dict<'a, 'a>,
     pub id: NodeId,
 }
 
+/// Error reply to one of our queries.
 #[derive(Debug)]
 pub struct ErrorResponse<'a> {
     pub txn_id: TxnId,
+
+    /// The `e` list of the error code and message, if any.
     pub list: Option<List<'a, 'a>>,
 }
 
+/// KRPC message of any type.
 #[derive(Debug)]
 pub enum Msg<'a> {
     Query(Query<'a>),
@@ -48,82 +98,112 @@ pub enum Msg<'a> {
     Error(ErrorResponse<'a>),
 }
 
-macro_rules! node_id {
-    ($dict: expr, $key: literal) => {{
-        let id = $dict.get_bytes($key)?;
-        if id.len() == 20 {
-            let ptr = id.as_ptr().cast::<NodeId>();
-            unsafe { *ptr }
-        } else {
-            return None;
-        }
-    }};
+fn node_id(dict: &Dict<'_, '_>, key: &'static str) -> Result<NodeId, DecodeError> {
+    let id = dict.get_bytes(key).ok_or(DecodeError::MissingField(key))?;
+    NodeId::try_from(id).map_err(|_| DecodeError::InvalidField(key))
 }
 
-impl<'a> Decode<'a, 'a> for Msg<'a> {
-    fn decode(entry: Entry<'a, 'a>) -> Option<Self> {
-        let dict = entry.as_dict()?;
-        let msg_type = dict.get_bytes("y")?;
-        let txn_id = dict.get_bytes("t")?;
-        let txn_id = txn_id.try_into().ok()?;
-        let txn_id = TxnId(u16::from_be_bytes(txn_id));
+/// Transaction id of a response or an error. We only send 2 byte ids and
+/// wide 4 byte ones, so anything else can't be a reply to one of our
+/// queries.
+fn reply_txn_id(txn_id: &[u8]) -> Result<TxnId, DecodeError> {
+    let id = match *txn_id {
+        [a, b] => TxnId(u16::from_be_bytes([a, b]) as u32),
+        [a, b, c, d] => TxnId(u32::from_be_bytes([a, b, c, d])),
+        _ => return Err(DecodeError::InvalidField("t")),
+    };
+
+    // A narrow id padded to 4 bytes is not what we sent
+    if id.is_wide() != (txn_id.len() == 4) {
+        return Err(DecodeError::InvalidField("t"));
+    }
+    Ok(id)
+}
+
+impl<'a> Msg<'a> {
+    /// Decode the message, reporting why it was rejected if it's malformed.
+    pub fn from_entry(entry: Entry<'a, 'a>) -> Result<Self, DecodeError> {
+        use DecodeError::*;
+
+        let dict = entry.as_dict().ok_or(NotADict)?;
+        let msg_type = dict.get_bytes("y").ok_or(MissingField("y"))?;
+        let txn_id = dict.get_bytes("t").ok_or(MissingField("t"))?;
 
         let msg = match msg_type {
             b"q" => {
-                let kind = dict.get_bytes("q")?;
-                let args = dict.get_dict("a")?;
+                if txn_id.len() > MAX_TXN_ID_LEN {
+                    return Err(TxnIdTooLong(txn_id.len()));
+                }
+
+                let kind = dict.get_bytes("q").ok_or(MissingField("q"))?;
+                let args = dict.get_dict("a").ok_or(MissingField("a"))?;
 
                 let query_kind = match kind {
                     b"ping" => QueryKind::Ping,
                     b"find_node" => QueryKind::FindNode {
-                        target: node_id!(args, "target"),
+                        target: node_id(&args, "target")?,
                     },
                     b"get_peers" => QueryKind::GetPeers {
-                        info_hash: node_id!(args, "info_hash"),
+                        info_hash: node_id(&args, "info_hash")?,
                     },
                     b"announce_peer" => {
                         let implied_port = args
                             .get_int("implied_port")
                             .map(|n: i64| n == 1)
                             .unwrap_or(false);
+                        let port = match args.get("port") {
+                            Some(port) => port.as_int().ok_or(InvalidField("port"))?,
+                            None => return Err(MissingField("port")),
+                        };
+                        let token = args.get_bytes("token").ok_or(MissingField("token"))?;
+                        if token.len() > MAX_TOKEN_LEN {
+                            return Err(TokenTooLong(token.len()));
+                        }
                         QueryKind::AnnouncePeer {
-                            info_hash: node_id!(args, "info_hash"),
+                            info_hash: node_id(&args, "info_hash")?,
                             implied_port,
-                            port: args.get_int("port")?,
-                            token: args.get_bytes("token")?,
+                            port,
+                            token,
                         }
                     }
-                    other => {
-                        trace!("Unexpected Query type: {:?}", other);
-                        return None;
-                    }
+                    other => QueryKind::Other(other),
                 };
                 Msg::Query(Query {
                     kind: query_kind,
-                    id: node_id!(args, "id"),
+                    id: node_id(&args, "id")?,
+                    args,
                     txn_id,
                 })
             }
             b"r" => {
-                let body = dict.get_dict("r")?;
+                let body = dict.get_dict("r").ok_or(MissingField("r"))?;
                 Msg::Response(Response {
-                    id: node_id!(body, "id"),
-                    txn_id,
+                    id: node_id(&body, "id")?,
+                    txn_id: reply_txn_id(txn_id)?,
                     body,
                 })
             }
             b"e" => {
                 trace!("Error: {:?}", dict);
-                let list = dict.get_list("r");
-                Msg::Error(ErrorResponse { txn_id, list })
+                let list = dict.get_list("e");
+                Msg::Error(ErrorResponse {
+                    txn_id: reply_txn_id(txn_id)?,
+                    list,
+                })
             }
             other => {
                 trace!("Unexpected Message type: {:?}", other);
-                return None;
+                return Err(UnknownType);
             }
         };
 
-        Some(msg)
+        Ok(msg)
+    }
+}
+
+impl<'a> Decode<'a, 'a> for Msg<'a> {
+    fn decode(entry: Entry<'a, 'a>) -> Option<Self> {
+        Self::from_entry(entry).ok()
     }
 }
 
@@ -141,7 +221,7 @@ mod tests {
         match msg {
             Msg::Query(query) => {
                 assert_eq!(query.id, NodeId::all(1));
-                assert_eq!(query.txn_id, TxnId(10));
+                assert_eq!(query.txn_id, b"\x00\n");
                 assert_eq!(query.kind, QueryKind::Ping);
             }
             _ => {
@@ -149,4 +229,75 @@ mod tests {
             }
         }
     }
+
+    fn decode_err(data: &[u8]) -> DecodeError {
+        let mut parser = Parser::new();
+        let entry = parser.parse::<Entry>(data).unwrap();
+        Msg::from_entry(entry).unwrap_err()
+    }
+
+    #[test]
+    fn incoming_custom_query() {
+        let data = b"d1:ad2:id20:\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x014:sizei3ee1:q6:sample1:t2:aa1:y1:qe";
+        let mut parser = Parser::new();
+        match parser.parse::<Msg>(data).unwrap() {
+            Msg::Query(query) => {
+                assert_eq!(query.kind, QueryKind::Other(b"sample"));
+                assert_eq!(query.args.get_int::<i64>("size"), Some(3));
+            }
+            _ => panic!("Incorrect msg type"),
+        }
+    }
+
+    #[test]
+    fn query_with_long_txn_id() {
+        let data = b"d1:ad2:id20:\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01e1:q4:ping1:t4:abcd1:y1:qe";
+        let mut parser = Parser::new();
+        let entry = parser.parse::<Entry>(data).unwrap();
+        match Msg::from_entry(entry).unwrap() {
+            Msg::Query(query) => assert_eq!(query.txn_id, b"abcd"),
+            _ => panic!("Incorrect msg type"),
+        }
+    }
+
+    #[test]
+    fn query_with_oversized_txn_id() {
+        let data = b"d1:ad2:id20:\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01e1:q4:ping1:t17:aaaaaaaaaaaaaaaaa1:y1:qe";
+        assert_eq!(decode_err(data), DecodeError::TxnIdTooLong(17));
+    }
+
+    #[test]
+    fn response_with_foreign_txn_id() {
+        let data = b"d1:rd2:id20:\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01e1:t1:a1:y1:re";
+        assert_eq!(decode_err(data), DecodeError::InvalidField("t"));
+    }
+
+    #[test]
+    fn response_with_wide_txn_id() {
+        let invalid = Err(DecodeError::InvalidField("t"));
+        assert_eq!(reply_txn_id(b"\x01\x02"), Ok(TxnId(0x0102)));
+        assert_eq!(reply_txn_id(b"\x00\x01\x00\x02"), Ok(TxnId(0x0001_0002)));
+        assert_eq!(reply_txn_id(b"\x00\x00\x01\x02"), invalid);
+        assert_eq!(reply_txn_id(b"\x01\x02\x03"), invalid);
+    }
+
+    #[test]
+    fn announce_with_oversized_token() {
+        let mut data = b"d1:ad2:id20:\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x019:info_hash20:\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x024:porti5000e5:token65:".to_vec();
+        data.extend([0; 65]);
+        data.extend(b"e1:q13:announce_peer1:t2:aa1:y1:qe");
+        assert_eq!(decode_err(&data), DecodeError::TokenTooLong(65));
+    }
+
+    #[test]
+    fn announce_with_invalid_port() {
+        let data = b"d1:ad2:id20:\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x019:info_hash20:\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x02\x024:porti70000e5:token1:xe1:q13:announce_peer1:t2:aa1:y1:qe";
+        assert_eq!(decode_err(data), DecodeError::InvalidField("port"));
+    }
+
+    #[test]
+    fn short_node_id() {
+        let data = b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(decode_err(data), DecodeError::InvalidField("id"));
+    }
 }
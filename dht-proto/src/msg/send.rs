@@ -3,6 +3,7 @@ use crate::msg::TxnId;
 use ben::DictEncoder;
 use ben::Encode;
 
+/// `ping` query, checking that a node is alive.
 #[derive(Debug)]
 pub struct Ping {
     pub txn_id: TxnId,
@@ -23,6 +24,7 @@ impl Encode for Ping {
     }
 }
 
+/// `find_node` query for the nodes closest to `target`.
 #[derive(Debug)]
 pub struct FindNode {
     pub txn_id: TxnId,
@@ -45,6 +47,7 @@ impl Encode for FindNode {
     }
 }
 
+/// `get_peers` query for the peers of a torrent.
 #[derive(Debug)]
 pub struct GetPeers {
     pub txn_id: TxnId,
@@ -67,6 +70,7 @@ impl Encode for GetPeers {
     }
 }
 
+/// `announce_peer` query, with the token of the node's `get_peers` reply.
 #[derive(Debug)]
 pub struct AnnouncePeer<'a> {
     pub txn_id: TxnId,
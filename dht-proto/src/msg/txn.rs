@@ -1,18 +1,76 @@
 use ben::Encode;
+use rand::Rng;
 
+/// Smallest of the 4 byte ids. The ids below it are sent as 2 bytes, so the
+/// value of an id tells its width.
+const MIN_WIDE: u32 = 1 << 16;
+
+/// Transaction id of our queries, sent as 2 bytes, or as 4 bytes once
+/// widened for crawlers with more queries in flight than 2 bytes can tell
+/// apart.
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
-pub struct TxnId(pub u16);
+pub struct TxnId(pub u32);
 
 impl TxnId {
+    /// Id to start counting from, random so that the ids of our queries
+    /// can't be guessed by nodes spoofing replies.
+    pub fn random(wide: bool) -> Self {
+        let mut rng = rand::thread_rng();
+        if wide {
+            Self(rng.gen_range(MIN_WIDE..=u32::MAX))
+        } else {
+            Self(rng.gen::<u16>() as u32)
+        }
+    }
+
+    pub fn is_wide(&self) -> bool {
+        self.0 >= MIN_WIDE
+    }
+
+    /// Returns the id and moves on to the next one of the same width.
     pub fn next_id(&mut self) -> Self {
         let out = *self;
-        self.0 = self.0.wrapping_add(1);
+        self.0 = if self.is_wide() {
+            self.0.checked_add(1).unwrap_or(MIN_WIDE)
+        } else {
+            (self.0 + 1) % MIN_WIDE
+        };
         out
     }
 }
 
 impl Encode for TxnId {
     fn encode(&self, buf: &mut Vec<u8>) {
-        ben::encode_bytes(buf, self.0.to_be_bytes());
+        if self.is_wide() {
+            ben::encode_bytes(buf, self.0.to_be_bytes());
+        } else {
+            ben::encode_bytes(buf, (self.0 as u16).to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_within_width() {
+        let mut id = TxnId(0xffff);
+        assert_eq!(id.next_id(), TxnId(0xffff));
+        assert_eq!(id, TxnId(0));
+
+        let mut id = TxnId(u32::MAX);
+        id.next_id();
+        assert_eq!(id, TxnId(MIN_WIDE));
+        assert!(id.is_wide());
+
+        assert!(!TxnId::random(false).is_wide());
+        assert!(TxnId::random(true).is_wide());
+    }
+
+    #[test]
+    fn encode_width() {
+        assert_eq!(TxnId(0x0102).encode_to_vec(), b"2:\x01\x02");
+        assert_eq!(TxnId(0x0102_0304).encode_to_vec(), b"4:\x01\x02\x03\x04");
     }
 }
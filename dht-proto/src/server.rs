@@ -1,17 +1,67 @@
-use crate::{id::NodeId, msg::recv::Msg, server::task::Task, table::RoutingTable};
-use ben::Parser;
+use crate::{
+    contact::Contact,
+    id::NodeId,
+    msg::recv::Msg,
+    server::task::Task,
+    table::{RoutingTable, TableConfig},
+};
+use ben::{Entry, Parser};
 use rpc::RpcManager;
 use slab::Slab;
-use std::{net::SocketAddr, time::Instant};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use token::Tokens;
 
 use self::task::{AnnounceTask, BootstrapTask, GetPeersTask, PingTask};
 
+pub use handler::{QueryHandler, QueryReply};
+pub use metrics::{Metrics, QueryCounts};
 pub use rpc::Event;
 pub use task::TaskId;
 
+mod handler;
+mod metrics;
+mod replies;
 mod rpc;
 mod task;
+mod token;
 
+/// Number of restored nodes pinged at a time.
+const RESTORE_BATCH: usize = 8;
+
+/// Time between two batches of pings to the restored nodes.
+const RESTORE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of times a restored node is pinged again when it doesn't answer.
+const RESTORE_RETRIES: u8 = 1;
+
+/// Pings of the live nodes we haven't heard from in a while. They keep the
+/// mappings of the NATs between us and the nodes open, so that the nodes
+/// can still reach us, and find the nodes which went away long before the
+/// 15 minute bucket refresh would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    /// Time between two rounds of pings. Nodes heard from within this long
+    /// aren't pinged.
+    pub interval: Duration,
+
+    /// Max number of nodes pinged per round.
+    pub max_pings: usize,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            max_pings: 8,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum ClientRequest {
     Announce { info_hash: NodeId },
     GetPeers { info_hash: NodeId },
@@ -24,18 +74,85 @@ pub struct Dht {
     tasks: Slab<Box<dyn Task>>,
     parser: Parser,
     rpc: RpcManager,
+
+    /// Restored nodes which haven't been pinged yet
+    unverified: VecDeque<(NodeId, SocketAddr)>,
+    next_restore: Instant,
+
+    /// Port announced for our peer, if not the port of the DHT itself
+    announce_port: Option<u16>,
+
+    keepalive: Option<KeepaliveConfig>,
+    next_keepalive: Instant,
 }
 
 impl Dht {
     pub fn new(id: NodeId, router_nodes: Vec<SocketAddr>, now: Instant) -> Self {
+        Self::with_config(id, router_nodes, TableConfig::default(), now)
+    }
+
+    /// DHT with the bucket sizes of `config` for its routing table.
+    pub fn with_config(
+        id: NodeId,
+        router_nodes: Vec<SocketAddr>,
+        config: TableConfig,
+        now: Instant,
+    ) -> Self {
         Self {
-            table: RoutingTable::new(id, router_nodes, now),
+            table: RoutingTable::with_config(id, router_nodes, config, now),
             tasks: Slab::new(),
             parser: Parser::new(),
-            rpc: RpcManager::new(id),
+            rpc: RpcManager::new(id, now),
+            unverified: VecDeque::new(),
+            next_restore: now,
+            announce_port: None,
+            keepalive: None,
+            next_keepalive: now,
         }
     }
 
+    /// Change the bucket sizes of the routing table. Nodes over the new
+    /// sizes are dropped.
+    pub fn set_table_config(&mut self, config: TableConfig) {
+        self.table.set_config(config);
+    }
+
+    pub fn table_config(&self) -> TableConfig {
+        self.table.config
+    }
+
+    /// Port announced to the nodes as the one our peer listens on. `None`
+    /// sets `implied_port`, so that the nodes take the source port of the
+    /// announce, i.e. the port of the DHT. Applies to the announces started
+    /// from now on.
+    pub fn set_announce_port(&mut self, port: Option<u16>) {
+        self.announce_port = port;
+    }
+
+    pub fn announce_port(&self) -> Option<u16> {
+        self.announce_port
+    }
+
+    /// Send 4 byte transaction ids instead of the usual 2 byte ones. Lets
+    /// crawlers keep more than 65536 queries in flight without their ids
+    /// colliding. Disabled by default.
+    pub fn set_wide_txn_ids(&mut self, enable: bool) {
+        self.rpc.set_wide_txn_ids(enable);
+    }
+
+    /// Ping the live nodes we haven't heard from in a while, as set by
+    /// `config`. `None`, the default, leaves them to the bucket refresh.
+    pub fn set_keepalive(&mut self, config: Option<KeepaliveConfig>, now: Instant) {
+        self.keepalive = config;
+        if let Some(k) = config {
+            self.next_keepalive = now + k.interval;
+        }
+    }
+
+    pub fn keepalive(&self) -> Option<KeepaliveConfig> {
+        self.keepalive
+    }
+
     pub fn is_idle(&self) -> bool {
         self.tasks.is_empty()
     }
@@ -44,44 +161,146 @@ impl Dht {
         self.rpc.events.pop_front()
     }
 
+    /// Ids and addresses of the live nodes in the routing table.
+    pub fn nodes(&self) -> Vec<(NodeId, SocketAddr)> {
+        self.table.live_nodes().collect()
+    }
+
+    /// Add nodes learned elsewhere, e.g. from another instance, to the
+    /// routing table.
+    pub fn add_nodes<I>(&mut self, nodes: I, now: Instant)
+    where
+        I: IntoIterator<Item = (NodeId, SocketAddr)>,
+    {
+        for (id, addr) in nodes {
+            self.table.add_contact(Contact::new(id, addr), now);
+        }
+    }
+
+    /// Add the nodes of an earlier session, e.g. saved to disk. They may
+    /// be long gone, so unlike with `add_nodes`, each node is only added to
+    /// the routing table once it answers a ping. The nodes are pinged in
+    /// batches of `RESTORE_BATCH`, once no bootstrap is running, and those
+    /// which don't answer get `RESTORE_RETRIES` more pings.
+    pub fn restore_nodes<I>(&mut self, nodes: I, now: Instant)
+    where
+        I: IntoIterator<Item = (NodeId, SocketAddr)>,
+    {
+        self.unverified.extend(nodes);
+        if now >= self.next_restore {
+            self.ping_restored(now);
+        }
+    }
+
+    /// Ping a node known only by its address, e.g. from the PORT message
+    /// of a peer. Like the restored nodes, it joins the routing table once
+    /// it answers.
+    pub fn ping_addr(&mut self, addr: SocketAddr, now: Instant) {
+        if self.unverified.iter().any(|(_, a)| *a == addr) {
+            return;
+        }
+        self.restore_nodes([(NodeId::new(), addr)], now);
+    }
+
+    /// Secrets of the get_peers tokens, e.g. to be restored in the next
+    /// session so that the tokens given out stay valid.
+    pub fn token_secrets(&self) -> [[u8; 20]; 2] {
+        self.rpc.own_tokens.secrets()
+    }
+
+    /// Restore the secrets of [`token_secrets`]. Their tokens stay valid
+    /// until the next rotation.
+    ///
+    /// [`token_secrets`]: Self::token_secrets
+    pub fn restore_token_secrets(&mut self, secrets: [[u8; 20]; 2], now: Instant) {
+        self.rpc.own_tokens = Tokens::with_secrets(secrets, now);
+    }
+
+    /// Answer the queries of methods we don't know with `handler`. Without
+    /// one they get a "Method Unknown" error.
+    pub fn set_query_handler(&mut self, handler: impl QueryHandler + 'static) {
+        self.rpc.query_handler = Some(Box::new(handler));
+    }
+
+    /// Counts of the queries and replies so far, and how long lookups take.
+    pub fn metrics(&self) -> Metrics {
+        self.rpc.metrics.clone()
+    }
+
+    /// Number of restored nodes waiting to be pinged.
+    pub fn num_unverified(&self) -> usize {
+        self.unverified.len()
+    }
+
     pub fn poll_timeout(&self) -> Option<Instant> {
         let a = self.rpc.next_timeout();
         let b = self.table.next_timeout();
+        let c = self.restoring().then_some(self.next_restore);
+        let d = self.keepalive.map(|_| self.next_keepalive);
 
-        match (a, b) {
-            (Some(a), Some(b)) => Some(a.min(b)),
-            _ => a.or(b),
-        }
+        [a, b, c, d].into_iter().flatten().min()
     }
 
     pub fn tick(&mut self, now: Instant) {
         trace!("Server::tick");
         self.rpc
             .check_timeouts(&mut self.table, &mut self.tasks, now);
+        self.rpc.own_tokens.rotate(now);
 
         if let Some(refresh) = self.table.next_refresh(now) {
             trace!("Time to refresh the routing table");
             self.add_request(refresh, now);
         }
+
+        self.send_pings(now);
+
+        if now >= self.next_restore {
+            self.ping_restored(now);
+        }
+
+        if let Some(keepalive) = self.keepalive {
+            if now >= self.next_keepalive {
+                self.send_keepalives(keepalive, now);
+            }
+        }
     }
 
     pub fn add_request(&mut self, request: ClientRequest, now: Instant) -> Option<TaskId> {
         use ClientRequest::*;
 
-        let entry = self.tasks.vacant_entry();
-        let tid = TaskId(entry.key());
-        let table = &mut self.table;
-        let mut task: Box<dyn Task> = match request {
+        let traversal = !matches!(request, Ping { .. });
+        let bootstrap = matches!(request, Bootstrap { .. });
+        let port = self.announce_port;
+        let tid = self.add_task(traversal, now, |table, tid| match request {
             GetPeers { info_hash } => Box::new(GetPeersTask::new(info_hash, table, tid)),
             Bootstrap { target } => Box::new(BootstrapTask::new(target, table, tid)),
-            Announce { info_hash } => Box::new(AnnounceTask::new(info_hash, table, tid)),
+            Announce { info_hash } => Box::new(AnnounceTask::new(info_hash, port, table, tid)),
             Ping { id, addr } => Box::new(PingTask::new(id, addr, tid)),
-        };
+        })?;
+
+        if bootstrap {
+            self.rpc.bootstraps += 1;
+        }
+        Some(tid)
+    }
+
+    fn add_task(
+        &mut self,
+        traversal: bool,
+        now: Instant,
+        make: impl FnOnce(&mut RoutingTable, TaskId) -> Box<dyn Task>,
+    ) -> Option<TaskId> {
+        let entry = self.tasks.vacant_entry();
+        let tid = TaskId(entry.key());
+        let mut task = make(&mut self.table, tid);
 
         let done = task.add_requests(&mut self.rpc, now);
         if done {
             None
         } else {
+            if traversal {
+                self.rpc.traversals.insert(tid, now);
+            }
             entry.insert(task);
             Some(tid)
         }
@@ -97,8 +316,9 @@ impl Dht {
     #[instrument(skip_all, fields(?addr))]
     pub fn receive(&mut self, buf: &[u8], addr: SocketAddr, now: Instant) {
         debug!("Got {} bytes", buf.len());
+        self.rpc.metrics.bytes_in += buf.len() as u64;
 
-        let msg = match self.parser.parse::<Msg>(buf) {
+        let entry = match self.parser.parse::<Entry>(buf) {
             Ok(x) => x,
             Err(e) => {
                 warn!("Error parsing message: {}", e);
@@ -106,8 +326,58 @@ impl Dht {
             }
         };
 
+        let msg = match Msg::from_entry(entry) {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("Dropping malformed message: {}", e);
+                return;
+            }
+        };
+
         self.rpc
-            .handle_response(msg, addr, &mut self.table, &mut self.tasks, now);
+            .handle_response(msg, buf, addr, &mut self.table, &mut self.tasks, now);
+
+        self.send_pings(now);
+    }
+
+    /// Ping the nodes which the routing table wants to evict.
+    fn send_pings(&mut self, now: Instant) {
+        while let Some(ping) = self.table.next_ping() {
+            self.add_request(ping, now);
+        }
+    }
+
+    fn send_keepalives(&mut self, config: KeepaliveConfig, now: Instant) {
+        let stale = self
+            .table
+            .stale_nodes(now, config.interval, config.max_pings);
+        trace!("Pinging {} nodes to keep them alive", stale.len());
+        for (id, addr) in stale {
+            self.add_request(ClientRequest::Ping { id, addr }, now);
+        }
+        self.next_keepalive = now + config.interval;
+    }
+
+    /// Whether restored nodes are waiting to be pinged. They wait for the
+    /// bootstraps, which fill the routing table with nodes known to be
+    /// alive.
+    fn restoring(&self) -> bool {
+        !self.unverified.is_empty() && self.rpc.bootstraps == 0
+    }
+
+    fn ping_restored(&mut self, now: Instant) {
+        if !self.restoring() {
+            return;
+        }
+
+        let n = self.unverified.len().min(RESTORE_BATCH);
+        trace!("Pinging {} restored nodes", n);
+        for (id, addr) in self.unverified.drain(..n).collect::<Vec<_>>() {
+            self.add_task(false, now, |_, tid| {
+                Box::new(PingTask::new(id, addr, tid).with_retries(RESTORE_RETRIES))
+            });
+        }
+        self.next_restore = now + RESTORE_INTERVAL;
     }
 }
 
@@ -119,11 +389,16 @@ mod tests {
 
     use crate::msg::{
         recv::QueryKind,
-        send::{FindNode, GetPeers},
+        send::{AnnouncePeer, FindNode, GetPeers},
+        TxnId,
     };
 
     use super::*;
 
+    fn contains(data: &[u8], part: &[u8]) -> bool {
+        data.windows(part.len()).any(|w| w == part)
+    }
+
     #[test]
     fn idle_by_default() {
         let now = Instant::now();
@@ -132,6 +407,22 @@ mod tests {
         assert_eq!(None, dht.poll_event());
     }
 
+    #[test]
+    fn txn_ids_skip_pending() {
+        let now = Instant::now();
+        let mut dht = Dht::new(NodeId::gen(), vec![], now);
+        let addr = SocketAddr::from(([0u8; 16], 0));
+        let mut txn_id = dht.rpc.txn_id;
+        let pending = txn_id.next_id();
+        dht.rpc
+            .txns
+            .insert(pending, NodeId::gen(), addr, TaskId(0), now);
+        assert_eq!(dht.rpc.new_txn(), txn_id);
+
+        dht.set_wide_txn_ids(true);
+        assert!(dht.rpc.new_txn().is_wide());
+    }
+
     #[test]
     fn bootstrap_without_router_fails() {
         let now = Instant::now();
@@ -175,7 +466,7 @@ mod tests {
         let mut dict = DictEncoder::new(buf);
         dict.insert("ip", [0u8; 16]);
         let mut r = dict.insert_dict("r");
-        r.insert("id", &id);
+        r.insert("id", id);
         r.insert("nodes", "");
         r.insert("p", 0);
         r.finish();
@@ -189,6 +480,14 @@ mod tests {
         assert_eq!(Event::Bootstrapped, dht.poll_event().unwrap());
         assert!(dht.is_idle());
         assert_eq!(None, dht.poll_event());
+
+        let metrics = dht.metrics();
+        assert_eq!(metrics.queries_out.find_node, 1);
+        assert_eq!(metrics.queries_out.total(), 1);
+        assert_eq!(metrics.response_rate(), Some(1.0));
+        assert_eq!(metrics.traversals, 1);
+        assert_eq!(metrics.bytes_out, find_node.encode_to_vec().len() as u64);
+        assert_eq!(metrics.bytes_in, buf.len() as u64);
     }
 
     #[test]
@@ -212,6 +511,172 @@ mod tests {
         assert_eq!(Event::Bootstrapped, dht.poll_event().unwrap());
         assert!(dht.is_idle());
         assert_eq!(None, dht.poll_event());
+
+        let metrics = dht.metrics();
+        assert_eq!(metrics.timeouts, 1);
+        assert_eq!(metrics.response_rate(), Some(0.0));
+        assert_eq!(metrics.avg_traversal_time(), Some(Duration::from_secs(100)));
+    }
+
+    #[test]
+    fn restored_nodes_are_pinged_in_batches() {
+        let mut now = Instant::now();
+        let mut dht = Dht::new(NodeId::all(0), vec![], now);
+        let txn_id = dht.rpc.txn_id;
+
+        let nodes: Vec<_> = (1..=10)
+            .map(|i| (NodeId::all(i), SocketAddr::from(([10, 0, 0, i], 6881))))
+            .collect();
+        dht.restore_nodes(nodes.iter().copied(), now);
+        assert_eq!(dht.num_unverified(), 10 - RESTORE_BATCH);
+
+        let mut pinged = 0;
+        while let Some(event) = dht.poll_event() {
+            assert!(matches!(event, Event::Transmit { .. }));
+            pinged += 1;
+        }
+        assert_eq!(pinged, RESTORE_BATCH);
+
+        // Nothing is live until it replies
+        assert!(dht.nodes().is_empty());
+        assert_eq!(dht.poll_timeout(), Some(now + RESTORE_INTERVAL));
+
+        let (id, addr) = nodes[0];
+        let buf = &mut vec![];
+        let mut dict = DictEncoder::new(buf);
+        let mut r = dict.insert_dict("r");
+        r.insert("id", id);
+        r.finish();
+        dict.insert("t", txn_id);
+        dict.insert("y", "r");
+        dict.finish();
+        dht.receive(buf, addr, now);
+        assert_eq!(dht.nodes(), [(id, addr)]);
+
+        now += RESTORE_INTERVAL;
+        dht.tick(now);
+        assert_eq!(dht.num_unverified(), 0);
+        let mut pinged = 0;
+        while dht.poll_event().is_some() {
+            pinged += 1;
+        }
+        assert_eq!(pinged, 10 - RESTORE_BATCH);
+    }
+
+    #[test]
+    fn restored_nodes_wait_for_bootstrap() {
+        let mut now = Instant::now();
+        let id = NodeId::all(0);
+        let router = SocketAddr::from(([10, 0, 0, 100], 6881));
+        let mut dht = Dht::new(id, vec![router], now);
+        dht.add_request(ClientRequest::Bootstrap { target: id }, now)
+            .unwrap();
+        dht.poll_event().unwrap();
+
+        let node = (NodeId::all(1), SocketAddr::from(([10, 0, 0, 1], 6881)));
+        dht.restore_nodes([node], now);
+        assert_eq!(dht.num_unverified(), 1);
+        assert_eq!(dht.poll_event(), None);
+
+        // The bootstrap times out, then the node is pinged
+        now += Duration::from_secs(100);
+        dht.tick(now);
+        assert_eq!(dht.poll_event(), Some(Event::Bootstrapped));
+        assert_eq!(dht.num_unverified(), 0);
+        assert!(matches!(
+            dht.poll_event(),
+            Some(Event::Transmit { target, .. }) if target == node.1
+        ));
+    }
+
+    #[test]
+    fn restored_nodes_are_pinged_again() {
+        let mut now = Instant::now();
+        let mut dht = Dht::new(NodeId::all(0), vec![], now);
+        let node = (NodeId::all(1), SocketAddr::from(([10, 0, 0, 1], 6881)));
+        dht.restore_nodes([node], now);
+
+        let mut pings = 0;
+        for _ in 0..4 {
+            while let Some(event) = dht.poll_event() {
+                assert!(matches!(event, Event::Transmit { .. }));
+                pings += 1;
+            }
+            now += Duration::from_secs(100);
+            dht.tick(now);
+        }
+        assert_eq!(pings, 1 + RESTORE_RETRIES as usize);
+        assert!(dht.is_idle());
+        assert!(dht.nodes().is_empty());
+    }
+
+    #[test]
+    fn node_pinged_by_addr_joins_once_it_answers() {
+        let now = Instant::now();
+        let mut dht = Dht::new(NodeId::all(0), vec![], now);
+        let txn_id = dht.rpc.txn_id;
+
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        dht.ping_addr(addr, now);
+        assert!(matches!(dht.poll_event(), Some(Event::Transmit { .. })));
+        assert!(dht.nodes().is_empty());
+
+        let id = NodeId::all(1);
+        let buf = &mut vec![];
+        let mut dict = DictEncoder::new(buf);
+        let mut r = dict.insert_dict("r");
+        r.insert("id", id);
+        r.finish();
+        dict.insert("t", txn_id);
+        dict.insert("y", "r");
+        dict.finish();
+        dht.receive(buf, addr, now);
+        assert_eq!(dht.nodes(), [(id, addr)]);
+    }
+
+    #[test]
+    fn keepalive_pings() {
+        let mut now = Instant::now();
+        let mut dht = Dht::new(NodeId::all(0), vec![], now);
+        let nodes: Vec<_> = (1..=4)
+            .map(|i| (NodeId::all(i), SocketAddr::from(([10, 0, 0, i], 6881))))
+            .collect();
+        dht.add_nodes(nodes.iter().copied(), now);
+        dht.table.heard_from(NodeId::all(1), now);
+
+        let config = KeepaliveConfig {
+            interval: Duration::from_secs(30),
+            max_pings: 2,
+        };
+        dht.set_keepalive(Some(config), now);
+        assert_eq!(dht.poll_timeout(), Some(now + config.interval));
+
+        let pinged = |dht: &mut Dht| -> Vec<_> {
+            std::iter::from_fn(|| dht.poll_event())
+                .map(|e| match e {
+                    Event::Transmit { node_id, data, .. } => {
+                        assert!(contains(&data, b"4:ping"));
+                        node_id
+                    }
+                    e => panic!("Unexpected event: {:?}", e),
+                })
+                .collect()
+        };
+
+        dht.tick(now);
+        assert!(pinged(&mut dht).is_empty());
+
+        // Never heard from first, within the budget
+        now += config.interval;
+        dht.tick(now);
+        let mut ids = pinged(&mut dht);
+        ids.sort();
+        assert_eq!(ids.len(), 2);
+        assert!(!ids.contains(&NodeId::all(1)));
+
+        dht.set_keepalive(None, now);
+        dht.tick(now + config.interval);
+        assert!(pinged(&mut dht).is_empty());
     }
 
     #[test]
@@ -249,7 +714,7 @@ mod tests {
         let mut dict = DictEncoder::new(buf);
         dict.insert("ip", [0u8; 16]);
         let mut r = dict.insert_dict("r");
-        r.insert("id", &id);
+        r.insert("id", id);
         r.insert("nodes", "");
         r.insert("p", 0);
         r.insert("token", "hello");
@@ -268,9 +733,20 @@ mod tests {
 
         assert_eq!(dht.rpc.tokens.get(&router).unwrap(), b"hello");
 
+        // The peers are reported before the lookup is done
         assert_eq!(
             Event::FoundPeers {
-                peers: [SocketAddr::from(([1, 2, 1, 2], 2))].into_iter().collect()
+                task_id,
+                peers: [SocketAddr::from(([1, 2, 1, 2], 2))].into_iter().collect(),
+                done: false,
+            },
+            dht.poll_event().unwrap()
+        );
+        assert_eq!(
+            Event::FoundPeers {
+                task_id,
+                peers: HashSet::new(),
+                done: true,
             },
             dht.poll_event().unwrap()
         );
@@ -278,6 +754,43 @@ mod tests {
         assert_eq!(None, dht.poll_event());
     }
 
+    #[test]
+    fn announce_explicit_port() {
+        let now = Instant::now();
+        let id = NodeId::gen();
+        let info_hash = NodeId::gen();
+        let router = SocketAddr::from(([0u8; 16], 0));
+
+        let mut dht = Dht::new(id, vec![router], now);
+        dht.set_announce_port(Some(6882));
+        let txn_id = dht.rpc.txn_id;
+        dht.add_request(ClientRequest::Announce { info_hash }, now)
+            .unwrap();
+        dht.poll_event().unwrap();
+
+        let buf = &mut vec![];
+        let mut dict = DictEncoder::new(buf);
+        let mut r = dict.insert_dict("r");
+        r.insert("id", id);
+        r.insert("nodes", "");
+        r.insert("token", "hello");
+        r.finish();
+        dict.insert("t", txn_id);
+        dict.insert("y", "r");
+        dict.finish();
+
+        dht.receive(buf, router, now);
+
+        let announce = std::iter::from_fn(|| dht.poll_event())
+            .find_map(|e| match e {
+                Event::Transmit { data, .. } if contains(&data, b"announce_peer") => Some(data),
+                _ => None,
+            })
+            .unwrap();
+        assert!(contains(&announce, b"12:implied_porti0e"));
+        assert!(contains(&announce, b"4:porti6882e"));
+    }
+
     #[test]
     fn get_peers_timeout() {
         let mut now = Instant::now();
@@ -286,7 +799,8 @@ mod tests {
         let router = SocketAddr::from(([0u8; 16], 0));
 
         let mut dht = Dht::new(id, vec![router], now);
-        dht.add_request(ClientRequest::GetPeers { info_hash }, now)
+        let task_id = dht
+            .add_request(ClientRequest::GetPeers { info_hash }, now)
             .unwrap();
 
         // Discard the Transmit event
@@ -299,7 +813,9 @@ mod tests {
 
         assert_eq!(
             Event::FoundPeers {
-                peers: HashSet::new()
+                task_id,
+                peers: HashSet::new(),
+                done: true,
             },
             dht.poll_event().unwrap()
         );
@@ -344,10 +860,240 @@ mod tests {
         match msg {
             Msg::Query(query) => {
                 assert_eq!(query.id, id);
-                assert_eq!(query.txn_id, txn_id);
+                assert_eq!(query.txn_id, (txn_id.0 as u16).to_be_bytes());
                 assert!(matches!(query.kind, QueryKind::FindNode { .. }));
             }
             _ => panic!("Unexpected msg: {:?}", msg),
         }
     }
+
+    #[test]
+    fn find_node_reply_with_exact_target() {
+        let now = Instant::now();
+        let mut dht = Dht::new(NodeId::gen(), vec![], now);
+
+        for i in 0..5 {
+            let c = Contact::new(NodeId::gen(), SocketAddr::from(([1, 1, 1, i], 1)));
+            dht.table.add_contact(c, now);
+        }
+
+        let target = Contact::new(NodeId::gen(), SocketAddr::from(([1, 2, 3, 4], 5)));
+        dht.table.add_contact(target.clone(), now);
+
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let query = FindNode {
+            txn_id: TxnId(7),
+            id: NodeId::gen(),
+            target: target.id,
+        };
+        dht.receive(&query.encode_to_vec(), addr, now);
+
+        let data = match dht.poll_event().unwrap() {
+            Event::Reply { data, target } => {
+                assert_eq!(target, addr);
+                data
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        };
+
+        let mut parser = Parser::new();
+        let reply = parser.parse::<Entry>(&data).unwrap();
+        let dict = reply.as_dict().unwrap();
+        assert_eq!(dict.get_bytes("ip").unwrap(), [10, 0, 0, 1, 0x1a, 0xe1]);
+
+        let nodes = dict.get_dict("r").unwrap().get_bytes("nodes").unwrap();
+        assert_eq!(nodes.len(), 26);
+        assert_eq!(&nodes[..20], &target.id[..]);
+        assert_eq!(&nodes[20..], [1, 2, 3, 4, 0, 5]);
+    }
+
+    fn reply_to(dht: &mut Dht, query: &[u8], addr: SocketAddr, now: Instant) -> Vec<u8> {
+        dht.receive(query, addr, now);
+        match dht.poll_event().unwrap() {
+            Event::Reply { data, target } => {
+                assert_eq!(target, addr);
+                data
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn announce_with_token() {
+        let now = Instant::now();
+        let mut dht = Dht::new(NodeId::gen(), vec![], now);
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let info_hash = NodeId::gen();
+
+        let query = GetPeers {
+            txn_id: TxnId(1),
+            id: NodeId::gen(),
+            info_hash,
+        };
+        let data = reply_to(&mut dht, &query.encode_to_vec(), addr, now);
+        let mut parser = Parser::new();
+        let reply = parser.parse::<Entry>(&data).unwrap();
+        let r = reply.as_dict().unwrap().get_dict("r").unwrap();
+        let token = r.get_bytes("token").unwrap().to_vec();
+
+        let announce = |token| AnnouncePeer {
+            txn_id: TxnId(2),
+            id: NodeId::gen(),
+            implied_port: false,
+            info_hash,
+            port: 5000,
+            token,
+        };
+
+        let data = reply_to(&mut dht, &announce(&token).encode_to_vec(), addr, now);
+        let reply = parser.parse::<Entry>(&data).unwrap();
+        assert_eq!(reply.as_dict().unwrap().get_str("y"), Some("r"));
+
+        // Token of another address
+        let other = SocketAddr::from(([10, 0, 0, 2], 6881));
+        let data = reply_to(&mut dht, &announce(&token).encode_to_vec(), other, now);
+        let reply = parser.parse::<Entry>(&data).unwrap();
+        let dict = reply.as_dict().unwrap();
+        assert_eq!(dict.get_str("y"), Some("e"));
+        let e = dict.get_list("e").unwrap();
+        assert_eq!(e.get_int::<i64>(0), Some(203));
+
+        // Tokens stay valid across a restart...
+        let mut restored = Dht::new(NodeId::gen(), vec![], now);
+        restored.restore_token_secrets(dht.token_secrets(), now);
+        let data = reply_to(&mut restored, &announce(&token).encode_to_vec(), addr, now);
+        let reply = parser.parse::<Entry>(&data).unwrap();
+        assert_eq!(reply.as_dict().unwrap().get_str("y"), Some("r"));
+
+        // ...until the next rotation, which happens on tick
+        let later = now + Duration::from_secs(5 * 60);
+        restored.tick(later);
+        while restored.poll_event().is_some() {}
+        let data = reply_to(
+            &mut restored,
+            &announce(&token).encode_to_vec(),
+            addr,
+            later,
+        );
+        let reply = parser.parse::<Entry>(&data).unwrap();
+        assert_eq!(reply.as_dict().unwrap().get_str("y"), Some("e"));
+    }
+
+    #[test]
+    fn repeated_queries() {
+        let now = Instant::now();
+        let mut dht = Dht::new(NodeId::gen(), vec![], now);
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let query = GetPeers {
+            txn_id: TxnId(1),
+            id: NodeId::gen(),
+            info_hash: NodeId::gen(),
+        }
+        .encode_to_vec();
+
+        let first = reply_to(&mut dht, &query, addr, now);
+        let second = reply_to(&mut dht, &query, addr, now + Duration::from_secs(1));
+        assert_eq!(first, second);
+        assert_eq!(dht.metrics().queries_in.get_peers, 1);
+        assert_eq!(dht.metrics().repeated_queries, 1);
+
+        // Not the same query from another node
+        let other = SocketAddr::from(([10, 0, 0, 2], 6881));
+        reply_to(&mut dht, &query, other, now);
+        assert_eq!(dht.metrics().queries_in.get_peers, 2);
+    }
+
+    struct Echo;
+
+    impl QueryHandler for Echo {
+        fn handle_query(
+            &mut self,
+            method: &[u8],
+            args: &ben::decode::Dict<'_, '_>,
+            _addr: SocketAddr,
+            reply: &mut ben::SortedDictEncoder<'_>,
+        ) -> QueryReply {
+            match method {
+                b"echo" => {
+                    let msg = args.get_bytes("msg").unwrap_or_default();
+                    reply.insert("msg", msg).unwrap();
+                    QueryReply::Reply
+                }
+                b"fail" => QueryReply::Error {
+                    code: 202,
+                    msg: "Failed".into(),
+                },
+                _ => QueryReply::Unknown,
+            }
+        }
+    }
+
+    fn custom_query(method: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut dict = DictEncoder::new(&mut buf);
+        let mut args = dict.insert_dict("a");
+        args.insert("id", NodeId::gen());
+        args.insert("msg", "hello");
+        args.finish();
+        dict.insert("q", method);
+        dict.insert("t", "aa");
+        dict.insert("y", "q");
+        dict.finish();
+        buf
+    }
+
+    #[test]
+    fn custom_queries() {
+        let now = Instant::now();
+        let id = NodeId::gen();
+        let mut dht = Dht::new(id, vec![], now);
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let mut parser = Parser::new();
+
+        let error_code = |data: &[u8], parser: &mut Parser| {
+            let reply = parser.parse::<Entry>(data).unwrap();
+            let dict = reply.as_dict().unwrap();
+            assert_eq!(dict.get_str("y"), Some("e"));
+            dict.get_list("e").unwrap().get_int::<i64>(0)
+        };
+
+        // No handler
+        let data = reply_to(&mut dht, &custom_query("echo"), addr, now);
+        assert_eq!(error_code(&data, &mut parser), Some(204));
+
+        dht.set_query_handler(Echo);
+        let data = reply_to(&mut dht, &custom_query("echo"), addr, now);
+        let reply = parser.parse::<Entry>(&data).unwrap();
+        let dict = reply.as_dict().unwrap();
+        assert_eq!(dict.get_str("y"), Some("r"));
+        assert_eq!(dict.get_bytes("t"), Some(&b"aa"[..]));
+        let r = dict.get_dict("r").unwrap();
+        assert_eq!(r.get_bytes("id"), Some(&id[..]));
+        assert_eq!(r.get_str("msg"), Some("hello"));
+
+        let data = reply_to(&mut dht, &custom_query("fail"), addr, now);
+        assert_eq!(error_code(&data, &mut parser), Some(202));
+
+        let data = reply_to(&mut dht, &custom_query("other"), addr, now);
+        assert_eq!(error_code(&data, &mut parser), Some(204));
+    }
+
+    #[test]
+    fn share_nodes() {
+        let now = Instant::now();
+        let mut a = Dht::new(NodeId::gen(), vec![], now);
+        let nodes: Vec<_> = (0..4)
+            .map(|i| (NodeId::gen(), SocketAddr::from(([10, 0, 0, i], 6881))))
+            .collect();
+        a.add_nodes(nodes.iter().copied(), now);
+
+        let mut b = Dht::new(NodeId::gen(), vec![], now);
+        b.add_nodes(a.nodes(), now);
+
+        let mut shared = b.nodes();
+        let mut expected = nodes;
+        shared.sort_by_key(|n| n.1);
+        expected.sort_by_key(|n| n.1);
+        assert_eq!(shared, expected);
+    }
 }
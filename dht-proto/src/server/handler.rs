@@ -0,0 +1,35 @@
+use ben::{decode::Dict, SortedDictEncoder};
+use std::net::SocketAddr;
+
+/// KRPC error code for queries of unknown methods.
+pub const METHOD_UNKNOWN: i64 = 204;
+
+/// What to do with a query given to a `QueryHandler`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryReply {
+    /// Send the reply written by the handler.
+    Reply,
+
+    /// Send a KRPC error instead.
+    Error { code: i64, msg: String },
+
+    /// Not a method of the handler either. Answered with a
+    /// "Method Unknown" error.
+    Unknown,
+}
+
+/// Answers the queries of methods the DHT doesn't handle itself, such as
+/// the extensions of a particular indexer.
+pub trait QueryHandler: Send {
+    /// Handle query `method` from `addr`. The reply is written to `reply`,
+    /// which is the "r" dictionary of the response and already has our
+    /// node id under "id". Keys sorting before "id" are rejected by the
+    /// encoder.
+    fn handle_query(
+        &mut self,
+        method: &[u8],
+        args: &Dict<'_, '_>,
+        addr: SocketAddr,
+        reply: &mut SortedDictEncoder<'_>,
+    ) -> QueryReply;
+}
@@ -0,0 +1,133 @@
+use crate::msg::recv::QueryKind;
+use std::time::Duration;
+
+/// Query methods, as far as the metrics are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Ping,
+    FindNode,
+    GetPeers,
+    AnnouncePeer,
+    Other,
+}
+
+impl Method {
+    pub fn of(kind: &QueryKind<'_>) -> Self {
+        match kind {
+            QueryKind::Ping => Self::Ping,
+            QueryKind::FindNode { .. } => Self::FindNode,
+            QueryKind::GetPeers { .. } => Self::GetPeers,
+            QueryKind::AnnouncePeer { .. } => Self::AnnouncePeer,
+            QueryKind::Other(_) => Self::Other,
+        }
+    }
+}
+
+/// Number of queries of each method.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryCounts {
+    pub ping: u64,
+    pub find_node: u64,
+    pub get_peers: u64,
+    pub announce_peer: u64,
+
+    /// Queries of methods we don't know.
+    pub other: u64,
+}
+
+impl QueryCounts {
+    pub fn total(&self) -> u64 {
+        self.ping + self.find_node + self.get_peers + self.announce_peer + self.other
+    }
+
+    pub(crate) fn add(&mut self, method: Method) {
+        let count = match method {
+            Method::Ping => &mut self.ping,
+            Method::FindNode => &mut self.find_node,
+            Method::GetPeers => &mut self.get_peers,
+            Method::AnnouncePeer => &mut self.announce_peer,
+            Method::Other => &mut self.other,
+        };
+        *count += 1;
+    }
+}
+
+/// Counters of the DHT traffic since the start.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Queries received from other nodes.
+    pub queries_in: QueryCounts,
+
+    /// Queries received again, which got the reply sent to them before
+    /// rather than being counted in `queries_in`.
+    pub repeated_queries: u64,
+
+    /// Queries sent to other nodes.
+    pub queries_out: QueryCounts,
+
+    /// Replies to our queries.
+    pub responses: u64,
+
+    /// Error replies to our queries.
+    pub errors: u64,
+
+    /// Queries of ours which got no reply in time.
+    pub timeouts: u64,
+
+    /// Lookups and bootstraps which ran to completion.
+    pub traversals: u64,
+
+    /// Total time the completed traversals took.
+    pub traversal_time: Duration,
+
+    /// Bytes of the messages we sent, queries and replies alike.
+    pub bytes_out: u64,
+
+    /// Bytes of the datagrams we received, including the malformed ones.
+    pub bytes_in: u64,
+}
+
+impl Metrics {
+    /// Fraction of our queries which got a reply rather than an error or
+    /// nothing. `None` until any query is answered or times out.
+    pub fn response_rate(&self) -> Option<f64> {
+        let total = self.responses + self.errors + self.timeouts;
+        (total > 0).then(|| self.responses as f64 / total as f64)
+    }
+
+    /// Average time a traversal takes to converge.
+    pub fn avg_traversal_time(&self) -> Option<Duration> {
+        let n = u32::try_from(self.traversals).ok().filter(|&n| n > 0)?;
+        Some(self.traversal_time / n)
+    }
+
+    pub(crate) fn traversal_done(&mut self, elapsed: Duration) {
+        self.traversals += 1;
+        self.traversal_time += elapsed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        let mut m = Metrics::default();
+        assert_eq!(m.response_rate(), None);
+        assert_eq!(m.avg_traversal_time(), None);
+
+        m.responses = 3;
+        m.timeouts = 1;
+        assert_eq!(m.response_rate(), Some(0.75));
+
+        m.traversal_done(Duration::from_secs(1));
+        m.traversal_done(Duration::from_secs(3));
+        assert_eq!(m.avg_traversal_time(), Some(Duration::from_secs(2)));
+
+        m.queries_in.add(Method::Ping);
+        m.queries_in.add(Method::Other);
+        assert_eq!(m.queries_in.ping, 1);
+        assert_eq!(m.queries_in.total(), 2);
+    }
+}
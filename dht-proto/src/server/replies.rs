@@ -0,0 +1,130 @@
+use hashbrown::HashMap;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// How long a reply is kept for retransmissions of its query.
+const REPLY_TTL: Duration = Duration::from_secs(10);
+
+/// Number of replies kept. Past it the oldest ones are dropped.
+const MAX_REPLIES: usize = 1024;
+
+struct Cached {
+    query: Vec<u8>,
+    reply: Vec<u8>,
+    expires: Instant,
+}
+
+/// Replies to the recent queries, keyed by the querying node's address and
+/// the transaction id.
+///
+/// Nodes retransmit the queries which they got no reply to in time. The
+/// same query from the same node gets the same reply again, without finding
+/// the closest nodes for it once more.
+pub struct RecentReplies {
+    replies: HashMap<(SocketAddr, Vec<u8>), Cached>,
+
+    /// Keys in the order they were added
+    order: VecDeque<(SocketAddr, Vec<u8>)>,
+}
+
+impl RecentReplies {
+    pub fn new() -> Self {
+        Self {
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Reply sent to the same `query` from `addr`, if it is recent.
+    pub fn get(
+        &self,
+        addr: SocketAddr,
+        txn_id: &[u8],
+        query: &[u8],
+        now: Instant,
+    ) -> Option<&[u8]> {
+        self.replies
+            .get(&(addr, txn_id.to_vec()))
+            .filter(|c| c.expires > now && c.query == query)
+            .map(|c| &c.reply[..])
+    }
+
+    pub fn insert(
+        &mut self,
+        addr: SocketAddr,
+        txn_id: &[u8],
+        query: &[u8],
+        reply: Vec<u8>,
+        now: Instant,
+    ) {
+        self.remove_expired(now);
+        while self.order.len() >= MAX_REPLIES {
+            if let Some(key) = self.order.pop_front() {
+                self.replies.remove(&key);
+            }
+        }
+
+        let key = (addr, txn_id.to_vec());
+        let cached = Cached {
+            query: query.to_vec(),
+            reply,
+            expires: now + REPLY_TTL,
+        };
+        if self.replies.insert(key.clone(), cached).is_some() {
+            self.order.retain(|k| *k != key);
+        }
+        self.order.push_back(key);
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        while let Some(key) = self.order.front() {
+            match self.replies.get(key) {
+                Some(c) if c.expires > now => break,
+                _ => {
+                    if let Some(key) = self.order.pop_front() {
+                        self.replies.remove(&key);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_expire() {
+        let now = Instant::now();
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let mut replies = RecentReplies::new();
+        replies.insert(addr, b"aa", b"query", b"reply".to_vec(), now);
+
+        assert_eq!(replies.get(addr, b"aa", b"query", now), Some(&b"reply"[..]));
+        assert_eq!(replies.get(addr, b"aa", b"other", now), None);
+        assert_eq!(replies.get(addr, b"ab", b"query", now), None);
+        assert_eq!(replies.get(addr, b"aa", b"query", now + REPLY_TTL), None);
+
+        // Adding more drops the expired ones
+        let later = now + REPLY_TTL;
+        replies.insert(addr, b"ab", b"query", b"reply".to_vec(), later);
+        assert_eq!(replies.replies.len(), 1);
+    }
+
+    #[test]
+    fn oldest_replies_dropped() {
+        let now = Instant::now();
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let mut replies = RecentReplies::new();
+        for i in 0..=MAX_REPLIES as u32 {
+            replies.insert(addr, &i.to_be_bytes(), b"q", vec![], now);
+        }
+        assert_eq!(replies.replies.len(), MAX_REPLIES);
+        assert!(replies.get(addr, &0u32.to_be_bytes(), b"q", now).is_none());
+        assert!(replies.get(addr, &1u32.to_be_bytes(), b"q", now).is_some());
+    }
+}
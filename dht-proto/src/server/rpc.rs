@@ -1,15 +1,16 @@
-use ben::{DictEncoder, Entry, Parser};
+use ben::{DictEncoder, Encode, Entry, Parser, SortedDictEncoder};
 use slab::Slab;
 
 use crate::{
     bucket::Bucket,
+    contact::{CompactNodeList, Node},
     id::NodeId,
     msg::{
         recv::{ErrorResponse, Msg, Query, QueryKind, Response},
         TxnId,
     },
     table::RoutingTable,
-    util::WithBytes,
+    util,
 };
 use hashbrown::HashMap;
 use std::{
@@ -19,32 +20,97 @@ use std::{
     time::{Duration, Instant},
 };
 
-use super::{task::Task, TaskId};
+use super::{
+    handler::{QueryHandler, QueryReply, METHOD_UNKNOWN},
+    metrics::{Method, Metrics},
+    replies::RecentReplies,
+    task::Task,
+    token::Tokens,
+    TaskId,
+};
+
+/// Value which is bencoded already.
+struct Encoded<'a>(&'a [u8]);
+
+impl Encode for Encoded<'_> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.0);
+    }
+}
 
 pub struct RpcManager {
     pub(crate) txn_id: TxnId,
     pub own_id: NodeId,
     pub tokens: HashMap<SocketAddr, Vec<u8>>,
+
+    /// Tokens we give out in get_peers replies
+    pub own_tokens: Tokens,
+
+    /// Answers the queries we don't know
+    pub query_handler: Option<Box<dyn QueryHandler>>,
     pub txns: Transactions,
     pub events: VecDeque<Event>,
+    pub metrics: Metrics,
+
+    /// Start time of the running traversals
+    pub traversals: HashMap<TaskId, Instant>,
+
+    /// Number of bootstraps running
+    pub bootstraps: usize,
+
+    /// Replies resent to the retransmitted queries
+    pub replies: RecentReplies,
 }
 
 impl RpcManager {
-    pub fn new(own_id: NodeId) -> Self {
+    pub fn new(own_id: NodeId, now: Instant) -> Self {
         Self {
-            txn_id: TxnId(0),
+            txn_id: TxnId::random(false),
             own_id,
             tokens: HashMap::new(),
+            own_tokens: Tokens::new(now),
+            query_handler: None,
             txns: Transactions::new(),
             events: VecDeque::new(),
+            metrics: Metrics::default(),
+            traversals: HashMap::new(),
+            bootstraps: 0,
+            replies: RecentReplies::new(),
         }
     }
 
+    /// Id for a new query. The ids of the queries still waiting for a reply
+    /// are skipped, so that a late reply isn't taken for the reply to
+    /// another query.
     pub fn new_txn(&mut self) -> TxnId {
-        self.txn_id.next_id()
+        let mut txn_id = self.txn_id.next_id();
+        for _ in 0..u16::MAX {
+            if !self.txns.contains(txn_id) {
+                break;
+            }
+            txn_id = self.txn_id.next_id();
+        }
+        txn_id
     }
 
-    pub fn transmit(&mut self, task_id: TaskId, node_id: NodeId, data: Vec<u8>, addr: SocketAddr) {
+    /// Send 4 byte transaction ids instead of 2 byte ones, starting from
+    /// a new random id.
+    pub fn set_wide_txn_ids(&mut self, enable: bool) {
+        if self.txn_id.is_wide() != enable {
+            self.txn_id = TxnId::random(enable);
+        }
+    }
+
+    pub fn transmit(
+        &mut self,
+        task_id: TaskId,
+        node_id: NodeId,
+        method: Method,
+        data: Vec<u8>,
+        addr: SocketAddr,
+    ) {
+        self.metrics.queries_out.add(method);
+        self.metrics.bytes_out += data.len() as u64;
         self.add_event(Event::Transmit {
             task_id,
             node_id,
@@ -54,6 +120,7 @@ impl RpcManager {
     }
 
     pub fn reply(&mut self, data: Vec<u8>, addr: SocketAddr) {
+        self.metrics.bytes_out += data.len() as u64;
         self.add_event(Event::Reply { data, target: addr });
     }
 
@@ -64,6 +131,7 @@ impl RpcManager {
     pub fn handle_response(
         &mut self,
         msg: Msg<'_>,
+        data: &[u8],
         addr: SocketAddr,
         table: &mut RoutingTable,
         tasks: &mut Slab<Box<dyn Task>>,
@@ -74,7 +142,37 @@ impl RpcManager {
         match msg {
             Msg::Response(r) => self.handle_ok(r, addr, table, tasks, now),
             Msg::Error(e) => self.handle_error(e, addr, table, tasks, now),
-            Msg::Query(q) => self.handle_query(q, addr, table, now),
+            Msg::Query(q) => self.handle_repeated_query(q, data, addr, table, now),
+        }
+    }
+
+    /// Answer the query, with the same reply as before if the node already
+    /// sent it.
+    fn handle_repeated_query(
+        &mut self,
+        query: Query<'_>,
+        data: &[u8],
+        addr: SocketAddr,
+        table: &mut RoutingTable,
+        now: Instant,
+    ) {
+        let txn_id = query.txn_id;
+        if let Some(reply) = self.replies.get(addr, txn_id, data, now) {
+            trace!("Resending the reply to a repeated query from {}", addr);
+            let reply = reply.to_vec();
+            table.heard_from(query.id, now);
+            self.metrics.repeated_queries += 1;
+            self.reply(reply, addr);
+            return;
+        }
+
+        let events = self.events.len();
+        self.handle_query(query, addr, table, now);
+        if self.events.len() > events {
+            if let Some(Event::Reply { data: reply, .. }) = self.events.back() {
+                let reply = reply.clone();
+                self.replies.insert(addr, txn_id, data, reply, now);
+            }
         }
     }
 
@@ -94,6 +192,7 @@ impl RpcManager {
             }
         };
 
+        self.metrics.responses += 1;
         if req.has_id && req.id == resp.id {
             table.heard_from(req.id, now);
         } else if req.has_id {
@@ -107,7 +206,7 @@ impl RpcManager {
                 task.set_failed(req.id, addr);
                 let done = task.add_requests(self, now);
                 if done {
-                    tasks.remove(req.task_id.0).done(self);
+                    self.finish_task(tasks.remove(req.task_id.0), now);
                 }
             }
             return;
@@ -117,7 +216,7 @@ impl RpcManager {
             task.handle_response(&resp, addr, table, self, req.has_id, now);
             let done = task.add_requests(self, now);
             if done {
-                tasks.remove(req.task_id.0).done(self);
+                self.finish_task(tasks.remove(req.task_id.0), now);
             }
         }
     }
@@ -138,6 +237,8 @@ impl RpcManager {
             }
         };
 
+        self.metrics.errors += 1;
+        debug!("Error from {}: {:?}", addr, err.list);
         if req.has_id {
             table.failed(req.id);
         }
@@ -146,11 +247,19 @@ impl RpcManager {
             task.set_failed(req.id, addr);
             let done = task.add_requests(self, now);
             if done {
-                tasks.remove(req.task_id.0).done(self);
+                self.finish_task(tasks.remove(req.task_id.0), now);
             }
         }
     }
 
+    fn finish_task(&mut self, mut task: Box<dyn Task>, now: Instant) {
+        if let Some(started) = self.traversals.remove(&task.id()) {
+            self.metrics
+                .traversal_done(now.saturating_duration_since(started));
+        }
+        task.done(self);
+    }
+
     fn handle_query(
         &mut self,
         query: Query<'_>,
@@ -159,33 +268,71 @@ impl RpcManager {
         now: Instant,
     ) {
         table.heard_from(query.id, now);
+        self.metrics.queries_in.add(Method::of(&query.kind));
+
+        if let QueryKind::AnnouncePeer { token, .. } = query.kind {
+            self.own_tokens.rotate(now);
+            if !self.own_tokens.validate(token, addr.ip()) {
+                debug!("Bad token in announce from {}", addr);
+                self.reply_error(query.txn_id, 203, "Bad token", addr);
+                return;
+            }
+        }
 
+        if let QueryKind::Other(method) = query.kind {
+            self.handle_other_query(&query, method, addr);
+            return;
+        }
+
+        let mut token = None;
         let mut buf = Vec::new();
         let mut dict = DictEncoder::new(&mut buf);
-        addr.ip().with_bytes(|b| dict.insert("ip", b));
+
+        // The requester's external address (BEP 42)
+        let mut ip = Vec::with_capacity(18);
+        util::write_addr(&mut ip, addr);
+        dict.insert("ip", &ip[..]);
 
         let mut r = dict.insert_dict("r");
-        r.insert("id", &self.own_id);
+        r.insert("id", self.own_id);
 
         match query.kind {
             QueryKind::Ping => {
                 // Nothing else to add
             }
-            QueryKind::FindNode { target } | QueryKind::GetPeers { info_hash: target } => {
-                let out = table.find_closest(target, Bucket::MAX_LEN);
-
-                let nodes = &mut Vec::with_capacity(256);
-                for c in out {
-                    c.write_compact(nodes);
+            QueryKind::FindNode { target } => {
+                let mut nodes = CompactNodeList::new();
+                match table.get_contact(target) {
+                    // Only the exact node when we know it
+                    Some(c) => {
+                        nodes.push(c.into());
+                    }
+                    None => {
+                        let closest = table.find_closest(target, Bucket::MAX_LEN);
+                        nodes.extend(closest.into_iter().map(Node::from));
+                    }
                 }
-                r.insert("nodes", &nodes[..]);
+                nodes.write_to(&mut r);
+            }
+            QueryKind::GetPeers { info_hash } => {
+                let mut nodes = CompactNodeList::new();
+                let closest = table.find_closest(info_hash, Bucket::MAX_LEN);
+                nodes.extend(closest.into_iter().map(Node::from));
+                nodes.write_to(&mut r);
+
+                self.own_tokens.rotate(now);
+                token = Some(self.own_tokens.generate(addr.ip()));
             }
             QueryKind::AnnouncePeer { .. } => {
-                warn!("Announce peer query is not yet implemented");
+                warn!("Storing announced peers is not yet implemented");
             }
+            QueryKind::Other(_) => unreachable!(),
         }
 
         r.insert("p", addr.port() as i64);
+        if let Some(token) = &token {
+            r.insert("token", &token[..]);
+        }
         r.finish();
 
         dict.insert("t", query.txn_id);
@@ -200,6 +347,66 @@ impl RpcManager {
         self.reply(buf, addr);
     }
 
+    /// Let the query handler answer a query of a method we don't know.
+    fn handle_other_query(&mut self, query: &Query<'_>, method: &[u8], addr: SocketAddr) {
+        let mut r = Vec::new();
+        let mut dict = SortedDictEncoder::new(&mut r);
+        dict.insert("id", self.own_id)
+            .expect("First key is always in order");
+
+        let result = match &mut self.query_handler {
+            Some(handler) => handler.handle_query(method, &query.args, addr, &mut dict),
+            None => QueryReply::Unknown,
+        };
+        dict.finish();
+
+        match result {
+            QueryReply::Reply => {}
+            QueryReply::Error { code, msg } => {
+                self.reply_error(query.txn_id, code, &msg, addr);
+                return;
+            }
+            QueryReply::Unknown => {
+                debug!(
+                    "Unknown query {:?} from {}",
+                    String::from_utf8_lossy(method),
+                    addr
+                );
+                self.reply_error(query.txn_id, METHOD_UNKNOWN, "Method Unknown", addr);
+                return;
+            }
+        }
+
+        let mut buf = Vec::new();
+        let mut dict = DictEncoder::new(&mut buf);
+
+        let mut ip = Vec::with_capacity(18);
+        util::write_addr(&mut ip, addr);
+        dict.insert("ip", &ip[..]);
+        dict.insert("r", Encoded(&r));
+        dict.insert("t", query.txn_id);
+        dict.insert("y", "r");
+        dict.finish();
+
+        self.reply(buf, addr);
+    }
+
+    fn reply_error(&mut self, txn_id: &[u8], code: i64, msg: &str, addr: SocketAddr) {
+        let mut buf = Vec::new();
+        let mut dict = DictEncoder::new(&mut buf);
+
+        let mut e = dict.insert_list("e");
+        e.push(code);
+        e.push(msg);
+        e.finish();
+
+        dict.insert("t", txn_id);
+        dict.insert("y", "e");
+        dict.finish();
+
+        self.reply(buf, addr);
+    }
+
     pub fn next_timeout(&self) -> Option<Instant> {
         self.txns.pending.values().map(|req| req.timeout).min()
     }
@@ -230,6 +437,7 @@ impl RpcManager {
 
         while let Some((txn_id, req)) = self.txns.timed_out.pop() {
             trace!("Txn {:?} expired", txn_id);
+            self.metrics.timeouts += 1;
             if req.has_id {
                 table.failed(req.id);
             }
@@ -238,7 +446,7 @@ impl RpcManager {
                 task.set_failed(req.id, req.addr);
                 let done = task.add_requests(self, now);
                 if done {
-                    tasks.remove(req.task_id.0).done(self);
+                    self.finish_task(tasks.remove(req.task_id.0), now);
                 }
             }
         }
@@ -302,6 +510,10 @@ impl Transactions {
             .insert(txn_id, Request::new(id, addr, task_id, now + self.timeout));
     }
 
+    pub fn contains(&self, txn_id: TxnId) -> bool {
+        self.pending.contains_key(&txn_id)
+    }
+
     pub fn remove(&mut self, txn_id: TxnId) -> Option<Request> {
         self.pending.remove(&txn_id)
     }
@@ -314,8 +526,12 @@ impl Transactions {
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Event {
+    /// Peers found by a get_peers lookup. Sent as soon as nodes reply with
+    /// new peers, and once more with `done` set when the lookup is over.
     FoundPeers {
+        task_id: TaskId,
         peers: HashSet<SocketAddr>,
+        done: bool,
     },
     Bootstrapped,
     Transmit {
@@ -333,7 +549,11 @@ pub enum Event {
 impl fmt::Display for Event {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::FoundPeers { .. } => f.debug_struct("FoundPeers").finish(),
+            Self::FoundPeers { peers, done, .. } => f
+                .debug_struct("FoundPeers")
+                .field("peers", &peers.len())
+                .field("done", done)
+                .finish(),
             Self::Bootstrapped { .. } => f.debug_struct("Bootstrapped").finish(),
             Self::Transmit { task_id, .. } => f
                 .debug_struct("Transmit")
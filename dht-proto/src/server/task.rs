@@ -1,4 +1,3 @@
-use crate::contact::Contact;
 use crate::id::NodeId;
 use crate::{msg::recv::Response, table::RoutingTable};
 use std::fmt;
@@ -18,7 +17,7 @@ pub use ping::PingTask;
 
 use super::rpc::RpcManager;
 
-pub trait Task {
+pub trait Task: Send {
     fn id(&self) -> TaskId;
 
     fn add_requests(&mut self, rpc: &mut RpcManager, now: Instant) -> bool;
@@ -55,11 +54,11 @@ pub struct DhtNode {
 }
 
 impl DhtNode {
-    pub fn new(c: &Contact, target: NodeId) -> Self {
+    pub fn new(id: NodeId, addr: SocketAddr, target: NodeId) -> Self {
         Self {
-            id: c.id,
-            key: c.id ^ target,
-            addr: c.addr,
+            id,
+            key: id ^ target,
+            addr,
             status: Status::INITIAL,
         }
     }
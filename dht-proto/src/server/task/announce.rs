@@ -1,10 +1,9 @@
 use ben::Encode;
 
-use crate::bucket::Bucket;
 use crate::id::NodeId;
 use crate::msg::recv::Response;
 use crate::msg::send::AnnouncePeer;
-use crate::server::task::Status;
+use crate::server::metrics::Method;
 use crate::server::RpcManager;
 use crate::table::RoutingTable;
 use std::{net::SocketAddr, time::Instant};
@@ -13,12 +12,21 @@ use super::{GetPeersTask, Task, TaskId};
 
 pub struct AnnounceTask {
     get_peers: GetPeersTask,
+
+    /// Port of our peer, or `None` for the implied port
+    port: Option<u16>,
 }
 
 impl AnnounceTask {
-    pub fn new(info_hash: NodeId, table: &mut RoutingTable, task_id: TaskId) -> Self {
+    pub fn new(
+        info_hash: NodeId,
+        port: Option<u16>,
+        table: &mut RoutingTable,
+        task_id: TaskId,
+    ) -> Self {
         Self {
             get_peers: GetPeersTask::new(info_hash, table, task_id),
+            port,
         }
     }
 }
@@ -59,15 +67,7 @@ impl Task for AnnounceTask {
         trace!("Finished ANNOUNCE's GET_PEERS. Time to announce");
 
         let mut announce_count = 0;
-        for n in &self.get_peers.base.nodes {
-            if announce_count == Bucket::MAX_LEN {
-                break;
-            }
-
-            if !n.status.contains(Status::ALIVE) {
-                continue;
-            }
-
+        for n in self.get_peers.base.closest_alive().iter() {
             let txn_id = rpc.new_txn();
             let token = match rpc.tokens.get(&n.addr) {
                 Some(t) => t,
@@ -82,14 +82,14 @@ impl Task for AnnounceTask {
                 txn_id,
                 id: rpc.own_id,
                 info_hash: self.get_peers.base.target,
-                port: 0,
-                implied_port: true,
+                port: self.port.unwrap_or(0),
+                implied_port: self.port.is_none(),
                 token,
             };
 
             msg.encode(&mut buf);
 
-            rpc.transmit(self.id(), n.id, buf, n.addr);
+            rpc.transmit(self.id(), n.id, Method::AnnouncePeer, buf, n.addr);
             debug!("Announced to {}", n.addr);
             announce_count += 1;
         }
@@ -1,10 +1,10 @@
 use std::{net::SocketAddr, time::Instant};
 
 use crate::{
-    bucket::Bucket,
+    contact::{CompactNodeList, Node},
     id::NodeId,
     msg::{recv::Response, TxnId},
-    server::rpc::RpcManager,
+    server::{metrics::Method, rpc::RpcManager},
     table::RoutingTable,
 };
 
@@ -16,16 +16,21 @@ pub struct BaseTask {
     pub branch_factor: u8,
     pub invoked: u8,
     pub task_id: TaskId,
+
+    /// Number of alive nodes closest to the target to look for.
+    pub k: usize,
 }
 
 impl BaseTask {
     pub fn new(target: NodeId, table: &RoutingTable, task_id: TaskId) -> Self {
-        let closest = table.find_closest(target, Bucket::MAX_LEN);
+        let k = table.config.bucket_size;
+        let mut closest = CompactNodeList::new();
+        closest.extend(table.find_closest(target, k).into_iter().map(Into::into));
 
-        let mut nodes = vec![];
-        for c in closest {
-            nodes.push(DhtNode::new(c, target));
-        }
+        let mut nodes: Vec<_> = closest
+            .iter()
+            .map(|n| DhtNode::new(n.id, n.addr, target))
+            .collect();
 
         info!("Closest nodes in the routing table: {}", nodes.len());
 
@@ -48,6 +53,7 @@ impl BaseTask {
             branch_factor: 3,
             invoked: 0,
             task_id,
+            k,
         }
     }
 
@@ -81,20 +87,17 @@ impl BaseTask {
             self.invoked -= 1;
         }
 
-        let result = table.read_nodes_with(resp, now, |c| {
+        table.read_nodes_with(resp, now, |c| {
             let key = c.id ^ self.target;
             let search_result = self.nodes.binary_search_by_key(&key, |n| n.key);
 
             // Insert if not present
             if let Err(i) = search_result {
-                self.nodes.insert(i, DhtNode::new(c, self.target));
+                self.nodes
+                    .insert(i, DhtNode::new(c.id, c.addr, self.target));
             }
         });
 
-        if let Err(e) = result {
-            warn!("{}", e);
-        }
-
         if self.nodes.len() > 100 {
             let mask = Status::QUERIED | Status::ALIVE | Status::FAILED;
 
@@ -122,7 +125,27 @@ impl BaseTask {
         }
     }
 
-    pub fn add_requests<F>(&mut self, rpc: &mut RpcManager, now: Instant, mut write_msg: F) -> bool
+    /// The alive nodes found, the closest to the target first, up to
+    /// `Bucket::MAX_LEN` of each address family.
+    pub fn closest_alive(&self) -> CompactNodeList {
+        let mut closest = CompactNodeList::new();
+        let alive = self
+            .nodes
+            .iter()
+            .filter(|n| n.status.contains(Status::ALIVE));
+        closest.extend(alive.map(|n| Node::new(n.id, n.addr)));
+        closest
+    }
+
+    /// Query the closest nodes not queried yet with the `method` query
+    /// written by `write_msg`.
+    pub fn add_requests<F>(
+        &mut self,
+        rpc: &mut RpcManager,
+        now: Instant,
+        method: Method,
+        mut write_msg: F,
+    ) -> bool
     where
         F: FnMut(&mut Vec<u8>, &mut RpcManager) -> TxnId,
     {
@@ -133,7 +156,7 @@ impl BaseTask {
         // it is not considered pending anymore and a new request can be made.
 
         for n in &mut self.nodes {
-            if alive == Bucket::MAX_LEN {
+            if alive == self.k {
                 break;
             }
 
@@ -157,7 +180,7 @@ impl BaseTask {
             let txn_id = write_msg(&mut buf, rpc);
             trace!("Send to {}", n.addr);
 
-            rpc.transmit(self.task_id, n.id, buf, n.addr);
+            rpc.transmit(self.task_id, n.id, method, buf, n.addr);
             n.status.insert(Status::QUERIED);
             rpc.txns.insert(txn_id, n.id, n.addr, self.task_id, now);
 
@@ -174,6 +197,6 @@ impl BaseTask {
 
         // We are done when there are no pending nodes and we found `k` alive nodes
         // OR there are no queried nodes
-        (pending == 0 && alive == Bucket::MAX_LEN) || self.invoked == 0
+        (pending == 0 && alive == self.k) || self.invoked == 0
     }
 }
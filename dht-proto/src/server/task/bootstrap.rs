@@ -1,6 +1,7 @@
 use crate::id::NodeId;
 use crate::msg::recv::Response;
 use crate::msg::send::FindNode;
+use crate::server::metrics::Method;
 use crate::server::rpc::Event;
 use crate::server::RpcManager;
 use crate::table::RoutingTable;
@@ -51,20 +52,22 @@ impl Task for BootstrapTask {
         trace!("Add BOOTSTRAP requests");
 
         let target = self.base.target;
-        self.base.add_requests(rpc, now, |buf, rpc| {
-            let msg = FindNode {
-                txn_id: rpc.new_txn(),
-                target,
-                id: rpc.own_id,
-            };
-            trace!("Send {:?}", msg);
+        self.base
+            .add_requests(rpc, now, Method::FindNode, |buf, rpc| {
+                let msg = FindNode {
+                    txn_id: rpc.new_txn(),
+                    target,
+                    id: rpc.own_id,
+                };
+                trace!("Send {:?}", msg);
 
-            msg.encode(buf);
-            msg.txn_id
-        })
+                msg.encode(buf);
+                msg.txn_id
+            })
     }
 
     fn done(&mut self, rpc: &mut RpcManager) {
+        rpc.bootstraps -= 1;
         rpc.add_event(Event::Bootstrapped)
     }
 }
@@ -1,6 +1,7 @@
 use crate::id::NodeId;
 use crate::msg::recv::Response;
 use crate::msg::send::GetPeers;
+use crate::server::metrics::Method;
 use crate::server::rpc::Event;
 use crate::server::RpcManager;
 use crate::table::RoutingTable;
@@ -48,14 +49,22 @@ impl Task for GetPeersTask {
             rpc.tokens.insert(addr, token.to_vec());
         }
 
-        if let Some(peers) = resp.body.get_list("values") {
-            let peers = peers.into_iter().flat_map(decode_peer);
-            self.peers.extend(peers);
+        // Report the new peers right away so that the client can connect to
+        // them while the lookup goes on
+        let mut new_peers = HashSet::new();
+        for key in ["values", "values6"] {
+            if let Some(peers) = resp.body.get_list(key) {
+                let peers = peers.into_iter().flat_map(decode_peer);
+                new_peers.extend(peers.filter(|p| self.peers.insert(*p)));
+            }
         }
 
-        if let Some(peers) = resp.body.get_list("values6") {
-            let peers = peers.into_iter().flat_map(decode_peer);
-            self.peers.extend(peers);
+        if !new_peers.is_empty() {
+            rpc.add_event(Event::FoundPeers {
+                task_id: self.id(),
+                peers: new_peers,
+                done: false,
+            });
         }
     }
 
@@ -68,23 +77,26 @@ impl Task for GetPeersTask {
         trace!("Add GET_PEERS requests");
 
         let info_hash = self.base.target;
-        self.base.add_requests(rpc, now, |buf, rpc| {
-            let msg = GetPeers {
-                txn_id: rpc.new_txn(),
-                id: rpc.own_id,
-                info_hash,
-            };
+        self.base
+            .add_requests(rpc, now, Method::GetPeers, |buf, rpc| {
+                let msg = GetPeers {
+                    txn_id: rpc.new_txn(),
+                    id: rpc.own_id,
+                    info_hash,
+                };
 
-            trace!("Send {:?}", msg);
-            msg.encode(buf);
-            msg.txn_id
-        })
+                trace!("Send {:?}", msg);
+                msg.encode(buf);
+                msg.txn_id
+            })
     }
 
     fn done(&mut self, rpc: &mut RpcManager) {
         info!("Found {} peers", self.peers.len());
         rpc.add_event(Event::FoundPeers {
-            peers: std::mem::take(&mut self.peers),
+            task_id: self.id(),
+            peers: HashSet::new(),
+            done: true,
         });
     }
 }
@@ -2,6 +2,7 @@ use crate::contact::Contact;
 use crate::id::NodeId;
 use crate::msg::recv::Response;
 use crate::msg::send::Ping;
+use crate::server::metrics::Method;
 use crate::server::task::{DhtNode, Status};
 use crate::server::RpcManager;
 use crate::table::RoutingTable;
@@ -15,6 +16,9 @@ pub struct PingTask {
     node: DhtNode,
     done: bool,
     task_id: TaskId,
+
+    /// Pings left to send after a timeout
+    retries: u8,
 }
 
 impl PingTask {
@@ -28,8 +32,15 @@ impl PingTask {
             },
             done: false,
             task_id,
+            retries: 0,
         }
     }
+
+    /// Ping the node again, up to `retries` times, when it doesn't answer.
+    pub fn with_retries(mut self, retries: u8) -> Self {
+        self.retries = retries;
+        self
+    }
 }
 
 impl Task for PingTask {
@@ -49,7 +60,9 @@ impl Task for PingTask {
     ) {
         trace!("Handle PING response");
 
-        if self.node.id == resp.id && self.node.addr == addr {
+        // Nodes pinged by address have no id yet
+        let id_matches = self.node.id.is_zero() || self.node.id == resp.id;
+        if id_matches && self.node.addr == addr {
             table.add_contact(Contact::new(resp.id, addr), now);
         } else {
             table.failed(resp.id);
@@ -60,6 +73,10 @@ impl Task for PingTask {
 
     fn set_failed(&mut self, id: NodeId, _addr: SocketAddr) {
         if self.node.id == id {
+            if self.retries > 0 {
+                self.retries -= 1;
+                return;
+            }
             self.node.status.insert(Status::FAILED);
         }
         self.done = true;
@@ -82,7 +99,7 @@ impl Task for PingTask {
 
         msg.encode(&mut buf);
 
-        rpc.transmit(self.id(), self.node.id, buf, self.node.addr);
+        rpc.transmit(self.id(), self.node.id, Method::Ping, buf, self.node.addr);
         self.node.status.insert(Status::QUERIED);
         rpc.txns
             .insert(txn_id, self.node.id, self.node.addr, self.task_id, now);
@@ -0,0 +1,191 @@
+use crate::util::WithBytes;
+use rand::Rng;
+use sha1::Sha1;
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Length of the secrets the tokens are derived from.
+const SECRET_LEN: usize = 20;
+
+/// Length of the tokens given out in get_peers replies.
+const TOKEN_LEN: usize = 8;
+
+/// Tokens are valid for two rotation periods, so a token is accepted for
+/// 5 to 10 minutes after it is given out as BEP 5 suggests.
+const ROTATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Tokens for get_peers replies, to be presented back in announce_peer.
+///
+/// Instead of storing the token given to each address, the token is the
+/// HMAC of the requester's IP with a secret. The secret rotates and the
+/// previous one stays valid, so checking a token only needs the two
+/// secrets, which can also be saved and restored across restarts.
+pub struct Tokens {
+    current: [u8; SECRET_LEN],
+    previous: [u8; SECRET_LEN],
+    rotated: Instant,
+}
+
+impl Tokens {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            current: gen_secret(),
+            previous: gen_secret(),
+            rotated: now,
+        }
+    }
+
+    /// Restore the current and previous secrets of [`secrets`].
+    ///
+    /// It's unknown when they were last rotated, so the restored current
+    /// secret becomes the previous generation and expires with the next
+    /// rotation, while the older one is dropped.
+    ///
+    /// [`secrets`]: Self::secrets
+    pub fn with_secrets(secrets: [[u8; SECRET_LEN]; 2], now: Instant) -> Self {
+        let [current, _] = secrets;
+        Self {
+            current: gen_secret(),
+            previous: current,
+            rotated: now,
+        }
+    }
+
+    pub fn secrets(&self) -> [[u8; SECRET_LEN]; 2] {
+        [self.current, self.previous]
+    }
+
+    pub fn rotate(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.rotated);
+        if elapsed < ROTATE_INTERVAL {
+            return;
+        }
+
+        self.previous = if elapsed < 2 * ROTATE_INTERVAL {
+            self.current
+        } else {
+            // Tokens of the current secret have expired as well
+            gen_secret()
+        };
+        self.current = gen_secret();
+        self.rotated = now;
+    }
+
+    pub fn generate(&self, ip: IpAddr) -> [u8; TOKEN_LEN] {
+        token(&self.current, ip)
+    }
+
+    pub fn validate(&self, given: &[u8], ip: IpAddr) -> bool {
+        given == token(&self.current, ip) || given == token(&self.previous, ip)
+    }
+}
+
+fn gen_secret() -> [u8; SECRET_LEN] {
+    let mut secret = [0; SECRET_LEN];
+    rand::thread_rng().fill(&mut secret);
+    secret
+}
+
+fn token(secret: &[u8; SECRET_LEN], ip: IpAddr) -> [u8; TOKEN_LEN] {
+    let mac = ip.with_bytes(|b| hmac_sha1(secret, b));
+    let mut token = [0; TOKEN_LEN];
+    token.copy_from_slice(&mac[..TOKEN_LEN]);
+    token
+}
+
+/// HMAC-SHA1 (RFC 2104) with a key shorter than the block size.
+fn hmac_sha1(key: &[u8; SECRET_LEN], msg: &[u8]) -> [u8; 20] {
+    let mut ipad = [0x36; 64];
+    let mut opad = [0x5c; 64];
+    for (i, k) in key.iter().enumerate() {
+        ipad[i] ^= k;
+        opad[i] ^= k;
+    }
+
+    let mut inner = Sha1::new();
+    inner.update(&ipad);
+    inner.update(msg);
+
+    let mut outer = Sha1::new();
+    outer.update(&opad);
+    outer.update(&inner.digest().bytes());
+    outer.digest().bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac() {
+        // RFC 2202, test case 1
+        let mac = hmac_sha1(&[0x0b; 20], b"Hi There");
+        let expected = [
+            0xb6, 0x17, 0x31, 0x86, 0x55, 0x05, 0x72, 0x64, 0xe2, 0x8b, 0xc0, 0xb6, 0xfb, 0x37,
+            0x8c, 0x8e, 0xf1, 0x46, 0xbe, 0x00,
+        ];
+        assert_eq!(mac, expected);
+    }
+
+    #[test]
+    fn tokens_are_bound_to_ip() {
+        let tokens = Tokens::new(Instant::now());
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let b = IpAddr::from([10, 0, 0, 2]);
+
+        let token = tokens.generate(a);
+        assert!(tokens.validate(&token, a));
+        assert!(!tokens.validate(&token, b));
+        assert!(!tokens.validate(&token[..4], a));
+    }
+
+    #[test]
+    fn tokens_expire_after_two_rotations() {
+        let now = Instant::now();
+        let mut tokens = Tokens::new(now);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let token = tokens.generate(ip);
+
+        tokens.rotate(now + ROTATE_INTERVAL / 2);
+        assert_eq!(tokens.generate(ip), token);
+
+        tokens.rotate(now + ROTATE_INTERVAL);
+        assert_ne!(tokens.generate(ip), token);
+        assert!(tokens.validate(&token, ip));
+
+        tokens.rotate(now + 2 * ROTATE_INTERVAL);
+        assert!(!tokens.validate(&token, ip));
+    }
+
+    #[test]
+    fn restored_secrets() {
+        let now = Instant::now();
+        let mut tokens = Tokens::new(now);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let old = tokens.generate(ip);
+        tokens.rotate(now + ROTATE_INTERVAL);
+        let new = tokens.generate(ip);
+
+        let mut restored = Tokens::with_secrets(tokens.secrets(), now);
+        assert!(!restored.validate(&old, ip));
+        assert!(restored.validate(&new, ip));
+        assert_ne!(restored.generate(ip), new);
+
+        // Restored tokens don't get a fresh lifetime
+        restored.rotate(now + ROTATE_INTERVAL);
+        assert!(!restored.validate(&new, ip));
+    }
+
+    #[test]
+    fn long_idle_drops_both_secrets() {
+        let now = Instant::now();
+        let mut tokens = Tokens::new(now);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let token = tokens.generate(ip);
+
+        tokens.rotate(now + 3 * ROTATE_INTERVAL);
+        assert!(!tokens.validate(&token, ip));
+    }
+}
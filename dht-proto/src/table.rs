@@ -10,16 +10,57 @@ use std::time::{Duration, Instant};
 
 const BUCKETS: usize = 160;
 
+/// Sizes of the buckets of the routing table.
+///
+/// Larger buckets keep more nodes around, e.g. for crawlers, at the cost
+/// of memory and refresh traffic. Lookups look for `bucket_size` nodes
+/// close to the target too. Replies to other nodes always carry at most
+/// `Bucket::MAX_LEN` nodes, as the other implementations expect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableConfig {
+    /// Max number of live nodes per bucket, the 'K' of Kademlia. At least
+    /// 1.
+    pub bucket_size: usize,
+
+    /// Max number of replacement nodes per bucket.
+    pub extra_size: usize,
+}
+
+impl Default for TableConfig {
+    fn default() -> Self {
+        Self {
+            bucket_size: Bucket::MAX_LEN,
+            extra_size: Bucket::MAX_LEN,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct RoutingTable {
     pub root_id: NodeId,
     pub buckets: [Bucket; BUCKETS],
     pub timeouts: [Instant; BUCKETS],
     pub router_nodes: HashSet<SocketAddr>,
+    pub config: TableConfig,
+
+    /// Pings of the nodes which may be evicted to make room for new ones.
+    pings: Vec<ClientRequest>,
 }
 
 impl RoutingTable {
+    #[cfg(test)]
     pub fn new(root_id: NodeId, router_nodes: Vec<SocketAddr>, now: Instant) -> Self {
+        Self::with_config(root_id, router_nodes, TableConfig::default(), now)
+    }
+
+    pub fn with_config(
+        root_id: NodeId,
+        router_nodes: Vec<SocketAddr>,
+        mut config: TableConfig,
+        now: Instant,
+    ) -> Self {
+        config.bucket_size = config.bucket_size.max(1);
+
         // Bucket is not `Copy`. So create it using an uninitialized array
         let buckets = unsafe {
             let mut buckets = MaybeUninit::<[Bucket; BUCKETS]>::uninit();
@@ -35,6 +76,17 @@ impl RoutingTable {
             buckets,
             timeouts: [next_timeout(now); BUCKETS],
             router_nodes: router_nodes.into_iter().collect(),
+            config,
+            pings: Vec::new(),
+        }
+    }
+
+    /// Change the bucket sizes. Nodes over the new sizes are dropped.
+    pub fn set_config(&mut self, mut config: TableConfig) {
+        config.bucket_size = config.bucket_size.max(1);
+        self.config = config;
+        for bucket in &mut self.buckets {
+            bucket.truncate(&config);
         }
     }
 
@@ -49,7 +101,7 @@ impl RoutingTable {
         self.timeouts[idx] = next_timeout(now);
         let bucket = &mut self.buckets[idx];
 
-        if bucket.is_full() {
+        if bucket.is_full(&self.config) {
             let c = bucket
                 .live
                 .iter()
@@ -106,9 +158,10 @@ impl RoutingTable {
             contact = bucket.extra.remove(i);
         }
 
-        if bucket.live.len() < Bucket::MAX_LEN {
+        let config = &self.config;
+        if bucket.live.len() < config.bucket_size {
             if bucket.live.is_empty() {
-                bucket.live.reserve(Bucket::MAX_LEN);
+                bucket.live.reserve(config.bucket_size);
             }
             bucket.live.push(contact);
             *timeout = next_timeout(now);
@@ -116,41 +169,50 @@ impl RoutingTable {
         }
 
         if contact.is_confirmed() {
-            return if bucket.replace_node(contact) {
+            // Nodes known to be bad make room right away
+            if let Some(i) = bucket.find_bad() {
+                bucket.live.remove(i);
+                bucket.live.push(contact);
                 *timeout = next_timeout(now);
-                true
-            } else {
-                false
-            };
+                return true;
+            }
+
+            // Otherwise check if the least recently seen node is still
+            // around. It is replaced only if it fails to respond, until
+            // then the new node waits in the replacement bucket.
+            if bucket.pinging.is_none() {
+                let c = &bucket.live[0];
+                bucket.pinging = Some(c.id);
+                self.pings.push(ClientRequest::Ping {
+                    id: c.id,
+                    addr: c.addr,
+                });
+            }
         }
 
         // if we can't replace anything in the live buckets, then try to insert
         // into the replacement bucket
-
-        // if we don't have any identified stale nodes in
-        // the bucket, and the bucket is full, we have to
-        // cache this node and wait until some node fails
-        // and then replace it.
         if let Some(c) = bucket.extra.iter_mut().find(|c| c.addr == contact.addr) {
             c.set_pinged();
             return true;
         }
 
-        if bucket.extra.len() >= Bucket::MAX_LEN {
-            if let Some(i) = bucket.extra.iter().position(|c| !c.is_pinged()) {
-                bucket.extra.remove(i);
-            } else {
-                return if bucket.replace_node(contact) {
-                    *timeout = next_timeout(now);
-                    true
-                } else {
-                    false
-                };
-            }
+        if config.extra_size == 0 {
+            return false;
+        }
+
+        if bucket.extra.len() >= config.extra_size {
+            // Drop the oldest node, preferring the ones we never heard from
+            let i = bucket
+                .extra
+                .iter()
+                .position(|c| !c.is_pinged())
+                .unwrap_or(0);
+            bucket.extra.remove(i);
         }
 
         if bucket.extra.is_empty() {
-            bucket.extra.reserve(Bucket::MAX_LEN);
+            bucket.extra.reserve(config.extra_size);
         }
         bucket.extra.push(contact);
         *timeout = next_timeout(now);
@@ -178,32 +240,25 @@ impl RoutingTable {
         out
     }
 
-    pub fn read_nodes_with<F>(
-        &mut self,
-        response: &Response,
-        now: Instant,
-        mut f: F,
-    ) -> anyhow::Result<()>
+    pub fn read_nodes_with<F>(&mut self, response: &Response, now: Instant, mut f: F)
     where
         F: FnMut(&Contact),
     {
         if let Some(nodes) = response.body.get_bytes("nodes") {
-            for c in CompactNodeIter::<4>::new(nodes)? {
+            for c in CompactNodeIter::<4>::new(nodes).map(Contact::from) {
                 f(&c);
                 self.add_contact(c, now);
             }
         }
 
         if let Some(nodes6) = response.body.get_bytes("nodes6") {
-            for c in CompactNodeIter::<16>::new(nodes6)? {
+            for c in CompactNodeIter::<16>::new(nodes6).map(Contact::from) {
                 f(&c);
                 self.add_contact(c, now);
             }
         }
 
         trace!("Live: {}, Extra: {}", self.len(), self.len_extra());
-
-        Ok(())
     }
 
     pub fn len(&self) -> usize {
@@ -219,14 +274,40 @@ impl RoutingTable {
         self.buckets.iter().all(|b| b.live.is_empty())
     }
 
-    pub fn find_contact(&mut self, id: NodeId) -> Option<&mut Contact> {
+    /// Ids and addresses of the live nodes.
+    pub fn live_nodes(&self) -> impl Iterator<Item = (NodeId, SocketAddr)> + '_ {
+        self.buckets
+            .iter()
+            .flat_map(|b| b.live.iter())
+            .map(|c| (c.id, c.addr))
+    }
+
+    pub fn get_contact(&self, id: NodeId) -> Option<&Contact> {
         let idx = self.idx_of(id);
-        self.buckets[idx].live.iter_mut().find(|c| c.id == id)
+        self.buckets[idx].live.iter().find(|c| c.id == id)
     }
 
     pub fn failed(&mut self, id: NodeId) {
-        if let Some(c) = self.find_contact(id) {
-            c.timed_out();
+        let idx = self.idx_of(id);
+        let bucket = &mut self.buckets[idx];
+
+        let i = match bucket.live.iter().position(|c| c.id == id) {
+            Some(i) => i,
+            None => return,
+        };
+
+        bucket.live[i].timed_out();
+
+        if bucket.pinging == Some(id) {
+            bucket.pinging = None;
+
+            // Gone; make room for the latest good node waiting for it
+            if let Some(j) = bucket.extra.iter().rposition(|c| c.is_confirmed()) {
+                trace!("Evicting unresponsive node {:?}", id);
+                let c = bucket.extra.remove(j);
+                bucket.live.remove(i);
+                bucket.live.push(c);
+            }
         }
     }
 
@@ -234,13 +315,49 @@ impl RoutingTable {
         let idx = self.idx_of(id);
         let bucket = &mut self.buckets[idx];
 
-        if let Some(c) = bucket.live.iter_mut().find(|c| c.id == id) {
+        if let Some(i) = bucket.live.iter().position(|c| c.id == id) {
+            let c = &mut bucket.live[i];
             c.status = ContactStatus::ALIVE | ContactStatus::QUERIED;
+            c.last_seen = Some(now);
             c.clear_timeout();
+            bucket.touch(i);
             self.timeouts[idx] = next_timeout(now);
+
+            if bucket.pinging == Some(id) {
+                bucket.pinging = None;
+            }
         }
     }
 
+    /// Up to `max` live nodes not heard from in `max_age`, the ones heard
+    /// from least recently first. Nodes being pinged for an eviction are
+    /// left out.
+    pub fn stale_nodes(
+        &self,
+        now: Instant,
+        max_age: Duration,
+        max: usize,
+    ) -> Vec<(NodeId, SocketAddr)> {
+        let mut stale: Vec<_> = self
+            .buckets
+            .iter()
+            .flat_map(|b| b.live.iter().filter(move |c| b.pinging != Some(c.id)))
+            .filter(|c| {
+                c.last_seen
+                    .is_none_or(|t| now.saturating_duration_since(t) >= max_age)
+            })
+            .collect();
+
+        // Never heard from goes first
+        stale.sort_by_key(|c| c.last_seen);
+        stale.iter().take(max).map(|c| (c.id, c.addr)).collect()
+    }
+
+    /// Ping of a node that may be evicted from its bucket.
+    pub fn next_ping(&mut self) -> Option<ClientRequest> {
+        self.pings.pop()
+    }
+
     fn idx_of(&self, id: NodeId) -> usize {
         self.root_id.xor_leading_zeros(id).min(BUCKETS - 1)
     }
@@ -349,4 +466,156 @@ mod tests {
 
         assert!(closest_iter.next().is_none());
     }
+
+    #[test]
+    fn ping_least_recently_seen_before_eviction() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId::all(0), vec![], now);
+        fn node(i: u8) -> NodeId {
+            let mut id = NodeId::all(0);
+            id[0] = 0x80 | i;
+            id
+        }
+
+        fn addr(i: u8) -> SocketAddr {
+            SocketAddr::from(([10, 0, 0, i], 100))
+        }
+
+        fn good(i: u8) -> Contact {
+            let mut c = Contact::new(node(i), addr(i));
+            c.set_confirmed();
+            c
+        }
+
+        for i in 0..8 {
+            assert!(table.add_contact(Contact::new(node(i), addr(i)), now));
+        }
+        assert_eq!(table.buckets[0].live.len(), 8);
+
+        // New good node waits while the least recently seen one is pinged
+        assert!(table.add_contact(good(8), now));
+        assert_eq!(table.buckets[0].extra.len(), 1);
+        assert!(table.get_contact(node(8)).is_none());
+        match table.next_ping() {
+            Some(ClientRequest::Ping { id, .. }) => assert_eq!(id, node(0)),
+            _ => panic!("Expected a ping"),
+        }
+        assert!(table.next_ping().is_none());
+
+        // Only one ping at a time per bucket
+        assert!(table.add_contact(good(9), now));
+        assert!(table.next_ping().is_none());
+
+        // It responded, so it stays
+        table.heard_from(node(0), now);
+        assert!(table.get_contact(node(0)).is_some());
+        assert_eq!(table.buckets[0].live.last().unwrap().id, node(0));
+
+        // Next one doesn't
+        assert!(table.add_contact(good(10), now));
+        match table.next_ping() {
+            Some(ClientRequest::Ping { id, .. }) => assert_eq!(id, node(1)),
+            _ => panic!("Expected a ping"),
+        }
+        table.failed(node(1));
+        assert!(table.get_contact(node(1)).is_none());
+        assert!(table.get_contact(node(10)).is_some());
+        assert_eq!(table.buckets[0].live.len(), 8);
+    }
+
+    #[test]
+    fn stale_nodes() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(NodeId::all(0), vec![], now);
+        let addr = SocketAddr::from(([10, 0, 0, 1], 100));
+        for i in 1..=4 {
+            assert!(table.add_contact(Contact::new(NodeId::all(i), addr), now));
+        }
+
+        let minute = Duration::from_secs(60);
+        table.heard_from(NodeId::all(1), now);
+        table.heard_from(NodeId::all(2), now + minute);
+        table.heard_from(NodeId::all(3), now + minute * 2);
+
+        let ids = |nodes: Vec<(NodeId, SocketAddr)>| -> Vec<_> {
+            nodes.into_iter().map(|(id, _)| id).collect()
+        };
+        let later = now + minute * 3;
+        assert_eq!(
+            ids(table.stale_nodes(later, minute * 3, 8)),
+            [NodeId::all(4), NodeId::all(1)]
+        );
+        assert_eq!(
+            ids(table.stale_nodes(later, minute * 2, 2)),
+            [NodeId::all(4), NodeId::all(1)]
+        );
+        assert_eq!(table.stale_nodes(later, minute * 2, 8).len(), 3);
+    }
+
+    #[test]
+    fn bucket_sizes() {
+        let now = Instant::now();
+        let config = TableConfig {
+            bucket_size: 16,
+            extra_size: 2,
+        };
+        let mut table = RoutingTable::with_config(NodeId::all(0), vec![], config, now);
+        fn node(i: u8) -> Contact {
+            let mut id = NodeId::all(0);
+            id[0] = 0x80 | i;
+            Contact::new(id, SocketAddr::from(([10, 0, 0, i], 100)))
+        }
+
+        for i in 0..20 {
+            assert!(table.add_contact(node(i), now));
+        }
+        assert_eq!(table.buckets[0].live.len(), 16);
+        assert_eq!(table.buckets[0].extra.len(), 2);
+
+        table.set_config(TableConfig {
+            bucket_size: 4,
+            extra_size: 0,
+        });
+        assert_eq!(table.len(), 4);
+        assert_eq!(table.len_extra(), 0);
+        assert_eq!(table.buckets[0].live[0].id, node(12).id);
+        assert!(!table.add_contact(node(30), now));
+    }
+
+    #[test]
+    fn bucket_shrink_promotes_extras() {
+        let now = Instant::now();
+        let config = TableConfig {
+            bucket_size: 8,
+            extra_size: 2,
+        };
+        let mut table = RoutingTable::with_config(NodeId::all(0), vec![], config, now);
+        fn node(i: u8) -> Contact {
+            let mut id = NodeId::all(0);
+            id[0] = 0x80 | i;
+            Contact::new(id, SocketAddr::from(([10, 0, 0, i], 100)))
+        }
+
+        for i in 0..10 {
+            assert!(table.add_contact(node(i), now));
+        }
+        // Hearing from node 9 again confirms it in the replacement list
+        assert!(table.add_contact(node(9), now));
+        let bucket = &mut table.buckets[0];
+        assert_eq!(bucket.extra.len(), 2);
+        for c in &mut bucket.live[5..] {
+            c.set_confirmed();
+            c.timed_out();
+        }
+
+        // Two of the failed nodes make room, node 9 replaces the third
+        table.set_config(TableConfig {
+            bucket_size: 6,
+            extra_size: 0,
+        });
+        let ids: Vec<_> = table.buckets[0].live.iter().map(|c| c.id).collect();
+        let expected: Vec<_> = [0, 1, 2, 3, 4, 9].map(|i| node(i).id).into();
+        assert_eq!(ids, expected);
+        assert_eq!(table.len_extra(), 0);
+    }
 }
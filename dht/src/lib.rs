@@ -3,5 +3,7 @@ extern crate tracing;
 
 mod server;
 
-pub use proto::NodeId;
-pub use server::Dht;
+pub use proto::{
+    KeepaliveConfig, Metrics, NodeId, QueryCounts, QueryHandler, QueryReply, TableConfig,
+};
+pub use server::{Dht, SharedTable};
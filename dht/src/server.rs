@@ -1,9 +1,12 @@
-use proto::{Event, NodeId};
+use proto::{Event, NodeId, TaskId};
 
 use futures::{select, FutureExt};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
     collections::HashSet,
+    io,
     net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 use tokio::{
@@ -11,91 +14,337 @@ use tokio::{
     time::{sleep_until, Instant as TokioInstant},
 };
 
+/// Routing table shared by the shards of a DHT server.
+///
+/// All the shards use the same node id. Each shard keeps its own routing
+/// table and exchanges the known nodes with the others through this
+/// snapshot whenever it handles a request.
+#[derive(Debug, Clone)]
+pub struct SharedTable {
+    id: NodeId,
+    nodes: Arc<Mutex<Vec<(NodeId, SocketAddr)>>>,
+}
+
+impl SharedTable {
+    pub fn new() -> Self {
+        Self {
+            id: NodeId::gen(),
+            nodes: Arc::default(),
+        }
+    }
+}
+
+impl Default for SharedTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Dht {
     dht: proto::Dht,
     socket: UdpSocket,
+
+    /// Socket of a shard's own queries. The kernel spreads the packets
+    /// arriving at the shared port by their source, so the replies to
+    /// queries sent from it would often reach another shard.
+    queries: Option<UdpSocket>,
     recv_buf: Vec<u8>,
+    shared: Option<SharedTable>,
+
+    /// Running peer lookup, if any
+    lookup: Option<TaskId>,
 }
 
 impl Dht {
     pub async fn new(port: u16, router_nodes: Vec<SocketAddr>) -> anyhow::Result<Self> {
-        let id = NodeId::gen();
+        let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).await?;
+        Ok(Self::with_socket(
+            NodeId::gen(),
+            socket,
+            None,
+            router_nodes,
+            None,
+        ))
+    }
+
+    /// Create one shard of a server listening on `port`.
+    ///
+    /// The shards bind with `SO_REUSEPORT`, so the kernel spreads incoming
+    /// queries between them. Each shard sends its own queries from a port
+    /// of its own, where it gets the replies. Each shard can be driven by
+    /// its own thread and runtime.
+    pub async fn shard(
+        port: u16,
+        router_nodes: Vec<SocketAddr>,
+        shared: &SharedTable,
+    ) -> anyhow::Result<Self> {
+        let socket = UdpSocket::from_std(bind_reuse_port(port)?)?;
+        let queries = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?;
+        let mut dht = Self::with_socket(
+            shared.id,
+            socket,
+            Some(queries),
+            router_nodes,
+            Some(shared.clone()),
+        );
+        dht.sync_table();
+        Ok(dht)
+    }
+
+    fn with_socket(
+        id: NodeId,
+        socket: UdpSocket,
+        queries: Option<UdpSocket>,
+        router_nodes: Vec<SocketAddr>,
+        shared: Option<SharedTable>,
+    ) -> Self {
         let now = Instant::now();
 
         let mut dht = proto::Dht::new(id, router_nodes, now);
-        let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).await?;
-
         dht.add_request(proto::ClientRequest::Bootstrap { target: id }, now);
 
-        Ok(Self {
+        Self {
             dht,
             socket,
+            queries,
             recv_buf: vec![0; 2048],
-        })
+            shared,
+            lookup: None,
+        }
     }
 
-    pub async fn get_peers(&mut self, info_hash: NodeId) -> anyhow::Result<HashSet<SocketAddr>> {
-        let req = proto::ClientRequest::Announce { info_hash };
-        self.wait_for_peers(req).await
+    /// Merge the nodes found by the other shards into our routing table and
+    /// publish the result.
+    fn sync_table(&mut self) {
+        let shared = match &self.shared {
+            Some(s) => s,
+            None => return,
+        };
+
+        let mut nodes = shared.nodes.lock().unwrap();
+        self.dht.add_nodes(nodes.iter().copied(), Instant::now());
+        *nodes = self.dht.nodes();
+    }
+
+    /// Add the nodes of an earlier session. They are pinged in batches
+    /// and only join the routing table once they answer, so that nodes
+    /// which went away in the meantime don't take up room.
+    pub fn restore_nodes(&mut self, nodes: impl IntoIterator<Item = (NodeId, SocketAddr)>) {
+        self.dht.restore_nodes(nodes, Instant::now());
+    }
+
+    /// Ping the node at `addr`, e.g. the DHT port of a peer, and add it to
+    /// the routing table once it answers.
+    pub fn ping_addr(&mut self, addr: SocketAddr) {
+        self.dht.ping_addr(addr, Instant::now());
+    }
+
+    /// Port the DHT socket is bound to.
+    pub fn port(&self) -> io::Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    /// Ids and addresses of the live nodes, e.g. to be restored in the
+    /// next session.
+    pub fn nodes(&self) -> Vec<(NodeId, SocketAddr)> {
+        self.dht.nodes()
+    }
+
+    /// Secrets of the get_peers tokens, to be restored in the next session
+    /// along with the nodes.
+    pub fn token_secrets(&self) -> [[u8; 20]; 2] {
+        self.dht.token_secrets()
+    }
+
+    pub fn restore_token_secrets(&mut self, secrets: [[u8; 20]; 2]) {
+        self.dht.restore_token_secrets(secrets, Instant::now());
+    }
+
+    /// Answer the queries of methods the DHT doesn't know with `handler`.
+    pub fn set_query_handler(&mut self, handler: impl proto::QueryHandler + 'static) {
+        self.dht.set_query_handler(handler);
+    }
+
+    /// Port our peer listens on, announced to the nodes. If it's the port of
+    /// the DHT socket, the nodes are told to take the source port of the
+    /// announces instead, which works through NATs that remap it.
+    pub fn set_peer_port(&mut self, port: u16) {
+        let own_port = self.query_socket().local_addr().map(|a| a.port()).ok();
+        let port = if own_port == Some(port) {
+            None
+        } else {
+            Some(port)
+        };
+        self.dht.set_announce_port(port);
+    }
+
+    /// Change the bucket sizes of the routing table, e.g. larger buckets
+    /// for a crawler. Nodes over the new sizes are dropped.
+    pub fn set_table_config(&mut self, config: proto::TableConfig) {
+        self.dht.set_table_config(config);
+    }
+
+    /// Ping the nodes we haven't heard from in a while, keeping the NAT
+    /// mappings to them open. `None`, the default, turns it off.
+    pub fn set_keepalive(&mut self, config: Option<proto::KeepaliveConfig>) {
+        self.dht.set_keepalive(config, Instant::now());
+    }
+
+    /// Counts of the queries and replies so far, and how long lookups take.
+    pub fn metrics(&self) -> proto::Metrics {
+        self.dht.metrics()
     }
 
-    pub async fn announce(&mut self, info_hash: NodeId) -> anyhow::Result<HashSet<SocketAddr>> {
-        let req = proto::ClientRequest::GetPeers { info_hash };
+    pub async fn get_peers(
+        &mut self,
+        info_hash: impl Into<NodeId>,
+    ) -> anyhow::Result<HashSet<SocketAddr>> {
+        let req = proto::ClientRequest::Announce {
+            info_hash: info_hash.into(),
+        };
         self.wait_for_peers(req).await
     }
 
-    async fn wait_for_peers(
+    pub async fn announce(
         &mut self,
-        req: proto::ClientRequest,
+        info_hash: impl Into<NodeId>,
     ) -> anyhow::Result<HashSet<SocketAddr>> {
-        if self.dht.add_request(req, Instant::now()).is_none() {
-            return Ok(HashSet::new());
+        let req = proto::ClientRequest::GetPeers {
+            info_hash: info_hash.into(),
+        };
+        self.wait_for_peers(req).await
+    }
+
+    /// Start looking up the peers of the torrent, replacing the lookup in
+    /// progress if any. The peers are received with `next_peers` as they
+    /// are found.
+    pub fn start_lookup(&mut self, info_hash: impl Into<NodeId>) {
+        self.start(proto::ClientRequest::GetPeers {
+            info_hash: info_hash.into(),
+        });
+    }
+
+    /// Wait for the next peers found by the lookup. Returns `None` once the
+    /// lookup is over.
+    pub async fn next_peers(&mut self) -> anyhow::Result<Option<HashSet<SocketAddr>>> {
+        let task_id = match self.lookup {
+            Some(task_id) => task_id,
+            None => return Ok(None),
+        };
+
+        loop {
+            if let Some((peers, done)) = self.process_events(Some(task_id)).await {
+                if done {
+                    self.lookup = None;
+                    if peers.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                return Ok(Some(peers));
+            }
+
+            self.step().await;
         }
+    }
+
+    /// Answer the queries of the other nodes and keep the routing table
+    /// fresh until `deadline`, e.g. while waiting for the next lookup.
+    /// Otherwise the queries would only be answered while a lookup runs.
+    ///
+    /// Peers found by a lookup in progress meanwhile are dropped.
+    pub async fn serve_until(&mut self, deadline: Instant) {
+        let deadline = TokioInstant::from_std(deadline);
+        loop {
+            self.process_events(None).await;
+
+            select! {
+                _ = sleep_until(deadline).fuse() => return,
+                _ = self.step().fuse() => {}
+            }
+        }
+    }
 
+    /// Wait for the next timer or packet and hand it to the DHT. Queries
+    /// are answered whatever the DHT is busy with, as the replies are
+    /// events like any other.
+    async fn step(&mut self) {
         let timer = sleep_until(self.next_timeout());
-        tokio::pin!(timer);
 
+        select! {
+            // Wait for timer
+            _ = timer.fuse() => self.dht.tick(Instant::now()),
+
+            // Listen for queries and responses
+            resp = self.recv().fuse() => {
+                match resp {
+                    Ok((len, addr)) => self.dht.receive(&self.recv_buf[..len], unmap_ipv4(addr), Instant::now()),
+                    Err(e) => warn!("Error: {}", e),
+                }
+            },
+        }
+    }
+
+    /// Receive the next packet from either of our sockets.
+    async fn recv(&mut self) -> io::Result<(usize, SocketAddr)> {
         loop {
-            select! {
-                // Wait for timer
-                _ = timer.as_mut().fuse() => self.dht.tick(Instant::now()),
-
-                // Listen for response
-                resp = self.socket.recv_from(&mut self.recv_buf).fuse() => {
-                    match resp {
-                        Ok((len, addr)) => self.dht.receive(&self.recv_buf[..len], unmap_ipv4(addr), Instant::now()),
-                        Err(e) => {
-                            warn!("Error: {}", e);
-                            continue;
-                        },
-                    }
+            let socket = match &self.queries {
+                Some(queries) => select! {
+                    r = self.socket.readable().fuse() => { r?; &self.socket },
+                    r = queries.readable().fuse() => { r?; queries },
                 },
+                None => return self.socket.recv_from(&mut self.recv_buf).await,
+            };
 
-                complete => break,
+            match socket.try_recv_from(&mut self.recv_buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return res,
             }
+        }
+    }
 
-            if let Some(peers) = self.process_events().await {
-                return Ok(peers);
-            }
+    fn query_socket(&self) -> &UdpSocket {
+        self.queries.as_ref().unwrap_or(&self.socket)
+    }
+
+    async fn wait_for_peers(
+        &mut self,
+        req: proto::ClientRequest,
+    ) -> anyhow::Result<HashSet<SocketAddr>> {
+        self.start(req);
 
-            timer.as_mut().reset(self.next_timeout());
+        let mut all_peers = HashSet::new();
+        while let Some(peers) = self.next_peers().await? {
+            all_peers.extend(peers);
         }
+        Ok(all_peers)
+    }
 
-        Ok(HashSet::new())
+    fn start(&mut self, req: proto::ClientRequest) {
+        self.sync_table();
+        self.lookup = self.dht.add_request(req, Instant::now());
     }
 
-    async fn process_events(&mut self) -> Option<HashSet<SocketAddr>> {
+    /// Handle the pending events. Returns the peers found by the given task
+    /// and whether it is done, if there are any.
+    async fn process_events(
+        &mut self,
+        task_id: Option<TaskId>,
+    ) -> Option<(HashSet<SocketAddr>, bool)> {
         while let Some(event) = self.dht.poll_event() {
             debug!("Received event: {}", event);
             match event {
-                Event::FoundPeers { peers } => return Some(peers),
-                Event::Bootstrapped { .. } => {}
+                Event::FoundPeers {
+                    task_id: id,
+                    peers,
+                    done,
+                } if Some(id) == task_id => return Some((peers, done)),
+                Event::FoundPeers { .. } | Event::Bootstrapped => {}
                 Event::Transmit {
                     task_id,
                     node_id,
                     data,
                     target,
-                } => match self.socket.send_to(&data, target).await {
+                } => match self.query_socket().send_to(&data, target).await {
                     Ok(n) if n == data.len() => {}
                     _ => self.dht.set_failed(task_id, node_id, target),
                 },
@@ -119,6 +368,16 @@ impl Dht {
     }
 }
 
+fn bind_reuse_port(port: u16) -> io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket.into())
+}
+
 fn unmap_ipv4(addr: SocketAddr) -> SocketAddr {
     if let IpAddr::V6(ip) = addr.ip() {
         if let Some(ip) = ip.to_ipv4() {
@@ -128,3 +387,36 @@ fn unmap_ipv4(addr: SocketAddr) -> SocketAddr {
 
     addr
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn queries_are_answered_between_lookups() {
+        let mut dht = Dht::new(0, vec![]).await.unwrap();
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, dht.port().unwrap()));
+
+        // No nodes to ask, so the lookup is over right away
+        dht.start_lookup([1; 20]);
+        assert!(dht.next_peers().await.unwrap().is_none());
+
+        let node = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let ping = async {
+            let query = b"d1:ad2:id20:aaaaaaaaaaaaaaaaaaaae1:q4:ping1:t2:xy1:y1:qe";
+            node.send_to(query, addr).await.unwrap();
+            let mut buf = [0; 1024];
+            let (len, from) = node.recv_from(&mut buf).await.unwrap();
+            assert_eq!(from, addr);
+            buf[..len].to_vec()
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let reply = select! {
+            _ = dht.serve_until(deadline).fuse() => panic!("Query not answered"),
+            reply = ping.fuse() => reply,
+        };
+        assert!(reply.ends_with(b"1:t2:xy1:y1:re"));
+    }
+}
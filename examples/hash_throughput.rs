@@ -0,0 +1,34 @@
+//! Measure how fast the pieces are verified with the SHA-1 backend
+//! compiled in.
+//!
+//! ```text
+//! cargo run --release --example hash_throughput
+//! cargo run --release --example hash_throughput --no-default-features
+//! ```
+
+use btrs::hash;
+use std::time::Instant;
+
+const PIECE_LEN: usize = 256 * 1024;
+const TOTAL: usize = 512 * 1024 * 1024;
+
+fn main() {
+    let piece: Vec<u8> = (0..PIECE_LEN).map(|i| i as u8).collect();
+
+    let start = Instant::now();
+    let mut digest = [0; 20];
+    for _ in 0..TOTAL / PIECE_LEN {
+        digest = hash::sha1(&piece);
+    }
+    let elapsed = start.elapsed();
+
+    let mb = TOTAL as f64 / (1024.0 * 1024.0);
+    println!(
+        "{}: {:.0} MiB/s ({} MiB in {:?}, last digest {:02x?})",
+        hash::BACKEND,
+        mb / elapsed.as_secs_f64(),
+        mb,
+        elapsed,
+        &digest[..4]
+    );
+}
@@ -0,0 +1,60 @@
+//! Print statistics about the swarm of a torrent.
+//!
+//! ```text
+//! cargo run --example swarm_inspect -- <info hash in hex> [tracker URL]...
+//! ```
+
+use btrs::swarm::{self, InspectConfig};
+use data_encoding::HEXLOWER_PERMISSIVE;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .compact()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let hex = args
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Info hash required"))?;
+    let info_hash: [u8; 20] = HEXLOWER_PERMISSIVE
+        .decode(hex.as_bytes())?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Info hash must be 20 bytes"))?;
+
+    let config = InspectConfig {
+        trackers: args.collect(),
+        ..InspectConfig::default()
+    };
+    let report = swarm::inspect_with(&info_hash, &config).await?;
+
+    println!(
+        "{} peers found, {} inspected, {} connected, {} seeds",
+        report.num_found,
+        report.peers.len(),
+        report.connected().count(),
+        report.num_seeds()
+    );
+
+    let num_pieces = report.num_pieces();
+    for peer in report.connected() {
+        println!(
+            "{:<45} {:<10} {:>6}/{:<6} {:>6.2} pieces/s {:>5} ms",
+            peer.addr,
+            peer.client().unwrap_or_default(),
+            peer.pieces.count(),
+            num_pieces,
+            peer.piece_rate,
+            peer.connect_time.unwrap_or_default().as_millis()
+        );
+    }
+
+    let availability = report.availability();
+    if let Some(min) = availability.iter().min() {
+        println!("Rarest piece is on {} of the connected peers", min);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,19 @@
+//! The 20-byte hashes which name things in BitTorrent, shared by the client
+//! and the DHT so that they pass them to each other as they are.
+//!
+//! A DHT lookup for a torrent searches for the nodes whose ids are closest
+//! to its info hash, so the DHT converts an [`InfoHash`] into a node id
+//! without copying it around by hand.
+
+/// Length of the hashes in bytes.
+pub const HASH_LEN: usize = 20;
+
+/// A SHA-1 hash, or anything else as long, e.g. a peer id.
+pub type Hash = [u8; HASH_LEN];
+
+/// SHA-1 hash of the info dictionary of a torrent, naming it in the swarm
+/// and in the DHT. Version 2 torrents use the truncated SHA-256 hash.
+pub type InfoHash = Hash;
+
+/// Id a peer gives itself in the handshake.
+pub type PeerId = Hash;
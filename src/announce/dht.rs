@@ -1,15 +1,27 @@
+use crate::traffic::{Category, Traffic};
 use client::InfoHash;
-use dht::Dht;
-use dht::NodeId;
+use dht::{Dht, KeepaliveConfig, NodeId};
 use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 use std::time::Duration;
 use std::time::Instant;
 
+/// Time between the lookups of a torrent.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
 pub struct DhtTracker {
     dht: Dht,
     next_announce: Instant,
+
+    /// Start of the lookup in progress, if any
+    lookup_start: Option<Instant>,
+
+    /// Where the DHT traffic is counted, if anywhere
+    traffic: Option<Traffic>,
+
+    /// Bytes out and in of the DHT already counted in `traffic`
+    counted: (u64, u64),
 }
 
 impl DhtTracker {
@@ -27,21 +39,75 @@ impl DhtTracker {
         .flatten()
         .collect();
 
-        let dht = Dht::new(6881, dht_routers).await?;
+        let mut dht = Dht::new(6881, dht_routers).await?;
+        dht.set_keepalive(Some(KeepaliveConfig::default()));
 
         Ok(Self {
             dht,
             next_announce: Instant::now(),
+            lookup_start: None,
+            traffic: None,
+            counted: (0, 0),
         })
     }
 
+    /// Announce `port` as the one our peer listens on. The next lookup
+    /// starts right away so that the nodes learn of the new port.
+    pub fn set_port(&mut self, port: u16) {
+        self.dht.set_peer_port(port);
+        self.next_announce = Instant::now();
+    }
+
+    /// Port our DHT node listens on, sent to the peers which run a DHT node
+    /// too.
+    pub fn dht_port(&self) -> Option<u16> {
+        self.dht.port().ok()
+    }
+
+    /// Ping the nodes we haven't heard from in a while, keeping the NAT
+    /// mappings to them open. On with the default config from the start,
+    /// `None` turns it off.
+    pub fn set_keepalive(&mut self, config: Option<KeepaliveConfig>) {
+        self.dht.set_keepalive(config);
+    }
+
+    /// Ping the DHT node of a peer, learned from its PORT message. It joins
+    /// the routing table once it answers.
+    pub fn add_node(&mut self, addr: SocketAddr) {
+        self.dht.ping_addr(addr);
+    }
+
+    /// Add the nodes of an earlier session. They join the routing table
+    /// once they answer a ping.
+    pub fn restore_nodes(&mut self, nodes: Vec<(NodeId, SocketAddr)>) {
+        self.dht.restore_nodes(nodes);
+    }
+
+    /// Count the bytes of the DHT messages in `traffic`. Counted as the
+    /// lookups make progress.
+    pub fn set_traffic(&mut self, traffic: Traffic) {
+        self.traffic = Some(traffic);
+    }
+
+    fn count_traffic(&mut self) {
+        if let Some(traffic) = &self.traffic {
+            let metrics = self.dht.metrics();
+            let (sent, received) = self.counted;
+            let up = metrics.bytes_out - sent;
+            traffic.add(Category::Dht, up, metrics.bytes_in - received);
+            self.counted = (metrics.bytes_out, metrics.bytes_in);
+        }
+    }
+
     pub async fn announce(&mut self, info_hash: &InfoHash) -> anyhow::Result<HashSet<SocketAddr>> {
-        tokio::time::sleep_until(self.next_announce.into()).await;
+        self.dht.serve_until(self.next_announce).await;
 
         debug!("Announcing to DHT");
         let start = Instant::now();
 
-        let peers = self.dht.announce(NodeId::from(*info_hash)).await?;
+        let peers = self.dht.announce(info_hash).await;
+        self.count_traffic();
+        let peers = peers?;
 
         let took = Instant::now() - start;
         debug!(
@@ -50,7 +116,40 @@ impl DhtTracker {
             peers.len()
         );
 
-        self.next_announce = Instant::now() + Duration::from_secs(15 * 60);
+        self.next_announce = Instant::now() + ANNOUNCE_INTERVAL;
         Ok(peers)
     }
+
+    /// Returns the peers of the torrent as they are found. A new lookup is
+    /// started once the previous one is over and the announce interval
+    /// has passed. Returns an empty set at the end of each lookup.
+    pub async fn next_peers(
+        &mut self,
+        info_hash: &InfoHash,
+    ) -> anyhow::Result<HashSet<SocketAddr>> {
+        if self.lookup_start.is_none() {
+            // Keep answering the other nodes until the next lookup
+            self.dht.serve_until(self.next_announce).await;
+            self.count_traffic();
+
+            debug!("Announcing to DHT");
+            self.dht.start_lookup(info_hash);
+            self.lookup_start = Some(Instant::now());
+        }
+
+        let peers = self.dht.next_peers().await;
+        self.count_traffic();
+        match peers? {
+            Some(peers) => {
+                debug!("Found {} peers", peers.len());
+                Ok(peers)
+            }
+            None => {
+                let took = self.lookup_start.take().unwrap().elapsed();
+                debug!("Lookup completed in {} ms", took.as_millis());
+                self.next_announce = Instant::now() + ANNOUNCE_INTERVAL;
+                Ok(HashSet::new())
+            }
+        }
+    }
 }
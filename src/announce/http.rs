@@ -1,66 +1,117 @@
 use crate::announce::{AnnounceRequest, AnnounceResponse};
+use crate::http::{redact, HttpClient};
 use crate::peer;
 use anyhow::Context;
 use ben::decode::Dict;
 use ben::Parser;
-use client::InfoHash;
 use percent_encoding::{percent_encode, PercentEncode, NON_ALPHANUMERIC};
-use reqwest::Client;
 use std::collections::HashSet;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
-fn encode_url(infohash: &InfoHash) -> PercentEncode {
-    percent_encode(infohash, NON_ALPHANUMERIC)
+fn encode_url(bytes: &[u8]) -> PercentEncode<'_> {
+    percent_encode(bytes, NON_ALPHANUMERIC)
 }
 
-pub async fn announce(req: AnnounceRequest<'_>) -> anyhow::Result<AnnounceResponse> {
-    let peer_id = std::str::from_utf8(&req.peer_id[..]).unwrap();
-    let info_hash_encoded = encode_url(&req.info_hash);
-    debug!("Infohash Encoded: {}", info_hash_encoded);
-    let url = format!("{}?info_hash={}", req.url, info_hash_encoded);
-    let data = Client::new()
-        .get(&url)
-        .query(&[("peer_id", peer_id)])
-        .query(&[("port", req.port)])
-        .query(&[("uploaded", "0"), ("downloaded", "0"), ("compact", "1")]) // prefer compact peer list
-        .send()
-        .await?
-        .bytes()
-        .await?;
+fn announce_url(req: &AnnounceRequest<'_>) -> String {
+    let separator = if req.url.contains('?') { '&' } else { '?' };
+    let mut url = format!(
+        "{}{}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+        req.url,
+        separator,
+        encode_url(&req.info_hash),
+        encode_url(&req.peer_id),
+        req.port,
+        req.uploaded,
+        req.downloaded,
+        req.left
+    );
+
+    if let Some(event) = req.event.as_str() {
+        url.push_str("&event=");
+        url.push_str(event);
+    }
+
+    // BEP 7: Let the tracker know we're reachable over IPv6 too
+    if let Some(ip) = req.ipv6 {
+        url.push_str("&ipv6=");
+        url.extend(encode_url(ip.to_string().as_bytes()));
+    }
+
+    if let Some(n) = req.num_want {
+        url.push_str("&numwant=");
+        url.push_str(&n.to_string());
+    }
+
+    let params = &req.params;
+    if params.no_peer_id {
+        url.push_str("&no_peer_id=1");
+    }
+    if params.report_corrupt {
+        url.push_str("&corrupt=");
+        url.push_str(&req.corrupt.to_string());
+    }
+
+    url
+}
+
+pub async fn announce(
+    req: AnnounceRequest<'_>,
+    http: &mut HttpClient,
+) -> anyhow::Result<AnnounceResponse> {
+    let url = announce_url(&req);
+    debug!("Announce URL: {}", redact(&url));
+
+    let resp = http.fetch(&url, None).await?;
+    anyhow::ensure!(resp.is_success(), "Tracker returned {}", resp.status);
+    req.count_traffic(resp.request_size, resp.body.len());
+    let data = resp.body;
 
     debug!("Announce response: {:?}", data);
+    parse_response(&data)
+}
+
+fn parse_response(data: &[u8]) -> anyhow::Result<AnnounceResponse> {
     let mut parser = Parser::new();
-    let value = parser.parse::<Dict>(&data)?;
+    let value = parser.parse::<Dict>(data)?;
     let interval = value.get_int("interval").unwrap_or(0);
 
-    let peers = match value.get("peers") {
-        Some(peers) if peers.is_list() => {
-            let mut v = hashset![];
-            for peer in peers.as_list().unwrap().iter() {
+    let mut peers = HashSet::new();
+    let mut peers6 = HashSet::new();
+
+    match value.get("peers") {
+        Some(list) if list.is_list() => {
+            // Non-compact list may contain both v4 and v6 peers
+            for peer in list.as_list().unwrap().iter() {
                 let peer = peer.as_dict().context("Peer not a dict")?;
-                let ip = peer
+                let ip: IpAddr = peer
                     .get_str("ip")
                     .context("IP not present")
                     .and_then(|v| v.parse().context("Invalid IP/DNS name"))?;
                 let port = peer.get_int("port").context("Port not present")?;
-                v.insert(SocketAddr::new(ip, port));
+
+                let addr = SocketAddr::new(ip, port);
+                if ip.is_ipv4() {
+                    peers.insert(addr);
+                } else {
+                    peers6.insert(addr);
+                }
             }
-            v
         }
-        Some(peers) => {
-            let peers = peers.as_bytes().unwrap_or_default();
-            anyhow::ensure!(peers.len() % 6 == 0, "Invalid peer len");
-            peers.chunks_exact(6).map(peer::v4).collect()
+        Some(compact) => {
+            let compact = compact.as_bytes().unwrap_or_default();
+            anyhow::ensure!(compact.len() % 6 == 0, "Invalid peer len");
+            peers.extend(compact.chunks_exact(6).map(peer::v4));
         }
-        None => hashset![],
-    };
-
-    debug!("Found {} peers (v4): {:?}", peers.len(), peers);
+        None => {}
+    }
 
-    let peers6 = value.get_bytes("peers6").unwrap_or_default();
-    anyhow::ensure!(peers6.len() % 18 == 0, "Invalid peer len");
+    // BEP 7: compact IPv6 peers
+    if let Some(compact) = value.get_bytes("peers6") {
+        anyhow::ensure!(compact.len() % 18 == 0, "Invalid peer6 len");
+        peers6.extend(compact.chunks_exact(18).map(peer::v6));
+    }
 
-    let peers6: HashSet<_> = peers6.chunks_exact(18).map(peer::v6).collect();
+    debug!("Found {} peers (v4): {:?}", peers.len(), peers);
     debug!("Found {} peers (v6): {:?}", peers6.len(), peers6);
 
     Ok(AnnounceResponse {
@@ -70,3 +121,94 @@ pub async fn announce(req: AnnounceRequest<'_>) -> anyhow::Result<AnnounceRespon
         resolved_addr: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::announce::{AnnounceParams, Event};
+
+    #[test]
+    fn announce_url_with_ipv6() {
+        let mut req = AnnounceRequest::new(
+            "http://a.com/announce",
+            None,
+            &[1; 20],
+            b"-UT3100-000000000000",
+            6881,
+        );
+        req.ipv6 = Some("2001:db8::1".parse().unwrap());
+
+        let url = announce_url(&req);
+        assert!(url.starts_with("http://a.com/announce?info_hash=%01%01"));
+        assert!(url.contains("&compact=1"));
+        assert!(url.ends_with("&ipv6=2001%3Adb8%3A%3A1"));
+    }
+
+    #[test]
+    fn announce_url_with_stats() {
+        let mut req = AnnounceRequest::new(
+            "http://a.com/announce?key=1",
+            None,
+            &[1; 20],
+            b"-UT3100-000000000000",
+            6881,
+        );
+        req.uploaded = 10;
+        req.downloaded = 20;
+        req.left = 30;
+        req.event = Event::Stopped;
+
+        let url = announce_url(&req);
+        assert!(url.starts_with("http://a.com/announce?key=1&info_hash="));
+        assert!(url.contains("&uploaded=10&downloaded=20&left=30&compact=1"));
+        assert!(url.ends_with("&event=stopped"));
+
+        req.num_want = Some(0);
+        assert!(announce_url(&req).ends_with("&event=stopped&numwant=0"));
+    }
+
+    #[test]
+    fn announce_url_with_params() {
+        let mut req = AnnounceRequest::new(
+            "http://a.com/announce",
+            None,
+            &[1; 20],
+            b"-UT3100-000000000000",
+            6881,
+        );
+        req.corrupt = 16384;
+        assert!(announce_url(&req).ends_with("&compact=1"));
+
+        req.params = AnnounceParams {
+            no_peer_id: true,
+            report_corrupt: true,
+        };
+        let url = announce_url(&req);
+        assert!(url.ends_with("&compact=1&no_peer_id=1&corrupt=16384"));
+    }
+
+    #[test]
+    fn parse_compact_peers() {
+        let mut data = b"d8:intervali1800e5:peers6:".to_vec();
+        data.extend([1, 2, 3, 4, 0x1a, 0xe1]);
+        data.extend(b"6:peers618:");
+        data.extend([
+            0x20, 1, 0xd, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1,
+        ]);
+        data.push(b'e');
+
+        let resp = parse_response(&data).unwrap();
+        assert_eq!(resp.interval, 1800);
+        assert_eq!(resp.peers, hashset!["1.2.3.4:6881".parse().unwrap()]);
+        assert_eq!(resp.peers6, hashset!["[2001:db8::1]:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn parse_peer_list_with_ipv6() {
+        let data = b"d5:peersld2:ip7:1.2.3.44:porti6881eed2:ip11:2001:db8::14:porti6882eeee";
+
+        let resp = parse_response(data).unwrap();
+        assert_eq!(resp.peers, hashset!["1.2.3.4:6881".parse().unwrap()]);
+        assert_eq!(resp.peers6, hashset!["[2001:db8::1]:6882".parse().unwrap()]);
+    }
+}
@@ -1,8 +1,14 @@
 use client::{InfoHash, PeerId};
 
 use crate::future::timeout;
-use std::collections::HashSet;
-use std::net::SocketAddr;
+use crate::http::{redact, HttpClient, HttpConfig};
+use crate::traffic::{Category, Traffic};
+use futures::channel::oneshot;
+use rand::Rng;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 mod dht;
@@ -13,7 +19,14 @@ pub use self::dht::DhtTracker;
 
 const MIN_TRACKER_INTERVAL: u64 = 10;
 
-#[derive(Debug, Clone, Copy)]
+/// Port announced as the one our peer listens on unless changed.
+pub const DEFAULT_PORT: u16 = 6881;
+
+/// Announces are delayed by up to this fraction of the interval so that
+/// the trackers of a torrent don't all come due at the same time.
+const ANNOUNCE_JITTER: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Event {
     None,
     Completed,
@@ -21,6 +34,167 @@ pub enum Event {
     Stopped,
 }
 
+impl Event {
+    /// Value of the `event` parameter of HTTP announces.
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Completed => Some("completed"),
+            Self::Started => Some("started"),
+            Self::Stopped => Some("stopped"),
+        }
+    }
+}
+
+/// Transfer counters reported to the trackers, in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransferStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+
+    /// Downloaded bytes of the pieces which failed the hash check.
+    pub corrupt: u64,
+}
+
+/// Extra parameters of the HTTP announces, which some trackers, private
+/// ones in particular, require. None are sent by default.
+///
+/// `supportcrypto` and `requirecrypto` are left out until the connections
+/// can be encrypted (MSE).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AnnounceParams {
+    /// `no_peer_id=1`: the peer ids can be left out of non-compact peer
+    /// lists.
+    pub no_peer_id: bool,
+
+    /// `corrupt=<bytes>`: report the bytes of the pieces which failed the
+    /// hash check along with the other counters.
+    pub report_corrupt: bool,
+}
+
+/// Limit on the announces of a torrent in flight at a time, so that its
+/// trackers aren't all hit at once.
+///
+/// The trackers waiting for a slot get it highest priority first, e.g. the
+/// ones which gave us the most new peers so far. Cloning returns a handle
+/// to the same slots.
+#[derive(Clone)]
+pub struct AnnounceSlots {
+    inner: Arc<Mutex<SlotsInner>>,
+}
+
+struct SlotsInner {
+    free: usize,
+    waiting: BinaryHeap<Waiter>,
+
+    /// Order of arrival, so that waiters of the same priority take turns
+    seq: u64,
+}
+
+struct Waiter {
+    priority: u64,
+    seq: u64,
+    tx: oneshot::Sender<AnnounceSlot>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+/// Permission to announce, given back to the slots when dropped.
+pub struct AnnounceSlot {
+    /// `None` once the slot went back, or when it's not to be released
+    slots: Option<AnnounceSlots>,
+}
+
+impl AnnounceSlots {
+    /// Slots for `n` announces at a time, at least one.
+    pub fn new(n: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(SlotsInner {
+                free: n.max(1),
+                waiting: BinaryHeap::new(),
+                seq: 0,
+            })),
+        }
+    }
+
+    /// Wait for a free slot. Higher `priority` goes first.
+    pub async fn acquire(&self, priority: u64) -> AnnounceSlot {
+        let rx = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.free > 0 {
+                inner.free -= 1;
+                return AnnounceSlot {
+                    slots: Some(self.clone()),
+                };
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let seq = inner.seq;
+            inner.seq += 1;
+            inner.waiting.push(Waiter { priority, seq, tx });
+            rx
+        };
+
+        // The sender is only dropped along with the slots, which we hold
+        rx.await.expect("Announce slots dropped")
+    }
+
+    /// Hand the slot to the next waiter, or free it if there's none.
+    fn release(&self) {
+        loop {
+            let waiter = {
+                let mut inner = self.inner.lock().unwrap();
+                match inner.waiting.pop() {
+                    Some(w) => w,
+                    None => {
+                        inner.free += 1;
+                        return;
+                    }
+                }
+            };
+
+            let slot = AnnounceSlot {
+                slots: Some(self.clone()),
+            };
+            match waiter.tx.send(slot) {
+                Ok(()) => return,
+                // The waiter gave up, try the next one without releasing
+                // the slot again
+                Err(mut slot) => slot.slots = None,
+            }
+        }
+    }
+}
+
+impl Drop for AnnounceSlot {
+    fn drop(&mut self) {
+        if let Some(slots) = self.slots.take() {
+            slots.release();
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Tracker {
     pub url: String,
@@ -28,29 +202,138 @@ pub struct Tracker {
     next_announce: Instant,
     interval: u64,
     buf: Box<[u8]>,
+    http: HttpClient,
+    ipv6: Option<Ipv6Addr>,
+    num_want: Option<u32>,
+    port: u16,
+    params: AnnounceParams,
+    traffic: Option<Traffic>,
+    started: bool,
 }
 
 impl Tracker {
     pub fn new(url: String) -> Self {
+        Self::with_config(url, HttpConfig::default())
+    }
+
+    /// Tracker with the given settings for HTTP announces. The URL is used
+    /// as is, so any passkey in it is preserved.
+    pub fn with_config(url: String, http: HttpConfig) -> Self {
         Self {
             url,
             resolved_addr: None,
             next_announce: Instant::now(),
             interval: MIN_TRACKER_INTERVAL,
             buf: vec![0; 2048].into_boxed_slice(),
+            http: HttpClient::with_config(http),
+            ipv6: None,
+            num_want: None,
+            port: DEFAULT_PORT,
+            params: AnnounceParams::default(),
+            traffic: None,
+            started: false,
         }
     }
 
+    /// Wait for the next announce interval and announce without any event
+    /// or transfer counters. Used to look up peers only.
     pub async fn announce(
         &mut self,
         info_hash: &InfoHash,
         peer_id: &PeerId,
     ) -> anyhow::Result<AnnounceResponse> {
+        self.wait().await;
+        self.announce_event(info_hash, peer_id, TransferStats::default(), Event::None)
+            .await
+    }
+
+    /// Number of peers to ask for in the announces. `None` leaves it to the
+    /// tracker. `Some(0)` only keeps the tracker up to date with us, e.g.
+    /// when we have all the peers we need or are about to stop.
+    pub fn set_num_want(&mut self, num_want: Option<u32>) {
+        self.num_want = num_want;
+    }
+
+    pub fn num_want(&self) -> Option<u32> {
+        self.num_want
+    }
+
+    /// Port our peer listens on. `DEFAULT_PORT` unless changed.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    /// Our global IPv6 address, sent in HTTP announces so that the tracker
+    /// hands it out along with the address the announce came from (BEP 7).
+    pub fn set_ipv6(&mut self, ip: Option<Ipv6Addr>) {
+        self.ipv6 = ip;
+    }
+
+    /// Extra parameters sent in HTTP announces. UDP trackers have no use
+    /// for them.
+    pub fn set_params(&mut self, params: AnnounceParams) {
+        self.params = params;
+    }
+
+    /// Count the bytes of the announces in `traffic`.
+    pub fn set_traffic(&mut self, traffic: Traffic) {
+        self.traffic = Some(traffic);
+    }
+
+    /// Announce `delay` from now instead of at the end of the interval. Used
+    /// to stagger the trackers of a torrent, and to tell them of changes
+    /// right away.
+    pub fn announce_in(&mut self, delay: Duration) {
+        self.next_announce = Instant::now() + delay;
+    }
+
+    /// Wait until the tracker wants to hear from us again.
+    pub async fn wait(&self) {
         tokio::time::sleep_until(self.next_announce.into()).await;
+    }
+
+    /// Regular announce. The first successful announce to the tracker is sent
+    /// with the `Started` event.
+    pub async fn announce_stats(
+        &mut self,
+        info_hash: &InfoHash,
+        peer_id: &PeerId,
+        stats: TransferStats,
+    ) -> anyhow::Result<AnnounceResponse> {
+        let event = if self.started {
+            Event::None
+        } else {
+            Event::Started
+        };
 
-        trace!("Announce to {}", self.url);
-        let req = AnnounceRequest::new(&self.url, self.resolved_addr, info_hash, peer_id, 6881);
-        let resp = match timeout(req.announce(&mut self.buf), 3).await {
+        let resp = self.announce_event(info_hash, peer_id, stats, event).await;
+        if resp.is_ok() {
+            self.started = true;
+        }
+        resp
+    }
+
+    /// Announce with the given event right away.
+    pub async fn announce_event(
+        &mut self,
+        info_hash: &InfoHash,
+        peer_id: &PeerId,
+        stats: TransferStats,
+        event: Event,
+    ) -> anyhow::Result<AnnounceResponse> {
+        trace!("Announce to {}, event: {:?}", redact(&self.url), event);
+        let mut req =
+            AnnounceRequest::new(&self.url, self.resolved_addr, info_hash, peer_id, self.port);
+        req.ipv6 = self.ipv6;
+        req.uploaded = stats.uploaded;
+        req.downloaded = stats.downloaded;
+        req.left = stats.left;
+        req.corrupt = stats.corrupt;
+        req.event = event;
+        req.params = self.params;
+        req.num_want = self.num_want;
+        req.traffic = self.traffic.clone();
+        let resp = match timeout(req.announce(&mut self.buf, &mut self.http), 3).await {
             Ok(r) => {
                 self.interval = MIN_TRACKER_INTERVAL.max(r.interval);
                 self.resolved_addr = r.resolved_addr;
@@ -58,11 +341,22 @@ impl Tracker {
             }
             Err(e) => Err(e),
         };
-        self.next_announce = Instant::now() + Duration::from_secs(self.interval);
+        self.next_announce = Instant::now() + jittered(self.interval);
+        if event == Event::Stopped {
+            // Whatever comes next is a new start
+            self.started = false;
+        }
         resp
     }
 }
 
+/// `interval` seconds plus a random delay of up to `ANNOUNCE_JITTER` of it.
+fn jittered(interval: u64) -> Duration {
+    let interval = Duration::from_secs(interval);
+    let max = interval.mul_f64(ANNOUNCE_JITTER);
+    interval + max.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
 #[derive(Debug)]
 pub struct AnnounceResponse {
     pub resolved_addr: Option<SocketAddr>,
@@ -84,7 +378,21 @@ pub struct AnnounceRequest<'a> {
     pub downloaded: u64,
     pub left: u64,
     pub uploaded: u64,
+    pub corrupt: u64,
     pub event: Event,
+
+    /// Extra parameters of HTTP announces.
+    pub params: AnnounceParams,
+
+    /// Our global IPv6 address, if any. Sent to HTTP trackers so that they
+    /// can hand it out to IPv6 peers.
+    pub ipv6: Option<Ipv6Addr>,
+
+    /// Number of peers wanted. `None` leaves it to the tracker.
+    pub num_want: Option<u32>,
+
+    /// Where the bytes sent and received are counted, if anywhere.
+    pub traffic: Option<Traffic>,
 }
 
 impl<'a> AnnounceRequest<'a> {
@@ -98,23 +406,79 @@ impl<'a> AnnounceRequest<'a> {
         Self {
             url,
             resolved_addr,
-            info_hash: info_hash.clone(),
-            peer_id: peer_id.clone(),
+            info_hash: *info_hash,
+            peer_id: *peer_id,
             port,
             downloaded: 0,
             left: 0,
             uploaded: 0,
+            corrupt: 0,
             event: Event::None,
+            params: AnnounceParams::default(),
+            ipv6: None,
+            num_want: None,
+            traffic: None,
         }
     }
 
-    pub async fn announce(self, buf: &mut [u8]) -> anyhow::Result<AnnounceResponse> {
+    pub async fn announce(
+        self,
+        buf: &mut [u8],
+        http: &mut HttpClient,
+    ) -> anyhow::Result<AnnounceResponse> {
         if self.url.starts_with("http") {
-            http::announce(self).await
+            http::announce(self, http).await
         } else if self.url.starts_with("udp") {
             udp::announce(self, buf).await
         } else {
             anyhow::bail!("Unsupported tracker URL");
         }
     }
+
+    fn count_traffic(&self, up: usize, down: usize) {
+        if let Some(traffic) = &self.traffic {
+            traffic.add(Category::Tracker, up as u64, down as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn announce_slots_by_priority() {
+        use futures::FutureExt;
+
+        let slots = AnnounceSlots::new(1);
+        let first = slots.acquire(0).await;
+        let mut low = Box::pin(slots.acquire(1));
+        let mut gone = Box::pin(slots.acquire(9));
+        let mut high = Box::pin(slots.acquire(5));
+        assert!((&mut low).now_or_never().is_none());
+        assert!((&mut gone).now_or_never().is_none());
+        assert!((&mut high).now_or_never().is_none());
+
+        // The highest priority waiter still waiting gets it next
+        drop(gone);
+        drop(first);
+        assert!((&mut low).now_or_never().is_none());
+        let high = high.now_or_never().unwrap();
+        drop(high);
+        let low = low.now_or_never().unwrap();
+        drop(low);
+        assert_eq!(slots.inner.lock().unwrap().free, 1);
+
+        // Nothing holds on to the slots after the waiter which gave up
+        assert_eq!(Arc::strong_count(&slots.inner), 1);
+    }
+
+    #[test]
+    fn jitter_bounds() {
+        for _ in 0..100 {
+            let d = jittered(1800);
+            assert!(d >= Duration::from_secs(1800));
+            assert!(d <= Duration::from_secs(1980));
+        }
+    }
 }
@@ -14,8 +14,12 @@ const TRACKER_CONSTANT: u64 = 0x0417_2710_1980;
 mod action {
     pub const CONNECT: u32 = 0;
     pub const ANNOUNCE: u32 = 1;
+    pub const ERROR: u32 = 3;
 }
 
+/// Length of the action and transaction id heading all the responses.
+const HEADER_LEN: usize = 8;
+
 pub async fn announce(
     req: AnnounceRequest<'_>,
     buf: &mut [u8],
@@ -61,6 +65,7 @@ impl<'a> UdpTracker<'a> {
 
         let n = self.write_connect(buf)?;
         let written = self.socket.send_to(&buf[..n], &self.addr).await?;
+        self.req.count_traffic(written, 0);
         anyhow::ensure!(written == n, "Error sending data");
 
         let (_, mut c) = self.read_response(action::CONNECT, buf, 16).await?;
@@ -78,6 +83,7 @@ impl<'a> UdpTracker<'a> {
 
         let n = self.write_announce(buf)?;
         let written = self.socket.send_to(&buf[..n], &self.addr).await?;
+        self.req.count_traffic(written, 0);
         anyhow::ensure!(written == n, "Error sending data");
 
         let (len, mut c) = self.read_response(action::ANNOUNCE, buf, 20).await?;
@@ -115,28 +121,31 @@ impl<'a> UdpTracker<'a> {
         Ok(resp)
     }
 
+    /// Wait for the response to our latest request. Datagrams from other
+    /// addresses or with other transaction ids, e.g. late responses to
+    /// earlier requests, are skipped; the caller's timeout bounds the wait.
     async fn read_response<'b>(
         &self,
         expected_action: u32,
         buf: &'b mut [u8],
         min_len: usize,
     ) -> anyhow::Result<(usize, Cursor<&'b [u8]>)> {
-        let (len, addr) = self.socket.recv_from(buf).await?;
-
-        anyhow::ensure!(addr == self.addr, "Packet received from unexpected address");
-        anyhow::ensure!(len >= min_len, "Packet too small");
-
-        let buf = &buf[..len];
-
-        let mut c = Cursor::new(buf);
-        let action = c.read_u32::<BE>()?;
-        let txn_id = c.read_u32::<BE>()?;
-
-        trace!("Received action: {}, txn_id: {}", action, txn_id);
-
-        anyhow::ensure!(expected_action == action, "Incorrect msg action received");
-        anyhow::ensure!(self.txn_id == txn_id, "Txn Id mismatch");
+        let len = loop {
+            let (len, addr) = self.socket.recv_from(buf).await?;
+            self.req.count_traffic(0, len);
+
+            if addr != self.addr {
+                debug!("Ignoring packet from unexpected address {}", addr);
+                continue;
+            }
+
+            if check_response(&buf[..len], expected_action, self.txn_id, min_len)? {
+                break len;
+            }
+        };
 
+        let mut c = Cursor::new(&buf[..len]);
+        c.set_position(HEADER_LEN as u64);
         Ok((len, c))
     }
 
@@ -155,18 +164,57 @@ impl<'a> UdpTracker<'a> {
         c.write_u32::<BE>(self.txn_id)?;
         c.write_all(self.req.info_hash.as_ref())?;
         c.write_all(&self.req.peer_id[..])?;
-        c.write_u64::<BE>(0)?; // downloaded
-        c.write_u64::<BE>(0)?; // left
-        c.write_u64::<BE>(0)?; // uploaded
+        c.write_u64::<BE>(self.req.downloaded)?;
+        c.write_u64::<BE>(self.req.left)?;
+        c.write_u64::<BE>(self.req.uploaded)?;
         c.write_u32::<BE>(self.req.event as u32)?;
         c.write_u32::<BE>(0)?; // IP addr
         c.write_u32::<BE>(0)?; // key
-        c.write_i32::<BE>(-1)?; // num_want
+        let num_want = self
+            .req
+            .num_want
+            .map_or(-1, |n| i32::try_from(n).unwrap_or(i32::MAX));
+        c.write_i32::<BE>(num_want)?;
         c.write_u16::<BE>(self.req.port)?; // port
         Ok(c.position() as usize)
     }
 }
 
+/// Check a response from the tracker. Returns false if it isn't a response
+/// to the request with `txn_id`, so it should be ignored, and fails with
+/// the message of the tracker if the tracker sent an error.
+fn check_response(
+    buf: &[u8],
+    expected_action: u32,
+    txn_id: u32,
+    min_len: usize,
+) -> anyhow::Result<bool> {
+    if buf.len() < HEADER_LEN {
+        debug!("Ignoring packet of {} bytes", buf.len());
+        return Ok(false);
+    }
+
+    let mut c = Cursor::new(buf);
+    let action = c.read_u32::<BE>()?;
+    let resp_txn_id = c.read_u32::<BE>()?;
+
+    trace!("Received action: {}, txn_id: {}", action, resp_txn_id);
+
+    if resp_txn_id != txn_id {
+        debug!("Ignoring response with txn id {}", resp_txn_id);
+        return Ok(false);
+    }
+
+    if action == action::ERROR {
+        let msg = String::from_utf8_lossy(&buf[HEADER_LEN..]);
+        anyhow::bail!("Tracker error: {}", msg.trim_end_matches('\0'));
+    }
+
+    anyhow::ensure!(expected_action == action, "Incorrect msg action received");
+    anyhow::ensure!(buf.len() >= min_len, "Packet too small");
+    Ok(true)
+}
+
 async fn resolve_addr(url: &str) -> anyhow::Result<SocketAddr> {
     let url: Url = url.parse().context("Failed to parse tracker url")?;
     anyhow::ensure!(url.scheme() == "udp", "Not a UDP url");
@@ -182,3 +230,39 @@ async fn resolve_addr(url: &str) -> anyhow::Result<SocketAddr> {
         anyhow::bail!("Host/port is not resolved to a socket addr")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(action: u32, txn_id: u32, body: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.write_u32::<BE>(action).unwrap();
+        buf.write_u32::<BE>(txn_id).unwrap();
+        buf.extend(body);
+        buf
+    }
+
+    #[test]
+    fn check_responses() {
+        let connect = response(action::CONNECT, 7, &[0; 8]);
+        assert!(check_response(&connect, action::CONNECT, 7, 16).unwrap());
+
+        // Stray responses are skipped
+        assert!(!check_response(&connect, action::CONNECT, 8, 16).unwrap());
+        assert!(!check_response(&connect[..4], action::CONNECT, 7, 16).unwrap());
+
+        assert!(check_response(&connect, action::ANNOUNCE, 7, 16).is_err());
+        assert!(check_response(&connect[..12], action::CONNECT, 7, 16).is_err());
+    }
+
+    #[test]
+    fn error_response() {
+        let error = response(action::ERROR, 7, b"Unregistered torrent\0");
+        let e = check_response(&error, action::ANNOUNCE, 7, 20).unwrap_err();
+        assert_eq!(e.to_string(), "Tracker error: Unregistered torrent");
+
+        // Not ours
+        assert!(!check_response(&error, action::ANNOUNCE, 8, 20).unwrap());
+    }
+}
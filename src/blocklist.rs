@@ -0,0 +1,193 @@
+use crate::peer::canonical_ip;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a first time offender stays banned.
+const BASE_BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Repeat offenders get a longer ban, up to this duration.
+const MAX_BAN_DURATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanReason {
+    /// Sent data which failed the hash check
+    CorruptData,
+
+    /// Violated the peer wire protocol
+    ProtocolViolation,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub reason: BanReason,
+
+    /// Number of times the IP was banned without its earlier bans
+    /// decaying.
+    pub count: u32,
+
+    /// The ban expires at this instant.
+    pub until: Instant,
+}
+
+impl Ban {
+    fn duration(count: u32) -> Duration {
+        let factor = 1_u32 << count.saturating_sub(1).min(16);
+        BASE_BAN_DURATION
+            .checked_mul(factor)
+            .map_or(MAX_BAN_DURATION, |d| d.min(MAX_BAN_DURATION))
+    }
+
+    /// Once a ban expires, it's still remembered for as long as it lasted
+    /// so that repeat offenders get banned for longer.
+    fn forget_at(&self) -> Instant {
+        self.until + Self::duration(self.count)
+    }
+}
+
+/// List of misbehaving peer IPs shared between all the torrents in a session.
+///
+/// Bans expire with time. Cloning returns a handle to the same list.
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    bans: Arc<Mutex<HashMap<IpAddr, Ban>>>,
+}
+
+impl Blocklist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ban the IP. If it was banned recently, the ban lasts longer.
+    pub fn ban(&self, ip: IpAddr, reason: BanReason) {
+        self.ban_at(ip, reason, Instant::now());
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned_at(ip, Instant::now())
+    }
+
+    /// Ban info of the IP, if it's currently banned.
+    pub fn get(&self, ip: IpAddr) -> Option<Ban> {
+        let now = Instant::now();
+        let bans = self.bans.lock().unwrap();
+        bans.get(&canonical_ip(ip))
+            .filter(|b| b.until > now)
+            .cloned()
+    }
+
+    /// Currently banned IPs.
+    pub fn banned(&self) -> Vec<(IpAddr, Ban)> {
+        let now = Instant::now();
+        let bans = self.bans.lock().unwrap();
+        bans.iter()
+            .filter(|(_, b)| b.until > now)
+            .map(|(ip, b)| (*ip, b.clone()))
+            .collect()
+    }
+
+    /// Lift the ban on the IP and forget its earlier offences.
+    pub fn unban(&self, ip: IpAddr) {
+        self.bans.lock().unwrap().remove(&canonical_ip(ip));
+    }
+
+    pub fn clear(&self) {
+        self.bans.lock().unwrap().clear();
+    }
+
+    fn ban_at(&self, ip: IpAddr, reason: BanReason, now: Instant) {
+        let ip = canonical_ip(ip);
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|_, b| b.forget_at() > now);
+
+        let count = bans.get(&ip).map_or(0, |b| b.count) + 1;
+        info!("Banning {} ({:?}); offence #{}", ip, reason, count);
+
+        bans.insert(
+            ip,
+            Ban {
+                reason,
+                count,
+                until: now + Ban::duration(count),
+            },
+        );
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let bans = self.bans.lock().unwrap();
+        bans.get(&canonical_ip(ip)).is_some_and(|b| b.until > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ban_expires() {
+        let list = Blocklist::new();
+        let now = Instant::now();
+
+        list.ban_at(ip("1.2.3.4"), BanReason::CorruptData, now);
+        assert!(list.is_banned_at(ip("1.2.3.4"), now));
+        assert!(list.is_banned_at(ip("::ffff:1.2.3.4"), now));
+        assert!(!list.is_banned_at(ip("1.2.3.5"), now));
+        assert!(!list.is_banned_at(ip("1.2.3.4"), now + BASE_BAN_DURATION));
+    }
+
+    #[test]
+    fn repeat_offender() {
+        let list = Blocklist::new();
+        let now = Instant::now();
+        let addr = ip("1.2.3.4");
+
+        list.ban_at(addr, BanReason::CorruptData, now);
+        let now = now + BASE_BAN_DURATION;
+        list.ban_at(addr, BanReason::ProtocolViolation, now);
+        assert!(list.is_banned_at(addr, now + BASE_BAN_DURATION));
+        assert!(!list.is_banned_at(addr, now + BASE_BAN_DURATION * 2));
+
+        let ban = list.bans.lock().unwrap()[&addr].clone();
+        assert_eq!(ban.count, 2);
+        assert_eq!(ban.reason, BanReason::ProtocolViolation);
+    }
+
+    #[test]
+    fn offences_decay() {
+        let list = Blocklist::new();
+        let now = Instant::now();
+        let addr = ip("1.2.3.4");
+
+        list.ban_at(addr, BanReason::CorruptData, now);
+        let now = now + BASE_BAN_DURATION * 2;
+        list.ban_at(addr, BanReason::CorruptData, now);
+        assert_eq!(list.bans.lock().unwrap()[&addr].count, 1);
+    }
+
+    #[test]
+    fn unban_and_clear() {
+        let list = Blocklist::new();
+        list.ban(ip("1.2.3.4"), BanReason::CorruptData);
+        list.ban(ip("::1"), BanReason::CorruptData);
+        assert_eq!(list.banned().len(), 2);
+
+        list.unban(ip("::ffff:1.2.3.4"));
+        assert!(!list.is_banned(ip("1.2.3.4")));
+        assert!(list.get(ip("::1")).is_some());
+
+        list.clear();
+        assert!(list.banned().is_empty());
+    }
+
+    #[test]
+    fn ban_duration_is_capped() {
+        assert_eq!(Ban::duration(1), BASE_BAN_DURATION);
+        assert_eq!(Ban::duration(2), BASE_BAN_DURATION * 2);
+        assert_eq!(Ban::duration(100), MAX_BAN_DURATION);
+    }
+}
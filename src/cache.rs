@@ -0,0 +1,336 @@
+use crate::storage::PieceSink;
+use crate::work::Piece;
+use client::InfoHash;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Size of the read cache unless changed.
+pub const DEFAULT_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Blocks served from the cache.
+    pub hits: u64,
+
+    /// Blocks which needed the piece to be read from the disk.
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 / total as f64)
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    data: Arc<[u8]>,
+    last_used: u64,
+}
+
+/// The pieces of a torrent in the cache.
+#[derive(Debug, Default)]
+struct Torrent {
+    size: usize,
+
+    /// Index of the pieces by their last use, the least recent first
+    lru: BTreeMap<u64, u32>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    pieces: HashMap<(InfoHash, u32), Entry>,
+    torrents: HashMap<InfoHash, Torrent>,
+    stats: CacheStats,
+}
+
+impl Inner {
+    /// Evict pieces until `extra` more bytes fit.
+    ///
+    /// The torrent using the most of the cache loses its least recently
+    /// used piece first, so that one popular torrent can't push the pieces
+    /// of all the others out.
+    fn make_room(&mut self, extra: usize) {
+        while self.size + extra > self.capacity && !self.pieces.is_empty() {
+            // The least recent use breaks ties between torrents of the
+            // same size
+            let (&info_hash, torrent) = self
+                .torrents
+                .iter_mut()
+                .max_by_key(|(_, t)| (t.size, Reverse(t.lru.keys().next().copied())))
+                .unwrap();
+            let (_, index) = torrent.lru.pop_first().unwrap();
+            self.remove(&info_hash, index);
+        }
+    }
+
+    fn remove(&mut self, info_hash: &InfoHash, index: u32) {
+        let Some(e) = self.pieces.remove(&(*info_hash, index)) else {
+            return;
+        };
+        self.size -= e.data.len();
+        let torrent = self.torrents.get_mut(info_hash).unwrap();
+        torrent.size -= e.data.len();
+        torrent.lru.remove(&e.last_used);
+        if torrent.lru.is_empty() {
+            self.torrents.remove(info_hash);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+/// Cache of whole pieces read from the disk to be uploaded, shared between
+/// the torrents in a session.
+///
+/// Peers tend to request the same pieces, e.g. the rarest ones, so a piece
+/// read once serves the blocks requested by all of them. Cloning returns a
+/// handle to the same cache.
+#[derive(Debug, Clone)]
+pub struct ReadCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Default for ReadCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_SIZE)
+    }
+}
+
+impl ReadCache {
+    /// Cache holding up to `capacity` bytes of pieces.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                size: 0,
+                tick: 0,
+                pieces: HashMap::new(),
+                torrents: HashMap::new(),
+                stats: CacheStats::default(),
+            })),
+        }
+    }
+
+    /// Change the size of the cache. Pieces are evicted if it shrinks.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity;
+        inner.make_room(0);
+    }
+
+    /// Bytes of pieces in the cache.
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats
+    }
+
+    /// Piece `index` of the torrent, if it's cached. Counted as a hit or a
+    /// miss.
+    pub fn get(&self, info_hash: &InfoHash, index: u32) -> Option<Arc<[u8]>> {
+        let mut inner = self.inner.lock().unwrap();
+        let tick = inner.next_tick();
+        let Some(e) = inner.pieces.get_mut(&(*info_hash, index)) else {
+            inner.stats.misses += 1;
+            return None;
+        };
+        let last_used = std::mem::replace(&mut e.last_used, tick);
+        let data = e.data.clone();
+
+        let lru = &mut inner.torrents.get_mut(info_hash).unwrap().lru;
+        lru.remove(&last_used);
+        lru.insert(tick, index);
+        inner.stats.hits += 1;
+        Some(data)
+    }
+
+    /// Cache piece `index` of the torrent. Pieces larger than the whole
+    /// cache are not kept.
+    pub fn insert(&self, info_hash: InfoHash, index: u32, data: Arc<[u8]>) {
+        let mut inner = self.inner.lock().unwrap();
+        if data.len() > inner.capacity {
+            return;
+        }
+
+        inner.remove(&info_hash, index);
+        inner.make_room(data.len());
+
+        let last_used = inner.next_tick();
+        inner.size += data.len();
+        let torrent = inner.torrents.entry(info_hash).or_default();
+        torrent.size += data.len();
+        torrent.lru.insert(last_used, index);
+        inner
+            .pieces
+            .insert((info_hash, index), Entry { data, last_used });
+    }
+
+    /// Drop the cached piece, e.g. because it's written again.
+    pub fn remove(&self, info_hash: &InfoHash, index: u32) {
+        self.inner.lock().unwrap().remove(info_hash, index);
+    }
+}
+
+/// [`PieceSink`] serving the blocks from a [`ReadCache`], reading whole
+/// pieces from `inner` on a miss.
+pub struct CachedSink<P> {
+    inner: P,
+    cache: ReadCache,
+    info_hash: InfoHash,
+    piece_len: usize,
+    length: u64,
+}
+
+impl<P: PieceSink> CachedSink<P> {
+    /// `piece_len` and `length` are those of the torrent, needed to know
+    /// the size of the last piece.
+    pub fn new(
+        inner: P,
+        cache: ReadCache,
+        info_hash: InfoHash,
+        piece_len: usize,
+        length: u64,
+    ) -> Self {
+        Self {
+            inner,
+            cache,
+            info_hash,
+            piece_len,
+            length,
+        }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn piece_size(&self, index: u32) -> io::Result<usize> {
+        let start = self.piece_len as u64 * index as u64;
+        if start >= self.length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Piece index out of range",
+            ));
+        }
+        Ok((self.length - start).min(self.piece_len as u64) as usize)
+    }
+}
+
+impl<P: PieceSink + Send> PieceSink for CachedSink<P> {
+    fn write_piece(&mut self, piece: Piece) -> BoxFuture<'_, io::Result<()>> {
+        self.cache.remove(&self.info_hash, piece.index);
+        self.inner.write_piece(piece)
+    }
+
+    fn read_block<'a>(
+        &'a mut self,
+        index: u32,
+        begin: u32,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            let piece = match self.cache.get(&self.info_hash, index) {
+                Some(piece) => piece,
+                None => {
+                    let mut data = vec![0; self.piece_size(index)?];
+                    self.inner.read_block(index, 0, &mut data).await?;
+                    let data: Arc<[u8]> = data.into();
+                    self.cache.insert(self.info_hash, index, data.clone());
+                    data
+                }
+            };
+
+            let begin = begin as usize;
+            let block = piece.get(begin..begin + buf.len()).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Block out of the piece")
+            })?;
+            buf.copy_from_slice(block);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageWriter;
+
+    fn piece(len: usize) -> Arc<[u8]> {
+        vec![0; len].into()
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let cache = ReadCache::new(8);
+        cache.insert([0; 20], 0, piece(4));
+        cache.insert([0; 20], 1, piece(4));
+        assert!(cache.get(&[0; 20], 0).is_some());
+
+        cache.insert([0; 20], 2, piece(4));
+        assert_eq!(cache.size(), 8);
+        assert!(cache.get(&[0; 20], 1).is_none());
+        assert!(cache.get(&[0; 20], 0).is_some());
+
+        cache.insert([0; 20], 3, piece(16));
+        assert!(cache.get(&[0; 20], 3).is_none());
+        assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 2 });
+    }
+
+    #[test]
+    fn largest_torrent_is_evicted_first() {
+        let cache = ReadCache::new(12);
+        cache.insert([1; 20], 0, piece(4));
+        cache.insert([2; 20], 0, piece(4));
+        cache.insert([2; 20], 1, piece(4));
+        cache.insert([2; 20], 2, piece(4));
+        assert!(cache.get(&[1; 20], 0).is_some());
+        assert!(cache.get(&[2; 20], 0).is_none());
+
+        cache.set_capacity(4);
+        assert_eq!(cache.size(), 4);
+        assert!(cache.get(&[1; 20], 0).is_some());
+    }
+
+    #[tokio::test]
+    async fn cached_sink() {
+        let cache = ReadCache::new(16);
+        let inner = StorageWriter::new(b"abcdefghij".to_vec(), 4);
+        let mut sink = CachedSink::new(inner, cache.clone(), [0; 20], 4, 10);
+
+        let mut buf = [0; 2];
+        sink.read_block(2, 0, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"ij");
+        sink.read_block(1, 1, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"fg");
+        sink.read_block(1, 2, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"gh");
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 2 });
+        assert!(sink.read_block(1, 3, &mut buf).await.is_err());
+        assert!(sink.read_block(3, 0, &mut buf).await.is_err());
+
+        let buf = b"EFGH".to_vec().into_boxed_slice();
+        sink.write_piece(Piece::new(1, buf)).await.unwrap();
+        let mut buf = [0; 4];
+        sink.read_block(1, 0, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"EFGH");
+    }
+}
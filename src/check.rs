@@ -0,0 +1,264 @@
+use crate::event::{EventBus, TorrentEvent};
+use crate::storage::Storage;
+use crate::work::{PieceInfo, WorkQueue};
+use client::bitfield::Bitfield;
+use futures::stream::{FuturesOrdered, FuturesUnordered};
+use futures::{future, StreamExt, TryStreamExt};
+use rand::seq::SliceRandom;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Max number of pieces being hashed at the same time.
+const MAX_PENDING: usize = 16;
+
+/// Min time between two progress events.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Hash every piece in `storage` to find out which pieces we have.
+///
+/// Progress is reported with `CheckProgress` events, and every run of
+/// pieces which fail the check with a `CheckFailed` event. Pieces beyond the
+/// end of the storage are missing, not an error. The reads and the hashing
+/// run on the blocking threads of the runtime.
+pub async fn check_pieces<S>(
+    storage: S,
+    work: &WorkQueue,
+    events: &EventBus,
+) -> io::Result<Bitfield>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    let storage = Arc::new(storage);
+    let total = work.num_pieces() as u32;
+    let mut have = Bitfield::with_size(total as usize);
+    let mut progress = Progress::new(total, events);
+    let mut pending = FuturesOrdered::new();
+    let mut pieces = work.all_pieces();
+
+    loop {
+        while pending.len() < MAX_PENDING {
+            let info = match pieces.next() {
+                Some(info) => info,
+                None => break,
+            };
+            pending.push_back(check_piece(&storage, work, &info));
+        }
+
+        let (index, verified) = match pending.next().await {
+            Some(result) => result?,
+            None => break,
+        };
+
+        if verified {
+            have.set_bit(index as usize);
+        }
+        progress.checked(index, verified);
+    }
+
+    progress.finish();
+    Ok(have)
+}
+
+/// Hash the `recent` pieces of `have`, the ones written last, and up to
+/// `count` randomly picked others to catch pieces whose write never made it
+/// to the storage, e.g. after a crash. Returns the pieces which fail the
+/// check, in order.
+pub async fn spot_check<S>(
+    storage: S,
+    work: &WorkQueue,
+    have: &Bitfield,
+    recent: &[u32],
+    count: usize,
+) -> io::Result<Vec<u32>>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    let storage = Arc::new(storage);
+    let recent: Vec<_> = recent
+        .iter()
+        .filter(|&&i| have.get_bit(i as usize))
+        .filter_map(|&i| work.piece_info(i))
+        .collect();
+    let others: Vec<_> = (0..have.len() as u32)
+        .filter(|&i| have.get_bit(i as usize) && !recent.iter().any(|p| p.index == i))
+        .filter_map(|i| work.piece_info(i))
+        .collect();
+
+    let picked = others.choose_multiple(&mut rand::thread_rng(), count);
+    let pending: FuturesUnordered<_> = recent
+        .iter()
+        .chain(picked)
+        .map(|info| check_piece(&storage, work, info))
+        .collect();
+
+    let mut failed: Vec<_> = pending
+        .try_filter_map(|(index, verified)| future::ok((!verified).then_some(index)))
+        .try_collect()
+        .await?;
+    failed.sort_unstable();
+    Ok(failed)
+}
+
+/// Read the piece from `storage` and hash it on a blocking thread. Returns
+/// the index of the piece and whether it passed the check; pieces beyond the
+/// end of the storage don't.
+fn check_piece<S>(
+    storage: &Arc<S>,
+    work: &WorkQueue,
+    info: &PieceInfo,
+) -> impl Future<Output = io::Result<(u32, bool)>>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    let storage = storage.clone();
+    let hasher = work.hasher();
+    let (index, len) = (info.index, info.len as usize);
+    let offset = work.piece_offset(index);
+    let check = tokio::task::spawn_blocking(move || {
+        let mut buf = vec![0; len];
+        match storage.read_exact_at(&mut buf, offset) {
+            Ok(()) => Ok((index, hasher.verify(index, &buf))),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok((index, false)),
+            Err(e) => Err(e),
+        }
+    });
+    async move { check.await? }
+}
+
+struct Progress<'a> {
+    events: &'a EventBus,
+    checked: u32,
+    total: u32,
+    failed_from: Option<u32>,
+    last_event: Option<Instant>,
+}
+
+impl<'a> Progress<'a> {
+    fn new(total: u32, events: &'a EventBus) -> Self {
+        Self {
+            events,
+            checked: 0,
+            total,
+            failed_from: None,
+            last_event: None,
+        }
+    }
+
+    /// Pieces are expected in order.
+    fn checked(&mut self, index: u32, verified: bool) {
+        self.checked += 1;
+
+        if verified {
+            self.end_failed(index);
+        } else if self.failed_from.is_none() {
+            self.failed_from = Some(index);
+        }
+
+        let due = self
+            .last_event
+            .is_none_or(|t| t.elapsed() >= PROGRESS_INTERVAL);
+        if due {
+            self.emit_progress();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.end_failed(self.checked);
+        self.emit_progress();
+    }
+
+    fn end_failed(&mut self, end: u32) {
+        if let Some(start) = self.failed_from.take() {
+            self.events
+                .emit(TorrentEvent::CheckFailed { pieces: start..end });
+        }
+    }
+
+    fn emit_progress(&mut self) {
+        self.last_event = Some(Instant::now());
+        self.events.emit(TorrentEvent::CheckProgress {
+            checked: self.checked,
+            total: self.total,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::work::BLOCK_SIZE;
+
+    fn work_queue(data: &[u8], piece_len: usize) -> WorkQueue {
+        let hashes = data.chunks(piece_len).flat_map(crate::hash::sha1).collect();
+        WorkQueue::new(piece_len, data.len(), hashes)
+    }
+
+    #[tokio::test]
+    async fn check_storage() {
+        let piece_len = BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..5u8).flat_map(|i| vec![i; piece_len]).collect();
+        let work = work_queue(&data, piece_len);
+
+        // Piece 1 and 2 are corrupt and piece 4 is missing
+        let mut storage = data[..piece_len * 4].to_vec();
+        storage[piece_len + 1] = 9;
+        storage[piece_len * 2] = 9;
+
+        let events = EventBus::new();
+        let rx = events.subscribe();
+        let have = check_pieces(storage, &work, &events).await.unwrap();
+        assert!(have.get_bit(0) && have.get_bit(3));
+        assert_eq!(have.count(), 2);
+
+        drop(events);
+        let events: Vec<_> = rx.collect().await;
+        assert!(events.contains(&TorrentEvent::CheckFailed { pieces: 1..3 }));
+        assert!(events.contains(&TorrentEvent::CheckFailed { pieces: 4..5 }));
+        assert_eq!(
+            events.last(),
+            Some(&TorrentEvent::CheckProgress {
+                checked: 5,
+                total: 5
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn spot_check_storage() {
+        let piece_len = BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..6u8).flat_map(|i| vec![i; piece_len]).collect();
+        let work = work_queue(&data, piece_len);
+
+        // Piece 2 was torn and piece 5 never written
+        let mut storage = data[..piece_len * 5].to_vec();
+        storage[piece_len * 2 + 5] = 0;
+
+        let mut have = Bitfield::with_size(6);
+        for i in [0, 2, 3, 5] {
+            have.set_bit(i);
+        }
+
+        let failed = spot_check(storage.clone(), &work, &have, &[], 10)
+            .await
+            .unwrap();
+        assert_eq!(failed, [2, 5]);
+
+        let failed = spot_check(storage.clone(), &work, &have, &[], 0)
+            .await
+            .unwrap();
+        assert!(failed.is_empty());
+
+        // The pieces written last are always checked
+        let failed = spot_check(storage.clone(), &work, &have, &[5, 2], 0)
+            .await
+            .unwrap();
+        assert_eq!(failed, [2, 5]);
+
+        have.clear_bit(2);
+        have.clear_bit(5);
+        let failed = spot_check(storage, &work, &have, &[5, 2], 1).await.unwrap();
+        assert!(failed.is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+//! Sharing of the upload slots among the peers of a torrent.
+//!
+//! The peers which send us the most get the slots, tit-for-tat, except for
+//! one slot which goes round the other interested peers so that new peers
+//! get a chance to show what they can send. Once we're seeding, nobody
+//! sends us anything, so the slots go to the peers taking the most instead,
+//! which spread the pieces the fastest.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often the slots are handed out again.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of rounds a peer keeps the optimistic slot.
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+#[derive(Debug)]
+struct PeerState {
+    interested: bool,
+    unchoked: bool,
+
+    /// Block bytes the peer has sent us so far
+    downloaded: u64,
+
+    /// `downloaded` as of the last round
+    downloaded_before: u64,
+
+    /// Block bytes we have sent the peer so far
+    uploaded: u64,
+
+    /// `uploaded` as of the last round
+    uploaded_before: u64,
+
+    /// Block bytes the peer sent us in the last round
+    rate: u64,
+
+    /// Block bytes we sent the peer in the last round
+    up_rate: u64,
+
+    /// When the peer last got the optimistic slot
+    last_optimistic: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    slots: usize,
+    peers: HashMap<SocketAddr, PeerState>,
+    last_round: Instant,
+    rounds: u32,
+    optimistic: Option<SocketAddr>,
+    seeding: bool,
+}
+
+/// Decides which of the interested peers we upload to.
+#[derive(Debug)]
+pub struct Choker {
+    inner: Mutex<Inner>,
+}
+
+impl Choker {
+    /// Choker unchoking up to `slots` peers at a time.
+    pub fn new(slots: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                slots,
+                peers: HashMap::new(),
+                last_round: Instant::now(),
+                rounds: 0,
+                optimistic: None,
+                seeding: false,
+            }),
+        }
+    }
+
+    /// Record the state of the peer: whether it wants our pieces, and the
+    /// block bytes it has sent us and we have sent it so far. Returns true
+    /// if the peer should be unchoked.
+    ///
+    /// Free slots go to the interested peers right away. The slots are
+    /// handed out again once in `RECHOKE_INTERVAL`.
+    pub fn update(
+        &self,
+        peer: SocketAddr,
+        interested: bool,
+        downloaded: u64,
+        uploaded: u64,
+    ) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let state = inner.peers.entry(peer).or_insert(PeerState {
+            interested,
+            unchoked: false,
+            downloaded,
+            downloaded_before: downloaded,
+            uploaded,
+            uploaded_before: uploaded,
+            rate: 0,
+            up_rate: 0,
+            last_optimistic: None,
+        });
+        state.interested = interested;
+        state.downloaded = downloaded;
+        state.uploaded = uploaded;
+        if !interested {
+            state.unchoked = false;
+        }
+
+        if inner.last_round.elapsed() >= RECHOKE_INTERVAL {
+            inner.rechoke();
+        }
+
+        let unchoked = inner.peers.values().filter(|p| p.unchoked).count();
+        let free = unchoked < inner.slots;
+        let state = inner.peers.get_mut(&peer).unwrap();
+        if interested && !state.unchoked && free {
+            state.unchoked = true;
+        }
+        state.unchoked
+    }
+
+    /// Give up the slot of a disconnected peer.
+    pub fn remove(&self, peer: &SocketAddr) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.peers.remove(peer);
+        if inner.optimistic == Some(*peer) {
+            inner.optimistic = None;
+        }
+    }
+
+    /// Rank the peers by what we send them rather than by what they send
+    /// us from the next round on, since we have all the pieces.
+    pub fn set_seeding(&self, seeding: bool) {
+        self.inner.lock().unwrap().seeding = seeding;
+    }
+
+    /// When the slots are handed out next.
+    pub fn next_round(&self) -> Instant {
+        self.inner.lock().unwrap().last_round + RECHOKE_INTERVAL
+    }
+}
+
+impl Inner {
+    fn rechoke(&mut self) {
+        let now = Instant::now();
+        self.last_round = now;
+        self.rounds += 1;
+        for p in self.peers.values_mut() {
+            p.rate = p.downloaded - p.downloaded_before;
+            p.downloaded_before = p.downloaded;
+            p.up_rate = p.uploaded - p.uploaded_before;
+            p.uploaded_before = p.uploaded;
+        }
+
+        // Move the optimistic slot on every few rounds, to the interested
+        // peer which had it the longest ago
+        let keep = self
+            .optimistic
+            .and_then(|a| self.peers.get(&a))
+            .is_some_and(|p| p.interested);
+        if !keep || self.rounds.is_multiple_of(OPTIMISTIC_ROUNDS) {
+            self.optimistic = self
+                .peers
+                .iter()
+                .filter(|(_, p)| p.interested)
+                .min_by_key(|(_, p)| p.last_optimistic)
+                .map(|(&a, _)| a);
+            if let Some(p) = self.optimistic.and_then(|a| self.peers.get_mut(&a)) {
+                p.last_optimistic = Some(now);
+            }
+        }
+
+        // The rest go to the peers which sent us the most, the ones we sent
+        // the least breaking the ties, or which took the most once seeding
+        let seeding = self.seeding;
+        let mut ranked: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|&(a, p)| p.interested && Some(*a) != self.optimistic)
+            .map(|(&a, p)| {
+                let rank = if seeding {
+                    (p.up_rate, 0)
+                } else {
+                    (p.rate, u64::MAX - p.up_rate)
+                };
+                (a, rank)
+            })
+            .collect();
+        ranked.sort_by_key(|&(_, rank)| std::cmp::Reverse(rank));
+        let regular = self
+            .slots
+            .saturating_sub(self.optimistic.is_some() as usize);
+        ranked.truncate(regular);
+
+        for (a, p) in self.peers.iter_mut() {
+            p.unchoked = p.interested
+                && (Some(*a) == self.optimistic || ranked.iter().any(|&(r, _)| r == *a));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    fn round(choker: &Choker) {
+        let mut inner = choker.inner.lock().unwrap();
+        inner.last_round -= RECHOKE_INTERVAL;
+    }
+
+    #[test]
+    fn free_slots_right_away() {
+        let choker = Choker::new(2);
+        assert!(choker.update(peer(1), true, 0, 0));
+        assert!(!choker.update(peer(2), false, 0, 0));
+        assert!(choker.update(peer(3), true, 0, 0));
+        assert!(!choker.update(peer(4), true, 0, 0));
+
+        // Losing interest frees the slot
+        assert!(!choker.update(peer(1), false, 0, 0));
+        assert!(choker.update(peer(4), true, 0, 0));
+        choker.remove(&peer(3));
+        assert!(choker.update(peer(1), true, 0, 0));
+    }
+
+    #[test]
+    fn best_uploaders_keep_their_slots() {
+        let choker = Choker::new(3);
+        for n in 1..=5 {
+            choker.update(peer(n), true, 0, 0);
+        }
+        choker.update(peer(5), true, 5000, 0);
+        choker.update(peer(4), true, 4000, 0);
+
+        round(&choker);
+        let unchoked: Vec<_> = (1..=5)
+            .filter(|&n| {
+                let downloaded = if n > 3 { 1000 * n as u64 } else { 0 };
+                choker.update(peer(n), true, downloaded, 0)
+            })
+            .collect();
+        assert_eq!(unchoked.len(), 3, "{:?}", unchoked);
+        assert!(unchoked.contains(&4) && unchoked.contains(&5));
+    }
+
+    #[test]
+    fn seeds_rank_by_upload() {
+        let choker = Choker::new(3);
+        choker.set_seeding(true);
+        for n in 1..=5 {
+            choker.update(peer(n), true, 0, 0);
+        }
+        choker.update(peer(1), true, 0, 1000);
+        choker.update(peer(2), true, 0, 2000);
+
+        round(&choker);
+        let unchoked: Vec<_> = (1..=5)
+            .filter(|&n| {
+                let uploaded = if n < 3 { 1000 * n as u64 } else { 0 };
+                choker.update(peer(n), true, 0, uploaded)
+            })
+            .collect();
+        assert_eq!(unchoked.len(), 3, "{:?}", unchoked);
+        assert!(unchoked.contains(&1) && unchoked.contains(&2));
+    }
+
+    #[test]
+    fn optimistic_slot_goes_round() {
+        let choker = Choker::new(1);
+        for n in 1..=3 {
+            choker.update(peer(n), true, 0, 0);
+        }
+
+        let mut optimistic = vec![];
+        for _ in 0..3 * OPTIMISTIC_ROUNDS {
+            round(&choker);
+            choker.update(peer(1), true, 0, 0);
+            let inner = choker.inner.lock().unwrap();
+            optimistic.push(inner.optimistic.unwrap());
+        }
+        optimistic.dedup();
+        assert!(optimistic.len() >= 3, "{:?}", optimistic);
+        for n in 1..=3 {
+            assert!(optimistic.contains(&peer(n)));
+        }
+    }
+}
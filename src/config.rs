@@ -0,0 +1,231 @@
+//! Session settings loaded from a file.
+//!
+//! The file has one `key = value` setting per line. Values are numbers,
+//! which may have `_` between the digits, `true` or `false`, or strings in
+//! double quotes with `\"`, `\\`, `\n` and `\t` escapes:
+//!
+//! ```text
+//! # Comments start with a hash
+//! listen_port = 6881
+//! download_limit = 1_048_576
+//! download_dir = "/srv/torrents"
+//! report_corrupt = true
+//! ```
+//!
+//! Settings left out keep their current value, so a file may set only a
+//! few of them. Sizes and rates are in bytes, and 0 turns the
+//! `download_limit` and the `recv_buffer_limit` off.
+
+use anyhow::{bail, ensure, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Settings of a [`Session`](crate::Session). `None` leaves a setting as it
+/// is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionConfig {
+    pub listen_port: Option<u16>,
+
+    /// Total download rate of all the torrents in bytes per second, 0 for
+    /// no limit.
+    pub download_limit: Option<u64>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_connections_per_subnet: Option<usize>,
+    pub read_cache_size: Option<usize>,
+    pub max_recv_buffer: Option<usize>,
+
+    /// Memory of the receive buffers of all the connections, 0 for no
+    /// limit.
+    pub recv_buffer_limit: Option<usize>,
+
+    /// Where the torrents are stored. The session itself doesn't store
+    /// anything, this is for the embedder.
+    pub download_dir: Option<PathBuf>,
+
+    /// Look up peers in the DHT. Also up to the embedder, which creates
+    /// the DHT trackers.
+    pub dht: Option<bool>,
+
+    /// Ping the DHT nodes we haven't heard from in a while, keeping the NAT
+    /// mappings to them open. On by default.
+    pub dht_keepalive: Option<bool>,
+
+    /// Extra parameters of the HTTP announces, see
+    /// [`AnnounceParams`](crate::announce::AnnounceParams).
+    pub no_peer_id: Option<bool>,
+    pub report_corrupt: Option<bool>,
+}
+
+impl SessionConfig {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            config
+                .set(line)
+                .with_context(|| format!("Invalid setting on line {}", i + 1))?;
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+        Self::parse(&text)
+    }
+
+    fn set(&mut self, line: &str) -> anyhow::Result<()> {
+        let (key, value) = line.split_once('=').context("Expected `key = value`")?;
+        let value = Value::parse(value.trim())?;
+        match key.trim() {
+            "listen_port" => self.listen_port = Some(value.int()?),
+            "download_limit" => self.download_limit = Some(value.int()?),
+            "max_connections_per_ip" => self.max_connections_per_ip = Some(value.int()?),
+            "max_connections_per_subnet" => self.max_connections_per_subnet = Some(value.int()?),
+            "read_cache_size" => self.read_cache_size = Some(value.int()?),
+            "max_recv_buffer" => self.max_recv_buffer = Some(value.int()?),
+            "recv_buffer_limit" => self.recv_buffer_limit = Some(value.int()?),
+            "download_dir" => self.download_dir = Some(value.string()?.into()),
+            "dht" => self.dht = Some(value.bool()?),
+            "dht_keepalive" => self.dht_keepalive = Some(value.bool()?),
+            // Announcing them would have peers expect encrypted connections
+            // (MSE), which we can't make
+            "support_crypto" | "require_crypto" => {
+                ensure!(!value.bool()?, "Encrypted connections aren't supported")
+            }
+            "no_peer_id" => self.no_peer_id = Some(value.bool()?),
+            "report_corrupt" => self.report_corrupt = Some(value.bool()?),
+            key => bail!("Unknown setting: {}", key),
+        }
+        Ok(())
+    }
+}
+
+enum Value {
+    Int(u64),
+    Bool(bool),
+    String(String),
+}
+
+impl Value {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        if let Some(rest) = s.strip_prefix('"') {
+            let (string, rest) = parse_string(rest)?;
+            ensure_comment(rest)?;
+            return Ok(Value::String(string));
+        }
+
+        // Unquoted values end at a comment
+        let s = s.split('#').next().unwrap_or_default().trim();
+        match s {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => {
+                let digits = s.replace('_', "");
+                let n = digits
+                    .parse()
+                    .with_context(|| format!("Invalid value: {}", s))?;
+                Ok(Value::Int(n))
+            }
+        }
+    }
+
+    fn int<T: TryFrom<u64>>(self) -> anyhow::Result<T> {
+        match self {
+            Value::Int(n) => T::try_from(n).ok().context("Number out of range"),
+            _ => bail!("Expected a number"),
+        }
+    }
+
+    fn bool(self) -> anyhow::Result<bool> {
+        match self {
+            Value::Bool(b) => Ok(b),
+            _ => bail!("Expected true or false"),
+        }
+    }
+
+    fn string(self) -> anyhow::Result<String> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => bail!("Expected a quoted string"),
+        }
+    }
+}
+
+/// String up to the closing quote, and what follows it.
+fn parse_string(s: &str) -> anyhow::Result<(String, &str)> {
+    let mut string = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((string, &s[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '"')) => string.push('"'),
+                Some((_, '\\')) => string.push('\\'),
+                Some((_, 'n')) => string.push('\n'),
+                Some((_, 't')) => string.push('\t'),
+                _ => bail!("Invalid escape"),
+            },
+            c => string.push(c),
+        }
+    }
+    bail!("Unterminated string")
+}
+
+fn ensure_comment(rest: &str) -> anyhow::Result<()> {
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        bail!("Unexpected text after the value: {}", rest);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_settings() {
+        let text = r#"
+            # Comment
+            listen_port = 51413
+            download_limit = 1_000_000 # bytes per second
+            download_dir = "/tmp/a \"b\"" # comment
+            dht = false
+            dht_keepalive = false
+            require_crypto = false
+            report_corrupt = true
+        "#;
+        let config = SessionConfig::parse(text).unwrap();
+        assert_eq!(
+            config,
+            SessionConfig {
+                listen_port: Some(51413),
+                download_limit: Some(1_000_000),
+                download_dir: Some("/tmp/a \"b\"".into()),
+                dht: Some(false),
+                dht_keepalive: Some(false),
+                report_corrupt: Some(true),
+                ..SessionConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_settings() {
+        assert!(SessionConfig::parse("listen_port = 70000").is_err());
+        assert!(SessionConfig::parse("listen_port = \"1\"").is_err());
+        assert!(SessionConfig::parse("dht = 1").is_err());
+        assert!(SessionConfig::parse("download_dir = \"/tmp").is_err());
+        assert!(SessionConfig::parse("download_dir = \"/tmp\" x").is_err());
+        assert!(SessionConfig::parse("unknown = 1").is_err());
+        assert!(SessionConfig::parse("listen_port").is_err());
+        assert!(SessionConfig::parse("support_crypto = true").is_err());
+        assert!(SessionConfig::parse("require_crypto = true").is_err());
+    }
+}
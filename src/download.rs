@@ -1,51 +1,189 @@
+use crate::choker::Choker;
+use crate::event::{EventBus, TorrentEvent};
 use crate::future::timeout;
-use crate::work::{Piece, PieceInfo, WorkQueue};
-use anyhow::Context;
+use crate::peer::{DualStack, PeerAddr};
+use crate::ratelimit::TorrentBandwidth;
+use crate::reputation::Reputation;
+use crate::traffic::Traffic;
+use crate::upload::PieceSource;
+use crate::work::{PartialPiece, Piece, StripedBlock, WorkQueue, BLOCK_SIZE};
+use crate::worker::WorkerConfig;
+use anyhow::{bail, ensure};
 use client::avg::MovingAverage;
-use client::msg::{Packet, PieceBlock};
-use client::{AsyncStream, Client};
-use futures::channel::mpsc::Sender;
-use futures::SinkExt;
-use std::collections::HashMap;
-use std::mem::MaybeUninit;
-use std::time::Instant;
+use client::bitfield::Bitfield;
+use client::msg::{HashRequest, Packet, PieceBlock};
+use client::{AsyncStream, Client, WireStats};
+use futures::channel::mpsc::{Sender, UnboundedSender};
+use futures::{FutureExt, SinkExt};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 const MAX_REQUESTS: u32 = 500;
 const MIN_REQUESTS: u32 = 2;
-const MAX_BLOCK_SIZE: u32 = 0x4000;
+
+/// Requests in flight are kept at this multiple of the bandwidth-delay
+/// product. The backlog is only refilled once it's nearly drained, so the
+/// measured rate falls short of what the peer can do; the gain makes up for
+/// it and keeps the pipeline growing while the peer can send faster.
+const BDP_GAIN: f64 = 3.0;
+
+/// Round trip time assumed until the peer has sent a block.
+const DEFAULT_RTT: Duration = Duration::from_secs(1);
+
+/// Max seconds to wait for the peer's bitfield when we're a seed.
+const BITFIELD_TIMEOUT: u64 = 10;
+
+/// Max seconds a peer with nothing for us gets to tell that it wants our
+/// pieces.
+const INTEREST_TIMEOUT: u64 = 10;
+
+/// Longest block the peers may request, as much as other clients allow.
+const MAX_REQUEST_LEN: u32 = 128 * 1024;
+
+/// How often a peer waiting for an upload slot checks for a free one.
+const SLOT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Max time to serve the last requests and write the last messages when
+/// draining a connection.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the transfer rate of each peer is logged.
+const RATE_SUMMARY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time a peer gets to ramp up before it can be found slow.
+const SLOW_PEER_GRACE: Duration = Duration::from_secs(20);
+
+/// Max number of failed pieces waiting for the hashes of their blocks.
+const MAX_FAILED_PIECES: usize = 4;
+
+/// Number of requests to keep in flight to a peer sending `rate` bytes per
+/// second with round trip time `rtt`: the bandwidth-delay product in
+/// blocks, scaled by `BDP_GAIN` and clamped to `MIN_REQUESTS..=cap`.
+///
+/// `rtt` should be the minimum seen. The smoothed one includes the time
+/// our own requests wait in the peer's queue, so it would grow with the
+/// number of requests and the requests with it.
+fn bdp_requests(rate: f64, rtt: Duration, cap: u32) -> u32 {
+    let bdp = rate * rtt.as_secs_f64() / BLOCK_SIZE as f64;
+
+    // Float to int casts saturate
+    let n = (bdp * BDP_GAIN).ceil() as u32;
+    n.max(MIN_REQUESTS).min(cap)
+}
+
+/// Both sides have all the pieces, so there's nothing to exchange.
+#[derive(Debug)]
+pub struct BothSeeds;
+
+impl fmt::Display for BothSeeds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Peer is a seed too")
+    }
+}
+
+impl std::error::Error for BothSeeds {}
+
+/// The peer kept us waiting for longer than the worker allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerTimeout {
+    /// Choked us for longer than the unchoke timeout.
+    Unchoke,
+
+    /// Sent none of the blocks we requested within the request timeout.
+    Request,
+
+    /// Sent nothing at all, not even a keep-alive, within the idle
+    /// timeout.
+    Idle,
+
+    /// Unchoked us but sent no blocks within the freeloader timeout,
+    /// only keeping the connection alive.
+    Freeloader,
+}
+
+impl fmt::Display for PeerTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unchoke => "Peer kept us choked for too long",
+            Self::Request => "Peer didn't send the requested blocks in time",
+            Self::Idle => "Peer sent nothing for too long",
+            Self::Freeloader => "Peer sent no data for too long while unchoking us",
+        })
+    }
+}
+
+impl std::error::Error for PeerTimeout {}
+
+/// A piece of a version 2 torrent which failed the hash check, waiting for
+/// the hashes of its blocks to tell which peers sent the bad ones.
+struct FailedPiece {
+    req: HashRequest,
+    index: u32,
+    data: Box<[u8]>,
+    peers: Vec<Option<SocketAddr>>,
+}
 
 struct PieceInProgress {
-    piece: PieceInfo,
-    buf: Box<[MaybeUninit<u8>]>,
-    downloaded: u32,
+    piece: PartialPiece,
     requested: u32,
 }
 
 impl PieceInProgress {
-    fn write_block(&mut self, begin: u32, data: &[u8]) -> bool {
-        self.buf
-            .get_mut(begin as usize..)
-            .and_then(|b| b.get_mut(..data.len()))
-            .map(|b| unsafe {
-                std::ptr::copy_nonoverlapping(data.as_ptr(), b.as_mut_ptr().cast(), data.len());
-            })
-            .is_some()
+    /// Whether any of the requested blocks is yet to arrive.
+    fn has_pending(&self) -> bool {
+        self.pending().next().is_some()
+    }
+
+    /// Offset and length of the requested blocks which are yet to arrive.
+    fn pending(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let len = self.piece.info.len;
+        (0..self.requested)
+            .step_by(BLOCK_SIZE as usize)
+            .filter(|&begin| !self.piece.has_block(begin))
+            .map(move |begin| (begin, BLOCK_SIZE.min(len - begin)))
+    }
+
+    /// Cancel the requested blocks which are yet to arrive. Returns the
+    /// number of requests cancelled.
+    fn cancel_pending<C: AsyncStream>(&mut self, client: &mut Client<C>) -> u32 {
+        let mut cancelled = 0;
+        for (begin, len) in self.pending() {
+            client.send_cancel(self.piece.info.index, begin, len);
+            cancelled += 1;
+        }
+        self.requested = 0;
+        cancelled
     }
 }
 
-pub struct Download<'w, C> {
+pub struct Download<'w, C: AsyncStream> {
     /// Peer connection
     client: Client<C>,
 
+    /// Peer address
+    peer: SocketAddr,
+
     /// Common work queue from where we pick the pieces to download
     work: &'w WorkQueue,
 
+    /// Torrent events
+    events: &'w EventBus,
+
+    /// Download bandwidth share of the torrent
+    bandwidth: &'w TorrentBandwidth,
+
     /// Channel to send the completed and verified pieces
     piece_tx: Sender<Piece>,
 
     /// In-progress pieces
     in_progress: HashMap<u32, PieceInProgress>,
 
+    /// Striped pieces the peer helps with, and the offsets and lengths of
+    /// their blocks requested from it which are yet to arrive
+    striped: HashMap<u32, Vec<(u32, u32)>>,
+
     /// Current pending block requests
     backlog: u32,
 
@@ -58,134 +196,940 @@ pub struct Download<'w, C> {
     /// Last time we requested pieces from this peer
     last_requested: Instant,
 
-    /// Block download rate
+    /// Last time the peer sent a block or unchoked us
+    last_block: Instant,
+
+    /// Last time the peer sent any message
+    last_msg: Instant,
+
+    /// When the peer choked us, if it's choking us
+    choked_since: Option<Instant>,
+
+    unchoke_timeout: Duration,
+    request_timeout: Duration,
+    idle_timeout: Duration,
+    freeloader_timeout: Option<Duration>,
+
+    /// Download rate in bytes per second
     rate: MovingAverage<10>,
+
+    /// Bytes downloaded since the last rate summary
+    summary_bytes: usize,
+
+    /// Block bytes uploaded since the last rate summary
+    summary_up: u64,
+
+    /// Block bytes downloaded over the connection
+    downloaded: u64,
+
+    /// Block bytes sent to the peer
+    uploaded: u64,
+
+    /// Time of the last rate summary
+    last_summary: Instant,
+
+    /// Never request anything from the peer, e.g. since we have it all
+    upload_only: bool,
+
+    /// We have told the peer we're interested
+    interested: bool,
+
+    /// Pieces the peer had and pieces left in the queue when we last found
+    /// nothing to download from it
+    interest_checked: (usize, usize),
+
+    /// We have unchoked the peer
+    unchoked: bool,
+
+    /// The peer has wanted our pieces at some point
+    peer_was_interested: bool,
+
+    /// Where the pieces uploaded to the peer are read from, if we upload
+    source: Option<PieceSource>,
+
+    /// Shares the upload slots among the peers of the torrent
+    choker: Option<&'w Choker>,
+
+    /// Position in the log of verified pieces up to which the peer has
+    /// been told about them
+    announced: usize,
+
+    /// Bandwidth for the next block is reserved already
+    reserved: bool,
+
+    /// Drop the peer if both of us are seeds
+    disconnect_seeds: bool,
+
+    /// Pieces of the peer counted in the piece availability
+    counted: Bitfield,
+
+    /// Rate in bytes per second below which the peer is slow
+    slow_peer_rate: Option<u64>,
+
+    /// The peer has been downloading slower than `slow_peer_rate`
+    slow: bool,
+
+    /// Time the download started
+    started: Instant,
+
+    /// Where the bytes exchanged with the peer are counted, if anywhere
+    traffic: Option<&'w Traffic>,
+
+    /// Bytes exchanged with the peer already counted in `traffic`
+    counted_traffic: WireStats,
+
+    /// Where the DHT node of the peer is sent once it tells its port
+    dht_nodes: Option<UnboundedSender<SocketAddr>>,
+
+    /// Where the downloads and the corrupt pieces of the peers are recorded
+    reputation: Option<&'w Reputation>,
+
+    /// Where the other address of the peer is recorded, if it has one
+    dual_stack: Option<&'w DualStack>,
+
+    /// The peer was parted with already
+    drained: bool,
+
+    /// Failed pieces whose block hashes were requested from the peer
+    failed_pieces: VecDeque<FailedPiece>,
 }
 
-impl<C> Drop for Download<'_, C> {
+impl<C: AsyncStream> Drop for Download<'_, C> {
     fn drop(&mut self) {
-        // Put any unfinished pieces back in the work queue
-        self.work
-            .extend(self.in_progress.drain().map(|(_i, p)| p.piece));
+        if !self.drained {
+            self.queue_goodbye();
+            let _ = self.client.close().now_or_never();
+        }
+
+        // Put any unfinished pieces back in the work queue along with
+        // the blocks downloaded so far
+        for (_, p) in self.in_progress.drain() {
+            self.work.add_partial(p.piece);
+        }
+        for (index, _) in self.striped.drain() {
+            self.work.release_striped(index, self.peer);
+        }
+        self.work.remove_availability(&self.counted);
+        if let Some(choker) = self.choker {
+            choker.remove(&self.peer);
+        }
+        self.count_traffic();
+        if let Some(reputation) = self.reputation {
+            if self.downloaded > 0 {
+                reputation.add_transfer(self.peer.ip(), self.downloaded, self.started.elapsed());
+            }
+        }
+        if let Some(dual_stack) = self.dual_stack {
+            let alt = self.client.peer_alt_addr(self.peer);
+            if let (Some(peer), Some(alt)) = (PeerAddr::new(self.peer), alt.and_then(PeerAddr::new))
+            {
+                dual_stack.add(peer, alt);
+            }
+        }
     }
 }
 
 impl<'w, C: AsyncStream> Download<'w, C> {
     pub async fn new(
         mut client: Client<C>,
+        peer: SocketAddr,
         work: &'w WorkQueue,
+        events: &'w EventBus,
+        bandwidth: &'w TorrentBandwidth,
         piece_tx: Sender<Piece>,
+        config: &WorkerConfig,
     ) -> anyhow::Result<Download<'w, C>> {
-        client.send_unchoke();
-        client.send_interested();
-        client.flush().await?;
+        client.set_max_packet_len(config.max_packet_len);
+        client.fit_bitfield(work.num_pieces());
+        client.set_download_only(config.download_only);
+        // The choker decides whom we upload to
+        client.set_auto_choke(false);
+        client.set_flush_policy(config.flush_policy);
+        // For the peer to learn our client version and extensions
+        client.send_ext_handshake();
+
+        let (have, announced) = work.verified_pieces();
+        if have.count() > 0 {
+            client.send_have_bitfield(&have);
+        }
 
-        client.wait_for_unchoke().await?;
+        // Nothing to ask for once we have it all
+        let upload_only = config.upload_only || work.left() == 0;
+        if !upload_only {
+            client.send_interested();
+        }
+        client.flush().await?;
 
         Ok(Download {
             client,
+            peer,
             work,
+            events,
+            bandwidth,
             piece_tx,
             in_progress: HashMap::new(),
+            striped: HashMap::new(),
             backlog: 0,
             max_requests: 5,
             last_requested_blocks: 0,
             last_requested: Instant::now(),
+            last_block: Instant::now(),
+            last_msg: Instant::now(),
+            choked_since: (!upload_only).then(Instant::now),
+            unchoke_timeout: config.unchoke_timeout,
+            request_timeout: config.request_timeout,
+            idle_timeout: config.idle_timeout,
+            freeloader_timeout: config.freeloader_timeout,
             rate: MovingAverage::new(),
+            summary_bytes: 0,
+            summary_up: 0,
+            downloaded: 0,
+            uploaded: 0,
+            last_summary: Instant::now(),
+            upload_only,
+            interested: !upload_only,
+            interest_checked: (0, 0),
+            unchoked: false,
+            peer_was_interested: false,
+            source: None,
+            choker: None,
+            announced,
+            reserved: false,
+            disconnect_seeds: config.disconnect_seeds,
+            counted: Bitfield::new(),
+            slow_peer_rate: config.slow_peer_rate,
+            slow: false,
+            started: Instant::now(),
+            traffic: None,
+            counted_traffic: WireStats::default(),
+            dht_nodes: None,
+            reputation: None,
+            dual_stack: None,
+            drained: false,
+            failed_pieces: VecDeque::new(),
         })
     }
 
+    /// Count the bytes exchanged with the peer in `traffic`, the handshake
+    /// and the rest of the setup included.
+    pub fn set_traffic(&mut self, traffic: &'w Traffic) {
+        self.traffic = Some(traffic);
+    }
+
+    /// Send the address of the peer's DHT node to `nodes` once the peer
+    /// tells its DHT port, so that our DHT can ping it.
+    pub fn set_dht_nodes(&mut self, nodes: UnboundedSender<SocketAddr>) {
+        self.dht_nodes = Some(nodes);
+    }
+
+    /// Record the download from the peer, and the corrupt pieces of any
+    /// peer it finds, in `reputation`.
+    pub fn set_reputation(&mut self, reputation: &'w Reputation) {
+        self.reputation = Some(reputation);
+    }
+
+    /// Record the peer's address of the other IP family in `dual_stack`,
+    /// once the peer tells it.
+    pub fn set_dual_stack(&mut self, dual_stack: &'w DualStack) {
+        self.dual_stack = Some(dual_stack);
+    }
+
+    /// Upload the pieces we have to the peer, read from `source`, whenever
+    /// `choker` gives it a slot.
+    pub fn set_uploads(&mut self, source: PieceSource, choker: &'w Choker) {
+        self.source = Some(source);
+        self.choker = Some(choker);
+    }
+
+    fn report_dht_port(&mut self) {
+        if let Some(port) = self.client.take_peer_dht_port() {
+            if let Some(nodes) = &self.dht_nodes {
+                nodes
+                    .unbounded_send(SocketAddr::new(self.peer.ip(), port))
+                    .ok();
+            }
+        }
+    }
+
+    /// Pass the changes of the peer's choke and interest state on to the
+    /// torrent's subscribers.
+    fn emit_peer_events(&mut self) {
+        while let Some(event) = self.client.poll_peer_event() {
+            let addr = self.peer;
+            self.events.emit(TorrentEvent::Peer { addr, event });
+        }
+    }
+
+    fn count_traffic(&mut self) {
+        let stats = self.client.wire_stats();
+        let new = stats.since(&self.counted_traffic);
+        self.counted_traffic = stats;
+
+        self.work.add_uploaded(new.payload_up);
+        self.summary_up += new.payload_up;
+        self.uploaded += new.payload_up;
+        if let Some(traffic) = self.traffic {
+            traffic.add_wire(&new);
+        }
+    }
+
+    /// Read packets until the peer turns out to be a seed or the bitfield
+    /// timeout passes. Peers behind NATs may send the bitfield late, send
+    /// only part of it and the rest as HAVEs, or skip it for HAVEs only, so
+    /// the first packet can't be trusted to tell. A peer which wants our
+    /// pieces is no seed.
+    async fn wait_for_seed(&mut self) -> anyhow::Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(BITFIELD_TIMEOUT);
+        while !self.peer_is_seed() && !self.client.is_peer_interested() {
+            let read = tokio::time::timeout_at(deadline.into(), self.client.read_packet());
+            match read.await {
+                Ok(packet) => {
+                    packet?;
+                }
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Read packets until the peer wants our pieces or the interest timeout
+    /// since the start passes. Peers tell their interest after sending
+    /// their pieces, so one with nothing for us may not have yet.
+    async fn wait_for_interest(&mut self) -> anyhow::Result<()> {
+        let deadline = self.started + Duration::from_secs(INTEREST_TIMEOUT);
+        while !self.client.is_peer_interested() {
+            let read = tokio::time::timeout_at(deadline.into(), self.client.read_packet());
+            match read.await {
+                Ok(packet) => {
+                    packet?;
+                }
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
     pub async fn start(&mut self) -> anyhow::Result<()> {
-        trace!("download");
+        if self.disconnect_seeds && self.work.left() == 0 {
+            self.wait_for_seed().await?;
+            ensure!(!self.peer_is_seed(), BothSeeds);
+        }
 
         loop {
-            self.pick_pieces();
+            // We may have got the last piece since, from any of the peers
+            let complete = self.work.left() == 0;
+            ensure!(
+                !(self.disconnect_seeds && complete && self.peer_is_seed()),
+                BothSeeds
+            );
+            self.announce_pieces();
+            let peer_pieces = self.client.peer_pieces();
+            self.work.add_availability(&mut self.counted, peer_pieces);
+            self.cancel_verified_pieces();
+            if self.interested && !self.client.is_choked() {
+                self.pick_pieces();
+            }
+            // Filling the backlog leaves the striped pieces the other peers
+            // have covered, so check for work after it
+            self.fill_backlog().await?;
+            if !self.upload_only {
+                self.update_interest();
+            }
+
+            self.update_choke();
+            self.serve_requests().await?;
+            let flush = tokio::time::timeout(self.idle_timeout, self.client.flush());
+            flush.await.map_err(|_| PeerTimeout::Idle)??;
 
-            trace!("Pending pieces: {}", self.in_progress.len());
-            if self.in_progress.is_empty() && self.backlog == 0 {
-                // No new pieces to download and no pending requests
-                // We're done
+            if !self.interested && !self.is_uploading() {
+                if self.choker.is_some()
+                    && !self.peer_was_interested
+                    && !self.peer_is_seed()
+                    && self.started.elapsed() < Duration::from_secs(INTEREST_TIMEOUT)
+                {
+                    self.wait_for_interest().await?;
+                    continue;
+                }
+
+                // Nothing to exchange with the peer. We're done
                 break;
             }
 
-            self.fill_backlog().await?;
+            // Stay within the torrent's bandwidth share by reserving
+            // a block worth of bandwidth before reading the next one
+            if self.backlog > 0 && !self.reserved {
+                self.bandwidth.consume(BLOCK_SIZE as usize).await;
+                self.reserved = true;
+            }
+            self.handle_msg().await?;
+            self.emit_peer_events();
+            self.report_dht_port();
+            self.count_traffic();
+            self.log_rate();
+        }
+        Ok(())
+    }
+
+    /// Tell the peer about the pieces verified since it was told last,
+    /// except the ones it has already.
+    fn announce_pieces(&mut self) {
+        for index in self.work.verified_since(&mut self.announced) {
+            if !self.client.peer_pieces().get_bit(index as usize) {
+                self.client.send_have(index);
+            }
+        }
+    }
+
+    /// Tell the peer when we stop wanting its pieces, i.e. it has unchoked
+    /// us and we found nothing to download from it, and when we want them
+    /// again once it has new pieces or pieces are back in the queue.
+    fn update_interest(&mut self) {
+        let busy = !self.in_progress.is_empty() || !self.striped.is_empty() || self.backlog > 0;
+        if self.interested {
+            if !busy && !self.client.is_choked() {
+                self.client.send_not_interested();
+                self.interested = false;
+                self.choked_since = None;
+                self.interest_checked = (self.counted.count(), self.work.len());
+            }
+            return;
+        }
+
+        let now = (self.counted.count(), self.work.len());
+        if now == self.interest_checked {
+            return;
+        }
+        self.interest_checked = now;
+        let peer_pieces = self.client.peer_pieces();
+        if self.work.has_piece(|i| peer_pieces.get_bit(i as usize)) {
+            self.client.send_interested();
+            self.interested = true;
+            if self.client.is_choked() {
+                self.choked_since = Some(Instant::now());
+            }
+        }
+    }
+
+    /// The peer wants our pieces and we may upload them to it.
+    fn is_uploading(&self) -> bool {
+        self.choker.is_some() && self.client.is_peer_interested()
+    }
 
-            trace!("Current backlog: {}", self.backlog);
-            timeout(self.handle_msg(), 60).await?;
+    /// Choke or unchoke the peer as the choker says.
+    fn update_choke(&mut self) {
+        let Some(choker) = self.choker else {
+            return;
+        };
+        let interested = self.client.is_peer_interested();
+        self.peer_was_interested |= interested;
+        choker.set_seeding(self.work.left() == 0);
+        let unchoke = choker.update(self.peer, interested, self.downloaded, self.uploaded);
+        if unchoke != self.unchoked {
+            if unchoke {
+                self.client.send_unchoke();
+            } else {
+                self.client.send_choke();
+            }
+            self.unchoked = unchoke;
+        }
+    }
+
+    /// Send the blocks the peer has requested. Requests for pieces we don't
+    /// have or can't read are dropped, like the ones sent while choked.
+    async fn serve_requests(&mut self) -> anyhow::Result<()> {
+        let Some(source) = &mut self.source else {
+            return Ok(());
+        };
+        if self.client.has_buffered_packet() {
+            // Cancels may be among them
+            return Ok(());
+        }
+        // The rare pieces first, so that they spread before the peers
+        // having them leave. The counts as of now do for the whole batch.
+        let availability = self.work.availability_snapshot();
+        while let Some(req) = self.client.pop_rarest_request(&availability) {
+            if !self.unchoked {
+                continue;
+            }
+            let info = self.work.piece_info(req.index);
+            ensure!(
+                info.is_some_and(|p| req.len <= MAX_REQUEST_LEN && req.begin + req.len <= p.len),
+                "Invalid request: {:?}",
+                req
+            );
+            if !self.work.is_verified(req.index) {
+                debug!(index = req.index, "Request for a piece we don't have");
+                continue;
+            }
+            if let Some(piece) = source.read(self.work, req.index).await {
+                let block = &piece[req.begin as usize..][..req.len as usize];
+                self.client.send_piece(req.index, req.begin, block);
+            }
         }
         Ok(())
     }
 
+    /// Part with the peer politely before dropping the connection: serve
+    /// the requests it sent already, cancel the requests still in flight,
+    /// tell the peer we're not interested anymore, choke it if we had
+    /// unchoked it, and close the connection once the queued messages are
+    /// written. The peer may be gone already, so failures only end the
+    /// draining early.
+    ///
+    /// A download dropped without draining, e.g. when the torrent is paused
+    /// or removed, writes what it can of the same messages without waiting.
+    pub async fn drain(&mut self) {
+        let deadline = tokio::time::Instant::now() + DRAIN_TIMEOUT;
+        match tokio::time::timeout_at(deadline, self.serve_requests()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("Serving the last requests failed: {}", e),
+            Err(_) => debug!("Timed out serving the last requests"),
+        }
+        self.queue_goodbye();
+        match tokio::time::timeout_at(deadline, self.client.close()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("Draining the connection failed: {}", e),
+            Err(_) => debug!("Timed out draining the connection"),
+        }
+        self.count_traffic();
+        self.drained = true;
+    }
+
+    /// Queue the messages parting with the peer.
+    fn queue_goodbye(&mut self) {
+        for s in self.in_progress.values_mut() {
+            s.cancel_pending(&mut self.client);
+        }
+        for (&index, requested) in &mut self.striped {
+            for (begin, len) in requested.drain(..) {
+                self.client.send_cancel(index, begin, len);
+            }
+        }
+        self.backlog = 0;
+
+        if self.interested {
+            self.client.send_not_interested();
+            self.interested = false;
+        }
+        if self.unchoked {
+            self.client.send_choke();
+            self.unchoked = false;
+        }
+    }
+
+    /// Drop the pieces which another peer finished first, e.g. in the
+    /// endgame, and cancel their requests so that the peer doesn't spend
+    /// its bandwidth and request queue on blocks we don't need anymore.
+    fn cancel_verified_pieces(&mut self) {
+        let work = self.work;
+        let verified: Vec<u32> = self
+            .in_progress
+            .keys()
+            .copied()
+            .filter(|&i| work.is_verified(i))
+            .collect();
+
+        for index in verified {
+            if let Some(mut s) = self.in_progress.remove(&index) {
+                let cancelled = s.cancel_pending(&mut self.client);
+                if cancelled > 0 {
+                    debug!(index, cancelled, "Piece finished by another peer");
+                }
+                self.backlog -= cancelled;
+            }
+        }
+
+        // Blocks of the striped pieces requested from more than one peer
+        // arrive after the piece is verified
+        let client = &mut self.client;
+        let backlog = &mut self.backlog;
+        self.striped.retain(|&index, requested| {
+            if !work.is_verified(index) {
+                return true;
+            }
+            for &(begin, len) in requested.iter() {
+                client.send_cancel(index, begin, len);
+            }
+            *backlog -= requested.len() as u32;
+            false
+        });
+    }
+
+    /// Log the transfer rate of the peer once in `RATE_SUMMARY_INTERVAL`.
+    fn log_rate(&mut self) {
+        let elapsed = self.last_summary.elapsed();
+        if elapsed < RATE_SUMMARY_INTERVAL {
+            return;
+        }
+
+        let secs = elapsed.as_secs_f64();
+        let kbps = self.summary_bytes as f64 / secs / 1000.0;
+        let up_kbps = self.summary_up as f64 / secs / 1000.0;
+        info!(
+            down_kbps = kbps as u64,
+            up_kbps = up_kbps as u64,
+            backlog = self.backlog,
+            max_requests = self.max_requests,
+            rtt_ms = self.client.rtt().map_or(0, |d| d.as_millis() as u64),
+            pieces = self.in_progress.len() + self.striped.len(),
+            "Transfer rate"
+        );
+        self.summary_bytes = 0;
+        self.summary_up = 0;
+        self.last_summary = Instant::now();
+    }
+
+    /// When the peer runs out of time and which of the timeouts that is.
+    /// Being choked and waiting for the requested blocks are timed apart
+    /// from the peer going quiet altogether, or sending nothing but
+    /// keep-alives while it has unchoked us and we have requests in flight.
+    fn deadline(&self) -> (Instant, PeerTimeout) {
+        let mut idle = (self.last_msg + self.idle_timeout, PeerTimeout::Idle);
+        let starved_since = self.client.starved_since().filter(|_| self.backlog > 0);
+        if let (Some(timeout), Some(since)) = (self.freeloader_timeout, starved_since) {
+            // Nothing was owed while we had nothing to ask for
+            let since = since.max(self.last_requested);
+            if since + timeout < idle.0 {
+                idle = (since + timeout, PeerTimeout::Freeloader);
+            }
+        }
+        let waiting = match self.choked_since {
+            Some(since) => (since + self.unchoke_timeout, PeerTimeout::Unchoke),
+            None if self.backlog > 0 => {
+                let since = self.last_block.max(self.last_requested);
+                (since + self.request_timeout, PeerTimeout::Request)
+            }
+            None => return idle,
+        };
+        if waiting.0 < idle.0 {
+            waiting
+        } else {
+            idle
+        }
+    }
+
+    /// When a peer waiting for an upload slot, or holding one, is to check
+    /// with the choker again, if it's either.
+    fn choke_deadline(&self) -> Option<Instant> {
+        let choker = self.choker?;
+        if !self.client.is_peer_interested() {
+            return None;
+        }
+        let round = choker.next_round();
+        if self.unchoked {
+            Some(round)
+        } else {
+            Some(round.min(Instant::now() + SLOT_POLL_INTERVAL))
+        }
+    }
+
+    /// Read packets until a block arrives, or a message which changes what
+    /// we upload to the peer: a request or a change of its interest.
     async fn handle_msg(&mut self) -> anyhow::Result<()> {
         let PieceBlock { begin, index, data } = loop {
-            let packet = self.client.read_packet().await?;
+            // Serve the requests once the burst they came in is read, since
+            // the peer may have cancelled some of them right after
+            if self.source.is_some() && self.client.num_requests() > 0 {
+                self.client.read_available()?;
+                if !self.client.has_buffered_packet() {
+                    return Ok(());
+                }
+            }
+
+            let (deadline, reason) = self.deadline();
+            let wake = self.choke_deadline().filter(|&t| t < deadline);
+            let interested = self.client.is_peer_interested();
+            let verified = self.work.piece_verified(self.announced);
+            let read =
+                tokio::time::timeout_at(wake.unwrap_or(deadline).into(), self.client.read_packet());
+            let packet = tokio::select! {
+                read = read => match read {
+                    Ok(packet) => packet?,
+                    // Time to check with the choker
+                    Err(_) if wake.is_some() => return Ok(()),
+                    Err(_) => return Err(reason.into()),
+                },
+                // Another connection got a piece to tell the peer about
+                _ = verified => return Ok(()),
+            };
+
+            let now = Instant::now();
+            self.last_msg = now;
+            match packet {
+                Some(Packet::HashRequest(req)) => {
+                    match self.work.hashes(&req) {
+                        Some(hashes) => self.client.send_hashes(req, &hashes),
+                        None => self.client.send_hash_reject(req),
+                    }
+                    timeout(self.client.flush(), 5).await?;
+                    continue;
+                }
+                Some(Packet::Hashes { req, hashes }) => {
+                    let Some(i) = self.failed_pieces.iter().position(|f| f.req == req) else {
+                        continue;
+                    };
+                    let f = self.failed_pieces.remove(i).unwrap();
+                    match self
+                        .work
+                        .check_failed_blocks(f.index, hashes, &f.data, &f.peers)
+                    {
+                        Some(banned) => self.handle_bans(banned)?,
+                        None => debug!(index = f.index, "Invalid block hashes"),
+                    }
+                    continue;
+                }
+                Some(Packet::HashReject(req)) => {
+                    self.failed_pieces.retain(|f| f.req != req);
+                    continue;
+                }
+                _ => {}
+            }
             if let Some(Packet::Piece(p)) = packet {
+                self.last_block = now;
+                self.reserved = false;
                 break p;
             }
+            if interested != self.client.is_peer_interested() {
+                return Ok(());
+            }
+
+            if !self.interested {
+                continue;
+            }
+            match (self.client.is_choked(), self.choked_since) {
+                (true, None) => self.choked_since = Some(now),
+                (false, Some(_)) => {
+                    // Time to request the blocks
+                    self.choked_since = None;
+                    self.last_block = now;
+                    return Ok(());
+                }
+                _ => {}
+            }
         };
 
-        let mut p = self
-            .in_progress
-            .remove(&index)
-            .context("Received a piece that was not requested")?;
+        let requested = self
+            .striped
+            .get_mut(&index)
+            .and_then(|r| Some(r.swap_remove(r.iter().position(|&(b, _)| b == begin)?)));
+        if requested.is_some() {
+            self.backlog -= 1;
+            let len = data.len();
+            let piece = match self.work.write_striped(index, begin, data, self.peer) {
+                StripedBlock::Duplicate => return Ok(()),
+                StripedBlock::Written => None,
+                StripedBlock::Complete(piece) => Some(piece),
+            };
+            self.work.add_downloaded(len);
+            self.summary_bytes += len;
+            self.downloaded += len as u64;
+            return match piece {
+                Some(piece) => self.piece_done(piece).await,
+                None => Ok(()),
+            };
+        }
 
-        if p.write_block(begin, &data) {
-            p.downloaded += data.len() as u32;
+        let mut p = match self.in_progress.remove(&index) {
+            Some(p) => p,
+            None if self.work.is_verified(index) => {
+                // Sent before the peer got our cancel
+                trace!(index, begin, "Block of a cancelled piece");
+                return Ok(());
+            }
+            None => bail!("Received a piece that was not requested"),
+        };
+
+        if p.piece.write_block(begin, data) {
+            p.piece.set_peer(begin, self.peer);
             self.work.add_downloaded(data.len());
+            self.summary_bytes += data.len();
+            self.downloaded += data.len() as u64;
             self.backlog -= 1;
-            trace!("current index {}: {}/{}", index, p.downloaded, p.piece.len);
+            trace!(
+                "current index {}: {}/{}",
+                index,
+                p.piece.downloaded(),
+                p.piece.info.len
+            );
         }
 
-        if p.downloaded < p.piece.len {
-            // Not done yet
-            self.in_progress.insert(index, p);
+        if !p.piece.is_complete() {
+            if self.slow && !p.has_pending() && !self.in_progress.is_empty() {
+                // Keep only one piece for a slow peer and let a faster
+                // peer finish the others. We'll pick them up again only if
+                // nobody else does.
+                self.work.add_partial(p.piece);
+            } else {
+                // Not done yet
+                self.in_progress.insert(index, p);
+            }
             return Ok(());
         }
 
-        self.piece_done(p).await
+        self.piece_done(p.piece).await
     }
 
-    async fn piece_done(&mut self, state: PieceInProgress) -> anyhow::Result<()> {
-        trace!("Piece downloaded: {}", state.piece.index);
+    async fn piece_done(&mut self, piece: PartialPiece) -> anyhow::Result<()> {
+        trace!("Piece downloaded: {}", piece.info.index);
 
-        // Safety: Piece's buffer is now fully initialized
-        let buf: Box<[u8]> = unsafe { std::mem::transmute(state.buf) };
-        let verified = self.work.verify(&state.piece, &buf).await;
+        let (verified, buf) = self.work.verify(&piece).await;
 
         if !verified {
-            error!("Bad piece: Hash mismatch for {}", state.piece.index);
-            self.work.add_piece(state.piece);
+            error!("Bad piece: Hash mismatch for {}", piece.info.index);
+            let peers = piece.contributors();
+            self.events.emit(TorrentEvent::HashFailed {
+                index: piece.info.index,
+                peers,
+            });
+            self.request_block_hashes(&piece, buf).await?;
+            let banned = self.work.piece_failed(&piece);
+            if !self.work.is_verified(piece.info.index) {
+                self.work.add_piece(piece.info);
+            }
+            return self.handle_bans(banned);
+        }
+
+        if !self.work.mark_verified(piece.info.index) {
+            debug!(
+                index = piece.info.index,
+                "Piece finished by another peer first"
+            );
             return Ok(());
         }
 
-        info!("Downloaded and Verified {} piece", state.piece.index);
-        self.client.send_have(state.piece.index);
-        let piece = Piece {
-            index: state.piece.index,
-            buf,
-        };
+        let banned = self.work.piece_passed(&piece);
+        let info = piece.info;
+
+        debug!(index = info.index, "Piece verified");
+        let piece = self.work.finished_piece(info.index, buf);
         self.piece_tx.send(piece).await?;
+        self.handle_bans(banned)
+    }
+
+    /// Ask the peer for the hashes of the blocks of a failed piece, if the
+    /// torrent has them, to ban the peers which sent the bad blocks.
+    async fn request_block_hashes(
+        &mut self,
+        piece: &PartialPiece,
+        data: Box<[u8]>,
+    ) -> anyhow::Result<()> {
+        let index = piece.info.index;
+        let Some(req) = self.work.blocks_request(index) else {
+            return Ok(());
+        };
+        if self.failed_pieces.len() >= MAX_FAILED_PIECES {
+            self.failed_pieces.pop_front();
+        }
+        self.failed_pieces.push_back(FailedPiece {
+            req,
+            index,
+            data,
+            peers: piece.peers.clone(),
+        });
+        self.client.send_hash_request(req);
+        timeout(self.client.flush(), 5).await
+    }
+
+    fn peer_is_seed(&self) -> bool {
+        self.client.peer_is_seed(self.work.num_pieces())
+    }
+
+    fn handle_bans(&self, banned: Vec<SocketAddr>) -> anyhow::Result<()> {
+        // Only the peers found to have sent the bad blocks are to blame, not
+        // everyone who had a hand in the piece
+        for addr in banned {
+            if let Some(reputation) = self.reputation {
+                reputation.add_corruption(addr.ip());
+            }
+            self.events.emit(TorrentEvent::PeerBanned { addr });
+        }
+
+        // Other peers will notice the ban when they finish their pieces
+        ensure!(
+            !self.work.is_banned(&self.peer),
+            "Peer banned for sending corrupt data"
+        );
         Ok(())
     }
 
     fn pick_pieces(&mut self) {
+        if self.slow && self.work.is_empty() {
+            self.share_pieces();
+        }
+
         if self.backlog >= self.max_requests {
             // We need to wait for the backlog to come down to pick
             // new pieces
             return;
         }
 
-        if let Some(piece) = self.work.remove_piece() {
-            let buf = vec![MaybeUninit::uninit(); piece.len as usize].into_boxed_slice();
-            self.in_progress.insert(
-                piece.index,
-                PieceInProgress {
-                    piece,
-                    buf,
-                    downloaded: 0,
-                    requested: 0,
-                },
-            );
+        let busy = !self.in_progress.is_empty() || !self.striped.is_empty();
+        if busy && !self.work.has_room_for_piece() {
+            // Too much buffered already. Finish the pieces in progress
+            // first, but never leave a peer without work.
+            return;
+        }
+
+        if self.slow && busy {
+            // One piece at a time is plenty
+            return;
+        }
+
+        // Help the other peers with the pieces due first before starting
+        // new ones
+        if !self.slow {
+            let peer_pieces = self.client.peer_pieces();
+            let striped = &self.striped;
+            let wanted = |i: u32| peer_pieces.get_bit(i as usize) && !striped.contains_key(&i);
+            if let Some(index) = self.work.striped_piece(self.peer, wanted) {
+                debug!(index, "Helping with a striped piece");
+                self.striped.insert(index, vec![]);
+                return;
+            }
+        }
+
+        // Slow peers start from the back of the queue, leaving the partial
+        // pieces at the front to the others
+        let peer_pieces = self.client.peer_pieces();
+        let has = |i: u32| peer_pieces.get_bit(i as usize);
+        let next = if self.slow {
+            self.work.remove_last_piece(has)
+        } else {
+            self.work.remove_piece(has)
+        };
+
+        if let Some(info) = next {
+            let index = info.index;
+            let piece = self
+                .work
+                .take_partial(index)
+                .unwrap_or_else(|| self.work.new_partial(info));
+
+            if self.work.has_deadline(index) {
+                // Let the other peers download its blocks too
+                self.work.start_striped(piece);
+                self.striped.insert(index, vec![]);
+            } else {
+                self.in_progress.insert(
+                    index,
+                    PieceInProgress {
+                        piece,
+                        requested: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Stripe the pieces of a slow peer once the queue has run out, so that
+    /// the other peers can help and the slow peer doesn't hold up the end
+    /// of the download. The blocks requested already are still expected.
+    fn share_pieces(&mut self) {
+        for (index, p) in self.in_progress.drain() {
+            debug!(index, "Sharing the piece of a slow peer");
+            let requested = p.pending().collect();
+            self.work.start_striped(p.piece);
+            self.striped.insert(index, requested);
         }
     }
 
@@ -201,15 +1145,41 @@ impl<'w, C: AsyncStream> Download<'w, C> {
 
         let mut need_flush = false;
 
+        // The striped pieces are due first
+        let work = self.work;
+        let peer = self.peer;
+        for (&index, requested) in &mut self.striped {
+            while self.backlog < self.max_requests {
+                let claimed = |begin| requested.iter().any(|&(b, _)| b == begin);
+                let Some((begin, len)) = work.claim_block(index, peer, claimed) else {
+                    break;
+                };
+                self.client.send_request(index, begin, len);
+                requested.push((begin, len));
+                self.backlog += 1;
+                need_flush = true;
+            }
+        }
+
+        // Leave the striped pieces the other peers have covered
+        self.striped.retain(|&index, requested| {
+            !requested.is_empty() || (work.is_striped(index) && self.backlog >= self.max_requests)
+        });
+
         for s in self.in_progress.values_mut() {
-            while self.backlog < self.max_requests && s.requested < s.piece.len {
-                let block_size = MAX_BLOCK_SIZE.min(s.piece.len - s.requested);
-                self.client
-                    .send_request(s.piece.index, s.requested, block_size);
+            let info = &s.piece.info;
+            while self.backlog < self.max_requests && s.requested < info.len {
+                let block_size = BLOCK_SIZE.min(info.len - s.requested);
+
+                // Partially downloaded pieces may have the block already
+                if !s.piece.has_block(s.requested) {
+                    self.client
+                        .send_request(info.index, s.requested, block_size);
+                    self.backlog += 1;
+                    need_flush = true;
+                }
 
-                self.backlog += 1;
                 s.requested += block_size;
-                need_flush = true;
             }
         }
 
@@ -224,8 +1194,18 @@ impl<'w, C: AsyncStream> Download<'w, C> {
         }
     }
 
+    /// Upper bound of `max_requests`. Peers silently drop the requests
+    /// exceeding their `reqq`, so never go beyond it.
+    fn max_requests_cap(&self) -> u32 {
+        match self.client.peer_reqq() {
+            Some(reqq) => reqq.min(MAX_REQUESTS),
+            None => MAX_REQUESTS,
+        }
+    }
+
     fn adjust_watermark(&mut self) {
-        debug!("Old max_requests: {}", self.max_requests);
+        let cap = self.max_requests_cap();
+        self.max_requests = self.max_requests.min(cap);
 
         let millis = (Instant::now() - self.last_requested).as_millis();
         if millis == 0 {
@@ -234,16 +1214,291 @@ impl<'w, C: AsyncStream> Download<'w, C> {
         }
 
         let blocks_done = self.last_requested_blocks - self.backlog;
-        let blocks_per_sec = 1000 * blocks_done as u128 / millis;
+        let bytes_per_sec = 1000 * blocks_done as u128 * BLOCK_SIZE as u128 / millis;
 
-        // Update the average block download rate
-        self.rate.add_sample(blocks_per_sec as isize);
+        // Update the average download rate
+        self.rate.add_sample(bytes_per_sec as isize);
 
-        let rate = self.rate.mean() as u32;
-        if rate > MIN_REQUESTS {
-            self.max_requests = rate.min(MAX_REQUESTS);
+        let rate = self.rate.mean();
+        self.check_slow(rate);
+        if self.slow {
+            self.max_requests = 1;
+            return;
+        }
+
+        if rate <= 0 {
+            return;
+        }
+
+        let rtt = self.client.min_rtt().unwrap_or(DEFAULT_RTT);
+        self.max_requests = bdp_requests(rate as f64, rtt, cap);
+
+        trace!(
+            rate,
+            rtt_ms = rtt.as_millis() as u64,
+            max_requests = self.max_requests,
+            "Adjusted max requests"
+        );
+    }
+
+    fn check_slow(&mut self, rate: isize) {
+        let slow = match self.slow_peer_rate {
+            Some(min) => self.started.elapsed() >= SLOW_PEER_GRACE && (rate.max(0) as u64) < min,
+            None => false,
+        };
+
+        if slow != self.slow {
+            debug!(rate, slow, "Peer speed changed");
+            self.slow = slow;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Simulate a peer with the given bandwidth and latency, starting with
+    /// `max_requests` of 5 like a new `Download`. Returns the number of
+    /// round trips it takes to get 90% of the bandwidth-delay product in
+    /// flight.
+    fn rounds_to_fill(bandwidth: f64, rtt: Duration, adjust: impl Fn(f64, u32) -> u32) -> usize {
+        let block = BLOCK_SIZE as f64;
+        let bdp = (0.9 * bandwidth * rtt.as_secs_f64() / block) as u32;
+        let mut rate = MovingAverage::<10>::new();
+        let mut max_requests = 5;
+
+        for round in 1..=100 {
+            // The whole backlog is sent in one go and drains after a round
+            // trip plus the transfer time
+            let secs = rtt.as_secs_f64() + max_requests as f64 * block / bandwidth;
+            rate.add_sample((max_requests as f64 * block / secs) as isize);
+            max_requests = adjust(rate.mean() as f64, max_requests);
+            if max_requests >= bdp.min(MAX_REQUESTS) {
+                return round;
+            }
         }
+        usize::MAX
+    }
 
-        debug!("New max_requests: {}", self.max_requests);
+    fn bdp(rtt: Duration) -> impl Fn(f64, u32) -> u32 {
+        move |rate, _| bdp_requests(rate, rtt, MAX_REQUESTS)
+    }
+
+    /// One second worth of blocks at the current rate, like before the
+    /// bandwidth-delay product was used.
+    fn per_second(rate: f64, max_requests: u32) -> u32 {
+        let blocks = (rate / BLOCK_SIZE as f64) as u32;
+        if blocks > MIN_REQUESTS {
+            blocks.min(MAX_REQUESTS)
+        } else {
+            max_requests
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_peer_keeps_its_piece() {
+        use crate::hash::sha1;
+        use client::testing::Peer;
+        use futures::channel::mpsc;
+
+        let piece_len = 4 * BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..3 * piece_len).map(|i| (i / 1000) as u8).collect();
+        let hashes = data.chunks(piece_len).flat_map(sha1).collect();
+        let work = WorkQueue::new(piece_len, data.len(), hashes);
+        let events = EventBus::new();
+        let bandwidth = crate::ratelimit::RateLimiter::new().register(1);
+        let config = WorkerConfig {
+            slow_peer_rate: Some(u64::MAX),
+            disconnect_seeds: false,
+            ..WorkerConfig::default()
+        };
+        let (piece_tx, _piece_rx) = mpsc::channel(10);
+        let (a, b) = Peer::create_pair();
+
+        // Seed noting the piece of each request
+        let seed = async {
+            let mut c = Client::new(b);
+            c.send_have_bitfield(&Bitfield::with_value(3, true));
+            c.send_unchoke();
+            c.flush().await.unwrap();
+            let mut requested = vec![];
+            while c.read_packet().await.is_ok() {
+                while let Some(req) = c.pop_request() {
+                    requested.push(req.index);
+                    let start = req.index as usize * piece_len + req.begin as usize;
+                    c.send_piece(req.index, req.begin, &data[start..][..req.len as usize]);
+                }
+                if c.flush().await.is_err() {
+                    break;
+                }
+            }
+            requested
+        };
+
+        let download = async {
+            let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+            let client = Client::new(a);
+            let mut dl = Download::new(client, addr, &work, &events, &bandwidth, piece_tx, &config)
+                .await
+                .unwrap();
+            dl.started -= SLOW_PEER_GRACE;
+            dl.slow = true;
+            dl.max_requests = 1;
+            dl.start().await.unwrap();
+        };
+
+        let (mut requested, ()) = futures::join!(seed, download);
+        assert_eq!(work.left(), 0);
+
+        // Each piece downloaded in one go, not handed back block by block
+        assert_eq!(requested.len(), 12);
+        requested.dedup();
+        assert_eq!(requested.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn slow_peer_shares_the_last_piece() {
+        use client::testing::Peer;
+        use futures::channel::mpsc;
+
+        let piece_len = 4 * BLOCK_SIZE as usize;
+        let work = WorkQueue::new(piece_len, piece_len, vec![0; 20]);
+        let events = EventBus::new();
+        let bandwidth = crate::ratelimit::RateLimiter::new().register(1);
+        let config = WorkerConfig::default();
+        let (piece_tx, _piece_rx) = mpsc::channel(10);
+        let (a, b) = Peer::create_pair();
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let mut dl = Download::new(
+            Client::new(a),
+            addr,
+            &work,
+            &events,
+            &bandwidth,
+            piece_tx,
+            &config,
+        )
+        .await
+        .unwrap();
+        dl.slow = true;
+
+        let mut seed = Client::new(b);
+        seed.send_have_bitfield(&Bitfield::with_value(1, true));
+        seed.flush().await.unwrap();
+        while !dl.client.peer_is_seed(1) {
+            dl.client.read_packet().await.unwrap();
+        }
+
+        dl.pick_pieces();
+        let p = dl.in_progress.get_mut(&0).unwrap();
+        p.requested = BLOCK_SIZE;
+        dl.backlog = 1;
+
+        // Nothing left in the queue for the others
+        dl.pick_pieces();
+        assert!(dl.in_progress.is_empty());
+        assert_eq!(dl.striped[&0], [(0, BLOCK_SIZE)]);
+        assert!(work.is_striped(0));
+
+        // The block requested already isn't claimed again, but the other
+        // peers can download it too
+        let claimed = |begin| begin == 0;
+        assert_eq!(
+            work.claim_block(0, addr, claimed),
+            Some((BLOCK_SIZE, BLOCK_SIZE))
+        );
+        let other = SocketAddr::from(([10, 0, 0, 2], 6881));
+        assert_eq!(work.striped_piece(other, |_| true), Some(0));
+        assert_eq!(work.claim_block(0, other, |_| false), Some((0, BLOCK_SIZE)));
+    }
+
+    #[tokio::test]
+    async fn drain_serves_the_last_requests() {
+        use crate::cache::ReadCache;
+        use crate::hash::sha1;
+        use crate::worker::PieceReader;
+        use client::testing::Peer;
+        use futures::channel::mpsc;
+        use futures::future;
+        use std::sync::Arc;
+
+        let data = vec![7; BLOCK_SIZE as usize];
+        let work = WorkQueue::new(data.len(), data.len(), sha1(&data).to_vec());
+        work.mark_verified(0);
+        let choker = Choker::new(1);
+        let events = EventBus::new();
+        let bandwidth = crate::ratelimit::RateLimiter::new().register(1);
+        let config = WorkerConfig::default();
+        let (piece_tx, _piece_rx) = mpsc::channel(10);
+        let (a, b) = Peer::create_pair();
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let mut dl = Download::new(
+            Client::new(a),
+            addr,
+            &work,
+            &events,
+            &bandwidth,
+            piece_tx,
+            &config,
+        )
+        .await
+        .unwrap();
+        let reader: PieceReader = Arc::new(move |_| future::ok(data.clone()).boxed());
+        let source = PieceSource::new(reader, ReadCache::new(1 << 20), [0; 20]);
+        dl.set_uploads(source, &choker);
+        dl.client.send_unchoke();
+        dl.unchoked = true;
+
+        let mut leecher = Client::new(b);
+        leecher.send_request(0, 0, BLOCK_SIZE);
+        leecher.flush().await.unwrap();
+        dl.client.read_packet().await.unwrap();
+        dl.drain().await;
+
+        // The block goes out before the choke
+        let mut served = false;
+        while let Ok(packet) = leecher.read_packet().await {
+            if let Some(Packet::Piece(block)) = packet {
+                assert_eq!((block.index, block.begin), (0, 0));
+                served = true;
+                assert!(!leecher.is_choked());
+            }
+        }
+        assert!(served);
+        assert!(leecher.is_choked());
+    }
+
+    #[test]
+    fn bdp_clamped() {
+        let rtt = Duration::from_millis(100);
+        assert_eq!(MIN_REQUESTS, bdp_requests(0.0, rtt, MAX_REQUESTS));
+        assert_eq!(MIN_REQUESTS, bdp_requests(1000.0, rtt, MAX_REQUESTS));
+        assert_eq!(MAX_REQUESTS, bdp_requests(1e12, rtt, MAX_REQUESTS));
+        assert_eq!(250, bdp_requests(1e12, rtt, 250));
+
+        // 10 MB/s at 100 ms is 1 MB in flight, i.e. 61 blocks
+        assert_eq!(184, bdp_requests(10e6, rtt, MAX_REQUESTS));
+    }
+
+    #[test]
+    fn bdp_ramp_up() {
+        let bandwidth = 5e6;
+        for ms in [20, 100, 500, 1000] {
+            let rtt = Duration::from_millis(ms);
+            let rounds = rounds_to_fill(bandwidth, rtt, bdp(rtt));
+            assert!(rounds <= 20, "{} rounds at {} ms", rounds, ms);
+        }
+    }
+
+    #[test]
+    fn bdp_ramps_up_faster_on_high_latency() {
+        let bandwidth = 5e6;
+        for ms in [500, 1000, 2000] {
+            let rtt = Duration::from_millis(ms);
+            let bdp = rounds_to_fill(bandwidth, rtt, bdp(rtt));
+            let old = rounds_to_fill(bandwidth, rtt, per_second);
+            assert!(bdp < old, "{} vs {} rounds at {} ms", bdp, old, ms);
+        }
     }
 }
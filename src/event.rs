@@ -0,0 +1,89 @@
+use crate::storage::StorageErrorKind;
+use crate::work::Progress;
+use client::event::PeerEvent;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// Notable things happening in a torrent download which an embedder may
+/// want to know about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentEvent {
+    /// A piece failed the hash check. `peers` contains the peers which
+    /// contributed at least one block of the piece.
+    HashFailed { index: u32, peers: Vec<SocketAddr> },
+
+    /// A peer was banned for sending corrupt data.
+    PeerBanned { addr: SocketAddr },
+
+    /// A peer choked or unchoked us, or changed its interest in our pieces.
+    Peer { addr: SocketAddr, event: PeerEvent },
+
+    /// Progress of the download, sent every second while it runs.
+    Progress(Progress),
+
+    /// Progress of checking the pieces already in the storage.
+    CheckProgress { checked: u32, total: u32 },
+
+    /// The pieces in the range are missing from the storage or corrupt.
+    CheckFailed { pieces: Range<u32> },
+
+    /// Writing piece `index`, or flushing the storage if `None`, failed. The
+    /// torrent is paused until resumed through its `TorrentHandle`, and the
+    /// piece is downloaded again.
+    StorageError {
+        index: Option<u32>,
+        kind: StorageErrorKind,
+        message: String,
+    },
+
+    /// The handshake of a peer failed the check, so it's not connected to
+    /// again.
+    HandshakeFailed {
+        addr: SocketAddr,
+        check: HandshakeCheck,
+    },
+}
+
+/// Check of a peer's handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeCheck {
+    /// The protocol string, or the length of it.
+    Protocol,
+
+    /// The info hash, which must be the torrent's.
+    InfoHash,
+
+    /// The handshake must arrive in time.
+    Timeout,
+
+    /// The connection must stay open until the handshake is complete.
+    Complete,
+}
+
+/// Delivers the torrent events to all the subscribers.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<UnboundedSender<TorrentEvent>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self) -> UnboundedReceiver<TorrentEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn emit(&self, event: TorrentEvent) {
+        // Drop the subscribers which are gone
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
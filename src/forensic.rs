@@ -0,0 +1,187 @@
+use crate::work::PartialPiece;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+/// Number of failed pieces a peer can send alone before it's banned.
+const MAX_STRIKES: u32 = 3;
+
+/// Max number of failed attempts recorded per piece.
+const MAX_ATTEMPTS: usize = 8;
+
+/// Keeps track of which peer sent which block of the pieces that failed the
+/// hash check, so that the peers sending corrupt data can be identified and
+/// banned.
+///
+/// When a piece fails, the hash of every block is recorded along with the
+/// peer which sent it. Once a later attempt of the piece passes, the recorded
+/// blocks are cross-checked with the good ones and the peers which sent a
+/// different block are banned. A peer which alone sent every block of several
+/// failed pieces is banned as well.
+#[derive(Default)]
+pub struct Forensics {
+    failed: HashMap<u32, Vec<Vec<BlockRecord>>>,
+    strikes: HashMap<SocketAddr, u32>,
+    banned: HashSet<SocketAddr>,
+}
+
+#[derive(Debug)]
+struct BlockRecord {
+    block: usize,
+    peer: SocketAddr,
+    hash: [u8; 20],
+}
+
+impl Forensics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_banned(&self, peer: &SocketAddr) -> bool {
+        self.banned.contains(peer)
+    }
+
+    /// Record the blocks of a piece which failed the hash check.
+    ///
+    /// Returns the peers banned as a result.
+    pub fn piece_failed(&mut self, piece: &PartialPiece) -> Vec<SocketAddr> {
+        let records: Vec<_> = blocks(piece)
+            .map(|(block, peer, data)| BlockRecord {
+                block,
+                peer,
+                hash: hash(data),
+            })
+            .collect();
+
+        let mut banned = vec![];
+
+        let peers: HashSet<_> = records.iter().map(|r| r.peer).collect();
+        if peers.len() == 1 && records.len() == piece.blocks.len() {
+            // The whole piece came from a single peer, so it's the culprit
+            let peer = records[0].peer;
+            let strikes = self.strikes.entry(peer).or_default();
+            *strikes += 1;
+            if *strikes >= MAX_STRIKES {
+                self.ban(peer, &mut banned);
+            }
+        }
+
+        let attempts = self.failed.entry(piece.info.index).or_default();
+        if attempts.len() < MAX_ATTEMPTS {
+            attempts.push(records);
+        }
+
+        banned
+    }
+
+    /// Cross-check the blocks of a piece which passed the hash check with
+    /// the earlier failed attempts, if any.
+    ///
+    /// Returns the peers banned as a result.
+    pub fn piece_passed(&mut self, piece: &PartialPiece) -> Vec<SocketAddr> {
+        let mut banned = vec![];
+
+        let attempts = match self.failed.remove(&piece.info.index) {
+            Some(attempts) => attempts,
+            None => return banned,
+        };
+
+        for record in attempts.iter().flatten() {
+            let data = piece.block(record.block).unwrap_or_default();
+            if hash(data) != record.hash {
+                self.ban(record.peer, &mut banned);
+            }
+        }
+
+        banned
+    }
+
+    /// Ban the peers which sent blocks known to be bad, e.g. from their
+    /// merkle hashes.
+    ///
+    /// Returns the peers banned as a result.
+    pub fn blocks_failed(
+        &mut self,
+        peers: impl IntoIterator<Item = SocketAddr>,
+    ) -> Vec<SocketAddr> {
+        let mut banned = vec![];
+        for peer in peers {
+            self.ban(peer, &mut banned);
+        }
+        banned
+    }
+
+    fn ban(&mut self, peer: SocketAddr, banned: &mut Vec<SocketAddr>) {
+        if self.banned.insert(peer) {
+            warn!("Banning peer {} for sending corrupt data", peer);
+            self.strikes.remove(&peer);
+            banned.push(peer);
+        }
+    }
+}
+
+/// Blocks of the piece with known senders.
+fn blocks(piece: &PartialPiece) -> impl Iterator<Item = (usize, SocketAddr, &[u8])> {
+    piece.peers.iter().enumerate().filter_map(move |(i, peer)| {
+        let peer = (*peer)?;
+        let data = piece.block(i)?;
+        Some((i, peer, data))
+    })
+}
+
+fn hash(data: &[u8]) -> [u8; 20] {
+    crate::hash::sha1(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::work::{PieceInfo, BLOCK_SIZE};
+
+    fn addr(n: u8) -> SocketAddr {
+        ([127, 0, 0, n], 6881).into()
+    }
+
+    fn piece(blocks: &[(u8, u8)]) -> PartialPiece {
+        let mut p = PartialPiece::new(PieceInfo {
+            index: 0,
+            len: BLOCK_SIZE * blocks.len() as u32,
+        });
+        for (i, &(peer, value)) in blocks.iter().enumerate() {
+            let begin = i as u32 * BLOCK_SIZE;
+            assert!(p.write_block(begin, &[value; BLOCK_SIZE as usize]));
+            p.set_peer(begin, addr(peer));
+        }
+        p
+    }
+
+    #[test]
+    fn ban_peer_sending_bad_block() {
+        let mut f = Forensics::new();
+
+        // Peer 2 sent a bad block
+        assert!(f.piece_failed(&piece(&[(1, 1), (2, 0)])).is_empty());
+
+        // Peer 3 sent the good one
+        let banned = f.piece_passed(&piece(&[(1, 1), (3, 2)]));
+        assert_eq!(banned, vec![addr(2)]);
+        assert!(f.is_banned(&addr(2)));
+        assert!(!f.is_banned(&addr(1)));
+        assert!(!f.is_banned(&addr(3)));
+    }
+
+    #[test]
+    fn ban_single_peer_after_repeated_failures() {
+        let mut f = Forensics::new();
+        for _ in 0..MAX_STRIKES - 1 {
+            assert!(f.piece_failed(&piece(&[(1, 0), (1, 0)])).is_empty());
+        }
+        assert_eq!(f.piece_failed(&piece(&[(1, 0), (1, 0)])), vec![addr(1)]);
+        assert!(f.is_banned(&addr(1)));
+    }
+
+    #[test]
+    fn passed_piece_without_failures() {
+        let mut f = Forensics::new();
+        assert!(f.piece_passed(&piece(&[(1, 1)])).is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+//! Hashes used to verify the pieces.
+//!
+//! Torrents of version 1 have a SHA-1 hash of each piece, while those of
+//! version 2 (BEP 52) have the root of a SHA-256 merkle tree over the 16 KiB
+//! blocks of each piece, see [`client::merkle`]. [`PieceHasher`] hides
+//! which one is in use from the [`WorkQueue`](crate::work::WorkQueue).
+//!
+//! With the `sha1-hw` feature (on by default), SHA-1 uses the SHA
+//! instructions of the CPU when it has them, detected at runtime. The
+//! `sha1-asm` feature adds the assembly implementation for CPUs without
+//! them. Without either feature a portable implementation is used, which
+//! suits constrained targets where the extra dependencies don't build.
+
+use client::merkle::{self, Hash, PieceRoot};
+use client::msg::HashRequest;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Name of the SHA-1 implementation compiled in.
+#[cfg(feature = "sha1-asm")]
+pub const BACKEND: &str = "sha1-asm";
+
+#[cfg(all(feature = "sha1-hw", not(feature = "sha1-asm")))]
+pub const BACKEND: &str = "sha1-hw";
+
+#[cfg(not(feature = "sha1-hw"))]
+pub const BACKEND: &str = "portable";
+
+#[cfg(feature = "sha1-hw")]
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    use sha1_hw::{Digest, Sha1};
+    Sha1::digest(data).into()
+}
+
+#[cfg(not(feature = "sha1-hw"))]
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    sha1::Sha1::from(data).digest().bytes()
+}
+
+/// Checks the data of a piece against the hashes in the metainfo.
+pub trait PieceHasher: Send + Sync {
+    /// Whether `data` is piece `index`. False for unknown pieces.
+    fn verify(&self, index: u32, data: &[u8]) -> bool;
+
+    /// The `hashes` reply to a hash request of a peer (BEP 52), or `None`
+    /// if the hashes aren't known.
+    fn hashes(&self, _req: &HashRequest) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Hash request for the hashes of the blocks of piece `index`, to find
+    /// the bad blocks once it fails the check. `None` if the blocks can't
+    /// be checked on their own.
+    fn blocks_request(&self, _index: u32) -> Option<HashRequest> {
+        None
+    }
+
+    /// Which blocks of `data`, piece `index`, match the `hashes` replied to
+    /// its [`blocks_request`](Self::blocks_request). `None` if they aren't
+    /// the hashes of the piece.
+    fn check_blocks(&self, _index: u32, _hashes: &[u8], _data: &[u8]) -> Option<Vec<bool>> {
+        None
+    }
+}
+
+/// SHA-1 hashes of the pieces of a version 1 torrent, concatenated as in
+/// the `pieces` of the info dictionary.
+pub struct Sha1Pieces {
+    hashes: Vec<u8>,
+}
+
+impl Sha1Pieces {
+    pub fn new(hashes: Vec<u8>) -> Self {
+        Self { hashes }
+    }
+}
+
+impl PieceHasher for Sha1Pieces {
+    fn verify(&self, index: u32, data: &[u8]) -> bool {
+        let start = index as usize * 20;
+        match self.hashes.get(start..start + 20) {
+            Some(expected) => sha1(data) == expected,
+            None => false,
+        }
+    }
+}
+
+/// Merkle roots of the pieces of a version 2 torrent.
+pub struct MerklePieces {
+    pieces: Vec<PieceRoot>,
+
+    /// Pieces of the files with a piece layer, by their pieces root
+    files: HashMap<Hash, Range<usize>>,
+}
+
+impl MerklePieces {
+    pub fn new(pieces: Vec<PieceRoot>) -> Self {
+        let mut files = HashMap::new();
+        let mut start = 0;
+        for (i, piece) in pieces.iter().enumerate() {
+            if piece.file_index == 0 {
+                start = i;
+            }
+            let last = pieces.get(i + 1).is_none_or(|p| p.file_index == 0);
+            if last && i > start {
+                files.entry(piece.pieces_root).or_insert(start..i + 1);
+            }
+        }
+        Self { pieces, files }
+    }
+}
+
+impl PieceHasher for MerklePieces {
+    fn verify(&self, index: u32, data: &[u8]) -> bool {
+        match self.pieces.get(index as usize) {
+            Some(piece) => piece.verify(data),
+            None => false,
+        }
+    }
+
+    /// Only the piece layers are kept, so the hashes of the other layers
+    /// aren't known.
+    fn hashes(&self, req: &HashRequest) -> Option<Vec<u8>> {
+        let pieces = &self.pieces[self.files.get(&req.pieces_root)?.clone()];
+        let first = &pieces[0];
+        let height = first.width.trailing_zeros();
+        if req.base_layer != height {
+            return None;
+        }
+        let layer: Vec<Hash> = pieces.iter().map(|p| p.root).collect();
+        let width = first.file_width / first.width;
+        merkle::layer_hashes(req, &layer, width, merkle::pad_hash(height))
+    }
+
+    fn blocks_request(&self, index: u32) -> Option<HashRequest> {
+        self.pieces.get(index as usize)?.blocks_request()
+    }
+
+    fn check_blocks(&self, index: u32, hashes: &[u8], data: &[u8]) -> Option<Vec<bool>> {
+        self.pieces.get(index as usize)?.check_blocks(hashes, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::HEXLOWER;
+
+    #[test]
+    fn known_digests() {
+        let hex = |data: &[u8]| HEXLOWER.encode(&sha1(data));
+        assert_eq!(hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            hex(&[b'a'; 1000]),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn v1_pieces() {
+        let hashes = [sha1(b"one"), sha1(b"two")].concat();
+        let hasher = Sha1Pieces::new(hashes);
+        assert!(hasher.verify(0, b"one"));
+        assert!(hasher.verify(1, b"two"));
+        assert!(!hasher.verify(1, b"one"));
+        assert!(!hasher.verify(2, b"one"));
+    }
+}
@@ -0,0 +1,249 @@
+use crate::peer::{canonical_ip, PeerAddr};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+
+/// Max number of connections to one IP across all the torrents unless
+/// changed.
+pub const DEFAULT_MAX_PER_IP: usize = 4;
+
+/// Max number of connections to one subnet across all the torrents unless
+/// changed.
+pub const DEFAULT_MAX_PER_SUBNET: usize = 8;
+
+/// The /24 of an IPv4 address or the /48 of an IPv6 one, the usual size of
+/// a network behind a single NAT or site.
+pub fn subnet(ip: IpAddr) -> IpAddr {
+    match canonical_ip(ip) {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    ips: HashMap<IpAddr, usize>,
+    subnets: HashMap<IpAddr, usize>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    max_per_ip: AtomicUsize,
+    max_per_subnet: AtomicUsize,
+    counts: Mutex<Counts>,
+}
+
+/// Number of connections to each peer IP and subnet, shared between all the
+/// torrents in a session.
+///
+/// A peer in several torrents needs a connection per torrent since a
+/// connection is tied to an info hash. Capping the connections per IP for
+/// the whole session keeps the torrents from opening a burst of
+/// connections to the same host at once. The cap per subnet does the same
+/// for a host or NAT presenting many ports. Cloning returns a handle to
+/// the same counts.
+#[derive(Debug, Clone)]
+pub struct IpConnections {
+    inner: Arc<Inner>,
+}
+
+impl Default for IpConnections {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PER_IP)
+    }
+}
+
+impl IpConnections {
+    pub fn new(max_per_ip: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                max_per_ip: AtomicUsize::new(max_per_ip),
+                max_per_subnet: AtomicUsize::new(DEFAULT_MAX_PER_SUBNET),
+                counts: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Change the limit. Connections over the new limit are kept, but no
+    /// more are allowed until they close.
+    pub fn set_max_per_ip(&self, max_per_ip: usize) {
+        self.inner.max_per_ip.store(max_per_ip, Relaxed);
+    }
+
+    pub fn max_per_ip(&self) -> usize {
+        self.inner.max_per_ip.load(Relaxed)
+    }
+
+    /// Change the limit per /24 for IPv4 and per /48 for IPv6. Like with
+    /// `set_max_per_ip`, connections over the new limit are kept.
+    pub fn set_max_per_subnet(&self, max_per_subnet: usize) {
+        self.inner.max_per_subnet.store(max_per_subnet, Relaxed);
+    }
+
+    pub fn max_per_subnet(&self) -> usize {
+        self.inner.max_per_subnet.load(Relaxed)
+    }
+
+    /// Number of open connections to the IP.
+    pub fn count(&self, ip: IpAddr) -> usize {
+        let counts = self.inner.counts.lock().unwrap();
+        counts.ips.get(&canonical_ip(ip)).copied().unwrap_or(0)
+    }
+
+    /// Number of open connections to the subnet of the IP.
+    pub fn subnet_count(&self, ip: IpAddr) -> usize {
+        let counts = self.inner.counts.lock().unwrap();
+        counts.subnets.get(&subnet(ip)).copied().unwrap_or(0)
+    }
+
+    /// Take a connection to the IP out of the budget, unless the budget of
+    /// the IP or of its subnet is used up. The connection is given back
+    /// when the permit is dropped.
+    pub fn acquire(&self, ip: IpAddr) -> Option<IpPermit> {
+        let ip = canonical_ip(ip);
+        let net = subnet(ip);
+        let mut counts = self.inner.counts.lock().unwrap();
+        let ip_count = counts.ips.get(&ip).copied().unwrap_or(0);
+        let net_count = counts.subnets.get(&net).copied().unwrap_or(0);
+        if ip_count >= self.max_per_ip() || net_count >= self.max_per_subnet() {
+            return None;
+        }
+
+        counts.ips.insert(ip, ip_count + 1);
+        counts.subnets.insert(net, net_count + 1);
+        Some(IpPermit {
+            inner: self.inner.clone(),
+            ip,
+        })
+    }
+
+    /// Order the peers to connect to so that the subnets with the fewest
+    /// connections come first, taking turns between the subnets, so that
+    /// the connection slots are spread over as many networks as possible.
+    /// Peers of the same subnet keep their order.
+    pub fn sort_by_diversity(&self, peers: &mut [PeerAddr]) {
+        let counts = self.inner.counts.lock().unwrap();
+        let mut queued: HashMap<IpAddr, usize> = HashMap::new();
+        let mut ranks = HashMap::with_capacity(peers.len());
+        for peer in peers.iter() {
+            let net = subnet(peer.ip());
+            let n = queued.entry(net).or_insert(0);
+            let open = counts.subnets.get(&net).copied().unwrap_or(0);
+            ranks.insert(*peer, open + *n);
+            *n += 1;
+        }
+        peers.sort_by_key(|p| ranks[p]);
+    }
+}
+
+/// A connection to an IP counted in `IpConnections`.
+#[derive(Debug)]
+pub struct IpPermit {
+    inner: Arc<Inner>,
+    ip: IpAddr,
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut counts = self.inner.counts.lock().unwrap();
+        release(&mut counts.ips, self.ip);
+        release(&mut counts.subnets, subnet(self.ip));
+    }
+}
+
+fn release(counts: &mut HashMap<IpAddr, usize>, key: IpAddr) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn budget_per_ip() {
+        let conns = IpConnections::new(2);
+        let a = conns.acquire(ip("1.2.3.4")).unwrap();
+        let _b = conns.acquire(ip("::ffff:1.2.3.4")).unwrap();
+        assert!(conns.acquire(ip("1.2.3.4")).is_none());
+        assert!(conns.acquire(ip("1.2.3.5")).is_some());
+        assert_eq!(conns.count(ip("1.2.3.4")), 2);
+
+        drop(a);
+        assert_eq!(conns.count(ip("1.2.3.4")), 1);
+        assert!(conns.clone().acquire(ip("1.2.3.4")).is_some());
+    }
+
+    #[test]
+    fn lower_limit() {
+        let conns = IpConnections::new(2);
+        let _a = conns.acquire(ip("1.2.3.4")).unwrap();
+        let b = conns.acquire(ip("1.2.3.4")).unwrap();
+        conns.set_max_per_ip(1);
+        drop(b);
+        assert!(conns.acquire(ip("1.2.3.4")).is_none());
+    }
+
+    #[test]
+    fn subnets() {
+        assert_eq!(subnet(ip("1.2.3.4")), ip("1.2.3.0"));
+        assert_eq!(subnet(ip("::ffff:1.2.3.4")), ip("1.2.3.0"));
+        assert_eq!(subnet(ip("2001:db8:1:2:3::1")), ip("2001:db8:1::"));
+    }
+
+    #[test]
+    fn budget_per_subnet() {
+        let conns = IpConnections::new(2);
+        conns.set_max_per_subnet(3);
+        let a = conns.acquire(ip("1.2.3.4")).unwrap();
+        let _b = conns.acquire(ip("1.2.3.4")).unwrap();
+        let _c = conns.acquire(ip("1.2.3.5")).unwrap();
+        assert!(conns.acquire(ip("1.2.3.6")).is_none());
+        assert!(conns.acquire(ip("1.2.4.6")).is_some());
+        assert_eq!(conns.subnet_count(ip("1.2.3.99")), 3);
+
+        drop(a);
+        assert!(conns.acquire(ip("1.2.3.6")).is_some());
+    }
+
+    #[test]
+    fn diverse_order() {
+        let conns = IpConnections::new(4);
+        let _a = conns.acquire(ip("10.0.0.1")).unwrap();
+
+        let peer = |s: &str| PeerAddr::new(s.parse().unwrap()).unwrap();
+        let mut peers = vec![
+            peer("10.0.0.2:6881"),
+            peer("10.0.0.3:6881"),
+            peer("10.0.1.1:6881"),
+            peer("10.0.1.2:6881"),
+            peer("10.0.2.1:6881"),
+        ];
+        conns.sort_by_diversity(&mut peers);
+        assert_eq!(
+            peers,
+            [
+                peer("10.0.1.1:6881"),
+                peer("10.0.2.1:6881"),
+                peer("10.0.0.2:6881"),
+                peer("10.0.1.2:6881"),
+                peer("10.0.0.3:6881"),
+            ]
+        );
+    }
+}
@@ -18,13 +18,37 @@ macro_rules! hashset {
 pub const CLIENT_NAME: &str = "95th 0.1";
 
 pub mod announce;
+pub mod blocklist;
+pub mod cache;
+mod check;
+pub mod choker;
+pub mod config;
 mod download;
+pub mod event;
+mod forensic;
 pub mod future;
+pub mod hash;
+pub mod http;
+pub mod iplimit;
 pub mod metadata;
 pub mod peer;
+pub mod pool;
+pub mod ratelimit;
+pub mod reputation;
+pub mod resume;
+pub mod session;
 pub mod storage;
+pub mod swarm;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod traffic;
+mod upload;
+pub mod webseed;
 pub mod work;
 mod worker;
 
 pub use client::torrent::*;
-pub use worker::TorrentWorker;
+pub use session::Session;
+pub use worker::{
+    CompletionHook, DialedStream, Dialer, PieceReader, TorrentHandle, TorrentWorker, WorkerConfig,
+};
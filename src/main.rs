@@ -1,19 +1,24 @@
 use btrs::announce::DhtTracker;
-use btrs::metadata::get_peers;
-use btrs::storage::StorageWriter;
+use btrs::config::SessionConfig;
+use btrs::event::TorrentEvent;
+use btrs::metadata::{announce_stopped, fetch_metadata, MetadataCache};
+use btrs::peer::{self, Identity, Reachability};
+use btrs::reputation::Reputation;
+use btrs::resume::ResumeData;
+use btrs::storage::{self, PieceSink, StorageWriter, Unpadded};
 use btrs::work::Piece;
-use btrs::{peer, Torrent, TorrentWorker};
+use btrs::{Session, Torrent, TorrentHandle, TorrentWorker};
 use clap::{App, Arg};
 use client::bitfield::Bitfield;
 use client::magnet::TorrentMagnet;
-use client::metadata::request_metadata;
+use client::metainfo::TorrentLimits;
 use futures::channel::mpsc;
 use futures::StreamExt;
-use std::fs;
-use tracing::{debug, error};
+use std::{fs, io};
+use tracing::{debug, info, warn};
 use tracing_subscriber::EnvFilter;
 
-#[tokio::main(flavor = "current_thread")]
+#[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt()
         .compact()
@@ -30,91 +35,264 @@ async fn main() -> anyhow::Result<()> {
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("paranoid")
+                .long("paranoid")
+                .help("Hash all the existing pieces on startup, even with resume data"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .help("Session settings file, reloaded on SIGHUP"),
+        )
+        .arg(
+            Arg::with_name("show")
+                .long("show")
+                .help("Print the details of the torrent file instead of downloading it"),
+        )
         .get_matches();
 
     let input = m.value_of("torrent|magnet").unwrap();
+    let paranoid = m.is_present("paranoid");
+    let config = m.value_of("config");
 
-    if input.starts_with("magnet") {
-        magnet(input).await
+    if m.is_present("show") {
+        let torrent = Torrent::parse_file(&fs::read(input)?)?;
+        print!("{}", torrent.summary());
+        Ok(())
+    } else if input.starts_with("magnet") {
+        magnet(input, paranoid, config).await
     } else {
-        torrent_file(input).await
+        torrent_file(input, paranoid, config).await
     }
 }
 
-pub async fn magnet(uri: &str) -> anyhow::Result<()> {
+pub async fn magnet(uri: &str, paranoid: bool, config: Option<&str>) -> anyhow::Result<()> {
     let magnet = TorrentMagnet::parse(uri)?;
-    let peer_id = peer::generate_peer_id();
+    let identity = Identity::default();
+    let peer_id = identity.generate_peer_id();
     debug!("Our peer_id: {:?}", peer_id);
 
+    let limits = TorrentLimits::default();
+    let cache = MetadataCache::user();
+    if let Some(metadata) = cache
+        .as_ref()
+        .and_then(|c| c.get(&magnet.info_hash, &limits))
+    {
+        info!("Using the cached metadata");
+        let peers = magnet.peer_addrs.clone();
+        let mut torrent = magnet.with_metadata(metadata);
+        torrent.peers = peers.iter().copied().filter(|a| a.is_ipv4()).collect();
+        torrent.peers_v6 = peers.iter().copied().filter(|a| a.is_ipv6()).collect();
+        return download(torrent, paranoid, config).await;
+    }
+
     let mut dht_tracker = DhtTracker::new().await?;
-    let (peers, peers6) = get_peers(
-        &magnet.info_hash,
+    let reachability = tokio::task::spawn_blocking(Reachability::detect).await?;
+    let fetched = fetch_metadata(
+        &magnet,
         &peer_id,
-        &magnet.tracker_urls,
+        &identity.version,
+        &limits,
+        reachability,
         &mut dht_tracker,
     )
-    .await?;
-    let metadata = request_metadata(
-        peers.iter().chain(peers6.iter()),
-        &magnet.info_hash,
-        &peer_id,
-    )
-    .await?;
+    .await;
+    let left = fetched
+        .as_ref()
+        .ok()
+        .map(|(metadata, ..)| metadata.length as u64);
+    announce_stopped(&magnet.info_hash, &peer_id, &magnet.tracker_urls, left).await;
+    let (metadata, peers, peers6) = fetched?;
+
+    if let Some(cache) = &cache {
+        if let Err(e) = cache.put(&magnet.info_hash, &metadata) {
+            warn!("Failed to cache the metadata: {}", e);
+        }
+    }
 
     let mut torrent = magnet.with_metadata(metadata);
     torrent.peers = peers;
     torrent.peers_v6 = peers6;
 
-    download(torrent).await
+    download(torrent, paranoid, config).await
 }
 
-pub async fn torrent_file(file: &str) -> anyhow::Result<()> {
+pub async fn torrent_file(file: &str, paranoid: bool, config: Option<&str>) -> anyhow::Result<()> {
     let buf = fs::read(file)?;
     let torrent = Torrent::parse_file(&buf)?;
-    download(torrent).await
+    download(torrent, paranoid, config).await
 }
 
-pub async fn download(torrent: Torrent) -> anyhow::Result<()> {
+/// Download the torrent, resuming from the resume data or the existing
+/// file if any. With `paranoid`, every existing piece is hashed instead of
+/// trusting the resume data. The session settings are loaded from the
+/// `config` file, if any, and reloaded when the process gets SIGHUP.
+pub async fn download(
+    torrent: Torrent,
+    paranoid: bool,
+    config_file: Option<&str>,
+) -> anyhow::Result<()> {
+    let config = match config_file {
+        Some(path) => SessionConfig::load(path)?,
+        None => SessionConfig::default(),
+    };
+    let dir = config.download_dir.clone().unwrap_or_default();
+
     let torrent_name = torrent.name.clone();
     let piece_len = torrent.piece_len;
+    let length = torrent.length as u64;
+    let file_map = torrent.file_map();
 
-    let dht = DhtTracker::new().await?;
-    let mut worker = TorrentWorker::new(torrent, peer::generate_peer_id(), dht);
+    let resume_file = dir.join(format!("{}.resume", torrent_name));
+    let reputation_file = dir.join(format!("{}.peers", torrent_name));
+
+    let mut session = Session::new();
+    session.apply_config(&config);
+    // Both ask the OS, so they stay off the runtime's threads
+    let (reachability, ipv6) =
+        tokio::task::spawn_blocking(|| (Reachability::detect(), peer::local_ipv6())).await?;
+    session.set_reachability(reachability);
+    session.set_local_ipv6(ipv6);
+    session.set_reputation(Reputation::load(&reputation_file));
+    let mut worker = if config.dht == Some(false) {
+        let peer_id = session.identity().generate_peer_id();
+        TorrentWorker::without_dht(session.clone(), torrent, peer_id)
+    } else {
+        session.add_torrent(torrent, DhtTracker::new().await?)
+    };
     let num_pieces = worker.num_pieces();
 
+    #[cfg(unix)]
+    if let Some(path) = config_file {
+        tokio::spawn(reload_on_hangup(session.clone(), path.to_owned()));
+    }
+
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(dir.join(&torrent_name))?;
+    let stored = file.metadata()?.len() > 0;
+    // The padding files line the files up with the pieces, but aren't
+    // worth storing
+    let file = Unpadded::new(file, file_map.clone());
+
+    // The check and the uploads read the pieces through handles of their
+    // own
+    let reader =
+        || -> io::Result<_> { Ok(Unpadded::new(file.get_ref().try_clone()?, file_map.clone())) };
+
+    let mut have = Bitfield::with_size(num_pieces);
+    let resume = if paranoid {
+        None
+    } else {
+        ResumeData::load(&resume_file, worker.info_hash(), num_pieces)
+    };
+    if let Some(resume) = resume {
+        info!(
+            "Resuming download: {} pieces and {} partial pieces",
+            resume.have.count(),
+            resume.partial.len()
+        );
+        have = worker.restore_checked(resume, reader()?).await?;
+    } else if stored {
+        tokio::spawn(print_check_progress(worker.subscribe()));
+        have = worker.check(reader()?).await?;
+        info!("Found {} of {} pieces", have.count(), num_pieces);
+    }
+
+    worker.set_piece_reader(storage::piece_reader(reader()?, piece_len, length));
+
     let (piece_tx, piece_rx) = mpsc::channel::<Piece>(200);
+    let mut storage = StorageWriter::new(file, piece_len);
+    let handle = worker.handle();
+    tokio::spawn(print_storage_errors(worker.subscribe()));
+
+    // Disk writes get a thread of their own so that they don't hold up the
+    // peers
+    let writer_task = tokio::task::spawn_blocking(move || {
+        let write = write_to_storage(&mut storage, have, piece_rx, handle);
+        let have = futures::executor::block_on(write);
+        (have, storage.into_inner().into_inner())
+    });
+    let download_task = tokio::spawn(async move {
+        // Dropping the download on interrupt sends unfinished pieces back
+        // to the work queue so that they can be saved in the resume data.
+        tokio::select! {
+            _ = worker.run(piece_tx) => {}
+            _ = tokio::signal::ctrl_c() => info!("Interrupted; saving resume data"),
+        }
+        worker
+    });
 
-    let writer_task = write_to_file(torrent_name, piece_len, num_pieces, piece_rx);
-    let download_task = worker.run(piece_tx);
+    let (writer, worker) = futures::join!(writer_task, download_task);
+    let ((have, file), mut worker) = (writer?, worker?);
+    worker.shutdown().await;
+    println!("File downloaded; size: {}", file.metadata()?.len());
+
+    if have.is_all_set() {
+        let _ = fs::remove_file(&resume_file);
+    } else if let Err(e) = worker.resume_data(have).save(&resume_file) {
+        warn!("Unable to save resume data: {}", e);
+    }
+
+    if let Err(e) = session.reputation().save(&reputation_file) {
+        warn!("Unable to save peer reputation: {}", e);
+    }
 
-    futures::join!(writer_task, download_task);
     Ok(())
 }
 
-async fn write_to_file(
-    torrent_name: String,
-    piece_len: usize,
-    num_pieces: usize,
-    mut piece_rx: mpsc::Receiver<Piece>,
-) {
-    let mut file = fs::OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(torrent_name)
-        .unwrap();
-    let mut storage = StorageWriter::new(&mut file, piece_len);
-    let mut bitfield = Bitfield::with_size(num_pieces);
-
-    // Save a piece to storage {
-    while let Some(piece) = piece_rx.next().await {
-        let index = piece.index as usize;
-        if bitfield.get_bit(index) {
-            error!("Duplicate piece downloaded: {}", index);
+/// Reload the settings of the session from `path` whenever the process
+/// gets SIGHUP. Settings of an invalid file are not applied.
+#[cfg(unix)]
+async fn reload_on_hangup(session: Session, path: String) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => return warn!("Unable to watch for SIGHUP: {}", e),
+    };
+    while hangups.recv().await.is_some() {
+        match SessionConfig::load(&path) {
+            Ok(config) => {
+                session.reload_config(&config);
+                info!("Reloaded the settings from {}", path);
+            }
+            Err(e) => warn!("Keeping the current settings: {:#}", e),
+        }
+    }
+}
+
+async fn print_check_progress(mut events: mpsc::UnboundedReceiver<TorrentEvent>) {
+    while let Some(event) = events.next().await {
+        if let TorrentEvent::CheckProgress { checked, total } = event {
+            println!("Checked {}/{} pieces", checked, total);
+            if checked == total {
+                break;
+            }
         }
+    }
+}
 
-        storage.insert(piece).unwrap();
-        bitfield.set_bit(index);
+async fn print_storage_errors(mut events: mpsc::UnboundedReceiver<TorrentEvent>) {
+    while let Some(event) = events.next().await {
+        if let TorrentEvent::StorageError { message, .. } = event {
+            println!("Storage error: {}; download paused", message);
+        }
     }
-    println!("All pieces downloaded: {}", bitfield.is_all_set());
-    println!("File downloaded; size: {}", file.metadata().unwrap().len());
+}
+
+async fn write_to_storage<S: PieceSink>(
+    sink: &mut S,
+    have: Bitfield,
+    piece_rx: mpsc::Receiver<Piece>,
+    handle: TorrentHandle,
+) -> Bitfield {
+    let have = storage::write_torrent_pieces(sink, have, piece_rx.map(Ok), &handle).await;
+    println!("All pieces downloaded: {}", have.is_all_set());
+    have
 }
@@ -1,9 +1,34 @@
-use std::{collections::HashSet, net::SocketAddr};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
+use ben::{decode::Dict, Parser};
+
+use client::magnet::TorrentMagnet;
+use client::metadata::request_metadata;
+use client::metainfo::{is_info_of, MetaInfo, TorrentLimits};
 use client::{InfoHash, PeerId};
-use futures::{stream::FuturesUnordered, StreamExt};
+use data_encoding::HEXLOWER;
+use futures::future::{Fuse, FusedFuture};
+use futures::{select, stream::FuturesUnordered, FutureExt, StreamExt};
+
+use crate::announce::{DhtTracker, Event, Tracker, TransferStats};
+use crate::future::timeout;
+use crate::http::redact;
+use crate::peer::Reachability;
+
+/// Max seconds to wait for a tracker to acknowledge the `Stopped` event.
+const STOPPED_TIMEOUT: u64 = 2;
 
-use crate::announce::{DhtTracker, Tracker};
+/// Max seconds to wait for the peers of the trackers and the DHT once the
+/// peers of the magnet sent the metadata.
+const LOOKUP_GRACE: u64 = 5;
+
+/// Bytes left announced while the length of the torrent is unknown, so that
+/// the trackers don't take us for a seed.
+const UNKNOWN_LEFT: u64 = 16 * 1024;
 
 pub async fn get_peers(
     info_hash: &InfoHash,
@@ -53,3 +78,301 @@ pub async fn get_peers(
 
     Ok((peers, peers6))
 }
+
+/// Fetch the metadata of the magnet along with the v4 and v6 peers of the
+/// torrent.
+///
+/// The `x.pe` peers of the magnet are asked right away, while the trackers
+/// and the DHT are looked up, so that a fresh magnet from a seed doesn't
+/// wait for the announces. The peers found by the lookup are asked too, and
+/// returned along with the magnet's either way.
+/// Metadata of torrents over the `limits` is rejected.
+pub async fn fetch_metadata(
+    magnet: &TorrentMagnet,
+    peer_id: &PeerId,
+    version: &str,
+    limits: &TorrentLimits,
+    reachability: Reachability,
+    dht_tracker: &mut DhtTracker,
+) -> anyhow::Result<(MetaInfo, HashSet<SocketAddr>, HashSet<SocketAddr>)> {
+    let info_hash = &magnet.info_hash;
+    let hinted: HashSet<_> = magnet
+        .peer_addrs
+        .iter()
+        .copied()
+        .filter(|a| reachability.allows(a.ip()))
+        .collect();
+
+    let direct = async {
+        anyhow::ensure!(!hinted.is_empty(), "No peers in the magnet");
+        debug!(
+            "Requesting metadata from {} peers of the magnet",
+            hinted.len()
+        );
+        request_metadata(hinted.iter(), info_hash, peer_id, version, limits).await
+    }
+    .fuse();
+
+    let lookup = get_peers(info_hash, peer_id, &magnet.tracker_urls, dht_tracker).fuse();
+    let from_found = Fuse::terminated();
+
+    futures::pin_mut!(direct, lookup, from_found);
+
+    let mut found = None;
+    let mut error = None;
+    let metadata = loop {
+        select! {
+            r = direct => match r {
+                Ok(metadata) => break metadata,
+                Err(e) => debug!("Magnet peers failed: {}", e),
+            },
+            r = lookup => match r {
+                Ok((peers, peers6)) => {
+                    let new: Vec<_> = peers
+                        .iter()
+                        .chain(peers6.iter())
+                        .filter(|a| !hinted.contains(*a) && reachability.allows(a.ip()))
+                        .copied()
+                        .collect();
+                    from_found.set(
+                        async move {
+                            request_metadata(new.iter(), info_hash, peer_id, version, limits).await
+                        }
+                        .fuse(),
+                    );
+                    found = Some((peers, peers6));
+                }
+                Err(e) => error = Some(e),
+            },
+            r = from_found => match r {
+                Ok(metadata) => break metadata,
+                Err(e) => error = Some(e),
+            },
+            complete => {
+                let e = error.unwrap_or_else(|| anyhow::anyhow!("Failed to retrieve metadata"));
+                return Err(e);
+            }
+        }
+    };
+
+    // The magnet peers may have sent the metadata before the lookup was
+    // done. Its peers are worth the wait, but not for long.
+    let (mut peers, mut peers6) = match found {
+        Some(found) => found,
+        None if !lookup.is_terminated() => timeout(lookup, LOOKUP_GRACE).await.unwrap_or_default(),
+        None => Default::default(),
+    };
+
+    for &addr in &hinted {
+        if addr.is_ipv4() {
+            peers.insert(addr);
+        } else {
+            peers6.insert(addr);
+        }
+    }
+    Ok((metadata, peers, peers6))
+}
+
+/// Tell the trackers which `get_peers` announced to that we're gone, once
+/// the metadata is fetched. Otherwise they keep handing out `peer_id` as a
+/// leecher until the announce interval runs out.
+///
+/// The download is expected to announce with its own peer id. Nothing is
+/// downloaded under `peer_id`, so `left` is the length of the torrent, if
+/// the metadata was fetched.
+pub async fn announce_stopped(
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+    trackers: &[String],
+    left: Option<u64>,
+) {
+    let stats = TransferStats {
+        left: left.unwrap_or(UNKNOWN_LEFT),
+        ..TransferStats::default()
+    };
+    let mut futs: FuturesUnordered<_> = trackers
+        .iter()
+        .map(|url| async move {
+            let mut t = Tracker::new(url.clone());
+            t.set_num_want(Some(0));
+            let f = t.announce_event(info_hash, peer_id, stats, Event::Stopped);
+            if let Err(e) = timeout(f, STOPPED_TIMEOUT).await {
+                debug!("Stopped announce to {} failed: {}", redact(url), e);
+            }
+        })
+        .collect();
+
+    while futs.next().await.is_some() {}
+}
+
+/// Metadata fetched from peers, saved as `<info hash>.torrent` files so
+/// that adding the same magnet again doesn't wait for the peers.
+#[derive(Debug, Clone)]
+pub struct MetadataCache {
+    dir: PathBuf,
+}
+
+impl MetadataCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The cache in `$XDG_CACHE_HOME/btrs`, or `~/.cache/btrs`. None if
+    /// neither variable is set.
+    pub fn user() -> Option<Self> {
+        let dir = std::env::var_os("XDG_CACHE_HOME")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))?;
+        Some(Self::new(dir.join("btrs")))
+    }
+
+    fn path(&self, info_hash: &InfoHash) -> PathBuf {
+        self.dir
+            .join(format!("{}.torrent", HEXLOWER.encode(info_hash)))
+    }
+
+    /// The cached metadata of the torrent. Files that don't hash to
+    /// `info_hash` or are over the `limits` are ignored, like the metadata
+    /// of a peer would be.
+    pub fn get(&self, info_hash: &InfoHash, limits: &TorrentLimits) -> Option<MetaInfo> {
+        let data = fs::read(self.path(info_hash)).ok()?;
+        let mut parser = Parser::new();
+        parser.binary_keys(true);
+        let dict = parser.parse::<Dict>(&data).ok()?;
+        let info = dict.get_dict("info")?.as_raw_bytes();
+        if !is_info_of(info, info_hash) {
+            warn!(
+                "Cached metadata of {} is corrupt",
+                HEXLOWER.encode(info_hash)
+            );
+            return None;
+        }
+        let layers = dict
+            .get_dict("piece layers")
+            .iter()
+            .flat_map(|d| d.raw_iter())
+            .filter_map(|(root, layer)| Some((root.try_into().ok()?, layer.as_bytes()?.to_vec())))
+            .collect();
+        match MetaInfo::parse_with_layers(info, &mut Parser::new(), limits, layers) {
+            Ok(metadata) => Some(metadata),
+            Err(e) => {
+                warn!(
+                    "Cached metadata of {} is invalid: {}",
+                    HEXLOWER.encode(info_hash),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Save the metadata of the torrent as a torrent file without trackers,
+    /// along with the piece layers of version 2 torrents. It's written to
+    /// a temporary file first, so that a crash doesn't leave half a torrent
+    /// behind.
+    pub fn put(&self, info_hash: &InfoHash, metadata: &MetaInfo) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut data = Vec::with_capacity(metadata.raw.len() + 8);
+        data.extend_from_slice(b"d4:info");
+        data.extend_from_slice(&metadata.raw);
+        if !metadata.piece_layers.is_empty() {
+            // Keys in order, as bencode wants them
+            let mut layers: Vec<_> = metadata.piece_layers.iter().collect();
+            layers.sort_by_key(|(root, _)| *root);
+            data.extend_from_slice(b"12:piece layersd");
+            for (root, layer) in layers {
+                data.extend_from_slice(b"32:");
+                data.extend_from_slice(root);
+                data.extend_from_slice(format!("{}:", layer.len()).as_bytes());
+                data.extend_from_slice(layer);
+            }
+            data.push(b'e');
+        }
+        data.push(b'e');
+
+        let path = self.path(info_hash);
+        let tmp = path.with_extension("torrent.part");
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::sha1;
+
+    #[test]
+    fn metadata_cache() {
+        let dir = std::env::temp_dir().join(format!("btrs-metadata-{}", std::process::id()));
+        let cache = MetadataCache::new(&dir);
+        let limits = TorrentLimits::default();
+
+        let raw = b"d6:lengthi5e4:name1:t12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let info_hash = sha1(raw);
+        assert!(cache.get(&info_hash, &limits).is_none());
+
+        let metadata = MetaInfo::parse(raw).unwrap();
+        cache.put(&info_hash, &metadata).unwrap();
+        let cached = cache.get(&info_hash, &limits).unwrap();
+        assert_eq!(cached.raw, raw);
+        assert_eq!(cached.name.as_deref(), Some("t"));
+
+        // Metadata that doesn't match the info hash isn't returned.
+        let other = [1; 20];
+        fs::copy(cache.path(&info_hash), cache.path(&other)).unwrap();
+        assert!(cache.get(&other, &limits).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Request target of the next announce to an HTTP tracker, which is
+    /// answered with no peers.
+    async fn next_announce(listener: &tokio::net::TcpListener) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; 4096];
+        let mut len = 0;
+        while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+            len += stream.read(&mut buf[len..]).await.unwrap();
+        }
+        let body = b"d8:intervali1800e5:peers0:e";
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+
+        let req = String::from_utf8_lossy(&buf[..len]);
+        req.split(' ').nth(1).unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn stopped_announce_sends_left() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let trackers = [format!(
+            "http://{}/announce",
+            listener.local_addr().unwrap()
+        )];
+        let info_hash = [1; 20];
+        let peer_id = [2; 20];
+
+        let (_, target) = futures::join!(
+            announce_stopped(&info_hash, &peer_id, &trackers, Some(5000)),
+            next_announce(&listener)
+        );
+        assert!(target.contains("&left=5000&"));
+        assert!(target.contains("event=stopped"));
+        assert!(target.contains("numwant=0"));
+
+        // Not a seed either without the metadata
+        let (_, target) = futures::join!(
+            announce_stopped(&info_hash, &peer_id, &trackers, None),
+            next_announce(&listener)
+        );
+        assert!(target.contains(&format!("&left={}&", UNKNOWN_LEFT)));
+    }
+}
@@ -1,6 +1,12 @@
 use client::PeerId;
 use rand::{distributions::Alphanumeric, Rng};
-use std::net::SocketAddr;
+use socket2::SockRef;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpSocket, TcpStream};
 
 pub fn v4(bytes: &[u8]) -> SocketAddr {
     let ip: [u8; 4] = bytes[..4].try_into().unwrap();
@@ -14,11 +20,460 @@ pub fn v6(bytes: &[u8]) -> SocketAddr {
     SocketAddr::from((ip, u16::from_be_bytes(port_bytes)))
 }
 
+/// Canonical peer address used to identify peers received from
+/// different sources.
+///
+/// IPv4-mapped IPv6 addresses are converted to IPv4, and the flow info and
+/// scope id of IPv6 addresses are dropped. Peers with the same IP but
+/// different ports are kept apart as they can be different clients
+/// behind a NAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerAddr(SocketAddr);
+
+impl PeerAddr {
+    /// Returns `None` if the address can't be a valid peer.
+    pub fn new(addr: SocketAddr) -> Option<Self> {
+        let ip = canonical_ip(addr.ip());
+
+        let invalid = match ip {
+            IpAddr::V4(ip) => ip.is_broadcast(),
+            IpAddr::V6(_) => false,
+        };
+
+        if addr.port() == 0 || ip.is_unspecified() || ip.is_multicast() || invalid {
+            return None;
+        }
+
+        Some(Self(SocketAddr::new(ip, addr.port())))
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.0
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.0.ip()
+    }
+}
+
+/// Convert IPv4-mapped IPv6 addresses to IPv4.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        },
+    }
+}
+
+impl From<PeerAddr> for SocketAddr {
+    fn from(peer: PeerAddr) -> Self {
+        peer.0
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Local address the OS would use to reach `target`, if it has a route.
+///
+/// Connecting a UDP socket doesn't send anything but makes the OS pick
+/// the local address it would use to reach the internet.
+fn route_source(bind: &str, target: &str) -> Option<IpAddr> {
+    let socket = UdpSocket::bind(bind).ok()?;
+    socket.connect(target).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// Find our global IPv6 address, if we have one. Blocks on the OS, so call
+/// it at startup or on a blocking thread.
+pub fn local_ipv6() -> Option<Ipv6Addr> {
+    match route_source("[::]:0", "[2001:4860:4860::8888]:53")? {
+        IpAddr::V6(ip) if is_global_unicast(&ip) => Some(ip),
+        _ => None,
+    }
+}
+
+/// Returns false for loopback, unspecified, link-local, unique local and
+/// multicast addresses, which peers on the internet can't reach.
+fn is_global_unicast(ip: &Ipv6Addr) -> bool {
+    let segment = ip.segments()[0];
+    let link_local = segment & 0xffc0 == 0xfe80;
+    let unique_local = segment & 0xfe00 == 0xfc00;
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || link_local || unique_local)
+}
+
+/// Address families over which peers can be reached.
+///
+/// Dialing peers of a family we have no route for fails, slowly at times,
+/// while holding a connection slot, so such peers are skipped.
+///
+/// Both families are reachable by default. [`Reachability::detect`] checks
+/// the routes instead, which blocks on the OS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reachability {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+impl Default for Reachability {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl Reachability {
+    /// Both families, e.g. to dial all the peers regardless.
+    pub const ALL: Self = Self {
+        ipv4: true,
+        ipv6: true,
+    };
+
+    /// Check which families the OS has a route to the internet for. IPv6
+    /// counts only with a global address, as a link-local or unique local
+    /// one can't reach peers. If neither family does, e.g. because the
+    /// network isn't up yet, both are assumed to be reachable.
+    pub fn detect() -> Self {
+        let ipv4 = route_source("0.0.0.0:0", "8.8.8.8:53").is_some();
+        let ipv6 = local_ipv6().is_some();
+        if !ipv4 && !ipv6 {
+            return Self::ALL;
+        }
+        Self { ipv4, ipv6 }
+    }
+
+    /// Returns true if peers at the IP can be dialed.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        match canonical_ip(ip) {
+            IpAddr::V4(_) => self.ipv4,
+            IpAddr::V6(_) => self.ipv6,
+        }
+    }
+}
+
+/// Number of dual-stack peers remembered. Past it new ones are ignored.
+const MAX_DUAL_STACK: usize = 10_000;
+
+/// Peers known to listen on both an IPv4 and an IPv6 address, e.g. from
+/// the addresses they sent in their extended handshakes.
+///
+/// Such a peer is dialed at both addresses, IPv6 first, and the first
+/// connection to succeed is kept. Cloning returns a handle to the same
+/// peers.
+#[derive(Debug, Clone, Default)]
+pub struct DualStack {
+    alts: Arc<Mutex<HashMap<PeerAddr, PeerAddr>>>,
+}
+
+impl DualStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `a` and `b` are the addresses of the same peer. Ignored
+    /// unless they are of different families.
+    pub fn add(&self, a: PeerAddr, b: PeerAddr) {
+        if a.ip().is_ipv4() == b.ip().is_ipv4() {
+            return;
+        }
+
+        let mut alts = self.alts.lock().unwrap();
+        if alts.len() + 2 > MAX_DUAL_STACK && !alts.contains_key(&a) {
+            return;
+        }
+        alts.insert(a, b);
+        alts.insert(b, a);
+    }
+
+    /// The other address of the peer at `addr`, if it has one.
+    pub fn alt(&self, addr: &PeerAddr) -> Option<PeerAddr> {
+        self.alts.lock().unwrap().get(addr).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.alts.lock().unwrap().len() / 2
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alts.lock().unwrap().is_empty()
+    }
+}
+
+/// Options of the TCP sockets connecting to the peers. The defaults leave
+/// the system's settings alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketConfig {
+    /// Send the messages right away rather than waiting to fill a packet
+    /// (`TCP_NODELAY`), so that requests go out without delay.
+    pub nodelay: bool,
+
+    /// Size of the kernel's send buffer of each socket in bytes.
+    pub send_buffer_size: Option<u32>,
+
+    /// Size of the kernel's receive buffer of each socket in bytes. It caps
+    /// the TCP window, so it's worth raising on fast links with high
+    /// latency.
+    pub recv_buffer_size: Option<u32>,
+
+    /// Type of service byte of the IPv4 packets (`IP_TOS`), or traffic
+    /// class of the IPv6 ones (`IPV6_TCLASS`), the DSCP marking in its upper
+    /// six bits. E.g. `0x20` for the CS1 "lower effort" class QoS networks
+    /// use for bulk transfers. IPv6 packets are only marked on the systems
+    /// which have the option, e.g. not on Windows.
+    pub tos: Option<u32>,
+}
+
+impl SocketConfig {
+    /// Connect to `addr` with a socket of these options.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(tos) = self.tos {
+            set_tos(&SockRef::from(&socket), addr, tos)?;
+        }
+
+        let stream = socket.connect(addr).await?;
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        Ok(stream)
+    }
+}
+
+fn set_tos(socket: &SockRef<'_>, addr: SocketAddr, tos: u32) -> io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => socket.set_tos(tos),
+        #[cfg(any(
+            target_os = "android",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "fuchsia",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "openbsd",
+        ))]
+        SocketAddr::V6(_) => socket.set_tclass_v6(tos),
+        #[cfg(not(any(
+            target_os = "android",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "fuchsia",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "openbsd",
+        )))]
+        SocketAddr::V6(_) => Ok(()),
+    }
+}
+
+/// How we present ourselves to the peers and the trackers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Start of our peer ids, the client fingerprint like `-BT0100-`. The
+    /// rest of the 20 bytes is random.
+    pub peer_id_prefix: String,
+
+    /// User agent of the HTTP tracker announces, unless the `HttpConfig`
+    /// of the torrent has its own.
+    pub user_agent: String,
+
+    /// Client name and version sent as "v" in the extended handshake.
+    pub version: String,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self {
+            peer_id_prefix: "-UT3100-".into(),
+            user_agent: crate::CLIENT_NAME.into(),
+            version: crate::CLIENT_NAME.into(),
+        }
+    }
+}
+
+impl Identity {
+    /// Peer id made of the prefix and random alphanumerics. A prefix over
+    /// 20 bytes is cut short.
+    pub fn generate_peer_id(&self) -> PeerId {
+        let mut buf = [0; 20];
+        let prefix = self.peer_id_prefix.as_bytes();
+        let n = prefix.len().min(buf.len());
+        buf[..n].copy_from_slice(&prefix[..n]);
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .zip(&mut buf[n..])
+            .for_each(|(c, b)| *b = c);
+        buf
+    }
+}
+
 pub fn generate_peer_id() -> PeerId {
-    let mut buf = *b"-UT3100-000000000000";
-    rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .zip(&mut buf[8..])
-        .for_each(|(c, b)| *b = c);
-    buf
+    Identity::default().generate_peer_id()
+}
+
+/// Client name and version from an Azureus-style peer id like
+/// `-UT3100-...`, e.g. "UT 3100". Falls back to the printable prefix of the
+/// peer id for other styles.
+pub fn client_name(peer_id: &PeerId) -> String {
+    let azureus = peer_id[0] == b'-'
+        && peer_id[7] == b'-'
+        && peer_id[1..7].iter().all(u8::is_ascii_alphanumeric);
+    if azureus {
+        let name = String::from_utf8_lossy(&peer_id[1..3]);
+        let version = String::from_utf8_lossy(&peer_id[3..7]);
+        return format!("{} {}", name, version);
+    }
+
+    peer_id
+        .iter()
+        .take_while(|b| b.is_ascii_graphic())
+        .map(|&b| b as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(s: &str) -> Option<PeerAddr> {
+        PeerAddr::new(s.parse().unwrap())
+    }
+
+    #[test]
+    fn canonical_peer_addr() {
+        assert_eq!(peer("[::ffff:1.2.3.4]:6881"), peer("1.2.3.4:6881"));
+        assert_eq!(peer("[fe80::1%2]:6881"), peer("[fe80::1]:6881"));
+        assert_ne!(peer("1.2.3.4:6881"), peer("1.2.3.4:6882"));
+        assert_eq!(
+            peer("[::ffff:1.2.3.4]:6881").unwrap().to_string(),
+            "1.2.3.4:6881"
+        );
+    }
+
+    #[tokio::test]
+    async fn socket_options() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = SocketConfig {
+            nodelay: true,
+            send_buffer_size: Some(0x10000),
+            recv_buffer_size: Some(0x10000),
+            tos: Some(0x20),
+        };
+        let stream = config
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert!(stream.nodelay().unwrap());
+
+        let socket = SockRef::from(&stream);
+        assert_eq!(socket.tos().unwrap(), 0x20);
+        assert!(socket.recv_buffer_size().unwrap() >= 0x10000);
+        assert!(socket.send_buffer_size().unwrap() >= 0x10000);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn ipv6_traffic_class() {
+        // Not every sandbox has IPv6
+        let Ok(listener) = tokio::net::TcpListener::bind("[::1]:0").await else {
+            return;
+        };
+        let config = SocketConfig {
+            tos: Some(0x20),
+            ..SocketConfig::default()
+        };
+        let stream = config
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(SockRef::from(&stream).tclass_v6().unwrap(), 0x20);
+    }
+
+    #[test]
+    fn dual_stack_peers() {
+        let dual = DualStack::new();
+        let v4 = peer("1.2.3.4:6881").unwrap();
+        let v6 = peer("[2001:db8::1]:6881").unwrap();
+        dual.add(v4, peer("5.6.7.8:6881").unwrap());
+        assert!(dual.is_empty());
+
+        dual.add(v4, v6);
+        assert_eq!(dual.len(), 1);
+        assert_eq!(dual.alt(&v4), Some(v6));
+        assert_eq!(dual.alt(&v6), Some(v4));
+        assert_eq!(dual.alt(&peer("5.6.7.8:6881").unwrap()), None);
+    }
+
+    #[test]
+    fn invalid_peer_addr() {
+        assert_eq!(peer("1.2.3.4:0"), None);
+        assert_eq!(peer("0.0.0.0:6881"), None);
+        assert_eq!(peer("255.255.255.255:6881"), None);
+        assert_eq!(peer("224.0.0.1:6881"), None);
+        assert_eq!(peer("[::]:6881"), None);
+        assert_eq!(peer("[::ffff:0.0.0.0]:6881"), None);
+    }
+
+    #[test]
+    fn reachability() {
+        let v4_only = Reachability {
+            ipv4: true,
+            ipv6: false,
+        };
+        assert!(v4_only.allows("1.2.3.4".parse().unwrap()));
+        assert!(v4_only.allows("::ffff:1.2.3.4".parse().unwrap()));
+        assert!(!v4_only.allows("2001:db8::1".parse().unwrap()));
+        assert!(Reachability::ALL.allows("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn global_unicast() {
+        let global = |s: &str| is_global_unicast(&s.parse().unwrap());
+        assert!(global("2001:4860::1"));
+        assert!(!global("fe80::1"));
+        assert!(!global("fd12:3456::1"));
+        assert!(!global("::1"));
+        assert!(!global("::"));
+        assert!(!global("ff02::1"));
+    }
+
+    #[test]
+    fn client_names() {
+        assert_eq!(client_name(b"-qB4250-abcdefghijkl"), "qB 4250");
+        assert_eq!(client_name(&generate_peer_id()), "UT 3100");
+        assert_eq!(client_name(b"M7-2-2--abcdefghijkl"), "M7-2-2--abcdefghijkl");
+        assert_eq!(client_name(b"S58B\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0"), "S58B");
+    }
+
+    #[test]
+    fn identity_peer_id() {
+        let identity = Identity {
+            peer_id_prefix: "-BT0100-".into(),
+            ..Identity::default()
+        };
+        let peer_id = identity.generate_peer_id();
+        assert_eq!(&peer_id[..8], b"-BT0100-");
+        assert!(peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(identity.generate_peer_id(), peer_id);
+
+        let long = Identity {
+            peer_id_prefix: "x".repeat(30),
+            ..Identity::default()
+        };
+        assert_eq!(long.generate_peer_id(), [b'x'; 20]);
+    }
 }
@@ -0,0 +1,319 @@
+use crate::work::BLOCK_SIZE;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+
+/// Max number of free blocks kept around for reuse.
+const MAX_FREE_BLOCKS: usize = 256;
+
+/// Max number of free piece buffers kept around for reuse.
+const MAX_FREE_BUFS: usize = 4;
+
+/// Hands out 16 KiB block buffers and takes them back when they are dropped,
+/// so that a piece in progress only holds the blocks received so far
+/// instead of a buffer for the whole piece.
+///
+/// The pool keeps count of the bytes held in blocks. The limit is a soft
+/// one: `alloc` always succeeds since requested blocks have to go somewhere,
+/// but `has_room` tells the downloads to hold off on starting new pieces.
+///
+/// The pool also keeps the piece buffers the blocks are assembled into
+/// once a piece is complete, so that they are reused for the next pieces.
+///
+/// Cloning returns a handle to the same pool.
+#[derive(Clone, Default)]
+pub struct BlockPool {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    free: Mutex<Vec<Box<[u8]>>>,
+
+    /// Free piece buffers
+    free_bufs: Mutex<Vec<Box<[u8]>>>,
+    buffered: AtomicUsize,
+
+    /// Bytes set aside with `reserve`, counted against the limit too.
+    reserved: AtomicUsize,
+
+    /// Bytes. Zero means unlimited.
+    limit: AtomicUsize,
+}
+
+impl BlockPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the max number of bytes held in blocks. `None` removes the limit.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.inner.limit.store(limit.unwrap_or(0), Relaxed);
+    }
+
+    /// Take a block from the pool, allocating one if there are no free
+    /// blocks. The contents of the block are unspecified.
+    pub fn alloc(&self) -> Block {
+        let buf = self.inner.free.lock().unwrap().pop();
+        let buf = buf.unwrap_or_else(|| vec![0; BLOCK_SIZE as usize].into_boxed_slice());
+        self.inner.buffered.fetch_add(buf.len(), Relaxed);
+        Block {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    /// Take a `len` byte piece buffer from the pool, allocating one if there
+    /// are no free buffers of that length. The contents of the buffer are
+    /// unspecified.
+    pub fn alloc_buf(&self, len: usize) -> Box<[u8]> {
+        let mut free = self.inner.free_bufs.lock().unwrap();
+        match free.iter().position(|b| b.len() == len) {
+            Some(i) => free.swap_remove(i),
+            None => vec![0; len].into_boxed_slice(),
+        }
+    }
+
+    /// Give back a buffer of `alloc_buf` once done with it.
+    pub fn recycle(&self, buf: Box<[u8]>) {
+        let mut free = self.inner.free_bufs.lock().unwrap();
+        if free.len() < MAX_FREE_BUFS {
+            free.push(buf);
+        }
+    }
+
+    /// Whether both handles are to the same pool.
+    pub fn same(&self, other: &BlockPool) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// Bytes currently held in blocks.
+    pub fn buffered(&self) -> usize {
+        self.inner.buffered.load(Relaxed)
+    }
+
+    /// Bytes set aside for data outside the blocks.
+    pub fn reserved(&self) -> usize {
+        self.inner.reserved.load(Relaxed)
+    }
+
+    /// Set aside `len` bytes until the reservation is dropped, for data
+    /// which isn't held in blocks yet or anymore, e.g. the blocks
+    /// requested from the peers or a piece on its way to the storage.
+    pub fn reserve(&self, len: usize) -> Reservation {
+        self.inner.reserved.fetch_add(len, Relaxed);
+        Reservation {
+            len,
+            pool: self.clone(),
+        }
+    }
+
+    /// Returns true if `len` more bytes fit within the limit, counting the
+    /// reserved bytes along with the blocks.
+    pub fn has_room(&self, len: usize) -> bool {
+        match self.inner.limit.load(Relaxed) {
+            0 => true,
+            limit => self.buffered() + self.reserved() + len <= limit,
+        }
+    }
+
+    fn release(&self, buf: Box<[u8]>) {
+        self.inner.buffered.fetch_sub(buf.len(), Relaxed);
+        let mut free = self.inner.free.lock().unwrap();
+        if free.len() < MAX_FREE_BLOCKS {
+            free.push(buf);
+        }
+    }
+}
+
+impl fmt::Debug for BlockPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockPool")
+            .field("buffered", &self.buffered())
+            .field("reserved", &self.reserved())
+            .field("limit", &self.inner.limit.load(Relaxed))
+            .finish()
+    }
+}
+
+/// A `BLOCK_SIZE` buffer which goes back to its pool when dropped.
+pub struct Block {
+    buf: Box<[u8]>,
+    pool: BlockPool,
+}
+
+impl Block {
+    /// Count the block in `pool` instead, and give it back there once
+    /// dropped.
+    pub fn move_to(&mut self, pool: &BlockPool) {
+        let len = self.buf.len();
+        self.pool.inner.buffered.fetch_sub(len, Relaxed);
+        pool.inner.buffered.fetch_add(len, Relaxed);
+        self.pool = pool.clone();
+    }
+}
+
+impl Deref for Block {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for Block {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Clone for Block {
+    fn clone(&self) -> Self {
+        let mut block = self.pool.alloc();
+        block.copy_from_slice(&self.buf);
+        block
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        self.pool.release(std::mem::take(&mut self.buf));
+    }
+}
+
+impl fmt::Debug for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Block")
+            .field("len", &self.buf.len())
+            .finish()
+    }
+}
+
+/// Bytes set aside in a pool, given back when dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    len: usize,
+    pool: BlockPool,
+}
+
+impl Reservation {
+    /// Pool the bytes are set aside in.
+    pub fn pool(&self) -> &BlockPool {
+        &self.pool
+    }
+
+    /// Set the bytes aside in `pool` instead.
+    pub fn move_to(&mut self, pool: &BlockPool) {
+        self.pool.inner.reserved.fetch_sub(self.len, Relaxed);
+        pool.inner.reserved.fetch_add(self.len, Relaxed);
+        self.pool = pool.clone();
+    }
+
+    /// Give back `len` of the bytes, e.g. once a requested block arrives
+    /// and takes up a block of the pool instead.
+    pub fn release(&mut self, len: usize) {
+        let len = len.min(self.len);
+        self.len -= len;
+        self.pool.inner.reserved.fetch_sub(len, Relaxed);
+    }
+}
+
+impl Clone for Reservation {
+    fn clone(&self) -> Self {
+        self.pool.reserve(self.len)
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.release(self.len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_reused() {
+        let pool = BlockPool::new();
+        let mut block = pool.alloc();
+        assert_eq!(block.len(), BLOCK_SIZE as usize);
+        assert_eq!(pool.buffered(), BLOCK_SIZE as usize);
+
+        block[0] = 7;
+        let ptr = block.as_ptr();
+        drop(block);
+        assert_eq!(pool.buffered(), 0);
+
+        let mut block = pool.alloc();
+        assert_eq!(block.as_ptr(), ptr);
+        assert_eq!(pool.buffered(), BLOCK_SIZE as usize);
+
+        let other = BlockPool::new();
+        block.move_to(&other);
+        assert_eq!(pool.buffered(), 0);
+        assert_eq!(other.buffered(), BLOCK_SIZE as usize);
+        drop(block);
+        assert_eq!(other.buffered(), 0);
+        assert_eq!(other.alloc().as_ptr(), ptr);
+    }
+
+    #[test]
+    fn piece_buffers_are_reused() {
+        let pool = BlockPool::new();
+        let buf = pool.alloc_buf(100);
+        let ptr = buf.as_ptr();
+        pool.recycle(buf);
+
+        assert_eq!(pool.alloc_buf(50).len(), 50);
+        let buf = pool.alloc_buf(100);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(pool.buffered(), 0);
+    }
+
+    #[test]
+    fn limit() {
+        let pool = BlockPool::new();
+        let block_len = BLOCK_SIZE as usize;
+        assert!(pool.has_room(usize::MAX / 2));
+
+        pool.set_limit(Some(block_len * 2));
+        let a = pool.alloc();
+        assert!(pool.has_room(block_len));
+        let b = a.clone();
+        assert!(!pool.has_room(block_len));
+        assert!(pool.has_room(0));
+
+        drop(a);
+        assert!(pool.has_room(block_len));
+        drop(b);
+
+        pool.set_limit(None);
+        let _blocks: Vec<_> = (0..4).map(|_| pool.alloc()).collect();
+        assert!(pool.has_room(block_len));
+    }
+
+    #[test]
+    fn reservations_count_against_limit() {
+        let pool = BlockPool::new();
+        let block_len = BLOCK_SIZE as usize;
+        pool.set_limit(Some(block_len * 2));
+
+        let mut a = pool.reserve(block_len * 2);
+        assert!(!pool.has_room(1));
+        let b = a.clone();
+        assert_eq!(pool.reserved(), block_len * 4);
+        drop(b);
+
+        a.release(block_len);
+        let _block = pool.alloc();
+        assert!(!pool.has_room(1));
+        a.release(block_len * 5);
+        assert_eq!(pool.reserved(), 0);
+        drop(a);
+        assert_eq!(pool.reserved(), 0);
+        assert!(pool.has_room(block_len));
+    }
+}
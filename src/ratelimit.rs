@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Torrents which haven't asked for bandwidth in this long don't get a share.
+const ACTIVE_WINDOW: Duration = Duration::from_secs(5);
+
+/// How long to wait before asking again when a torrent gets no bandwidth.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// How the download bandwidth of a session is divided between its torrents.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BandwidthPolicy {
+    /// Every active torrent gets an equal share.
+    #[default]
+    EqualShare,
+
+    /// Active torrents get a share proportional to their priority.
+    Weighted,
+
+    /// The active torrent with the highest priority gets all the bandwidth.
+    /// Torrents with equal priority are served in the order they were added.
+    StrictOrder,
+}
+
+/// Download rate limiter shared between the torrents of a session.
+///
+/// Cloning returns a handle to the same limiter.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Bytes per second. `None` means unlimited.
+    limit: Option<u64>,
+    policy: BandwidthPolicy,
+    torrents: HashMap<u64, Slot>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Slot {
+    priority: u32,
+    tokens: f64,
+    last_refill: Instant,
+    last_active: Instant,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the download limit in bytes per second. `None` removes the limit.
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.inner.lock().unwrap().limit = limit;
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.inner.lock().unwrap().limit
+    }
+
+    pub fn set_policy(&self, policy: BandwidthPolicy) {
+        self.inner.lock().unwrap().policy = policy;
+    }
+
+    pub fn policy(&self) -> BandwidthPolicy {
+        self.inner.lock().unwrap().policy
+    }
+
+    /// Register a torrent with given priority. Higher priority torrents get
+    /// more bandwidth with `Weighted` and `StrictOrder` policies.
+    pub fn register(&self, priority: u32) -> TorrentBandwidth {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.torrents.insert(
+            id,
+            Slot {
+                priority,
+                tokens: 0.0,
+                last_refill: now,
+                last_active: now,
+            },
+        );
+
+        TorrentBandwidth {
+            id,
+            limiter: self.clone(),
+        }
+    }
+
+    /// Take `n` bytes from the torrent's share. Returns how long to wait
+    /// before trying again if there isn't enough bandwidth.
+    fn try_consume(&self, id: u64, n: usize, now: Instant) -> Result<(), Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        let limit = match inner.limit {
+            Some(limit) => limit as f64,
+            None => return Ok(()),
+        };
+
+        let rate = limit * inner.share(id, now);
+        let slot = match inner.torrents.get_mut(&id) {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+
+        slot.last_active = now;
+
+        // Allow a burst of up to a second worth of data, but never less
+        // than what's asked for; otherwise big requests would never pass.
+        let elapsed = now.saturating_duration_since(slot.last_refill);
+        slot.last_refill = now;
+        let capacity = rate.max(n as f64);
+        slot.tokens = (slot.tokens + rate * elapsed.as_secs_f64()).min(capacity);
+
+        let n = n as f64;
+        if slot.tokens >= n {
+            slot.tokens -= n;
+            return Ok(());
+        }
+
+        if rate <= 0.0 {
+            return Err(IDLE_WAIT);
+        }
+
+        Err(Duration::from_secs_f64((n - slot.tokens) / rate))
+    }
+}
+
+impl Inner {
+    /// Fraction of the limit the torrent gets.
+    fn share(&self, id: u64, now: Instant) -> f64 {
+        let active = self.torrents.iter().filter(|&(&i, s)| {
+            i == id || now.saturating_duration_since(s.last_active) < ACTIVE_WINDOW
+        });
+
+        match self.policy {
+            BandwidthPolicy::EqualShare => 1.0 / active.count() as f64,
+            BandwidthPolicy::Weighted => {
+                let total: u64 = active.map(|(_, s)| u64::from(s.priority)).sum();
+                match self.torrents.get(&id) {
+                    Some(s) if total > 0 => f64::from(s.priority) / total as f64,
+                    _ => 0.0,
+                }
+            }
+            BandwidthPolicy::StrictOrder => {
+                // Highest priority first, then the oldest one
+                let first = active.min_by_key(|&(&i, s)| (std::cmp::Reverse(s.priority), i));
+                match first {
+                    Some((&i, _)) if i == id => 1.0,
+                    _ => 0.0,
+                }
+            }
+        }
+    }
+}
+
+/// Bandwidth share of a single torrent. Dropping it gives the share
+/// to the other torrents.
+#[derive(Debug)]
+pub struct TorrentBandwidth {
+    id: u64,
+    limiter: RateLimiter,
+}
+
+impl TorrentBandwidth {
+    /// Wait until `n` bytes can be downloaded within the limits.
+    pub async fn consume(&self, n: usize) {
+        while let Err(wait) = self.limiter.try_consume(self.id, n, Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn set_priority(&self, priority: u32) {
+        let mut inner = self.limiter.inner.lock().unwrap();
+        if let Some(slot) = inner.torrents.get_mut(&self.id) {
+            slot.priority = priority;
+        }
+    }
+}
+
+impl Drop for TorrentBandwidth {
+    fn drop(&mut self) {
+        let mut inner = self.limiter.inner.lock().unwrap();
+        inner.torrents.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(policy: BandwidthPolicy) -> RateLimiter {
+        let limiter = RateLimiter::new();
+        limiter.set_limit(Some(1000));
+        limiter.set_policy(policy);
+        limiter
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = RateLimiter::new();
+        let t = limiter.register(1);
+        assert_eq!(limiter.try_consume(t.id, 1 << 30, Instant::now()), Ok(()));
+    }
+
+    #[test]
+    fn equal_share() {
+        let limiter = limiter(BandwidthPolicy::EqualShare);
+        let a = limiter.register(1);
+        let b = limiter.register(5);
+        let now = Instant::now() + Duration::from_secs(1);
+
+        // Each gets half of the bandwidth
+        assert_eq!(limiter.try_consume(a.id, 500, now), Ok(()));
+        assert_eq!(limiter.try_consume(b.id, 500, now), Ok(()));
+        assert_eq!(
+            limiter.try_consume(a.id, 100, now),
+            Err(Duration::from_millis(200))
+        );
+    }
+
+    #[test]
+    fn weighted() {
+        let limiter = limiter(BandwidthPolicy::Weighted);
+        let a = limiter.register(1);
+        let b = limiter.register(3);
+        let now = Instant::now() + Duration::from_secs(1);
+
+        assert_eq!(limiter.try_consume(a.id, 250, now), Ok(()));
+        assert_eq!(limiter.try_consume(b.id, 750, now), Ok(()));
+        assert!(limiter.try_consume(a.id, 10, now).is_err());
+        assert!(limiter.try_consume(b.id, 10, now).is_err());
+    }
+
+    #[test]
+    fn strict_order() {
+        let limiter = limiter(BandwidthPolicy::StrictOrder);
+        let a = limiter.register(1);
+        let b = limiter.register(1);
+        let now = Instant::now() + Duration::from_secs(1);
+
+        assert_eq!(limiter.try_consume(a.id, 1000, now), Ok(()));
+        assert_eq!(limiter.try_consume(b.id, 1, now), Err(IDLE_WAIT));
+
+        // Higher priority takes over
+        b.set_priority(2);
+        let now = now + Duration::from_secs(1);
+        assert_eq!(limiter.try_consume(b.id, 1000, now), Ok(()));
+        assert_eq!(limiter.try_consume(a.id, 1, now), Err(IDLE_WAIT));
+
+        // Once it's gone, the other one gets the bandwidth
+        drop(b);
+        let now = now + Duration::from_secs(1);
+        assert_eq!(limiter.try_consume(a.id, 1000, now), Ok(()));
+    }
+
+    #[test]
+    fn inactive_torrents_dont_get_a_share() {
+        let limiter = limiter(BandwidthPolicy::EqualShare);
+        let a = limiter.register(1);
+        let _b = limiter.register(1);
+        let now = Instant::now() + ACTIVE_WINDOW;
+
+        assert_eq!(limiter.try_consume(a.id, 1000, now), Ok(()));
+    }
+}
@@ -0,0 +1,328 @@
+//! Reputation of the peers, remembered across sessions.
+//!
+//! Each IP has a record of the downloads from it, the corrupt pieces it
+//! was found to have sent and how fast it sent the data. The torrents of a session
+//! connect to the peers with the best records first, and saving the
+//! records lets later sessions start with what this one learned.
+
+use crate::peer::{canonical_ip, PeerAddr};
+use anyhow::Context;
+use ben::decode::{Dict, List};
+use ben::{DictEncoder, Encode, Parser};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Number of IPs remembered. Past it the least known ones are forgotten.
+const MAX_RECORDS: usize = 10_000;
+
+/// Number of the least known IPs forgotten at once when there are too many,
+/// so that making room isn't a scan of all the records every time.
+const FORGET_BATCH: usize = MAX_RECORDS / 10;
+
+/// Corrupt pieces beyond the clean transfers which put a peer below the
+/// unknown ones. A single bad piece may be bad luck, e.g. a flipped bit.
+const CORRUPT_STRIKES: u32 = 2;
+
+/// What a peer has done for us so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerRecord {
+    /// Connections over which blocks were downloaded from the peer.
+    pub transfers: u32,
+
+    /// Pieces which failed the hash check because of blocks from the peer.
+    pub corrupt: u32,
+
+    /// Block bytes downloaded from the peer.
+    pub bytes: u64,
+
+    /// Time spent downloading them, in milliseconds.
+    pub millis: u64,
+}
+
+impl PeerRecord {
+    /// Average download rate in bytes per second.
+    pub fn avg_speed(&self) -> u64 {
+        match self.millis {
+            0 => 0,
+            millis => (self.bytes as u128 * 1000 / millis as u128) as u64,
+        }
+    }
+
+    /// Higher is better. Unknown peers score zero. Each corrupt piece
+    /// cancels out a clean transfer, and repeat offenders go below the
+    /// unknown peers however fast they were.
+    fn score(&self) -> f64 {
+        let strikes = self.corrupt.saturating_sub(self.transfers);
+        if strikes >= CORRUPT_STRIKES {
+            return -(strikes as f64);
+        }
+        if self.transfers == 0 {
+            return 0.0;
+        }
+        let clean = self.transfers.saturating_sub(self.corrupt);
+        self.avg_speed() as f64 * clean as f64 / self.transfers as f64
+    }
+
+    fn activity(&self) -> u64 {
+        self.transfers as u64 + self.corrupt as u64
+    }
+}
+
+/// Records of the peers of all the torrents in a session, keyed by IP.
+///
+/// Cloning returns a handle to the same records.
+#[derive(Debug, Clone, Default)]
+pub struct Reputation {
+    records: Arc<Mutex<HashMap<IpAddr, PeerRecord>>>,
+}
+
+impl Reputation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record of the IP, if we know it.
+    pub fn get(&self, ip: IpAddr) -> Option<PeerRecord> {
+        let records = self.records.lock().unwrap();
+        records.get(&canonical_ip(ip)).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.lock().unwrap().is_empty()
+    }
+
+    /// Record a connection which downloaded `bytes` of blocks over
+    /// `elapsed`.
+    pub fn add_transfer(&self, ip: IpAddr, bytes: u64, elapsed: Duration) {
+        self.update(ip, |r| {
+            r.transfers = r.transfers.saturating_add(1);
+            r.bytes = r.bytes.saturating_add(bytes);
+            r.millis = r.millis.saturating_add(elapsed.as_millis() as u64);
+        });
+    }
+
+    /// Record a piece which failed the hash check because of the IP, i.e.
+    /// once it's known to be the one which sent the bad blocks.
+    pub fn add_corruption(&self, ip: IpAddr) {
+        self.update(ip, |r| r.corrupt = r.corrupt.saturating_add(1));
+    }
+
+    /// Order the peers best first. Peers of the same score keep their
+    /// order.
+    pub fn sort(&self, peers: &mut [PeerAddr]) {
+        let records = self.records.lock().unwrap();
+        let score = |p: &PeerAddr| {
+            let ip = canonical_ip(p.ip());
+            records.get(&ip).map_or(0.0, |r| r.score())
+        };
+        peers.sort_by(|a, b| score(b).total_cmp(&score(a)));
+    }
+
+    fn update(&self, ip: IpAddr, f: impl FnOnce(&mut PeerRecord)) {
+        let ip = canonical_ip(ip);
+        let mut records = self.records.lock().unwrap();
+        if records.len() >= MAX_RECORDS && !records.contains_key(&ip) {
+            forget_least_known(&mut records);
+        }
+        f(records.entry(ip).or_default());
+    }
+
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let parser = &mut Parser::new();
+        let dict = parser.parse::<Dict>(data)?;
+        let list = dict.get_list("peers").context("Peers are required")?;
+
+        let mut records = HashMap::new();
+        for entry in list.iter().filter_map(|e| e.as_list()) {
+            if let Some((ip, record)) = parse_record(entry) {
+                records.insert(canonical_ip(ip), record);
+            }
+        }
+        Ok(Self {
+            records: Arc::new(Mutex::new(records)),
+        })
+    }
+
+    /// Load the records saved at `path`. Missing or invalid files give no
+    /// records.
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => return Self::new(),
+        };
+        Self::parse(&data).unwrap_or_else(|e| {
+            warn!("Invalid peer reputation data: {}", e);
+            Self::new()
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.encode_to_vec())
+    }
+}
+
+/// Forget the `FORGET_BATCH` least known IPs.
+fn forget_least_known(records: &mut HashMap<IpAddr, PeerRecord>) {
+    let mut activity: Vec<_> = records.values().map(PeerRecord::activity).collect();
+    let n = FORGET_BATCH.min(activity.len());
+    if n == 0 {
+        return;
+    }
+    let (_, &mut most, _) = activity.select_nth_unstable(n - 1);
+
+    let mut left = n;
+    records.retain(|_, r| {
+        let forget = left > 0 && r.activity() <= most;
+        left -= forget as usize;
+        !forget
+    });
+}
+
+/// `[ip, transfers, corrupt, bytes, millis]`
+fn parse_record(list: List) -> Option<(IpAddr, PeerRecord)> {
+    let ip = list.get_str(0)?.parse().ok()?;
+    let record = PeerRecord {
+        transfers: list.get_int(1)?,
+        corrupt: list.get_int(2)?,
+        bytes: list.get_int(3)?,
+        millis: list.get_int(4)?,
+    };
+    Some((ip, record))
+}
+
+impl Encode for Reputation {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let records = self.records.lock().unwrap();
+        let mut dict = DictEncoder::new(buf);
+        let mut list = dict.insert_list("peers");
+        for (ip, r) in records.iter() {
+            let mut l = list.push_list();
+            l.push(ip.to_string());
+            l.push(r.transfers as i64);
+            l.push(r.corrupt as i64);
+            l.push(r.bytes.min(i64::MAX as u64) as i64);
+            l.push(r.millis.min(i64::MAX as u64) as i64);
+            l.finish();
+        }
+        list.finish();
+        dict.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn peer(s: &str) -> PeerAddr {
+        PeerAddr::new(s.parse::<SocketAddr>().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn records_add_up() {
+        let rep = Reputation::new();
+        rep.add_transfer(ip("1.2.3.4"), 1000, Duration::from_secs(1));
+        rep.add_transfer(ip("::ffff:1.2.3.4"), 3000, Duration::from_secs(1));
+        rep.add_corruption(ip("5.6.7.8"));
+
+        let r = rep.get(ip("1.2.3.4")).unwrap();
+        assert_eq!(r.transfers, 2);
+        assert_eq!(r.avg_speed(), 2000);
+        assert_eq!(rep.get(ip("5.6.7.8")).unwrap().corrupt, 1);
+        assert_eq!(rep.get(ip("9.9.9.9")), None);
+    }
+
+    #[test]
+    fn good_peers_first() {
+        let rep = Reputation::new();
+        rep.add_transfer(ip("1.1.1.1"), 1000, Duration::from_secs(1));
+        rep.add_transfer(ip("2.2.2.2"), 5000, Duration::from_secs(1));
+        rep.add_transfer(ip("3.3.3.3"), 9000, Duration::from_secs(1));
+        for _ in 0..3 {
+            rep.add_corruption(ip("3.3.3.3"));
+        }
+
+        let mut peers = vec![
+            peer("3.3.3.3:1"),
+            peer("4.4.4.4:1"),
+            peer("1.1.1.1:1"),
+            peer("5.5.5.5:1"),
+            peer("2.2.2.2:1"),
+        ];
+        rep.sort(&mut peers);
+        let order: Vec<_> = peers.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            order,
+            [
+                "2.2.2.2:1",
+                "1.1.1.1:1",
+                "4.4.4.4:1",
+                "5.5.5.5:1",
+                "3.3.3.3:1"
+            ]
+        );
+    }
+
+    #[test]
+    fn single_corruption_is_forgiven() {
+        let rep = Reputation::new();
+        rep.add_corruption(ip("1.1.1.1"));
+        rep.add_transfer(ip("2.2.2.2"), 8000, Duration::from_secs(1));
+        rep.add_transfer(ip("2.2.2.2"), 8000, Duration::from_secs(1));
+        rep.add_corruption(ip("2.2.2.2"));
+
+        let mut peers = vec![peer("1.1.1.1:1"), peer("3.3.3.3:1"), peer("2.2.2.2:1")];
+        rep.sort(&mut peers);
+        let order: Vec<_> = peers.iter().map(|p| p.to_string()).collect();
+        assert_eq!(order, ["2.2.2.2:1", "1.1.1.1:1", "3.3.3.3:1"]);
+    }
+
+    #[test]
+    fn least_known_are_forgotten() {
+        let rep = Reputation::new();
+        for i in 0..MAX_RECORDS as u32 {
+            let ip = IpAddr::from((i + 1).to_be_bytes());
+            rep.add_transfer(ip, 1000, Duration::from_secs(1));
+            if i % 2 == 0 {
+                rep.add_transfer(ip, 1000, Duration::from_secs(1));
+            }
+        }
+        assert_eq!(rep.len(), MAX_RECORDS);
+
+        rep.add_corruption(ip("255.1.1.1"));
+        assert_eq!(rep.len(), MAX_RECORDS - FORGET_BATCH + 1);
+        let records = rep.records.lock().unwrap();
+        let known = records.values().filter(|r| r.transfers == 2).count();
+        assert_eq!(known, MAX_RECORDS / 2);
+    }
+
+    #[test]
+    fn encode_parse() {
+        let rep = Reputation::new();
+        rep.add_transfer(ip("1.2.3.4"), 1 << 40, Duration::from_millis(1500));
+        rep.add_corruption(ip("2001:db8::1"));
+
+        let parsed = Reputation::parse(&rep.encode_to_vec()).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed.get(ip("1.2.3.4")), rep.get(ip("1.2.3.4")));
+        assert_eq!(parsed.get(ip("2001:db8::1")).unwrap().corrupt, 1);
+
+        // Broken records are skipped
+        let parsed = Reputation::parse(b"d5:peersll7:1.2.3.4i1ei0ei10ei5eel3:badeee").unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert!(Reputation::parse(b"de").is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use crate::work::{PartialPiece, PieceInfo, BLOCK_SIZE};
+use anyhow::Context;
+use ben::decode::Dict;
+use ben::{DictEncoder, Encode, Parser};
+use client::bitfield::Bitfield;
+use client::InfoHash;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// State of a torrent download which is saved on shutdown so that the download
+/// can be resumed later without starting from scratch.
+#[derive(Debug)]
+pub struct ResumeData {
+    pub info_hash: InfoHash,
+
+    /// Pieces which are downloaded, verified and written to the storage.
+    pub have: Bitfield,
+
+    /// Pieces which are only partially downloaded. Only the blocks marked in
+    /// their block bitmap contain valid data.
+    pub partial: Vec<PartialPiece>,
+
+    /// Peers we have exchanged data with, to connect to on startup before
+    /// the trackers and the DHT have answered.
+    pub peers: Vec<SocketAddr>,
+
+    /// Pieces of `have` which were written last, the most recent last.
+    /// A crash is most likely to have cut their writes short, so the spot
+    /// check on resume always hashes them.
+    pub recent: Vec<u32>,
+}
+
+impl ResumeData {
+    pub fn parse(data: &[u8], num_pieces: usize) -> anyhow::Result<Self> {
+        let parser = &mut Parser::new();
+        let dict = parser.parse::<Dict>(data)?;
+
+        let info_hash: InfoHash = dict
+            .get_bytes("info_hash")
+            .and_then(|b| b.try_into().ok())
+            .context("Info hash is required")?;
+
+        let mut have = Bitfield::new();
+        have.copy_from_slice(dict.get_bytes("pieces").context("Pieces are required")?);
+        have.resize(num_pieces);
+
+        let mut partial = vec![];
+        if let Some(list) = dict.get_list("partial") {
+            for p in list.iter().filter_map(|p| p.as_dict()) {
+                partial.push(parse_partial(p)?);
+            }
+        }
+
+        let peers = dict
+            .get_list("peers")
+            .map(|list| {
+                list.iter()
+                    .filter_map(|p| p.as_str())
+                    .filter_map(|p| p.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let recent = dict
+            .get_list("recent")
+            .map(|list| list.iter().filter_map(|i| i.as_int()).collect())
+            .unwrap_or_default();
+
+        Ok(Self {
+            info_hash,
+            have,
+            partial,
+            peers,
+            recent,
+        })
+    }
+
+    /// Load the resume data from given path if it exists and belongs to the given torrent.
+    pub fn load(path: impl AsRef<Path>, info_hash: &InfoHash, num_pieces: usize) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        match Self::parse(&data, num_pieces) {
+            Ok(r) if r.info_hash == *info_hash => Some(r),
+            Ok(_) => {
+                warn!("Resume data belongs to a different torrent");
+                None
+            }
+            Err(e) => {
+                warn!("Invalid resume data: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.encode_to_vec())
+    }
+}
+
+fn parse_partial(dict: Dict) -> anyhow::Result<PartialPiece> {
+    let index = dict.get_int("index").context("Piece index is required")?;
+    let data = dict.get_bytes("data").context("Piece data is required")?;
+    let blocks = dict
+        .get_bytes("blocks")
+        .context("Piece blocks are required")?;
+
+    let mut piece = PartialPiece::new(PieceInfo {
+        index,
+        len: data.len() as u32,
+    });
+
+    let mut have = Bitfield::new();
+    have.copy_from_slice(blocks);
+
+    for (i, block) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+        if have.get_bit(i) {
+            piece.write_block(i as u32 * BLOCK_SIZE, block);
+        }
+    }
+    Ok(piece)
+}
+
+impl Encode for ResumeData {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let mut dict = DictEncoder::new(buf);
+        dict.insert("info_hash", self.info_hash);
+
+        let mut list = dict.insert_list("partial");
+        for p in &self.partial {
+            let mut d = list.push_dict();
+            d.insert("blocks", p.blocks.as_bytes());
+            d.insert("data", &p.to_buf()[..]);
+            d.insert("index", p.info.index as i64);
+            d.finish();
+        }
+        list.finish();
+
+        let mut list = dict.insert_list("peers");
+        for p in &self.peers {
+            list.push(p.to_string());
+        }
+        list.finish();
+
+        dict.insert("pieces", self.have.as_bytes());
+
+        let mut list = dict.insert_list("recent");
+        for &i in &self.recent {
+            list.push(i as i64);
+        }
+        list.finish();
+        dict.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_parse() {
+        let mut have = Bitfield::with_size(10);
+        have.set_bit(3);
+
+        let mut p = PartialPiece::new(PieceInfo {
+            index: 4,
+            len: BLOCK_SIZE * 2,
+        });
+        assert!(p.write_block(BLOCK_SIZE, &[7; BLOCK_SIZE as usize]));
+
+        let resume = ResumeData {
+            info_hash: [1; 20],
+            have,
+            partial: vec![p],
+            peers: vec![
+                "1.2.3.4:6881".parse().unwrap(),
+                "[::1]:51413".parse().unwrap(),
+            ],
+            recent: vec![3],
+        };
+
+        let parsed = ResumeData::parse(&resume.encode_to_vec(), 10).unwrap();
+        assert_eq!(parsed.info_hash, [1; 20]);
+        assert_eq!(parsed.have.len(), 10);
+        assert!(parsed.have.get_bit(3));
+        assert_eq!(parsed.have.count(), 1);
+
+        assert_eq!(parsed.partial.len(), 1);
+        let p = &parsed.partial[0];
+        assert_eq!(
+            p.info,
+            PieceInfo {
+                index: 4,
+                len: BLOCK_SIZE * 2
+            }
+        );
+        assert!(!p.has_block(0));
+        assert!(p.has_block(BLOCK_SIZE));
+        assert_eq!(p.block(1), Some(&[7; BLOCK_SIZE as usize][..]));
+
+        assert_eq!(parsed.peers, resume.peers);
+        assert_eq!(parsed.recent, [3]);
+    }
+
+    #[test]
+    fn parse_without_peers() {
+        let data = b"d9:info_hash20:\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x016:pieces1:\x10e";
+        let parsed = ResumeData::parse(data, 4).unwrap();
+        assert!(parsed.have.get_bit(3));
+        assert!(parsed.peers.is_empty());
+        assert!(parsed.recent.is_empty());
+    }
+}
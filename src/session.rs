@@ -0,0 +1,322 @@
+use crate::announce::{AnnounceParams, DhtTracker, DEFAULT_PORT};
+use crate::blocklist::Blocklist;
+use crate::cache::ReadCache;
+use crate::config::SessionConfig;
+use crate::iplimit::IpConnections;
+use crate::peer::{DualStack, Identity, Reachability};
+use crate::ratelimit::{BandwidthPolicy, RateLimiter};
+use crate::reputation::Reputation;
+use crate::traffic::Traffic;
+use crate::{TorrentHandle, TorrentWorker};
+use client::buf::BufBudget;
+use client::torrent::Torrent;
+use dht::KeepaliveConfig;
+use std::net::Ipv6Addr;
+use std::sync::atomic::{AtomicU16, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+struct ListenPort {
+    port: AtomicU16,
+
+    /// Torrents told about changes of the port
+    torrents: Mutex<Vec<TorrentHandle>>,
+}
+
+impl Default for ListenPort {
+    fn default() -> Self {
+        Self {
+            port: AtomicU16::new(DEFAULT_PORT),
+            torrents: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+/// State shared between all the torrents downloaded together.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    blocklist: Blocklist,
+    rate_limiter: RateLimiter,
+    identity: Identity,
+    ip_connections: IpConnections,
+    read_cache: ReadCache,
+    listen_port: Arc<ListenPort>,
+    reachability: Arc<Mutex<Reachability>>,
+    traffic: Traffic,
+    recv_budget: BufBudget,
+    reputation: Reputation,
+    dual_stack: DualStack,
+    announce_params: AnnounceParams,
+    local_ipv6: Arc<Mutex<Option<Ipv6Addr>>>,
+
+    /// Keepalive of the DHT trackers of the torrents added from now on,
+    /// `None` to leave them as they are
+    dht_keepalive: Option<bool>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Session presenting itself with `identity` in the handshakes and the
+    /// tracker announces of all its torrents.
+    pub fn with_identity(identity: Identity) -> Self {
+        Self {
+            identity,
+            ..Self::default()
+        }
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Peers banned in any of the torrents of this session.
+    pub fn blocklist(&self) -> &Blocklist {
+        &self.blocklist
+    }
+
+    /// Connections to each peer IP across the torrents of this session.
+    pub fn ip_connections(&self) -> &IpConnections {
+        &self.ip_connections
+    }
+
+    /// Limit the number of connections to the same IP, counting those of
+    /// all the torrents.
+    pub fn set_max_connections_per_ip(&self, max: usize) {
+        self.ip_connections.set_max_per_ip(max);
+    }
+
+    /// Limit the number of connections to the same /24 for IPv4 or /48 for
+    /// IPv6, counting those of all the torrents.
+    pub fn set_max_connections_per_subnet(&self, max: usize) {
+        self.ip_connections.set_max_per_subnet(max);
+    }
+
+    /// Records of the peers of all the torrents, used to connect to the
+    /// good ones first. Save them on shutdown and pass them to
+    /// [`Session::set_reputation`] in the next session.
+    pub fn reputation(&self) -> &Reputation {
+        &self.reputation
+    }
+
+    /// Peers of all the torrents known to be reachable over both IPv4 and
+    /// IPv6, which are dialed at both addresses at once.
+    pub fn dual_stack(&self) -> &DualStack {
+        &self.dual_stack
+    }
+
+    /// Start from the peer records of an earlier session. Applies to the
+    /// torrents added from now on.
+    pub fn set_reputation(&mut self, reputation: Reputation) {
+        self.reputation = reputation;
+    }
+
+    /// Cache of the pieces read for uploading, shared by the torrents of
+    /// this session. Wrap their sinks in a `CachedSink` to use it.
+    pub fn read_cache(&self) -> &ReadCache {
+        &self.read_cache
+    }
+
+    /// Set the size of the read cache in bytes.
+    pub fn set_read_cache_size(&self, size: usize) {
+        self.read_cache.set_capacity(size);
+    }
+
+    /// Address families the torrents dial peers over, both of them unless
+    /// set otherwise.
+    pub fn reachability(&self) -> Reachability {
+        *self.reachability.lock().unwrap()
+    }
+
+    /// Set the address families to dial peers over, e.g. those found with
+    /// [`Reachability::detect`] or after the network changes. Applies to
+    /// the running torrents too.
+    pub fn set_reachability(&self, reachability: Reachability) {
+        *self.reachability.lock().unwrap() = reachability;
+    }
+
+    /// Our global IPv6 address, which the trackers of the torrents started
+    /// from now on are told.
+    pub fn local_ipv6(&self) -> Option<Ipv6Addr> {
+        *self.local_ipv6.lock().unwrap()
+    }
+
+    /// Set our global IPv6 address, e.g. found with
+    /// [`local_ipv6`](crate::peer::local_ipv6) at startup.
+    pub fn set_local_ipv6(&self, ip: Option<Ipv6Addr>) {
+        *self.local_ipv6.lock().unwrap() = ip;
+    }
+
+    /// Port the torrents of this session announce as the one they listen
+    /// on.
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port.port.load(Relaxed)
+    }
+
+    /// Change the port announced by the torrents, e.g. after the user picks
+    /// another one or a router maps a different external port. Running
+    /// torrents re-announce to their trackers and the DHT right away.
+    pub fn set_listen_port(&self, port: u16) {
+        let mut torrents = self.listen_port.torrents.lock().unwrap();
+        self.listen_port.port.store(port, Relaxed);
+        torrents.retain(|t| !t.is_closed());
+        for t in torrents.iter() {
+            t.set_port(port);
+        }
+    }
+
+    /// Tell the torrent about changes of the listen port. Returns the
+    /// current port.
+    pub(crate) fn watch_port(&self, handle: TorrentHandle) -> u16 {
+        let mut torrents = self.listen_port.torrents.lock().unwrap();
+        torrents.push(handle);
+        self.listen_port()
+    }
+
+    /// Stop telling the torrent about changes of the listen port, once it's
+    /// removed.
+    pub(crate) fn unwatch_port(&self, handle: &TorrentHandle) {
+        let mut torrents = self.listen_port.torrents.lock().unwrap();
+        torrents.retain(|t| !t.is_same(handle));
+    }
+
+    /// Extra parameters the torrents send in their HTTP announces.
+    pub fn announce_params(&self) -> AnnounceParams {
+        self.announce_params
+    }
+
+    /// Send the extra parameters in the HTTP announces, e.g. those a
+    /// private tracker requires. Applies to the torrents added from now on.
+    pub fn set_announce_params(&mut self, params: AnnounceParams) {
+        self.announce_params = params;
+    }
+
+    /// Bytes transferred by the torrents of this session, broken down into
+    /// block data, peer protocol overhead, tracker and DHT traffic.
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Memory of the receive buffers of the peer connections of all the
+    /// torrents.
+    pub fn recv_budget(&self) -> &BufBudget {
+        &self.recv_budget
+    }
+
+    /// Set the size the receive buffer of each connection grows to ahead
+    /// of the data. 1 MiB by default.
+    pub fn set_max_recv_buffer(&self, size: usize) {
+        self.recv_budget.set_max_buf_size(size);
+    }
+
+    /// Limit the memory of the receive buffers of all the connections.
+    /// Buffers stop growing ahead of the data past it, though each still
+    /// fits the packet it's reading. `None` removes the limit.
+    pub fn set_recv_buffer_limit(&self, limit: Option<usize>) {
+        self.recv_budget.set_limit(limit);
+    }
+
+    /// Download rate limiter shared by the torrents of this session.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Limit the total download rate of all the torrents in bytes per second.
+    /// `None` removes the limit.
+    pub fn set_download_limit(&self, limit: Option<u64>) {
+        self.rate_limiter.set_limit(limit);
+    }
+
+    /// Set how the download bandwidth is divided between the torrents.
+    pub fn set_bandwidth_policy(&self, policy: BandwidthPolicy) {
+        self.rate_limiter.set_policy(policy);
+    }
+
+    /// Apply the settings of the config, e.g. loaded on startup. Settings
+    /// which only affect new torrents apply to the torrents added from now
+    /// on.
+    pub fn apply_config(&mut self, config: &SessionConfig) {
+        self.reload_config(config);
+
+        let params = &mut self.announce_params;
+        let flags = [
+            (&mut params.no_peer_id, config.no_peer_id),
+            (&mut params.report_corrupt, config.report_corrupt),
+        ];
+        for (flag, value) in flags {
+            if let Some(value) = value {
+                *flag = value;
+            }
+        }
+        if config.dht_keepalive.is_some() {
+            self.dht_keepalive = config.dht_keepalive;
+        }
+    }
+
+    /// Apply the settings of the config which the running torrents pick up
+    /// right away: the listen port, the limits and the buffer sizes. Use it
+    /// to reload the config while the session runs.
+    pub fn reload_config(&self, config: &SessionConfig) {
+        if let Some(port) = config.listen_port {
+            if port != self.listen_port() {
+                self.set_listen_port(port);
+            }
+        }
+        if let Some(limit) = config.download_limit {
+            self.set_download_limit(Some(limit).filter(|&l| l > 0));
+        }
+        if let Some(max) = config.max_connections_per_ip {
+            self.set_max_connections_per_ip(max);
+        }
+        if let Some(max) = config.max_connections_per_subnet {
+            self.set_max_connections_per_subnet(max);
+        }
+        if let Some(size) = config.read_cache_size {
+            self.set_read_cache_size(size);
+        }
+        if let Some(size) = config.max_recv_buffer {
+            self.set_max_recv_buffer(size);
+        }
+        if let Some(limit) = config.recv_buffer_limit {
+            self.set_recv_buffer_limit(Some(limit).filter(|&l| l > 0));
+        }
+    }
+
+    /// Create a worker for the torrent which is part of this session, with
+    /// a peer id of the session's identity.
+    pub fn add_torrent(&self, torrent: Torrent, mut dht: DhtTracker) -> TorrentWorker {
+        if let Some(enable) = self.dht_keepalive {
+            dht.set_keepalive(enable.then(KeepaliveConfig::default));
+        }
+        let peer_id = self.identity.generate_peer_id();
+        TorrentWorker::with_session(self.clone(), torrent, peer_id, dht)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Swarm;
+
+    #[test]
+    fn removed_torrents_stop_watching_the_port() {
+        let session = Session::new();
+        let swarm = Swarm::new(2 * 16384, 16384);
+        let worker = |session: &Session| {
+            let peer_id = session.identity().generate_peer_id();
+            TorrentWorker::without_dht(session.clone(), swarm.torrent(), peer_id)
+        };
+        let watched = || session.listen_port.torrents.lock().unwrap().len();
+
+        let first = worker(&session);
+        let second = worker(&session);
+        assert_eq!(watched(), 2);
+
+        drop(first);
+        assert_eq!(watched(), 1);
+        drop(second);
+        assert_eq!(watched(), 0);
+    }
+}
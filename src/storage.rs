@@ -1,6 +1,366 @@
 use crate::work::Piece;
+use crate::worker::{PieceReader, TorrentHandle};
+use client::bitfield::Bitfield;
+use client::metainfo::FileMap;
+use futures::future::{self, BoxFuture};
+use futures::{ready, FutureExt, Stream, StreamExt, TryStream, TryStreamExt};
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Destination of the downloaded and verified pieces.
+///
+/// Implement this to keep the pieces somewhere other than a local file,
+/// e.g. an object store or a database. [`StorageWriter`] is the default
+/// implementation on top of a [`Storage`].
+pub trait PieceSink {
+    /// Store a verified piece.
+    fn write_piece(&mut self, piece: Piece) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Fill `buf` with the data of piece `index` starting at `begin`.
+    fn read_block<'a>(
+        &'a mut self,
+        index: u32,
+        begin: u32,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Make sure that the pieces written so far are persisted.
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// Write the pieces received from the torrent worker to `sink` until the
+/// channel is closed. `have` is updated with the pieces written and
+/// returned at the end.
+///
+/// The receiver is mapped with `Ok`, or wrapped in [`OrderedPieces`] if
+/// the sink needs the pieces in order.
+pub async fn write_pieces<S, P>(
+    sink: &mut S,
+    mut have: Bitfield,
+    mut piece_rx: P,
+) -> io::Result<Bitfield>
+where
+    S: PieceSink,
+    P: TryStream<Ok = Piece, Error = io::Error> + Unpin,
+{
+    while let Some(piece) = piece_rx.try_next().await? {
+        let index = piece.index as usize;
+        if have.get_bit(index) {
+            error!("Duplicate piece downloaded: {}", index);
+        }
+
+        sink.write_piece(piece).await?;
+        have.set_bit(index);
+    }
+
+    sink.flush().await?;
+    Ok(have)
+}
+
+/// Like [`write_pieces`], but a failed write doesn't stop the writing.
+/// The error is handed to `on_error` along with the index of the piece,
+/// which isn't set in `have`, e.g. to pause the torrent with
+/// `TorrentHandle::storage_failed` until the storage is usable again.
+/// An error of the receiver is handed over without an index and ends the
+/// writing.
+pub async fn write_pieces_reporting<S, P, F>(
+    sink: &mut S,
+    mut have: Bitfield,
+    mut piece_rx: P,
+    mut on_error: F,
+) -> Bitfield
+where
+    S: PieceSink,
+    P: TryStream<Ok = Piece, Error = io::Error> + Unpin,
+    F: FnMut(Option<u32>, io::Error),
+{
+    loop {
+        let piece = match piece_rx.try_next().await {
+            Ok(Some(piece)) => piece,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Receiving the pieces failed: {}", e);
+                on_error(None, e);
+                break;
+            }
+        };
+        let index = piece.index;
+        if have.get_bit(index as usize) {
+            error!("Duplicate piece downloaded: {}", index);
+        }
+
+        match sink.write_piece(piece).await {
+            Ok(()) => have.set_bit(index as usize),
+            Err(e) => {
+                warn!("Writing piece {} failed: {}", index, e);
+                on_error(Some(index), e);
+            }
+        }
+    }
+
+    if let Err(e) = sink.flush().await {
+        warn!("Flushing the storage failed: {}", e);
+        on_error(None, e);
+    }
+    have
+}
+
+/// Like [`write_pieces_reporting`], for the torrent of `handle`: the
+/// failures are reported to its worker, which pauses the torrent, and
+/// once all the pieces are written and flushed the worker is told so. Its
+/// completion hook waits for that.
+pub async fn write_torrent_pieces<S, P>(
+    sink: &mut S,
+    have: Bitfield,
+    piece_rx: P,
+    handle: &TorrentHandle,
+) -> Bitfield
+where
+    S: PieceSink,
+    P: TryStream<Ok = Piece, Error = io::Error> + Unpin,
+{
+    let mut failed = false;
+    let on_error = |index: Option<u32>, e: io::Error| {
+        failed = true;
+        handle.storage_failed(index, &e);
+    };
+    let have = write_pieces_reporting(sink, have, piece_rx, on_error).await;
+    if !failed {
+        handle.storage_flushed();
+    }
+    have
+}
+
+/// Why the storage failed, to tell whether waiting can help.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageErrorKind {
+    /// Out of space or over the quota. Writes succeed again once some space
+    /// is freed.
+    DiskFull,
+
+    /// The device failed to read or write.
+    Io,
+
+    Other,
+}
+
+impl StorageErrorKind {
+    pub fn of(e: &io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(code) if DISK_FULL_ERRORS.contains(&code) => Self::DiskFull,
+            Some(IO_ERROR) => Self::Io,
+            _ => Self::Other,
+        }
+    }
+}
+
+// ENOSPC and EDQUOT
+#[cfg(target_os = "linux")]
+const DISK_FULL_ERRORS: [i32; 2] = [28, 122];
+#[cfg(all(unix, not(target_os = "linux")))]
+const DISK_FULL_ERRORS: [i32; 2] = [28, 69];
+
+// EIO
+#[cfg(unix)]
+const IO_ERROR: i32 = 5;
+
+// ERROR_HANDLE_DISK_FULL and ERROR_DISK_FULL
+#[cfg(windows)]
+const DISK_FULL_ERRORS: [i32; 2] = [39, 112];
+
+// ERROR_IO_DEVICE
+#[cfg(windows)]
+const IO_ERROR: i32 = 1117;
+
+/// Stream of verified pieces in index order.
+///
+/// Pieces which arrive early are held in memory until the ones before them
+/// are received. Holding more than `max_pending` of them is an error, as
+/// is the end of `inner` while some are still waiting. Pieces set in
+/// `have` are skipped.
+pub struct OrderedPieces<S> {
+    inner: S,
+    have: Bitfield,
+    next: usize,
+    pending: BTreeMap<u32, Piece>,
+    max_pending: usize,
+}
+
+impl<S> OrderedPieces<S> {
+    pub fn new(inner: S, have: Bitfield, max_pending: usize) -> Self {
+        let mut ordered = Self {
+            inner,
+            have,
+            next: 0,
+            pending: BTreeMap::new(),
+            max_pending,
+        };
+        ordered.skip_have();
+        ordered
+    }
+
+    /// Number of pieces waiting for the ones before them.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn skip_have(&mut self) {
+        while self.next < self.have.len() && self.have.get_bit(self.next) {
+            self.next += 1;
+        }
+    }
+
+    fn pop_next(&mut self) -> Option<Piece> {
+        let piece = self.pending.remove(&(self.next as u32))?;
+        self.next += 1;
+        self.skip_have();
+        Some(piece)
+    }
+}
+
+impl<S: Stream<Item = Piece> + Unpin> Stream for OrderedPieces<S> {
+    type Item = io::Result<Piece>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(piece) = self.pop_next() {
+                return Poll::Ready(Some(Ok(piece)));
+            }
+
+            match ready!(self.inner.poll_next_unpin(cx)) {
+                Some(piece) => {
+                    let index = piece.index as usize;
+                    if index < self.next || self.have.get_bit(index) {
+                        warn!("Piece {} is already written; dropping", index);
+                    } else if self.pending.len() >= self.max_pending {
+                        let msg = format!(
+                            "{} pieces are waiting for piece {}",
+                            self.pending.len() + 1,
+                            self.next
+                        );
+                        return Poll::Ready(Some(Err(io::Error::other(msg))));
+                    } else {
+                        self.pending.insert(piece.index, piece);
+                    }
+                }
+                None if self.pending.is_empty() => return Poll::Ready(None),
+                None => {
+                    let msg = format!(
+                        "Pieces ended with {} of them waiting for piece {}",
+                        self.pending.len(),
+                        self.next
+                    );
+                    self.pending.clear();
+                    return Poll::Ready(Some(Err(io::Error::other(msg))));
+                }
+            }
+        }
+    }
+}
+
+/// Writes the pieces one after another to a target that can't seek, e.g. a
+/// pipe or an upload. The pieces must come in order, like the pieces of an
+/// [`OrderedPieces`] with the same `have`.
+///
+/// The pieces set in `have` up to the first one missing are taken to be
+/// written already, e.g. by the previous run. The later ones aren't
+/// downloaded again, so they're read back with the reader of
+/// [`with_reader`](Self::with_reader) when their turn comes, and without
+/// one they're an error.
+pub struct SequentialWriter<W> {
+    inner: W,
+    have: Bitfield,
+    next: usize,
+    reader: Option<PieceReader>,
+}
+
+impl<W: Write> SequentialWriter<W> {
+    pub fn new(inner: W, have: Bitfield) -> Self {
+        let mut next = 0;
+        while next < have.len() && have.get_bit(next) {
+            next += 1;
+        }
+        Self {
+            inner,
+            have,
+            next,
+            reader: None,
+        }
+    }
+
+    pub fn with_reader(inner: W, have: Bitfield, reader: PieceReader) -> Self {
+        Self {
+            reader: Some(reader),
+            ..Self::new(inner, have)
+        }
+    }
+
+    /// Write the next piece. The pieces set in `have` before it must have
+    /// been written with [`write_have`](Self::write_have).
+    pub fn insert(&mut self, piece: Piece) -> io::Result<()> {
+        if piece.index as usize != self.next {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected piece {}, got {}", self.next, piece.index),
+            ));
+        }
+
+        self.inner.write_all(&piece.buf)?;
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Write the pieces set in `have` from the next one up to `end`,
+    /// reading them back with the reader.
+    pub async fn write_have(&mut self, end: usize) -> io::Result<()> {
+        while self.next < end.min(self.have.len()) && self.have.get_bit(self.next) {
+            let Some(reader) = &self.reader else {
+                let msg = format!("piece {} is only in the storage", self.next);
+                return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+            };
+            let buf = reader(self.next as u32).await?;
+            self.inner.write_all(&buf)?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write + Send> PieceSink for SequentialWriter<W> {
+    fn write_piece(&mut self, piece: Piece) -> BoxFuture<'_, io::Result<()>> {
+        async move {
+            self.write_have(piece.index as usize).await?;
+            self.insert(piece)
+        }
+        .boxed()
+    }
+
+    fn read_block<'a>(
+        &'a mut self,
+        _index: u32,
+        _begin: u32,
+        _buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let e = io::Error::new(io::ErrorKind::Unsupported, "sequential writer can't read");
+        future::ready(Err(e)).boxed()
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        async move {
+            self.write_have(self.have.len()).await?;
+            self.inner.flush()
+        }
+        .boxed()
+    }
+}
 
 pub struct StorageWriter<T> {
     inner: T,
@@ -25,6 +385,11 @@ impl<T: Storage> StorageWriter<T> {
         Ok(())
     }
 
+    pub fn read_block(&self, index: u32, begin: u32, buf: &mut [u8]) -> io::Result<()> {
+        let offset = self.index_to_offset(index) + begin as u64;
+        self.inner.read_exact_at(buf, offset)
+    }
+
     pub fn into_inner(self) -> T {
         self.inner
     }
@@ -34,6 +399,53 @@ impl<T: Storage> StorageWriter<T> {
     }
 }
 
+impl<T: Storage> PieceSink for StorageWriter<T> {
+    fn write_piece(&mut self, piece: Piece) -> BoxFuture<'_, io::Result<()>> {
+        future::ready(self.insert(piece)).boxed()
+    }
+
+    fn read_block<'a>(
+        &'a mut self,
+        index: u32,
+        begin: u32,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<()>> {
+        future::ready(StorageWriter::read_block(self, index, begin, buf)).boxed()
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        future::ready(self.inner.flush()).boxed()
+    }
+}
+
+/// [`PieceReader`] reading the pieces of a torrent of `len` bytes from
+/// `storage`, where they lie one after another like [`StorageWriter`]
+/// writes them. The reads run on the blocking threads of the runtime.
+pub fn piece_reader<T>(storage: T, piece_len: usize, len: u64) -> PieceReader
+where
+    T: Storage + Send + Sync + 'static,
+{
+    let storage = Arc::new(storage);
+    Arc::new(move |index| {
+        let storage = storage.clone();
+        let offset = piece_len as u64 * index as u64;
+        async move {
+            if offset >= len {
+                let msg = format!("Piece {} is out of range", index);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+            let piece_len = (len - offset).min(piece_len as u64) as usize;
+            let read = tokio::task::spawn_blocking(move || {
+                let mut buf = vec![0; piece_len];
+                storage.read_exact_at(&mut buf, offset)?;
+                Ok(buf)
+            });
+            read.await?
+        }
+        .boxed()
+    })
+}
+
 /// Storage
 pub trait Storage {
     /// Reads a number of bytes starting from a given offset.
@@ -46,6 +458,11 @@ pub trait Storage {
     /// Returns the number of bytes written.
     fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize>;
 
+    /// Persists the data written so far.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
     fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
         while !buf.is_empty() {
             match self.read_at(buf, offset) {
@@ -90,13 +507,91 @@ pub trait Storage {
     }
 }
 
+/// [`Storage`] of a torrent with padding files (BEP 47), which are left
+/// out of `inner`. The other files lie one after another in it, the
+/// padding reads as zeros and writes to it are dropped.
+pub struct Unpadded<T> {
+    inner: T,
+    files: FileMap,
+
+    /// Offset of each file in `inner`
+    stored_starts: Vec<u64>,
+}
+
+impl<T> Unpadded<T> {
+    pub fn new(inner: T, files: FileMap) -> Self {
+        let mut stored_starts = Vec::with_capacity(files.num_files());
+        let mut stored_len = 0;
+        for file in 0..files.num_files() {
+            stored_starts.push(stored_len);
+            if !files.is_padding(file) {
+                stored_len += files.file_len(file);
+            }
+        }
+        Self {
+            inner,
+            files,
+            stored_starts,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// File at `offset` in the torrent, the offset within it and how many
+    /// of `len` bytes from there fall in it.
+    fn locate(&self, offset: u64, len: usize) -> Option<(usize, u64, usize)> {
+        let (file, pos) = self.files.file_at(offset)?;
+        let len = (self.files.file_len(file) - pos).min(len as u64) as usize;
+        Some((file, pos, len))
+    }
+}
+
+impl<T: Storage> Storage for Unpadded<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let Some((file, pos, len)) = self.locate(offset, buf.len()) else {
+            return Ok(0);
+        };
+        if self.files.is_padding(file) {
+            buf[..len].fill(0);
+            return Ok(len);
+        }
+        let offset = self.stored_starts[file] + pos;
+        self.inner.read_at(&mut buf[..len], offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let Some((file, pos, len)) = self.locate(offset, buf.len()) else {
+            return Ok(0);
+        };
+        if self.files.is_padding(file) {
+            return Ok(len);
+        }
+        let offset = self.stored_starts[file] + pos;
+        self.inner.write_at(&buf[..len], offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 impl<T: Storage> Storage for &mut T {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
-        (&**self).read_at(buf, offset)
+        (**self).read_at(buf, offset)
     }
 
     fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
-        (&mut **self).write_at(buf, offset)
+        (**self).write_at(buf, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
     }
 }
 
@@ -109,6 +604,10 @@ impl Storage for File {
     fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
         std::os::unix::fs::FileExt::write_at(self, buf, offset)
     }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
 }
 
 #[cfg(windows)]
@@ -120,6 +619,10 @@ impl Storage for File {
     fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
         std::os::windows::fs::FileExt::seek_write(self, buf, offset)
     }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
 }
 
 impl Storage for Vec<u8> {
@@ -149,6 +652,8 @@ impl Storage for Vec<u8> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::channel::mpsc;
+    use futures::{stream, SinkExt};
     use std::env::temp_dir;
     use std::fs::{File, OpenOptions};
     use std::str;
@@ -214,4 +719,191 @@ mod tests {
         }
         check!(std::fs::remove_file(&filename));
     }
+
+    #[test]
+    fn padding_is_not_stored() {
+        let files = FileMap::with_padding(4, [(3, false), (1, true), (0, false), (5, false)]);
+        let mut storage = Unpadded::new(vec![], files);
+        check!(storage.write_all_at(b"abc\xffdefgh", 0));
+        assert_eq!(storage.inner, b"abcdefgh");
+
+        let mut buf = [1; 9];
+        check!(storage.read_exact_at(&mut buf, 0));
+        assert_eq!(&buf, b"abc\0defgh");
+        assert_eq!(check!(storage.read_at(&mut buf, 9)), 0);
+    }
+
+    #[tokio::test]
+    async fn piece_sink() {
+        let mut sink = StorageWriter::new(vec![], 4);
+        let (mut tx, rx) = mpsc::channel(2);
+        let write = async {
+            for (index, data) in [(1, b"efgh"), (0, b"abcd")] {
+                let buf = data.to_vec().into_boxed_slice();
+                tx.send(Piece::new(index, buf)).await.unwrap();
+            }
+            drop(tx);
+        };
+
+        let (have, ()) = futures::join!(
+            write_pieces(&mut sink, Bitfield::with_size(3), rx.map(Ok)),
+            write
+        );
+        let have = have.unwrap();
+        assert!(have.get_bit(0) && have.get_bit(1) && !have.get_bit(2));
+
+        let mut buf = [0; 3];
+        PieceSink::read_block(&mut sink, 1, 1, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf, b"fgh");
+        assert_eq!(sink.into_inner(), b"abcdefgh");
+    }
+
+    /// Fails writing piece 1.
+    struct BadSector(Vec<u8>);
+
+    impl Storage for BadSector {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.0.read_at(buf, offset)
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            if offset == 4 {
+                return Err(io::Error::from_raw_os_error(IO_ERROR));
+            }
+            self.0.write_at(buf, offset)
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_writes_are_reported() {
+        let mut sink = StorageWriter::new(BadSector(vec![]), 4);
+        let (mut tx, rx) = mpsc::channel(3);
+        for (index, data) in [(0, b"abcd"), (1, b"efgh"), (2, b"ijkl")] {
+            let buf = data.to_vec().into_boxed_slice();
+            tx.send(Piece::new(index, buf)).await.unwrap();
+        }
+        drop(tx);
+
+        let mut failed = vec![];
+        let on_error = |index, e: io::Error| failed.push((index, StorageErrorKind::of(&e)));
+        let rx = rx.map(Ok);
+        let have = write_pieces_reporting(&mut sink, Bitfield::with_size(3), rx, on_error).await;
+        assert!(have.get_bit(0) && !have.get_bit(1) && have.get_bit(2));
+        assert_eq!(failed, [(Some(1), StorageErrorKind::Io)]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn storage_error_kind() {
+        let kind = |code| StorageErrorKind::of(&io::Error::from_raw_os_error(code));
+        assert_eq!(kind(28), StorageErrorKind::DiskFull);
+        assert_eq!(kind(122), StorageErrorKind::DiskFull);
+        assert_eq!(kind(5), StorageErrorKind::Io);
+        assert_eq!(kind(13), StorageErrorKind::Other);
+        let e = io::Error::other("oops");
+        assert_eq!(StorageErrorKind::of(&e), StorageErrorKind::Other);
+    }
+
+    #[tokio::test]
+    async fn ordered_pieces() {
+        let mut have = Bitfield::with_size(6);
+        have.set_bit(2);
+        let (mut tx, rx) = mpsc::channel(6);
+        for index in [4, 1, 5, 0, 3] {
+            let buf = vec![index as u8].into_boxed_slice();
+            tx.send(Piece::new(index, buf)).await.unwrap();
+        }
+        drop(tx);
+
+        let ordered = OrderedPieces::new(rx, have, 6);
+        let indices: Vec<_> = ordered.map_ok(|p| p.index).try_collect().await.unwrap();
+        assert_eq!(indices, [0, 1, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn ordered_pieces_are_bounded() {
+        let pieces = |indices: &[u32]| {
+            let pieces: Vec<_> = indices
+                .iter()
+                .map(|&i| Piece::new(i, Box::new([0])))
+                .collect();
+            stream::iter(pieces)
+        };
+        let have = Bitfield::with_size(4);
+
+        let ordered = OrderedPieces::new(pieces(&[3, 2, 1]), have.clone(), 1);
+        let result: io::Result<Vec<_>> = ordered.try_collect().await;
+        assert!(result.is_err());
+
+        // Waiting for piece 0 at the end
+        let mut ordered = OrderedPieces::new(pieces(&[2, 1]), have, 2);
+        assert!(ordered.next().await.unwrap().is_err());
+        assert!(ordered.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn sequential_writer() {
+        let mut sink = SequentialWriter::new(vec![], Bitfield::with_size(3));
+        let (mut tx, rx) = mpsc::channel(3);
+        for (index, data) in [(2, b"ij"), (0, b"ab"), (1, b"cd")] {
+            let buf = data.to_vec().into_boxed_slice();
+            tx.send(Piece::new(index, buf)).await.unwrap();
+        }
+        drop(tx);
+
+        let have = Bitfield::with_size(3);
+        let rx = OrderedPieces::new(rx, have.clone(), 3);
+        let have = write_pieces(&mut sink, have, rx).await.unwrap();
+        assert!(have.is_all_set());
+        assert_eq!(sink.into_inner(), b"abcdij");
+
+        let mut sink = SequentialWriter::new(vec![], Bitfield::with_size(3));
+        let piece = Piece::new(1, Box::new([0]));
+        assert!(sink.insert(piece).is_err());
+    }
+
+    #[tokio::test]
+    async fn sequential_writer_resumes() {
+        // Pieces 0 and 1 were written by the previous run, piece 3 only
+        // made it to the storage
+        let mut have = Bitfield::with_size(6);
+        have.set_bit(0);
+        have.set_bit(1);
+        have.set_bit(3);
+        have.set_bit(5);
+        let reader: PieceReader = Arc::new(|index| {
+            let buf = match index {
+                3 => b"gh".to_vec(),
+                5 => b"kl".to_vec(),
+                _ => panic!("Piece {} read back", index),
+            };
+            future::ok(buf).boxed()
+        });
+        let write = |sink: SequentialWriter<Vec<u8>>| {
+            let have = have.clone();
+            async move {
+                let mut sink = sink;
+                let (mut tx, rx) = mpsc::channel(2);
+                for (index, data) in [(4, b"ij"), (2, b"ef")] {
+                    let buf = data.to_vec().into_boxed_slice();
+                    tx.send(Piece::new(index, buf)).await.unwrap();
+                }
+                drop(tx);
+
+                let rx = OrderedPieces::new(rx, have.clone(), 2);
+                let have = write_pieces(&mut sink, have, rx).await?;
+                assert!(have.is_all_set());
+                Ok::<_, io::Error>(sink.into_inner())
+            }
+        };
+
+        let sink = SequentialWriter::with_reader(vec![], have.clone(), reader);
+        assert_eq!(write(sink).await.unwrap(), b"efghijkl");
+
+        // Without a reader, piece 3 can't be written
+        let sink = SequentialWriter::new(vec![], have.clone());
+        assert!(write(sink).await.is_err());
+    }
 }
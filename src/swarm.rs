@@ -0,0 +1,272 @@
+//! Inspecting the swarm of a torrent without downloading from it.
+//!
+//! Connects to the peers of an info hash, records what they tell about
+//! themselves in the handshake and the bitfield, listens for a while to see
+//! how fast they complete pieces, and disconnects. Nothing is requested, so
+//! the torrent's metadata isn't needed.
+
+use crate::announce::{DhtTracker, Tracker};
+use crate::future::timeout;
+use crate::peer::{self, PeerAddr};
+use client::bitfield::Bitfield;
+use client::{Client, InfoHash, PeerId};
+use futures::stream::{self, StreamExt};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone)]
+pub struct InspectConfig {
+    /// Max number of peers connected to.
+    pub max_peers: usize,
+
+    /// Max number of peers connected to at the same time.
+    pub concurrency: usize,
+
+    /// How long to stay connected to each peer after the handshake.
+    pub listen_time: Duration,
+
+    /// Trackers asked for peers, along with the DHT.
+    pub trackers: Vec<String>,
+}
+
+impl Default for InspectConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 50,
+            concurrency: 20,
+            listen_time: Duration::from_secs(10),
+            trackers: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerReport {
+    pub addr: SocketAddr,
+
+    /// Time taken to connect and exchange the handshakes.
+    pub connect_time: Option<Duration>,
+    pub peer_id: Option<PeerId>,
+
+    /// Pieces the peer has, as of the end of the listen time. May be empty
+    /// if the peer sent neither a bitfield nor HAVEs.
+    pub pieces: Bitfield,
+
+    /// Pieces completed by the peer per second while we listened, going by
+    /// its HAVE messages.
+    pub piece_rate: f64,
+
+    /// Why the connection failed, if it did.
+    pub error: Option<String>,
+}
+
+impl PeerReport {
+    /// Client name and version from the peer id, like "UT 3100".
+    pub fn client(&self) -> Option<String> {
+        self.peer_id.as_ref().map(peer::client_name)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.peer_id.is_some()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SwarmReport {
+    pub info_hash: InfoHash,
+
+    /// All the peers found, including the ones beyond `max_peers` which
+    /// weren't connected to.
+    pub num_found: usize,
+    pub peers: Vec<PeerReport>,
+}
+
+impl SwarmReport {
+    pub fn connected(&self) -> impl Iterator<Item = &PeerReport> {
+        self.peers.iter().filter(|p| p.is_connected())
+    }
+
+    /// Number of pieces of the torrent, going by the last piece any peer
+    /// has since the metadata isn't known. Exact as soon as one peer has
+    /// the last piece, e.g. any seed. The bitfields themselves are padded
+    /// to whole bytes, so their length can't be trusted.
+    pub fn num_pieces(&self) -> usize {
+        self.peers
+            .iter()
+            .filter_map(|p| (0..p.pieces.len()).rev().find(|&i| p.pieces.get_bit(i)))
+            .max()
+            .map_or(0, |last| last + 1)
+    }
+
+    /// Number of connected peers having each of the `num_pieces` pieces.
+    pub fn availability(&self) -> Vec<u32> {
+        let mut counts = vec![0; self.num_pieces()];
+        for peer in &self.peers {
+            for (count, have) in counts.iter_mut().zip(peer.pieces.iter()) {
+                *count += have as u32;
+            }
+        }
+        counts
+    }
+
+    /// Number of peers which have all the pieces. See `num_pieces` for how
+    /// the number of pieces is found.
+    pub fn num_seeds(&self) -> usize {
+        let num_pieces = self.num_pieces();
+        self.peers
+            .iter()
+            .filter(|p| num_pieces > 0 && p.pieces.count() == num_pieces)
+            .count()
+    }
+}
+
+/// Inspect the swarm of the torrent with the default settings, finding the
+/// peers through the DHT.
+pub async fn inspect(info_hash: &InfoHash) -> anyhow::Result<SwarmReport> {
+    inspect_with(info_hash, &InspectConfig::default()).await
+}
+
+/// Inspect the swarm, finding the peers through the DHT and the trackers of
+/// the config.
+pub async fn inspect_with(
+    info_hash: &InfoHash,
+    config: &InspectConfig,
+) -> anyhow::Result<SwarmReport> {
+    let peer_id = peer::generate_peer_id();
+    let mut peers = HashSet::new();
+
+    for url in &config.trackers {
+        let mut tracker = Tracker::new(url.clone());
+        match tracker.announce(info_hash, &peer_id).await {
+            Ok(resp) => peers.extend(resp.peers.into_iter().chain(resp.peers6)),
+            Err(e) => debug!("Announce error: {}", e),
+        }
+    }
+
+    let mut dht = DhtTracker::new().await?;
+    match dht.announce(info_hash).await {
+        Ok(found) => peers.extend(found),
+        Err(e) => debug!("DHT announce error: {}", e),
+    }
+
+    Ok(inspect_peers(info_hash, &peer_id, peers, config).await)
+}
+
+/// Inspect the given peers of the torrent.
+pub async fn inspect_peers(
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+    peers: impl IntoIterator<Item = SocketAddr>,
+    config: &InspectConfig,
+) -> SwarmReport {
+    let peers: HashSet<_> = peers.into_iter().filter_map(PeerAddr::new).collect();
+    let num_found = peers.len();
+    debug!(
+        "Inspecting {} of {} peers",
+        config.max_peers.min(num_found),
+        num_found
+    );
+
+    let reports = stream::iter(peers.into_iter().take(config.max_peers))
+        .map(|peer| inspect_peer(peer.addr(), info_hash, peer_id, config.listen_time))
+        .buffer_unordered(config.concurrency.max(1))
+        .collect()
+        .await;
+
+    SwarmReport {
+        info_hash: *info_hash,
+        num_found,
+        peers: reports,
+    }
+}
+
+async fn inspect_peer(
+    addr: SocketAddr,
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+    listen_time: Duration,
+) -> PeerReport {
+    let mut report = PeerReport {
+        addr,
+        connect_time: None,
+        peer_id: None,
+        pieces: Bitfield::new(),
+        piece_rate: 0.0,
+        error: None,
+    };
+
+    let start = Instant::now();
+    let result = async {
+        let socket = timeout(TcpStream::connect(addr), 3).await?;
+        let mut client = Client::new(socket);
+        client.send_handshake(info_hash, peer_id).await?;
+        report.peer_id = Some(client.recv_handshake(info_hash).await?);
+        report.connect_time = Some(start.elapsed());
+
+        // The bitfield, if any, comes first. The pieces after it are
+        // the ones completed while we listen.
+        let deadline = Instant::now() + listen_time;
+        let mut first: Option<(Instant, usize)> = None;
+        while let Ok(packet) = tokio::time::timeout_at(deadline.into(), client.read_packet()).await
+        {
+            // Keep what we have so far if the peer goes away
+            if let Err(e) = packet {
+                report.error = Some(e.to_string());
+                break;
+            }
+            let count = client.peer_pieces().count();
+            if first.is_none() && count > 0 {
+                first = Some((Instant::now(), count));
+            }
+        }
+
+        report.pieces = client.peer_pieces().clone();
+        if let Some((at, first_count)) = first {
+            let completed = report.pieces.count() - first_count;
+            report.piece_rate = completed as f64 / at.elapsed().as_secs_f64();
+        }
+        anyhow::Ok(())
+    };
+
+    if let Err(e) = result.await {
+        debug!("Inspecting {} failed: {}", addr, e);
+        report.error = Some(e.to_string());
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(pieces: &[u8]) -> PeerReport {
+        let mut bitfield = Bitfield::new();
+        bitfield.copy_from_slice(pieces);
+        PeerReport {
+            addr: SocketAddr::from(([1, 1, 1, 1], 1)),
+            connect_time: None,
+            peer_id: Some(*b"-UT3100-000000000000"),
+            pieces: bitfield,
+            piece_rate: 0.0,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn availability() {
+        let report = SwarmReport {
+            info_hash: [0; 20],
+            num_found: 3,
+            peers: vec![report(&[0xff, 0xc0]), report(&[0x80]), report(&[])],
+        };
+
+        assert_eq!(report.num_pieces(), 10);
+        assert_eq!(report.availability(), [2, 1, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(report.num_seeds(), 1);
+        assert_eq!(report.connected().count(), 3);
+        assert_eq!(report.peers[0].client().as_deref(), Some("UT 3100"));
+    }
+}
@@ -0,0 +1,943 @@
+//! In-memory swarm for running a whole [`TorrentWorker`] download without
+//! sockets.
+//!
+//! [`Swarm`] makes up a torrent and hands out addresses of simulated peers,
+//! each either a seed or a leech with some of the pieces. The worker dials
+//! them through a [`Dialer`] which connects it to the peers over in-memory
+//! streams, so a download runs end to end with nothing but the scheduling
+//! of the worker deciding who serves what. The leeches download the pieces
+//! they lack from the worker in turn. Handy for regression tests of
+//! the piece picking and the connection handling.
+//!
+//! The peers are served by [`Swarm::run`] on the task running the worker
+//! rather than on tasks of their own, and the worker hashes the pieces on
+//! that task too. Run the tests on a current-thread runtime with the clock
+//! paused, e.g. `#[tokio::test(start_paused = true)]`, and nothing but
+//! the futures involved decides the order of events: the timeouts fire
+//! once everything else is waiting, whatever the speed of the machine.
+//!
+//! Only available with the `testing` feature.
+
+use crate::resume::ResumeData;
+use crate::work::{Piece, PieceIter, BLOCK_SIZE};
+use crate::worker::{DialedStream, Dialer, PieceReader};
+use crate::{Session, Torrent, TorrentWorker};
+use client::bitfield::Bitfield;
+use client::event::PeerEvent;
+use client::merkle;
+use client::metainfo::Version;
+use client::msg::Packet;
+use client::testing::Peer;
+use client::{Client, InfoHash, PeerId};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::future::{self, BoxFuture};
+use futures::stream::FuturesUnordered;
+use futures::{select, Future, FutureExt, StreamExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// Max number of requests a simulated leech keeps in flight.
+const MAX_REQUESTS: usize = 16;
+
+/// Pieces a simulated peer has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Role {
+    /// All the pieces.
+    Seed,
+
+    /// Only the pieces with these indexes. Downloads the others from the
+    /// worker.
+    Leech(Vec<u32>),
+
+    /// All the pieces, but never unchokes the worker.
+    Choker,
+
+    /// No pieces. Cancels every other block of its first requests to the
+    /// worker right after sending them.
+    Fickle,
+}
+
+/// Torrent data shared by the simulated peers.
+#[derive(Debug)]
+struct Content {
+    info_hash: InfoHash,
+    data: Vec<u8>,
+    piece_len: usize,
+    version: Version,
+}
+
+impl Content {
+    fn num_pieces(&self) -> u32 {
+        self.data.len().div_ceil(self.piece_len) as u32
+    }
+
+    fn piece(&self, index: u32) -> &[u8] {
+        let start = index as usize * self.piece_len;
+        let end = (start + self.piece_len).min(self.data.len());
+        &self.data[start..end]
+    }
+}
+
+#[derive(Debug)]
+struct SimPeer {
+    role: Role,
+
+    /// Block bytes sent to the worker
+    uploaded: u64,
+
+    /// Block bytes received from the worker
+    downloaded: u64,
+
+    /// Block bytes received from the worker without being requested, e.g.
+    /// cancelled ones
+    wasted: u64,
+
+    /// Requests the worker cancelled
+    cancelled: u64,
+
+    /// The worker wants pieces from the peer
+    wanted: bool,
+
+    /// The worker chokes the peer
+    choked: bool,
+}
+
+/// Simulated peers of a made-up torrent.
+#[derive(Clone)]
+pub struct Swarm {
+    content: Arc<Content>,
+    peers: Arc<Mutex<HashMap<SocketAddr, SimPeer>>>,
+
+    /// Connections opened by the worker, for `run` to serve
+    conn_tx: UnboundedSender<BoxFuture<'static, ()>>,
+    conn_rx: Arc<futures::lock::Mutex<UnboundedReceiver<BoxFuture<'static, ()>>>>,
+}
+
+impl fmt::Debug for Swarm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Swarm")
+            .field("content", &self.content)
+            .field("peers", &self.peers)
+            .finish()
+    }
+}
+
+impl Swarm {
+    /// Swarm of a torrent of `len` bytes in pieces of `piece_len`. The data
+    /// is the same for the same length, so runs can be compared.
+    pub fn new(len: usize, piece_len: usize) -> Self {
+        Self::with_version(len, piece_len, Version::V1)
+    }
+
+    /// Swarm of a torrent with the metadata of `version`, whose pieces are
+    /// checked with SHA-1 or their merkle roots accordingly.
+    pub fn with_version(len: usize, piece_len: usize, version: Version) -> Self {
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let info_hash = crate::hash::sha1(&data);
+        let (conn_tx, conn_rx) = mpsc::unbounded();
+        Self {
+            content: Arc::new(Content {
+                info_hash,
+                data,
+                piece_len,
+                version,
+            }),
+            peers: Arc::default(),
+            conn_tx,
+            conn_rx: Arc::new(futures::lock::Mutex::new(conn_rx)),
+        }
+    }
+
+    /// Add a peer and return its address. The peers get addresses in
+    /// different subnets so that the connection limits of the session
+    /// don't hold them back.
+    pub fn add_peer(&self, role: Role) -> SocketAddr {
+        let mut peers = self.peers.lock().unwrap();
+        let n = peers.len() + 1;
+        let addr = SocketAddr::from(([10, (n >> 8) as u8, n as u8, 1], 6881));
+        peers.insert(
+            addr,
+            SimPeer {
+                role,
+                uploaded: 0,
+                downloaded: 0,
+                wasted: 0,
+                cancelled: 0,
+                wanted: false,
+                choked: true,
+            },
+        );
+        addr
+    }
+
+    /// The data of the torrent, which a download must end up with.
+    pub fn data(&self) -> &[u8] {
+        &self.content.data
+    }
+
+    /// Block bytes the peer at `addr` has sent so far.
+    pub fn uploaded(&self, addr: SocketAddr) -> u64 {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&addr).map_or(0, |p| p.uploaded)
+    }
+
+    /// Block bytes the peer at `addr` has received from the worker so far.
+    pub fn downloaded(&self, addr: SocketAddr) -> u64 {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&addr).map_or(0, |p| p.downloaded)
+    }
+
+    /// Block bytes the peer received from the worker without wanting them.
+    pub fn wasted(&self, addr: SocketAddr) -> u64 {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&addr).map_or(0, |p| p.wasted)
+    }
+
+    /// Number of requests the worker cancelled at the peer at `addr`.
+    pub fn cancelled(&self, addr: SocketAddr) -> u64 {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&addr).map_or(0, |p| p.cancelled)
+    }
+
+    /// Whether the worker told the peer it's done with it, neither
+    /// interested nor unchoking it anymore, e.g. before hanging up.
+    pub fn parted(&self, addr: SocketAddr) -> bool {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&addr).is_some_and(|p| !p.wanted && p.choked)
+    }
+
+    /// The torrent, with the peers added so far as its peers.
+    pub fn torrent(&self) -> Torrent {
+        let content = &self.content;
+        let piece_hashes = match content.version {
+            Version::V2 => vec![],
+            _ => (0..content.num_pieces())
+                .flat_map(|i| crate::hash::sha1(content.piece(i)))
+                .collect(),
+        };
+        let piece_roots = match content.version {
+            Version::V1 => vec![],
+            _ => {
+                let width = content.piece_len / merkle::BLOCK_SIZE;
+                let layer: Vec<u8> = (0..content.num_pieces())
+                    .flat_map(|i| merkle::data_root(content.piece(i), width))
+                    .collect();
+                let leaves = content.data.len().div_ceil(merkle::BLOCK_SIZE);
+                let root = merkle::data_root(&content.data, leaves.next_power_of_two());
+                let len = content.data.len() as u64;
+                merkle::piece_roots(content.piece_len, len, root, Some(&layer)).unwrap()
+            }
+        };
+        Torrent {
+            info_hash: content.info_hash,
+            info_hash_v2: None,
+            version: content.version,
+            piece_hashes,
+            piece_roots,
+            piece_len: content.piece_len,
+            length: content.data.len(),
+            name: "swarm".into(),
+            files: vec![],
+            private: false,
+            tracker_urls: vec![],
+            url_list: vec![],
+            http_seeds: vec![],
+            peers: self.peers.lock().unwrap().keys().copied().collect(),
+            peers_v6: Default::default(),
+        }
+    }
+
+    /// Connects to the simulated peers, which are served by `run`.
+    /// Addresses of other peers are refused.
+    pub fn dialer(&self) -> Dialer {
+        let swarm = self.clone();
+        Arc::new(move |addr| {
+            let swarm = swarm.clone();
+            async move {
+                let role = {
+                    let peers = swarm.peers.lock().unwrap();
+                    match peers.get(&addr) {
+                        Some(p) => p.role.clone(),
+                        None => return Err(std::io::ErrorKind::ConnectionRefused.into()),
+                    }
+                };
+                let (ours, theirs) = Peer::create_pair();
+                let serve = swarm.clone();
+                let conn = async move {
+                    if let Err(e) = serve.serve(addr, role, theirs).await {
+                        debug!("Simulated peer {} is done: {}", addr, e);
+                    }
+                };
+                swarm.conn_tx.unbounded_send(conn.boxed()).ok();
+                Ok(Box::new(ours) as DialedStream)
+            }
+            .boxed()
+        })
+    }
+
+    /// Worker downloading the torrent from the simulated peers only, with
+    /// no DHT and no trackers.
+    pub fn worker(&self) -> TorrentWorker {
+        let session = Session::new();
+        let peer_id = session.identity().generate_peer_id();
+        let mut worker = TorrentWorker::without_dht(session, self.torrent(), peer_id);
+        worker.set_dialer(self.dialer());
+        worker.set_piece_reader(self.piece_reader());
+        worker.hash_inline();
+        worker
+    }
+
+    /// Worker which has all the pieces already, to upload them to the
+    /// leeches.
+    pub fn seed_worker(&self) -> TorrentWorker {
+        let mut worker = self.worker();
+        worker.restore(ResumeData {
+            info_hash: self.content.info_hash,
+            have: Bitfield::with_value(self.content.num_pieces() as usize, true),
+            partial: vec![],
+            peers: vec![],
+            recent: vec![],
+        });
+        worker
+    }
+
+    /// Reads the pieces from the data of the torrent, as if the worker had
+    /// written them.
+    pub fn piece_reader(&self) -> PieceReader {
+        let content = self.content.clone();
+        Arc::new(move |index| future::ready(Ok(content.piece(index).to_vec())).boxed())
+    }
+
+    /// Drive `f`, e.g. a run of the worker, along with the peers it connects
+    /// to. Once `f` is done, the peers are served until the worker's side
+    /// of their connections is gone.
+    pub async fn run<T>(&self, f: impl Future<Output = T>) -> T {
+        let mut conn_rx = self.conn_rx.lock().await;
+        let mut serving = FuturesUnordered::new();
+        let f = f.fuse();
+        futures::pin_mut!(f);
+        let out = loop {
+            select! {
+                out = f => break out,
+                conn = conn_rx.select_next_some() => serving.push(conn),
+                () = serving.select_next_some() => {}
+            }
+        };
+
+        while let Ok(conn) = conn_rx.try_recv() {
+            serving.push(conn);
+        }
+        while serving.next().await.is_some() {}
+        out
+    }
+
+    /// Run the worker until it's done and return the pieces it verified,
+    /// put together in order.
+    pub async fn download(&self, worker: &mut TorrentWorker) -> Vec<u8> {
+        let (piece_tx, piece_rx) = mpsc::channel::<Piece>(200);
+        let handle = worker.handle();
+        let collect = async move {
+            let pieces = piece_rx.collect::<Vec<_>>().await;
+            handle.storage_flushed();
+            pieces
+        };
+        let run = async { futures::join!(worker.run(piece_tx), collect) };
+        let ((), mut pieces) = self.run(run).await;
+        pieces.sort_by_key(|p| p.index);
+        pieces.dedup_by_key(|p| p.index);
+        pieces.iter().flat_map(|p| p.buf.iter().copied()).collect()
+    }
+
+    /// Answer the worker like a peer with the pieces of `role`, until the
+    /// worker hangs up.
+    async fn serve(&self, addr: SocketAddr, role: Role, stream: Peer) -> anyhow::Result<()> {
+        let content = &self.content;
+        let mut peer_id: PeerId = *b"-SM0001-000000000000";
+        if let IpAddr::V4(ip) = addr.ip() {
+            peer_id[16..].copy_from_slice(&ip.octets());
+        }
+
+        let mut client = Client::new(stream);
+        client.recv_handshake(&content.info_hash).await?;
+        client.send_handshake(&content.info_hash, &peer_id).await?;
+
+        let have: Vec<u32> = match &role {
+            Role::Seed | Role::Choker => (0..content.num_pieces()).collect(),
+            Role::Leech(pieces) => pieces.clone(),
+            Role::Fickle => vec![],
+        };
+        for &index in &have {
+            client.send_have(index);
+        }
+
+        // Blocks of the pieces the peer lacks, to request from the worker
+        let piece_len = content.piece_len;
+        let mut missing: Vec<_> = PieceIter::new(piece_len, content.data.len())
+            .filter(|p| !have.contains(&p.index))
+            .flat_map(|p| {
+                (0..p.len)
+                    .step_by(BLOCK_SIZE as usize)
+                    .map(move |begin| (p.index, begin, BLOCK_SIZE.min(p.len - begin)))
+            })
+            .collect();
+        let mut requested = vec![];
+        let mut cancelled = false;
+        if !missing.is_empty() {
+            client.send_interested();
+        }
+        if role == Role::Choker {
+            // Not even when the worker is interested
+            client.set_download_only(true);
+        } else {
+            client.send_unchoke();
+        }
+        client.flush().await?;
+
+        loop {
+            let read = client.read_packet().await.map(|packet| match packet {
+                Some(Packet::Piece(block)) => {
+                    let piece = content.piece(block.index);
+                    let data = piece.get(block.begin as usize..).unwrap_or_default();
+                    let valid = data.starts_with(block.data);
+                    Some((block.index, block.begin, block.data.len(), valid))
+                }
+                Some(Packet::Cancel { .. }) => {
+                    if let Some(p) = self.peers.lock().unwrap().get_mut(&addr) {
+                        p.cancelled += 1;
+                    }
+                    None
+                }
+                _ => None,
+            });
+            while let Some(event) = client.poll_peer_event() {
+                let mut peers = self.peers.lock().unwrap();
+                if let Some(p) = peers.get_mut(&addr) {
+                    match event {
+                        PeerEvent::Choked => p.choked = true,
+                        PeerEvent::Unchoked => p.choked = false,
+                        PeerEvent::InterestedInUs => p.wanted = true,
+                        PeerEvent::NotInterestedInUs => p.wanted = false,
+                    }
+                }
+            }
+            let block = match read {
+                // Writing fails once the worker stops reading, but the
+                // messages it sent before that are still to be read
+                Err(e) if is_broken_pipe(&e) => continue,
+                read => read?,
+            };
+
+            if let Some((index, begin, len, valid)) = block {
+                anyhow::ensure!(valid, "Block {} of piece {} is corrupt", begin, index);
+                if let Some(i) = requested
+                    .iter()
+                    .position(|&(i, b, _)| i == index && b == begin)
+                {
+                    requested.remove(i);
+                    let mut peers = self.peers.lock().unwrap();
+                    if let Some(p) = peers.get_mut(&addr) {
+                        p.downloaded += len as u64;
+                    }
+                } else if let Some(p) = self.peers.lock().unwrap().get_mut(&addr) {
+                    p.wasted += len as u64;
+                }
+            }
+            if client.is_choked() {
+                // The worker drops the requests of the peers it chokes
+                missing.append(&mut requested);
+            } else {
+                let mut i = 0;
+                while requested.len() < MAX_REQUESTS && i < missing.len() {
+                    let (index, begin, len) = missing[i];
+                    if client.peer_pieces().get_bit(index as usize) {
+                        client.send_request(index, begin, len);
+                        requested.push(missing.remove(i));
+                    } else {
+                        i += 1;
+                    }
+                }
+                if role == Role::Fickle && !cancelled && !requested.is_empty() {
+                    cancelled = true;
+                    let mut i = 0;
+                    requested.retain(|&(index, begin, len)| {
+                        i += 1;
+                        if i % 2 == 0 {
+                            client.send_cancel(index, begin, len);
+                            missing.push((index, begin, len));
+                        }
+                        i % 2 != 0
+                    });
+                }
+                if missing.is_empty() && requested.is_empty() && block.is_some() {
+                    // Got it all
+                    client.send_not_interested();
+                }
+            }
+
+            while let Some(req) = client.pop_request() {
+                if role == Role::Choker || !have.contains(&req.index) {
+                    continue;
+                }
+                let piece = content.piece(req.index);
+                let begin = req.begin as usize;
+                let end = begin + req.len as usize;
+                anyhow::ensure!(end <= piece.len(), "Request out of range: {:?}", req);
+                client.send_piece(req.index, req.begin, &piece[begin..end]);
+
+                let mut peers = self.peers.lock().unwrap();
+                if let Some(p) = peers.get_mut(&addr) {
+                    p.uploaded += req.len as u64;
+                }
+            }
+            match client.flush().await {
+                // The worker is gone, possibly without reading the blocks
+                // sent to it, but not before it parted with us
+                Err(e) if is_broken_pipe(&e) => continue,
+                flushed => flushed?,
+            }
+        }
+    }
+}
+
+fn is_broken_pipe(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WorkerConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    const PIECE_LEN: usize = 0x8000;
+
+    #[tokio::test(start_paused = true)]
+    async fn download_from_seed() {
+        let swarm = Swarm::new(5 * PIECE_LEN + 1000, PIECE_LEN);
+        let seed = swarm.add_peer(Role::Seed);
+
+        let mut worker = swarm.worker();
+        let data = swarm.download(&mut worker).await;
+        assert_eq!(data, swarm.data());
+        assert_eq!(swarm.uploaded(seed), data.len() as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chokers_time_out() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        let choker = swarm.add_peer(Role::Choker);
+        swarm.add_peer(Role::Seed);
+
+        let mut worker = swarm.worker();
+        worker.set_config(WorkerConfig {
+            unchoke_timeout: Duration::from_millis(100),
+            ..WorkerConfig::default()
+        });
+        let download = swarm.download(&mut worker);
+        let data = tokio::time::timeout(Duration::from_secs(10), download)
+            .await
+            .unwrap();
+        assert_eq!(data, swarm.data());
+        assert_eq!(swarm.uploaded(choker), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_are_drained() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        let seed = swarm.add_peer(Role::Seed);
+        let leech = swarm.add_peer(Role::Leech(vec![]));
+
+        let mut worker = swarm.worker();
+        swarm.download(&mut worker).await;
+        assert!(swarm.parted(seed));
+        assert!(swarm.parted(leech));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_are_drained_on_pause() {
+        let swarm = Swarm::new(8 * PIECE_LEN, PIECE_LEN);
+        let seed = swarm.add_peer(Role::Seed);
+
+        let mut worker = swarm.worker();
+        let handle = worker.handle();
+        let (piece_tx, mut piece_rx) = mpsc::channel::<Piece>(200);
+        let pause = async {
+            piece_rx.next().await.unwrap();
+            assert!(!swarm.parted(seed));
+            handle.pause();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        };
+        let run = worker.run(piece_tx).fuse();
+        let pause = pause.fuse();
+        futures::pin_mut!(run, pause);
+        swarm
+            .run(async {
+                select! {
+                    _ = run => panic!("worker is done"),
+                    _ = pause => {}
+                }
+            })
+            .await;
+        assert!(swarm.parted(seed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn download_v2() {
+        let swarm = Swarm::with_version(3 * PIECE_LEN + 1000, PIECE_LEN, Version::V2);
+        swarm.add_peer(Role::Seed);
+
+        let mut worker = swarm.worker();
+        let data = swarm.download(&mut worker).await;
+        assert_eq!(data, swarm.data());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn download_from_leeches() {
+        let swarm = Swarm::new(4 * PIECE_LEN, PIECE_LEN);
+        let a = swarm.add_peer(Role::Leech(vec![0, 2]));
+        let b = swarm.add_peer(Role::Leech(vec![1, 3]));
+
+        let mut worker = swarm.worker();
+        let data = swarm.download(&mut worker).await;
+        assert_eq!(data, swarm.data());
+        assert_eq!(swarm.uploaded(a), 2 * PIECE_LEN as u64);
+        assert_eq!(swarm.uploaded(b), 2 * PIECE_LEN as u64);
+
+        // Each got the pieces of the other through the worker
+        assert_eq!(swarm.downloaded(a), 2 * PIECE_LEN as u64);
+        assert_eq!(swarm.downloaded(b), 2 * PIECE_LEN as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn upload_to_leeches() {
+        let swarm = Swarm::new(3 * PIECE_LEN + 1000, PIECE_LEN);
+        let a = swarm.add_peer(Role::Leech(vec![]));
+        let b = swarm.add_peer(Role::Leech(vec![1]));
+
+        let mut worker = swarm.seed_worker();
+        let download = swarm.download(&mut worker);
+        tokio::time::timeout(Duration::from_secs(10), download)
+            .await
+            .unwrap();
+        let len = swarm.data().len() as u64;
+        assert_eq!(swarm.downloaded(a), len);
+        assert_eq!(swarm.downloaded(b), len - PIECE_LEN as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_requests_are_not_served() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        let fickle = swarm.add_peer(Role::Fickle);
+
+        let mut worker = swarm.seed_worker();
+        let download = swarm.download(&mut worker);
+        tokio::time::timeout(Duration::from_secs(10), download)
+            .await
+            .unwrap();
+        assert_eq!(swarm.downloaded(fickle), 2 * PIECE_LEN as u64);
+        assert_eq!(swarm.wasted(fickle), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn upload_slots_are_shared() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        let leeches: Vec<_> = (0..3)
+            .map(|_| swarm.add_peer(Role::Leech(vec![])))
+            .collect();
+
+        // One at a time
+        let mut worker = swarm.seed_worker();
+        worker.set_config(WorkerConfig {
+            max_uploads: 1,
+            ..WorkerConfig::default()
+        });
+        let reads = Arc::new(AtomicUsize::new(0));
+        let reader = swarm.piece_reader();
+        let counted = reads.clone();
+        worker.set_piece_reader(Arc::new(move |index| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            reader(index)
+        }));
+        let download = swarm.download(&mut worker);
+        tokio::time::timeout(Duration::from_secs(10), download)
+            .await
+            .unwrap();
+        for leech in leeches {
+            assert_eq!(swarm.downloaded(leech), 2 * PIECE_LEN as u64);
+        }
+
+        // The later peers got the pieces from the cache
+        assert_eq!(reads.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn download_only_never_uploads() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        let leech = swarm.add_peer(Role::Leech(vec![0]));
+        swarm.add_peer(Role::Seed);
+
+        let mut worker = swarm.worker();
+        worker.set_config(WorkerConfig {
+            download_only: true,
+            ..WorkerConfig::default()
+        });
+        let data = swarm.download(&mut worker).await;
+        assert_eq!(data, swarm.data());
+        assert_eq!(swarm.downloaded(leech), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duplicate_pieces_are_cancelled() {
+        let swarm = Swarm::new(8 * PIECE_LEN, PIECE_LEN);
+        let seeds: Vec<_> = (0..3).map(|_| swarm.add_peer(Role::Seed)).collect();
+
+        // Due right away, so each piece is downloaded from several seeds
+        let mut worker = swarm.worker();
+        let handle = worker.handle();
+        for index in 0..8 {
+            handle.set_piece_deadline(index, std::time::Instant::now());
+        }
+        let download = swarm.download(&mut worker);
+        let data = tokio::time::timeout(Duration::from_secs(10), download)
+            .await
+            .unwrap();
+        assert_eq!(data, swarm.data());
+
+        // Blocks of the pieces another seed finished first are cancelled
+        let cancelled: u64 = seeds.iter().map(|&s| swarm.cancelled(s)).sum();
+        assert!(cancelled > 0);
+        let uploaded: u64 = seeds.iter().map(|&s| swarm.uploaded(s)).sum();
+        assert!(uploaded < 3 * data.len() as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn striped_piece_from_several_seeds() {
+        let len = 64 * crate::work::BLOCK_SIZE as usize;
+        let swarm = Swarm::new(len, len);
+        let seeds: Vec<_> = (0..3).map(|_| swarm.add_peer(Role::Seed)).collect();
+
+        // One big piece, due right away
+        let mut worker = swarm.worker();
+        worker
+            .handle()
+            .set_piece_deadline(0, std::time::Instant::now());
+        let download = swarm.download(&mut worker);
+        let data = tokio::time::timeout(Duration::from_secs(10), download)
+            .await
+            .unwrap();
+        assert_eq!(data, swarm.data());
+
+        // Its blocks came from more than one seed, mostly once each
+        let uploaded: Vec<_> = seeds.iter().map(|&s| swarm.uploaded(s)).collect();
+        assert!(
+            uploaded.iter().filter(|&&n| n > 0).count() > 1,
+            "{:?}",
+            uploaded
+        );
+        assert!(
+            uploaded.iter().sum::<u64>() < 2 * len as u64,
+            "{:?}",
+            uploaded
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn download_within_recv_budget() {
+        let swarm = Swarm::new(4 * PIECE_LEN, PIECE_LEN);
+        swarm.add_peer(Role::Seed);
+        swarm.add_peer(Role::Seed);
+
+        let session = Session::new();
+        session.set_max_recv_buffer(0x8000);
+        session.set_recv_buffer_limit(Some(0x8000));
+        let peer_id = session.identity().generate_peer_id();
+        let mut worker = TorrentWorker::without_dht(session.clone(), swarm.torrent(), peer_id);
+        worker.set_dialer(swarm.dialer());
+
+        let data = swarm.download(&mut worker).await;
+        assert_eq!(data, swarm.data());
+        drop(worker);
+        assert_eq!(session.recv_budget().used(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn downloads_build_reputation() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        let seed = swarm.add_peer(Role::Seed);
+
+        let session = Session::new();
+        let peer_id = session.identity().generate_peer_id();
+        let mut worker = TorrentWorker::without_dht(session.clone(), swarm.torrent(), peer_id);
+        worker.set_dialer(swarm.dialer());
+        swarm.download(&mut worker).await;
+
+        let record = session.reputation().get(seed.ip()).unwrap();
+        assert_eq!(record.transfers, 1);
+        assert_eq!(record.bytes, 2 * PIECE_LEN as u64);
+        assert_eq!(record.corrupt, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn completion_hook() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        swarm.add_peer(Role::Seed);
+
+        let (done_tx, done_rx) = mpsc::unbounded();
+        let mut worker = swarm.worker();
+        worker.set_completion_hook(Arc::new(move |info_hash| {
+            done_tx.unbounded_send(info_hash).unwrap();
+            async {}.boxed()
+        }));
+        swarm.download(&mut worker).await;
+
+        // Not again once complete
+        swarm.download(&mut worker).await;
+        drop(worker);
+        let done: Vec<_> = done_rx.collect().await;
+        assert_eq!(done, [swarm.torrent().info_hash]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn completion_hook_waits_for_storage() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        swarm.add_peer(Role::Seed);
+
+        let written = Arc::new(AtomicUsize::new(0));
+        let seen = written.clone();
+        let mut worker = swarm.worker();
+        worker.set_completion_hook(Arc::new(move |_| {
+            assert_eq!(seen.load(Ordering::SeqCst), 2);
+            async {}.boxed()
+        }));
+
+        let (piece_tx, piece_rx) = mpsc::channel::<Piece>(200);
+        let handle = worker.handle();
+        let write = async {
+            // Slower than the worker
+            let mut piece_rx = piece_rx;
+            while piece_rx.next().await.is_some() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                written.fetch_add(1, Ordering::SeqCst);
+            }
+            handle.storage_flushed();
+        };
+        swarm
+            .run(async { futures::join!(worker.run(piece_tx), write) })
+            .await;
+        assert_eq!(written.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn completion_hook_is_skipped_if_not_flushed() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        swarm.add_peer(Role::Seed);
+
+        let called = Arc::new(AtomicUsize::new(0));
+        let calls = called.clone();
+        let mut worker = swarm.worker();
+        worker.set_completion_hook(Arc::new(move |_| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async {}.boxed()
+        }));
+
+        // The writer never reports the pieces flushed
+        let (piece_tx, piece_rx) = mpsc::channel::<Piece>(200);
+        let write = piece_rx.collect::<Vec<_>>();
+        let ((), pieces) = swarm
+            .run(async { futures::join!(worker.run(piece_tx), write) })
+            .await;
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(called.load(Ordering::SeqCst), 0);
+    }
+
+    /// HTTP tracker on localhost which has no peers for anyone. Passes on
+    /// the event of each announce, empty for regular ones.
+    async fn http_tracker() -> (String, mpsc::UnboundedReceiver<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0; 4096];
+                let mut len = 0;
+                while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf[len..]).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => len += n,
+                    }
+                }
+                let req = String::from_utf8_lossy(&buf[..len]);
+                let target = req.split(' ').nth(1).unwrap_or_default();
+                let event = target
+                    .split(['?', '&'])
+                    .find_map(|p| p.strip_prefix("event="))
+                    .unwrap_or_default();
+                tx.unbounded_send(event.to_string()).ok();
+
+                let body = b"d8:intervali1800e5:peers0:e";
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).await.ok();
+                stream.write_all(body).await.ok();
+            }
+        });
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn removed_tracker_is_stopped() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        let (url, mut events) = http_tracker().await;
+
+        // No peers, so the worker runs until the test is done
+        let mut worker = swarm.worker();
+        let handle = worker.handle();
+        let (piece_tx, _piece_rx) = mpsc::channel::<Piece>(200);
+        let trackers = async {
+            handle.add_tracker(url.clone());
+            assert_eq!(events.next().await.unwrap(), "started");
+            handle.remove_tracker(url);
+            assert_eq!(events.next().await.unwrap(), "stopped");
+        };
+        let run = worker.run(piece_tx);
+        futures::pin_mut!(run);
+        let trackers = tokio::time::timeout(Duration::from_secs(10), trackers);
+        futures::select! {
+            _ = run.fuse() => panic!("worker is done"),
+            r = trackers.fuse() => r.unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn new_port_is_announced_to_the_running_tracker() {
+        let swarm = Swarm::new(2 * PIECE_LEN, PIECE_LEN);
+        let (url, mut events) = http_tracker().await;
+
+        let mut worker = swarm.worker();
+        let handle = worker.handle();
+        let (piece_tx, _piece_rx) = mpsc::channel::<Piece>(200);
+        let trackers = async {
+            handle.add_tracker(url.clone());
+            assert_eq!(events.next().await.unwrap(), "started");
+
+            // Announced right away, without starting over
+            handle.set_port(7000);
+            assert_eq!(events.next().await.unwrap(), "");
+        };
+        let run = worker.run(piece_tx);
+        futures::pin_mut!(run);
+        let trackers = tokio::time::timeout(Duration::from_secs(10), trackers);
+        futures::select! {
+            _ = run.fuse() => panic!("worker is done"),
+            r = trackers.fuse() => r.unwrap(),
+        }
+    }
+}
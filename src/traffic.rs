@@ -0,0 +1,183 @@
+use client::WireStats;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+
+/// What the transferred bytes were spent on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// Block data exchanged with the peers.
+    Payload,
+
+    /// Everything else exchanged with the peers: handshakes, message
+    /// headers, requests, haves, extended messages.
+    Protocol,
+
+    /// Announces to the HTTP and UDP trackers.
+    Tracker,
+
+    /// DHT queries and replies.
+    Dht,
+}
+
+impl Category {
+    const ALL: [Category; 4] = [
+        Category::Payload,
+        Category::Protocol,
+        Category::Tracker,
+        Category::Dht,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Bytes sent and received.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transferred {
+    pub up: u64,
+    pub down: u64,
+}
+
+impl Transferred {
+    pub fn total(&self) -> u64 {
+        self.up + self.down
+    }
+}
+
+/// Snapshot of the bytes transferred in each category.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub payload: Transferred,
+    pub protocol: Transferred,
+    pub tracker: Transferred,
+    pub dht: Transferred,
+}
+
+impl TrafficStats {
+    pub fn get(&self, category: Category) -> Transferred {
+        match category {
+            Category::Payload => self.payload,
+            Category::Protocol => self.protocol,
+            Category::Tracker => self.tracker,
+            Category::Dht => self.dht,
+        }
+    }
+
+    /// Bytes transferred in all the categories together.
+    pub fn total(&self) -> Transferred {
+        let mut sum = Transferred::default();
+        for c in Category::ALL {
+            let t = self.get(c);
+            sum.up += t.up;
+            sum.down += t.down;
+        }
+        sum
+    }
+
+    /// Fraction of all the transferred bytes which were block data. `None`
+    /// until anything is transferred.
+    pub fn efficiency(&self) -> Option<f64> {
+        let total = self.total().total();
+        (total > 0).then(|| self.payload.total() as f64 / total as f64)
+    }
+}
+
+impl fmt::Display for TrafficStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = ["payload", "protocol", "tracker", "dht"];
+        for (name, &c) in names.iter().zip(Category::ALL.iter()) {
+            let t = self.get(c);
+            write!(f, "{} {}/{} B, ", name, t.up, t.down)?;
+        }
+        match self.efficiency() {
+            Some(e) => write!(f, "efficiency {:.1}%", e * 100.0),
+            None => f.write_str("efficiency n/a"),
+        }
+    }
+}
+
+/// Counters of the bytes transferred by the torrents of a session, broken
+/// down by category so that the protocol overhead can be told apart from
+/// the data. Cloning returns a handle to the same counters.
+///
+/// Tracker traffic is counted as the request URL or datagram sent and the
+/// response body or datagram received; HTTP headers and lower layers aren't
+/// included.
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    /// Bytes up and down of each category, in the order of `Category::ALL`
+    counters: Arc<[AtomicU64; 8]>,
+}
+
+impl Traffic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, category: Category, up: u64, down: u64) {
+        let i = category.index() * 2;
+        self.counters[i].fetch_add(up, Relaxed);
+        self.counters[i + 1].fetch_add(down, Relaxed);
+    }
+
+    /// Count the bytes exchanged with a peer, splitting off the block data
+    /// from the protocol overhead.
+    pub fn add_wire(&self, stats: &WireStats) {
+        self.add(Category::Payload, stats.payload_up, stats.payload_down);
+        self.add(
+            Category::Protocol,
+            stats.protocol_up(),
+            stats.protocol_down(),
+        );
+    }
+
+    pub fn stats(&self) -> TrafficStats {
+        let get = |c: Category| Transferred {
+            up: self.counters[c.index() * 2].load(Relaxed),
+            down: self.counters[c.index() * 2 + 1].load(Relaxed),
+        };
+        TrafficStats {
+            payload: get(Category::Payload),
+            protocol: get(Category::Protocol),
+            tracker: get(Category::Tracker),
+            dht: get(Category::Dht),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn categories() {
+        let traffic = Traffic::new();
+        assert_eq!(traffic.stats().efficiency(), None);
+
+        let shared = traffic.clone();
+        shared.add_wire(&WireStats {
+            bytes_up: 100,
+            bytes_down: 1100,
+            payload_up: 0,
+            payload_down: 1000,
+        });
+        shared.add(Category::Tracker, 300, 200);
+        shared.add(Category::Dht, 250, 150);
+
+        let stats = traffic.stats();
+        assert_eq!(stats.payload, Transferred { up: 0, down: 1000 });
+        assert_eq!(stats.protocol, Transferred { up: 100, down: 100 });
+        assert_eq!(stats.tracker.total(), 500);
+        assert_eq!(stats.get(Category::Dht).up, 250);
+        assert_eq!(
+            stats.total(),
+            Transferred {
+                up: 650,
+                down: 1450
+            }
+        );
+        assert_eq!(stats.efficiency(), Some(1000.0 / 2100.0));
+    }
+}
@@ -0,0 +1,53 @@
+use crate::cache::ReadCache;
+use crate::work::WorkQueue;
+use crate::worker::PieceReader;
+use client::InfoHash;
+use std::sync::Arc;
+
+/// Pieces read from the storage for uploading them. They are hash checked
+/// after reading, so that a piece which is yet to be written, or got
+/// corrupted on the disk, is never sent.
+pub struct PieceSource {
+    reader: PieceReader,
+
+    /// Verified pieces of the torrent, shared with the other connections
+    /// and torrents of the session, since peers request the blocks of
+    /// a piece in a row and tend to want the same pieces
+    cache: ReadCache,
+    info_hash: InfoHash,
+}
+
+impl PieceSource {
+    pub fn new(reader: PieceReader, cache: ReadCache, info_hash: InfoHash) -> Self {
+        Self {
+            reader,
+            cache,
+            info_hash,
+        }
+    }
+
+    /// The data of piece `index`, or `None` if it can't be read or doesn't
+    /// match its hash.
+    pub async fn read(&mut self, work: &WorkQueue, index: u32) -> Option<Arc<[u8]>> {
+        if let Some(piece) = self.cache.get(&self.info_hash, index) {
+            return Some(piece);
+        }
+
+        let buf = match (self.reader)(index).await {
+            Ok(buf) => buf.into_boxed_slice(),
+            Err(e) => {
+                debug!(index, "Unable to read the piece: {}", e);
+                return None;
+            }
+        };
+        let (verified, buf) = work.verify_buf(index, buf).await;
+        if !verified {
+            // Possibly still on its way to the storage
+            debug!(index, "Piece in the storage doesn't match its hash");
+            return None;
+        }
+        let piece: Arc<[u8]> = buf.into();
+        self.cache.insert(self.info_hash, index, piece.clone());
+        Some(piece)
+    }
+}
@@ -0,0 +1,690 @@
+//! Web seeds: HTTP servers which either host the files of the torrent
+//! (BEP 19, `url-list`) or hand out its pieces through a script (BEP 17,
+//! `httpseeds`).
+//!
+//! A `url-list` URL of a multi-file torrent is the directory holding the
+//! torrent's directory, so a piece spanning files is requested from each
+//! of them. The padding files aren't requested, they're all zeros.
+//!
+//! The web seeds fill in while the peers are slow, starting with the
+//! pieces the fewest peers have. One request is made at a time, taking
+//! turns among the seeds. A seed which fails is left alone for a while,
+//! twice as long after every failure in a row, and dropped after
+//! `MAX_FAILURES`.
+
+use crate::event::{EventBus, TorrentEvent};
+use crate::future::timeout;
+use crate::http::{redact, HttpClient, Response};
+use crate::work::{PartialPiece, Piece, WorkQueue, BLOCK_SIZE};
+use anyhow::ensure;
+use client::metainfo::FileMap;
+use client::torrent::Torrent;
+use client::InfoHash;
+use futures::channel::mpsc::Sender;
+use futures::SinkExt;
+use percent_encoding::{percent_encode, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::fmt;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+/// Max bytes requested at once. Larger pieces are fetched in parts.
+const REQUEST_LEN: u32 = 1024 * 1024;
+
+/// Seconds to wait for a response.
+const REQUEST_TIMEOUT: u64 = 30;
+
+/// Wait after the first failure of a seed. Doubled for every failure after.
+const BASE_BACKOFF: Duration = Duration::from_secs(5);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// Seeds failing this many times in a row are dropped.
+const MAX_FAILURES: u32 = 10;
+
+/// Period the download rate of the peers is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// How often to check for work while the web seeds are idle.
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+/// Characters escaped in the file name appended to a `url-list` URL.
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    /// BEP 19: URL of the file, requested in byte ranges.
+    UrlList,
+
+    /// BEP 17: Script taking the info hash, piece index and range.
+    HttpSeed,
+}
+
+/// Request for part of a piece.
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    /// GET the URL, for the range of the file in bytes if any.
+    Get {
+        url: String,
+        range: Option<Range<u64>>,
+    },
+
+    /// Bytes of padding files, which the servers don't have.
+    Padding(u64),
+}
+
+/// Files of a multi-file torrent, which `url-list` seeds serve one by one.
+#[derive(Debug)]
+struct Files {
+    map: FileMap,
+
+    /// Path of each file below the URL of the seeds, escaped
+    paths: Vec<String>,
+}
+
+impl Files {
+    fn new(torrent: &Torrent) -> Self {
+        let paths = torrent
+            .files
+            .iter()
+            .map(|file| {
+                let path = file
+                    .path
+                    .iter()
+                    .map(|p| utf8_percent_encode(p, PATH).to_string());
+                path.collect::<Vec<_>>().join("/")
+            })
+            .collect();
+        Self {
+            map: torrent.file_map(),
+            paths,
+        }
+    }
+
+    /// Requests for `range` of the torrent's data, one for each file it
+    /// spans.
+    fn requests(&self, url: &str, range: Range<u64>) -> Vec<Request> {
+        let mut requests = vec![];
+        let mut pos = range.start;
+        while pos < range.end {
+            let Some((file, offset)) = self.map.file_at(pos) else {
+                break;
+            };
+            let len = (range.end - pos).min(self.map.file_len(file) - offset);
+            if self.map.is_padding(file) {
+                requests.push(Request::Padding(len));
+            } else {
+                requests.push(Request::Get {
+                    url: format!("{}{}", url, self.paths[file]),
+                    range: Some(offset..offset + len),
+                });
+            }
+            pos += len;
+        }
+        requests
+    }
+}
+
+#[derive(Debug)]
+pub struct WebSeed {
+    pub url: String,
+    pub kind: WebSeedKind,
+
+    /// Failures since the last success
+    failures: u32,
+
+    /// Not used until then after a failure
+    retry_at: Option<Instant>,
+}
+
+impl WebSeed {
+    /// A `url-list` URL ending in `/` is the directory holding the file,
+    /// or the directory of a multi-file torrent, which is always one.
+    pub fn url_list(url: &str, name: &str, multi_file: bool) -> Self {
+        let mut url = url.to_owned();
+        if multi_file && !url.ends_with('/') {
+            url.push('/');
+        }
+        if url.ends_with('/') {
+            url.extend(utf8_percent_encode(name, PATH));
+        }
+        if multi_file {
+            url.push('/');
+        }
+        Self::new(url, WebSeedKind::UrlList)
+    }
+
+    pub fn http_seed(url: &str) -> Self {
+        Self::new(url.to_owned(), WebSeedKind::HttpSeed)
+    }
+
+    fn new(url: String, kind: WebSeedKind) -> Self {
+        Self {
+            url,
+            kind,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    fn is_ready(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|t| t <= now)
+    }
+
+    /// Requests for `range` of piece `index`.
+    fn requests(
+        &self,
+        info_hash: &InfoHash,
+        piece_len: u64,
+        files: Option<&Files>,
+        index: u32,
+        range: Range<u32>,
+    ) -> Vec<Request> {
+        match self.kind {
+            WebSeedKind::UrlList => {
+                let offset = index as u64 * piece_len;
+                let start = offset + range.start as u64;
+                let end = offset + range.end as u64;
+                match files {
+                    Some(files) => files.requests(&self.url, start..end),
+                    None => vec![Request::Get {
+                        url: self.url.clone(),
+                        range: Some(start..end),
+                    }],
+                }
+            }
+            WebSeedKind::HttpSeed => {
+                let separator = if self.url.contains('?') { '&' } else { '?' };
+                let url = format!(
+                    "{}{}info_hash={}&piece={}&ranges={}-{}",
+                    self.url,
+                    separator,
+                    percent_encode(info_hash, NON_ALPHANUMERIC),
+                    index,
+                    range.start,
+                    range.end - 1
+                );
+                vec![Request::Get { url, range: None }]
+            }
+        }
+    }
+}
+
+/// The web seeds of a torrent along with their failures.
+#[derive(Debug, Default)]
+pub struct WebSeeds {
+    seeds: Vec<WebSeed>,
+    info_hash: InfoHash,
+    piece_len: u64,
+
+    /// Files of a multi-file torrent
+    files: Option<Files>,
+
+    /// Seed to try first next time
+    next: usize,
+}
+
+impl WebSeeds {
+    /// HTTPS seeds need the `https` feature. The seeds of other schemes
+    /// are ignored.
+    pub fn new(torrent: &Torrent) -> Self {
+        let files = (!torrent.files.is_empty()).then(|| Files::new(torrent));
+        let multi_file = files.is_some();
+        let url_list = torrent
+            .url_list
+            .iter()
+            .map(|url| WebSeed::url_list(url, &torrent.name, multi_file));
+        let http_seeds = torrent.http_seeds.iter().map(|url| WebSeed::http_seed(url));
+
+        let seeds = url_list
+            .chain(http_seeds)
+            .filter(|seed| {
+                let supported = seed.url.starts_with("http://")
+                    || (cfg!(feature = "https") && seed.url.starts_with("https://"));
+                if !supported {
+                    debug!("Ignoring web seed {}", redact(&seed.url));
+                }
+                supported
+            })
+            .collect();
+
+        Self {
+            seeds,
+            info_hash: torrent.info_hash,
+            piece_len: torrent.piece_len as u64,
+            files,
+            next: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seeds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seeds.is_empty()
+    }
+
+    /// Index of the seed to use next. The seeds which are not backing off
+    /// take turns.
+    fn next_ready(&mut self, now: Instant) -> Option<usize> {
+        let n = self.seeds.len();
+        let i = (0..n)
+            .map(|k| (self.next + k) % n)
+            .find(|&i| self.seeds[i].is_ready(now))?;
+        self.next = i + 1;
+        Some(i)
+    }
+
+    fn succeeded(&mut self, i: usize) {
+        let seed = &mut self.seeds[i];
+        seed.failures = 0;
+        seed.retry_at = None;
+    }
+
+    /// Back off from seed `i`, for `retry_after` if the server asked for it.
+    fn failed(&mut self, i: usize, now: Instant, retry_after: Option<Duration>) {
+        let seed = &mut self.seeds[i];
+        seed.failures += 1;
+
+        if seed.failures >= MAX_FAILURES {
+            warn!(
+                "Dropping web seed {} after {} failures",
+                redact(&seed.url),
+                seed.failures
+            );
+            self.seeds.remove(i);
+            if self.next > i {
+                self.next -= 1;
+            }
+            return;
+        }
+
+        let backoff = retry_after.unwrap_or(BASE_BACKOFF * 2u32.pow(seed.failures - 1));
+        seed.retry_at = Some(now + backoff.min(MAX_BACKOFF));
+    }
+}
+
+/// Download rate of the peers, leaving out the bytes from the web seeds.
+#[derive(Debug)]
+struct SwarmRate {
+    since: Instant,
+
+    /// Bytes downloaded by the torrent as of `since`
+    total: u64,
+
+    /// Bytes downloaded from the web seeds since `since`
+    own: u64,
+    rate: Option<f64>,
+}
+
+impl SwarmRate {
+    fn new(total: u64, now: Instant) -> Self {
+        Self {
+            since: now,
+            total,
+            own: 0,
+            rate: None,
+        }
+    }
+
+    /// Bytes per second over the last full window, given the bytes
+    /// downloaded by the torrent so far. `None` until a window has passed.
+    fn update(&mut self, total: u64, now: Instant) -> Option<f64> {
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed >= RATE_WINDOW {
+            let swarm = total.saturating_sub(self.total).saturating_sub(self.own);
+            self.rate = Some(swarm as f64 / elapsed.as_secs_f64());
+            self.since = now;
+            self.total = total;
+            self.own = 0;
+        }
+        self.rate
+    }
+}
+
+/// The server is overloaded and asks to retry later.
+#[derive(Debug)]
+struct Unavailable(Option<Duration>);
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Web seed unavailable")
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+/// Wait asked for in a 503 response. BEP 17 seeds send the seconds in the
+/// body, other servers in the `Retry-After` header.
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let secs = match resp.header("retry-after") {
+        Some(secs) => secs,
+        None => std::str::from_utf8(&resp.body).ok()?,
+    };
+    secs.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Download pieces from the web seeds while the peers download slower than
+/// `cutoff` bytes per second.
+///
+/// Returns once the torrent is complete or every seed has been dropped.
+/// `busy` is set while a piece is taken from the work queue.
+pub async fn run(
+    seeds: &mut WebSeeds,
+    work: &WorkQueue,
+    events: &EventBus,
+    mut piece_tx: Sender<Piece>,
+    cutoff: u64,
+    busy: &AtomicBool,
+    paused: &AtomicBool,
+) {
+    let mut http = HttpClient::new();
+    let mut rate = SwarmRate::new(work.total_downloaded(), Instant::now());
+
+    while work.left() > 0 && !seeds.is_empty() {
+        let now = Instant::now();
+        let swarm_is_slow = rate
+            .update(work.total_downloaded(), now)
+            .is_none_or(|r| r < cutoff as f64);
+
+        let i = match seeds.next_ready(now) {
+            Some(i) if swarm_is_slow && !paused.load(Relaxed) => i,
+            _ => {
+                time::sleep(IDLE_CHECK).await;
+                continue;
+            }
+        };
+
+        busy.store(true, Relaxed);
+        let info = match work.remove_rarest_piece() {
+            Some(info) => info,
+            None => {
+                busy.store(false, Relaxed);
+                time::sleep(IDLE_CHECK).await;
+                continue;
+            }
+        };
+
+        // Blocks a peer left behind are kept
+        let index = info.index;
+        let mut piece = match work.take_partial(index) {
+            Some(piece) => piece,
+            None => work.new_partial(info),
+        };
+
+        let seed = &seeds.seeds[i];
+        match fetch_piece(&mut http, seeds, seed, &mut piece).await {
+            Ok(n) => {
+                work.add_downloaded(n);
+                rate.own += n as u64;
+
+                let (verified, buf) = work.verify(&piece).await;
+                if verified {
+                    debug!(index, url = %redact(&seed.url), "Piece verified");
+                    seeds.succeeded(i);
+
+                    // Pieces due soon may be finished by a peer first
+                    if work.mark_verified(index) {
+                        emit_bans(events, work.piece_passed(&piece));
+                        if piece_tx
+                            .send(work.finished_piece(index, buf))
+                            .await
+                            .is_err()
+                        {
+                            busy.store(false, Relaxed);
+                            return;
+                        }
+                    }
+                } else {
+                    warn!("Bad piece {} from web seed {}", index, redact(&seed.url));
+                    events.emit(TorrentEvent::HashFailed {
+                        index,
+                        peers: piece.contributors(),
+                    });
+                    emit_bans(events, work.piece_failed(&piece));
+                    seeds.failed(i, Instant::now(), None);
+                    if !work.is_verified(index) {
+                        work.add_piece(piece.info);
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Web seed {} failed: {}", redact(&seed.url), e);
+                let retry_after = e.downcast_ref::<Unavailable>().and_then(|u| u.0);
+                seeds.failed(i, Instant::now(), retry_after);
+                work.add_partial(piece);
+            }
+        }
+        busy.store(false, Relaxed);
+    }
+}
+
+/// Fetch the missing blocks of the piece. Returns the number of bytes
+/// downloaded.
+async fn fetch_piece(
+    http: &mut HttpClient,
+    seeds: &WebSeeds,
+    seed: &WebSeed,
+    piece: &mut PartialPiece,
+) -> anyhow::Result<usize> {
+    let mut downloaded = 0;
+    let len = piece.info.len;
+
+    for start in (0..len).step_by(REQUEST_LEN as usize) {
+        let end = len.min(start + REQUEST_LEN);
+        if (start..end)
+            .step_by(BLOCK_SIZE as usize)
+            .all(|b| piece.has_block(b))
+        {
+            continue;
+        }
+
+        let requests = seed.requests(
+            &seeds.info_hash,
+            seeds.piece_len,
+            seeds.files.as_ref(),
+            piece.info.index,
+            start..end,
+        );
+        let mut body = Vec::with_capacity((end - start) as usize);
+        for request in requests {
+            let (url, range) = match request {
+                Request::Get { url, range } => (url, range),
+                Request::Padding(len) => {
+                    body.resize(body.len() + len as usize, 0);
+                    continue;
+                }
+            };
+            let len = range
+                .as_ref()
+                .map_or((end - start) as u64, |r| r.end - r.start);
+            let resp = timeout(http.fetch(&url, range), REQUEST_TIMEOUT).await?;
+
+            if resp.status == 503 {
+                return Err(Unavailable(retry_after(&resp)).into());
+            }
+            ensure!(resp.is_success(), "Unexpected status {}", resp.status);
+            ensure!(
+                resp.body.len() as u64 == len,
+                "Expected {} bytes, got {}",
+                len,
+                resp.body.len()
+            );
+            downloaded += resp.body.len();
+            body.extend_from_slice(&resp.body);
+        }
+
+        for (i, block) in body.chunks(BLOCK_SIZE as usize).enumerate() {
+            piece.write_block(start + i as u32 * BLOCK_SIZE, block);
+        }
+    }
+
+    Ok(downloaded)
+}
+
+fn emit_bans(events: &EventBus, banned: Vec<SocketAddr>) {
+    for addr in banned {
+        events.emit(TorrentEvent::PeerBanned { addr });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seeds(urls: &[&str]) -> WebSeeds {
+        WebSeeds {
+            seeds: urls.iter().map(|url| WebSeed::http_seed(url)).collect(),
+            ..WebSeeds::default()
+        }
+    }
+
+    fn get(url: &str, range: Option<Range<u64>>) -> Request {
+        Request::Get {
+            url: url.to_owned(),
+            range,
+        }
+    }
+
+    #[test]
+    fn url_list_request() {
+        let seed = WebSeed::url_list("http://a.com/files/", "my file.iso", false);
+        assert_eq!(seed.url, "http://a.com/files/my%20file.iso");
+
+        let requests = seed.requests(&[0; 20], 100, None, 3, 10..20);
+        assert_eq!(
+            requests,
+            [get("http://a.com/files/my%20file.iso", Some(310..320))]
+        );
+
+        let seed = WebSeed::url_list("http://a.com/file.iso", "other.iso", false);
+        assert_eq!(seed.url, "http://a.com/file.iso");
+    }
+
+    #[test]
+    fn multi_file_url_list_requests() {
+        let files = Files {
+            map: FileMap::with_padding(100, [(150, false), (50, true), (300, false)]),
+            paths: vec!["a.txt".into(), "pad".into(), "dir/b%20c.bin".into()],
+        };
+        let seed = WebSeed::url_list("http://a.com/seeds/", "My Torrent", true);
+        assert_eq!(seed.url, "http://a.com/seeds/My%20Torrent/");
+        let seed = WebSeed::url_list("http://a.com/seeds", "My Torrent", true);
+        assert_eq!(seed.url, "http://a.com/seeds/My%20Torrent/");
+
+        let requests = seed.requests(&[0; 20], 100, Some(&files), 1, 40..100);
+        assert_eq!(
+            requests,
+            [
+                get("http://a.com/seeds/My%20Torrent/a.txt", Some(140..150)),
+                Request::Padding(50),
+            ]
+        );
+
+        let requests = seed.requests(&[0; 20], 100, Some(&files), 4, 0..100);
+        assert_eq!(
+            requests,
+            [get(
+                "http://a.com/seeds/My%20Torrent/dir/b%20c.bin",
+                Some(200..300)
+            )]
+        );
+    }
+
+    #[test]
+    fn http_seed_request() {
+        let seed = WebSeed::http_seed("http://a.com/seed?key=1");
+        let requests = seed.requests(&[0xab; 20], 100, None, 3, 0..100);
+        let url = format!(
+            "http://a.com/seed?key=1&info_hash={}&piece=3&ranges=0-99",
+            "%AB".repeat(20)
+        );
+        assert_eq!(requests, [get(&url, None)]);
+    }
+
+    #[test]
+    fn seeds_take_turns_and_back_off() {
+        let mut seeds = seeds(&["http://a", "http://b"]);
+        let now = Instant::now();
+        assert_eq!(seeds.next_ready(now), Some(0));
+        assert_eq!(seeds.next_ready(now), Some(1));
+        assert_eq!(seeds.next_ready(now), Some(0));
+
+        seeds.failed(0, now, None);
+        assert_eq!(seeds.next_ready(now), Some(1));
+        assert_eq!(seeds.next_ready(now), Some(1));
+        assert_eq!(seeds.next_ready(now + BASE_BACKOFF), Some(0));
+
+        // The wait doubles with every failure in a row
+        seeds.failed(0, now, None);
+        assert_eq!(seeds.seeds[0].retry_at, Some(now + BASE_BACKOFF * 2));
+        seeds.failed(0, now, Some(Duration::from_secs(1)));
+        assert_eq!(seeds.seeds[0].retry_at, Some(now + Duration::from_secs(1)));
+
+        seeds.succeeded(0);
+        assert!(seeds.seeds[0].is_ready(now));
+
+        seeds.failed(1, now, None);
+        assert_eq!(seeds.next_ready(now), Some(0));
+        assert_eq!(seeds.next_ready(now), Some(0));
+    }
+
+    #[test]
+    fn seeds_are_dropped_after_too_many_failures() {
+        let mut seeds = seeds(&["http://a", "http://b"]);
+        let now = Instant::now();
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(seeds.seeds[0].url, "http://a");
+            seeds.failed(0, now, None);
+        }
+        assert_eq!(seeds.len(), 1);
+        assert_eq!(seeds.seeds[0].url, "http://b");
+        assert_eq!(seeds.next_ready(now), Some(0));
+
+        let later = now + MAX_BACKOFF;
+        for _ in 0..MAX_FAILURES {
+            seeds.failed(0, later, None);
+        }
+        assert!(seeds.is_empty());
+        assert_eq!(seeds.next_ready(later), None);
+    }
+
+    #[test]
+    fn swarm_rate_leaves_out_web_seeds() {
+        let now = Instant::now();
+        let mut rate = SwarmRate::new(1000, now);
+        assert_eq!(rate.update(2000, now + Duration::from_secs(1)), None);
+
+        rate.own = 5000;
+        let rate = rate.update(16000, now + RATE_WINDOW).unwrap();
+        assert_eq!(rate, 10000.0 / RATE_WINDOW.as_secs_f64());
+    }
+
+    #[test]
+    fn retry_after_503() {
+        let resp = |headers: Vec<(String, String)>, body: &[u8]| Response {
+            status: 503,
+            headers,
+            body: body.to_vec(),
+            request_size: 0,
+        };
+
+        let header = vec![("Retry-After".to_owned(), "120".to_owned())];
+        assert_eq!(
+            retry_after(&resp(header, b"")),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&resp(vec![], b"30\n")),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(retry_after(&resp(vec![], b"busy")), None);
+    }
+}
@@ -1,107 +1,1017 @@
+use crate::forensic::Forensics;
+use crate::hash::{PieceHasher, Sha1Pieces};
+use crate::pool::{Block, BlockPool, Reservation};
+use crate::resume::ResumeData;
+use client::avg::MovingAverage;
+use client::bitfield::Bitfield;
+use client::msg::HashRequest;
+use client::InfoHash;
 use futures::channel::oneshot;
 use rayon::ThreadPool;
 use rayon::ThreadPoolBuilder;
-use sha1::Sha1;
-use std::cell::Cell;
-use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::future::{self, Future};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
 
+/// Size of the blocks a piece is requested in.
+pub const BLOCK_SIZE: u32 = 0x4000;
+
+/// The blocks of the pieces due within this long are requested from more
+/// than one peer, so that a slow peer can't make them miss their deadline.
+const DEADLINE_WINDOW: Duration = Duration::from_secs(3);
+
+/// Number of rate samples the download rate is averaged over.
+const RATE_SAMPLES: usize = 10;
+
+/// Number of the pieces written last which the resume data lists for the
+/// spot check.
+const RECENT_PIECES: usize = 8;
+
+/// Pieces left to download, shared by all the connections of a torrent.
+///
+/// The connections may run on different threads of the runtime.
 pub struct WorkQueue {
-    pieces: RefCell<VecDeque<PieceInfo>>,
+    pieces: Mutex<VecDeque<PieceInfo>>,
+    partial: Mutex<HashMap<u32, PartialPiece>>,
     verifier: PieceVerifier,
-    downloaded: Cell<usize>,
+    forensics: Mutex<Forensics>,
+    pool: BlockPool,
+
+    /// Number of connected peers having each piece
+    availability: Mutex<Vec<u32>>,
+
+    /// Pieces needed by a certain time, e.g. for streaming
+    deadlines: Mutex<HashMap<u32, Instant>>,
+
+    /// Pieces with a deadline being downloaded from several peers at once
+    striped: Mutex<HashMap<u32, StripedPiece>>,
+
+    /// Pieces which passed the hash check
+    verified: Mutex<Bitfield>,
+
+    /// Pieces in the order they passed the hash check in this session,
+    /// for telling the peers. Locked after `verified`.
+    verified_log: Mutex<Vec<u32>>,
+
+    /// Connections waiting in `piece_verified`. Locked after `verified_log`.
+    verified_wakers: Mutex<Vec<Waker>>,
+    downloaded: AtomicUsize,
+    total_downloaded: AtomicU64,
+    total_uploaded: AtomicU64,
+    rate: Mutex<MovingAverage<RATE_SAMPLES>>,
+    corrupt: AtomicU64,
+    left: AtomicU64,
+    num_pieces: usize,
+    piece_len: usize,
+    len: usize,
 }
 
 impl WorkQueue {
+    /// Work queue of a version 1 torrent with the SHA-1 `hashes` of the
+    /// pieces.
     pub fn new(piece_len: usize, len: usize, hashes: Vec<u8>) -> Self {
-        let pieces = PieceIter::new(piece_len, len).collect();
+        Self::with_hasher(piece_len, len, Sha1Pieces::new(hashes))
+    }
+
+    /// Work queue checking the pieces with `hasher`.
+    pub fn with_hasher(piece_len: usize, len: usize, hasher: impl PieceHasher + 'static) -> Self {
+        let pieces: VecDeque<_> = PieceIter::new(piece_len, len).collect();
+        let num_pieces = pieces.len();
 
         Self {
-            pieces: RefCell::new(pieces),
-            downloaded: Cell::new(0),
-            verifier: PieceVerifier::new(2, hashes),
+            num_pieces,
+            pieces: Mutex::new(pieces),
+            partial: Mutex::new(HashMap::new()),
+            downloaded: AtomicUsize::new(0),
+            total_downloaded: AtomicU64::new(0),
+            total_uploaded: AtomicU64::new(0),
+            rate: Mutex::new(MovingAverage::new()),
+            corrupt: AtomicU64::new(0),
+            left: AtomicU64::new(len as u64),
+            verifier: PieceVerifier::new(hash_threads(), Arc::new(hasher)),
+            forensics: Mutex::new(Forensics::new()),
+            pool: BlockPool::new(),
+            availability: Mutex::new(vec![0; num_pieces]),
+            deadlines: Mutex::new(HashMap::new()),
+            striped: Mutex::new(HashMap::new()),
+            verified: Mutex::new(Bitfield::with_size(num_pieces)),
+            verified_log: Mutex::new(vec![]),
+            verified_wakers: Mutex::new(vec![]),
+            piece_len,
+            len,
+        }
+    }
+
+    /// Drop the pieces we already have and pick up the partially downloaded
+    /// pieces from an earlier session.
+    pub fn restore(&self, resume: ResumeData) {
+        let mut pieces = self.pieces.lock().unwrap();
+        let mut verified = self.verified.lock().unwrap();
+        pieces.retain(|p| {
+            let have = resume.have.get_bit(p.index as usize);
+            if have {
+                self.left.fetch_sub(p.len as u64, Relaxed);
+                verified.set_bit(p.index as usize);
+            }
+            !have
+        });
+        drop(verified);
+
+        for mut partial in resume.partial {
+            partial.set_pool(&self.pool);
+            let index = partial.info.index;
+            match pieces.iter().position(|p| p.index == index) {
+                Some(i) if pieces[i].len == partial.info.len => {
+                    // Finish the partial pieces first
+                    let info = pieces.remove(i).unwrap();
+                    pieces.push_front(info);
+                    self.partial.lock().unwrap().insert(index, partial);
+                }
+                _ => warn!("Discarding invalid partial piece: {}", index),
+            }
+        }
+    }
+
+    /// Create resume data for the current state of the queue.
+    ///
+    /// `have` contains the pieces which are already written to the storage.
+    pub fn resume_data(&self, info_hash: InfoHash, have: Bitfield) -> ResumeData {
+        let striped = self.striped.lock().unwrap();
+        let partial = self
+            .partial
+            .lock()
+            .unwrap()
+            .values()
+            .chain(striped.values().map(|s| &s.piece))
+            .filter(|p| !have.get_bit(p.info.index as usize))
+            .cloned()
+            .collect();
+
+        let log = self.verified_log.lock().unwrap();
+        let mut recent: Vec<_> = log
+            .iter()
+            .rev()
+            .filter(|&&i| have.get_bit(i as usize))
+            .take(RECENT_PIECES)
+            .copied()
+            .collect();
+        recent.reverse();
+
+        ResumeData {
+            info_hash,
+            have,
+            partial,
+            peers: vec![],
+            recent,
+        }
+    }
+
+    /// Put a partially downloaded piece back in the queue so that it can be
+    /// resumed later, possibly by another peer.
+    ///
+    /// Pieces which are verified already, because another peer finished
+    /// them first, are dropped.
+    pub fn add_partial(&self, mut partial: PartialPiece) {
+        if self.is_verified(partial.info.index) {
+            return;
+        }
+        partial.set_pool(&self.pool);
+
+        if partial.blocks.count() == 0 || partial.is_complete() {
+            self.add_piece(partial.info);
+            return;
+        }
+
+        self.pieces.lock().unwrap().push_front(PieceInfo {
+            index: partial.info.index,
+            len: partial.info.len,
+        });
+        self.partial
+            .lock()
+            .unwrap()
+            .insert(partial.info.index, partial);
+    }
+
+    /// Take the partially downloaded piece for given index, if any.
+    pub fn take_partial(&self, index: u32) -> Option<PartialPiece> {
+        self.partial.lock().unwrap().remove(&index)
+    }
+
+    /// Start a piece with its blocks buffered in the torrent's pool.
+    pub fn new_partial(&self, info: PieceInfo) -> PartialPiece {
+        PartialPiece::with_pool(info, self.pool.clone())
+    }
+
+    /// Pool the blocks of the pieces in progress are buffered in.
+    pub fn block_pool(&self) -> &BlockPool {
+        &self.pool
+    }
+
+    /// Returns true if another piece can be started without going over
+    /// the limit on buffered bytes. The blocks requested and yet to arrive
+    /// count, as do the copies of the pieces being hashed or on their way
+    /// to the storage.
+    pub fn has_room_for_piece(&self) -> bool {
+        self.pool.has_room(self.piece_len)
+    }
+
+    /// Count the pieces a peer has announced since `counted` was updated.
+    /// `counted` keeps track of the pieces already counted for the peer.
+    pub fn add_availability(&self, counted: &mut Bitfield, peer_pieces: &Bitfield) {
+        if counted.count() == peer_pieces.count() {
+            return;
+        }
+
+        if counted.len() < peer_pieces.len() {
+            counted.resize(peer_pieces.len());
+        }
+
+        let mut availability = self.availability.lock().unwrap();
+        for (i, have) in peer_pieces.iter().enumerate() {
+            if have && !counted.get_bit(i) {
+                counted.set_bit(i);
+                if let Some(n) = availability.get_mut(i) {
+                    *n += 1;
+                }
+            }
+        }
+    }
+
+    /// Number of connected peers which have piece `index`.
+    pub fn availability(&self, index: u32) -> u32 {
+        let availability = self.availability.lock().unwrap();
+        availability.get(index as usize).copied().unwrap_or(0)
+    }
+
+    /// Number of connected peers which have each piece, by index. A copy,
+    /// for going through many pieces without taking the lock for each.
+    pub fn availability_snapshot(&self) -> Vec<u32> {
+        self.availability.lock().unwrap().clone()
+    }
+
+    /// Forget the pieces counted for a disconnected peer.
+    pub fn remove_availability(&self, counted: &Bitfield) {
+        let mut availability = self.availability.lock().unwrap();
+        for (n, have) in availability.iter_mut().zip(counted.iter()) {
+            if have {
+                *n = n.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Take the piece fewest connected peers have. Ties go to the pieces
+    /// further back in the queue, which the peers get to last. Pieces with
+    /// a deadline go before all the others.
+    pub fn remove_rarest_piece(&self) -> Option<PieceInfo> {
+        let availability = self.availability.lock().unwrap();
+        let mut pieces = self.pieces.lock().unwrap();
+        let deadlines = self.deadlines.lock().unwrap();
+        let (i, _) = pieces.iter().enumerate().rev().min_by_key(|(_, p)| {
+            let due = deadlines.get(&p.index);
+            // Pieces with a deadline first, the earliest one first
+            (
+                due.is_none(),
+                due.copied(),
+                availability.get(p.index as usize).copied(),
+            )
+        })?;
+        pieces.remove(i)
+    }
+
+    /// Download piece `index` by `deadline`: it's picked before the pieces
+    /// without a deadline or with a later one, and once the deadline is
+    /// near, its blocks are requested from other peers as well. Setting it
+    /// again moves the deadline.
+    ///
+    /// The pieces with a deadline are striped: the peers which have one
+    /// download its blocks together rather than one peer the whole piece.
+    pub fn set_piece_deadline(&self, index: u32, deadline: Instant) {
+        if index as usize >= self.num_pieces || self.is_verified(index) {
+            return;
+        }
+        self.deadlines.lock().unwrap().insert(index, deadline);
+    }
+
+    /// Download piece `index` in the usual order again.
+    pub fn clear_piece_deadline(&self, index: u32) {
+        self.deadlines.lock().unwrap().remove(&index);
+    }
+
+    /// Returns true if piece `index` has a deadline.
+    pub fn has_deadline(&self, index: u32) -> bool {
+        self.deadlines.lock().unwrap().contains_key(&index)
+    }
+
+    /// Share a piece with a deadline with the other peers, so that they can
+    /// download its blocks too. See `claim_block`.
+    pub fn start_striped(&self, piece: PartialPiece) {
+        let owners = vec![None; piece.blocks.len()];
+        let index = piece.info.index;
+        let striped = StripedPiece { piece, owners };
+        self.striped.lock().unwrap().insert(index, striped);
+    }
+
+    /// Returns true if piece `index` is being downloaded from several peers.
+    pub fn is_striped(&self, index: u32) -> bool {
+        self.striped.lock().unwrap().contains_key(&index)
+    }
+
+    /// Take the striped piece due first which `peer` can help with: one
+    /// with blocks nobody is downloading, or, once it's due soon, blocks
+    /// other peers are downloading. `wanted` tells the pieces the peer can
+    /// download.
+    pub fn striped_piece(&self, peer: SocketAddr, wanted: impl Fn(u32) -> bool) -> Option<u32> {
+        let striped = self.striped.lock().unwrap();
+        let deadlines = self.deadlines.lock().unwrap();
+        let soon = Instant::now() + DEADLINE_WINDOW;
+        let helpful = |s: &StripedPiece, due: Option<Instant>| match due {
+            Some(due) if due <= soon => s.missing().any(|i| s.owners[i] != Some(peer)),
+            _ => s.unclaimed().next().is_some(),
+        };
+        striped
+            .iter()
+            .filter(|&(&i, _)| wanted(i))
+            .map(|(&i, s)| (i, s, deadlines.get(&i).copied()))
+            .filter(|&(_, s, due)| helpful(s, due))
+            .min_by_key(|&(i, _, due)| (due.is_none(), due, i))
+            .map(|(i, _, _)| i)
+    }
+
+    /// Claim a block of striped piece `index` for `peer` to request, and
+    /// return its offset and length. The blocks nobody is downloading go
+    /// first. Once the piece is due soon, the blocks other peers are
+    /// downloading are claimed too, so that a slow peer can't hold up the
+    /// piece. Blocks `requested` from `peer` already are never claimed.
+    pub fn claim_block(
+        &self,
+        index: u32,
+        peer: SocketAddr,
+        requested: impl Fn(u32) -> bool,
+    ) -> Option<(u32, u32)> {
+        let mut striped = self.striped.lock().unwrap();
+        let s = striped.get_mut(&index)?;
+        let unclaimed = s.unclaimed().find(|&i| !requested(block_begin(i)));
+        let i = match unclaimed {
+            Some(i) => i,
+            None => {
+                let due = self.deadlines.lock().unwrap().get(&index).copied()?;
+                if due > Instant::now() + DEADLINE_WINDOW {
+                    return None;
+                }
+                s.missing()
+                    .find(|&i| s.owners[i] != Some(peer) && !requested(block_begin(i)))?
+            }
+        };
+        s.owners[i] = Some(peer);
+        let begin = block_begin(i);
+        Some((begin, BLOCK_SIZE.min(s.piece.info.len - begin)))
+    }
+
+    /// Write a block of striped piece `index` sent by `peer`. The peer
+    /// whose block completes the piece gets it to verify.
+    pub fn write_striped(
+        &self,
+        index: u32,
+        begin: u32,
+        data: &[u8],
+        peer: SocketAddr,
+    ) -> StripedBlock {
+        let mut striped = self.striped.lock().unwrap();
+        let s = match striped.get_mut(&index) {
+            Some(s) => s,
+            None => return StripedBlock::Duplicate,
+        };
+        if !s.piece.write_block(begin, data) {
+            return StripedBlock::Duplicate;
+        }
+        s.piece.set_peer(begin, peer);
+        if !s.piece.is_complete() {
+            return StripedBlock::Written;
+        }
+        match striped.remove(&index) {
+            Some(s) => StripedBlock::Complete(s.piece),
+            None => unreachable!(),
+        }
+    }
+
+    /// Give up the blocks of striped piece `index` which `peer` is yet to
+    /// send, e.g. as it disconnects. Once nobody is downloading any of its
+    /// blocks, the piece goes back to the queue with the blocks downloaded
+    /// so far.
+    pub fn release_striped(&self, index: u32, peer: SocketAddr) {
+        let mut striped = self.striped.lock().unwrap();
+        let s = match striped.get_mut(&index) {
+            Some(s) => s,
+            None => return,
+        };
+        for owner in &mut s.owners {
+            if *owner == Some(peer) {
+                *owner = None;
+            }
+        }
+        if s.missing().any(|i| s.owners[i].is_some()) {
+            return;
+        }
+        if let Some(s) = striped.remove(&index) {
+            drop(striped);
+            self.add_partial(s.piece);
+        }
+    }
+
+    /// Record that piece `index` passed the hash check. Returns false if it
+    /// had already, i.e. another peer downloading it too finished first.
+    pub fn mark_verified(&self, index: u32) -> bool {
+        self.deadlines.lock().unwrap().remove(&index);
+        let mut verified = self.verified.lock().unwrap();
+        if verified.get_bit(index as usize) {
+            return false;
+        }
+        verified.set_bit(index as usize);
+        let mut log = self.verified_log.lock().unwrap();
+        log.push(index);
+        for waker in self.verified_wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+        true
+    }
+
+    pub fn is_verified(&self, index: u32) -> bool {
+        self.verified.lock().unwrap().get_bit(index as usize)
+    }
+
+    /// The pieces verified so far, for telling a new peer, along with the
+    /// position in the log of verified pieces to pass to `verified_since`
+    /// for the ones verified later.
+    pub fn verified_pieces(&self) -> (Bitfield, usize) {
+        let verified = self.verified.lock().unwrap();
+        let log = self.verified_log.lock().unwrap();
+        (verified.clone(), log.len())
+    }
+
+    /// Pieces verified since `seen` of the log, moving `seen` past them.
+    pub fn verified_since(&self, seen: &mut usize) -> Vec<u32> {
+        let log = self.verified_log.lock().unwrap();
+        let new = log.get(*seen..).unwrap_or_default().to_vec();
+        *seen = log.len();
+        new
+    }
+
+    /// Resolves once a piece is verified past `seen` of the log, so that
+    /// an idle connection can tell its peer about it.
+    pub fn piece_verified(&self, seen: usize) -> impl Future<Output = ()> + '_ {
+        future::poll_fn(move |cx| {
+            let log = self.verified_log.lock().unwrap();
+            if log.len() > seen {
+                return Poll::Ready(());
+            }
+            let mut wakers = self.verified_wakers.lock().unwrap();
+            if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+    }
+
+    /// Returns true if the queue has a piece for which `wanted` is true,
+    /// e.g. one the peer has.
+    pub fn has_piece(&self, wanted: impl Fn(u32) -> bool) -> bool {
+        self.pieces.lock().unwrap().iter().any(|p| wanted(p.index))
+    }
+
+    /// Total number of pieces in the torrent.
+    pub fn num_pieces(&self) -> usize {
+        self.num_pieces
+    }
+
+    /// All the pieces of the torrent.
+    pub fn all_pieces(&self) -> PieceIter {
+        PieceIter::new(self.piece_len, self.len)
+    }
+
+    /// Index and length of piece `index`, if there's such a piece.
+    pub fn piece_info(&self, index: u32) -> Option<PieceInfo> {
+        let offset = self.piece_offset(index) as usize;
+        if index as usize >= self.num_pieces || offset >= self.len {
+            return None;
         }
+        let len = self.piece_len.min(self.len - offset) as u32;
+        Some(PieceInfo { index, len })
+    }
+
+    /// Offset of the piece in the torrent data.
+    pub fn piece_offset(&self, index: u32) -> u64 {
+        self.piece_len as u64 * index as u64
     }
 
+    /// Queue the piece for downloading, again if it was verified already,
+    /// e.g. because writing it failed. Such a piece counts as left again.
     pub fn add_piece(&self, info: PieceInfo) {
-        self.pieces.borrow_mut().push_back(info);
+        let mut pieces = self.pieces.lock().unwrap();
+        let mut verified = self.verified.lock().unwrap();
+        if verified.get_bit(info.index as usize) {
+            verified.clear_bit(info.index as usize);
+            self.left.fetch_add(info.len as u64, Relaxed);
+        }
+        drop(verified);
+        pieces.push_back(info);
+    }
+
+    /// Take the piece with the earliest deadline, or the one nearest the
+    /// front of the queue if none has a deadline. Only pieces for which
+    /// `wanted` is true are taken, e.g. those the asking peer has.
+    pub fn remove_piece(&self, wanted: impl Fn(u32) -> bool) -> Option<PieceInfo> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let deadlines = self.deadlines.lock().unwrap();
+        // Usually none, so skip the scan of the whole queue for them
+        let due = if deadlines.is_empty() {
+            None
+        } else {
+            pieces
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| wanted(p.index))
+                .filter_map(|(i, p)| Some((i, deadlines.get(&p.index)?)))
+                .min_by_key(|&(_, due)| due)
+        };
+        let i = match due {
+            Some((i, _)) => i,
+            None => pieces.iter().position(|p| wanted(p.index))?,
+        };
+        pieces.remove(i)
     }
 
-    pub fn remove_piece(&self) -> Option<PieceInfo> {
-        self.pieces.borrow_mut().pop_front()
+    /// Take the piece nearest the back of the queue, away from the partial
+    /// pieces put back at the front.
+    pub fn remove_last_piece(&self, wanted: impl Fn(u32) -> bool) -> Option<PieceInfo> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let i = pieces.iter().rposition(|p| wanted(p.index))?;
+        pieces.remove(i)
     }
 
     pub fn len(&self) -> usize {
-        self.pieces.borrow().len()
+        self.pieces.lock().unwrap().len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.pieces.borrow().is_empty()
+        self.pieces.lock().unwrap().is_empty()
     }
 
     pub fn extend<I>(&self, iter: I)
     where
         I: IntoIterator<Item = PieceInfo>,
     {
-        self.pieces.borrow_mut().extend(iter);
+        self.pieces.lock().unwrap().extend(iter);
     }
 
-    pub async fn verify(&self, piece_info: &PieceInfo, data: &[u8]) -> bool {
-        self.verifier.verify(piece_info.index as usize, data).await
+    /// Check the hash of a downloaded piece. The hashing is done on a
+    /// thread pool so that the runtime can keep serving the peers.
+    ///
+    /// The blocks are assembled into a piece buffer which is handed back
+    /// along with the result.
+    pub async fn verify(&self, piece: &PartialPiece) -> (bool, Box<[u8]>) {
+        let _copy = self.pool.reserve(piece.info.len as usize);
+        self.verify_buf(piece.info.index, piece.to_buf()).await
+    }
+
+    /// Verified piece to send to the storage. Its buffer counts against the
+    /// limit on buffered bytes until the storage is done with it.
+    pub fn finished_piece(&self, index: u32, buf: Box<[u8]>) -> Piece {
+        Piece {
+            index,
+            reserved: Some(self.pool.reserve(buf.len())),
+            buf,
+        }
+    }
+
+    /// Hash the pieces on the task verifying them instead of the hashing
+    /// threads.
+    pub fn hash_inline(&mut self) {
+        self.verifier = PieceVerifier::inline(self.verifier.hasher.clone());
+    }
+
+    /// Check the hash of piece `index`. The buffer is handed back along with
+    /// the result.
+    pub async fn verify_buf(&self, index: u32, buf: Box<[u8]>) -> (bool, Box<[u8]>) {
+        self.verifier.verify(index, buf).await
+    }
+
+    /// The hasher checking the pieces, for hashing them off the runtime,
+    /// e.g. with the read of a stored piece.
+    pub fn hasher(&self) -> Arc<dyn PieceHasher> {
+        self.verifier.hasher.clone()
+    }
+
+    /// The `hashes` reply to a hash request of a peer, see
+    /// [`PieceHasher::hashes`].
+    pub fn hashes(&self, req: &HashRequest) -> Option<Vec<u8>> {
+        self.verifier.hasher.hashes(req)
+    }
+
+    /// Hash request for the hashes of the blocks of piece `index`, see
+    /// [`PieceHasher::blocks_request`].
+    pub fn blocks_request(&self, index: u32) -> Option<HashRequest> {
+        self.verifier.hasher.blocks_request(index)
+    }
+
+    /// Check the blocks of `data`, piece `index` which failed the hash
+    /// check, with their `hashes` and ban the `peers` which sent the bad
+    /// ones. Returns the peers banned, or `None` if the hashes are invalid.
+    pub fn check_failed_blocks(
+        &self,
+        index: u32,
+        hashes: &[u8],
+        data: &[u8],
+        peers: &[Option<SocketAddr>],
+    ) -> Option<Vec<SocketAddr>> {
+        let checked = self.verifier.hasher.check_blocks(index, hashes, data)?;
+        let culprits = checked
+            .iter()
+            .zip(peers)
+            .filter(|(&good, _)| !good)
+            .filter_map(|(_, &peer)| peer);
+        Some(self.forensics.lock().unwrap().blocks_failed(culprits))
+    }
+
+    /// Record the contributors of a piece which failed the hash check.
+    /// Returns the peers banned as a result.
+    pub fn piece_failed(&self, piece: &PartialPiece) -> Vec<SocketAddr> {
+        self.corrupt.fetch_add(piece.info.len as u64, Relaxed);
+        self.forensics.lock().unwrap().piece_failed(piece)
+    }
+
+    /// Cross-check a verified piece with its failed attempts, if any.
+    /// Returns the peers banned as a result.
+    pub fn piece_passed(&self, piece: &PartialPiece) -> Vec<SocketAddr> {
+        let len = piece.info.len as u64;
+        let _ = self
+            .left
+            .fetch_update(Relaxed, Relaxed, |left| Some(left.saturating_sub(len)));
+        self.forensics.lock().unwrap().piece_passed(piece)
+    }
+
+    pub fn is_banned(&self, peer: &SocketAddr) -> bool {
+        self.forensics.lock().unwrap().is_banned(peer)
     }
 
     pub fn add_downloaded(&self, n: usize) {
-        let old = self.downloaded.get();
-        self.downloaded.set(old + n);
+        self.downloaded.fetch_add(n, Relaxed);
+        self.total_downloaded.fetch_add(n as u64, Relaxed);
     }
 
-    pub fn get_downloaded_and_reset(&self) -> usize {
-        let n = self.downloaded.get();
-        self.downloaded.set(0);
-        n
+    /// Bytes downloaded in this session.
+    pub fn total_downloaded(&self) -> u64 {
+        self.total_downloaded.load(Relaxed)
+    }
+
+    /// Count `n` bytes of block data sent to a peer.
+    pub fn add_uploaded(&self, n: u64) {
+        self.total_uploaded.fetch_add(n, Relaxed);
+    }
+
+    /// Block bytes uploaded to the peers in this session.
+    pub fn total_uploaded(&self) -> u64 {
+        self.total_uploaded.load(Relaxed)
+    }
+
+    /// Bytes of the pieces which failed the hash check in this session.
+    pub fn total_corrupt(&self) -> u64 {
+        self.corrupt.load(Relaxed)
+    }
+
+    /// Bytes of the torrent not verified yet.
+    pub fn left(&self) -> u64 {
+        self.left.load(Relaxed)
+    }
+
+    /// Add the bytes downloaded since the last sample, `elapsed` ago, to
+    /// the average download rate. Returns the rate of this sample in bytes
+    /// per second.
+    pub fn sample_rate(&self, elapsed: Duration) -> u64 {
+        let n = self.downloaded.swap(0, Relaxed) as u64;
+        let millis = elapsed.as_millis().max(1) as u64;
+        let rate = n.saturating_mul(1000) / millis;
+        let sample = rate.min(isize::MAX as u64 / 64) as isize;
+        self.rate.lock().unwrap().add_sample(sample);
+        rate
+    }
+
+    /// Average download rate in bytes per second.
+    pub fn download_rate(&self) -> u64 {
+        self.rate.lock().unwrap().mean().max(0) as u64
+    }
+
+    pub fn progress(&self) -> Progress {
+        let total = self.len as u64;
+        Progress {
+            total,
+            verified: total.saturating_sub(self.left()),
+            downloaded: self.total_downloaded(),
+            uploaded: self.total_uploaded(),
+            rate: self.download_rate(),
+        }
     }
 }
 
+/// How far the download of a torrent is, for display.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Length of the torrent.
+    pub total: u64,
+
+    /// Bytes of the pieces which passed the hash check, including those
+    /// of earlier sessions.
+    pub verified: u64,
+
+    /// Block bytes downloaded in this session, including those of the
+    /// pieces which aren't verified yet or failed the hash check.
+    pub downloaded: u64,
+
+    /// Block bytes uploaded in this session.
+    pub uploaded: u64,
+
+    /// Average download rate in bytes per second.
+    pub rate: u64,
+}
+
+impl Progress {
+    /// Share of the torrent verified, from 0 to 100.
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+        self.verified as f64 * 100.0 / self.total as f64
+    }
+
+    /// Bytes uploaded per byte downloaded in this session. `None` while
+    /// nothing is downloaded.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.downloaded > 0).then(|| self.uploaded as f64 / self.downloaded as f64)
+    }
+
+    /// Time left at the average rate. `None` while nothing is downloaded.
+    pub fn eta(&self) -> Option<Duration> {
+        let left = self.total.saturating_sub(self.verified);
+        if left == 0 {
+            return Some(Duration::ZERO);
+        }
+        (self.rate > 0).then(|| Duration::from_secs(left.div_ceil(self.rate)))
+    }
+}
+
+/// A piece whose blocks are downloaded from several peers at once.
+struct StripedPiece {
+    piece: PartialPiece,
+
+    /// Peer each block was last requested from
+    owners: Vec<Option<SocketAddr>>,
+}
+
+impl StripedPiece {
+    /// Blocks yet to arrive.
+    fn missing(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.owners.len()).filter(|&i| !self.piece.blocks.get_bit(i))
+    }
+
+    /// Blocks yet to arrive which nobody is downloading.
+    fn unclaimed(&self) -> impl Iterator<Item = usize> + '_ {
+        self.missing().filter(|&i| self.owners[i].is_none())
+    }
+}
+
+/// What became of a block of a striped piece.
 #[derive(Debug)]
+pub enum StripedBlock {
+    /// Another peer sent the block first, or the piece isn't striped
+    /// anymore.
+    Duplicate,
+
+    /// Written, with more blocks to go.
+    Written,
+
+    /// Written, and it was the last block missing.
+    Complete(PartialPiece),
+}
+
+fn block_begin(i: usize) -> u32 {
+    i as u32 * BLOCK_SIZE
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct PieceInfo {
     pub index: u32,
     pub len: u32,
 }
 
+/// A piece along with the blocks downloaded so far.
+///
+/// The blocks are buffered individually as they arrive so that a piece
+/// only takes up memory for the blocks received.
+#[derive(Debug, Clone)]
+pub struct PartialPiece {
+    pub info: PieceInfo,
+    pub blocks: Bitfield,
+    data: Vec<Option<Block>>,
+    pool: BlockPool,
+
+    /// Bytes of the blocks yet to arrive
+    missing: Reservation,
+
+    /// Peers which sent each block, if known
+    pub peers: Vec<Option<SocketAddr>>,
+}
+
+impl PartialPiece {
+    pub fn new(info: PieceInfo) -> Self {
+        Self::with_pool(info, BlockPool::new())
+    }
+
+    pub fn with_pool(info: PieceInfo, pool: BlockPool) -> Self {
+        let num_blocks = info.len.div_ceil(BLOCK_SIZE) as usize;
+        Self {
+            blocks: Bitfield::with_size(num_blocks),
+            data: (0..num_blocks).map(|_| None).collect(),
+            peers: vec![None; num_blocks],
+            missing: pool.reserve(info.len as usize),
+            pool,
+            info,
+        }
+    }
+
+    /// Returns true if the block starting at `begin` is downloaded.
+    pub fn has_block(&self, begin: u32) -> bool {
+        self.blocks.get_bit((begin / BLOCK_SIZE) as usize)
+    }
+
+    /// Write a block to the piece buffer.
+    ///
+    /// Returns false if the block doesn't belong to this piece or
+    /// it is already written.
+    pub fn write_block(&mut self, begin: u32, data: &[u8]) -> bool {
+        if !begin.is_multiple_of(BLOCK_SIZE) || self.has_block(begin) {
+            return false;
+        }
+
+        let expected_len = BLOCK_SIZE.min(self.info.len.saturating_sub(begin));
+        if data.len() != expected_len as usize {
+            return false;
+        }
+
+        let i = (begin / BLOCK_SIZE) as usize;
+        match self.data.get_mut(i) {
+            Some(slot) => {
+                let mut block = self.pool.alloc();
+                block[..data.len()].copy_from_slice(data);
+                *slot = Some(block);
+                self.missing.release(data.len());
+            }
+            None => return false,
+        }
+
+        self.blocks.set_bit(i);
+        true
+    }
+
+    /// Data of the `i`th block, if downloaded.
+    pub fn block(&self, i: usize) -> Option<&[u8]> {
+        let block = self.data.get(i)?.as_ref()?;
+        let begin = i as u32 * BLOCK_SIZE;
+        let len = BLOCK_SIZE.min(self.info.len - begin);
+        Some(&block[..len as usize])
+    }
+
+    /// Assemble the blocks into a piece buffer taken from the pool. Missing
+    /// blocks are zeroed.
+    pub fn to_buf(&self) -> Box<[u8]> {
+        let mut buf = self.pool.alloc_buf(self.info.len as usize);
+        for (i, chunk) in buf.chunks_mut(BLOCK_SIZE as usize).enumerate() {
+            match self.block(i) {
+                Some(block) => chunk.copy_from_slice(block),
+                None => chunk.fill(0),
+            }
+        }
+        buf
+    }
+
+    /// Move the blocks to `pool`, e.g. the torrent's pool for a piece
+    /// loaded from the resume data.
+    pub fn set_pool(&mut self, pool: &BlockPool) {
+        if self.pool.same(pool) {
+            return;
+        }
+        for block in self.data.iter_mut().flatten() {
+            block.move_to(pool);
+        }
+        self.missing.move_to(pool);
+        self.pool = pool.clone();
+    }
+
+    /// Remember the peer which sent the block starting at `begin`.
+    pub fn set_peer(&mut self, begin: u32, peer: SocketAddr) {
+        if let Some(p) = self.peers.get_mut((begin / BLOCK_SIZE) as usize) {
+            *p = Some(peer);
+        }
+    }
+
+    /// Peers which sent at least one block of this piece.
+    pub fn contributors(&self) -> Vec<SocketAddr> {
+        let mut peers: Vec<_> = self.peers.iter().flatten().copied().collect();
+        peers.sort_unstable();
+        peers.dedup();
+        peers
+    }
+
+    /// Number of bytes downloaded so far.
+    pub fn downloaded(&self) -> u32 {
+        let last = self.blocks.len().saturating_sub(1);
+        let last_len = self.info.len - last as u32 * BLOCK_SIZE;
+
+        let mut n = self.blocks.count() as u32 * BLOCK_SIZE;
+        if self.blocks.get_bit(last) {
+            n = n - BLOCK_SIZE + last_len;
+        }
+        n
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.blocks.is_all_set()
+    }
+}
+
 pub struct PieceVerifier {
-    pool: ThreadPool,
-    hashes: Vec<u8>,
+    /// Hashes on the calling task if `None`
+    pool: Option<ThreadPool>,
+    hasher: Arc<dyn PieceHasher>,
 }
 
 impl PieceVerifier {
-    pub fn new(num_threads: usize, hashes: Vec<u8>) -> Self {
+    pub fn new(num_threads: usize, hasher: Arc<dyn PieceHasher>) -> Self {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
         Self {
-            pool: ThreadPoolBuilder::new()
-                .num_threads(num_threads)
-                .build()
-                .unwrap(),
-            hashes,
+            pool: Some(pool),
+            hasher,
         }
     }
 
-    async fn verify(&self, index: usize, data: &[u8]) -> bool {
-        let expected_hash = &self.hashes[20 * index..][..20];
+    /// Verifier hashing on the calling task, e.g. for tests which need the
+    /// same order of events every run.
+    pub fn inline(hasher: Arc<dyn PieceHasher>) -> Self {
+        Self { pool: None, hasher }
+    }
+
+    async fn verify(&self, index: u32, data: Box<[u8]>) -> (bool, Box<[u8]>) {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return (self.hasher.verify(index, &data), data),
+        };
+        let hasher = self.hasher.clone();
         let (sender, receiver) = oneshot::channel();
 
-        self.pool.install(|| {
-            let actual_hash = Sha1::from(data).digest().bytes();
-            let matched = expected_hash == actual_hash;
-            let _ = sender.send(matched);
+        pool.spawn(move || {
+            let matched = hasher.verify(index, &data);
+            let _ = sender.send((matched, data));
         });
 
         receiver.await.unwrap()
     }
 }
 
+/// Number of threads hashing the pieces.
+fn hash_threads() -> usize {
+    std::thread::available_parallelism().map_or(2, |n| n.get())
+}
+
 pub struct Piece {
     pub index: u32,
     pub buf: Box<[u8]>,
+
+    /// Gives the buffer back to the pool once the storage is done with it
+    reserved: Option<Reservation>,
+}
+
+impl Piece {
+    pub fn new(index: u32, buf: Box<[u8]>) -> Self {
+        Self {
+            index,
+            buf,
+            reserved: None,
+        }
+    }
+}
+
+impl Drop for Piece {
+    fn drop(&mut self) {
+        if let Some(reserved) = &self.reserved {
+            reserved.pool().recycle(std::mem::take(&mut self.buf));
+        }
+    }
 }
 
 impl PartialEq for Piece {
@@ -114,7 +1024,7 @@ impl Eq for Piece {}
 
 impl PartialOrd for Piece {
     fn partial_cmp(&self, other: &Piece) -> Option<Ordering> {
-        other.index.partial_cmp(&self.index)
+        Some(self.cmp(other))
     }
 }
 
@@ -161,3 +1071,494 @@ impl Iterator for PieceIter {
         Some(piece)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn piece(len: u32) -> PartialPiece {
+        PartialPiece::new(PieceInfo { index: 0, len })
+    }
+
+    #[test]
+    fn partial_piece_blocks() {
+        let mut p = piece(BLOCK_SIZE * 2 + 10);
+        assert_eq!(p.blocks.len(), 3);
+        assert_eq!(p.downloaded(), 0);
+
+        assert!(p.write_block(BLOCK_SIZE * 2, &[1; 10]));
+        assert_eq!(p.downloaded(), 10);
+        assert!(p.has_block(BLOCK_SIZE * 2));
+        assert!(!p.has_block(0));
+
+        assert!(p.write_block(0, &[2; BLOCK_SIZE as usize]));
+        assert_eq!(p.downloaded(), BLOCK_SIZE + 10);
+        assert!(!p.is_complete());
+
+        assert!(p.write_block(BLOCK_SIZE, &[3; BLOCK_SIZE as usize]));
+        assert_eq!(p.downloaded(), BLOCK_SIZE * 2 + 10);
+        assert!(p.is_complete());
+        assert_eq!(p.block(2), Some(&[1; 10][..]));
+
+        let buf = p.to_buf();
+        assert_eq!(buf.len(), BLOCK_SIZE as usize * 2 + 10);
+        assert_eq!(buf[BLOCK_SIZE as usize - 1], 2);
+        assert_eq!(buf[BLOCK_SIZE as usize * 2], 1);
+    }
+
+    #[test]
+    fn partial_piece_rejects_bad_blocks() {
+        let mut p = piece(BLOCK_SIZE * 2);
+
+        // Unaligned
+        assert!(!p.write_block(1, &[1; BLOCK_SIZE as usize]));
+
+        // Incorrect size
+        assert!(!p.write_block(0, &[1; 10]));
+
+        // Out of bounds
+        assert!(!p.write_block(BLOCK_SIZE * 2, &[1; BLOCK_SIZE as usize]));
+
+        // Duplicate
+        assert!(p.write_block(0, &[1; BLOCK_SIZE as usize]));
+        assert!(!p.write_block(0, &[1; BLOCK_SIZE as usize]));
+    }
+
+    #[test]
+    fn partial_pieces_are_pooled() {
+        let work = WorkQueue::new(BLOCK_SIZE as usize * 2, BLOCK_SIZE as usize * 6, vec![]);
+        work.block_pool().set_limit(Some(BLOCK_SIZE as usize * 3));
+
+        // The blocks yet to arrive count
+        let mut a = work.new_partial(work.remove_piece(|_| true).unwrap());
+        assert_eq!(work.block_pool().reserved(), BLOCK_SIZE as usize * 2);
+        assert!(!work.has_room_for_piece());
+        assert!(a.write_block(0, &[1; BLOCK_SIZE as usize]));
+        assert!(a.write_block(BLOCK_SIZE, &[1; BLOCK_SIZE as usize]));
+        assert_eq!(work.block_pool().buffered(), BLOCK_SIZE as usize * 2);
+        assert_eq!(work.block_pool().reserved(), 0);
+        assert!(!work.has_room_for_piece());
+
+        assert_eq!(&a.to_buf()[..], &[1; BLOCK_SIZE as usize * 2][..]);
+
+        drop(a);
+        assert_eq!(work.block_pool().buffered(), 0);
+        assert!(work.has_room_for_piece());
+
+        // So does a piece on its way to the storage
+        let b = work.new_partial(work.remove_piece(|_| true).unwrap());
+        let piece = work.finished_piece(b.info.index, b.to_buf());
+        drop(b);
+        assert!(!work.has_room_for_piece());
+        let ptr = piece.buf.as_ptr();
+        drop(piece);
+        assert!(work.has_room_for_piece());
+
+        // Its buffer is reused for the next piece
+        let mut c = work.new_partial(work.remove_piece(|_| true).unwrap());
+        assert!(c.write_block(0, &[2; BLOCK_SIZE as usize]));
+        let buf = c.to_buf();
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf[BLOCK_SIZE as usize], 0);
+
+        // Pieces from elsewhere move to the torrent's pool
+        let mut d = PartialPiece::new(c.info.clone());
+        assert!(d.write_block(0, &[3; BLOCK_SIZE as usize]));
+        drop(c);
+        assert_eq!(work.block_pool().buffered(), 0);
+        work.add_partial(d);
+        assert_eq!(work.block_pool().buffered(), BLOCK_SIZE as usize);
+        assert_eq!(work.block_pool().reserved(), BLOCK_SIZE as usize);
+    }
+
+    #[test]
+    fn partial_pieces_are_resumed_first() {
+        let work = WorkQueue::new(BLOCK_SIZE as usize * 2, BLOCK_SIZE as usize * 6, vec![]);
+        assert_eq!(work.len(), 3);
+
+        let info = work.remove_piece(|_| true).unwrap();
+        let info2 = work.remove_piece(|_| true).unwrap();
+        assert_eq!(info2.index, 1);
+
+        let mut p = PartialPiece::new(info2);
+        assert!(p.write_block(0, &[1; BLOCK_SIZE as usize]));
+        work.add_partial(p);
+
+        // Partial pieces without any block are just queued back
+        work.add_partial(PartialPiece::new(info));
+
+        assert_eq!(work.remove_piece(|_| true).unwrap().index, 1);
+        assert!(work.take_partial(1).unwrap().has_block(0));
+        assert!(work.take_partial(1).is_none());
+    }
+
+    #[test]
+    fn rarest_piece() {
+        let work = WorkQueue::new(BLOCK_SIZE as usize, BLOCK_SIZE as usize * 4, vec![]);
+        let bitfield = |bits: &[usize]| {
+            let mut b = Bitfield::with_size(4);
+            bits.iter().for_each(|&i| b.set_bit(i));
+            b
+        };
+
+        let mut a = Bitfield::new();
+        let mut b = Bitfield::new();
+        work.add_availability(&mut a, &bitfield(&[0, 1, 3]));
+        work.add_availability(&mut b, &bitfield(&[1]));
+        work.add_availability(&mut b, &bitfield(&[1, 2]));
+        assert_eq!(a.count(), 3);
+        assert_eq!(b.count(), 2);
+        assert_eq!(work.availability(1), 2);
+        assert_eq!(work.availability(9), 0);
+        assert_eq!(work.availability_snapshot()[..4], [1, 2, 1, 1]);
+
+        // All but 1 are had by a single peer, 3 is furthest back
+        assert_eq!(work.remove_rarest_piece().unwrap().index, 3);
+
+        work.remove_availability(&a);
+        assert_eq!(work.remove_rarest_piece().unwrap().index, 0);
+        assert_eq!(work.remove_rarest_piece().unwrap().index, 2);
+        assert_eq!(work.remove_rarest_piece().unwrap().index, 1);
+        assert_eq!(work.remove_rarest_piece(), None);
+    }
+
+    #[test]
+    fn partial_pieces_go_first() {
+        let work = WorkQueue::new(BLOCK_SIZE as usize * 2, BLOCK_SIZE as usize * 6, vec![]);
+
+        let info = work.remove_piece(|_| true).unwrap();
+        let mut partial = work.new_partial(info);
+        assert!(partial.write_block(0, &[1; BLOCK_SIZE as usize]));
+        work.add_partial(partial);
+
+        assert_eq!(work.remove_last_piece(|_| true).unwrap().index, 2);
+        assert_eq!(work.remove_piece(|_| true).unwrap().index, 0);
+        assert_eq!(work.remove_last_piece(|_| true).unwrap().index, 1);
+        assert_eq!(work.remove_last_piece(|_| true), None);
+    }
+
+    #[test]
+    fn only_wanted_pieces_are_taken() {
+        let work = WorkQueue::new(BLOCK_SIZE as usize, BLOCK_SIZE as usize * 4, vec![]);
+        work.set_piece_deadline(3, Instant::now());
+
+        assert_eq!(work.remove_piece(|i| i % 2 == 1).unwrap().index, 3);
+        assert_eq!(work.remove_piece(|i| i % 2 == 1).unwrap().index, 1);
+        assert_eq!(work.remove_piece(|i| i % 2 == 1), None);
+        assert_eq!(work.remove_last_piece(|i| i != 2).unwrap().index, 0);
+        assert_eq!(work.len(), 1);
+    }
+
+    #[test]
+    fn deadline_pieces_go_first() {
+        let work = WorkQueue::new(BLOCK_SIZE as usize, BLOCK_SIZE as usize * 5, vec![]);
+        let now = Instant::now();
+        work.set_piece_deadline(3, now + Duration::from_secs(60));
+        work.set_piece_deadline(2, now + Duration::from_secs(30));
+        work.set_piece_deadline(9, now);
+
+        assert_eq!(work.remove_piece(|_| true).unwrap().index, 2);
+        assert_eq!(work.remove_rarest_piece().unwrap().index, 3);
+        assert_eq!(work.remove_piece(|_| true).unwrap().index, 0);
+
+        // Back to the usual order
+        work.set_piece_deadline(4, now);
+        work.clear_piece_deadline(4);
+        assert_eq!(work.remove_piece(|_| true).unwrap().index, 1);
+    }
+
+    #[test]
+    fn deadline_pieces_are_striped() {
+        let b = BLOCK_SIZE;
+        let work = WorkQueue::new(b as usize * 4, b as usize * 16, vec![]);
+        let now = Instant::now();
+        work.set_piece_deadline(1, now + Duration::from_secs(1));
+        work.set_piece_deadline(2, now + Duration::from_secs(60));
+        let peer_a: SocketAddr = "1.1.1.1:1".parse().unwrap();
+        let peer_b: SocketAddr = "2.2.2.2:2".parse().unwrap();
+
+        // Still in the queue
+        assert_eq!(work.striped_piece(peer_a, |_| true), None);
+
+        for index in [1, 2] {
+            let info = work.remove_piece(|_| true).unwrap();
+            assert_eq!(info.index, index);
+            assert!(work.has_deadline(index));
+            work.start_striped(work.new_partial(info));
+        }
+        assert_eq!(work.striped_piece(peer_a, |_| true), Some(1));
+        assert_eq!(work.striped_piece(peer_a, |i| i != 1), Some(2));
+
+        // Each peer gets blocks of its own
+        for i in 0..3 {
+            assert_eq!(work.claim_block(1, peer_a, |_| false), Some((i * b, b)));
+        }
+        assert_eq!(work.claim_block(1, peer_b, |_| false), Some((3 * b, b)));
+
+        // Due soon, so the blocks of the other peer are claimed too
+        assert_eq!(work.claim_block(1, peer_b, |o| o == 0), Some((b, b)));
+        assert_eq!(work.striped_piece(peer_a, |i| i == 1), Some(1));
+
+        // But not before that
+        for _ in 0..4 {
+            assert!(work.claim_block(2, peer_a, |_| false).is_some());
+        }
+        assert_eq!(work.claim_block(2, peer_b, |_| false), None);
+        assert_eq!(work.striped_piece(peer_b, |i| i == 2), None);
+
+        let data = vec![0; b as usize];
+        assert!(matches!(
+            work.write_striped(1, 0, &data, peer_a),
+            StripedBlock::Written
+        ));
+        assert!(matches!(
+            work.write_striped(1, 0, &data, peer_b),
+            StripedBlock::Duplicate
+        ));
+        assert!(matches!(
+            work.write_striped(1, b, &data, peer_b),
+            StripedBlock::Written
+        ));
+        assert!(matches!(
+            work.write_striped(1, 2 * b, &data, peer_a),
+            StripedBlock::Written
+        ));
+        let piece = match work.write_striped(1, 3 * b, &data, peer_b) {
+            StripedBlock::Complete(piece) => piece,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!(piece.contributors(), [peer_a, peer_b]);
+        assert!(!work.is_striped(1));
+        assert!(matches!(
+            work.write_striped(1, 0, &data, peer_a),
+            StripedBlock::Duplicate
+        ));
+
+        // Back in the queue once nobody downloads it
+        assert!(matches!(
+            work.write_striped(2, 0, &data, peer_a),
+            StripedBlock::Written
+        ));
+        work.release_striped(2, peer_b);
+        assert!(work.is_striped(2));
+        work.release_striped(2, peer_a);
+        assert!(!work.is_striped(2));
+        assert_eq!(work.remove_piece(|_| true).unwrap().index, 2);
+        assert_eq!(work.take_partial(2).unwrap().downloaded(), b);
+
+        // The first copy to pass the check wins
+        assert!(work.mark_verified(1));
+        assert!(!work.mark_verified(1));
+
+        // Verified pieces aren't queued again
+        work.add_partial(piece);
+        assert_eq!(work.len(), 2);
+
+        // Unless the piece is lost after all
+        work.add_piece(work.piece_info(1).unwrap());
+        assert!(!work.is_verified(1));
+    }
+
+    #[test]
+    fn verified_log() {
+        let work = WorkQueue::new(BLOCK_SIZE as usize, BLOCK_SIZE as usize * 4, vec![]);
+        work.mark_verified(2);
+        let (have, mut seen) = work.verified_pieces();
+        assert!(have.get_bit(2));
+        assert!(work.verified_since(&mut seen).is_empty());
+
+        work.mark_verified(0);
+        work.mark_verified(3);
+        assert_eq!(work.verified_since(&mut seen), [0, 3]);
+        assert!(work.verified_since(&mut seen).is_empty());
+        assert!(work.has_piece(|i| i == 1));
+
+        let mut verified = Box::pin(work.piece_verified(seen));
+        assert!((&mut verified).now_or_never().is_none());
+        work.mark_verified(1);
+        assert!(verified.now_or_never().is_some());
+
+        // Only the pieces which made it to the storage are recent
+        let mut written = Bitfield::with_size(4);
+        for i in [0, 2, 3] {
+            written.set_bit(i);
+        }
+        assert_eq!(work.resume_data([0; 20], written).recent, [2, 0, 3]);
+    }
+
+    #[test]
+    fn piece_info() {
+        let work = WorkQueue::new(BLOCK_SIZE as usize * 2, BLOCK_SIZE as usize * 5, vec![]);
+        let info = |index, len| Some(PieceInfo { index, len });
+        assert_eq!(work.piece_info(0), info(0, BLOCK_SIZE * 2));
+        assert_eq!(work.piece_info(2), info(2, BLOCK_SIZE));
+        assert_eq!(work.piece_info(3), None);
+    }
+
+    #[test]
+    fn transfer_counters() {
+        let work = WorkQueue::new(BLOCK_SIZE as usize * 2, BLOCK_SIZE as usize * 5, vec![]);
+        assert_eq!(work.left(), BLOCK_SIZE as u64 * 5);
+
+        let mut have = Bitfield::with_size(3);
+        have.set_bit(0);
+        work.restore(ResumeData {
+            info_hash: [0; 20],
+            have,
+            partial: vec![],
+            peers: vec![],
+            recent: vec![],
+        });
+        assert_eq!(work.left(), BLOCK_SIZE as u64 * 3);
+
+        let info = work.remove_piece(|_| true).unwrap();
+        work.add_downloaded(info.len as usize);
+        let index = info.index;
+        work.mark_verified(index);
+        work.piece_passed(&PartialPiece::new(info));
+        assert_eq!(work.left(), BLOCK_SIZE as u64);
+        assert_eq!(work.total_downloaded(), BLOCK_SIZE as u64 * 2);
+
+        // Writing the piece failed, so it's downloaded again
+        work.add_piece(work.piece_info(index).unwrap());
+        assert_eq!(work.left(), BLOCK_SIZE as u64 * 3);
+        work.add_piece(work.piece_info(index).unwrap());
+        assert_eq!(work.left(), BLOCK_SIZE as u64 * 3);
+
+        work.add_uploaded(100);
+        work.add_uploaded(300);
+        assert_eq!(work.total_uploaded(), 400);
+    }
+
+    #[test]
+    fn progress_and_eta() {
+        let work = WorkQueue::new(1000, 4000, vec![]);
+        assert_eq!(work.progress().eta(), None);
+
+        let info = work.remove_piece(|_| true).unwrap();
+        work.add_downloaded(1000);
+        work.piece_passed(&PartialPiece::new(info));
+        assert_eq!(work.sample_rate(Duration::from_secs(2)), 500);
+        work.add_downloaded(1000);
+        assert_eq!(work.sample_rate(Duration::from_millis(500)), 2000);
+
+        let progress = work.progress();
+        assert_eq!(progress.verified, 1000);
+        assert_eq!(progress.downloaded, 2000);
+        assert_eq!(progress.rate, 1250);
+        assert_eq!(progress.percent(), 25.0);
+        assert_eq!(progress.eta(), Some(Duration::from_secs(3)));
+        assert_eq!(progress.ratio(), Some(0.0));
+        work.add_uploaded(3000);
+        assert_eq!(work.progress().ratio(), Some(1.5));
+
+        let done = Progress {
+            verified: 4000,
+            ..progress
+        };
+        assert_eq!(done.eta(), Some(Duration::ZERO));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn verify_concurrently() {
+        let len = BLOCK_SIZE as usize;
+        let data: Vec<_> = (0..8u8).map(|i| vec![i; len]).collect();
+        let hashes = data.iter().flat_map(|d| crate::hash::sha1(d)).collect();
+        let work = std::sync::Arc::new(WorkQueue::new(len, len * 8, hashes));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let work = work.clone();
+                let mut piece = PartialPiece::new(PieceInfo {
+                    index: i,
+                    len: len as u32,
+                });
+
+                // Odd pieces get the data of the next piece
+                assert!(piece.write_block(0, &vec![(i + i % 2) as u8; len]));
+                tokio::spawn(async move { work.verify(&piece).await.0 })
+            })
+            .collect();
+
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await.unwrap(), i % 2 == 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_merkle_pieces() {
+        use crate::hash::MerklePieces;
+        use client::merkle::{data_root, piece_roots};
+
+        let piece_len = BLOCK_SIZE as usize * 2;
+        let data = vec![7; piece_len + BLOCK_SIZE as usize];
+        let layer: Vec<u8> = data
+            .chunks(piece_len)
+            .flat_map(|piece| data_root(piece, 2))
+            .collect();
+        let roots = piece_roots(
+            piece_len,
+            data.len() as u64,
+            data_root(&data, 4),
+            Some(&layer),
+        );
+        let work = WorkQueue::with_hasher(piece_len, data.len(), MerklePieces::new(roots.unwrap()));
+
+        for (i, piece) in data.chunks(piece_len).enumerate() {
+            let (verified, _) = work.verify_buf(i as u32, piece.into()).await;
+            assert!(verified);
+        }
+        let (verified, _) = work.verify_buf(1, data[..piece_len].into()).await;
+        assert!(!verified);
+    }
+
+    #[test]
+    fn merkle_hashes_find_bad_blocks() {
+        use crate::hash::MerklePieces;
+        use client::merkle::{data_root, layer_hashes, piece_roots, sha256, BLOCK_SIZE};
+
+        let piece_len = BLOCK_SIZE * 2;
+        let data: Vec<u8> = (0..piece_len * 3).map(|i| (i / 1000) as u8).collect();
+        let layer: Vec<u8> = data
+            .chunks(piece_len)
+            .flat_map(|piece| data_root(piece, 2))
+            .collect();
+        let file_root = data_root(&data, 8);
+        let roots = piece_roots(piece_len, data.len() as u64, file_root, Some(&layer)).unwrap();
+        let work = WorkQueue::with_hasher(piece_len, data.len(), MerklePieces::new(roots));
+
+        // The piece layer is served with its proof
+        let piece_layer = client::metainfo::PieceLayer {
+            pieces_root: file_root,
+            piece_len,
+            num_pieces: 3,
+        };
+        for req in piece_layer.requests() {
+            let hashes = work.hashes(&req).unwrap();
+            assert!(client::merkle::verify_hashes(&req, &hashes).is_some());
+        }
+
+        // Block hashes of piece 1 as a peer would send them
+        let req = work.blocks_request(1).unwrap();
+        assert!(work.hashes(&req).is_none());
+        let leaves: Vec<_> = data.chunks(BLOCK_SIZE).map(sha256).collect();
+        let hashes = layer_hashes(&req, &leaves, 8, [0; 32]).unwrap();
+
+        let mut piece = data[piece_len..piece_len * 2].to_vec();
+        piece[BLOCK_SIZE] ^= 1;
+        let good = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let bad = SocketAddr::from(([10, 0, 0, 2], 6881));
+        let peers = [Some(good), Some(bad)];
+        let banned = work
+            .check_failed_blocks(1, &hashes, &piece, &peers)
+            .unwrap();
+        assert_eq!(banned, [bad]);
+        assert!(work.is_banned(&bad) && !work.is_banned(&good));
+
+        // Hashes of another piece
+        assert!(work
+            .check_failed_blocks(0, &hashes, &piece, &peers)
+            .is_none());
+    }
+}
@@ -1,125 +1,969 @@
 use crate::{
-    announce::{DhtTracker, Tracker},
-    download::Download,
+    announce::{AnnounceSlots, DhtTracker, Event, Tracker, TransferStats},
+    blocklist::BanReason,
+    check,
+    choker::Choker,
+    download::{BothSeeds, Download, PeerTimeout},
+    event::{EventBus, HandshakeCheck, TorrentEvent},
     future::timeout,
+    hash::MerklePieces,
+    http::{redact, HttpConfig},
+    peer::{self, PeerAddr, SocketConfig},
+    ratelimit::TorrentBandwidth,
+    resume::ResumeData,
+    session::Session,
+    storage::{Storage, StorageErrorKind},
+    upload::PieceSource,
+    webseed::{self, WebSeeds},
     work::{Piece, WorkQueue},
 };
-use client::{torrent::Torrent, Client, InfoHash, PeerId};
+use client::{
+    bitfield::Bitfield, metainfo::Version, torrent::Torrent, AsyncStream, Client, FlushPolicy,
+    InfoHash, PeerId,
+};
+use data_encoding::HEXLOWER;
+use dht::NodeId;
 use futures::{
-    channel::mpsc::{self, Sender},
+    channel::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+    future::{self, AbortHandle, BoxFuture, FusedFuture},
+    lock::Mutex,
     select,
     stream::{self, FuturesUnordered},
     FutureExt, SinkExt, StreamExt,
 };
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet},
+    io::{self, IoSlice},
+    iter,
     net::SocketAddr,
-    time::Duration,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time,
 };
-use tokio::{net::TcpStream, time};
-use tracing::Instrument;
+use tracing::{field, Instrument, Span};
+
+/// Head start of the IPv6 address of a dual-stack peer before its IPv4
+/// address is dialed too.
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Bandwidth priority of torrents unless changed.
+const DEFAULT_PRIORITY: u32 = 1;
+
+/// Max seconds to wait for the trackers to acknowledge `Completed` and
+/// `Stopped` events, so that dead trackers don't delay the exit.
+const EVENT_ANNOUNCE_TIMEOUT: u64 = 2;
+
+/// Max time the completion hook waits for the writer to flush the pieces.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Gap between the first announces to the trackers of a torrent.
+const TRACKER_STAGGER: Duration = Duration::from_millis(200);
+
+/// How long to wait before reconnecting to a peer which had nothing for us.
+const IDLE_PEER_RETRY: Duration = Duration::from_secs(60);
+
+/// Longest wait before reconnecting to a peer which failed.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// Max number of peers saved in the resume data.
+const MAX_SAVED_PEERS: usize = 50;
+
+/// Number of pieces from the resume data hashed by `restore_checked`.
+const SPOT_CHECK_PIECES: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// Max number of peers downloaded from at the same time.
+    pub max_connections: usize,
+
+    /// Fraction of the connections reserved for peers we have never
+    /// connected to. Keeps discovering new, possibly faster peers even when
+    /// the other connections are all taken.
+    pub new_peer_ratio: f32,
+
+    /// Settings for announcing to HTTP trackers.
+    pub http: HttpConfig,
+
+    /// Options of the TCP sockets connecting to the peers.
+    pub socket: SocketConfig,
+
+    /// Max number of trackers announced to at the same time. The trackers
+    /// which gave us the most new peers so far go first.
+    pub max_concurrent_announces: usize,
+
+    /// Only seed the pieces we already have. Nothing is requested from the
+    /// peers.
+    pub upload_only: bool,
+
+    /// Never unchoke the peers, so that nothing is uploaded.
+    pub download_only: bool,
+
+    /// Max number of peers uploaded to at the same time. The slots go to
+    /// the peers which send us the most, but for one which goes round the
+    /// others.
+    pub max_uploads: usize,
+
+    /// Disconnect from seeds once we're a seed too, since there's nothing
+    /// to exchange.
+    pub disconnect_seeds: bool,
+
+    /// How long a peer has to send its handshake after connecting.
+    pub handshake_timeout: Duration,
+
+    /// How long a peer may keep us choked before we look for another one.
+    /// It's not held against the peer, which is retried later.
+    pub unchoke_timeout: Duration,
+
+    /// How long a peer which unchoked us has to send a block we requested.
+    pub request_timeout: Duration,
+
+    /// How long a peer may send nothing at all, not even a keep-alive,
+    /// before the connection is taken for lost.
+    pub idle_timeout: Duration,
+
+    /// How long a peer which unchoked us may send no blocks while we're
+    /// interested and have requests in flight, even if it keeps the
+    /// connection alive, before it's dropped as a freeloader and retried
+    /// later. `None` keeps such peers.
+    pub freeloader_timeout: Option<Duration>,
+
+    /// Max bytes buffered for the pieces in progress, `None` for no limit.
+    /// New pieces are not started while over the limit, except to keep
+    /// a peer busy, so it can be exceeded by up to a piece per peer.
+    pub max_buffered: Option<usize>,
+
+    /// Download from the web seeds while the peers download slower than
+    /// this many bytes per second. `None` never uses the web seeds.
+    pub web_seed_cutoff: Option<u64>,
+
+    /// Max number of times in a row connecting to a peer may fail before
+    /// the peer is given up on. Failed handshakes give up right away.
+    pub max_connect_attempts: u32,
+
+    /// Wait before reconnecting to a peer after its first failure. The wait
+    /// doubles with every failure after that.
+    pub connect_retry_delay: Duration,
+
+    /// Max length of the packets read from the peers. Raised as needed to
+    /// fit the bitfield of the torrent, since torrents with a lot of pieces
+    /// have bitfields over the 1 MiB default.
+    pub max_packet_len: usize,
+
+    /// Peers downloading slower than this many bytes per second are sent
+    /// one request at a time and give their pieces back to the queue after
+    /// every block, so that they can't hold up a piece the faster peers
+    /// could finish. `None` treats all the peers alike.
+    pub slow_peer_rate: Option<u64>,
+
+    /// When the messages to the peers are written to the sockets. Holding
+    /// them back briefly batches the requests into fewer writes.
+    pub flush_policy: FlushPolicy,
+
+    /// Log the bytes transferred by the session so far, broken down into
+    /// payload, protocol overhead, tracker and DHT traffic, this often.
+    /// `None` never logs them.
+    pub traffic_log_interval: Option<Duration>,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            new_peer_ratio: 0.2,
+            http: HttpConfig::default(),
+            socket: SocketConfig::default(),
+            max_concurrent_announces: 4,
+            upload_only: false,
+            download_only: false,
+            max_uploads: 4,
+            disconnect_seeds: true,
+            handshake_timeout: Duration::from_secs(10),
+            unchoke_timeout: Duration::from_secs(60),
+            request_timeout: Duration::from_secs(60),
+            idle_timeout: Duration::from_secs(150),
+            freeloader_timeout: Some(Duration::from_secs(300)),
+            max_buffered: None,
+            web_seed_cutoff: Some(1024 * 1024),
+            max_connect_attempts: 5,
+            connect_retry_delay: Duration::from_secs(30),
+            max_packet_len: client::DEFAULT_MAX_PACKET_LEN,
+            slow_peer_rate: Some(4 * 1024),
+            flush_policy: FlushPolicy::IMMEDIATE,
+            traffic_log_interval: None,
+        }
+    }
+}
+
+impl WorkerConfig {
+    fn reserved_slots(&self) -> usize {
+        let n = (self.max_connections as f32 * self.new_peer_ratio.clamp(0.0, 1.0)).ceil();
+        (n as usize).min(self.max_connections)
+    }
+}
+
+/// Connection to a peer opened by a `Dialer`.
+pub type DialedStream = Box<dyn AsyncStream + Send>;
+
+/// Opens the connections to the peers in place of TCP, e.g. to run
+/// a worker against in-memory peers.
+pub type Dialer =
+    Arc<dyn Fn(SocketAddr) -> BoxFuture<'static, io::Result<DialedStream>> + Send + Sync>;
+
+/// Connection to a peer. Only the ones opened by a `Dialer` are boxed.
+enum PeerStream {
+    Tcp(TcpStream),
+    Dialed(DialedStream),
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            PeerStream::Dialed(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            PeerStream::Dialed(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            PeerStream::Dialed(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            PeerStream::Tcp(s) => s.is_write_vectored(),
+            PeerStream::Dialed(s) => s.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            PeerStream::Dialed(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            PeerStream::Dialed(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Reads piece `index` of the torrent from the storage, for uploading it
+/// to the peers. See [`storage::piece_reader`](crate::storage::piece_reader).
+pub type PieceReader = Arc<dyn Fn(u32) -> BoxFuture<'static, io::Result<Vec<u8>>> + Send + Sync>;
+
+/// Called with the info hash once the worker has verified the last piece,
+/// e.g. to move the files, notify the user or start post-processing. The
+/// worker closes the piece channel first and waits until the writer of the
+/// pieces reports them flushed with `TorrentHandle::storage_flushed`, as
+/// [`storage::write_torrent_pieces`](crate::storage::write_torrent_pieces)
+/// does, so the hook sees the complete files. The peers are still served
+/// meanwhile. If the pieces aren't flushed within `FLUSH_TIMEOUT`, the hook
+/// isn't called. The completion is announced to the trackers once the
+/// returned future is done.
+pub type CompletionHook = Arc<dyn Fn(InfoHash) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Changes to a torrent sent through a `TorrentHandle`.
+#[derive(Debug)]
+enum Command {
+    AddTracker(String),
+    RemoveTracker(String),
+    AddPeer(SocketAddr),
+    SetPort(u16),
+    RestoreDhtNodes(Vec<(NodeId, SocketAddr)>),
+    SetPieceDeadline(u32, Option<Instant>),
+    Pause,
+    Resume,
+    StorageFailed {
+        index: Option<u32>,
+        kind: StorageErrorKind,
+        message: String,
+    },
+    StorageFlushed,
+}
+
+/// Handle for changing the trackers and peers of a torrent without
+/// restarting it. Changes made while the worker isn't running take effect
+/// once it runs again.
+#[derive(Debug, Clone)]
+pub struct TorrentHandle {
+    commands: UnboundedSender<Command>,
+}
+
+impl TorrentHandle {
+    /// Start announcing to the tracker. Does nothing if the torrent already
+    /// has the tracker.
+    pub fn add_tracker(&self, url: impl Into<String>) {
+        self.send(Command::AddTracker(url.into()));
+    }
+
+    /// Stop announcing to the tracker.
+    pub fn remove_tracker(&self, url: impl Into<String>) {
+        self.send(Command::RemoveTracker(url.into()));
+    }
+
+    /// Connect to the peer, even if connecting to it has failed before.
+    pub fn add_peer(&self, addr: SocketAddr) {
+        self.send(Command::AddPeer(addr));
+    }
+
+    /// Announce `port` as the one we listen on. The trackers and the DHT
+    /// are told right away.
+    pub fn set_port(&self, port: u16) {
+        self.send(Command::SetPort(port));
+    }
+
+    /// Add the DHT nodes of an earlier session, e.g. saved from
+    /// `Dht::nodes`. They are pinged and join the routing table once they
+    /// answer.
+    pub fn restore_dht_nodes(&self, nodes: Vec<(NodeId, SocketAddr)>) {
+        self.send(Command::RestoreDhtNodes(nodes));
+    }
+
+    /// Download piece `index` by `deadline`, e.g. the next piece of a media
+    /// file being played. Pieces with a deadline are picked first, and
+    /// their blocks spread over all the peers which have them. Once the
+    /// deadline is near, the blocks still missing are requested from more
+    /// than one peer.
+    pub fn set_piece_deadline(&self, index: u32, deadline: Instant) {
+        self.send(Command::SetPieceDeadline(index, Some(deadline)));
+    }
+
+    /// Download piece `index` in the usual order again, e.g. after seeking
+    /// away from it.
+    pub fn clear_piece_deadline(&self, index: u32) {
+        self.send(Command::SetPieceDeadline(index, None));
+    }
+
+    /// Stop downloading. The connections are dropped and the blocks
+    /// downloaded so far are kept for when the torrent is resumed.
+    pub fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(Command::Resume);
+    }
+
+    /// Report that writing piece `index` to the storage failed, or flushing
+    /// the storage if `None`. The torrent is paused and emits
+    /// `TorrentEvent::StorageError`; resume it once the storage is usable
+    /// again, e.g. after freeing some space.
+    pub fn storage_failed(&self, index: Option<u32>, error: &io::Error) {
+        self.send(Command::StorageFailed {
+            index,
+            kind: StorageErrorKind::of(error),
+            message: error.to_string(),
+        });
+    }
+
+    /// Report that the pieces sent by the worker were written and flushed,
+    /// once its piece channel is closed. The completion hook waits for it.
+    pub fn storage_flushed(&self) {
+        self.send(Command::StorageFlushed);
+    }
+
+    /// Returns true if the worker is gone.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+
+    /// Returns true if both handles are of the same worker.
+    pub(crate) fn is_same(&self, other: &Self) -> bool {
+        self.commands.same_receiver(&other.commands)
+    }
+
+    fn send(&self, command: Command) {
+        // The worker is gone, so there's nothing to change
+        let _ = self.commands.unbounded_send(command);
+    }
+}
 
 pub struct TorrentWorker {
     peer_id: PeerId,
     info_hash: InfoHash,
     work: WorkQueue,
     trackers: Vec<String>,
+
+    /// Trackers announced to so far, kept between the announces and the runs
+    /// for what they've learnt, e.g. the interval and the resolved address.
+    running_trackers: HashMap<String, SharedTracker>,
     peers: HashSet<SocketAddr>,
     peers6: HashSet<SocketAddr>,
-    dht_tracker: DhtTracker,
+
+    /// Peers which we have exchanged data with and which haven't failed
+    /// since. Saved in the resume data.
+    good_peers: HashSet<SocketAddr>,
+    web_seeds: WebSeeds,
+    dht_tracker: Option<DhtTracker>,
+    dialer: Option<Dialer>,
+    piece_reader: Option<PieceReader>,
+    on_complete: Option<CompletionHook>,
+    events: EventBus,
+    session: Session,
+    bandwidth: TorrentBandwidth,
+    config: WorkerConfig,
+    commands: UnboundedReceiver<Command>,
+    command_tx: UnboundedSender<Command>,
+
+    /// Port announced as the one we listen on.
+    port: u16,
+
+    /// Whether we have announced to the trackers at all.
+    started: bool,
+
+    /// Whether the trackers know that the download is complete.
+    completed: bool,
 }
 
 impl TorrentWorker {
     pub fn new(torrent: Torrent, peer_id: PeerId, dht: DhtTracker) -> Self {
-        let work = WorkQueue::new(torrent.piece_len, torrent.length, torrent.piece_hashes);
+        Self::with_session(Session::new(), torrent, peer_id, dht)
+    }
+
+    pub fn with_session(
+        session: Session,
+        torrent: Torrent,
+        peer_id: PeerId,
+        dht: DhtTracker,
+    ) -> Self {
+        Self::build(session, torrent, peer_id, Some(dht))
+    }
+
+    /// Worker finding its peers through the trackers only, e.g. for
+    /// private torrents or tests.
+    pub fn without_dht(session: Session, torrent: Torrent, peer_id: PeerId) -> Self {
+        Self::build(session, torrent, peer_id, None)
+    }
+
+    fn build(
+        session: Session,
+        torrent: Torrent,
+        peer_id: PeerId,
+        mut dht: Option<DhtTracker>,
+    ) -> Self {
+        let web_seeds = WebSeeds::new(&torrent);
+        let work = match torrent.version {
+            Version::V2 => {
+                let hasher = MerklePieces::new(torrent.piece_roots);
+                WorkQueue::with_hasher(torrent.piece_len, torrent.length, hasher)
+            }
+            _ => WorkQueue::new(torrent.piece_len, torrent.length, torrent.piece_hashes),
+        };
+        let (command_tx, commands) = mpsc::unbounded();
+        let port = session.watch_port(TorrentHandle {
+            commands: command_tx.clone(),
+        });
+        if let Some(dht) = &mut dht {
+            dht.set_port(port);
+            dht.set_traffic(session.traffic().clone());
+        }
+        let mut config = WorkerConfig::default();
+        config.http.user_agent = Some(session.identity().user_agent.clone());
 
         Self {
             peer_id,
             info_hash: torrent.info_hash,
             peers: torrent.peers,
             peers6: torrent.peers_v6,
+            good_peers: HashSet::new(),
+            web_seeds,
             work,
             trackers: torrent.tracker_urls,
+            running_trackers: HashMap::new(),
             dht_tracker: dht,
+            dialer: None,
+            piece_reader: None,
+            on_complete: None,
+            events: EventBus::new(),
+            bandwidth: session.rate_limiter().register(DEFAULT_PRIORITY),
+            session,
+            config,
+            commands,
+            command_tx,
+            port,
+            started: false,
+            completed: false,
         }
     }
 
     pub fn num_pieces(&self) -> usize {
-        self.work.len()
+        self.work.num_pieces()
+    }
+
+    /// Handle for adding and removing trackers and peers, also while the
+    /// worker is running.
+    pub fn handle(&self) -> TorrentHandle {
+        TorrentHandle {
+            commands: self.command_tx.clone(),
+        }
+    }
+
+    /// Subscribe to the events of this torrent.
+    pub fn subscribe(&self) -> UnboundedReceiver<TorrentEvent> {
+        self.events.subscribe()
+    }
+
+    /// Set the bandwidth priority of this torrent. Higher priority torrents
+    /// get more bandwidth depending on the session's bandwidth policy.
+    pub fn set_priority(&self, priority: u32) {
+        self.bandwidth.set_priority(priority);
+    }
+
+    /// Set the config of the worker. Trackers get the user agent of the
+    /// session's identity unless `config.http` has one.
+    pub fn set_config(&mut self, mut config: WorkerConfig) {
+        if config.http.user_agent.is_none() {
+            config.http.user_agent = Some(self.session.identity().user_agent.clone());
+        }
+        self.work.block_pool().set_limit(config.max_buffered);
+        self.config = config;
+    }
+
+    /// Connect to the peers with `dialer` instead of TCP.
+    pub fn set_dialer(&mut self, dialer: Dialer) {
+        self.dialer = Some(dialer);
+    }
+
+    /// Hash the pieces on the task running the worker, so that the order of
+    /// events only depends on its futures. Meant for tests; it holds up
+    /// the connections while hashing.
+    pub fn hash_inline(&mut self) {
+        self.work.hash_inline();
+    }
+
+    /// Upload the pieces we have to the peers, reading them with `reader`.
+    /// Nothing is uploaded without one.
+    pub fn set_piece_reader(&mut self, reader: PieceReader) {
+        self.piece_reader = Some(reader);
+    }
+
+    /// Call `hook` when the download completes. Torrents which were
+    /// complete already when `run` started don't call it.
+    pub fn set_completion_hook(&mut self, hook: CompletionHook) {
+        self.on_complete = Some(hook);
+    }
+
+    pub fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+
+    /// Resume the download from the state saved in an earlier session.
+    ///
+    /// The saved peers are connected to right away, without waiting for
+    /// the trackers.
+    pub fn restore(&mut self, mut resume: ResumeData) {
+        for addr in resume.peers.drain(..) {
+            if addr.is_ipv4() {
+                self.peers.insert(addr);
+            } else {
+                self.peers6.insert(addr);
+            }
+        }
+        self.work.restore(resume);
+    }
+
+    /// Resume the download like `restore`, after hashing the pieces written
+    /// last and a few others which the resume data says we have. Pieces
+    /// which fail the check, e.g. because a crash cut their write short, are
+    /// downloaded again. Returns the pieces we have.
+    pub async fn restore_checked<S>(
+        &mut self,
+        mut resume: ResumeData,
+        storage: S,
+    ) -> io::Result<Bitfield>
+    where
+        S: Storage + Send + Sync + 'static,
+    {
+        let failed = check::spot_check(
+            storage,
+            &self.work,
+            &resume.have,
+            &resume.recent,
+            SPOT_CHECK_PIECES,
+        )
+        .await?;
+        if !failed.is_empty() {
+            warn!(
+                "Pieces {:?} failed the check; downloading them again",
+                failed
+            );
+        }
+        for index in failed {
+            resume.have.clear_bit(index as usize);
+        }
+
+        let have = resume.have.clone();
+        self.restore(resume);
+        Ok(have)
+    }
+
+    /// Hash the pieces already in `storage` to find out which ones we
+    /// have, e.g. when there's no resume data. Use instead of `restore`.
+    ///
+    /// Progress is reported to the subscribers with `CheckProgress` and
+    /// `CheckFailed` events.
+    pub async fn check<S>(&mut self, storage: S) -> io::Result<Bitfield>
+    where
+        S: Storage + Send + Sync + 'static,
+    {
+        let have = check::check_pieces(storage, &self.work, &self.events).await?;
+        self.work.restore(ResumeData {
+            info_hash: self.info_hash,
+            have: have.clone(),
+            partial: vec![],
+            peers: vec![],
+            recent: vec![],
+        });
+        Ok(have)
     }
 
+    /// Current state of the download. `have` contains the pieces which
+    /// are written to the storage.
+    pub fn resume_data(&self, have: Bitfield) -> ResumeData {
+        let mut resume = self.work.resume_data(self.info_hash, have);
+        resume.peers = self
+            .good_peers
+            .iter()
+            .take(MAX_SAVED_PEERS)
+            .copied()
+            .collect();
+        resume
+    }
+
+    /// Tell the trackers that we're leaving the swarm. Trackers that don't
+    /// respond quickly are given up on.
+    pub async fn shutdown(&mut self) {
+        if self.started {
+            let stats = transfer_stats(&self.work);
+            let trackers = self
+                .trackers
+                .iter()
+                .filter_map(|url| self.running_trackers.get(url).cloned());
+            announce_all(
+                trackers,
+                &self.info_hash,
+                &self.peer_id,
+                stats,
+                Event::Stopped,
+            )
+            .await;
+            self.started = false;
+        }
+    }
+
+    #[instrument(skip_all, fields(info_hash = %HEXLOWER.encode(&self.info_hash)))]
     pub async fn run(&mut self, piece_tx: Sender<Piece>) {
+        // Nothing to report if the download was already complete
+        self.completed |= self.work.left() == 0;
+        self.started = true;
+
+        // Closes the piece channel for all the senders on completion
+        let mut piece_closer = piece_tx.clone();
+
         let work = &self.work;
+        let events = &self.events;
+        let bandwidth = &self.bandwidth;
+        let config = &self.config;
+        let blocklist = self.session.blocklist();
+        let ip_connections = self.session.ip_connections();
+        let version = &self.session.identity().version;
+        let session = &self.session;
+        let traffic = session.traffic();
+        let recv_budget = self.session.recv_budget();
+        let reputation = self.session.reputation();
+        let dual_stack = session.dual_stack();
+        let mut own_events = events.subscribe();
         let info_hash = &self.info_hash;
         let peer_id = &self.peer_id;
-        let mut all_peers = self.peers.iter().copied().collect::<HashSet<_>>();
-        let mut all_peers6 = self.peers6.iter().copied().collect::<HashSet<_>>();
-        let mut trackers = self
-            .trackers
-            .iter()
-            .map(|t| Tracker::new(t.clone()))
-            .collect::<VecDeque<_>>();
-        let dht_tracker = &mut self.dht_tracker;
+        let port = &mut self.port;
+        let mut all_peers = HashSet::new();
+        let mut connected = HashMap::new();
+        let mut tried = HashSet::new();
+        let mut idle: HashMap<_, Instant> = HashMap::new();
+        let mut failed = FailedPeers::new(config);
+        let good_peers = &mut self.good_peers;
+        add_peers(
+            &mut all_peers,
+            &failed,
+            self.peers.iter().chain(self.peers6.iter()).copied(),
+        );
+        let dht_tracker = self.dht_tracker.as_mut();
+        let dht_port = dht_tracker.as_ref().and_then(|dht| dht.dht_port());
+        let dialer = self.dialer.as_ref();
+        let piece_reader = self.piece_reader.as_ref().filter(|_| !config.download_only);
+        let choker = &Choker::new(config.max_uploads);
+
+        // Set while the web seeds hold a piece taken from the queue
+        let web_seed_busy = AtomicBool::new(false);
+        let paused = AtomicBool::new(false);
+        let web_seeds = &mut self.web_seeds;
+        let web_seeding = async {
+            match config.web_seed_cutoff {
+                Some(cutoff) if !web_seeds.is_empty() => {
+                    let piece_tx = piece_tx.clone();
+                    let busy = &web_seed_busy;
+                    webseed::run(web_seeds, work, events, piece_tx, cutoff, busy, &paused).await
+                }
+                _ => future::pending().await,
+            }
+        }
+        .fuse();
+        futures::pin_mut!(web_seeding);
+
+        // New peers each tracker has given us, which decides the order of
+        // the announces waiting for a slot
+        let mut new_peers: HashMap<String, u64> = HashMap::new();
+        let announce_slots = &AnnounceSlots::new(config.max_concurrent_announces);
+        // The port is set on each announce, and `delay` replaces the wait for
+        // the interval
+        let announce = |tracker: SharedTracker,
+                        url: String,
+                        priority: u64,
+                        port: u16,
+                        delay: Option<Duration>| async move {
+            let resp = {
+                let mut tracker = tracker.lock().await;
+                tracker.set_port(port);
+                if let Some(delay) = delay {
+                    tracker.announce_in(delay);
+                }
+                tracker.wait().await;
+                let _slot = announce_slots.acquire(priority).await;
+                let stats = transfer_stats(work);
+                tracker.announce_stats(info_hash, peer_id, stats).await
+            };
+            (resp, tracker, url)
+        };
+
+        // Announces of removed trackers are aborted, and the trackers told
+        // that we've stopped
+        let mut tracker_handles: HashMap<String, AbortHandle> = HashMap::new();
+        let mut stopping = FuturesUnordered::new();
 
+        let running_trackers = &mut self.running_trackers;
         let pending_downloads = FuturesUnordered::new();
-        let pending_trackers = FuturesUnordered::new();
+        let pending_trackers: FuturesUnordered<_> = self
+            .trackers
+            .iter()
+            .enumerate()
+            .map(|(i, url)| {
+                let tracker = running_tracker(running_trackers, url, &config.http, session);
+                let delay = TRACKER_STAGGER * i as u32;
+                let f = announce(tracker, url.clone(), 0, *port, Some(delay));
+                let (f, handle) = future::abortable(f);
+                tracker_handles.insert(url.clone(), handle);
+                f
+            })
+            .collect();
+        let trackers = &mut self.trackers;
+        let commands = &mut self.commands;
 
         futures::pin_mut!(pending_downloads);
         futures::pin_mut!(pending_trackers);
 
-        let dht_tracker = stream::unfold(dht_tracker, |dht| async {
-            let peers = dht.announce(info_hash).await;
-            Some((peers, dht))
-        })
+        // Port changes and the DHT nodes of the peers, or restored ones,
+        // interrupt the wait for the next lookup
+        let (dht_port_tx, dht_port_rx) = mpsc::unbounded();
+        let (dht_node_tx, dht_node_rx) = mpsc::unbounded();
+        let (dht_restore_tx, dht_restore_rx) = mpsc::unbounded();
+        let dht_tracker = stream::unfold(
+            (dht_tracker, dht_port_rx, dht_node_rx, dht_restore_rx),
+            |(dht, mut port_rx, mut node_rx, mut restore_rx)| async move {
+                // Without a DHT the stream never ends, like a DHT which
+                // finds nothing
+                let dht = match dht {
+                    Some(dht) => dht,
+                    None => future::pending().await,
+                };
+                loop {
+                    select! {
+                        peers = dht.next_peers(info_hash).fuse() => {
+                            return Some((peers, (Some(dht), port_rx, node_rx, restore_rx)));
+                        }
+                        port = port_rx.select_next_some() => dht.set_port(port),
+                        addr = node_rx.select_next_some() => dht.add_node(addr),
+                        nodes = restore_rx.select_next_some() => dht.restore_nodes(nodes),
+                    }
+                }
+            },
+        )
         .fuse();
 
         futures::pin_mut!(dht_tracker);
 
-        // TODO: Make this configurable
-        let max_connections = 10;
-        let mut connected = HashSet::new();
-        let mut failed = HashSet::new();
-        let mut to_connect = Vec::with_capacity(10);
+        let mut slots = Slots::new(config);
+        let mut to_connect = Vec::with_capacity(config.max_connections);
 
         let (mut add_conn_tx, mut add_conn_rx) = mpsc::channel(10);
 
         // Add initial connections
-        if !all_peers.is_empty() || !all_peers6.is_empty() {
+        if !all_peers.is_empty() {
             add_conn_tx.send(()).await.unwrap();
         }
 
         let mut print_speed_interval = time::interval(Duration::from_secs(1));
+        let mut last_traffic_log = Instant::now();
+        let mut last_rate_sample = Instant::now();
+
+        // The completion hook may move the files, so once the pieces are done
+        // the writer is given a while to flush them first
+        let mut flushed = self.completed || self.on_complete.is_none();
+        let mut flushing = false;
+        let mut flush_timed_out = false;
+        let flush_timeout = future::Fuse::terminated();
+        futures::pin_mut!(flush_timeout);
 
         loop {
+            let mut done = false;
             select! {
                 // Add new download connections
                 _ = add_conn_rx.next() => {
-                    if connected.len() < max_connections {
-                        to_connect.extend(
-                            all_peers
-                                .iter()
-                                .chain(all_peers6.iter())
-                                .filter(|&p| !connected.contains(p) && !failed.contains(p))
-                                .take(max_connections - connected.len())
-                                .copied(),
-                        );
-
-                        for peer in to_connect.drain(..) {
+                    if !slots.is_full() && !paused.load(Relaxed) {
+                        let reachability = session.reachability();
+                        let mut candidates: Vec<_> = all_peers.iter().copied().filter(|p| {
+                            !connected.contains_key(p)
+                                && failed.can_retry(p)
+                                && idle
+                                    .get(p)
+                                    .is_none_or(|t| t.elapsed() >= IDLE_PEER_RETRY)
+                                && !work.is_banned(&p.addr())
+                                && !blocklist.is_banned(p.ip())
+                                && reachability.allows(p.ip())
+                                && !dual_stack.alt(p).is_some_and(|a| connected.contains_key(&a))
+                        }).collect();
+
+                        // Peers which served us well before go first, then
+                        // spread the connections over as many networks as
+                        // possible
+                        reputation.sort(&mut candidates);
+                        ip_connections.sort_by_diversity(&mut candidates);
+
+                        for peer in candidates {
+                            // Other torrents may be connected to the IP too
+                            let permit = match ip_connections.acquire(peer.ip()) {
+                                Some(p) => p,
+                                None => continue,
+                            };
+                            if let Some(slot) = slots.take(!tried.contains(&peer)) {
+                                to_connect.push((peer, slot, permit));
+                            }
+
+                            if slots.is_full() {
+                                break;
+                            }
+                        }
+
+                        for (peer, slot, permit) in to_connect.drain(..) {
+                            // The other address of the peer is raced against
+                            // this one, if we may connect to it
+                            let alt = dual_stack
+                                .alt(&peer)
+                                .filter(|a| {
+                                    reachability.allows(a.ip())
+                                        && !blocklist.is_banned(a.ip())
+                                        && !work.is_banned(&a.addr())
+                                });
                             let piece_tx = piece_tx.clone();
+                            let dht_node_tx = dht_node_tx.clone();
                             pending_downloads.push(async move {
-                                let span = info_span!("conn", addr = ?peer);
+                                let span = info_span!(
+                                    "conn",
+                                    addr = %peer,
+                                    peer_id = field::Empty,
+                                    client = field::Empty
+                                );
+                                // The address which answered first, which the
+                                // peer goes by from then on
+                                let mut winner = peer;
                                 let f = async {
-                                    let socket = timeout(TcpStream::connect(peer), 3).await?;
-                                    let mut client = Client::new(socket);
-                                    client.send_handshake(info_hash, peer_id).await?;
-                                    client.recv_handshake(info_hash).await?;
-                                    let mut dl = Download::new(client, work, piece_tx).await?;
-                                    dl.start().await
+                                    let alt_addr = alt.map(|a| a.addr());
+                                    let (mut client, addr) = connect(
+                                        peer.addr(), alt_addr, info_hash, peer_id, dht_port, dialer, config,
+                                    )
+                                    .await?;
+                                    // Held for as long as the connection, for
+                                    // the IP we ended up connected to
+                                    let _permit = match alt.filter(|a| a.addr() == addr) {
+                                        Some(alt) => {
+                                            winner = alt;
+                                            drop(permit);
+                                            ip_connections.acquire(alt.ip()).ok_or_else(|| {
+                                                anyhow::anyhow!("Too many connections to {}", alt.ip())
+                                            })?
+                                        }
+                                        None => permit,
+                                    };
+                                    client.set_client_version(version.as_str());
+                                    client.set_recv_budget(recv_budget.clone());
+                                    let mut dl = Download::new(
+                                        client, addr, work, events, bandwidth, piece_tx, config,
+                                    )
+                                    .await?;
+                                    dl.set_traffic(traffic);
+                                    dl.set_dht_nodes(dht_node_tx);
+                                    dl.set_reputation(reputation);
+                                    dl.set_dual_stack(dual_stack);
+                                    if let Some(reader) = piece_reader {
+                                        let cache = session.read_cache().clone();
+                                        let source = PieceSource::new(reader.clone(), cache, *info_hash);
+                                        dl.set_uploads(source, choker);
+                                    }
+                                    let result = dl.start().await;
+
+                                    // We're done with the peer, e.g. it timed
+                                    // out, rather than the connection being
+                                    // broken
+                                    let done = match &result {
+                                        Ok(()) => true,
+                                        Err(e) => e.is::<BothSeeds>() || e.is::<PeerTimeout>(),
+                                    };
+                                    if done {
+                                        dl.drain().await;
+                                    }
+                                    result
                                 };
-                                f.instrument(span).await.map_err(|e| (e, peer))
+                                let result = f.instrument(span).await;
+                                (peer, winner, result)
                             });
 
-                            connected.insert(peer);
+                            connected.insert(peer, slot);
+                            tried.insert(peer);
+                            idle.remove(&peer);
 
                             debug!(
                                 "{} active connections, {} pending trackers, {} pending downloads",
@@ -134,35 +978,118 @@ impl TorrentWorker {
                 // Check pending downloads
                 maybe_result = pending_downloads.next() => {
                     match maybe_result {
-                        Some(Ok(())) => {},
-                        Some(Err((e, peer))) => {
-                            warn!("Error occurred for peer {} : {}", peer, e);
-
-                            if connected.remove(&peer) {
-                                failed.insert(peer);
-                                add_conn_tx.send(()).await.unwrap();
+                        Some((dialed, peer, Ok(()))) => {
+                            // The peer has nothing more for us right now
+                            release_slot(&mut connected, &mut slots, &dialed);
+                            good_peers.insert(peer.addr());
+                            failed.remove(&peer);
+                            idle.insert(peer, Instant::now());
+                            add_conn_tx.send(()).await.unwrap();
+                        }
+                        Some((dialed, peer, Err(e))) => {
+                            if e.is::<BothSeeds>() {
+                                debug!("Disconnected from seed {}", peer);
+                                good_peers.insert(peer.addr());
+                                failed.give_up(peer);
+                            } else if let Some(PeerTimeout::Unchoke) = e.downcast_ref() {
+                                // Busy serving others, so try it again
+                                // later like an idle peer
+                                debug!("{} for {}", PeerTimeout::Unchoke, peer);
+                                idle.insert(peer, Instant::now());
+                            } else if let Some(PeerTimeout::Freeloader) = e.downcast_ref() {
+                                // It may have been saturated rather than
+                                // greedy, so give it another chance later
+                                debug!("{} for {}", PeerTimeout::Freeloader, peer);
+                                good_peers.remove(&peer.addr());
+                                idle.insert(peer, Instant::now());
+                            } else if let Some(client::Error::SelfConnection) = e.downcast_ref() {
+                                // Trackers and the DHT keep handing out our
+                                // own address. Other peers may share the IP,
+                                // e.g. behind the same NAT, so only the
+                                // address is given up on.
+                                debug!("{} is our own address", peer);
+                                failed.give_up(peer);
                             } else {
-                                debug_assert!(false, "peer should be in `connected` list")
+                                if let Some(check) = handshake_check(&e) {
+                                    debug!("Handshake with {} failed: {}", peer, e);
+                                    events.emit(TorrentEvent::HandshakeFailed {
+                                        addr: peer.addr(),
+                                        check,
+                                    });
+                                    // A slow peer may make it in time on
+                                    // another try, with the usual backoff.
+                                    // The others sent a handshake we can't
+                                    // use.
+                                    if check == HandshakeCheck::Timeout {
+                                        failed.insert(peer, Instant::now());
+                                    } else {
+                                        failed.give_up(peer);
+                                    }
+                                } else {
+                                    warn!("Error occurred for peer {} : {}", peer, e);
+                                    failed.insert(peer, Instant::now());
+                                }
+                                good_peers.remove(&peer.addr());
+                            }
+
+                            if let Some(
+                                client::Error::TooManyUnknownMessages(_)
+                                | client::Error::ExtendedFlood,
+                            ) = e.downcast_ref()
+                            {
+                                blocklist.ban(peer.ip(), BanReason::ProtocolViolation);
                             }
+
+                            release_slot(&mut connected, &mut slots, &dialed);
+                            add_conn_tx.send(()).await.unwrap();
                         }
                         None => {
-                            if work.is_empty() {
-                                break;
+                            // A seed stays until it has offered its pieces
+                            // to all the peers
+                            let reachability = session.reachability();
+                            let untried = piece_reader.is_some()
+                                && all_peers.iter().any(|p| {
+                                    !tried.contains(p)
+                                        && !blocklist.is_banned(p.ip())
+                                        && reachability.allows(p.ip())
+                                });
+                            if untried {
+                                add_conn_tx.try_send(()).ok();
+                            } else if work.is_empty() && !web_seed_busy.load(Relaxed) {
+                                done = true;
                             }
                         },
                     }
                 }
 
+                // The web seeds are done or all gone
+                () = web_seeding => {
+                    if work.is_empty() && pending_downloads.is_empty() {
+                        done = true;
+                    }
+                }
+
+                // The writer is stuck, so the hook is skipped
+                () = flush_timeout => {
+                    warn!("Pieces not flushed in time, skipping the completion hook");
+                    flush_timed_out = true;
+                    break;
+                }
+
+                // Share the bans with the other torrents of the session
+                event = own_events.next() => {
+                    if let Some(TorrentEvent::PeerBanned { addr }) = event {
+                        blocklist.ban(addr.ip(), BanReason::CorruptData);
+                    }
+                }
+
                 // Check DHT Tracker announce
                 peers = dht_tracker.next() => {
                     match peers {
                         Some(Ok(peers)) => {
-                            all_peers.extend(peers);
-
-                            // We don't want to connect failed peers again
-                            all_peers.retain(|p| !failed.contains(p));
-                            all_peers6.retain(|p| !failed.contains(p));
-                            add_conn_tx.send(()).await.unwrap();
+                            if add_peers(&mut all_peers, &failed, peers) > 0 {
+                                add_conn_tx.send(()).await.unwrap();
+                            }
                         }
                         Some(Err(e)) => {
                             warn!("DHT announce error: {}", e);
@@ -176,44 +1103,826 @@ impl TorrentWorker {
 
                 // Check other tracker announce
                 resp = pending_trackers.next() => {
-                    let resp = match resp {
-                        Some((resp, tracker)) => {
-                            trackers.push_back(tracker);
-                            resp
-                        },
+                    let (resp, tracker, url) = match resp {
+                        Some(Ok(r)) => r,
+                        // The tracker was removed
+                        Some(Err(_)) => continue,
                         None => {
                             debug!("Trackers are all done");
                             continue;
                         }
                     };
 
-                    while let Some(mut tracker) = trackers.pop_front() {
-                        pending_trackers.push(async move {
-                            let resp = tracker.announce(info_hash, peer_id).await;
-                            (resp, tracker)
-                        });
+                    let added = match resp {
+                        Ok(resp) => {
+                            let peers = resp.peers.into_iter().chain(resp.peers6);
+                            add_peers(&mut all_peers, &failed, peers)
+                        }
+                        Err(e) => {
+                            warn!("Announce error: {}", e);
+                            0
+                        }
+                    };
+
+                    let priority = new_peers.entry(url.clone()).or_default();
+                    *priority += added as u64;
+                    let f = announce(tracker, url.clone(), *priority, *port, None);
+                    let (f, handle) = future::abortable(f);
+                    tracker_handles.insert(url, handle);
+                    pending_trackers.push(f);
+
+                    if added > 0 {
+                        add_conn_tx.send(()).await.unwrap();
                     }
+                }
 
-                    match resp {
-                        Ok(resp) => {
-                            all_peers.extend(resp.peers);
-                            all_peers6.extend(resp.peers6);
+                // Stopped announces to the removed trackers
+                _ = stopping.select_next_some() => {}
 
-                            // We don't want to connect failed peers again
-                            all_peers.retain(|p| !failed.contains(p));
-                            all_peers6.retain(|p| !failed.contains(p));
-                            add_conn_tx.send(()).await.unwrap();
+                // Changes made through the handles
+                command = commands.next() => {
+                    match command {
+                        Some(Command::AddTracker(url)) if !trackers.contains(&url) => {
+                            debug!("Adding tracker {}", redact(&url));
+                            let tracker =
+                                running_tracker(running_trackers, &url, &config.http, session);
+                            let f = announce(tracker, url.clone(), 0, *port, None);
+                            let (f, handle) = future::abortable(f);
+                            tracker_handles.insert(url.clone(), handle);
+                            pending_trackers.push(f);
+                            trackers.push(url);
+                        }
+                        // Already announcing to it
+                        Some(Command::AddTracker(_)) => {}
+                        Some(Command::RemoveTracker(url)) => {
+                            if let Some(handle) = tracker_handles.remove(&url) {
+                                debug!("Removing tracker {}", redact(&url));
+                                handle.abort();
+                            }
+                            // Told with the state of the running tracker, once
+                            // the aborted announce lets go of it
+                            if let Some(tracker) = running_trackers.remove(&url) {
+                                stopping.push(announce_all(
+                                    iter::once(tracker),
+                                    info_hash,
+                                    peer_id,
+                                    transfer_stats(work),
+                                    Event::Stopped,
+                                ));
+                            }
+                            new_peers.remove(&url);
+                            trackers.retain(|t| *t != url);
+                        }
+                        Some(Command::AddPeer(addr)) => {
+                            if let Some(peer) = PeerAddr::new(addr) {
+                                failed.remove(&peer);
+                                all_peers.insert(peer);
+                                add_conn_tx.send(()).await.unwrap();
+                            }
+                        }
+                        Some(Command::RestoreDhtNodes(nodes)) => {
+                            dht_restore_tx.unbounded_send(nodes).ok();
                         }
-                       Err(e) => warn!("Announce error: {}", e),
+                        Some(Command::SetPort(new_port)) if new_port != *port => {
+                            debug!("Announcing port {}", new_port);
+                            *port = new_port;
+                            dht_port_tx.unbounded_send(new_port).ok();
+
+                            // Announce the new port to the trackers
+                            // right away, like a new start
+                            for url in trackers.iter() {
+                                if let Some(handle) = tracker_handles.remove(url) {
+                                    handle.abort();
+                                }
+                                let tracker =
+                                    running_tracker(running_trackers, url, &config.http, session);
+                                let priority = new_peers.get(url).copied().unwrap_or(0);
+                                let f = announce(
+                                    tracker,
+                                    url.clone(),
+                                    priority,
+                                    new_port,
+                                    Some(Duration::ZERO),
+                                );
+                                let (f, handle) = future::abortable(f);
+                                tracker_handles.insert(url.clone(), handle);
+                                pending_trackers.push(f);
+                            }
+                        }
+                        // Same port as before
+                        Some(Command::SetPort(_)) => {}
+                        // Only awaited on completion
+                        Some(Command::StorageFlushed) => flushed |= flushing,
+                        Some(Command::SetPieceDeadline(index, deadline)) => {
+                            match deadline {
+                                Some(deadline) => work.set_piece_deadline(index, deadline),
+                                None => work.clear_piece_deadline(index),
+                            }
+                        }
+                        Some(Command::StorageFailed { index, kind, message }) => {
+                            error!("Storage error ({:?}): {}; pausing", kind, message);
+                            if let Some(info) = index.and_then(|i| work.piece_info(i)) {
+                                work.add_piece(info);
+                            }
+                            events.emit(TorrentEvent::StorageError { index, kind, message });
+                            pause(&paused, &mut connected, &mut slots);
+                            pending_downloads.set(FuturesUnordered::new());
+
+                            // The piece channel is closed, so the pieces
+                            // can't be written again
+                            if flushing {
+                                break;
+                            }
+                        }
+                        Some(Command::Pause) => {
+                            debug!("Pausing");
+                            pause(&paused, &mut connected, &mut slots);
+                            pending_downloads.set(FuturesUnordered::new());
+                        }
+                        Some(Command::Resume) => {
+                            let was_paused = paused.swap(false, Relaxed);
+                            if was_paused {
+                                debug!("Resuming");
+                                add_conn_tx.send(()).await.unwrap();
+                            }
+                        }
+                        // We hold a sender, so this never happens
+                        None => {}
                     }
                 }
 
                 // Print download speed
                 _ = print_speed_interval.tick().fuse() => {
-                    let n = work.get_downloaded_and_reset();
-                    println!("{} kBps", n / 1000);
+                    let rate = work.sample_rate(last_rate_sample.elapsed());
+                    last_rate_sample = Instant::now();
+                    println!("{} kBps", rate / 1000);
+                    events.emit(TorrentEvent::Progress(work.progress()));
+
+                    if config
+                        .traffic_log_interval
+                        .is_some_and(|i| last_traffic_log.elapsed() >= i)
+                    {
+                        info!("Session traffic: {}", traffic.stats());
+                        last_traffic_log = Instant::now();
+                    }
+
+                    // Idle peers may have new pieces by now, and failed
+                    // ones may be up again
+                    if (!idle.is_empty() || failed.has_retries()) && !slots.is_full() {
+                        add_conn_tx.try_send(()).ok();
+                    }
+                }
+            }
+
+            if flushed && (done || flushing) {
+                break;
+            }
+            if done && !flushing {
+                if work.left() > 0 {
+                    break;
                 }
+                debug!("Waiting for the pieces to be flushed");
+                flushing = true;
+                piece_closer.close_channel();
+                flush_timeout.set(time::sleep(FLUSH_TIMEOUT).fuse());
             }
         }
+
+        if !self.completed && work.left() == 0 && (flushed || flush_timed_out) {
+            if let Some(hook) = self.on_complete.as_ref().filter(|_| flushed) {
+                hook(*info_hash).await;
+            }
+
+            // The pending announces hold on to the trackers
+            pending_trackers.clear();
+            let stats = transfer_stats(work);
+            let trackers = trackers
+                .iter()
+                .filter_map(|url| running_trackers.get(url).cloned());
+            announce_all(trackers, info_hash, peer_id, stats, Event::Completed).await;
+            self.completed = true;
+        }
+    }
+}
+
+impl Drop for TorrentWorker {
+    fn drop(&mut self) {
+        self.session.unwatch_port(&self.handle());
+    }
+}
+
+fn transfer_stats(work: &WorkQueue) -> TransferStats {
+    TransferStats {
+        uploaded: work.total_uploaded(),
+        downloaded: work.total_downloaded(),
+        left: work.left(),
+        corrupt: work.total_corrupt(),
+    }
+}
+
+/// Tracker shared by its regular announces and the event announces.
+type SharedTracker = Arc<Mutex<Tracker>>;
+
+/// The running tracker of `url`, or a new one with the extra parameters of
+/// the session, its traffic counted in the session's.
+fn running_tracker(
+    running: &mut HashMap<String, SharedTracker>,
+    url: &str,
+    http: &HttpConfig,
+    session: &Session,
+) -> SharedTracker {
+    running
+        .entry(url.to_string())
+        .or_insert_with(|| {
+            let mut tracker = Tracker::with_config(url.to_string(), http.clone());
+            tracker.set_params(session.announce_params());
+            tracker.set_ipv6(session.local_ipv6());
+            tracker.set_traffic(session.traffic().clone());
+            Arc::new(Mutex::new(tracker))
+        })
+        .clone()
+}
+
+/// Announce the event to all the trackers at once, giving up on the ones
+/// which don't respond in time.
+async fn announce_all(
+    trackers: impl Iterator<Item = SharedTracker>,
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+    stats: TransferStats,
+    event: Event,
+) {
+    let mut pending: FuturesUnordered<_> = trackers
+        .map(|tracker| async move {
+            let mut tracker = tracker.lock().await;
+            let num_want = tracker.num_want();
+            if event == Event::Stopped {
+                // No use for peers on the way out
+                tracker.set_num_want(Some(0));
+            }
+            let f = tracker.announce_event(info_hash, peer_id, stats, event);
+            let resp = timeout(f, EVENT_ANNOUNCE_TIMEOUT).await;
+            tracker.set_num_want(num_want);
+            if let Err(e) = resp {
+                debug!(
+                    "{:?} announce to {} failed: {}",
+                    event,
+                    redact(&tracker.url),
+                    e
+                );
+            }
+        })
+        .collect();
+
+    while pending.next().await.is_some() {}
+}
+
+/// Kind of connection slot a peer is using.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Regular,
+
+    /// One of the slots reserved for never-tried peers.
+    New,
+}
+
+/// Connection slots in use, split into regular and reserved slots.
+#[derive(Debug)]
+struct Slots {
+    regular: usize,
+    new: usize,
+    max_regular: usize,
+    max_new: usize,
+}
+
+impl Slots {
+    fn new(config: &WorkerConfig) -> Self {
+        let max_new = config.reserved_slots();
+        Self {
+            regular: 0,
+            new: 0,
+            max_regular: config.max_connections - max_new,
+            max_new,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.regular == self.max_regular && self.new == self.max_new
+    }
+
+    /// Take a slot for a peer. Never-tried peers go to the reserved slots
+    /// first, and only they may use them.
+    fn take(&mut self, is_new: bool) -> Option<Slot> {
+        if is_new && self.new < self.max_new {
+            self.new += 1;
+            Some(Slot::New)
+        } else if self.regular < self.max_regular {
+            self.regular += 1;
+            Some(Slot::Regular)
+        } else {
+            None
+        }
+    }
+
+    fn release(&mut self, slot: Slot) {
+        match slot {
+            Slot::Regular => self.regular -= 1,
+            Slot::New => self.new -= 1,
+        }
+    }
+}
+
+/// Stop connecting to the peers and give back the slots of the connected
+/// ones. The caller drops the downloads, which puts their pieces back in
+/// the queue.
+fn pause(paused: &AtomicBool, connected: &mut HashMap<PeerAddr, Slot>, slots: &mut Slots) {
+    paused.store(true, Relaxed);
+    for (_, slot) in connected.drain() {
+        slots.release(slot);
+    }
+}
+
+/// Free the slot of a closed connection. A freed regular slot is handed to
+/// a connection in a reserved slot, if any, so that the reserved slot can
+/// be used to try another new peer.
+fn release_slot(connected: &mut HashMap<PeerAddr, Slot>, slots: &mut Slots, peer: &PeerAddr) {
+    let slot = match connected.remove(peer) {
+        Some(slot) => slot,
+        None => {
+            debug_assert!(false, "peer should be in `connected` list");
+            return;
+        }
+    };
+
+    slots.release(slot);
+
+    if slot == Slot::Regular {
+        if let Some(s) = connected.values_mut().find(|s| **s == Slot::New) {
+            *s = Slot::Regular;
+            slots.release(Slot::New);
+            slots.regular += 1;
+        }
+    }
+}
+
+/// Peers which failed, with when to try connecting to them again.
+#[derive(Debug)]
+struct FailedPeers {
+    peers: HashMap<PeerAddr, Retry>,
+    max_attempts: u32,
+    delay: Duration,
+}
+
+#[derive(Debug)]
+struct Retry {
+    attempts: u32,
+
+    /// `None` if the peer was given up on.
+    at: Option<Instant>,
+}
+
+impl FailedPeers {
+    fn new(config: &WorkerConfig) -> Self {
+        Self {
+            peers: HashMap::new(),
+            max_attempts: config.max_connect_attempts,
+            delay: config.connect_retry_delay,
+        }
+    }
+
+    /// Record a failure which may be temporary, like a connect timeout.
+    fn insert(&mut self, peer: PeerAddr, now: Instant) {
+        let retry = self.peers.entry(peer).or_insert(Retry {
+            attempts: 0,
+            at: None,
+        });
+        retry.attempts += 1;
+
+        if retry.attempts >= self.max_attempts {
+            debug!("Giving up on {} after {} attempts", peer, retry.attempts);
+            retry.at = None;
+        } else {
+            let delay = self.delay.saturating_mul(1 << (retry.attempts - 1).min(16));
+            retry.at = Some(now + delay.min(MAX_RETRY_DELAY));
+        }
+    }
+
+    /// Never connect to the peer again, unless it's removed.
+    fn give_up(&mut self, peer: PeerAddr) {
+        let retry = self.peers.entry(peer).or_insert(Retry {
+            attempts: 0,
+            at: None,
+        });
+        retry.at = None;
+    }
+
+    fn remove(&mut self, peer: &PeerAddr) {
+        self.peers.remove(peer);
+    }
+
+    /// Returns true if the peer hasn't failed or its retry is due.
+    fn can_retry(&self, peer: &PeerAddr) -> bool {
+        match self.peers.get(peer) {
+            Some(retry) => retry.at.is_some_and(|t| t <= Instant::now()),
+            None => true,
+        }
+    }
+
+    fn has_given_up(&self, peer: &PeerAddr) -> bool {
+        self.peers.get(peer).is_some_and(|r| r.at.is_none())
+    }
+
+    /// Returns true if some failed peers will be retried.
+    fn has_retries(&self) -> bool {
+        self.peers.values().any(|r| r.at.is_some())
+    }
+}
+
+/// Add the canonical addresses of the given peers to `all_peers`, skipping
+/// the invalid ones and the ones given up on. Returns the number of new
+/// peers.
+fn add_peers(
+    all_peers: &mut HashSet<PeerAddr>,
+    failed: &FailedPeers,
+    peers: impl IntoIterator<Item = SocketAddr>,
+) -> usize {
+    let old_len = all_peers.len();
+
+    all_peers.extend(
+        peers
+            .into_iter()
+            .filter_map(PeerAddr::new)
+            .filter(|p| !failed.has_given_up(p)),
+    );
+
+    all_peers.len() - old_len
+}
+
+/// Connect to the peer and exchange the handshakes.
+///
+/// If the peer rejects our handshake, it's retried once without advertising
+/// any extensions since some clients don't like unknown reserved bits.
+async fn connect(
+    addr: SocketAddr,
+    alt: Option<SocketAddr>,
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+    dht_port: Option<u16>,
+    dialer: Option<&Dialer>,
+    config: &WorkerConfig,
+) -> anyhow::Result<(Client<PeerStream>, SocketAddr)> {
+    match handshake(
+        addr, alt, info_hash, peer_id, true, dht_port, dialer, config,
+    )
+    .await
+    {
+        Err(e) if is_protocol_mismatch(&e) => {
+            debug!("Handshake failed: {}; retrying without extensions", e);
+            handshake(addr, alt, info_hash, peer_id, false, None, dialer, config).await
+        }
+        result => result,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handshake(
+    addr: SocketAddr,
+    alt: Option<SocketAddr>,
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+    extended: bool,
+    dht_port: Option<u16>,
+    dialer: Option<&Dialer>,
+    config: &WorkerConfig,
+) -> anyhow::Result<(Client<PeerStream>, SocketAddr)> {
+    let (socket, addr) = dial(addr, alt, dialer, &config.socket).await?;
+    let mut client = Client::new(socket);
+    client.set_extended(extended);
+    client.set_dht_port(dht_port);
+    client.set_handshake_timeout(config.handshake_timeout);
+    client.send_handshake(info_hash, peer_id).await?;
+    let peer_id = client.recv_handshake(info_hash).await?;
+
+    let span = Span::current();
+    span.record("peer_id", field::display(HEXLOWER.encode(&peer_id)));
+    span.record("client", field::display(peer::client_name(&peer_id)));
+    Ok((client, addr))
+}
+
+/// Open a connection to the peer at `addr`.
+///
+/// A peer with an address of the other IP family in `alt` is dialed at both,
+/// IPv6 first and the other one after [`HAPPY_EYEBALLS_DELAY`] or as soon as
+/// the first fails. The first connection to open is returned along with
+/// the address it's open to.
+async fn dial(
+    addr: SocketAddr,
+    alt: Option<SocketAddr>,
+    dialer: Option<&Dialer>,
+    config: &SocketConfig,
+) -> anyhow::Result<(PeerStream, SocketAddr)> {
+    let open = |addr| async move {
+        let socket = match dialer {
+            Some(dial) => PeerStream::Dialed(timeout(dial(addr), 3).await?),
+            None => PeerStream::Tcp(timeout(config.connect(addr), 3).await?),
+        };
+        anyhow::Ok((socket, addr))
+    };
+
+    let alt = match alt {
+        Some(alt) if alt.is_ipv4() != addr.is_ipv4() => alt,
+        _ => return open(addr).await,
+    };
+    let (first, second) = if addr.is_ipv6() {
+        (addr, alt)
+    } else {
+        (alt, addr)
+    };
+
+    let first_conn = open(first).fuse();
+    let second_conn = future::Fuse::terminated();
+    let delay = time::sleep(HAPPY_EYEBALLS_DELAY).fuse();
+    futures::pin_mut!(first_conn, second_conn, delay);
+    let mut error = None;
+    let mut second_started = false;
+    loop {
+        select! {
+            result = first_conn => match result {
+                Ok(socket) => return Ok(socket),
+                Err(e) => {
+                    debug!("Unable to connect to {}: {}", first, e);
+                    error = Some(e);
+                }
+            },
+            _ = delay => {}
+            result = second_conn => match result {
+                Ok(socket) => return Ok(socket),
+                Err(e) => {
+                    debug!("Unable to connect to {}: {}", second, e);
+                    error = Some(e);
+                }
+            },
+            complete => break,
+        }
+
+        if !second_started && (delay.is_terminated() || first_conn.is_terminated()) {
+            second_started = true;
+            second_conn.set(open(second).fuse());
+        }
+    }
+
+    // Both of them failed
+    Err(error.unwrap())
+}
+
+/// The peer either hung up cleanly in the middle of the handshakes or
+/// replied with a handshake we don't understand. A reset connection is no
+/// sign of the peer disliking our handshake, so it's not retried.
+fn is_protocol_mismatch(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref(),
+        Some(client::Error::UnsupportedProtocol | client::Error::IncompleteHandshake(_))
+    )
+}
+
+/// The check of the peer's handshake that failed, if any.
+fn handshake_check(e: &anyhow::Error) -> Option<HandshakeCheck> {
+    match e.downcast_ref()? {
+        client::Error::UnsupportedProtocol => Some(HandshakeCheck::Protocol),
+        client::Error::InfoHashMismatch => Some(HandshakeCheck::InfoHash),
+        client::Error::HandshakeTimeout => Some(HandshakeCheck::Timeout),
+        client::Error::IncompleteHandshake(_) => Some(HandshakeCheck::Complete),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_connections: usize, new_peer_ratio: f32) -> WorkerConfig {
+        WorkerConfig {
+            max_connections,
+            new_peer_ratio,
+            ..WorkerConfig::default()
+        }
+    }
+
+    #[test]
+    fn worker_is_send() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        // Checked at compile time, so no need to call it
+        #[allow(dead_code)]
+        fn run(worker: &mut TorrentWorker, piece_tx: Sender<Piece>) {
+            assert_send(worker);
+            assert_send(&worker.run(piece_tx));
+        }
+    }
+
+    #[test]
+    fn handle_outlives_worker() {
+        let (commands, mut rx) = mpsc::unbounded();
+        let handle = TorrentHandle { commands };
+        handle.add_tracker("udp://tracker.example:80");
+        handle.remove_tracker("udp://tracker.example:80");
+        handle.add_peer(SocketAddr::from(([1, 2, 3, 4], 6881)));
+        handle.clear_piece_deadline(3);
+        assert!(matches!(rx.try_recv(), Ok(Command::AddTracker(_))));
+        assert!(matches!(rx.try_recv(), Ok(Command::RemoveTracker(_))));
+        assert!(matches!(rx.try_recv(), Ok(Command::AddPeer(_))));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Command::SetPieceDeadline(3, None))
+        ));
+
+        // Sending to a worker which is gone is not an error
+        drop(rx);
+        handle.add_peer(SocketAddr::from(([1, 2, 3, 4], 6881)));
+    }
+
+    #[tokio::test]
+    async fn storage_writer_reports_to_the_worker() {
+        use crate::storage::{write_torrent_pieces, SequentialWriter};
+
+        let write = |order: [u32; 2]| async move {
+            let (commands, rx) = mpsc::unbounded();
+            let handle = TorrentHandle { commands };
+            let mut sink = SequentialWriter::new(vec![], Bitfield::with_value(2, false));
+            let pieces = order.map(|i| Piece::new(i, vec![i as u8; 4].into()));
+            write_torrent_pieces(
+                &mut sink,
+                Bitfield::with_value(2, false),
+                stream::iter(pieces.map(Ok)),
+                &handle,
+            )
+            .await;
+            drop(handle);
+            rx.collect::<Vec<_>>().await
+        };
+
+        let commands = write([0, 1]).await;
+        assert!(matches!(commands[..], [Command::StorageFlushed]));
+
+        // Out of order, so piece 1 isn't written and nothing is flushed as
+        // far as the worker knows
+        let commands = write([1, 0]).await;
+        assert!(matches!(
+            commands[..],
+            [Command::StorageFailed { index: Some(1), .. }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn dial_dual_stack_peers() {
+        let v4 = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let v6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 6881));
+        let dialer = |v6_hangs: bool| -> Dialer {
+            Arc::new(move |addr: SocketAddr| {
+                if addr.is_ipv4() {
+                    let (stream, _) = client::testing::Peer::create_pair();
+                    future::ready(Ok(Box::new(stream) as DialedStream)).boxed()
+                } else if v6_hangs {
+                    future::pending().boxed()
+                } else {
+                    future::ready(Err(io::ErrorKind::ConnectionRefused.into())).boxed()
+                }
+            })
+        };
+
+        // The IPv4 address is dialed once the IPv6 one takes too long
+        let config = SocketConfig::default();
+        let dial_v6_hangs = dialer(true);
+        let start = Instant::now();
+        let (_, addr) = dial(v4, Some(v6), Some(&dial_v6_hangs), &config)
+            .await
+            .unwrap();
+        assert_eq!(addr, v4);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // Or right away if it fails
+        let dial_v6_fails = dialer(false);
+        let (_, addr) = dial(v6, Some(v4), Some(&dial_v6_fails), &config)
+            .await
+            .unwrap();
+        assert_eq!(addr, v4);
+        assert!(dial(v6, None, Some(&dial_v6_fails), &config).await.is_err());
+    }
+
+    #[test]
+    fn failed_peers_backoff() {
+        let config = WorkerConfig {
+            max_connect_attempts: 3,
+            connect_retry_delay: Duration::from_secs(10),
+            ..WorkerConfig::default()
+        };
+        let mut failed = FailedPeers::new(&config);
+        let peer = PeerAddr::new(SocketAddr::from(([1, 1, 1, 1], 1))).unwrap();
+        assert!(failed.can_retry(&peer));
+
+        let now = Instant::now();
+        failed.insert(peer, now - Duration::from_secs(15));
+        assert!(failed.can_retry(&peer));
+        assert!(failed.has_retries());
+
+        // The second wait is twice as long
+        failed.insert(peer, now - Duration::from_secs(15));
+        assert!(!failed.can_retry(&peer));
+        assert!(!failed.has_given_up(&peer));
+
+        failed.insert(peer, now);
+        assert!(!failed.can_retry(&peer));
+        assert!(failed.has_given_up(&peer));
+        assert!(!failed.has_retries());
+
+        // Connecting again starts over
+        failed.remove(&peer);
+        assert!(failed.can_retry(&peer));
+    }
+
+    #[test]
+    fn given_up_peers_are_not_added() {
+        let mut failed = FailedPeers::new(&WorkerConfig::default());
+        let a = SocketAddr::from(([1, 1, 1, 1], 1));
+        let b = SocketAddr::from(([2, 2, 2, 2], 2));
+        failed.give_up(PeerAddr::new(a).unwrap());
+
+        let mut all_peers = HashSet::new();
+        assert_eq!(add_peers(&mut all_peers, &failed, [a, b]), 1);
+    }
+
+    #[test]
+    fn reserved_slots() {
+        assert_eq!(config(10, 0.2).reserved_slots(), 2);
+        assert_eq!(config(10, 0.25).reserved_slots(), 3);
+        assert_eq!(config(10, 0.0).reserved_slots(), 0);
+        assert_eq!(config(10, 2.0).reserved_slots(), 10);
+    }
+
+    #[test]
+    fn known_peers_cannot_use_reserved_slots() {
+        let mut slots = Slots::new(&config(3, 0.3));
+        assert_eq!(slots.take(false), Some(Slot::Regular));
+        assert_eq!(slots.take(false), Some(Slot::Regular));
+        assert_eq!(slots.take(false), None);
+        assert!(!slots.is_full());
+
+        assert_eq!(slots.take(true), Some(Slot::New));
+        assert!(slots.is_full());
+        assert_eq!(slots.take(true), None);
+    }
+
+    #[test]
+    fn promote_new_peer_to_freed_regular_slot() {
+        let a = PeerAddr::new(SocketAddr::from(([1, 1, 1, 1], 1))).unwrap();
+        let b = PeerAddr::new(SocketAddr::from(([2, 2, 2, 2], 2))).unwrap();
+
+        let mut slots = Slots::new(&config(2, 0.5));
+        let mut connected = HashMap::new();
+        connected.insert(a, slots.take(false).unwrap());
+        connected.insert(b, slots.take(true).unwrap());
+        assert!(slots.is_full());
+
+        release_slot(&mut connected, &mut slots, &a);
+        assert_eq!(connected[&b], Slot::Regular);
+
+        // The reserved slot is free again for another new peer
+        assert_eq!(slots.take(false), None);
+        assert_eq!(slots.take(true), Some(Slot::New));
+    }
+
+    #[test]
+    fn only_handshake_mismatches_are_retried() {
+        assert!(is_protocol_mismatch(
+            &client::Error::UnsupportedProtocol.into()
+        ));
+        assert!(is_protocol_mismatch(
+            &client::Error::IncompleteHandshake(0).into()
+        ));
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(!is_protocol_mismatch(&reset.into()));
+        assert!(!is_protocol_mismatch(&client::Error::SelfConnection.into()));
+    }
+
+    #[test]
+    fn failed_handshake_checks() {
+        let check = |e: client::Error| handshake_check(&e.into());
+        assert_eq!(
+            check(client::Error::InfoHashMismatch),
+            Some(HandshakeCheck::InfoHash)
+        );
+        assert_eq!(
+            check(client::Error::HandshakeTimeout),
+            Some(HandshakeCheck::Timeout)
+        );
+        assert_eq!(
+            check(client::Error::IncompleteHandshake(20)),
+            Some(HandshakeCheck::Complete)
+        );
+        assert_eq!(check(client::Error::ExtendedFlood), None);
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(handshake_check(&reset.into()), None);
     }
 }